@@ -0,0 +1,9 @@
+// Generates the gRPC backend's client, and the server side mocks are written
+// against, from proto/remotefs.proto. protoc is looked up on PATH, or taken
+// from PROTOC. tonic-build only comes with the grpc feature.
+fn main() {
+    println!("cargo:rerun-if-changed=proto/remotefs.proto");
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/remotefs.proto")
+        .expect("Failed to compile proto/remotefs.proto");
+}
@@ -0,0 +1,27 @@
+// Mounts an in-memory tree at the given directory until Ctrl-C, to try the
+// filesystem without a server:
+//
+//     cargo run --example mount_mock -- /tmp/mnt
+//
+// Everything written to it is gone once it is unmounted.
+
+use anyhow::{Context, Result};
+use log::LevelFilter;
+use std::sync::Arc;
+use std::time::Duration;
+
+use remotefs::{init_logging, mount_client, LogFormat, MockBackend, MountConfig};
+
+fn main() -> Result<()> {
+    init_logging(LogFormat::default(), LevelFilter::Info)?;
+    let mountpoint = std::env::args().nth(1).context("Usage: mount_mock <mountpoint>")?;
+
+    let mock = MockBackend::new();
+    mock.add_dir("/docs");
+    mock.add_file("/docs/readme.txt", b"Served from memory by MockBackend\n");
+    mock.add_file("/hello.txt", b"hello\n");
+
+    let handle = mount_client(Arc::new(mock), MountConfig::new(Vec::new()), &mountpoint)?;
+    println!("Mounted at {}, Ctrl-C to unmount", handle.mountpoint());
+    handle.run_until_signal(Duration::from_secs(5))
+}
@@ -1,16 +1,288 @@
 use anyhow::{Context, Result};
-use reqwest::blocking::Client;
+use reqwest::blocking::{Body, Client, Request, RequestBuilder, Response};
+use reqwest::header::{
+    HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_RANGE, DATE, ETAG, IF_MATCH, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, IF_UNMODIFIED_SINCE, LAST_MODIFIED, RANGE,
+};
+use reqwest::{Method, StatusCode};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::fmt;
+use std::io::Read;
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::filesystem::FsError;
+
+mod archive;
+mod breaker;
+mod capabilities;
+mod clock;
+mod context;
+mod endpoint;
+mod events;
+mod http;
+mod limiter;
+mod ndjson;
+mod selftest;
+mod singleflight;
+mod stats;
+mod timestamp;
+mod websocket;
+
+use archive::ARCHIVE_TIMEOUT;
+use breaker::CircuitBreaker;
+use clock::ClockSkew;
+use limiter::RequestLimiter;
+use singleflight::SingleFlight;
+use stats::RequestStats;
+
+pub use archive::ArchiveReader;
+pub use breaker::{BreakerState, CircuitOpen};
+pub use capabilities::{CapabilitySource, ServerCapabilities};
+pub use events::{ChangeEvent, ChangeKind, EventStream, ServerEvent};
+pub use http::HttpConfig;
+pub use selftest::{Probe, ProbeResult, SelfTestReport, SELFTEST_DIR};
+pub use stats::RequestStatsSnapshot;
+pub use timestamp::Timestamp;
+pub(crate) use context::{new_trace_id, upload_mtime, with_caller, with_trace_id, with_upload_mtime};
+pub(crate) use endpoint::Endpoint;
+pub(crate) use stats::take_thread_requests;
+
+const REQUEST_ID: &str = "x-request-id";
+const TRACEPARENT: &str = "traceparent";
+const X_MTIME: &str = "x-mtime";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_FAILOVER_THRESHOLD: u32 = 3;
+const DEFAULT_FAILBACK_INTERVAL: Duration = Duration::from_secs(30);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_BREAKER_COOL_DOWN: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_CONCURRENT: usize = 16;
+const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+const MIN_CHUNK_SIZE: u64 = 4 * 1024;
+const MAX_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_PARTS_PER_READ: usize = 4;
+const PART_ATTEMPTS: u32 = 3;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileEntry {
     pub name: String,
     pub is_dir: bool,
     pub size: u64,
-    pub mtime: f64,
-    pub ctime: f64,
+    #[serde(default = "Timestamp::unknown")]
+    pub mtime: Timestamp,
+    #[serde(default = "Timestamp::unknown")]
+    pub ctime: Timestamp,
     pub mode: u32,
+    // Stable identifier of the object, when the server has one
+    #[serde(default)]
+    pub id: Option<u64>,
+    // Numeric owner on the server, when it reports one
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    // Device number of a device node, whose type is in the S_IFMT bits of mode
+    #[serde(default)]
+    pub major: Option<u32>,
+    #[serde(default)]
+    pub minor: Option<u32>,
+    // Link count of a directory, 2 and one per subdirectory, when the server
+    // counts them
+    #[serde(default)]
+    pub nlink: Option<u32>,
+}
+
+// Identifies the version of a remote file or listing, taken from the ETag
+// header or, when the server sends none, from Last-Modified
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Version {
+    ETag(String),
+    LastModified(String),
+}
+
+// A response with an error status. It is kept typed so callers can tell a
+// missing file from a refused or failed request.
+#[derive(Debug, Clone, Copy)]
+pub struct ServerError {
+    pub status: StatusCode,
+    // The response carried X-Maintenance: true
+    pub maintenance: bool,
+}
+
+impl ServerError {
+    // The server takes no writes for now, overloaded or under maintenance
+    pub fn is_maintenance(&self) -> bool {
+        self.maintenance || self.status == StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+impl From<&Response> for ServerError {
+    fn from(response: &Response) -> Self {
+        let maintenance = response
+            .headers()
+            .get("x-maintenance")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"));
+        Self {
+            status: response.status(),
+            maintenance,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.maintenance {
+            write!(f, "Server returned error: {} (maintenance)", self.status)
+        } else {
+            write!(f, "Server returned error: {}", self.status)
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+// Builds the URL of `path` under `route`. Every segment is percent-encoded
+// so names containing spaces, '#', '?', '+' or '%' reach the server intact.
+// Paths go through remote_path first.
+fn url(base: &str, route: &str, path: &str) -> String {
+    format!("{}/{}/{}", base, route, encode_path(path))
+}
+
+// The path as the server's routes take it, without the leading slash. Empty,
+// '.' and '..' segments are refused: servers that normalize the URL would
+// resolve them to another file, or to one outside the export.
+fn remote_path(path: &str) -> Result<&str> {
+    let path = path.trim_start_matches('/');
+    let path = path.strip_suffix('/').unwrap_or(path);
+    if !path.is_empty() && path.split('/').any(|s| s.is_empty() || s == "." || s == "..") {
+        return Err(anyhow::Error::new(FsError::InvalidPath)
+            .context(format!("Refusing to send the path '/{}'", path)));
+    }
+    Ok(path)
+}
+
+// The last byte of `len` > 0 bytes at `offset`, for Range and Content-Range
+pub(crate) fn last_byte(offset: u64, len: u64) -> Result<u64> {
+    offset.checked_add(len - 1).ok_or_else(|| {
+        anyhow::Error::new(FsError::InvalidArgument)
+            .context(format!("{} bytes at {} end past the largest offset", len, offset))
+    })
+}
+
+// For features the server said it lacks, failing like the 501 it would send
+fn unsupported(what: &str) -> anyhow::Error {
+    let error = ServerError {
+        status: StatusCode::NOT_IMPLEMENTED,
+        maintenance: false,
+    };
+    anyhow::Error::new(error).context(format!("The server does not support {}", what))
+}
+
+// Percent-encodes every byte of `path` but unreserved ones and the slashes
+pub(crate) fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for (n, segment) in path.split('/').enumerate() {
+        if n > 0 {
+            encoded.push('/');
+        }
+        for byte in segment.bytes() {
+            if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                encoded.push(byte as char);
+            } else {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    encoded
+}
+
+impl Version {
+    pub(crate) fn from_response(response: &Response) -> Option<Self> {
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        header(ETAG)
+            .map(Version::ETag)
+            .or_else(|| header(LAST_MODIFIED).map(Version::LastModified))
+    }
+
+    // Makes the request conditional on the remote side having changed since
+    pub(crate) fn condition(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Version::ETag(etag) => request.header(IF_NONE_MATCH, etag.as_str()),
+            Version::LastModified(date) => request.header(IF_MODIFIED_SINCE, date.as_str()),
+        }
+    }
+}
+
+// What a change expects to find on the server, so one made elsewhere in
+// the meantime is not overwritten. A mismatch fails with 412.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Expected {
+    Any,
+    // Nothing at the path yet
+    Absent,
+    Version(Version),
+}
+
+impl Expected {
+    pub(crate) fn condition(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Expected::Any => request,
+            Expected::Absent => request.header(IF_NONE_MATCH, "*"),
+            Expected::Version(Version::ETag(etag)) => request.header(IF_MATCH, etag.as_str()),
+            Expected::Version(Version::LastModified(date)) => {
+                request.header(IF_UNMODIFIED_SINCE, date.as_str())
+            }
+        }
+    }
+}
+
+// Result of a conditional request against a cached version
+pub enum Conditional<T> {
+    NotModified,
+    Modified(T),
+}
+
+#[derive(Debug, Clone)]
+pub struct Listing {
+    pub entries: Vec<FileEntry>,
+    pub version: Option<Version>,
+}
+
+// File contents along with the version they were read from
+#[derive(Debug, Clone)]
+pub struct FileData {
+    pub data: Vec<u8>,
+    pub version: Option<Version>,
+}
+
+// A credential that never shows up in Debug output or logs
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -18,150 +290,1377 @@ struct ListResponse {
     entries: Vec<FileEntry>,
 }
 
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    // Replicas of the storage API, in order of preference
+    pub base_urls: Vec<String>,
+    pub timeout: Duration,
+    // Consecutive transport failures before switching to the next URL
+    pub failover_threshold: u32,
+    // How often the primary is probed while running on a fallback URL
+    pub failback_interval: Duration,
+    // Consecutive transport failures before requests fail fast for
+    // breaker_cool_down, zero never fails fast
+    pub breaker_threshold: u32,
+    pub breaker_cool_down: Duration,
+    pub max_concurrent: usize,
+    // Requests per second, unlimited when unset
+    pub max_rps: Option<f64>,
+    // Unit of transfer: file contents are cached, prefetched, downloaded in
+    // parallel and uploaded in pieces of this size
+    pub chunk_size: u64,
+    // Chunks of a single read fetched concurrently
+    pub max_parts_per_read: usize,
+    // Sent as a bearer token with every request
+    pub token: Option<Secret>,
+    // Send a W3C traceparent header with every request, in the same trace
+    // as the X-Request-Id
+    pub otel: bool,
+    // Connection options of the HTTP client
+    pub http: HttpConfig,
+}
+
+impl ClientConfig {
+    pub fn new(base_urls: Vec<String>) -> Self {
+        Self {
+            base_urls,
+            timeout: DEFAULT_TIMEOUT,
+            failover_threshold: DEFAULT_FAILOVER_THRESHOLD,
+            failback_interval: DEFAULT_FAILBACK_INTERVAL,
+            breaker_threshold: DEFAULT_BREAKER_THRESHOLD,
+            breaker_cool_down: DEFAULT_BREAKER_COOL_DOWN,
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+            max_rps: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_parts_per_read: DEFAULT_MAX_PARTS_PER_READ,
+            token: None,
+            otel: false,
+            http: HttpConfig::default(),
+        }
+    }
+
+    // Accepts both repeated `--server` values and comma-separated lists
+    pub fn parse_base_urls<S: AsRef<str>>(values: &[S]) -> Vec<String> {
+        values
+            .iter()
+            .flat_map(|value| value.as_ref().split(','))
+            .map(|url| url.trim().trim_end_matches('/'))
+            .filter(|url| !url.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    // Parses sizes like `65536`, `512K` or `4M` for `--chunk-size`
+    pub fn parse_chunk_size(value: &str) -> Result<u64> {
+        let size = parse_size(value).context("Invalid chunk size")?;
+        Self::validate_chunk_size(size)?;
+        Ok(size)
+    }
+
+    pub fn validate_chunk_size(size: u64) -> Result<()> {
+        if !size.is_power_of_two() || !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&size) {
+            anyhow::bail!(
+                "Chunk size must be a power of two between {} KiB and {} MiB, got {} bytes",
+                MIN_CHUNK_SIZE / 1024,
+                MAX_CHUNK_SIZE / (1024 * 1024),
+                size
+            );
+        }
+        Ok(())
+    }
+}
+
+// Parses byte counts like `65536`, `512K` or `4M`, units are powers of 1024
+pub fn parse_size(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match value[digits.len()..].to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        unit => anyhow::bail!("Unknown size unit '{}' in '{}'", unit, value),
+    };
+
+    digits
+        .parse::<u64>()
+        .with_context(|| format!("Invalid size '{}'", value))?
+        .checked_mul(multiplier)
+        .with_context(|| format!("Size '{}' is too large", value))
+}
+
 pub struct ApiClient {
-    base_url: String,
+    config: ClientConfig,
     client: Client,
+    // One per base URL, in the same order
+    endpoints: Vec<Endpoint>,
+    // Authorization header added to every request
+    auth: Option<HeaderValue>,
+    limiter: RequestLimiter,
+    // Concurrent identical reads share one request
+    listings: SingleFlight<Listing>,
+    reads: SingleFlight<FileData>,
+    active: AtomicUsize,
+    consecutive_failures: AtomicU32,
+    failed_over_at: Mutex<Option<Instant>>,
+    breaker: CircuitBreaker,
+    // Negotiated at mount, partial writes are also dropped once the server
+    // rejects one
+    capabilities: RwLock<ServerCapabilities>,
+    clock: ClockSkew,
+    stats: RequestStats,
 }
 
 impl ApiClient {
     pub fn new(base_url: String) -> Result<Self> {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(30))
-            .build()
-            .context("Failed to create HTTP client")?;
+        Self::with_config(ClientConfig::new(vec![base_url]))
+    }
 
-        Ok(Self { base_url, client })
+    pub fn with_config(config: ClientConfig) -> Result<Self> {
+        let client = config.http.client()?;
+        Self::with_http_client(config, client)
     }
 
-    pub fn list_directory(&self, path: &str) -> Result<Vec<FileEntry>> {
-        let url = format!("{}/list/{}", self.base_url, path.trim_start_matches('/'));
-        log::debug!("Listing directory: {}", url);
+    // Shares `client`, and with it the connection pool, with other clients,
+    // e.g. those of other mounts in the same process. Timeouts and the token
+    // still come from `config`, connection options from `client`.
+    pub fn with_http_client(config: ClientConfig, client: Client) -> Result<Self> {
+        if config.base_urls.is_empty() {
+            anyhow::bail!("At least one server URL is required");
+        }
+        ClientConfig::validate_chunk_size(config.chunk_size)?;
+        let endpoints = config
+            .base_urls
+            .iter()
+            .map(|url| Endpoint::new(url, &config.http))
+            .collect::<Result<_>>()?;
 
+        let auth = match &config.token {
+            Some(token) => {
+                let mut value = HeaderValue::from_str(&format!("Bearer {}", token.expose()))
+                    .context("Token contains characters not allowed in a header")?;
+                value.set_sensitive(true);
+                Some(value)
+            }
+            None => None,
+        };
+
+        let limiter = RequestLimiter::new(config.max_concurrent, config.max_rps);
+        let breaker = CircuitBreaker::new(config.breaker_threshold, config.breaker_cool_down);
+
+        Ok(Self {
+            config,
+            client,
+            endpoints,
+            auth,
+            limiter,
+            listings: SingleFlight::default(),
+            reads: SingleFlight::default(),
+            active: AtomicUsize::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            failed_over_at: Mutex::new(None),
+            breaker,
+            capabilities: RwLock::new(ServerCapabilities::default()),
+            clock: ClockSkew::default(),
+            stats: RequestStats::default(),
+        })
+    }
+
+    // `expected` as request headers. A server without ETags would fail every
+    // If-Match with 412, so ETag conditions are left out for it.
+    fn condition(&self, expected: &Expected, request: RequestBuilder) -> RequestBuilder {
+        match expected {
+            Expected::Version(Version::ETag(_)) if !self.capabilities.read().unwrap().etags => {
+                request
+            }
+            _ => expected.condition(request),
+        }
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.config.chunk_size
+    }
+
+    // Whether the last request to the active endpoint went through
+    pub fn is_healthy(&self) -> bool {
+        self.consecutive_failures.load(Ordering::Relaxed) == 0
+    }
+
+    pub fn breaker_state(&self) -> BreakerState {
+        self.breaker.state()
+    }
+
+    pub fn active_endpoint(&self) -> &str {
+        &self.config.base_urls[self.active.load(Ordering::Relaxed)]
+    }
+
+    pub fn in_flight_requests(&self) -> usize {
+        self.limiter.in_flight()
+    }
+
+    pub fn throttled_requests(&self) -> u64 {
+        self.limiter.throttled()
+    }
+
+    pub fn stats(&self) -> RequestStatsSnapshot {
+        let mut snapshot = self.stats.snapshot();
+        snapshot.breaker = self.breaker.state();
+        snapshot.breaker_opened = self.breaker.opened();
+        snapshot.breaker_rejected = self.breaker.rejected();
+        snapshot.capabilities = Some(self.capabilities());
+        snapshot.clock_skew = self.clock_skew();
+        snapshot
+    }
+
+    // Seconds the server's clock runs ahead of ours, negative when behind,
+    // None until a response carried a Date
+    pub fn clock_skew(&self) -> Option<i64> {
+        self.clock.offset()
+    }
+
+    // Sends a request through send_unguarded, failing with CircuitOpen
+    // without trying while the server is known to be down
+    fn send<F>(&self, replayable: bool, build: F) -> Result<Response>
+    where
+        F: Fn(&Client, &str) -> RequestBuilder,
+    {
+        self.breaker.allow()?;
+        Ok(self.send_unguarded(replayable, build)?)
+    }
+
+    // Sends a request to the active endpoint, failing over to the next one after
+    // `failover_threshold` consecutive transport failures. Requests that are not
+    // `replayable` are only resent if the connection was never established, since
+    // otherwise the first host may already have applied them. Health checks
+    // call this directly, they are what closes an open circuit.
+    fn send_unguarded<F>(&self, replayable: bool, build: F) -> reqwest::Result<Response>
+    where
+        F: Fn(&Client, &str) -> RequestBuilder,
+    {
+        self.maybe_fail_back();
+
+        let endpoints = self.config.base_urls.len();
+        let max_attempts = if endpoints > 1 {
+            endpoints * self.config.failover_threshold.max(1) as usize
+        } else {
+            1
+        };
+
+        // Retries keep the id, so the server can tell them apart from new requests
+        let trace_id = context::request_id();
+
+        let mut attempt = 1;
+        loop {
+            let index = self.active.load(Ordering::Relaxed);
+            let endpoint = &self.endpoints[index];
+            let client = endpoint.client(&self.client);
+            let mut request = build(client, &endpoint.base).build()?;
+            request.timeout_mut().get_or_insert(self.config.timeout);
+            let headers = request.headers_mut();
+            if let Some(auth) = &self.auth {
+                headers.insert(AUTHORIZATION, auth.clone());
+            }
+            if let Ok(id) = HeaderValue::from_str(&format!("{:032x}", trace_id)) {
+                headers.insert(REQUEST_ID, id);
+            }
+            if self.config.otel {
+                if let Ok(parent) = HeaderValue::from_str(&context::traceparent(trace_id)) {
+                    headers.insert(TRACEPARENT, parent);
+                }
+            }
+            let method = request.method().clone();
+            if matches!(method, Method::PUT | Method::PATCH) {
+                if let Some(mtime) = context::upload_mtime() {
+                    // Seconds since the epoch with their nanoseconds
+                    let mtime = Timestamp::from(mtime).to_string();
+                    if let Ok(mtime) = HeaderValue::from_str(&mtime) {
+                        request.headers_mut().insert(X_MTIME, mtime);
+                    }
+                }
+            }
+            let uploaded = request
+                .body()
+                .and_then(|body| body.as_bytes())
+                .map_or(0, |body| body.len());
+
+            match self.execute(client, request) {
+                Ok(response) => {
+                    self.stats.record(&method, Some(response.status()), uploaded);
+                    self.stats.protocol(response.version());
+                    self.clock.observe(response.headers().get(DATE));
+                    self.consecutive_failures.store(0, Ordering::Relaxed);
+                    self.breaker.record_success();
+                    return Ok(response);
+                }
+                Err(e) => {
+                    if e.is_builder() {
+                        return Err(e);
+                    }
+
+                    self.stats.record(&method, None, uploaded);
+                    self.record_failure(index);
+                    let may_replay = replayable || e.is_connect();
+                    if !may_replay || attempt >= max_attempts {
+                        return Err(e);
+                    }
+                    log::debug!("Request to {} failed, retrying: {}", self.config.base_urls[index], e);
+                    self.stats.retry();
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    // One attempt, a child span of the operation it is made for
+    #[cfg(feature = "tracing")]
+    fn execute(&self, client: &Client, request: Request) -> reqwest::Result<Response> {
+        let span = tracing::info_span!(
+            "http",
+            method = %request.method(),
+            url = %request.url(),
+            status = tracing::field::Empty,
+        );
+        let _entered = span.enter();
+        let result = client.execute(request);
+        if let Ok(response) = &result {
+            span.record("status", response.status().as_u16());
+        }
+        result
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    fn execute(&self, client: &Client, request: Request) -> reqwest::Result<Response> {
+        client.execute(request)
+    }
+
+    fn record_failure(&self, index: usize) {
+        self.breaker.record_failure();
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        let endpoints = self.config.base_urls.len();
+        if endpoints < 2 || failures < self.config.failover_threshold {
+            return;
+        }
+
+        let next = (index + 1) % endpoints;
+        if self
+            .active
+            .compare_exchange(index, next, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            *self.failed_over_at.lock().unwrap() = Some(Instant::now());
+            log::warn!(
+                "Endpoint {} failed {} times in a row, switching to {}",
+                self.config.base_urls[index],
+                failures,
+                self.config.base_urls[next]
+            );
+        }
+    }
+
+    fn maybe_fail_back(&self) {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        {
+            let mut failed_over_at = self.failed_over_at.lock().unwrap();
+            match *failed_over_at {
+                Some(at) if at.elapsed() >= self.config.failback_interval => {
+                    *failed_over_at = Some(Instant::now());
+                }
+                _ => return,
+            }
+        }
+
+        let primary = &self.config.base_urls[0];
+        let endpoint = &self.endpoints[0];
+        let probe = endpoint
+            .client(&self.client)
+            .get(format!("{}/health", endpoint.base))
+            .timeout(PROBE_TIMEOUT)
+            .send();
+
+        if matches!(probe, Ok(ref response) if response.status().is_success()) {
+            self.active.store(0, Ordering::Relaxed);
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            log::info!("Primary endpoint {} is reachable again, switching back", primary);
+        }
+    }
+
+    pub fn list_directory(&self, path: &str) -> Result<Listing> {
+        self.listings
+            .run(format!("list:{}", path), || self.fetch_listing(path))
+    }
+
+    // Lists a directory only if it changed since `version` was fetched
+    pub fn revalidate_listing(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Listing>> {
+        let path = remote_path(path)?;
+        log::debug!("Revalidating listing: /{}", path);
+
+        let _permit = self.limiter.acquire(self.config.timeout)?;
         let response = self
-            .client
-            .get(&url)
-            .send()
+            .send(true, |client, base| {
+                version.condition(
+                    client
+                        .get(url(base, "list", path))
+                        .header(ACCEPT, ndjson::ACCEPT_LISTING),
+                )
+            })
             .context("Failed to send list request")?;
 
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Conditional::NotModified);
+        }
+        self.parse_listing(response).map(Conditional::Modified)
+    }
+
+    // Asks whether a file changed since `version` was read, without downloading it
+    pub fn revalidate_file(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Option<Version>>> {
+        let path = remote_path(path)?;
+        log::debug!("Revalidating file: /{}", path);
+
+        let _permit = self.limiter.acquire(self.config.timeout)?;
+        let response = self
+            .send(true, |client, base| {
+                version.condition(client.head(url(base, "files", path)))
+            })
+            .context("Failed to send revalidation request")?;
+
+        match response.status() {
+            StatusCode::NOT_MODIFIED => Ok(Conditional::NotModified),
+            StatusCode::NOT_FOUND => Ok(Conditional::Modified(None)),
+            status if status.is_success() => {
+                Ok(Conditional::Modified(Version::from_response(&response)))
+            }
+            _ => Err(ServerError::from(&response).into()),
+        }
+    }
+
+    // The current version of a file, without downloading it
+    pub fn file_version(&self, path: &str) -> Result<Option<Version>> {
+        let path = remote_path(path)?;
+        log::debug!("Checking version of file: /{}", path);
+
+        let _permit = self.limiter.acquire(self.config.timeout)?;
+        let response = self
+            .send(true, |client, base| client.head(url(base, "files", path)))
+            .context("Failed to send stat request")?;
+
+        if !response.status().is_success() {
+            return Err(ServerError::from(&response).into());
+        }
+
+        Ok(Version::from_response(&response))
+    }
+
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        self.reads
+            .run(format!("read:{}", path), || self.fetch_file(path))
+            .map(|file| file.data)
+    }
+
+    // Reads up to `len` bytes starting at `offset`, returning fewer at end of file
+    pub fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        self.reads.run(format!("read:{}:{}+{}", path, offset, len), || {
+            if len > self.config.chunk_size && self.config.max_parts_per_read > 1 {
+                self.fetch_parts(path, offset, len)
+            } else {
+                self.fetch_range(path, offset, len)
+            }
+        })
+    }
+
+    // Reads a range spanning several chunks with one request per chunk, up to
+    // max_parts_per_read at a time, and joins them back in order. Each part is
+    // retried on its own before the read fails, and all of them go through the
+    // limiter, which bounds concurrency globally.
+    fn fetch_parts(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        let chunk_size = self.config.chunk_size;
+        let parts: Vec<(u64, u64)> = (offset..offset + len)
+            .step_by(chunk_size as usize)
+            .map(|start| (start, chunk_size.min(offset + len - start)))
+            .collect();
+        log::debug!("Reading {} bytes of {} in {} parts", len, path, parts.len());
+
+        let next = AtomicUsize::new(0);
+        let slots: Vec<Mutex<Option<Result<FileData>>>> =
+            parts.iter().map(|_| Mutex::new(None)).collect();
+        // The parts belong to the operation that asked for the read
+        let trace_id = context::request_id();
+        #[cfg(feature = "tracing")]
+        let span = tracing::Span::current();
+        let requests = AtomicU64::new(0);
+        thread::scope(|scope| {
+            for _ in 0..self.config.max_parts_per_read.min(parts.len()) {
+                scope.spawn(|| {
+                    #[cfg(feature = "tracing")]
+                    let _entered = span.enter();
+                    context::with_trace_id(trace_id, || loop {
+                        let n = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(&(start, len)) = parts.get(n) else {
+                            break;
+                        };
+                        *slots[n].lock().unwrap() = Some(self.fetch_part(path, start, len));
+                    });
+                    requests.fetch_add(take_thread_requests(), Ordering::Relaxed);
+                });
+            }
+        });
+        stats::count_thread_requests(requests.into_inner());
+        let results = slots.into_iter().map(|slot| {
+            slot.into_inner()
+                .unwrap()
+                .unwrap_or_else(|| Err(anyhow::anyhow!("Part was never read")))
+        });
+
+        let mut data = Vec::with_capacity(len as usize);
+        let mut version = None;
+        for (n, (result, &(_, part_len))) in results.zip(&parts).enumerate() {
+            let part = result?;
+            if n == 0 {
+                version = part.version;
+            } else if part.version != version {
+                anyhow::bail!("{} changed during a parallel read", path);
+            }
+
+            let complete = part.data.len() as u64 == part_len;
+            data.extend_from_slice(&part.data);
+            // Parts past the end of the file come back short or empty
+            if !complete {
+                break;
+            }
+        }
+
+        Ok(FileData { data, version })
+    }
+
+    fn fetch_part(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        let mut attempt = 1;
+        loop {
+            match self.fetch_range(path, offset, len) {
+                Ok(part) => return Ok(part),
+                Err(e) if attempt < PART_ATTEMPTS => {
+                    log::debug!("Retrying part {}+{} of {}: {:#}", offset, len, path, e);
+                    self.stats.retry();
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn fetch_listing(&self, path: &str) -> Result<Listing> {
+        let path = remote_path(path)?;
+        log::debug!("Listing directory: /{}", path);
+
+        let _permit = self.limiter.acquire(self.config.timeout)?;
+        let response = self.send_list(path)?;
+        self.parse_listing(response)
+    }
+
+    fn send_list(&self, path: &str) -> Result<Response> {
+        self.send(true, |client, base| {
+            client
+                .get(url(base, "list", path))
+                .header(ACCEPT, ndjson::ACCEPT_LISTING)
+        })
+        .context("Failed to send list request")
+    }
+
+    fn parse_listing(&self, response: Response) -> Result<Listing> {
+        let mut entries = Vec::new();
+        let version = self.read_listing(response, &mut |entry| entries.push(entry))?;
+        Ok(Listing { entries, version })
+    }
+
+    // Passes on the entries of a listing response, line by line as they
+    // arrive when the server streams them
+    fn read_listing(
+        &self,
+        response: Response,
+        entry: &mut dyn FnMut(FileEntry),
+    ) -> Result<Option<Version>> {
         if !response.status().is_success() {
-            anyhow::bail!("Server returned error: {}", response.status());
+            return Err(ServerError::from(&response).into());
+        }
+
+        let version = Version::from_response(&response);
+        if ndjson::is_ndjson(&response) {
+            let read = ndjson::read_entries(response, entry)?;
+            self.stats.downloaded(read);
+            return Ok(version);
         }
 
+        if let Some(len) = response.content_length() {
+            self.stats.downloaded(len as usize);
+        }
         let list_response: ListResponse = response
             .json()
             .context("Failed to parse list response")?;
+        list_response.entries.into_iter().for_each(entry);
+        Ok(version)
+    }
+
+    // Lists a directory passing each entry on as soon as it is parsed, so
+    // the first ones of a large directory are usable before the last arrive
+    pub fn list_directory_streamed(
+        &self,
+        path: &str,
+        entry: &mut dyn FnMut(FileEntry),
+    ) -> Result<Option<Version>> {
+        let path = remote_path(path)?;
+        log::debug!("Listing directory: /{}", path);
 
-        Ok(list_response.entries)
+        let _permit = self.limiter.acquire(self.config.timeout)?;
+        let response = self.send_list(path)?;
+        self.read_listing(response, entry)
     }
 
-    pub fn read_file(&self, path: &str) -> Result<Vec<u8>> {
-        let url = format!("{}/files/{}", self.base_url, path.trim_start_matches('/'));
-        log::debug!("Reading file: {}", url);
+    // A tar archive of the subtree at `path` from GET /archive, streamed
+    // rather than downloaded first. Entry paths are relative to `path`.
+    pub fn download_archive(&self, path: &str) -> Result<ArchiveReader<'_>> {
+        let path = remote_path(path)?;
+        log::debug!("Downloading archive of /{}", path);
+        if !self.capabilities.read().unwrap().archive {
+            return Err(unsupported("archives"));
+        }
 
+        let permit = self.limiter.acquire(self.config.timeout)?;
         let response = self
-            .client
-            .get(&url)
-            .send()
+            .send(true, |client, base| {
+                client
+                    .get(url(base, "archive", path))
+                    .timeout(ARCHIVE_TIMEOUT)
+            })
+            .context("Failed to send archive request")?;
+
+        if !response.status().is_success() {
+            return Err(ServerError::from(&response).into());
+        }
+
+        Ok(ArchiveReader {
+            response,
+            stats: &self.stats,
+            _permit: permit,
+        })
+    }
+
+    fn fetch_file(&self, path: &str) -> Result<FileData> {
+        let path = remote_path(path)?;
+        log::debug!("Reading file: /{}", path);
+
+        let _permit = self.limiter.acquire(self.config.timeout)?;
+        let response = self
+            .send(true, |client, base| client.get(url(base, "files", path)))
             .context("Failed to send read request")?;
 
         if !response.status().is_success() {
-            anyhow::bail!("Server returned error: {}", response.status());
+            return Err(ServerError::from(&response).into());
+        }
+
+        let version = Version::from_response(&response);
+        let bytes = response.bytes().context("Failed to read response")?;
+        self.stats.downloaded(bytes.len());
+        Ok(FileData {
+            data: bytes.to_vec(),
+            version,
+        })
+    }
+
+    fn fetch_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        if len == 0 {
+            return Ok(FileData {
+                data: Vec::new(),
+                version: None,
+            });
+        }
+
+        let path = remote_path(path)?;
+        log::debug!("Reading file: /{} ({} bytes at {})", path, len, offset);
+
+        let range = format!("bytes={}-{}", offset, last_byte(offset, len)?);
+        let _permit = self.limiter.acquire(self.config.timeout)?;
+        let response = self
+            .send(true, |client, base| {
+                client
+                    .get(url(base, "files", path))
+                    .header(RANGE, range.as_str())
+            })
+            .context("Failed to send read request")?;
+
+        let status = response.status();
+        let version = Version::from_response(&response);
+        if status == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(FileData {
+                data: Vec::new(),
+                version,
+            });
+        }
+        if !status.is_success() {
+            return Err(ServerError::from(&response).into());
         }
 
         let bytes = response.bytes().context("Failed to read response")?;
-        Ok(bytes.to_vec())
+        self.stats.downloaded(bytes.len());
+        let data = if status == StatusCode::PARTIAL_CONTENT {
+            bytes.to_vec()
+        } else {
+            // The server ignored the Range header and sent the whole file
+            let start = (offset as usize).min(bytes.len());
+            let end = (offset + len).min(bytes.len() as u64) as usize;
+            bytes[start..end].to_vec()
+        };
+
+        Ok(FileData { data, version })
     }
 
     pub fn write_file(&self, path: &str, data: &[u8]) -> Result<()> {
-        let url = format!("{}/files/{}", self.base_url, path.trim_start_matches('/'));
-        log::debug!("Writing file: {} ({} bytes)", url, data.len());
+        self.write_file_if(path, data, &Expected::Any).map(|_| ())
+    }
 
+    // Like write_file, failing with 412 unless the server has `expected`.
+    // Returns the version of the new contents, when the server reports it.
+    pub fn write_file_if(
+        &self,
+        path: &str,
+        data: &[u8],
+        expected: &Expected,
+    ) -> Result<Option<Version>> {
+        let path = remote_path(path)?;
+        log::debug!("Writing file: /{} ({} bytes)", path, data.len());
+
+        let _permit = self.limiter.acquire(self.config.timeout)?;
         let response = self
-            .client
-            .put(&url)
-            .body(data.to_vec())
-            .send()
+            .send(false, |client, base| {
+                let request = client.put(url(base, "files", path)).body(data.to_vec());
+                self.condition(expected, request)
+            })
             .context("Failed to send write request")?;
 
         if !response.status().is_success() {
-            anyhow::bail!("Server returned error: {}", response.status());
+            return Err(ServerError::from(&response).into());
         }
 
+        Ok(Version::from_response(&response))
+    }
+
+    // Uploads a whole file without holding it in memory. `open` is called for
+    // every attempt and must return a reader positioned at the start.
+    pub fn write_file_streamed<R, F>(&self, path: &str, len: u64, open: F) -> Result<()>
+    where
+        R: Read + Send + 'static,
+        F: Fn() -> R,
+    {
+        let path = remote_path(path)?;
+        log::debug!("Writing file: /{} ({} bytes, streamed)", path, len);
+
+        let _permit = self.limiter.acquire(self.config.timeout)?;
+        let response = self
+            .send(false, |client, base| {
+                client
+                    .put(url(base, "files", path))
+                    .body(Body::sized(open(), len))
+            })
+            .context("Failed to send write request")?;
+
+        if !response.status().is_success() {
+            return Err(ServerError::from(&response).into());
+        }
+
+        self.stats.uploaded(len as usize);
         Ok(())
     }
 
+    // Overwrites part of a file in place with a Content-Range PATCH. Returns
+    // false without writing anything when the server does not support it.
+    pub fn write_range(&self, path: &str, offset: u64, data: &[u8]) -> Result<bool> {
+        if data.is_empty() {
+            return Ok(true);
+        }
+        if !self.capabilities.read().unwrap().partial_writes {
+            return Ok(false);
+        }
+
+        let path = remote_path(path)?;
+        log::debug!("Writing file: /{} ({} bytes at {})", path, data.len(), offset);
+
+        let range = format!("bytes {}-{}/*", offset, last_byte(offset, data.len() as u64)?);
+        let _permit = self.limiter.acquire(self.config.timeout)?;
+        // Writing the same bytes at the same offset twice is harmless, so this may be replayed
+        let response = self
+            .send(true, |client, base| {
+                client
+                    .patch(url(base, "files", path))
+                    .header(CONTENT_RANGE, range.as_str())
+                    .body(data.to_vec())
+            })
+            .context("Failed to send write request")?;
+
+        match response.status() {
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => {
+                log::info!("Server does not support partial writes, uploading whole files");
+                self.capabilities.write().unwrap().partial_writes = false;
+                Ok(false)
+            }
+            status if status.is_success() => Ok(true),
+            _ => Err(ServerError::from(&response).into()),
+        }
+    }
+
+    // A PATCH without a body or range, the time going in X-Mtime as it does
+    // with uploads. Servers that refuse it cannot set one on its own, and
+    // uploading the whole file again just for its time is not worth it.
+    pub fn set_mtime(&self, path: &str, mtime: SystemTime) -> Result<()> {
+        let remote = remote_path(path)?;
+        log::debug!("Setting modification time of /{}", remote);
+
+        let _permit = self.limiter.acquire(self.config.timeout)?;
+        // The same time set twice is harmless, so this may be replayed
+        let response = with_upload_mtime(mtime, || {
+            self.send(true, |client, base| client.patch(url(base, "files", remote)))
+        })
+        .context("Failed to send mtime request")?;
+
+        match response.status() {
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => {
+                Err(anyhow::Error::new(FsError::Unsupported)
+                    .context(format!("The server cannot set the modification time of {}", path)))
+            }
+            status if status.is_success() => Ok(()),
+            _ => Err(ServerError::from(&response).into()),
+        }
+    }
+
     pub fn create_directory(&self, path: &str) -> Result<()> {
-        let url = format!("{}/mkdir/{}", self.base_url, path.trim_start_matches('/'));
-        log::debug!("Creating directory: {}", url);
+        let path = remote_path(path)?;
+        log::debug!("Creating directory: /{}", path);
 
+        // Exclusive, like mkdir(2): servers that honor the header refuse an
+        // existing directory or file instead of reporting success
+        let _permit = self.limiter.acquire(self.config.timeout)?;
         let response = self
-            .client
-            .post(&url)
-            .send()
+            .send(false, |client, base| {
+                Expected::Absent.condition(client.post(url(base, "mkdir", path)))
+            })
             .context("Failed to send mkdir request")?;
 
-        if !response.status().is_success() {
-            anyhow::bail!("Server returned error: {}", response.status());
+        match response.status() {
+            status if status.is_success() => {}
+            StatusCode::CONFLICT | StatusCode::PRECONDITION_FAILED => {
+                return Err(anyhow::Error::new(FsError::AlreadyExists)
+                    .context(format!("/{} already exists", path)));
+            }
+            _ => return Err(ServerError::from(&response).into()),
         }
 
         Ok(())
     }
 
     pub fn delete(&self, path: &str) -> Result<()> {
-        let url = format!("{}/files/{}", self.base_url, path.trim_start_matches('/'));
-        log::debug!("Deleting: {}", url);
+        self.delete_if(path, &Expected::Any)
+    }
+
+    // Like delete, failing with 412 unless the server has `expected`
+    pub fn delete_if(&self, path: &str, expected: &Expected) -> Result<()> {
+        let path = remote_path(path)?;
+        log::debug!("Deleting: /{}", path);
 
+        let _permit = self.limiter.acquire(self.config.timeout)?;
         let response = self
-            .client
-            .delete(&url)
-            .send()
+            .send(false, |client, base| {
+                self.condition(expected, client.delete(url(base, "files", path)))
+            })
             .context("Failed to send delete request")?;
 
         if !response.status().is_success() {
-            anyhow::bail!("Server returned error: {}", response.status());
+            return Err(ServerError::from(&response).into());
         }
 
         Ok(())
     }
 
     pub fn rename(&self, from: &str, to: &str) -> Result<()> {
-        let url = format!("{}/rename", self.base_url);
         log::debug!("Renaming: {} -> {}", from, to);
+        // Sent in the body as given, but checked like any path in a URL
+        remote_path(from)?;
+        remote_path(to)?;
+        if !self.capabilities.read().unwrap().rename {
+            return Err(unsupported("renames"));
+        }
 
         #[derive(Serialize)]
         struct RenameRequest {
             from: String,
             to: String,
+            // Replace what is at `to`, the filesystem checked it may
+            overwrite: bool,
         }
 
         let request_body = RenameRequest {
             from: from.to_string(),
             to: to.to_string(),
+            overwrite: true,
         };
 
+        let _permit = self.limiter.acquire(self.config.timeout)?;
         let response = self
-            .client
-            .post(&url)
-            .json(&request_body)
-            .send()
+            .send(false, |client, base| {
+                client.post(format!("{}/rename", base)).json(&request_body)
+            })
             .context("Failed to send rename request")?;
 
         if !response.status().is_success() {
-            anyhow::bail!("Server returned error: {}", response.status());
+            return Err(ServerError::from(&response).into());
         }
 
         Ok(())
     }
 
     pub fn health_check(&self) -> Result<()> {
-        let url = format!("{}/health", self.base_url);
-        let response = self.client.get(&url).send()?;
+        let _permit = self.limiter.acquire(self.config.timeout)?;
+        let response = self
+            .send_unguarded(true, |client, base| client.get(format!("{}/health", base)))?;
 
         if !response.status().is_success() {
             anyhow::bail!("Health check failed");
         }
 
+        log::info!("Using endpoint {}", self.active_endpoint());
+        Ok(())
+    }
+
+    // The health check followed by a listing of the root, each given at most
+    // `timeout`, to tell before mounting whether the server is usable. Error
+    // statuses come back as ServerError.
+    pub fn check_reachable(&self, timeout: Duration) -> Result<()> {
+        let response = self
+            .send_unguarded(false, |client, base| {
+                client.get(format!("{}/health", base)).timeout(timeout)
+            })
+            .context("Failed to send health check")?;
+        if !response.status().is_success() {
+            return Err(ServerError::from(&response).into());
+        }
+
+        let response = self
+            .send_unguarded(false, |client, base| {
+                client.get(url(base, "list", "")).timeout(timeout)
+            })
+            .context("Failed to list the root")?;
+        if !response.status().is_success() {
+            return Err(ServerError::from(&response).into());
+        }
+        Ok(())
+    }
+
+    // A health check that also fails while the server announces
+    // maintenance, which a 2xx may do with X-Maintenance too
+    pub fn check_writable(&self, timeout: Duration) -> Result<()> {
+        let response = self
+            .send_unguarded(false, |client, base| {
+                client.get(format!("{}/health", base)).timeout(timeout)
+            })
+            .context("Failed to send health check")?;
+        let error = ServerError::from(&response);
+        if !response.status().is_success() || error.is_maintenance() {
+            return Err(error.into());
+        }
         Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Names that break naive URL building
+    const NASTY: [&str; 10] = [
+        "with space.txt",
+        "100%.txt",
+        "#hash",
+        "what?.txt",
+        "a+b",
+        "semi;colon&amp",
+        "caffè.txt",
+        "日本語",
+        "tab\there",
+        "~tilde-under_score.",
+    ];
+
+    fn decode(encoded: &str) -> String {
+        let bytes = encoded.as_bytes();
+        let mut decoded = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            } else {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+        String::from_utf8(decoded).unwrap()
+    }
+
+    fn error_kind(error: anyhow::Error) -> FsError {
+        FsError::from_backend(&error)
+    }
+
+    // Answers one request with `status`, handing back its head and body
+    fn serve_once(status: u16) -> (String, std::thread::JoinHandle<String>) {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let served = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                head.push_str(&line);
+                if line.len() <= 2 {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            head.push_str(&String::from_utf8_lossy(&body));
+            let mut stream = reader.into_inner();
+            write!(stream, "HTTP/1.1 {} -\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            head
+        });
+        (url, served)
+    }
+
+    #[test]
+    fn requests_carry_the_trace_of_their_operation() {
+        let (url, served) = serve_once(200);
+        let mut config = ClientConfig::new(vec![url]);
+        config.otel = true;
+        let client = ApiClient::with_config(config).unwrap();
+        let trace_id = 0x1234_5678_9abc_def0_1234_5678_9abc_def0;
+        context::with_trace_id(trace_id, || {
+            client.set_mtime("/a", std::time::UNIX_EPOCH).unwrap();
+        });
+
+        let head = served.join().unwrap().to_lowercase();
+        let id = format!("{:032x}", trace_id);
+        assert!(head.contains(&format!("x-request-id: {}\r\n", id)), "{}", head);
+        assert!(head.contains(&format!("traceparent: 00-{}-", id)), "{}", head);
+    }
+
+    #[test]
+    fn skew_is_measured_from_the_date_of_responses() {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let served = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let mut stream = reader.into_inner();
+            write!(stream, "HTTP/1.1 200 -\r\nContent-Length: 0\r\n").unwrap();
+            write!(stream, "Date: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n").unwrap();
+        });
+        let client = ApiClient::new(url).unwrap();
+        assert_eq!(client.clock_skew(), None);
+        client.set_mtime("/a", SystemTime::now()).unwrap();
+        served.join().unwrap();
+
+        // Decades behind
+        let skew = client.clock_skew().unwrap();
+        assert!(skew < -900_000_000, "{}", skew);
+        assert_eq!(client.stats().clock_skew, Some(skew));
+    }
+
+    #[test]
+    fn mkdir_is_exclusive() {
+        let (url, served) = serve_once(201);
+        ApiClient::new(url).unwrap().create_directory("/docs/new").unwrap();
+        let head = served.join().unwrap().to_lowercase();
+        assert!(head.starts_with("post /mkdir/docs/new "), "{}", head);
+        assert!(head.contains("if-none-match: *\r\n"), "{}", head);
+
+        for status in [409, 412] {
+            let (url, served) = serve_once(status);
+            let client = ApiClient::new(url).unwrap();
+            let error = client.create_directory("/docs").unwrap_err();
+            assert_eq!(error_kind(error), FsError::AlreadyExists, "{}", status);
+            served.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn renames_replace_the_target() {
+        let (url, served) = serve_once(200);
+        ApiClient::new(url).unwrap().rename("/a.txt", "/b.txt").unwrap();
+        let request = served.join().unwrap();
+        assert!(request.to_lowercase().starts_with("post /rename "), "{}", request);
+        let body = &request[request.find("\r\n\r\n").unwrap() + 4..];
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["from"], "/a.txt");
+        assert_eq!(body["to"], "/b.txt");
+        assert_eq!(body["overwrite"], true);
+    }
+
+    #[test]
+    fn mtime_is_set_without_uploading_the_file() {
+        let (url, served) = serve_once(200);
+        let client = ApiClient::new(url).unwrap();
+        let mtime = std::time::UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        client.set_mtime("/docs/a.txt", mtime).unwrap();
+
+        let head = served.join().unwrap().to_lowercase();
+        assert!(head.starts_with("patch /files/docs/a.txt "), "{}", head);
+        assert!(head.contains("x-mtime: 1700000000.123456789\r\n"), "{}", head);
+        assert!(!head.contains("content-range"), "{}", head);
+    }
+
+    #[test]
+    fn mtime_the_server_cannot_set_is_unsupported() {
+        for status in [405, 501] {
+            let (url, served) = serve_once(status);
+            let client = ApiClient::new(url).unwrap();
+            let error = client.set_mtime("/a.txt", SystemTime::now()).unwrap_err();
+            assert_eq!(error_kind(error), FsError::Unsupported);
+            served.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn revalidation_asks_for_changes_since_the_cached_version() {
+        let version = Version::ETag("\"v1\"".to_string());
+        let (url, served) = serve_once(304);
+        let client = ApiClient::new(url).unwrap();
+        let answer = client.revalidate_file("/a.txt", &version).unwrap();
+        assert!(matches!(answer, Conditional::NotModified));
+
+        let head = served.join().unwrap().to_lowercase();
+        assert!(head.starts_with("head /files/a.txt "), "{}", head);
+        assert!(head.contains("if-none-match: \"v1\"\r\n"), "{}", head);
+
+        // Deleted since, or changed with no version to remember
+        for status in [404, 200] {
+            let (url, served) = serve_once(status);
+            let client = ApiClient::new(url).unwrap();
+            let answer = client.revalidate_file("/a.txt", &version).unwrap();
+            assert!(matches!(answer, Conditional::Modified(None)), "{}", status);
+            served.join().unwrap();
+        }
+    }
+
+    // Serves ranges of `body` to `requests` requests, one per connection, each
+    // with the ETag `etag` gives its first byte. Hands back the ranges asked for.
+    fn serve_ranges(
+        body: Vec<u8>,
+        requests: usize,
+        etag: fn(u64) -> &'static str,
+    ) -> (String, std::thread::JoinHandle<Vec<(u64, u64)>>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let served = std::thread::spawn(move || {
+            let mut ranges = Vec::new();
+            for stream in listener.incoming().take(requests) {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut range = None;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if let Some(value) = line.to_lowercase().strip_prefix("range: bytes=") {
+                        let (first, last) = value.trim().split_once('-').unwrap();
+                        range = Some((first.parse::<u64>().unwrap(), last.parse::<u64>().unwrap()));
+                    }
+                    line.clear();
+                }
+                let (first, last) = range.unwrap();
+                ranges.push((first, last));
+
+                let mut stream = reader.into_inner();
+                let len = body.len() as u64;
+                if first >= len {
+                    write!(stream, "HTTP/1.1 416 -\r\nContent-Length: 0\r\n").unwrap();
+                    write!(stream, "Connection: close\r\n\r\n").unwrap();
+                    continue;
+                }
+                let part = &body[first as usize..=last.min(len - 1) as usize];
+                write!(stream, "HTTP/1.1 206 -\r\nContent-Length: {}\r\n", part.len()).unwrap();
+                write!(stream, "ETag: {}\r\nConnection: close\r\n\r\n", etag(first)).unwrap();
+                stream.write_all(part).unwrap();
+            }
+            ranges.sort();
+            ranges
+        });
+        (url, served)
+    }
+
+    const CHUNK: u64 = MIN_CHUNK_SIZE;
+
+    // Two and a half chunks
+    fn chunks_body() -> Vec<u8> {
+        (0..CHUNK * 5 / 2).map(|n| (n % 251) as u8).collect()
+    }
+
+    fn parallel_client(url: String) -> ApiClient {
+        let mut config = ClientConfig::new(vec![url]);
+        config.chunk_size = CHUNK;
+        config.max_parts_per_read = 4;
+        ApiClient::with_config(config).unwrap()
+    }
+
+    #[test]
+    fn reads_over_a_chunk_are_split_into_parts_joined_in_order() {
+        let (url, served) = serve_ranges(chunks_body(), 4, |_| "\"v1\"");
+        let read = parallel_client(url).read_range("/a.bin", 0, 4 * CHUNK).unwrap();
+        assert_eq!(read.data, chunks_body());
+        assert_eq!(read.version, Some(Version::ETag("\"v1\"".to_string())));
+        // The last part is past the end of the file
+        let parts: Vec<_> = (0..4).map(|n| (n * CHUNK, (n + 1) * CHUNK - 1)).collect();
+        assert_eq!(served.join().unwrap(), parts);
+    }
+
+    #[test]
+    fn parts_of_different_versions_fail_the_read() {
+        let (url, served) = serve_ranges(chunks_body(), 3, |first| {
+            if first < CHUNK {
+                "\"v1\""
+            } else {
+                "\"v2\""
+            }
+        });
+        let error = parallel_client(url).read_range("/a.bin", 0, 3 * CHUNK).unwrap_err();
+        assert!(error.to_string().contains("changed during a parallel read"), "{}", error);
+        served.join().unwrap();
+    }
+
+    #[test]
+    fn remote_path_strips_outer_slashes() {
+        assert_eq!(remote_path("/").unwrap(), "");
+        assert_eq!(remote_path("").unwrap(), "");
+        assert_eq!(remote_path("/docs/a.txt").unwrap(), "docs/a.txt");
+        assert_eq!(remote_path("/docs/").unwrap(), "docs");
+        assert_eq!(remote_path("docs/a.txt").unwrap(), "docs/a.txt");
+    }
+
+    #[test]
+    fn remote_path_refuses_dot_and_empty_segments() {
+        for path in ["/a/../b", "/..", "/a/./b", "/.", "/a//b", "/a/b/.."] {
+            let error = remote_path(path).unwrap_err();
+            assert_eq!(error_kind(error), FsError::InvalidPath, "{}", path);
+        }
+        // Dots within a name are just part of it
+        assert_eq!(remote_path("/a/..b/.c").unwrap(), "a/..b/.c");
+    }
+
+    #[test]
+    fn encode_path_escapes_everything_but_unreserved() {
+        assert_eq!(encode_path("docs/a b.txt"), "docs/a%20b.txt");
+        assert_eq!(encode_path("100%/#?+"), "100%25/%23%3F%2B");
+        assert_eq!(encode_path("caffè"), "caff%C3%A8");
+        assert_eq!(encode_path("-._~az09AZ"), "-._~az09AZ");
+        assert_eq!(encode_path(""), "");
+    }
+
+    #[test]
+    fn encoded_names_round_trip() {
+        for name in NASTY {
+            let path = format!("dir/{}", name);
+            let encoded = encode_path(&path);
+            assert!(
+                encoded.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._~%/".contains(&b)),
+                "{}",
+                encoded
+            );
+            assert_eq!(encoded.matches('/').count(), 1, "{}", encoded);
+            assert_eq!(decode(&encoded), path);
+        }
+    }
+
+    #[test]
+    fn url_joins_route_and_encoded_path() {
+        assert_eq!(
+            url("http://server:8080", "files", "dir/a b"),
+            "http://server:8080/files/dir/a%20b"
+        );
+        assert_eq!(url("http://server", "list", ""), "http://server/list/");
+    }
+
+    #[test]
+    fn last_byte_is_inclusive() {
+        assert_eq!(last_byte(0, 1).unwrap(), 0);
+        assert_eq!(last_byte(100, 50).unwrap(), 149);
+        assert_eq!(last_byte(u64::MAX, 1).unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn last_byte_past_the_largest_offset_fails() {
+        let error = last_byte(u64::MAX, 2).unwrap_err();
+        assert_eq!(error_kind(error), FsError::InvalidArgument);
+        assert!(last_byte(u64::MAX - 9, 11).is_err());
+    }
+
+    #[test]
+    fn base_urls_from_repeated_and_comma_separated_values() {
+        let urls = ClientConfig::parse_base_urls(&[
+            "http://a:8080/, http://b:8080",
+            "http://c:8080",
+            " , ",
+        ]);
+        assert_eq!(urls, ["http://a:8080", "http://b:8080", "http://c:8080"]);
+        assert!(ClientConfig::parse_base_urls::<&str>(&[]).is_empty());
+    }
+
+    #[test]
+    fn sizes_with_units() {
+        assert_eq!(parse_size("65536").unwrap(), 65536);
+        assert_eq!(parse_size(" 512K ").unwrap(), 512 * 1024);
+        assert_eq!(parse_size("4m").unwrap(), 4 << 20);
+        assert_eq!(parse_size("1GiB").unwrap(), 1 << 30);
+        assert_eq!(parse_size("10B").unwrap(), 10);
+        assert_eq!(parse_size("0").unwrap(), 0);
+    }
+
+    #[test]
+    fn bad_sizes_are_refused() {
+        for value in ["", "K", "4T", "1.5M", "-1", "abc", "99999999999999999999G"] {
+            assert!(parse_size(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn chunk_sizes_are_powers_of_two_in_range() {
+        assert_eq!(ClientConfig::parse_chunk_size("1M").unwrap(), 1 << 20);
+        assert!(ClientConfig::parse_chunk_size("1000K").is_err());
+        assert!(ClientConfig::parse_chunk_size("1").is_err());
+    }
+
+    #[test]
+    fn client_takes_only_valid_chunk_sizes() {
+        let mut config = ClientConfig::new(vec!["http://127.0.0.1:1".to_string()]);
+        config.chunk_size = 3 << 20;
+        assert!(ApiClient::with_config(config.clone()).is_err());
+        config.chunk_size = MIN_CHUNK_SIZE;
+        assert_eq!(ApiClient::with_config(config).unwrap().chunk_size(), MIN_CHUNK_SIZE);
+    }
+}
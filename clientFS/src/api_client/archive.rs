@@ -0,0 +1,26 @@
+use reqwest::blocking::Response;
+use std::io::{self, Read};
+use std::time::Duration;
+
+use super::limiter::Permit;
+use super::stats::RequestStats;
+
+// The body of an archive arrives as fast as the server walks the subtree,
+// the request timeout would cut large ones off
+pub const ARCHIVE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+// A tar stream of a subtree, read as it arrives. It keeps its request slot
+// until dropped and counts what is read as downloaded.
+pub struct ArchiveReader<'a> {
+    pub(super) response: Response,
+    pub(super) stats: &'a RequestStats,
+    pub(super) _permit: Permit<'a>,
+}
+
+impl Read for ArchiveReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.response.read(buf)?;
+        self.stats.downloaded(read);
+        Ok(read)
+    }
+}
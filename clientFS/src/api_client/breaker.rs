@@ -0,0 +1,221 @@
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    // Requests go through
+    #[default]
+    Closed,
+    // Requests fail at once until the cool-down passes
+    Open,
+    // One request is let through to see whether the server is back
+    HalfOpen,
+}
+
+// The error of a request refused while the circuit is open
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitOpen {
+    pub retry_in: Duration,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Server unreachable, not trying again for {:.1}s",
+            self.retry_in.as_secs_f64()
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+struct Circuit {
+    state: BreakerState,
+    failures: u32,
+    // When the circuit opened, or the half-open probe was let through
+    since: Instant,
+}
+
+// Fails requests fast while the server is down, instead of having every one
+// wait out its timeout and retries. `threshold` consecutive transport
+// failures open the circuit for `cool_down`; the first request after it is
+// a probe, closing the circuit if it gets a response and opening it again
+// if not. A threshold of zero never opens it.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cool_down: Duration,
+    circuit: Mutex<Circuit>,
+    opened: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cool_down: Duration) -> Self {
+        Self {
+            threshold,
+            cool_down,
+            circuit: Mutex::new(Circuit {
+                state: BreakerState::Closed,
+                failures: 0,
+                since: Instant::now(),
+            }),
+            opened: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    // Whether a request may be sent now. A probe that never reports back
+    // does not hold the circuit half-open past another cool-down.
+    pub fn allow(&self) -> Result<(), CircuitOpen> {
+        let mut circuit = self.circuit.lock().unwrap();
+        let waited = circuit.since.elapsed();
+        match circuit.state {
+            BreakerState::Closed => return Ok(()),
+            BreakerState::Open | BreakerState::HalfOpen if waited >= self.cool_down => {
+                log::debug!("Circuit half-open, probing the server");
+                circuit.state = BreakerState::HalfOpen;
+                circuit.since = Instant::now();
+                return Ok(());
+            }
+            BreakerState::Open | BreakerState::HalfOpen => {}
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        Err(CircuitOpen {
+            retry_in: self.cool_down - waited,
+        })
+    }
+
+    pub fn record_success(&self) {
+        let mut circuit = self.circuit.lock().unwrap();
+        if circuit.state != BreakerState::Closed {
+            log::info!("Server answering again, circuit closed");
+        }
+        circuit.state = BreakerState::Closed;
+        circuit.failures = 0;
+    }
+
+    pub fn record_failure(&self) {
+        let mut circuit = self.circuit.lock().unwrap();
+        circuit.failures = circuit.failures.saturating_add(1);
+        let open = match circuit.state {
+            BreakerState::Closed => self.threshold > 0 && circuit.failures >= self.threshold,
+            // The probe failed, or a request sent before the circuit opened
+            BreakerState::HalfOpen | BreakerState::Open => true,
+        };
+        if !open {
+            return;
+        }
+        if circuit.state == BreakerState::Closed {
+            self.opened.fetch_add(1, Ordering::Relaxed);
+            log::warn!(
+                "{} requests in a row failed, failing fast for {:.1}s",
+                circuit.failures,
+                self.cool_down.as_secs_f64()
+            );
+        }
+        circuit.state = BreakerState::Open;
+        circuit.since = Instant::now();
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.circuit.lock().unwrap().state
+    }
+
+    // Times the circuit opened, and requests refused while it was open
+    pub fn opened(&self) -> u64 {
+        self.opened.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const COOL_DOWN: Duration = Duration::from_millis(50);
+
+    // Applies a script of request outcomes, true for a response
+    fn run(breaker: &CircuitBreaker, outcomes: &[bool]) {
+        for &answered in outcomes {
+            if answered {
+                breaker.record_success();
+            } else {
+                breaker.record_failure();
+            }
+        }
+    }
+
+    #[test]
+    fn opens_after_threshold_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, COOL_DOWN);
+        run(&breaker, &[false, false, true, false, false]);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow().is_ok());
+
+        run(&breaker, &[false]);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(breaker.opened(), 1);
+        let refused = breaker.allow().unwrap_err();
+        assert!(refused.retry_in <= COOL_DOWN);
+        assert_eq!(breaker.rejected(), 1);
+    }
+
+    #[test]
+    fn probe_after_cool_down_closes_on_success() {
+        let breaker = CircuitBreaker::new(1, COOL_DOWN);
+        run(&breaker, &[false]);
+        assert!(breaker.allow().is_err());
+
+        thread::sleep(COOL_DOWN);
+        assert!(breaker.allow().is_ok());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        // Only the probe goes through
+        assert!(breaker.allow().is_err());
+
+        run(&breaker, &[true]);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow().is_ok());
+    }
+
+    #[test]
+    fn failed_probe_opens_again() {
+        let breaker = CircuitBreaker::new(2, COOL_DOWN);
+        run(&breaker, &[false, false]);
+        thread::sleep(COOL_DOWN);
+        assert!(breaker.allow().is_ok());
+
+        run(&breaker, &[false]);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.allow().is_err());
+        // Still one opening, the probe only kept it open
+        assert_eq!(breaker.opened(), 1);
+    }
+
+    #[test]
+    fn lost_probe_does_not_hold_it_half_open() {
+        let breaker = CircuitBreaker::new(1, COOL_DOWN);
+        run(&breaker, &[false]);
+        thread::sleep(COOL_DOWN);
+        assert!(breaker.allow().is_ok());
+        thread::sleep(COOL_DOWN);
+        assert!(breaker.allow().is_ok());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+    }
+
+    #[test]
+    fn zero_threshold_never_opens() {
+        let breaker = CircuitBreaker::new(0, COOL_DOWN);
+        run(&breaker, &[false; 100]);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow().is_ok());
+    }
+}
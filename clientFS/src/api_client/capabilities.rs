@@ -0,0 +1,364 @@
+use anyhow::{Context, Result};
+use reqwest::header::{ALLOW, ETAG};
+use reqwest::StatusCode;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+use super::{url, ApiClient, ServerError};
+
+// Where the capabilities of a server were learned
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CapabilitySource {
+    // Not negotiated yet, each feature is found missing when a request fails
+    #[default]
+    Assumed,
+    // The server's GET /capabilities
+    Advertised,
+    // OPTIONS requests to the optional routes, for servers without it
+    Probed,
+}
+
+// Optional features of the server the client adapts to rather than finding
+// them missing one failed request at a time. Features the server does not
+// say anything about are assumed present.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ServerCapabilities {
+    pub source: CapabilitySource,
+    // Content-Range PATCH, without it every write uploads the whole file
+    pub partial_writes: bool,
+    // POST /rename, without it renames fail with ENOTSUP
+    pub rename: bool,
+    // Without ETags, changes are not made conditional with If-Match
+    pub etags: bool,
+    // Change notifications as server-sent events on /events
+    pub events: bool,
+    // The same over a WebSocket on /ws
+    pub websocket: bool,
+    // Tar streams of subtrees on /archive, for preloading
+    pub archive: bool,
+}
+
+impl Default for ServerCapabilities {
+    fn default() -> Self {
+        Self {
+            source: CapabilitySource::Assumed,
+            partial_writes: true,
+            rename: true,
+            etags: true,
+            events: true,
+            websocket: true,
+            archive: true,
+        }
+    }
+}
+
+impl ServerCapabilities {
+    // From the document served on /capabilities, which maps feature names
+    // to false, true or an object of parameters, meaning supported
+    fn advertised(document: &HashMap<String, Value>) -> Self {
+        let supported = |name: &str| {
+            document
+                .get(name)
+                .is_none_or(|value| !matches!(value, Value::Bool(false) | Value::Null))
+        };
+        Self {
+            source: CapabilitySource::Advertised,
+            partial_writes: supported("partial_writes"),
+            rename: supported("rename"),
+            etags: supported("etags"),
+            events: supported("events"),
+            websocket: supported("websocket"),
+            archive: supported("archive"),
+        }
+    }
+
+    // Each feature by name, for stats and reports
+    pub fn features(&self) -> [(&'static str, bool); 6] {
+        [
+            ("partial_writes", self.partial_writes),
+            ("rename", self.rename),
+            ("etags", self.etags),
+            ("events", self.events),
+            ("websocket", self.websocket),
+            ("archive", self.archive),
+        ]
+    }
+}
+
+impl fmt::Display for ServerCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match self.source {
+            CapabilitySource::Assumed => "assumed",
+            CapabilitySource::Advertised => "advertised",
+            CapabilitySource::Probed => "probed",
+        };
+        let names = |supported: bool| {
+            let names: Vec<&str> = self
+                .features()
+                .into_iter()
+                .filter(|(_, has)| *has == supported)
+                .map(|(name, _)| name)
+                .collect();
+            if names.is_empty() {
+                "none".to_string()
+            } else {
+                names.join(" ")
+            }
+        };
+        write!(f, "{} ({}), missing {}", names(true), source, names(false))
+    }
+}
+
+// Whether `methods`, from an Allow header, has `method`. A server that does
+// not say is taken to have it.
+fn allows(methods: &[String], method: &str) -> bool {
+    methods.is_empty() || methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+}
+
+impl ApiClient {
+    // What the server was found to support, everything until negotiated
+    pub fn capabilities(&self) -> ServerCapabilities {
+        self.capabilities.read().unwrap().clone()
+    }
+
+    // Asks the server for its optional features, on GET /capabilities or,
+    // when it has none, with OPTIONS requests to the routes, and adapts to
+    // them from then on. Called at mount and again after reconnecting,
+    // since the server may have changed meanwhile.
+    pub fn negotiate_capabilities(&self) -> Result<ServerCapabilities> {
+        let capabilities = match self.fetch_capabilities()? {
+            Some(capabilities) => capabilities,
+            None => self.probe_capabilities()?,
+        };
+        log::info!("Server capabilities: {}", capabilities);
+        *self.capabilities.write().unwrap() = capabilities.clone();
+        Ok(capabilities)
+    }
+
+    // None when the server has no /capabilities
+    fn fetch_capabilities(&self) -> Result<Option<ServerCapabilities>> {
+        let _permit = self.limiter.acquire(self.config.timeout)?;
+        let response = self
+            .send(true, |client, base| {
+                client.get(format!("{}/capabilities", base))
+            })
+            .context("Failed to send capabilities request")?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND
+            | StatusCode::METHOD_NOT_ALLOWED
+            | StatusCode::NOT_IMPLEMENTED => Ok(None),
+            status if !status.is_success() => Err(ServerError::from(&response).into()),
+            _ => {
+                let document: HashMap<String, Value> =
+                    response.json().context("Failed to parse capabilities")?;
+                Ok(Some(ServerCapabilities::advertised(&document)))
+            }
+        }
+    }
+
+    fn probe_capabilities(&self) -> Result<ServerCapabilities> {
+        // Any path of the route will do, a missing file says nothing
+        let files = self.allowed_methods(|base| url(base, "files", ""))?;
+        let rename = self.allowed_methods(|base| format!("{}/rename", base))?;
+        let events = self.allowed_methods(|base| format!("{}/events", base))?;
+        let websocket = self.allowed_methods(|base| format!("{}/ws", base))?;
+        let archive = self.allowed_methods(|base| url(base, "archive", ""))?;
+
+        let etags = {
+            let _permit = self.limiter.acquire(self.config.timeout)?;
+            let response = self
+                .send(true, |client, base| client.get(url(base, "list", "")))
+                .context("Failed to send list request")?;
+            if !response.status().is_success() {
+                return Err(ServerError::from(&response).into());
+            }
+            response.headers().contains_key(ETAG)
+        };
+
+        Ok(ServerCapabilities {
+            source: CapabilitySource::Probed,
+            partial_writes: files.is_none_or(|methods| allows(&methods, "PATCH")),
+            rename: rename.is_some_and(|methods| allows(&methods, "POST")),
+            etags,
+            events: events.is_some_and(|methods| allows(&methods, "GET")),
+            websocket: websocket.is_some_and(|methods| allows(&methods, "GET")),
+            archive: archive.is_some_and(|methods| allows(&methods, "GET")),
+        })
+    }
+
+    // The methods OPTIONS on a route reports in its Allow header, empty when
+    // it reports none. None when the route is not found.
+    fn allowed_methods<F>(&self, route: F) -> Result<Option<Vec<String>>>
+    where
+        F: Fn(&str) -> String,
+    {
+        let _permit = self.limiter.acquire(self.config.timeout)?;
+        let response = self
+            .send(true, |client, base| {
+                client.request(reqwest::Method::OPTIONS, route(base))
+            })
+            .context("Failed to send OPTIONS request")?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let methods = response
+            .headers()
+            .get_all(ALLOW)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|method| method.trim().to_string())
+            .filter(|method| !method.is_empty())
+            .collect();
+        Ok(Some(methods))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::FsError;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    type Route = fn(&str, &str) -> (u16, &'static str, &'static str);
+
+    // A server answering every request with the status, headers and body of
+    // `route(method, path)`, and the "METHOD path" of the requests it got
+    fn serve(route: Route) -> (ApiClient, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut request = String::new();
+                let mut line = String::new();
+                let _ = reader.read_line(&mut request);
+                while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                    line.clear();
+                }
+                let mut parts = request.split_whitespace();
+                let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+                seen.lock().unwrap().push(format!("{} {}", method, path));
+                let (status, headers, body) = route(method, path);
+                let _ = write!(
+                    reader.into_inner(),
+                    "HTTP/1.1 {} -\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    headers,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        (ApiClient::new(url).unwrap(), requests)
+    }
+
+    #[test]
+    fn unmentioned_features_are_assumed() {
+        let document = serde_json::from_str(
+            r#"{"partial_writes": false, "websocket": null, "events": {"max": 4},
+                "rename": true, "unknown": false}"#,
+        )
+        .unwrap();
+        let advertised = ServerCapabilities::advertised(&document);
+        assert_eq!(
+            advertised,
+            ServerCapabilities {
+                source: CapabilitySource::Advertised,
+                partial_writes: false,
+                websocket: false,
+                ..ServerCapabilities::default()
+            }
+        );
+        assert_eq!(
+            advertised.to_string(),
+            "rename etags events archive (advertised), missing partial_writes websocket"
+        );
+        assert_eq!(
+            ServerCapabilities::default().to_string(),
+            "partial_writes rename etags events websocket archive (assumed), missing none"
+        );
+    }
+
+    #[test]
+    fn allow_headers_are_matched_loosely() {
+        let methods = ["get".to_string(), "PUT".to_string()];
+        assert!(allows(&methods, "GET"));
+        assert!(!allows(&methods, "PATCH"));
+        assert!(allows(&[], "PATCH"));
+    }
+
+    #[test]
+    fn advertised_capabilities_are_adapted_to() {
+        let (client, requests) = serve(|method, path| match (method, path) {
+            ("GET", "/capabilities") => (200, "", r#"{"rename": false, "archive": false}"#),
+            _ => (500, "", ""),
+        });
+        assert_eq!(client.capabilities().source, CapabilitySource::Assumed);
+        let capabilities = client.negotiate_capabilities().unwrap();
+        assert!(!capabilities.rename && !capabilities.archive && capabilities.events);
+        assert_eq!(client.capabilities(), capabilities);
+
+        // Refused without asking the server
+        let error = client.rename("/a", "/b").unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::Unsupported);
+        assert!(client.download_archive("/").is_err());
+        assert_eq!(*requests.lock().unwrap(), ["GET /capabilities"]);
+    }
+
+    #[test]
+    fn servers_without_the_document_are_probed() {
+        let (client, requests) = serve(|method, path| match (method, path) {
+            ("GET", "/capabilities") => (404, "", ""),
+            ("OPTIONS", "/files/") => (204, "Allow: GET, PUT\r\nAllow: DELETE\r\n", ""),
+            ("OPTIONS", "/events") => (204, "Allow: GET\r\n", ""),
+            ("OPTIONS", "/ws") => (204, "Allow: POST\r\n", ""),
+            // Says nothing, so has everything
+            ("OPTIONS", "/archive/") => (200, "", ""),
+            ("GET", "/list/") => (200, "ETag: \"l1\"\r\n", r#"{"entries": []}"#),
+            _ => (404, "", ""),
+        });
+        let capabilities = client.negotiate_capabilities().unwrap();
+        assert_eq!(
+            capabilities,
+            ServerCapabilities {
+                source: CapabilitySource::Probed,
+                partial_writes: false,
+                rename: false,
+                etags: true,
+                events: true,
+                websocket: false,
+                archive: true,
+            }
+        );
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                "GET /capabilities",
+                "OPTIONS /files/",
+                "OPTIONS /rename",
+                "OPTIONS /events",
+                "OPTIONS /ws",
+                "OPTIONS /archive/",
+                "GET /list/",
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_negotiation_keeps_what_was_known() {
+        let (client, _) = serve(|_, _| (403, "", ""));
+        let error = client.negotiate_capabilities().unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::PermissionDenied);
+        assert_eq!(client.capabilities(), ServerCapabilities::default());
+    }
+}
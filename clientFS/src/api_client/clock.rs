@@ -0,0 +1,99 @@
+use reqwest::header::HeaderValue;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dates::parse_http_date;
+
+// Date headers have whole seconds and arrive a little after they were
+// stamped, differences up to this are not skew
+const SKEW_TOLERANCE: i64 = 2;
+
+const UNMEASURED: i64 = i64::MIN;
+
+// How far the server's clock runs ahead of ours, in seconds, measured from
+// the Date header of its responses. The estimate only moves once it is off
+// by more than the tolerance, so times corrected by it stay put.
+pub struct ClockSkew {
+    offset: AtomicI64,
+    warned: AtomicBool,
+}
+
+impl Default for ClockSkew {
+    fn default() -> Self {
+        Self {
+            offset: AtomicI64::new(UNMEASURED),
+            warned: AtomicBool::new(false),
+        }
+    }
+}
+
+impl ClockSkew {
+    pub fn observe(&self, date: Option<&HeaderValue>) {
+        let Some(server) = date
+            .and_then(|date| date.to_str().ok())
+            .and_then(parse_http_date)
+        else {
+            return;
+        };
+        let local = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        let measured = (server - local).round() as i64;
+
+        let previous = self.offset.load(Ordering::Relaxed);
+        if previous != UNMEASURED && (measured - previous).abs() <= SKEW_TOLERANCE {
+            return;
+        }
+        self.offset.store(measured, Ordering::Relaxed);
+        if measured.abs() > SKEW_TOLERANCE && !self.warned.swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "Server clock is {}s {} ours, future times it reports are shifted back",
+                measured.abs(),
+                if measured > 0 { "ahead of" } else { "behind" }
+            );
+        }
+    }
+
+    // None until a response carried a Date
+    pub fn offset(&self) -> Option<i64> {
+        Some(self.offset.load(Ordering::Relaxed)).filter(|offset| *offset != UNMEASURED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observe(clock: &ClockSkew, date: &'static str) {
+        clock.observe(Some(&HeaderValue::from_static(date)));
+    }
+
+    #[test]
+    fn skew_is_measured_from_dates() {
+        let clock = ClockSkew::default();
+        clock.observe(None);
+        observe(&clock, "not a date");
+        assert_eq!(clock.offset(), None);
+
+        observe(&clock, "Sun, 06 Nov 1994 08:49:37 GMT");
+        let behind = clock.offset().unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        assert!((behind - (784_111_777 - now)).abs() <= 1, "{}", behind);
+        assert!(clock.warned.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn skew_only_moves_past_the_tolerance() {
+        let clock = ClockSkew::default();
+        observe(&clock, "Sun, 06 Nov 1994 08:49:37 GMT");
+        let first = clock.offset().unwrap();
+        // A second later is the same skew, seen through a whole-second Date
+        observe(&clock, "Sun, 06 Nov 1994 08:49:38 GMT");
+        assert_eq!(clock.offset(), Some(first));
+        observe(&clock, "Sun, 06 Nov 1994 08:49:47 GMT");
+        assert!((clock.offset().unwrap() - (first + 10)).abs() <= 1);
+    }
+}
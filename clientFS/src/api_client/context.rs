@@ -0,0 +1,141 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+static IDS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // Trace id of the operation running on this thread
+    static CURRENT: Cell<Option<u128>> = const { Cell::new(None) };
+    // Modification time uploads sent from this thread ask the server to keep
+    static UPLOAD_MTIME: Cell<Option<SystemTime>> = const { Cell::new(None) };
+    // Process, or thread, whose system call the operation on this thread serves
+    static CALLER: Cell<u32> = const { Cell::new(0) };
+}
+
+// Ids unlikely to repeat across processes, without a random number crate
+fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(IDS.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+pub fn new_trace_id() -> u128 {
+    (((random_u64() as u128) << 64) | random_u64() as u128).max(1)
+}
+
+// Runs `f` with requests it sends carrying `trace_id`
+pub fn with_trace_id<R>(trace_id: u128, f: impl FnOnce() -> R) -> R {
+    let outer = CURRENT.replace(Some(trace_id));
+    let result = f();
+    CURRENT.set(outer);
+    result
+}
+
+// Runs `f` with the uploads it sends carrying `mtime`, so the server keeps
+// it rather than stamping the time they arrive
+pub fn with_upload_mtime<R>(mtime: SystemTime, f: impl FnOnce() -> R) -> R {
+    let outer = UPLOAD_MTIME.replace(Some(mtime));
+    let result = f();
+    UPLOAD_MTIME.set(outer);
+    result
+}
+
+// Modification time uploads from this thread ask to keep, see with_upload_mtime
+pub fn upload_mtime() -> Option<SystemTime> {
+    UPLOAD_MTIME.get()
+}
+
+// Runs `f` on behalf of the kernel request from `pid`, see caller_interrupted
+pub fn with_caller<R>(pid: u32, f: impl FnOnce() -> R) -> R {
+    let outer = CALLER.replace(pid);
+    let result = f();
+    CALLER.set(outer);
+    result
+}
+
+// Whether the caller of the operation on this thread gave up on it. fuser
+// answers FUSE_INTERRUPT itself, so what the kernel sends it for is looked
+// at directly: a signal pending that the caller neither blocks nor ignores,
+// or the caller having exited.
+pub fn caller_interrupted() -> bool {
+    let pid = CALLER.get();
+    if pid == 0 {
+        return false;
+    }
+    let Ok(status) = fs::read_to_string(format!("/proc/{}/status", pid)) else {
+        return true;
+    };
+    let mask = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| u64::from_str_radix(value.trim(), 16).ok())
+            .unwrap_or(0)
+    };
+    let zombie = status.lines().any(|line| line.starts_with("State:") && line.contains('Z'));
+    let pending = (mask("SigPnd") | mask("ShdPnd")) & !(mask("SigBlk") | mask("SigIgn"));
+    zombie || pending != 0
+}
+
+// X-Request-Id of a request, the current operation's trace id or a fresh
+// one for requests made outside of any operation
+pub fn request_id() -> u128 {
+    CURRENT.get().unwrap_or_else(new_trace_id)
+}
+
+// W3C traceparent of one request within trace `trace_id`, sampled
+pub fn traceparent(trace_id: u128) -> String {
+    format!("00-{:032x}-{:016x}-01", trace_id, random_u64().max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_ids_nest_and_are_restored() {
+        let outer = new_trace_id();
+        let inner = new_trace_id();
+        assert_ne!(outer, inner);
+        with_trace_id(outer, || {
+            assert_eq!(request_id(), outer);
+            with_trace_id(inner, || assert_eq!(request_id(), inner));
+            assert_eq!(request_id(), outer);
+        });
+        // Outside of any operation every request gets its own
+        assert_ne!(request_id(), request_id());
+    }
+
+    #[test]
+    fn traceparents_are_w3c_and_sampled() {
+        let trace_id = 0xabc;
+        let parent = traceparent(trace_id);
+        let fields: Vec<&str> = parent.split('-').collect();
+        assert_eq!(fields.len(), 4);
+        assert_eq!(fields[0], "00");
+        assert_eq!(fields[1], format!("{:032x}", trace_id));
+        assert_eq!(fields[2].len(), 16);
+        assert_ne!(fields[2], "0000000000000000");
+        assert_eq!(fields[3], "01");
+    }
+
+    #[test]
+    fn upload_mtime_is_set_for_the_closure_only() {
+        let mtime = SystemTime::UNIX_EPOCH;
+        assert_eq!(upload_mtime(), None);
+        with_upload_mtime(mtime, || assert_eq!(upload_mtime(), Some(mtime)));
+        assert_eq!(upload_mtime(), None);
+    }
+
+    #[test]
+    fn interruption_is_read_from_the_caller() {
+        assert!(!caller_interrupted());
+        with_caller(std::process::id(), || assert!(!caller_interrupted()));
+        // A caller that is gone gave up on the operation
+        with_caller(u32::MAX, || assert!(caller_interrupted()));
+    }
+}
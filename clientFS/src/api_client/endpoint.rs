@@ -0,0 +1,150 @@
+use anyhow::{Context, Result};
+use reqwest::blocking::Client;
+use std::path::PathBuf;
+
+use super::HttpConfig;
+
+// Requests over a Unix socket are addressed to this host
+const UNIX_BASE: &str = "http://localhost";
+
+// A server URL as requests are built on it. `unix:///run/storage.sock/api`
+// is the server listening on /run/storage.sock, with /api in front of every
+// path: it gets a client of its own, connected to the socket, and requests
+// go to http://localhost/api. Other URLs are used as they are, with the
+// shared client.
+pub(crate) struct Endpoint {
+    pub base: String,
+    client: Option<Client>,
+}
+
+impl Endpoint {
+    pub fn new(url: &str, http: &HttpConfig) -> Result<Self> {
+        let Some((socket, prefix)) = unix_socket(url)? else {
+            return Ok(Self {
+                base: url.to_string(),
+                client: None,
+            });
+        };
+        let client = http
+            .builder()?
+            .unix_socket(socket.as_path())
+            .build()
+            .with_context(|| format!("Failed to create HTTP client for {}", socket.display()))?;
+        Ok(Self {
+            base: format!("{}{}", UNIX_BASE, prefix),
+            client: Some(client),
+        })
+    }
+
+    // What requests to this endpoint are sent with
+    pub fn client<'a>(&'a self, shared: &'a Client) -> &'a Client {
+        self.client.as_ref().unwrap_or(shared)
+    }
+}
+
+// The socket and path prefix of a `unix://` URL. The socket path ends with
+// the first segment named `*.sock`, or is all of it when none is.
+fn unix_socket(url: &str) -> Result<Option<(PathBuf, String)>> {
+    let Some((scheme, path)) = url.split_once("://") else {
+        return Ok(None);
+    };
+    match scheme {
+        "unix" | "http+unix" => {}
+        "https+unix" | "unixs" => {
+            anyhow::bail!(
+                "TLS is not supported over Unix sockets, use unix://{}",
+                path
+            )
+        }
+        _ => return Ok(None),
+    }
+    anyhow::ensure!(
+        path.starts_with('/'),
+        "Expected an absolute socket path like unix:///run/storage.sock, got {}",
+        url
+    );
+
+    let split = path
+        .match_indices('/')
+        .map(|(at, _)| at)
+        .chain([path.len()])
+        .skip(1)
+        .find(|&end| path[..end].ends_with(".sock"))
+        .unwrap_or(path.len());
+    let (socket, prefix) = path.split_at(split);
+    Ok(Some((
+        PathBuf::from(socket),
+        prefix.trim_end_matches('/').to_string(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_client::{ApiClient, ClientConfig};
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixListener;
+
+    fn socket(url: &str) -> Option<(String, String)> {
+        unix_socket(url)
+            .unwrap()
+            .map(|(socket, prefix)| (socket.display().to_string(), prefix))
+    }
+
+    #[test]
+    fn socket_urls_split_at_the_socket() {
+        let split = |socket: &str, prefix: &str| Some((socket.to_string(), prefix.to_string()));
+        assert_eq!(socket("unix:///run/storage.sock"), split("/run/storage.sock", ""));
+        assert_eq!(socket("unix:///run/storage.sock/api/"), split("/run/storage.sock", "/api"));
+        assert_eq!(
+            socket("http+unix:///run/a.sock.d/s.sock/v1"),
+            split("/run/a.sock.d/s.sock", "/v1")
+        );
+        // Without a `*.sock` segment all of it is the socket
+        assert_eq!(socket("unix:///run/storage"), split("/run/storage", ""));
+        assert_eq!(socket("http://localhost:8080/api"), None);
+        assert_eq!(socket("localhost"), None);
+    }
+
+    #[test]
+    fn bad_socket_urls_are_refused() {
+        let error = unix_socket("https+unix:///run/s.sock").unwrap_err().to_string();
+        assert_eq!(error, "TLS is not supported over Unix sockets, use unix:///run/s.sock");
+        assert!(unix_socket("unixs:///run/s.sock").is_err());
+        assert!(unix_socket("unix://run/s.sock").is_err());
+    }
+
+    #[test]
+    fn only_socket_endpoints_have_their_own_client() {
+        let http = ClientConfig::new(Vec::new()).http;
+        let tcp = Endpoint::new("http://server:8080/api", &http).unwrap();
+        assert_eq!(tcp.base, "http://server:8080/api");
+        assert!(tcp.client.is_none());
+        let unix = Endpoint::new("unix:///run/s.sock/api", &http).unwrap();
+        assert_eq!(unix.base, "http://localhost/api");
+        assert!(unix.client.is_some());
+    }
+
+    #[test]
+    fn requests_go_through_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("storage.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let served = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            while reader.read_line(&mut head).unwrap() > 2 {}
+            let mut stream = reader.into_inner();
+            write!(stream, "HTTP/1.1 204 -\r\nContent-Length: 0\r\n\r\n").unwrap();
+            head
+        });
+
+        let url = format!("unix://{}/api", path.display());
+        let client = ApiClient::new(url).unwrap();
+        client.set_mtime("/a.txt", std::time::UNIX_EPOCH).unwrap();
+        let head = served.join().unwrap().to_lowercase();
+        assert!(head.starts_with("patch /api/"), "{}", head);
+        assert!(head.contains("host: localhost\r\n"), "{}", head);
+    }
+}
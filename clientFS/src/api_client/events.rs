@@ -0,0 +1,295 @@
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderValue, ACCEPT, CONTENT_TYPE};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::io::{BufRead, BufReader, Read};
+use std::time::Duration;
+
+use super::{ApiClient, ServerError};
+
+const LAST_EVENT_ID: &str = "last-event-id";
+// A stream is reopened this often, resuming where it stopped, since the
+// client's timeout covers the whole response
+const STREAM_LIFETIME: Duration = Duration::from_secs(3600);
+
+// What happened to a path on the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Deleted,
+}
+
+// A change notification, the data of an event on /events
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ChangeEvent {
+    pub path: String,
+    pub kind: ChangeKind,
+    #[serde(default)]
+    pub etag: Option<String>,
+}
+
+// One server-sent event
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerEvent {
+    // Sent back as Last-Event-Id to resume after this event
+    pub id: Option<String>,
+    // "message" unless the server named it
+    pub event: String,
+    pub data: String,
+}
+
+impl ServerEvent {
+    // The change carried by a "message" or "change" event
+    pub fn change(&self) -> Option<Result<ChangeEvent>> {
+        match self.event.as_str() {
+            "message" | "change" => Some(
+                serde_json::from_str(&self.data)
+                    .with_context(|| format!("Invalid change event '{}'", self.data)),
+            ),
+            _ => None,
+        }
+    }
+}
+
+// Change notifications as they arrive, until the connection ends or fails
+pub struct EventStream {
+    events: Box<dyn Iterator<Item = Result<ServerEvent>> + Send>,
+}
+
+impl EventStream {
+    // Events read off a text/event-stream body
+    pub fn new(reader: impl Read + Send + 'static) -> Self {
+        Self::from_events(SseReader {
+            reader: BufReader::new(reader),
+        })
+    }
+
+    // Events from any other transport
+    pub fn from_events(events: impl Iterator<Item = Result<ServerEvent>> + Send + 'static) -> Self {
+        Self {
+            events: Box::new(events),
+        }
+    }
+}
+
+impl Iterator for EventStream {
+    type Item = Result<ServerEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.events.next()
+    }
+}
+
+struct SseReader<R> {
+    reader: BufReader<R>,
+}
+
+impl<R: Read> Iterator for SseReader<R> {
+    type Item = Result<ServerEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut event = ServerEvent::default();
+        let mut data: Option<String> = None;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(anyhow::Error::new(e).context("Event stream failed"))),
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            // A blank line ends the event, events without data are dropped
+            if line.is_empty() {
+                match data.take() {
+                    Some(data) => {
+                        event.data = data;
+                        if event.event.is_empty() {
+                            event.event = "message".to_string();
+                        }
+                        return Some(Ok(event));
+                    }
+                    None => {
+                        event = ServerEvent::default();
+                        continue;
+                    }
+                }
+            }
+            // Comments keep the connection alive
+            if line.starts_with(':') {
+                continue;
+            }
+
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "data" => match &mut data {
+                    Some(data) => {
+                        data.push('\n');
+                        data.push_str(value);
+                    }
+                    None => data = Some(value.to_string()),
+                },
+                "event" => event.event = value.to_string(),
+                "id" if !value.contains('\0') => event.id = Some(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl ApiClient {
+    // Opens the change notifications on /events, resuming after
+    // `last_event_id` when given. None when the server has no event stream.
+    // The stream stays open, so it is not counted against max_concurrent.
+    pub fn events(&self, last_event_id: Option<&str>) -> Result<Option<EventStream>> {
+        if !self.capabilities().events {
+            return Ok(None);
+        }
+        let last_event_id = last_event_id.and_then(|id| HeaderValue::from_str(id).ok());
+        let response = self
+            .send(false, |client, base| {
+                let request = client
+                    .get(format!("{}/events", base))
+                    .header(ACCEPT, "text/event-stream")
+                    .timeout(STREAM_LIFETIME);
+                match &last_event_id {
+                    Some(id) => request.header(LAST_EVENT_ID, id.clone()),
+                    None => request,
+                }
+            })
+            .context("Failed to open the event stream")?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND
+            | StatusCode::METHOD_NOT_ALLOWED
+            | StatusCode::NOT_IMPLEMENTED => return Ok(None),
+            status if !status.is_success() => return Err(ServerError::from(&response).into()),
+            _ => {}
+        }
+        let is_stream = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/event-stream"));
+        if !is_stream {
+            return Ok(None);
+        }
+        Ok(Some(EventStream::new(response)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(body: &'static str) -> Vec<ServerEvent> {
+        EventStream::new(body.as_bytes()).map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn events_are_read_off_the_stream() {
+        let body = ": keepalive\n\
+                    id: 7\n\
+                    data: {\"path\": \"/a\", \"kind\": \"created\"}\n\
+                    \n\
+                    event: reset\r\n\
+                    data: \r\n\
+                    \r\n\
+                    id: 8\n\
+                    \n\
+                    data: one\n\
+                    data:two\n\
+                    \n\
+                    data: cut off";
+        let read = events(body);
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[0].id.as_deref(), Some("7"));
+        assert_eq!(read[0].event, "message");
+        assert_eq!((read[1].id.as_deref(), read[1].event.as_str()), (None, "reset"));
+        // The event without data was dropped with its id
+        assert_eq!((read[2].id.as_deref(), read[2].data.as_str()), (None, "one\ntwo"));
+    }
+
+    #[test]
+    fn changes_come_from_message_and_change_events() {
+        let change = ServerEvent {
+            id: None,
+            event: "change".to_string(),
+            data: r#"{"path": "/a", "kind": "modified", "etag": "\"x\""}"#.to_string(),
+        };
+        let expected = ChangeEvent {
+            path: "/a".to_string(),
+            kind: ChangeKind::Modified,
+            etag: Some("\"x\"".to_string()),
+        };
+        assert_eq!(change.change().unwrap().unwrap(), expected);
+
+        let invalid = ServerEvent {
+            data: "{".to_string(),
+            ..change.clone()
+        };
+        assert!(invalid.change().unwrap().is_err());
+        let other = ServerEvent {
+            event: "ping".to_string(),
+            ..change
+        };
+        assert!(other.change().is_none());
+    }
+
+    #[test]
+    fn streams_resume_after_the_last_event() {
+        use std::io::Write;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let served = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            while reader.read_line(&mut head).unwrap() > 2 {}
+            let body = "id: 9\ndata: {\"path\": \"/b\", \"kind\": \"deleted\"}\n\n";
+            write!(
+                reader.into_inner(),
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                 Content-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            head.to_lowercase()
+        });
+
+        let client = ApiClient::new(url).unwrap();
+        let stream = client.events(Some("8")).unwrap().unwrap();
+        let read: Vec<ServerEvent> = stream.map(Result::unwrap).collect();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].id.as_deref(), Some("9"));
+        let head = served.join().unwrap();
+        assert!(head.starts_with("get /events "), "{}", head);
+        assert!(head.contains("last-event-id: 8\r\n"), "{}", head);
+        assert!(head.contains("accept: text/event-stream\r\n"), "{}", head);
+    }
+
+    #[test]
+    fn servers_without_events_have_no_stream() {
+        use std::io::Write;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            while reader.read_line(&mut head).unwrap() > 2 {}
+            let response = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+            reader.into_inner().write_all(response.as_bytes()).unwrap();
+        });
+
+        let client = ApiClient::new(url).unwrap();
+        assert!(client.events(None).unwrap().is_none());
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::{Context, Result};
+use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::Certificate;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Matches max_concurrent, so requests in flight find a connection to reuse
+pub const DEFAULT_POOL_MAX_IDLE_PER_HOST: usize = 16;
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+pub const DEFAULT_TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+// How connections to the server are made. Every HTTP client is built from
+// it, and clients shared between mounts only when their options agree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpConfig {
+    // Speak HTTP/2 from the first byte, for h2c servers that never offer
+    // it. Parallel ranged reads then share one connection.
+    pub http2_prior_knowledge: bool,
+    pub pool_max_idle_per_host: usize,
+    // Idle connections are closed after this long, never when None
+    pub pool_idle_timeout: Option<Duration>,
+    // Probes dead peers on idle connections, off when None
+    pub tcp_keepalive: Option<Duration>,
+    pub tcp_nodelay: bool,
+    // PEM certificates trusted next to the system's, for servers with a
+    // private CA
+    pub ca_cert: Option<PathBuf>,
+    // Accept any certificate, for test servers only
+    pub tls_insecure: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            http2_prior_knowledge: false,
+            pool_max_idle_per_host: DEFAULT_POOL_MAX_IDLE_PER_HOST,
+            pool_idle_timeout: Some(DEFAULT_POOL_IDLE_TIMEOUT),
+            tcp_keepalive: Some(DEFAULT_TCP_KEEPALIVE),
+            tcp_nodelay: true,
+            ca_cert: None,
+            tls_insecure: false,
+        }
+    }
+}
+
+impl HttpConfig {
+    // The options applied, for clients that add their own before building.
+    // Fails when the CA certificates cannot be read.
+    pub fn builder(&self) -> Result<ClientBuilder> {
+        let mut builder = Client::builder()
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .tcp_keepalive(self.tcp_keepalive)
+            .tcp_nodelay(self.tcp_nodelay);
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(path) = &self.ca_cert {
+            let pem = fs::read(path)
+                .with_context(|| format!("Failed to read CA certificates {}", path.display()))?;
+            let certs = Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Invalid CA certificates in {}", path.display()))?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if self.tls_insecure {
+            log::warn!("Accepting any TLS certificate, the server is not authenticated");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder)
+    }
+
+    pub fn client(&self) -> Result<Client> {
+        self.builder()?
+            .build()
+            .context("Failed to create HTTP client")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn prior_knowledge_starts_with_the_http2_preface() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let served = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut preface = [0; 24];
+            stream.read_exact(&mut preface).unwrap();
+            preface
+        });
+        let http = HttpConfig {
+            http2_prior_knowledge: true,
+            ..HttpConfig::default()
+        };
+        // The server hangs up, what matters is what it was sent
+        let _ = http.client().unwrap().get(url).send();
+        assert_eq!(&served.join().unwrap(), b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n");
+    }
+
+    #[test]
+    fn ca_certificates_must_be_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = HttpConfig {
+            ca_cert: Some(dir.path().join("missing.pem")),
+            ..HttpConfig::default()
+        };
+        let error = missing.client().unwrap_err().to_string();
+        assert!(error.starts_with("Failed to read CA certificates"), "{}", error);
+
+        let path = dir.path().join("bad.pem");
+        fs::write(&path, "-----BEGIN CERTIFICATE-----\n!!\n-----END CERTIFICATE-----\n").unwrap();
+        let bad = HttpConfig {
+            ca_cert: Some(path),
+            ..HttpConfig::default()
+        };
+        let error = bad.client().unwrap_err().to_string();
+        assert!(error.starts_with("Invalid CA certificates"), "{}", error);
+    }
+
+    #[test]
+    fn every_option_can_be_turned_off() {
+        let http = HttpConfig {
+            pool_max_idle_per_host: 0,
+            pool_idle_timeout: None,
+            tcp_keepalive: None,
+            tcp_nodelay: false,
+            tls_insecure: true,
+            ..HttpConfig::default()
+        };
+        http.client().unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use super::context::caller_interrupted;
+use crate::filesystem::FsError;
+
+// How often a waiting request checks whether its caller was interrupted
+const INTERRUPT_POLL: Duration = Duration::from_millis(50);
+
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate.max(1.0),
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Takes a token if one is available, otherwise returns how long until the next one
+    fn try_take(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+// Bounds the number of in-flight requests and optionally their rate
+pub struct RequestLimiter {
+    max_in_flight: usize,
+    in_flight: Mutex<usize>,
+    released: Condvar,
+    bucket: Option<Mutex<TokenBucket>>,
+    throttled: AtomicU64,
+}
+
+pub struct Permit<'a> {
+    limiter: &'a RequestLimiter,
+}
+
+impl RequestLimiter {
+    pub fn new(max_in_flight: usize, max_rps: Option<f64>) -> Self {
+        Self {
+            max_in_flight: max_in_flight.max(1),
+            in_flight: Mutex::new(0),
+            released: Condvar::new(),
+            bucket: max_rps
+                .filter(|rate| *rate > 0.0)
+                .map(|rate| Mutex::new(TokenBucket::new(rate))),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    // Waits for a request slot, giving up once `timeout` has elapsed, or
+    // with Interrupted as soon as the caller of the operation is signalled
+    pub fn acquire(&self, timeout: Duration) -> Result<Permit<'_>> {
+        let deadline = Instant::now() + timeout;
+        let mut throttled = false;
+
+        if let Some(bucket) = &self.bucket {
+            loop {
+                let wait = match bucket.lock().unwrap().try_take() {
+                    Some(wait) => wait,
+                    None => break,
+                };
+
+                throttled = true;
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    self.throttled.fetch_add(1, Ordering::Relaxed);
+                    anyhow::bail!("Timed out waiting for the request rate limiter");
+                }
+                self.check_interrupted()?;
+                std::thread::sleep(wait.min(remaining).min(INTERRUPT_POLL));
+            }
+        }
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while *in_flight >= self.max_in_flight {
+            throttled = true;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                self.throttled.fetch_add(1, Ordering::Relaxed);
+                anyhow::bail!(
+                    "Timed out waiting for one of {} request slots",
+                    self.max_in_flight
+                );
+            }
+            self.check_interrupted()?;
+            let wait = remaining.min(INTERRUPT_POLL);
+            in_flight = self.released.wait_timeout(in_flight, wait).unwrap().0;
+        }
+        *in_flight += 1;
+
+        if throttled {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(Permit { limiter: self })
+    }
+
+    fn check_interrupted(&self) -> Result<()> {
+        if caller_interrupted() {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            return Err(anyhow::Error::new(FsError::Interrupted)
+                .context("Interrupted waiting for a request slot"));
+        }
+        Ok(())
+    }
+
+    pub fn in_flight(&self) -> usize {
+        *self.in_flight.lock().unwrap()
+    }
+
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.limiter.in_flight.lock().unwrap();
+        *in_flight -= 1;
+        self.limiter.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_client::with_caller;
+    use std::process::Command;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn never_more_in_flight_than_allowed() {
+        let limiter = Arc::new(RequestLimiter::new(4, None));
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let workers: Vec<_> = (0..32)
+            .map(|_| {
+                let (limiter, running, most) = (limiter.clone(), running.clone(), most.clone());
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        let _permit = limiter.acquire(Duration::from_secs(10)).unwrap();
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        most.fetch_max(now, Ordering::SeqCst);
+                        thread::yield_now();
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert!(most.load(Ordering::SeqCst) <= 4);
+        assert_eq!(limiter.in_flight(), 0);
+        assert!(limiter.throttled() > 0);
+    }
+
+    #[test]
+    fn rate_is_held_to_the_limit() {
+        let limiter = RequestLimiter::new(16, Some(20.0));
+        let started = Instant::now();
+        // The first 20 are the bucket's burst, the next 10 take half a second
+        for _ in 0..30 {
+            drop(limiter.acquire(Duration::from_secs(10)).unwrap());
+        }
+        assert!(started.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn waiting_past_the_timeout_fails() {
+        let limiter = RequestLimiter::new(1, None);
+        let _held = limiter.acquire(Duration::from_secs(1)).unwrap();
+        let error = limiter.acquire(Duration::from_millis(100)).err().unwrap();
+        assert_eq!(FsError::from_backend(&error), FsError::Io);
+        assert_eq!(limiter.in_flight(), 1);
+    }
+
+    #[test]
+    fn interrupted_caller_stops_waiting() {
+        let limiter = RequestLimiter::new(1, None);
+        let _held = limiter.acquire(Duration::from_secs(1)).unwrap();
+        // Gone, as a killed caller is
+        let mut child = Command::new("true").spawn().unwrap();
+        let gone = child.id();
+        child.wait().unwrap();
+
+        let started = Instant::now();
+        let error = with_caller(gone, || limiter.acquire(Duration::from_secs(30)))
+            .err()
+            .unwrap();
+        assert_eq!(FsError::from_backend(&error).errno(), libc::EINTR);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // A caller still waiting keeps waiting until the timeout
+        let error = with_caller(std::process::id(), || limiter.acquire(Duration::from_millis(100)))
+            .err()
+            .unwrap();
+        assert_eq!(FsError::from_backend(&error), FsError::Io);
+    }
+}
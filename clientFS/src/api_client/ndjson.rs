@@ -0,0 +1,59 @@
+use anyhow::{Context, Result};
+use reqwest::blocking::Response;
+use reqwest::header::CONTENT_TYPE;
+use std::io::{BufRead, BufReader, Read};
+
+use super::FileEntry;
+
+// Asked for on /list, servers that do not know it answer with the array
+pub const ACCEPT_LISTING: &str = "application/x-ndjson, application/json;q=0.9";
+const NDJSON: &str = "application/x-ndjson";
+
+// Bytes read from the server at a time, and the longest line accepted. The
+// parse buffer never grows beyond this, however large the directory.
+const READ_BUFFER: usize = 64 * 1024;
+const MAX_LINE: u64 = 64 * 1024;
+
+// Whether the server sent one entry per line
+pub fn is_ndjson(response: &Response) -> bool {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case(NDJSON))
+}
+
+// Parses entries one line at a time as they arrive, passing each on.
+// Returns the bytes read.
+pub fn read_entries(body: impl Read, entry: &mut dyn FnMut(FileEntry)) -> Result<usize> {
+    let mut reader = BufReader::with_capacity(READ_BUFFER, body);
+    let mut line = String::new();
+    let mut read = 0;
+    for number in 1.. {
+        line.clear();
+        let len = (&mut reader)
+            .take(MAX_LINE + 1)
+            .read_line(&mut line)
+            .with_context(|| format!("Failed to read listing line {}", number))?;
+        if len == 0 {
+            break;
+        }
+        read += len;
+        anyhow::ensure!(
+            len as u64 <= MAX_LINE,
+            "Listing line {} is longer than {} bytes",
+            number,
+            MAX_LINE
+        );
+        let text = line.trim();
+        if text.is_empty() {
+            continue;
+        }
+        entry(
+            serde_json::from_str(text)
+                .with_context(|| format!("Invalid listing entry on line {}", number))?,
+        );
+    }
+    Ok(read)
+}
@@ -0,0 +1,536 @@
+use reqwest::blocking::Response;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use serde_json::json;
+use std::fmt;
+
+use super::{url, ApiClient, ListResponse, ServerCapabilities};
+
+// Mutating probes work in here and remove it afterwards
+pub const SELFTEST_DIR: &str = ".remotefs-selftest";
+const PROBE_DATA: &[u8] = b"remotefs self-test\n";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeResult {
+    Supported,
+    // The server answered without the capability, or failed
+    Missing(String),
+    // Not tried, for instance because the check is read-only
+    Skipped(String),
+}
+
+// One capability of the server the client relies on
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub name: &'static str,
+    // Without it the mount does not work at all
+    pub mandatory: bool,
+    pub result: ProbeResult,
+    // What the client does without it
+    pub degraded: &'static str,
+}
+
+// Capabilities of a server, from ApiClient::self_test
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub endpoint: String,
+    pub read_only: bool,
+    pub probes: Vec<Probe>,
+    // What a mount would negotiate, None when that failed
+    pub capabilities: Option<ServerCapabilities>,
+}
+
+impl SelfTestReport {
+    // Mandatory capabilities the server lacks, the mount cannot work if any
+    pub fn missing_mandatory(&self) -> Vec<&Probe> {
+        self.probes
+            .iter()
+            .filter(|probe| probe.mandatory && matches!(probe.result, ProbeResult::Missing(_)))
+            .collect()
+    }
+
+    // Optional capabilities the server lacks, the mount works without them
+    pub fn warnings(&self) -> Vec<&Probe> {
+        self.probes
+            .iter()
+            .filter(|probe| !probe.mandatory && matches!(probe.result, ProbeResult::Missing(_)))
+            .collect()
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Server {}", self.endpoint)?;
+        for probe in &self.probes {
+            let (status, detail) = match &probe.result {
+                ProbeResult::Supported => ("ok", ""),
+                ProbeResult::Missing(reason) => ("missing", reason.as_str()),
+                ProbeResult::Skipped(reason) => ("skipped", reason.as_str()),
+            };
+            let kind = if probe.mandatory {
+                "required"
+            } else {
+                "optional"
+            };
+            let line = format!("  {:<14} {:<8} {:<9} {}", probe.name, kind, status, detail);
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        if let Some(capabilities) = &self.capabilities {
+            writeln!(f, "Capabilities: {}", capabilities)?;
+        }
+        for probe in self.warnings() {
+            writeln!(f, "warning: no {}: {}", probe.name, probe.degraded)?;
+        }
+        for probe in self.missing_mandatory() {
+            writeln!(f, "error: no {}: {}", probe.name, probe.degraded)?;
+        }
+        Ok(())
+    }
+}
+
+// Succeeds on a 2xx, anything else is described for the report
+fn success(result: anyhow::Result<Response>) -> Result<Response, String> {
+    match result {
+        Ok(response) if response.status().is_success() => Ok(response),
+        Ok(response) => Err(format!("HTTP {}", response.status())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn outcome<T>(result: Result<T, String>) -> ProbeResult {
+    match result {
+        Ok(_) => ProbeResult::Supported,
+        Err(reason) => ProbeResult::Missing(reason),
+    }
+}
+
+impl ApiClient {
+    // Probes every endpoint the client uses, in a throwaway directory for
+    // the ones that change something, and reports which are missing. With
+    // `read_only` nothing is written and the read probes use a file found
+    // in the root, if any. Each probe is sent once, without retries.
+    pub fn self_test(&self, read_only: bool) -> SelfTestReport {
+        let mut probes = Vec::new();
+        let mut probe = |name, mandatory, degraded, result| {
+            probes.push(Probe {
+                name,
+                mandatory,
+                result,
+                degraded,
+            })
+        };
+
+        let health =
+            success(self.send(false, |client, base| client.get(format!("{}/health", base))));
+        probe(
+            "health",
+            true,
+            "the mount refuses to start",
+            outcome(health),
+        );
+
+        let root = success(self.send(false, |client, base| client.get(url(base, "list", ""))))
+            .and_then(|response| {
+                response
+                    .json::<ListResponse>()
+                    .map_err(|e| format!("invalid listing: {}", e))
+            });
+        let first_file = root.as_ref().ok().and_then(|listing| {
+            listing
+                .entries
+                .iter()
+                .find(|entry| !entry.is_dir && entry.size > 0)
+                .map(|entry| entry.name.clone())
+        });
+        probe("list", true, "directories cannot be listed", outcome(root));
+
+        let skipped = |reason: &str| ProbeResult::Skipped(reason.to_string());
+        let read_only_skip = || skipped("read-only check");
+
+        // Mutating probes, creating the file the read probes use
+        let dir_created = !read_only
+            && success(self.send(false, |client, base| {
+                client.post(url(base, "mkdir", SELFTEST_DIR))
+            }))
+            .is_ok();
+        // Without mkdir the test file goes in the root
+        let file = if dir_created {
+            format!("{}/probe-{}", SELFTEST_DIR, std::process::id())
+        } else {
+            format!("{}-{}", SELFTEST_DIR, std::process::id())
+        };
+        let renamed = format!("{}-renamed", file);
+        let mut written = false;
+        if read_only {
+            probe(
+                "mkdir",
+                false,
+                "directories cannot be created",
+                read_only_skip(),
+            );
+            probe("write", true, "files cannot be written", read_only_skip());
+            probe(
+                "partial write",
+                false,
+                "every write uploads the whole file",
+                read_only_skip(),
+            );
+        } else {
+            probe(
+                "mkdir",
+                false,
+                "directories cannot be created",
+                if dir_created {
+                    ProbeResult::Supported
+                } else {
+                    ProbeResult::Missing("could not create the test directory".to_string())
+                },
+            );
+            let put = success(self.send(false, |client, base| {
+                client
+                    .put(url(base, "files", &file))
+                    .body(PROBE_DATA.to_vec())
+            }));
+            written = put.is_ok();
+            probe("write", true, "files cannot be written", outcome(put));
+
+            let patch = if written {
+                let range = format!("bytes 0-{}/*", PROBE_DATA.len() - 1);
+                success(self.send(false, |client, base| {
+                    client
+                        .patch(url(base, "files", &file))
+                        .header(CONTENT_RANGE, range.as_str())
+                        .body(PROBE_DATA.to_vec())
+                }))
+                .map(|_| ())
+            } else {
+                Err("no test file".to_string())
+            };
+            probe(
+                "partial write",
+                false,
+                "every write uploads the whole file",
+                outcome(patch),
+            );
+        }
+
+        // Read probes, on the test file or else on a file of the root
+        let target = if written {
+            Some(file.clone())
+        } else {
+            first_file
+        };
+        match &target {
+            Some(target) => {
+                let get = success(
+                    self.send(false, |client, base| client.get(url(base, "files", target))),
+                );
+                probe("read", true, "files cannot be read", outcome(get));
+
+                let head = success(self.send(false, |client, base| {
+                    client.head(url(base, "files", target))
+                }));
+                probe(
+                    "stat",
+                    false,
+                    "cached contents cannot be revalidated and are downloaded again",
+                    outcome(head),
+                );
+
+                let ranged = success(self.send(false, |client, base| {
+                    client
+                        .get(url(base, "files", target))
+                        .header(RANGE, "bytes=0-0")
+                }))
+                .and_then(|response| match response.status() {
+                    StatusCode::PARTIAL_CONTENT => Ok(()),
+                    status => Err(format!("Range ignored, HTTP {}", status)),
+                });
+                probe(
+                    "ranged read",
+                    false,
+                    "every read downloads the whole file",
+                    outcome(ranged),
+                );
+            }
+            None => {
+                let reason = "no file to read in the root";
+                probe("read", true, "files cannot be read", skipped(reason));
+                probe(
+                    "stat",
+                    false,
+                    "cached contents cannot be revalidated and are downloaded again",
+                    skipped(reason),
+                );
+                probe(
+                    "ranged read",
+                    false,
+                    "every read downloads the whole file",
+                    skipped(reason),
+                );
+            }
+        }
+
+        let websocket = match self.ws_events(&["/".to_string()], None) {
+            Ok(Some(_)) => ProbeResult::Supported,
+            Ok(None) => ProbeResult::Missing("no WebSocket endpoint".to_string()),
+            Err(e) => ProbeResult::Missing(format!("{:#}", e)),
+        };
+        probe(
+            "websocket",
+            false,
+            "change notifications need server-sent events",
+            websocket,
+        );
+
+        let events = match self.events(None) {
+            Ok(Some(_)) => ProbeResult::Supported,
+            Ok(None) => ProbeResult::Missing("no event stream".to_string()),
+            Err(e) => ProbeResult::Missing(format!("{:#}", e)),
+        };
+        probe(
+            "events",
+            false,
+            "changes made elsewhere show up once cached entries expire",
+            events,
+        );
+
+        if read_only {
+            probe("rename", false, "renames fail", read_only_skip());
+            probe(
+                "delete",
+                false,
+                "files and directories cannot be removed",
+                read_only_skip(),
+            );
+        } else {
+            let rename = if written {
+                let body = json!({ "from": format!("/{}", file), "to": format!("/{}", renamed) });
+                success(self.send(false, |client, base| {
+                    client.post(format!("{}/rename", base)).json(&body)
+                }))
+                .map(|_| ())
+            } else {
+                Err("no test file".to_string())
+            };
+            let renamed_ok = rename.is_ok();
+            probe("rename", false, "renames fail", outcome(rename));
+
+            // Cleaning up is the delete probe
+            let leftover = if renamed_ok { &renamed } else { &file };
+            let delete = if written {
+                success(self.send(false, |client, base| {
+                    client.delete(url(base, "files", leftover))
+                }))
+                .map(|_| ())
+            } else {
+                Err("no test file".to_string())
+            };
+            // The directory goes even when the file could not be written
+            let delete = if dir_created {
+                let dir = success(self.send(false, |client, base| {
+                    client.delete(url(base, "files", SELFTEST_DIR))
+                }));
+                delete.and(dir.map(|_| ()))
+            } else {
+                delete
+            };
+            if let Err(reason) = &delete {
+                log::warn!("Failed to clean up /{}: {}", SELFTEST_DIR, reason);
+            }
+            probe(
+                "delete",
+                false,
+                "files and directories cannot be removed",
+                outcome(delete),
+            );
+        }
+
+        // Last, so the probes above are not skipped for features it rules out
+        let capabilities = match self.negotiate_capabilities() {
+            Ok(capabilities) => Some(capabilities),
+            Err(e) => {
+                log::warn!("Failed to negotiate capabilities: {:#}", e);
+                None
+            }
+        };
+
+        SelfTestReport {
+            endpoint: self.active_endpoint().to_string(),
+            read_only,
+            probes,
+            capabilities,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    // A server answering every request with `route(method, path, ranged)`,
+    // and the "METHOD path" of the requests it got
+    fn serve(
+        route: fn(&str, &str, bool) -> (u16, &'static str),
+    ) -> (ApiClient, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut request = String::new();
+                if reader.read_line(&mut request).is_err() {
+                    continue;
+                }
+                let (mut length, mut ranged) = (0, false);
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                    let lower = line.to_lowercase();
+                    if let Some(value) = lower.strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    ranged |= lower.starts_with("range:");
+                    line.clear();
+                }
+                let _ = reader.by_ref().take(length).read_to_end(&mut Vec::new());
+
+                let mut parts = request.split_whitespace();
+                let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+                seen.lock().unwrap().push(format!("{} {}", method, path));
+                let (status, body) = route(method, path, ranged);
+                let _ = write!(
+                    reader.into_inner(),
+                    "HTTP/1.1 {} -\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        (ApiClient::new(url).unwrap(), requests)
+    }
+
+    fn result(report: &SelfTestReport, name: &str) -> ProbeResult {
+        let probe = report.probes.iter().find(|probe| probe.name == name);
+        probe.unwrap().result.clone()
+    }
+
+    const LISTING: &str =
+        r#"{"entries": [{"name": "a.txt", "is_dir": false, "size": 3, "mode": 420}]}"#;
+
+    fn full_server(method: &str, path: &str, ranged: bool) -> (u16, &'static str) {
+        match (method, path) {
+            ("GET", "/list/") => (200, LISTING),
+            ("GET", "/capabilities") => (200, "{}"),
+            ("GET", path) if path.starts_with("/files/") && ranged => (206, "r"),
+            ("GET" | "HEAD" | "PUT" | "PATCH" | "DELETE", path) if path.starts_with("/files/") => {
+                (200, "")
+            }
+            ("GET", "/health") | ("POST", "/rename") => (200, ""),
+            ("POST", path) if path.starts_with("/mkdir/") => (200, ""),
+            _ => (404, ""),
+        }
+    }
+
+    // Without mkdir, rename, partial writes or ranges
+    fn old_server(method: &str, path: &str, _ranged: bool) -> (u16, &'static str) {
+        match (method, path) {
+            ("PATCH", _) => (405, ""),
+            ("POST", _) => (404, ""),
+            _ => full_server(method, path, false),
+        }
+    }
+
+    #[test]
+    fn a_complete_server_passes_and_is_cleaned_up() {
+        let (client, requests) = serve(full_server);
+        let report = client.self_test(false);
+        assert!(report.missing_mandatory().is_empty(), "{}", report);
+        for name in [
+            "health",
+            "list",
+            "mkdir",
+            "write",
+            "partial write",
+            "read",
+            "stat",
+        ] {
+            assert_eq!(result(&report, name), ProbeResult::Supported, "{}", name);
+        }
+        for name in ["ranged read", "rename", "delete"] {
+            assert_eq!(result(&report, name), ProbeResult::Supported, "{}", name);
+        }
+
+        let requests = requests.lock().unwrap();
+        let dir = format!("/files/{}", SELFTEST_DIR);
+        assert!(requests.contains(&format!("POST /mkdir/{}", SELFTEST_DIR)));
+        assert!(requests
+            .iter()
+            .any(|r| r.starts_with(&format!("PUT {}/probe-", dir))));
+        assert!(requests
+            .iter()
+            .any(|r| r.starts_with(&format!("DELETE {}/probe-", dir))));
+        assert!(requests.contains(&format!("DELETE {}", dir)));
+    }
+
+    #[test]
+    fn an_old_server_only_gets_warnings() {
+        let (client, requests) = serve(old_server);
+        let report = client.self_test(false);
+        assert!(report.missing_mandatory().is_empty(), "{}", report);
+        let warned: Vec<&str> = report.warnings().iter().map(|probe| probe.name).collect();
+        for name in ["mkdir", "partial write", "ranged read", "rename"] {
+            assert!(warned.contains(&name), "{} not in {:?}", name, warned);
+        }
+        assert!(report
+            .to_string()
+            .contains("warning: no rename: renames fail\n"));
+
+        // The test file went in the root, and was removed under its first name
+        let file = format!("/files/{}-{}", SELFTEST_DIR, std::process::id());
+        let requests = requests.lock().unwrap();
+        assert!(requests.contains(&format!("PUT {}", file)));
+        assert!(requests.contains(&format!("DELETE {}", file)));
+    }
+
+    #[test]
+    fn read_only_checks_change_nothing() {
+        let (client, requests) = serve(full_server);
+        let report = client.self_test(true);
+        assert!(report.read_only);
+        assert_eq!(result(&report, "read"), ProbeResult::Supported);
+        assert!(matches!(result(&report, "write"), ProbeResult::Skipped(_)));
+        assert!(matches!(result(&report, "delete"), ProbeResult::Skipped(_)));
+
+        let requests = requests.lock().unwrap();
+        assert!(requests.contains(&"GET /files/a.txt".to_string()));
+        let changes = ["PUT", "PATCH", "POST", "DELETE"];
+        assert!(!requests
+            .iter()
+            .any(|r| changes.contains(&r.split(' ').next().unwrap())));
+    }
+
+    #[test]
+    fn missing_mandatory_endpoints_are_errors() {
+        let (client, _requests) = serve(|method, path, ranged| match path {
+            "/health" => (404, ""),
+            _ => full_server(method, path, ranged),
+        });
+        let report = client.self_test(true);
+        let missing: Vec<&str> = report.missing_mandatory().iter().map(|p| p.name).collect();
+        assert_eq!(missing, ["health"]);
+        assert_eq!(
+            result(&report, "health"),
+            ProbeResult::Missing("HTTP 404 Not Found".into())
+        );
+        let text = report.to_string();
+        let line = "  health         required missing   HTTP 404 Not Found\n";
+        assert!(text.contains(line), "{}", text);
+        assert!(text.contains("error: no health: the mount refuses to start\n"));
+    }
+}
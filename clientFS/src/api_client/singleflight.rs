@@ -0,0 +1,249 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use super::ServerError;
+use crate::filesystem::FsError;
+
+// How the leader failed. Error statuses stay typed for every waiter, anything
+// else keeps its kind, so waiters fail with the leader's errno, and message.
+enum Failure {
+    Server(ServerError),
+    Other(FsError, String),
+}
+
+struct Call<T> {
+    result: Mutex<Option<Result<T, Failure>>>,
+    done: Condvar,
+}
+
+// Collapses concurrent identical requests into one: the first caller for a key
+// performs it and every caller arriving meanwhile receives a copy of its result
+pub struct SingleFlight<T> {
+    calls: Mutex<HashMap<String, Arc<Call<T>>>>,
+}
+
+// Completes the call even if the leader panics, so waiters are never stranded
+struct Leader<'a, T> {
+    group: &'a SingleFlight<T>,
+    key: &'a str,
+    call: Arc<Call<T>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        Self {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub fn run<F>(&self, key: String, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let (call, is_leader) = {
+            let mut calls = lock(&self.calls);
+            match calls.get(&key) {
+                Some(call) => (call.clone(), false),
+                None => {
+                    let call = Arc::new(Call {
+                        result: Mutex::new(None),
+                        done: Condvar::new(),
+                    });
+                    calls.insert(key.clone(), call.clone());
+                    (call, true)
+                }
+            }
+        };
+
+        if !is_leader {
+            log::debug!("Joining in-flight request {}", key);
+            let mut result = lock(&call.result);
+            while result.is_none() {
+                result = call
+                    .done
+                    .wait(result)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+            }
+            return match result.as_ref() {
+                Some(Ok(value)) => Ok(value.clone()),
+                Some(Err(Failure::Server(e))) => Err((*e).into()),
+                Some(Err(Failure::Other(kind, message))) => {
+                    Err(anyhow::Error::new(*kind).context(message.clone()))
+                }
+                None => unreachable!(),
+            };
+        }
+
+        let leader = Leader {
+            group: self,
+            key: &key,
+            call,
+        };
+
+        let result = f();
+        leader.finish(match &result {
+            Ok(value) => Ok(value.clone()),
+            Err(e) => Err(match e.downcast_ref::<ServerError>() {
+                Some(server) => Failure::Server(*server),
+                None => Failure::Other(FsError::from_backend(e), format!("{:#}", e)),
+            }),
+        });
+        result
+    }
+}
+
+impl<T> Leader<'_, T> {
+    fn finish(&self, result: Result<T, Failure>) {
+        let mut slot = lock(&self.call.result);
+        if slot.is_none() {
+            *slot = Some(result);
+        }
+    }
+}
+
+impl<T> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        if lock(&self.call.result).is_none() {
+            let abandoned = "request was abandoned".to_string();
+            self.finish(Err(Failure::Other(FsError::Io, abandoned)));
+        }
+        lock(&self.group.calls).remove(self.key);
+        self.call.done.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+
+    // Runs `f` as the leader for "key" with `waiters` more callers joining
+    // it while it is held, returning every caller's result
+    fn joined<T: Clone + Send + 'static>(
+        waiters: usize,
+        f: impl FnOnce() -> Result<T> + Send + 'static,
+    ) -> (Vec<Result<T>>, usize) {
+        let group = Arc::new(SingleFlight::default());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (release, held) = mpsc::channel::<()>();
+        let started = Arc::new(Barrier::new(2));
+
+        let leader = {
+            let (group, runs, started) = (group.clone(), runs.clone(), started.clone());
+            thread::spawn(move || {
+                group.run("key".to_string(), || {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    started.wait();
+                    let _ = held.recv();
+                    f()
+                })
+            })
+        };
+        started.wait();
+        let waiting: Vec<_> = (0..waiters)
+            .map(|_| {
+                let (group, runs) = (group.clone(), runs.clone());
+                thread::spawn(move || {
+                    group.run("key".to_string(), || {
+                        runs.fetch_add(1, Ordering::SeqCst);
+                        anyhow::bail!("ran on its own")
+                    })
+                })
+            })
+            .collect();
+        // Long enough for the waiters to join, they would run on their own otherwise
+        while Arc::strong_count(&lock(&group.calls)["key"]) < waiters + 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        release.send(()).unwrap();
+
+        let mut results = vec![leader.join().unwrap()];
+        results.extend(waiting.into_iter().map(|waiter| waiter.join().unwrap()));
+        (results, runs.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn concurrent_callers_share_one_run() {
+        let (results, runs) = joined(8, || Ok(42));
+        assert_eq!(runs, 1);
+        assert!(results.iter().all(|result| *result.as_ref().unwrap() == 42));
+    }
+
+    #[test]
+    fn waiters_fail_with_the_leaders_errno() {
+        let (results, _) = joined::<()>(3, || {
+            Err(anyhow::Error::new(FsError::NoSpace).context("Failed to upload /a"))
+        });
+        for result in results {
+            let error = result.unwrap_err();
+            assert_eq!(FsError::from_backend(&error), FsError::NoSpace);
+            assert!(format!("{:#}", error).contains("Failed to upload /a"));
+        }
+
+        let conflict = ServerError {
+            status: StatusCode::CONFLICT,
+            maintenance: false,
+        };
+        let (results, _) = joined::<()>(3, move || Err(conflict.into()));
+        for result in results {
+            let error = result.unwrap_err();
+            assert!(error.downcast_ref::<ServerError>().is_some());
+            assert_eq!(FsError::from_backend(&error), FsError::AlreadyExists);
+        }
+    }
+
+    #[test]
+    fn callers_after_the_run_start_a_new_one() {
+        let group = SingleFlight::default();
+        let runs = AtomicUsize::new(0);
+        for _ in 0..3 {
+            let value = group.run("key".to_string(), || Ok(runs.fetch_add(1, Ordering::SeqCst)));
+            assert_eq!(value.unwrap(), runs.load(Ordering::SeqCst) - 1);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert!(lock(&group.calls).is_empty());
+    }
+
+    #[test]
+    fn waiters_are_released_when_the_leader_panics() {
+        let group = Arc::new(SingleFlight::<u32>::default());
+        let (release, held) = mpsc::channel::<()>();
+        let started = Arc::new(Barrier::new(2));
+        let leader = {
+            let (group, started) = (group.clone(), started.clone());
+            thread::spawn(move || {
+                group.run("key".to_string(), || {
+                    started.wait();
+                    let _ = held.recv();
+                    panic!("leader died")
+                })
+            })
+        };
+        started.wait();
+        let waiter = {
+            let group = group.clone();
+            thread::spawn(move || group.run("key".to_string(), || Ok(0)))
+        };
+        while Arc::strong_count(&lock(&group.calls)["key"]) < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        release.send(()).unwrap();
+
+        assert!(leader.join().is_err());
+        let error = waiter.join().unwrap().unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::Io);
+    }
+}
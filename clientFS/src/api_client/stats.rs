@@ -0,0 +1,190 @@
+use reqwest::{Method, StatusCode, Version};
+use serde::Serialize;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{BreakerState, ServerCapabilities};
+
+const METHODS: [Method; 6] = [
+    Method::GET,
+    Method::HEAD,
+    Method::PUT,
+    Method::PATCH,
+    Method::POST,
+    Method::DELETE,
+];
+// Status classes 1xx to 5xx, then requests that never got a response
+const OUTCOMES: [&str; 6] = ["1xx", "2xx", "3xx", "4xx", "5xx", "failed"];
+const PROTOCOLS: [Version; 4] = [
+    Version::HTTP_10,
+    Version::HTTP_11,
+    Version::HTTP_2,
+    Version::HTTP_3,
+];
+
+thread_local! {
+    // Attempts made by this thread, so a FUSE operation can tell how many
+    // requests it issued even while others run concurrently
+    static THREAD_REQUESTS: Cell<u64> = const { Cell::new(0) };
+}
+
+// Attempts made by the calling thread since the last call
+pub fn take_thread_requests() -> u64 {
+    THREAD_REQUESTS.take()
+}
+
+// Counts attempts that helper threads made on behalf of the calling thread
+pub fn count_thread_requests(requests: u64) {
+    THREAD_REQUESTS.set(THREAD_REQUESTS.get() + requests);
+}
+
+// Traffic counters, updated with relaxed atomics so counting costs next to
+// nothing on the request path
+#[derive(Default)]
+pub struct RequestStats {
+    requests: [[AtomicU64; OUTCOMES.len()]; METHODS.len()],
+    bytes_uploaded: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    retries: AtomicU64,
+    responses: [AtomicU64; PROTOCOLS.len()],
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RequestStatsSnapshot {
+    // Method to status class to count, classes never seen are left out
+    pub requests: BTreeMap<String, BTreeMap<String, u64>>,
+    pub bytes_uploaded: u64,
+    pub bytes_downloaded: u64,
+    pub retries: u64,
+    // Responses by the HTTP version they came over, to tell whether HTTP/2
+    // is in use
+    pub protocols: BTreeMap<String, u64>,
+    // Requests that failed in transport or got a 5xx
+    pub errors: u64,
+    // The circuit breaker, filled in by the client
+    pub breaker: BreakerState,
+    pub breaker_opened: u64,
+    // Requests failed fast while the circuit was open
+    pub breaker_rejected: u64,
+    // What the server supports, for clients that negotiate it
+    pub capabilities: Option<ServerCapabilities>,
+    // Seconds the server's clock runs ahead of ours, from its Date headers
+    pub clock_skew: Option<i64>,
+}
+
+impl RequestStats {
+    // Records one attempt, `status` is None when no response arrived
+    pub fn record(&self, method: &Method, status: Option<StatusCode>, uploaded: usize) {
+        THREAD_REQUESTS.set(THREAD_REQUESTS.get() + 1);
+        let Some(m) = METHODS.iter().position(|known| known == method) else {
+            return;
+        };
+        let outcome = match status {
+            Some(status) => (status.as_u16() as usize / 100).clamp(1, 5) - 1,
+            None => OUTCOMES.len() - 1,
+        };
+
+        self.requests[m][outcome].fetch_add(1, Ordering::Relaxed);
+        self.bytes_uploaded
+            .fetch_add(uploaded as u64, Ordering::Relaxed);
+    }
+
+    // Bodies streamed from a reader are not known to `record` and counted here
+    pub fn uploaded(&self, bytes: usize) {
+        self.bytes_uploaded
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn downloaded(&self, bytes: usize) {
+        self.bytes_downloaded
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn protocol(&self, version: Version) {
+        if let Some(p) = PROTOCOLS.iter().position(|known| *known == version) {
+            self.responses[p].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> RequestStatsSnapshot {
+        let mut snapshot = RequestStatsSnapshot {
+            bytes_uploaded: self.bytes_uploaded.load(Ordering::Relaxed),
+            bytes_downloaded: self.bytes_downloaded.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+
+        for (version, count) in PROTOCOLS.iter().zip(&self.responses) {
+            let count = count.load(Ordering::Relaxed);
+            if count > 0 {
+                snapshot.protocols.insert(format!("{:?}", version), count);
+            }
+        }
+
+        for (method, counts) in METHODS.iter().zip(&self.requests) {
+            for (outcome, count) in OUTCOMES.iter().zip(counts) {
+                let count = count.load(Ordering::Relaxed);
+                if count == 0 {
+                    continue;
+                }
+                if matches!(*outcome, "5xx" | "failed") {
+                    snapshot.errors += count;
+                }
+                snapshot
+                    .requests
+                    .entry(method.to_string())
+                    .or_default()
+                    .insert(outcome.to_string(), count);
+            }
+        }
+
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_counted_by_method_and_status_class() {
+        let stats = RequestStats::default();
+        stats.record(&Method::GET, Some(StatusCode::OK), 0);
+        stats.record(&Method::GET, Some(StatusCode::PARTIAL_CONTENT), 0);
+        stats.record(&Method::PUT, Some(StatusCode::SERVICE_UNAVAILABLE), 10);
+        stats.record(&Method::PUT, None, 5);
+        // Not one of METHODS
+        stats.record(&Method::OPTIONS, Some(StatusCode::OK), 100);
+        stats.downloaded(7);
+        stats.protocol(Version::HTTP_2);
+        stats.retry();
+
+        let snapshot = stats.snapshot();
+        let get = BTreeMap::from([("2xx".to_string(), 2)]);
+        let put = BTreeMap::from([("5xx".to_string(), 1), ("failed".to_string(), 1)]);
+        let requests = BTreeMap::from([("GET".to_string(), get), ("PUT".to_string(), put)]);
+        assert_eq!(snapshot.requests, requests);
+        assert_eq!(snapshot.errors, 2);
+        assert_eq!((snapshot.bytes_uploaded, snapshot.bytes_downloaded), (15, 7));
+        assert_eq!(snapshot.protocols, BTreeMap::from([("HTTP/2.0".to_string(), 1)]));
+        assert_eq!(snapshot.retries, 1);
+    }
+
+    #[test]
+    fn requests_are_counted_per_thread() {
+        take_thread_requests();
+        let stats = RequestStats::default();
+        stats.record(&Method::GET, Some(StatusCode::OK), 0);
+        std::thread::scope(|scope| {
+            scope.spawn(|| stats.record(&Method::GET, Some(StatusCode::OK), 0));
+        });
+        count_thread_requests(2);
+        assert_eq!(take_thread_requests(), 3);
+        assert_eq!(take_thread_requests(), 0);
+    }
+}
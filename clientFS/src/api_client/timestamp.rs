@@ -0,0 +1,249 @@
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::dates::{parse_http_date, parse_rfc3339};
+
+const NANOS_PER_SEC: u32 = 1_000_000_000;
+
+// A point in time to the nanosecond, as seconds since the epoch and the
+// nanoseconds past them. Tools like make and rsync compare mtimes exactly,
+// which seconds in an f64 cannot hold.
+//
+// Sent as {"secs": 1700000000, "nanos": 123456789}. Servers that send the
+// older float seconds are still understood, to the precision an f64 has, as
+// are dates in text. Missing and unreadable times are UNKNOWN rather than
+// the epoch, which would make every such file look decades old.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct Timestamp {
+    pub secs: i64,
+    // Always below one second, also for times before the epoch
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    pub const UNKNOWN: Self = Self {
+        secs: i64::MIN,
+        nanos: 0,
+    };
+
+    pub fn unknown() -> Self {
+        Self::UNKNOWN
+    }
+
+    pub fn is_known(&self) -> bool {
+        *self != Self::UNKNOWN
+    }
+
+    pub fn new(secs: i64, nanos: u32) -> Self {
+        Self {
+            secs: secs + (nanos / NANOS_PER_SEC) as i64,
+            nanos: nanos % NANOS_PER_SEC,
+        }
+    }
+
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+
+    // From float seconds, as older servers and the WebDAV and S3 dates have
+    // them. Anything that is not a number is unknown.
+    pub fn from_secs_f64(secs: f64) -> Self {
+        if !secs.is_finite() {
+            return Self::UNKNOWN;
+        }
+        let whole = secs.floor();
+        let nanos = ((secs - whole) * NANOS_PER_SEC as f64).round() as u32;
+        Self::new(whole as i64, nanos)
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => Self::new(since.as_secs() as i64, since.subsec_nanos()),
+            Err(e) => {
+                let before = e.duration();
+                match before.subsec_nanos() {
+                    0 => Self::new(-(before.as_secs() as i64), 0),
+                    nanos => Self::new(-(before.as_secs() as i64) - 1, NANOS_PER_SEC - nanos),
+                }
+            }
+        }
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(time: Timestamp) -> Self {
+        let moment = if time.secs >= 0 {
+            UNIX_EPOCH.checked_add(Duration::from_secs(time.secs as u64))
+        } else {
+            UNIX_EPOCH.checked_sub(Duration::from_secs(time.secs.unsigned_abs()))
+        };
+        moment
+            .and_then(|moment| moment.checked_add(Duration::from_nanos(time.nanos as u64)))
+            .unwrap_or(UNIX_EPOCH)
+    }
+}
+
+// Like the X-Mtime header has it, "1700000000.123456789"
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.secs < 0 && self.nanos > 0 {
+            write!(f, "-{}.{:09}", -(self.secs + 1), NANOS_PER_SEC - self.nanos)
+        } else {
+            write!(f, "{}.{:09}", self.secs, self.nanos)
+        }
+    }
+}
+
+// Decimal seconds like "1700000000.123456789", read exactly where parsing
+// them as an f64 would round. Digits past the nanoseconds are dropped.
+impl FromStr for Timestamp {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (whole, fraction) = value.trim().split_once('.').unwrap_or((value.trim(), ""));
+        let negative = whole.starts_with('-');
+        let secs: i64 = whole.parse()?;
+        let digits = &fraction[..fraction.len().min(9)];
+        let nanos = if digits.is_empty() {
+            0
+        } else {
+            digits.parse::<u32>()? * 10u32.pow(9 - digits.len() as u32)
+        };
+        Ok(match (negative, nanos) {
+            (true, nanos) if nanos > 0 => Self::new(secs - 1, NANOS_PER_SEC - nanos),
+            _ => Self::new(secs, nanos),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Wire {
+            Parts {
+                secs: i64,
+                #[serde(default)]
+                nanos: u32,
+            },
+            Seconds(f64),
+            // Decimal seconds, RFC 3339 or an HTTP date
+            Text(String),
+            Other(IgnoredAny),
+        }
+
+        Ok(match Wire::deserialize(deserializer)? {
+            Wire::Parts { secs, nanos } => Self::new(secs, nanos),
+            Wire::Seconds(secs) => Self::from_secs_f64(secs),
+            Wire::Text(text) => match text.parse() {
+                Ok(time) => time,
+                Err(_) => match parse_rfc3339(&text).or_else(|| parse_http_date(&text)) {
+                    Some(secs) => Self::from_secs_f64(secs),
+                    None => {
+                        log::debug!("Unreadable time '{}', taken as unknown", text);
+                        Self::UNKNOWN
+                    }
+                },
+            },
+            Wire::Other(_) => Self::UNKNOWN,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(json: &str) -> Timestamp {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn nanoseconds_past_a_second_carry() {
+        assert_eq!(
+            Timestamp::new(5, 1_500_000_000),
+            Timestamp::new(6, 500_000_000)
+        );
+        assert_eq!(
+            Timestamp::from_secs_f64(1.25),
+            Timestamp::new(1, 250_000_000)
+        );
+        assert_eq!(
+            Timestamp::from_secs_f64(-0.25),
+            Timestamp::new(-1, 750_000_000)
+        );
+    }
+
+    #[test]
+    fn system_times_convert_without_floats() {
+        let times = [
+            UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789),
+            UNIX_EPOCH - Duration::new(10, 1),
+            UNIX_EPOCH - Duration::from_secs(10),
+            UNIX_EPOCH,
+        ];
+        for time in times {
+            assert_eq!(SystemTime::from(Timestamp::from(time)), time, "{:?}", time);
+        }
+        let before = Timestamp::from(UNIX_EPOCH - Duration::new(10, 1));
+        assert_eq!(before, Timestamp::new(-11, 999_999_999));
+    }
+
+    #[test]
+    fn decimal_seconds_round_trip_exactly() {
+        let time = Timestamp::new(1_700_000_000, 123_456_789);
+        assert_eq!(time.to_string(), "1700000000.123456789");
+        assert_eq!("1700000000.123456789".parse::<Timestamp>().unwrap(), time);
+        assert_eq!(Timestamp::new(-2, 750_000_000).to_string(), "-1.250000000");
+        assert_eq!(
+            "-1.25".parse::<Timestamp>().unwrap(),
+            Timestamp::new(-2, 750_000_000)
+        );
+        assert_eq!("12".parse::<Timestamp>().unwrap(), Timestamp::new(12, 0));
+        // Past the nanoseconds the digits are dropped, not rounded
+        let long = "1.1234567899".parse::<Timestamp>().unwrap();
+        assert_eq!(long, Timestamp::new(1, 123_456_789));
+        assert!("soon".parse::<Timestamp>().is_err());
+        assert!("1.5x".parse::<Timestamp>().is_err());
+    }
+
+    #[test]
+    fn parts_and_float_seconds_are_read() {
+        let time = Timestamp::new(1_700_000_000, 123_456_789);
+        let json = serde_json::to_string(&time).unwrap();
+        assert_eq!(json, r#"{"secs":1700000000,"nanos":123456789}"#);
+        assert_eq!(parsed(&json), time);
+        assert_eq!(parsed(r#"{"secs": 7}"#), Timestamp::new(7, 0));
+        // As older servers and disk caches have them
+        assert_eq!(parsed("1.5"), Timestamp::new(1, 500_000_000));
+        assert_eq!(parsed("1700000000"), Timestamp::new(1_700_000_000, 0));
+    }
+
+    #[test]
+    fn text_dates_are_read() {
+        let time = Timestamp::new(1_700_000_000, 500_000_000);
+        assert_eq!(parsed(r#""1700000000.5""#), time);
+        assert_eq!(parsed(r#""2023-11-14T22:13:20.5Z""#), time);
+        let date = parsed(r#""Tue, 14 Nov 2023 22:13:20 GMT""#);
+        assert_eq!(date, Timestamp::new(1_700_000_000, 0));
+    }
+
+    #[test]
+    fn missing_and_unreadable_times_are_unknown() {
+        for json in ["null", r#""last tuesday""#, "[1, 2]", r#"{"when": 5}"#] {
+            assert!(!parsed(json).is_known(), "{}", json);
+        }
+        assert!(Timestamp::from_secs_f64(f64::NAN) == Timestamp::UNKNOWN);
+        assert!(Timestamp::new(0, 0).is_known());
+
+        let entry = r#"{"name": "a", "is_dir": false, "size": 0, "mode": 420}"#;
+        let entry: crate::api_client::FileEntry = serde_json::from_str(entry).unwrap();
+        assert!(!entry.mtime.is_known());
+        assert!(!entry.ctime.is_known());
+    }
+}
@@ -0,0 +1,303 @@
+#[cfg(feature = "websocket")]
+mod transport {
+    use anyhow::{Context, Result};
+    use reqwest::StatusCode;
+    use serde::Deserialize;
+    use serde_json::json;
+    use std::io::ErrorKind;
+    use std::net::{TcpStream, ToSocketAddrs};
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+    use tungstenite::client::IntoClientRequest;
+    use tungstenite::http::header::AUTHORIZATION;
+    use tungstenite::http::HeaderValue;
+    use tungstenite::stream::MaybeTlsStream;
+    use tungstenite::{Error, HandshakeError, Message, WebSocket};
+
+    use super::super::{ApiClient, EventStream, ServerError, ServerEvent};
+
+    // How often the client pings, and how long the server may stay silent
+    const PING_INTERVAL: Duration = Duration::from_secs(15);
+    const PONG_TIMEOUT: Duration = Duration::from_secs(45);
+    // Reads wake up this often to send pings
+    const READ_POLL: Duration = Duration::from_secs(1);
+
+    // The fields every message from the server has, the rest is the change
+    #[derive(Deserialize)]
+    struct Frame {
+        #[serde(rename = "type")]
+        kind: String,
+        #[serde(default)]
+        id: Option<String>,
+    }
+
+    // http://host/api is reached as ws://host/api/ws
+    fn ws_url(base: &str) -> Result<String> {
+        let (scheme, rest) = base
+            .split_once("://")
+            .with_context(|| format!("Invalid server URL '{}'", base))?;
+        let scheme = match scheme {
+            "https" | "wss" => "wss",
+            "http" | "ws" => "ws",
+            other => anyhow::bail!("Unsupported scheme '{}' in '{}'", other, base),
+        };
+        Ok(format!("{}://{}/ws", scheme, rest.trim_end_matches('/')))
+    }
+
+    fn connect(host: &str, port: u16, timeout: Duration) -> Result<TcpStream> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let mut last_error = None;
+        for addr in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match last_error {
+            Some(e) => Err(anyhow::Error::new(e).context(format!("Failed to connect to {}", host))),
+            None => anyhow::bail!("{} has no address", host),
+        }
+    }
+
+    impl ApiClient {
+        pub fn ws_events(
+            &self,
+            prefixes: &[String],
+            last_event_id: Option<&str>,
+        ) -> Result<Option<EventStream>> {
+            if !self.capabilities().websocket {
+                return Ok(None);
+            }
+            let base = &self.config.base_urls[self.active.load(Ordering::Relaxed)];
+            let url = ws_url(base)?;
+            let mut request = url
+                .as_str()
+                .into_client_request()
+                .with_context(|| format!("Invalid WebSocket URL '{}'", url))?;
+            if let Some(auth) = &self.auth {
+                let auth = HeaderValue::from_bytes(auth.as_bytes())?;
+                request.headers_mut().insert(AUTHORIZATION, auth);
+            }
+            let host = request.uri().host().unwrap_or_default().to_string();
+            let default_port = if url.starts_with("wss:") { 443 } else { 80 };
+            let port = request.uri().port_u16().unwrap_or(default_port);
+
+            let stream = connect(&host, port, self.config.timeout)?;
+            stream.set_read_timeout(Some(self.config.timeout))?;
+            stream.set_write_timeout(Some(self.config.timeout))?;
+            // Kept to shorten the read timeout once the handshake is done
+            let tcp = stream.try_clone()?;
+            let mut socket = match tungstenite::client_tls(request, stream) {
+                Ok((socket, _)) => socket,
+                // Anything but an upgrade means there is no WebSocket endpoint
+                Err(HandshakeError::Failure(Error::Http(response))) => {
+                    let status = StatusCode::from_u16(response.status().as_u16())?;
+                    return match status {
+                        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                            Err(ServerError {
+                                status,
+                                maintenance: false,
+                            }
+                            .into())
+                        }
+                        _ if status.is_server_error() && status != StatusCode::NOT_IMPLEMENTED => {
+                            Err(ServerError {
+                                status,
+                                maintenance: false,
+                            }
+                            .into())
+                        }
+                        _ => Ok(None),
+                    };
+                }
+                Err(HandshakeError::Failure(e)) => {
+                    return Err(anyhow::Error::new(e).context("WebSocket handshake failed"))
+                }
+                Err(HandshakeError::Interrupted(_)) => {
+                    anyhow::bail!("WebSocket handshake timed out")
+                }
+            };
+
+            // Sent on every connection, so a reconnect subscribes again
+            let subscribe = json!({
+                "type": "subscribe",
+                "prefixes": prefixes,
+                "last_event_id": last_event_id,
+            });
+            socket
+                .send(Message::Text(subscribe.to_string().into()))
+                .context("Failed to subscribe to change notifications")?;
+            tcp.set_read_timeout(Some(READ_POLL))?;
+
+            Ok(Some(EventStream::from_events(WsReader {
+                socket,
+                last_ping: Instant::now(),
+                last_seen: Instant::now(),
+            })))
+        }
+    }
+
+    struct WsReader {
+        socket: WebSocket<MaybeTlsStream<TcpStream>>,
+        last_ping: Instant,
+        last_seen: Instant,
+    }
+
+    impl Iterator for WsReader {
+        type Item = Result<ServerEvent>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            loop {
+                if self.last_seen.elapsed() > PONG_TIMEOUT {
+                    return Some(Err(anyhow::anyhow!("WebSocket stopped answering pings")));
+                }
+                if self.last_ping.elapsed() >= PING_INTERVAL {
+                    self.last_ping = Instant::now();
+                    if let Err(e) = self.socket.send(Message::Ping(Vec::new().into())) {
+                        return Some(Err(anyhow::Error::new(e).context("WebSocket ping failed")));
+                    }
+                }
+
+                match self.socket.read() {
+                    Ok(Message::Text(text)) => {
+                        self.last_seen = Instant::now();
+                        let data = text.to_string();
+                        match serde_json::from_str::<Frame>(&data) {
+                            Ok(frame) => {
+                                return Some(Ok(ServerEvent {
+                                    id: frame.id,
+                                    event: frame.kind,
+                                    data,
+                                }))
+                            }
+                            Err(e) => log::warn!("Invalid WebSocket message '{}': {}", data, e),
+                        }
+                    }
+                    Ok(Message::Close(_)) => return None,
+                    // Pongs, and pings, which are answered on the next read
+                    Ok(_) => self.last_seen = Instant::now(),
+                    Err(Error::Io(e))
+                        if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                    Err(Error::ConnectionClosed | Error::AlreadyClosed) => return None,
+                    Err(e) => return Some(Err(anyhow::Error::new(e).context("WebSocket failed"))),
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+        use std::thread;
+
+        fn listen() -> (TcpListener, String) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            (listener, url)
+        }
+
+        // Answers the upgrade with a plain HTTP `status`
+        fn refuse(listener: TcpListener, status: u16) {
+            thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                while reader.read_line(&mut head).unwrap() > 2 {}
+                let response = format!("HTTP/1.1 {} -\r\nContent-Length: 0\r\n\r\n", status);
+                reader.into_inner().write_all(response.as_bytes()).unwrap();
+            });
+        }
+
+        #[test]
+        fn server_urls_become_websocket_urls() {
+            assert_eq!(ws_url("http://host:8080/api/").unwrap(), "ws://host:8080/api/ws");
+            assert_eq!(ws_url("https://host").unwrap(), "wss://host/ws");
+            assert!(ws_url("ftp://host").is_err());
+            assert!(ws_url("host").is_err());
+        }
+
+        #[test]
+        fn subscribes_and_reads_changes_until_closed() {
+            let (listener, url) = listen();
+            let served = thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let mut socket = tungstenite::accept(stream).unwrap();
+                let subscribe = socket.read().unwrap().into_text().unwrap().to_string();
+                let change = r#"{"type": "change", "id": "5", "path": "/a", "kind": "created"}"#;
+                socket.send(Message::Text(change.into())).unwrap();
+                socket.send(Message::Text("not json".into())).unwrap();
+                socket.close(None).unwrap();
+                while socket.read().is_ok() {}
+                subscribe
+            });
+
+            let client = ApiClient::new(url).unwrap();
+            let prefixes = ["/docs".to_string()];
+            let stream = client.ws_events(&prefixes, Some("4")).unwrap().unwrap();
+            let events: Vec<ServerEvent> = stream.map(Result::unwrap).collect();
+            assert_eq!(events.len(), 1);
+            assert_eq!((events[0].id.as_deref(), events[0].event.as_str()), (Some("5"), "change"));
+            let change = events[0].change().unwrap().unwrap();
+            assert_eq!(change.path, "/a");
+
+            let subscribe: serde_json::Value =
+                serde_json::from_str(&served.join().unwrap()).unwrap();
+            assert_eq!(
+                subscribe,
+                json!({"type": "subscribe", "prefixes": ["/docs"], "last_event_id": "4"})
+            );
+        }
+
+        #[test]
+        fn servers_without_the_endpoint_have_no_stream() {
+            let (listener, url) = listen();
+            refuse(listener, 404);
+            let client = ApiClient::new(url).unwrap();
+            assert!(client.ws_events(&[], None).unwrap().is_none());
+        }
+
+        #[test]
+        fn refused_credentials_are_errors() {
+            let (listener, url) = listen();
+            refuse(listener, 401);
+            let client = ApiClient::new(url).unwrap();
+            let error = client.ws_events(&[], None).err().unwrap();
+            let status = error.downcast_ref::<ServerError>().unwrap().status;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+    }
+}
+
+#[cfg(not(feature = "websocket"))]
+mod transport {
+    use anyhow::Result;
+
+    use super::super::{ApiClient, EventStream};
+
+    impl ApiClient {
+        // Without the websocket feature the server is never asked, and
+        // notifications fall back to the next transport
+        pub fn ws_events(
+            &self,
+            _prefixes: &[String],
+            _last_event_id: Option<&str>,
+        ) -> Result<Option<EventStream>> {
+            log::debug!("Built without WebSocket support");
+            Ok(None)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn the_server_is_never_asked() {
+            // Nothing listens there
+            let client = ApiClient::new("http://127.0.0.1:1".to_string()).unwrap();
+            assert!(client.ws_events(&[], None).unwrap().is_none());
+        }
+    }
+}
@@ -0,0 +1,880 @@
+use anyhow::{Context, Result};
+use clap::builder::PossibleValuesParser;
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use log::LevelFilter;
+use std::env;
+use std::path::{self, PathBuf};
+
+use crate::api_client::{parse_size, Secret};
+use crate::config::{ConfigFile, Mode, Profile, Size};
+use crate::{
+    BackendKind, ConflictMode, ControlRequest, DaemonConfig, FsConfig, LogFormat, MountConfig,
+    NotifyMode, OfflineMode, SortDirs, StaleHandles,
+};
+
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+// What the binary was asked to do
+#[derive(Debug)]
+pub enum Invocation {
+    // Serve the mountpoint until it is unmounted or a signal arrives
+    Mount {
+        config: Box<MountConfig>,
+        mountpoint: String,
+        // Fork into the background once mounted, as mount helpers do
+        daemon: Option<DaemonConfig>,
+        log_level: LevelFilter,
+    },
+    Unmount {
+        mountpoint: PathBuf,
+        lazy: bool,
+    },
+    // Report what the server supports. Read-only leaves it untouched.
+    Check {
+        config: Box<MountConfig>,
+        read_only: bool,
+        log_level: LevelFilter,
+    },
+    // Serve several profiles of the configuration file from one process
+    Supervise {
+        path: Option<PathBuf>,
+        // Every profile naming a mountpoint when empty
+        profiles: Vec<String>,
+        log_level: LevelFilter,
+    },
+    // Send a command to a mount's control socket and print the answer
+    Control {
+        socket: PathBuf,
+        request: ControlRequest,
+    },
+    // Check every profile of a configuration file without mounting
+    ValidateConfig {
+        path: PathBuf,
+    },
+}
+
+impl Invocation {
+    // The command line of the binary: `remotefs mount <URL> <MOUNTPOINT>`,
+    // `remotefs unmount <MOUNTPOINT>`, `remotefs check <URL>`,
+    // `remotefs supervise [PROFILE...]`, `remotefs ctl <SOCKET> <COMMAND>` and
+    // `remotefs config validate [FILE]`. Flags
+    // that cannot work together are refused here, before anything is read.
+    pub fn command() -> Command {
+        Command::new("remotefs")
+            .version(env!("CARGO_PKG_VERSION"))
+            .about("Mounts a remote file system through FUSE")
+            .subcommand_required(true)
+            .arg_required_else_help(true)
+            .arg(
+                Arg::new("log_level")
+                    .long("log-level")
+                    .value_name("LEVEL")
+                    .env("REMOTEFS_LOG")
+                    .default_value("info")
+                    .value_parser(PossibleValuesParser::new(LOG_LEVELS))
+                    .global(true)
+                    .help("Most verbose messages logged to stderr"),
+            )
+            .subcommand(mount_command())
+            .subcommand(
+                Command::new("unmount")
+                    .about("Unmounts a remotefs mount, reporting what keeps it busy")
+                    .arg(
+                        Arg::new("mountpoint")
+                            .value_name("MOUNTPOINT")
+                            .required(true)
+                            .value_parser(value_parser!(PathBuf))
+                            .help("Where the file system is mounted"),
+                    )
+                    .arg(
+                        Arg::new("lazy")
+                            .short('z')
+                            .long("lazy")
+                            .action(ArgAction::SetTrue)
+                            .help("Detach the mount right away, even while it is in use"),
+                    ),
+            )
+            .subcommand(
+                Command::new("check")
+                    .about("Reports which capabilities the server offers, without mounting")
+                    .arg(url_arg())
+                    .args(connection_args())
+                    .arg(
+                        Arg::new("read_only")
+                            .long("read-only")
+                            .action(ArgAction::SetTrue)
+                            .help("Skip the probes that write to the server"),
+                    ),
+            )
+            .subcommand(
+                Command::new("supervise")
+                    .about("Mounts several profiles of the configuration file from one process")
+                    .arg(
+                        Arg::new("config")
+                            .short('c')
+                            .long("config")
+                            .value_name("FILE")
+                            .value_parser(value_parser!(PathBuf))
+                            .help("Configuration file, instead of ~/.config/remotefs/config.toml"),
+                    )
+                    .arg(
+                        Arg::new("profiles")
+                            .value_name("PROFILE")
+                            .action(ArgAction::Append)
+                            .help("Profiles to mount, every one naming a mountpoint by default"),
+                    ),
+            )
+            .subcommand(
+                Command::new("ctl")
+                    .about("Sends a command to a mount through its control socket")
+                    .arg(
+                        Arg::new("socket")
+                            .value_name("SOCKET")
+                            .required(true)
+                            .value_parser(value_parser!(PathBuf))
+                            .help("The mount's control_socket"),
+                    )
+                    .arg(
+                        Arg::new("command")
+                            .value_name("COMMAND")
+                            .required(true)
+                            .num_args(1..)
+                            .help(
+                                "stats, drop-caches [path], flush, set-log-level <level> or \
+                                 reload-config",
+                            ),
+                    ),
+            )
+            .subcommand(
+                Command::new("config")
+                    .about("Works with the configuration file")
+                    .subcommand_required(true)
+                    .subcommand(
+                        Command::new("validate")
+                            .about("Checks every profile of the configuration file")
+                            .arg(
+                                Arg::new("file")
+                                    .value_name("FILE")
+                                    .value_parser(value_parser!(PathBuf))
+                                    .help("File to check, instead of the one mount would read"),
+                            ),
+                    ),
+            )
+    }
+
+    // Resolves the configuration the way MountConfig::load does, with the
+    // flags applied last
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let log_level = matches
+            .get_one::<String>("log_level")
+            .map_or(Ok(LevelFilter::Info), |level| log_level(level))?;
+
+        match matches.subcommand() {
+            Some(("mount", matches)) => {
+                let config = load(matches)?;
+                // A daemon runs from /
+                let mountpoint = absolute(matches.get_one::<String>("mountpoint").unwrap())?;
+                let daemon = matches.get_flag("daemon").then(|| DaemonConfig {
+                    log_file: matches.get_one::<PathBuf>("log_file").cloned(),
+                    pidfile: matches.get_one::<PathBuf>("pidfile").cloned(),
+                });
+                Ok(Self::Mount {
+                    config: Box::new(config),
+                    mountpoint,
+                    daemon,
+                    log_level,
+                })
+            }
+            Some(("unmount", matches)) => Ok(Self::Unmount {
+                mountpoint: matches.get_one::<PathBuf>("mountpoint").unwrap().clone(),
+                lazy: matches.get_flag("lazy"),
+            }),
+            Some(("check", matches)) => Ok(Self::Check {
+                config: Box::new(load(matches)?),
+                read_only: matches.get_flag("read_only"),
+                log_level,
+            }),
+            Some(("supervise", matches)) => Ok(Self::Supervise {
+                path: matches.get_one::<PathBuf>("config").cloned(),
+                profiles: matches
+                    .get_many::<String>("profiles")
+                    .map(|names| names.cloned().collect())
+                    .unwrap_or_default(),
+                log_level,
+            }),
+            Some(("ctl", matches)) => {
+                let words: Vec<&String> = matches.get_many("command").unwrap().collect();
+                Ok(Self::Control {
+                    socket: matches.get_one::<PathBuf>("socket").unwrap().clone(),
+                    request: ControlRequest::parse(&words)?,
+                })
+            }
+            Some(("config", matches)) => match matches.subcommand() {
+                Some(("validate", matches)) => {
+                    let path = matches.get_one::<PathBuf>("file").cloned();
+                    let path = path
+                        .or_else(|| env::var_os("REMOTEFS_CONFIG").map(PathBuf::from))
+                        .or_else(ConfigFile::default_path)
+                        .context("No configuration file to validate")?;
+                    Ok(Self::ValidateConfig { path })
+                }
+                _ => anyhow::bail!("Expected config validate"),
+            },
+            _ => anyhow::bail!("Expected mount, unmount, check, supervise, ctl or config"),
+        }
+    }
+
+    // Serves `mount -t remotefs`, which runs mount.remotefs with the
+    // arguments of MountConfig::from_mount_helper. The mount goes into the
+    // background once it is up, as mount(8) waits for the helper to exit.
+    pub fn from_mount_helper<I, S>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let (config, mountpoint) = MountConfig::from_mount_helper(args)?;
+        let log_level = match env::var("REMOTEFS_LOG") {
+            Ok(level) => log_level(&level).context("Invalid REMOTEFS_LOG")?,
+            Err(_) => LevelFilter::Info,
+        };
+        Ok(Self::Mount {
+            config: Box::new(config),
+            mountpoint: absolute(&mountpoint)?,
+            daemon: Some(DaemonConfig::default()),
+            log_level,
+        })
+    }
+}
+
+fn mount_command() -> Command {
+    let flag = |id: &'static str, help: &'static str| {
+        Arg::new(id)
+            .long(id.replace('_', "-"))
+            .action(ArgAction::SetTrue)
+            .help(help)
+    };
+    let value = |id: &'static str, name: &'static str, help: &'static str| {
+        Arg::new(id)
+            .long(id.replace('_', "-"))
+            .value_name(name)
+            .help(help)
+    };
+    let seconds = |id: &'static str, help: &'static str| {
+        value(id, "SECS", help).value_parser(value_parser!(f64))
+    };
+    let size = |id: &'static str, help: &'static str| {
+        value(id, "SIZE", help).value_parser(size_arg)
+    };
+    let count = |id: &'static str, help: &'static str| {
+        value(id, "N", help).value_parser(value_parser!(usize))
+    };
+    let mode = |id: &'static str, help: &'static str| {
+        value(id, "MODE", help).value_parser(mode_arg)
+    };
+
+    Command::new("mount")
+        .about("Mounts the server and serves it until unmounted or signaled")
+        .after_help(
+            "Flags win over the profile, which wins over the defaults. Sizes take a K, M or G \
+             suffix. Options not listed here are read from the configuration file and \
+             REMOTEFS_<KEY> variables.",
+        )
+        .arg(url_arg())
+        .arg(
+            Arg::new("mountpoint")
+                .value_name("MOUNTPOINT")
+                .required(true)
+                .help("Existing directory to mount on"),
+        )
+        .args(connection_args())
+        .arg(
+            value("backend", "BACKEND", "rest, webdav, s3, sftp, grpc or local:<dir>")
+                .value_parser(|value: &str| BackendKind::parse(value)),
+        )
+        .arg(
+            Arg::new("options")
+                .short('o')
+                .value_name("OPTIONS")
+                .action(ArgAction::Append)
+                .help("Comma-separated mount options, like allow_other or fsname=work"),
+        )
+        .arg(
+            flag("read_only", "Refuse every change with EROFS")
+                .conflicts_with_all(["preload_data", "offline_writes", "journal"]),
+        )
+        .arg(flag("allow_other", "Let other users access the mount").conflicts_with("allow_root"))
+        .arg(flag("allow_root", "Let root access the mount"))
+        .arg(flag(
+            "default_permissions",
+            "Have the kernel check the presented permissions",
+        ))
+        .arg(
+            value("uid", "UID", "Owner of entries the server reports none for")
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(
+            value("gid", "GID", "Group of entries the server reports none for")
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(flag("local_owner", "Ignore the owner the server reports"))
+        .arg(
+            flag("map_remote_ids", "Deprecated, the server's owner is shown by default")
+                .conflicts_with("local_owner")
+                .hide(true),
+        )
+        .arg(seconds("mount_timeout", "How long to wait for the server to answer"))
+        .arg(
+            value("mount_retries", "N", "Attempts to reach the server before giving up")
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(count("max_concurrent", "Requests to the server in flight at once"))
+        .arg(
+            value("max_rps", "RATE", "Requests to the server per second at most")
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(flag("http2_prior_knowledge", "Speak HTTP/2 to the server without upgrading"))
+        .arg(flag("ssh_insecure", "Accept any SFTP host key, for test servers only"))
+        .arg(count("max_concurrent_ops", "FUSE operations served at once"))
+        .arg(size("chunk_size", "Size of the ranges files are read in"))
+        .arg(seconds("attr_timeout", "How long attributes are cached"))
+        .arg(seconds("negative_timeout", "How long missing names are remembered"))
+        .arg(seconds("listing_timeout", "How long directory listings are cached"))
+        .arg(size("max_data_bytes", "Memory for cached file contents"))
+        .arg(count("max_inodes", "Inodes kept in memory before unused ones are dropped"))
+        .arg(
+            value("cache_dir", "DIR", "Keep a persistent cache here")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(size("max_disk_bytes", "Disk space for the persistent cache"))
+        .arg(
+            value("preload", "PATH", "Walk this subtree after mounting, may be repeated")
+                .action(ArgAction::Append),
+        )
+        .arg(size(
+            "preload_data",
+            "Also cache the contents of preloaded files up to this size",
+        ))
+        .arg(
+            value("offline_mode", "MODE", "auto serves cached data while offline, off fails")
+                .value_parser(|value: &str| OfflineMode::parse(value)),
+        )
+        .arg(flag(
+            "offline_writes",
+            "Queue changes made offline under --cache-dir, needs --offline-mode auto",
+        ))
+        .arg(flag(
+            "journal",
+            "Journal buffered writes under --cache-dir to upload them after a crash",
+        ))
+        .arg(
+            value("exclude", "GLOB", "Hide matching entries, may be repeated")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            value("include", "GLOB", "Show matching entries even if excluded, may be repeated")
+                .action(ArgAction::Append),
+        )
+        .arg(mode("file_mode", "Permissions shown for files instead of the server's"))
+        .arg(mode("dir_mode", "Permissions shown for directories instead of the server's"))
+        .arg(mode("umask", "Bits cleared from the server's permissions, like 022"))
+        .arg(size("blksize", "I/O size presented in st_blksize"))
+        .arg(flag("casefold", "Match names against listings ignoring case"))
+        .arg(
+            value("sort_dirs", "ORDER", "none, name or name-ci")
+                .value_parser(|value: &str| SortDirs::parse(value)),
+        )
+        .arg(
+            value("on_conflict", "MODE", "conflict-copy or error, when a file changed remotely")
+                .value_parser(|value: &str| ConflictMode::parse(value)),
+        )
+        .arg(
+            value("stale_handles", "POLICY", "error or refresh, for files replaced remotely")
+                .value_parser(|value: &str| StaleHandles::parse(value)),
+        )
+        .arg(
+            value("notify", "MODE", "ws, sse, poll or off, how remote changes are learned")
+                .value_parser(|value: &str| NotifyMode::parse(value)),
+        )
+        .arg(flag("show_stats_file", "Show statistics in a .remotefs-stats file at the root"))
+        .arg(flag("trace_ops", "Log every FUSE operation with its duration"))
+        .arg(
+            value("slow_op_ms", "MS", "Log operations taking longer than this")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(value("metrics_addr", "ADDR", "Serve Prometheus metrics here, like 127.0.0.1:9600"))
+        .arg(
+            value("control_socket", "PATH", "Unix socket taking control requests")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            value("log_format", "FORMAT", "text or json")
+                .value_parser(|value: &str| LogFormat::parse(value)),
+        )
+        .arg(
+            Arg::new("daemon")
+                .short('d')
+                .long("daemon")
+                .action(ArgAction::SetTrue)
+                .help("Go into the background once mounted"),
+        )
+        .arg(
+            value("log_file", "FILE", "Where a daemon appends its log, discarded otherwise")
+                .value_parser(value_parser!(PathBuf))
+                .requires("daemon"),
+        )
+        .arg(
+            value("pidfile", "FILE", "Where a daemon writes its process id")
+                .value_parser(value_parser!(PathBuf))
+                .requires("daemon"),
+        )
+}
+
+fn url_arg() -> Arg {
+    Arg::new("url")
+        .value_name("URL")
+        .required(true)
+        .help("Server to connect to, or a comma-separated list to fail over between")
+}
+
+// How to reach the server, shared by mount and check
+fn connection_args() -> [Arg; 6] {
+    [
+        Arg::new("config")
+            .short('c')
+            .long("config")
+            .value_name("FILE")
+            .value_parser(value_parser!(PathBuf))
+            .help("Configuration file, instead of ~/.config/remotefs/config.toml"),
+        Arg::new("profile")
+            .short('p')
+            .long("profile")
+            .value_name("NAME")
+            .help("Profile of the configuration file to start from"),
+        Arg::new("token")
+            .long("token")
+            .value_name("TOKEN")
+            .env("REMOTEFS_TOKEN")
+            .hide_env_values(true)
+            .help("Bearer token sent with every request"),
+        Arg::new("ca_cert")
+            .long("ca-cert")
+            .value_name("FILE")
+            .help("PEM certificates to trust next to the system's"),
+        Arg::new("tls_insecure")
+            .long("insecure")
+            .action(ArgAction::SetTrue)
+            .help("Accept any TLS certificate, for test servers only"),
+        Arg::new("timeout")
+            .long("timeout")
+            .value_name("SECS")
+            .value_parser(value_parser!(f64))
+            .help("Timeout of each request to the server"),
+    ]
+}
+
+fn log_level(value: &str) -> Result<LevelFilter> {
+    value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Unknown log level '{}'", value))
+}
+
+// Sizes are checked as they are parsed, and applied by the profile
+fn size_arg(value: &str) -> Result<String> {
+    parse_size(value)?;
+    Ok(value.to_string())
+}
+
+// Like sizes, octal permissions are applied by the profile
+fn mode_arg(value: &str) -> Result<String> {
+    FsConfig::parse_mode(value)?;
+    Ok(value.to_string())
+}
+
+fn absolute(path: &str) -> Result<String> {
+    let absolute = path::absolute(path).with_context(|| format!("Invalid path {}", path))?;
+    Ok(absolute.to_string_lossy().into_owned())
+}
+
+// The configuration file, profile and environment with the flags of
+// `matches` on top, as one more profile
+fn load(matches: &ArgMatches) -> Result<MountConfig> {
+    let mut config = MountConfig::load(
+        matches.get_one::<PathBuf>("config").map(PathBuf::as_path),
+        matches.get_one::<String>("profile").map(String::as_str),
+    )?;
+    flags(matches)
+        .apply(&mut config)
+        .context("Invalid command line")?;
+    config.fs.validate()?;
+    Ok(config)
+}
+
+// Flags that were given, the others leave the profile's values alone
+fn flags(matches: &ArgMatches) -> Profile {
+    // Values of the types a value_parser produced
+    fn parsed<T: Copy + Send + Sync + 'static>(matches: &ArgMatches, id: &str) -> Option<T> {
+        matches.try_get_one::<T>(id).ok().flatten().copied()
+    }
+    let string = |id: &str| matches.try_get_one::<String>(id).ok().flatten().cloned();
+    let strings = |id: &str| {
+        let values = matches.try_get_many::<String>(id).ok().flatten()?;
+        Some(values.cloned().collect())
+    };
+    let path = |id: &str| {
+        let path = matches.try_get_one::<PathBuf>(id).ok().flatten()?;
+        Some(path.to_string_lossy().into_owned())
+    };
+    let set = |id: &str| {
+        let set = matches.try_get_one::<bool>(id).ok().flatten().copied();
+        set.filter(|set| *set)
+    };
+    let seconds = |id: &str| matches.try_get_one::<f64>(id).ok().flatten().copied();
+    let count = |id: &str| matches.try_get_one::<usize>(id).ok().flatten().copied();
+    let id = |id: &str| matches.try_get_one::<u32>(id).ok().flatten().copied();
+    let size = |id: &str| string(id).map(Size::Text);
+    let mode = |id: &str| string(id).map(Mode::Octal);
+
+    Profile {
+        server: string("url").map(|url| vec![url]),
+        backend: matches
+            .try_get_one::<BackendKind>("backend")
+            .ok()
+            .flatten()
+            .cloned(),
+        token: string("token").map(Secret::new),
+        log_format: parsed(matches, "log_format"),
+        options: strings("options"),
+        mount_timeout: seconds("mount_timeout"),
+        mount_retries: parsed(matches, "mount_retries"),
+        timeout: seconds("timeout"),
+        max_concurrent: count("max_concurrent"),
+        max_rps: parsed(matches, "max_rps"),
+        chunk_size: size("chunk_size"),
+        http2_prior_knowledge: set("http2_prior_knowledge"),
+        ca_cert: string("ca_cert"),
+        tls_insecure: set("tls_insecure"),
+        ssh_insecure: set("ssh_insecure"),
+        attr_timeout: seconds("attr_timeout"),
+        negative_timeout: seconds("negative_timeout"),
+        listing_timeout: seconds("listing_timeout"),
+        max_inodes: count("max_inodes"),
+        max_data_bytes: size("max_data_bytes"),
+        cache_dir: path("cache_dir"),
+        max_disk_bytes: size("max_disk_bytes"),
+        preload: strings("preload"),
+        preload_data_max: size("preload_data"),
+        max_concurrent_ops: count("max_concurrent_ops"),
+        read_only: set("read_only"),
+        uid: id("uid"),
+        gid: id("gid"),
+        local_owner: set("local_owner"),
+        map_remote_ids: set("map_remote_ids"),
+        file_mode: mode("file_mode"),
+        dir_mode: mode("dir_mode"),
+        umask: mode("umask"),
+        blksize: size("blksize"),
+        casefold: set("casefold"),
+        exclude: strings("exclude"),
+        include: strings("include"),
+        sort_dirs: parsed(matches, "sort_dirs"),
+        allow_other: set("allow_other"),
+        allow_root: set("allow_root"),
+        default_permissions: set("default_permissions"),
+        trace_ops: set("trace_ops"),
+        slow_op_ms: parsed(matches, "slow_op_ms"),
+        metrics_addr: string("metrics_addr"),
+        show_stats_file: set("show_stats_file"),
+        control_socket: path("control_socket"),
+        offline_mode: parsed(matches, "offline_mode"),
+        offline_writes: set("offline_writes"),
+        journal: set("journal"),
+        on_conflict: parsed(matches, "on_conflict"),
+        stale_handles: parsed(matches, "stale_handles"),
+        notify: parsed(matches, "notify"),
+        ..Profile::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+    use std::io::Write;
+
+    fn parse(args: &[&str]) -> Result<ArgMatches, clap::Error> {
+        Invocation::command().try_get_matches_from(["remotefs"].iter().chain(args))
+    }
+
+    fn error(args: &[&str]) -> ErrorKind {
+        parse(args).unwrap_err().kind()
+    }
+
+    // Resolved against an empty configuration file, not the user's
+    fn resolve(args: &[&str]) -> Result<Invocation> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"").unwrap();
+        let config = file.path().to_str().unwrap();
+        let (command, rest) = args.split_first().unwrap();
+        let mut args = vec![*command, "--config", config];
+        args.extend(rest);
+        Invocation::from_matches(&parse(&args).unwrap())
+    }
+
+    fn mounted(args: &[&str]) -> (MountConfig, String) {
+        match resolve(args).unwrap() {
+            Invocation::Mount {
+                config, mountpoint, ..
+            } => (*config, mountpoint),
+            other => panic!("not a mount: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn every_option_is_documented() {
+        let command = Invocation::command();
+        command.clone().debug_assert();
+        for sub in command.get_subcommands() {
+            assert!(sub.get_about().is_some(), "{}", sub.get_name());
+            for arg in sub.get_arguments() {
+                let documented = arg.get_help().is_some() || arg.get_id() == "help";
+                assert!(documented, "{} {}", sub.get_name(), arg.get_id());
+            }
+        }
+    }
+
+    #[test]
+    fn mount_takes_a_server_and_a_mountpoint() {
+        let (config, mountpoint) = mounted(&["mount", "https://files.example.com/", "/mnt/r"]);
+        assert_eq!(config.client.base_urls, ["https://files.example.com"]);
+        assert_eq!(mountpoint, "/mnt/r");
+        assert!(!config.fs.read_only);
+
+        let missing = ErrorKind::MissingRequiredArgument;
+        assert_eq!(error(&["mount", "https://files.example.com"]), missing);
+        assert_eq!(error(&[]), ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand);
+        assert_eq!(error(&["serve"]), ErrorKind::InvalidSubcommand);
+    }
+
+    #[test]
+    fn mount_flags_reach_the_configuration() {
+        let (config, _) = mounted(&[
+            "mount",
+            "http://a,http://b",
+            "/mnt/r",
+            "--read-only",
+            "--allow-other",
+            "--uid=1000",
+            "--attr-timeout=2.5",
+            "--max-data-bytes=64M",
+            "--chunk-size=1M",
+            "--max-concurrent-ops=8",
+            "--timeout=10",
+            "--token=secret",
+            "--insecure",
+            "--exclude=*.tmp",
+            "--exclude=.git",
+            "-o",
+            "fsname=work,noatime",
+        ]);
+        assert_eq!(config.client.base_urls, ["http://a", "http://b"]);
+        assert!(config.fs.read_only);
+        assert!(config.fs.allow_other);
+        assert_eq!(config.fs.uid, Some(1000));
+        assert_eq!(config.fs.cache.attr_timeout.as_secs_f64(), 2.5);
+        assert_eq!(config.fs.cache.max_data_bytes, 64 << 20);
+        assert_eq!(config.client.chunk_size, 1 << 20);
+        assert_eq!(config.fs.max_concurrent_ops, 8);
+        assert_eq!(config.client.timeout.as_secs(), 10);
+        assert_eq!(config.client.token.unwrap().expose(), "secret");
+        assert!(config.client.http.tls_insecure);
+        assert_eq!(config.fs.exclude.len(), 2);
+        assert_eq!(config.options.len(), 2);
+    }
+
+    #[test]
+    fn tuning_flags_reach_the_configuration() {
+        let (config, _) = mounted(&[
+            "mount",
+            "http://a",
+            "/mnt/r",
+            "--max-rps=50",
+            "--mount-retries=3",
+            "--http2-prior-knowledge",
+            "--ssh-insecure",
+            "--max-inodes=1000",
+            "--file-mode=0640",
+            "--dir-mode=750",
+            "--umask=027",
+            "--blksize=64K",
+            "--casefold",
+            "--sort-dirs=name-ci",
+            "--on-conflict=error",
+            "--stale-handles=refresh",
+            "--notify=poll",
+            "--show-stats-file",
+            "--trace-ops",
+            "--slow-op-ms=250",
+            "--metrics-addr=127.0.0.1:9600",
+            "--control-socket=/run/r.sock",
+        ]);
+        assert_eq!(config.client.max_rps, Some(50.0));
+        assert_eq!(config.startup.retries, 3);
+        assert!(config.client.http.http2_prior_knowledge);
+        assert!(config.sftp.insecure);
+        assert_eq!(config.fs.cache.max_inodes, Some(1000));
+        assert_eq!(config.fs.file_mode, Some(0o640));
+        assert_eq!(config.fs.dir_mode, Some(0o750));
+        assert_eq!(config.fs.umask, 0o027);
+        assert_eq!(config.fs.blksize, 64 << 10);
+        assert!(config.fs.casefold);
+        assert_eq!(config.fs.sort_dirs, SortDirs::parse("name-ci").unwrap());
+        assert_eq!(config.fs.on_conflict, ConflictMode::parse("error").unwrap());
+        assert_eq!(config.fs.stale_handles, StaleHandles::parse("refresh").unwrap());
+        assert_eq!(config.fs.notify, NotifyMode::Poll);
+        assert!(config.fs.show_stats_file);
+        assert!(config.fs.trace_ops);
+        assert_eq!(config.fs.slow_op, Some(std::time::Duration::from_millis(250)));
+        assert_eq!(config.fs.metrics_addr.unwrap().port(), 9600);
+        assert_eq!(config.fs.control_socket, Some(PathBuf::from("/run/r.sock")));
+
+        let mount = |flag: &'static str| error(&["mount", "http://a", "/mnt/r", flag]);
+        assert_eq!(mount("--umask=999"), ErrorKind::ValueValidation);
+        assert_eq!(mount("--notify=carrier-pigeon"), ErrorKind::ValueValidation);
+        assert_eq!(mount("--max-inodes=-1"), ErrorKind::ValueValidation);
+    }
+
+    // A flag replaces the value of the profile, the profile's others stay
+    #[test]
+    fn flags_win_over_the_profile() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"[profiles.default]\nnotify = \"sse\"\numask = \"077\"\n").unwrap();
+        let config = file.path().to_str().unwrap();
+        let args = ["mount", "--config", config, "http://a", "/mnt/r", "--notify=off"];
+        match Invocation::from_matches(&parse(&args).unwrap()).unwrap() {
+            Invocation::Mount { config, .. } => {
+                assert_eq!(config.fs.notify, NotifyMode::Off);
+                assert_eq!(config.fs.umask, 0o077);
+            }
+            other => panic!("not a mount: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn map_remote_ids_is_a_deprecated_alias() {
+        let (config, _) = mounted(&["mount", "http://a", "/mnt/r", "--map-remote-ids"]);
+        assert!(!config.fs.local_owner);
+        let both = ["mount", "http://a", "/mnt/r", "--map-remote-ids", "--local-owner"];
+        assert_eq!(error(&both), ErrorKind::ArgumentConflict);
+
+        // Turned off, it presented the mount's own user, which local_owner does now
+        let (mut config, _) = mounted(&["mount", "http://a", "/mnt/r"]);
+        let off = Profile {
+            map_remote_ids: Some(false),
+            ..Profile::default()
+        };
+        off.apply(&mut config).unwrap();
+        assert!(config.fs.local_owner);
+        let overridden = Profile {
+            local_owner: Some(false),
+            ..off
+        };
+        overridden.apply(&mut config).unwrap();
+        assert!(!config.fs.local_owner);
+    }
+
+    #[test]
+    fn options_that_cannot_work_together_are_refused() {
+        let mount = |flags: &[&'static str]| {
+            let mut args = vec!["mount", "http://a", "/mnt/r"];
+            args.extend(flags);
+            error(&args)
+        };
+        let conflict = ErrorKind::ArgumentConflict;
+        assert_eq!(mount(&["--read-only", "--preload=/", "--preload-data=1M"]), conflict);
+        assert_eq!(mount(&["--read-only", "--journal", "--cache-dir=/c"]), conflict);
+        assert_eq!(mount(&["--allow-other", "--allow-root"]), conflict);
+        assert_eq!(mount(&["--log-file=/l"]), ErrorKind::MissingRequiredArgument);
+        assert_eq!(mount(&["--chunk-size=lots"]), ErrorKind::ValueValidation);
+        assert_eq!(mount(&["--backend=ftp"]), ErrorKind::ValueValidation);
+        assert_eq!(mount(&["--uid=-1"]), ErrorKind::ValueValidation);
+        assert_eq!(mount(&["--log-level=loud"]), ErrorKind::InvalidValue);
+        assert_eq!(mount(&["--read-write"]), ErrorKind::UnknownArgument);
+
+        // Only known once the profile is applied
+        let e = resolve(&["mount", "http://a", "/mnt/r", "--journal"]).unwrap_err();
+        assert!(format!("{:#}", e).contains("journal needs a cache_dir"), "{:#}", e);
+        let e = resolve(&["mount", "http://a", "/mnt/r", "--preload-data=1M"]).unwrap_err();
+        assert!(format!("{:#}", e).contains("nothing to preload"), "{:#}", e);
+    }
+
+    #[test]
+    fn unmount_and_check() {
+        match resolve(&["check", "http://a", "--read-only"]).unwrap() {
+            Invocation::Check {
+                config, read_only, ..
+            } => {
+                assert_eq!(config.client.base_urls, ["http://a"]);
+                assert!(read_only);
+            }
+            other => panic!("not a check: {:?}", other),
+        }
+        // Mount flags are not check flags
+        assert_eq!(error(&["check", "http://a", "--journal"]), ErrorKind::UnknownArgument);
+
+        let matches = parse(&["unmount", "-z", "/mnt/r"]).unwrap();
+        match Invocation::from_matches(&matches).unwrap() {
+            Invocation::Unmount { mountpoint, lazy } => {
+                assert_eq!(mountpoint, PathBuf::from("/mnt/r"));
+                assert!(lazy);
+            }
+            other => panic!("not an unmount: {:?}", other),
+        }
+        assert_eq!(error(&["unmount"]), ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn supervise_takes_profiles() {
+        let matches = parse(&["supervise", "-c", "/etc/r.toml", "work", "home"]).unwrap();
+        match Invocation::from_matches(&matches).unwrap() {
+            Invocation::Supervise { path, profiles, .. } => {
+                assert_eq!(path, Some(PathBuf::from("/etc/r.toml")));
+                assert_eq!(profiles, ["work", "home"]);
+            }
+            other => panic!("not a supervisor: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ctl_takes_a_socket_and_a_command() {
+        let matches = parse(&["ctl", "/run/r.sock", "drop-caches", "/docs"]).unwrap();
+        match Invocation::from_matches(&matches).unwrap() {
+            Invocation::Control { socket, request } => {
+                assert_eq!(socket, PathBuf::from("/run/r.sock"));
+                let path = Some("/docs".to_string());
+                assert_eq!(request, ControlRequest::DropCaches { path });
+            }
+            other => panic!("not a control command: {:?}", other),
+        }
+        let matches = parse(&["ctl", "/run/r.sock", "reboot"]).unwrap();
+        assert!(Invocation::from_matches(&matches).is_err());
+        assert_eq!(error(&["ctl", "/run/r.sock"]), ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn config_validate_takes_a_file() {
+        let matches = parse(&["config", "validate", "/etc/r.toml"]).unwrap();
+        match Invocation::from_matches(&matches) {
+            Ok(Invocation::ValidateConfig { path }) => {
+                assert_eq!(path, PathBuf::from("/etc/r.toml"))
+            }
+            other => panic!("not a validation: {:?}", other),
+        }
+        assert_eq!(error(&["config"]), ErrorKind::MissingSubcommand);
+    }
+
+    #[test]
+    fn log_level_is_global() {
+        let matches = parse(&["unmount", "/mnt/r", "--log-level", "debug"]).unwrap();
+        assert_eq!(matches.get_one::<String>("log_level").unwrap(), "debug");
+    }
+}
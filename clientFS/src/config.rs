@@ -0,0 +1,1176 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::api_client::{parse_size, ClientConfig, Secret};
+use crate::{
+    BackendKind, ConflictMode, Faults, FsConfig, Glob, LogFormat, MountConfig, NotifyMode,
+    OfflineMode, SortDirs, StaleHandles, Watch,
+};
+
+const USER_CONFIG: &str = ".config/remotefs/config.toml";
+const SYSTEM_CONFIG: &str = "/etc/remotefs.toml";
+const DEFAULT_PROFILE: &str = "default";
+
+const ENV_PREFIX: &str = "REMOTEFS_";
+// Pick the file and profile, or are read by the binary itself
+const ENV_RESERVED: [&str; 3] = ["LOG", "CONFIG", "PROFILE"];
+// Comma-separated lists, and strings that must not be taken for numbers
+const ENV_LISTS: [&str; 6] = ["server", "options", "preload", "watch", "exclude", "include"];
+const ENV_STRINGS: [&str; 10] = [
+    "token",
+    "ca_cert",
+    "mountpoint",
+    "cache_dir",
+    "spill_dir",
+    "uid_map",
+    "gid_map",
+    "file_mode",
+    "dir_mode",
+    "umask",
+];
+
+// Named mount profiles read from a TOML file, for example
+//
+//     [profiles.work]
+//     server = ["https://files.example.com"]
+//     mountpoint = "/mnt/work"
+//     chunk_size = "4M"
+//     attr_timeout = 5
+//
+// Every key is optional and unknown keys are rejected. String values may
+// refer to environment variables as ${NAME}.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+// Permissions, either a TOML integer like 0o644 or an octal string like "0644"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Mode {
+    Bits(u32),
+    Octal(String),
+}
+
+// Byte counts, either a plain number or a string like "512K"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+// Keys are named after the fields of ClientConfig, CacheConfig and FsConfig.
+// Durations are in seconds.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub server: Option<Vec<String>>,
+    // "rest", "webdav", "s3", "sftp", "grpc" or "local:<dir>"
+    pub backend: Option<BackendKind>,
+    pub token: Option<Secret>,
+    pub mountpoint: Option<String>,
+    // "text" or "json"
+    pub log_format: Option<LogFormat>,
+    // Entries of a `-o` list, like "allow_other" or "fsname=work"
+    pub options: Option<Vec<String>>,
+    pub mount_timeout: Option<f64>,
+    pub mount_retries: Option<u32>,
+
+    pub timeout: Option<f64>,
+    pub failover_threshold: Option<u32>,
+    pub failback_interval: Option<f64>,
+    pub breaker_threshold: Option<u32>,
+    pub breaker_cool_down: Option<f64>,
+    pub max_concurrent: Option<usize>,
+    pub max_rps: Option<f64>,
+    pub chunk_size: Option<Size>,
+    pub max_parts_per_read: Option<usize>,
+    pub otel: Option<bool>,
+    pub http2_prior_knowledge: Option<bool>,
+    pub pool_max_idle_per_host: Option<usize>,
+    // Zero turns either off: idle connections are kept, no probes sent
+    pub pool_idle_timeout: Option<f64>,
+    pub tcp_keepalive: Option<f64>,
+    pub tcp_nodelay: Option<bool>,
+    // PEM file of extra trusted certificates
+    pub ca_cert: Option<String>,
+    pub tls_insecure: Option<bool>,
+
+    pub s3_region: Option<String>,
+    pub s3_endpoint: Option<String>,
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<Secret>,
+    pub s3_session_token: Option<Secret>,
+    // Profile of the AWS shared credentials file
+    pub s3_profile: Option<String>,
+    pub multipart_threshold: Option<Size>,
+
+    pub ssh_identity_file: Option<String>,
+    pub ssh_password: Option<Secret>,
+    pub ssh_known_hosts: Option<String>,
+    pub ssh_insecure: Option<bool>,
+    pub ssh_sessions: Option<usize>,
+
+    // Faults injected by the local backend
+    pub fault_latency: Option<f64>,
+    pub fault_error_rate: Option<f64>,
+    pub fault_seed: Option<u64>,
+
+    #[serde(alias = "attr_ttl")]
+    pub attr_timeout: Option<f64>,
+    #[serde(alias = "negative_ttl")]
+    pub negative_timeout: Option<f64>,
+    #[serde(alias = "listing_ttl")]
+    pub listing_timeout: Option<f64>,
+    pub max_attr_entries: Option<usize>,
+    pub max_listing_entries: Option<usize>,
+    pub max_inodes: Option<usize>,
+    pub max_data_bytes: Option<Size>,
+    pub cache_dir: Option<String>,
+    pub max_disk_bytes: Option<Size>,
+    pub trim_interval: Option<f64>,
+
+    pub flush_threshold: Option<Size>,
+    pub readahead_window: Option<Size>,
+    pub write_debounce: Option<f64>,
+    pub preload: Option<Vec<String>>,
+    pub preload_data_max: Option<Size>,
+    pub refresh_interval: Option<f64>,
+    pub refresh_top_n: Option<usize>,
+    // "<path>[:interval]" entries like "/shared:10"
+    pub watch: Option<Vec<String>>,
+    pub spill_threshold: Option<Size>,
+    pub spill_dir: Option<String>,
+    pub max_concurrent_ops: Option<usize>,
+    pub read_only: Option<bool>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub local_owner: Option<bool>,
+    // Deprecated, the server's owner is shown unless local_owner is set.
+    // false still means local_owner, where that is not set itself.
+    pub map_remote_ids: Option<bool>,
+    // remote:local pairs like "1000:501,1001:502"
+    pub uid_map: Option<String>,
+    pub gid_map: Option<String>,
+    pub file_mode: Option<Mode>,
+    pub dir_mode: Option<Mode>,
+    pub umask: Option<Mode>,
+    pub blksize: Option<Size>,
+    pub max_path_len: Option<usize>,
+    pub casefold: Option<bool>,
+    // Patterns like ".snapshots" or "**/*.tmp"
+    pub exclude: Option<Vec<String>>,
+    pub include: Option<Vec<String>>,
+    // "none", "name" or "name-ci"
+    pub sort_dirs: Option<SortDirs>,
+    pub allow_other: Option<bool>,
+    pub allow_root: Option<bool>,
+    pub default_permissions: Option<bool>,
+    pub trace_ops: Option<bool>,
+    // In milliseconds, unlike the other durations
+    pub slow_op_ms: Option<u64>,
+    // Like "127.0.0.1:9600"
+    pub metrics_addr: Option<String>,
+    pub show_stats_file: Option<bool>,
+    pub control_socket: Option<String>,
+    // "auto" or "off"
+    pub offline_mode: Option<OfflineMode>,
+    pub offline_writes: Option<bool>,
+    pub journal: Option<bool>,
+    // "conflict-copy" or "error"
+    pub on_conflict: Option<ConflictMode>,
+    // "error" or "refresh"
+    pub stale_handles: Option<StaleHandles>,
+    pub maintenance_hold: Option<Size>,
+    pub notify: Option<NotifyMode>,
+}
+
+// The file and profile a configuration was loaded from, to load it again
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSource {
+    pub path: Option<PathBuf>,
+    pub profile: Option<String>,
+}
+
+impl ConfigSource {
+    // Reads the file, profile and environment again. Command line flags are
+    // not part of it.
+    pub fn load(&self) -> Result<MountConfig> {
+        MountConfig::load(self.path.as_deref(), self.profile.as_deref())
+    }
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&text)
+            .with_context(|| format!("Invalid configuration in {}", path.display()))
+    }
+
+    // The user's ~/.config/remotefs/config.toml, or else /etc/remotefs.toml,
+    // whichever exists first
+    pub fn default_path() -> Option<PathBuf> {
+        let user = env::var_os("HOME").map(|home| Path::new(&home).join(USER_CONFIG));
+        user.into_iter()
+            .chain([PathBuf::from(SYSTEM_CONFIG)])
+            .find(|path| path.is_file())
+    }
+
+    pub fn profile(&self, name: &str) -> Result<&Profile> {
+        self.profiles.get(name).with_context(|| {
+            let known: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            format!(
+                "No profile '{}', known profiles: {}",
+                name,
+                known.join(", ")
+            )
+        })
+    }
+
+    // Layers the named profile over `config`, so values set there win over
+    // defaults and flags applied afterwards win over the profile
+    pub fn apply(&self, name: &str, config: &mut MountConfig) -> Result<()> {
+        self.profile(name)?
+            .apply(config)
+            .with_context(|| format!("Invalid profile '{}'", name))
+    }
+
+    // Every profile that names a mountpoint, as a configuration labeled with
+    // the profile's name, for serving them all from one process
+    pub fn mounts(&self) -> Result<Vec<(String, MountConfig)>> {
+        let mut mounts = Vec::new();
+        for (name, profile) in &self.profiles {
+            if profile.mountpoint.is_none() {
+                continue;
+            }
+            let mut config = MountConfig::new(Vec::new());
+            self.apply(name, &mut config)?;
+            config.fs.label = Some(name.clone());
+            mounts.push((name.clone(), config));
+        }
+        Ok(mounts)
+    }
+
+    // Checks every profile, reporting the first invalid one by name
+    pub fn validate(&self) -> Result<()> {
+        for name in self.profiles.keys() {
+            self.apply(name, &mut MountConfig::new(Vec::new()))?;
+        }
+        Ok(())
+    }
+}
+
+impl MountConfig {
+    // Resolves the configuration before command line flags are applied:
+    // defaults, then the profile from the configuration file, then REMOTEFS_*
+    // environment variables. The file and profile come from the arguments,
+    // else REMOTEFS_CONFIG and REMOTEFS_PROFILE, else the default locations,
+    // where a missing "default" profile is not an error.
+    pub fn load(config_path: Option<&Path>, profile: Option<&str>) -> Result<Self> {
+        Self::load_with_env(config_path, profile, &env_vars())
+    }
+
+    // Like load, with `vars` standing in for the environment
+    fn load_with_env(
+        config_path: Option<&Path>,
+        profile: Option<&str>,
+        vars: &[(String, String)],
+    ) -> Result<Self> {
+        let mut config = Self::new(Vec::new());
+
+        let var = |name: &str| vars.iter().find(|(var, _)| var == name).map(|(_, v)| v);
+        let path = config_path
+            .map(Path::to_path_buf)
+            .or_else(|| var("REMOTEFS_CONFIG").map(PathBuf::from))
+            .or_else(ConfigFile::default_path);
+        let profile = profile.map(String::from).or_else(|| var("REMOTEFS_PROFILE").cloned());
+        let file = match &path {
+            // A mistake in any profile is found now, not when it is first used
+            Some(path) => {
+                let file = ConfigFile::load(path)?;
+                file.validate()
+                    .with_context(|| format!("Invalid configuration in {}", path.display()))?;
+                file
+            }
+            None => ConfigFile::default(),
+        };
+        match &profile {
+            Some(name) => file.apply(name, &mut config)?,
+            None if file.profiles.contains_key(DEFAULT_PROFILE) => {
+                file.apply(DEFAULT_PROFILE, &mut config)?
+            }
+            None => {}
+        }
+
+        Profile::from_vars(vars)?
+            .apply(&mut config)
+            .context("Invalid REMOTEFS_ environment variable")?;
+        config.fs.config_source = Some(ConfigSource { path, profile });
+        Ok(config)
+    }
+
+    // Translates the arguments mount(8) passes to mount.remotefs for
+    // `mount -t remotefs <server> <mountpoint> -o <options>`, returning the
+    // configuration and the mountpoint. The options may name a config file
+    // and profile, set ownership, permissions and access, or be plain mount
+    // options; the ones only meaningful to mount(8) and fstab are dropped.
+    pub fn from_mount_helper<I, S>(args: I) -> Result<(Self, String)>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut positional = Vec::new();
+        let mut options = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_ref() {
+                "-o" => {
+                    let list = args.next().context("Missing value for -o")?;
+                    options.extend(list.as_ref().split(',').map(str::to_string));
+                }
+                // Sloppy, fake, no mtab and verbose have no meaning here
+                "-s" | "-f" | "-n" | "-v" => {}
+                flag if flag.starts_with('-') => anyhow::bail!("Unknown option {}", flag),
+                value => positional.push(value.to_string()),
+            }
+        }
+        let [source, mountpoint] = <[String; 2]>::try_from(positional)
+            .map_err(|_| anyhow::anyhow!("Expected a server and a mountpoint"))?;
+
+        let value_of = |key: &str| {
+            options
+                .iter()
+                .find_map(|option| option.strip_prefix(key)?.strip_prefix('='))
+        };
+        let mut config = Self::load(value_of("config").map(Path::new), value_of("profile"))?;
+        if source != "none" {
+            config.client.base_urls = ClientConfig::parse_base_urls(&[source]);
+        }
+
+        let fs = &mut config.fs;
+        for option in options.iter().map(|option| option.trim()) {
+            let (key, value) = match option.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (option, None),
+            };
+            let id = || -> Result<u32> {
+                let value = value.with_context(|| format!("Missing value for {}", key))?;
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid {} '{}'", key, value))
+            };
+            let number = || -> Result<f64> {
+                let value = value.with_context(|| format!("Missing value for {}", key))?;
+                value
+                    .parse()
+                    .with_context(|| format!("Invalid {} '{}'", key, value))
+            };
+            let mode = || FsConfig::parse_mode(value.unwrap_or_default());
+
+            match key {
+                "" | "config" | "profile" | "defaults" | "auto" | "noauto" | "user" | "nouser"
+                | "users" | "owner" | "_netdev" | "nofail" => {}
+                key if key.starts_with("x-") => {}
+                "ro" => fs.read_only = true,
+                "rw" => fs.read_only = false,
+                "allow_other" => fs.allow_other = true,
+                "allow_root" => fs.allow_root = true,
+                "default_permissions" => fs.default_permissions = Some(true),
+                "uid" => fs.uid = Some(id()?),
+                "gid" => fs.gid = Some(id()?),
+                "local_owner" => fs.local_owner = true,
+                "map_remote_ids" => {
+                    warn_map_remote_ids();
+                    fs.local_owner = false;
+                }
+                "file_mode" => fs.file_mode = Some(mode()?),
+                "dir_mode" => fs.dir_mode = Some(mode()?),
+                "umask" => fs.umask = mode()?,
+                "blksize" => {
+                    let value = value.with_context(|| format!("Missing value for {}", key))?;
+                    let bytes = parse_size(value).with_context(|| format!("Invalid {}", key))?;
+                    fs.blksize = FsConfig::check_blksize(bytes)?
+                }
+                "max_path_len" => fs.max_path_len = FsConfig::check_max_path_len(id()? as usize)?,
+                "casefold" => fs.casefold = true,
+                "exclude" => fs.exclude.push(Glob::parse(value.unwrap_or_default())?),
+                "include" => fs.include.push(Glob::parse(value.unwrap_or_default())?),
+                "sort_dirs" => fs.sort_dirs = SortDirs::parse(value.unwrap_or_default())?,
+                "show_stats_file" => fs.show_stats_file = true,
+                "backend" => config.backend = BackendKind::parse(value.unwrap_or_default())?,
+                "s3_region" => config.s3.region = value.map(str::to_string),
+                "s3_endpoint" => config.s3.endpoint = value.map(str::to_string),
+                "s3_profile" => config.s3.profile = value.map(str::to_string),
+                "ssh_identity_file" => config.sftp.identity_file = value.map(PathBuf::from),
+                "ssh_insecure" => config.sftp.insecure = true,
+                "http2_prior_knowledge" => config.client.http.http2_prior_knowledge = true,
+                "ca_cert" => config.client.http.ca_cert = value.map(PathBuf::from),
+                "tls_insecure" => config.client.http.tls_insecure = true,
+                "fault_latency" => config.faults.latency = seconds(key, number()?)?,
+                "fault_error_rate" => config.faults.error_rate = Faults::parse_rate(number()?)?,
+                "fault_seed" => config.faults.seed = number()? as u64,
+                "offline_mode" => {
+                    fs.offline_mode = OfflineMode::parse(value.unwrap_or_default())?
+                }
+                "offline_writes" => fs.offline_writes = true,
+                "journal" => fs.journal = true,
+                "notify" => fs.notify = NotifyMode::parse(value.unwrap_or_default())?,
+                "watch" => fs.watch.push(Watch::parse(value.unwrap_or_default())?),
+                "on_conflict" => {
+                    fs.on_conflict = ConflictMode::parse(value.unwrap_or_default())?
+                }
+                "stale_handles" => {
+                    fs.stale_handles = StaleHandles::parse(value.unwrap_or_default())?
+                }
+                _ => config.options.extend(Self::parse_mount_options(option)),
+            }
+        }
+        anyhow::ensure!(
+            !(fs.allow_other && fs.allow_root),
+            "allow_other and allow_root cannot be used together"
+        );
+
+        Ok((config, mountpoint))
+    }
+}
+
+impl Profile {
+    // Reads REMOTEFS_<KEY> variables, e.g. REMOTEFS_CACHE_DIR for cache_dir.
+    // Unlike in the configuration file, unknown keys are skipped with a
+    // warning: the environment is shared with other versions and tools.
+    pub fn from_env() -> Result<Self> {
+        Self::from_vars(&env_vars())
+    }
+
+    fn from_vars(vars: &[(String, String)]) -> Result<Self> {
+        let mut table = toml::Table::new();
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if ENV_RESERVED.contains(&key) {
+                continue;
+            }
+
+            let key = key.to_ascii_lowercase();
+            let value = if ENV_LISTS.contains(&key.as_str()) {
+                let items = value.split(',').map(|item| item.trim().to_string().into());
+                toml::Value::Array(items.collect())
+            } else if ENV_STRINGS.contains(&key.as_str()) {
+                toml::Value::String(value.clone())
+            } else if let Ok(flag) = value.parse::<bool>() {
+                toml::Value::Boolean(flag)
+            } else if let Ok(integer) = value.parse::<i64>() {
+                toml::Value::Integer(integer)
+            } else if let Ok(float) = value.parse::<f64>() {
+                toml::Value::Float(float)
+            } else {
+                toml::Value::String(value.clone())
+            };
+            if !is_profile_key(&key) {
+                log::warn!("Ignoring {}, not a configuration key", name);
+                continue;
+            }
+            table.insert(key, value);
+        }
+
+        toml::Value::Table(table)
+            .try_into()
+            .context("Invalid REMOTEFS_ environment variable")
+    }
+
+    pub fn apply(&self, config: &mut MountConfig) -> Result<()> {
+        if let Some(servers) = &self.server {
+            let servers = servers
+                .iter()
+                .map(|s| expand_env(s))
+                .collect::<Result<Vec<_>>>()?;
+            config.client.base_urls = ClientConfig::parse_base_urls(&servers);
+        }
+        if let Some(backend) = &self.backend {
+            config.backend = backend.clone();
+        }
+        if let Some(options) = &self.options {
+            for option in options {
+                config
+                    .options
+                    .extend(MountConfig::parse_mount_options(&expand_env(option)?));
+            }
+        }
+        if let Some(token) = &self.token {
+            config.client.token = Some(Secret::new(expand_env(token.expose())?));
+        }
+        if let Some(mountpoint) = &self.mountpoint {
+            config.mountpoint = Some(expand_env(mountpoint)?);
+        }
+        if let Some(format) = self.log_format {
+            config.log_format = format;
+        }
+        if let Some(timeout) = self.mount_timeout {
+            config.startup.timeout = seconds("mount_timeout", timeout)?;
+        }
+        if let Some(retries) = self.mount_retries {
+            config.startup.retries = retries;
+        }
+
+        let client = &mut config.client;
+        if let Some(timeout) = self.timeout {
+            client.timeout = seconds("timeout", timeout)?;
+        }
+        if let Some(threshold) = self.failover_threshold {
+            client.failover_threshold = threshold;
+        }
+        if let Some(interval) = self.failback_interval {
+            client.failback_interval = seconds("failback_interval", interval)?;
+        }
+        if let Some(threshold) = self.breaker_threshold {
+            client.breaker_threshold = threshold;
+        }
+        if let Some(cool_down) = self.breaker_cool_down {
+            client.breaker_cool_down = seconds("breaker_cool_down", cool_down)?;
+        }
+        if let Some(max) = self.max_concurrent {
+            anyhow::ensure!(max > 0, "max_concurrent must be at least 1");
+            client.max_concurrent = max;
+        }
+        if let Some(rps) = self.max_rps {
+            anyhow::ensure!(rps > 0.0, "max_rps must be positive, got {}", rps);
+            client.max_rps = Some(rps);
+        }
+        if let Some(chunk_size) = &self.chunk_size {
+            client.chunk_size = size("chunk_size", chunk_size)?;
+            ClientConfig::validate_chunk_size(client.chunk_size)?;
+        }
+        if let Some(parts) = self.max_parts_per_read {
+            client.max_parts_per_read = parts;
+        }
+        if let Some(otel) = self.otel {
+            client.otel = otel;
+        }
+        let http = &mut client.http;
+        if let Some(prior_knowledge) = self.http2_prior_knowledge {
+            http.http2_prior_knowledge = prior_knowledge;
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            http.pool_max_idle_per_host = max;
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            let timeout = seconds("pool_idle_timeout", timeout)?;
+            http.pool_idle_timeout = (!timeout.is_zero()).then_some(timeout);
+        }
+        if let Some(interval) = self.tcp_keepalive {
+            let interval = seconds("tcp_keepalive", interval)?;
+            http.tcp_keepalive = (!interval.is_zero()).then_some(interval);
+        }
+        if let Some(nodelay) = self.tcp_nodelay {
+            http.tcp_nodelay = nodelay;
+        }
+        if let Some(path) = &self.ca_cert {
+            http.ca_cert = Some(PathBuf::from(expand_env(path)?));
+        }
+        if let Some(insecure) = self.tls_insecure {
+            http.tls_insecure = insecure;
+        }
+
+        let s3 = &mut config.s3;
+        if let Some(region) = &self.s3_region {
+            s3.region = Some(expand_env(region)?);
+        }
+        if let Some(endpoint) = &self.s3_endpoint {
+            s3.endpoint = Some(expand_env(endpoint)?.trim_end_matches('/').to_string());
+        }
+        if let Some(id) = &self.s3_access_key_id {
+            s3.access_key_id = Some(expand_env(id)?);
+        }
+        if let Some(secret) = &self.s3_secret_access_key {
+            s3.secret_access_key = Some(Secret::new(expand_env(secret.expose())?));
+        }
+        if let Some(token) = &self.s3_session_token {
+            s3.session_token = Some(Secret::new(expand_env(token.expose())?));
+        }
+        if let Some(profile) = &self.s3_profile {
+            s3.profile = Some(profile.clone());
+        }
+        if let Some(threshold) = &self.multipart_threshold {
+            s3.multipart_threshold = size("multipart_threshold", threshold)?;
+        }
+
+        let sftp = &mut config.sftp;
+        if let Some(path) = &self.ssh_identity_file {
+            sftp.identity_file = Some(PathBuf::from(expand_env(path)?));
+        }
+        if let Some(password) = &self.ssh_password {
+            sftp.password = Some(Secret::new(expand_env(password.expose())?));
+        }
+        if let Some(path) = &self.ssh_known_hosts {
+            sftp.known_hosts = Some(PathBuf::from(expand_env(path)?));
+        }
+        if let Some(insecure) = self.ssh_insecure {
+            sftp.insecure = insecure;
+        }
+        if let Some(sessions) = self.ssh_sessions {
+            anyhow::ensure!(sessions > 0, "ssh_sessions must be at least 1");
+            sftp.sessions = sessions;
+        }
+
+        let faults = &mut config.faults;
+        if let Some(latency) = self.fault_latency {
+            faults.latency = seconds("fault_latency", latency)?;
+        }
+        if let Some(rate) = self.fault_error_rate {
+            faults.error_rate = Faults::parse_rate(rate)?;
+        }
+        if let Some(seed) = self.fault_seed {
+            faults.seed = seed;
+        }
+
+        let cache = &mut config.fs.cache;
+        if let Some(timeout) = self.attr_timeout {
+            cache.attr_timeout = seconds("attr_timeout", timeout)?;
+        }
+        if let Some(timeout) = self.negative_timeout {
+            cache.negative_timeout = seconds("negative_timeout", timeout)?;
+        }
+        if let Some(timeout) = self.listing_timeout {
+            cache.listing_timeout = seconds("listing_timeout", timeout)?;
+        }
+        if let Some(max) = self.max_attr_entries {
+            cache.max_attr_entries = max;
+        }
+        if let Some(max) = self.max_listing_entries {
+            cache.max_listing_entries = max;
+        }
+        if let Some(max) = self.max_inodes {
+            anyhow::ensure!(max > 0, "max_inodes must be at least 1");
+            cache.max_inodes = Some(max);
+        }
+        if let Some(max) = &self.max_data_bytes {
+            cache.max_data_bytes = size("max_data_bytes", max)? as usize;
+        }
+        if let Some(dir) = &self.cache_dir {
+            cache.cache_dir = Some(PathBuf::from(expand_env(dir)?));
+        }
+        if let Some(max) = &self.max_disk_bytes {
+            cache.max_disk_bytes = size("max_disk_bytes", max)?;
+        }
+        if let Some(interval) = self.trim_interval {
+            cache.trim_interval = seconds("trim_interval", interval)?;
+            anyhow::ensure!(
+                !cache.trim_interval.is_zero(),
+                "trim_interval must not be zero"
+            );
+        }
+
+        let fs = &mut config.fs;
+        if let Some(threshold) = &self.flush_threshold {
+            fs.flush_threshold = size("flush_threshold", threshold)? as usize;
+        }
+        if let Some(window) = &self.readahead_window {
+            fs.readahead_window = size("readahead_window", window)?;
+        }
+        if let Some(debounce) = self.write_debounce {
+            fs.write_debounce = seconds("write_debounce", debounce)?;
+        }
+        if let Some(preload) = &self.preload {
+            fs.preload = preload
+                .iter()
+                .map(|p| expand_env(p))
+                .collect::<Result<_>>()?;
+        }
+        if let Some(max) = &self.preload_data_max {
+            fs.preload_data_max = size("preload_data_max", max)?;
+            anyhow::ensure!(
+                fs.preload_data_max == 0 || !fs.preload.is_empty(),
+                "preload_data_max is set but there is nothing to preload"
+            );
+        }
+        if let Some(interval) = self.refresh_interval {
+            fs.refresh_interval = seconds("refresh_interval", interval)?;
+        }
+        if let Some(top_n) = self.refresh_top_n {
+            fs.refresh_top_n = top_n;
+        }
+        if let Some(watches) = &self.watch {
+            fs.watch = watches
+                .iter()
+                .map(|watch| Watch::parse(&expand_env(watch)?))
+                .collect::<Result<_>>()?;
+        }
+        if let Some(threshold) = &self.spill_threshold {
+            fs.spill_threshold = size("spill_threshold", threshold)?;
+        }
+        if let Some(dir) = &self.spill_dir {
+            fs.spill_dir = PathBuf::from(expand_env(dir)?);
+        }
+        if let Some(max) = self.max_concurrent_ops {
+            anyhow::ensure!(max > 0, "max_concurrent_ops must be at least 1");
+            fs.max_concurrent_ops = max;
+        }
+        if let Some(read_only) = self.read_only {
+            fs.read_only = read_only;
+        }
+        if let Some(uid) = self.uid {
+            fs.uid = Some(uid);
+        }
+        if let Some(gid) = self.gid {
+            fs.gid = Some(gid);
+        }
+        if let Some(map_remote_ids) = self.map_remote_ids {
+            warn_map_remote_ids();
+            fs.local_owner = self.local_owner.unwrap_or(!map_remote_ids);
+        }
+        if let Some(local_owner) = self.local_owner {
+            fs.local_owner = local_owner;
+        }
+        if let Some(map) = &self.uid_map {
+            fs.uid_map = FsConfig::parse_id_map(map).context("Invalid uid_map")?;
+        }
+        if let Some(map) = &self.gid_map {
+            fs.gid_map = FsConfig::parse_id_map(map).context("Invalid gid_map")?;
+        }
+        if let Some(file_mode) = &self.file_mode {
+            fs.file_mode = Some(mode("file_mode", file_mode)?);
+        }
+        if let Some(dir_mode) = &self.dir_mode {
+            fs.dir_mode = Some(mode("dir_mode", dir_mode)?);
+        }
+        if let Some(umask) = &self.umask {
+            fs.umask = mode("umask", umask)?;
+        }
+        if let Some(blksize) = &self.blksize {
+            fs.blksize = FsConfig::check_blksize(size("blksize", blksize)?)?;
+        }
+        if let Some(max) = self.max_path_len {
+            fs.max_path_len = FsConfig::check_max_path_len(max)?;
+        }
+        if let Some(casefold) = self.casefold {
+            fs.casefold = casefold;
+        }
+        if let Some(patterns) = &self.exclude {
+            fs.exclude = patterns
+                .iter()
+                .map(|pattern| Glob::parse(&expand_env(pattern)?))
+                .collect::<Result<_>>()?;
+        }
+        if let Some(patterns) = &self.include {
+            fs.include = patterns
+                .iter()
+                .map(|pattern| Glob::parse(&expand_env(pattern)?))
+                .collect::<Result<_>>()?;
+        }
+        if let Some(order) = self.sort_dirs {
+            fs.sort_dirs = order;
+        }
+        if let Some(allow_other) = self.allow_other {
+            fs.allow_other = allow_other;
+        }
+        if let Some(allow_root) = self.allow_root {
+            fs.allow_root = allow_root;
+        }
+        if let Some(default_permissions) = self.default_permissions {
+            fs.default_permissions = Some(default_permissions);
+        }
+        if let Some(trace_ops) = self.trace_ops {
+            fs.trace_ops = trace_ops;
+        }
+        if let Some(ms) = self.slow_op_ms {
+            fs.slow_op = Some(Duration::from_millis(ms));
+        }
+        if let Some(addr) = &self.metrics_addr {
+            let addr = expand_env(addr)?;
+            fs.metrics_addr = Some(
+                addr.parse()
+                    .with_context(|| format!("Invalid metrics_addr '{}'", addr))?,
+            );
+        }
+        if let Some(show_stats_file) = self.show_stats_file {
+            fs.show_stats_file = show_stats_file;
+        }
+        if let Some(path) = &self.control_socket {
+            fs.control_socket = Some(PathBuf::from(expand_env(path)?));
+        }
+        if let Some(mode) = self.offline_mode {
+            fs.offline_mode = mode;
+        }
+        if let Some(offline_writes) = self.offline_writes {
+            fs.offline_writes = offline_writes;
+        }
+        if let Some(journal) = self.journal {
+            fs.journal = journal;
+        }
+        if let Some(mode) = self.on_conflict {
+            fs.on_conflict = mode;
+        }
+        if let Some(policy) = self.stale_handles {
+            fs.stale_handles = policy;
+        }
+        if let Some(hold) = &self.maintenance_hold {
+            fs.maintenance_hold = size("maintenance_hold", hold)?;
+        }
+        if let Some(mode) = self.notify {
+            fs.notify = mode;
+        }
+        anyhow::ensure!(
+            !(fs.allow_other && fs.allow_root),
+            "allow_other and allow_root cannot be used together"
+        );
+
+        Ok(())
+    }
+}
+
+// The environment as UTF-8 pairs, others cannot name a key anyway
+fn env_vars() -> Vec<(String, String)> {
+    env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .collect()
+}
+
+// Whether `key` names a field of Profile, asked of serde with a value of
+// the wrong type so that only an unknown key reads as such
+fn is_profile_key(key: &str) -> bool {
+    let mut probe = toml::Table::new();
+    probe.insert(key.to_string(), toml::Value::Table(toml::Table::new()));
+    match toml::Value::Table(probe).try_into::<Profile>() {
+        Ok(_) => true,
+        Err(e) => !e.to_string().contains("unknown field"),
+    }
+}
+
+fn warn_map_remote_ids() {
+    log::warn!(
+        "map_remote_ids is deprecated, the server's owner is shown unless local_owner is set"
+    );
+}
+
+fn seconds(key: &str, value: f64) -> Result<Duration> {
+    Duration::try_from_secs_f64(value)
+        .ok()
+        .with_context(|| format!("{} must be a number of seconds, got {}", key, value))
+}
+
+fn size(key: &str, value: &Size) -> Result<u64> {
+    match value {
+        Size::Bytes(bytes) => Ok(*bytes),
+        Size::Text(text) => {
+            parse_size(&expand_env(text)?).with_context(|| format!("Invalid {}", key))
+        }
+    }
+}
+
+fn mode(key: &str, value: &Mode) -> Result<u16> {
+    match value {
+        Mode::Bits(bits) => FsConfig::parse_mode(&format!("{:o}", bits)),
+        Mode::Octal(text) => FsConfig::parse_mode(text),
+    }
+    .with_context(|| format!("Invalid {}", key))
+}
+
+// Replaces every ${NAME} with the value of that environment variable
+fn expand_env(value: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("Unterminated variable in '{}'", value))?;
+        let name = &rest[start + 2..start + end];
+        let var =
+            env::var(name).with_context(|| format!("Environment variable {} is not set", name))?;
+        expanded.push_str(&var);
+        rest = &rest[start + end + 1..];
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures").join(name)
+    }
+
+    #[test]
+    fn valid_file_validates() {
+        let file = ConfigFile::load(&fixture("valid.toml")).unwrap();
+        file.validate().unwrap();
+
+        let mounts = file.mounts().unwrap();
+        assert_eq!(mounts.len(), 1);
+        let (name, config) = &mounts[0];
+        assert_eq!(name, "work");
+        assert_eq!(config.mountpoint.as_deref(), Some("/mnt/work"));
+        assert!(config.fs.read_only);
+    }
+
+    #[test]
+    fn invalid_profile_is_named() {
+        let file = ConfigFile::load(&fixture("invalid.toml")).unwrap();
+        let e = file.validate().unwrap_err();
+        assert!(format!("{:#}", e).contains("Invalid profile 'broken'"), "{:#}", e);
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn unknown_environment_keys_are_skipped() {
+        let profile = Profile::from_vars(&vars(&[
+            ("REMOTEFS_ATTR_TIMEOUT", "3"),
+            ("REMOTEFS_NO_SUCH_KEY", "1"),
+            ("REMOTEFS_LOG", "debug"),
+            ("HOME", "/root"),
+        ]))
+        .unwrap();
+        assert_eq!(profile.attr_timeout, Some(3.0));
+
+        // A known key with a bad value is still an error
+        let e = Profile::from_vars(&vars(&[("REMOTEFS_READ_ONLY", "[1]")])).unwrap_err();
+        assert!(format!("{:#}", e).contains("read_only"), "{:#}", e);
+    }
+
+    #[test]
+    fn values_are_layered_in_order() {
+        let path = fixture("valid.toml");
+        let env = vars(&[("REMOTEFS_ATTR_TIMEOUT", "7"), ("REMOTEFS_NOTIFY", "poll")]);
+        let mut config = MountConfig::load_with_env(Some(&path), None, &env).unwrap();
+        let defaults = MountConfig::new(Vec::new());
+
+        // The file over the defaults
+        assert_eq!(config.client.chunk_size, 4 << 20);
+        assert_ne!(defaults.client.chunk_size, 4 << 20);
+        assert_eq!(config.fs.cache.listing_timeout, Duration::from_secs_f64(2.5));
+        // The environment over the file
+        assert_eq!(config.fs.cache.attr_timeout, Duration::from_secs(7));
+        assert_eq!(config.fs.notify, NotifyMode::Poll);
+        // Left alone by both
+        assert_eq!(config.fs.max_concurrent_ops, defaults.fs.max_concurrent_ops);
+
+        // Command line flags, as cli applies them, over the environment
+        let flags = Profile {
+            attr_timeout: Some(1.0),
+            ..Profile::default()
+        };
+        flags.apply(&mut config).unwrap();
+        assert_eq!(config.fs.cache.attr_timeout, Duration::from_secs(1));
+        assert_eq!(config.fs.notify, NotifyMode::Poll);
+    }
+
+    #[test]
+    fn environment_picks_the_profile() {
+        let path = fixture("valid.toml");
+        let env = vars(&[("REMOTEFS_PROFILE", "work")]);
+        let config = MountConfig::load_with_env(Some(&path), None, &env).unwrap();
+        assert_eq!(config.backend, BackendKind::WebDav);
+        let config = MountConfig::load_with_env(Some(&path), Some("default"), &env).unwrap();
+        assert_eq!(config.backend, BackendKind::Rest);
+    }
+
+    #[test]
+    fn allow_other_and_allow_root_exclude_each_other() {
+        let mut config = MountConfig::new(Vec::new());
+        let profile = Profile {
+            allow_other: Some(true),
+            allow_root: Some(true),
+            ..Profile::default()
+        };
+        let e = profile.apply(&mut config).unwrap_err();
+        assert!(e.to_string().contains("cannot be used together"), "{}", e);
+
+        let profile = Profile {
+            allow_other: Some(true),
+            default_permissions: Some(false),
+            ..Profile::default()
+        };
+        let mut config = MountConfig::new(Vec::new());
+        profile.apply(&mut config).unwrap();
+        assert!(config.fs.allow_other);
+        assert_eq!(config.fs.default_permissions, Some(false));
+    }
+
+    #[test]
+    fn connection_options_come_from_profiles() {
+        let mut config = MountConfig::new(Vec::new());
+        let profile = Profile {
+            http2_prior_knowledge: Some(true),
+            pool_max_idle_per_host: Some(2),
+            pool_idle_timeout: Some(0.0),
+            tcp_keepalive: Some(15.0),
+            tcp_nodelay: Some(false),
+            ..Profile::default()
+        };
+        profile.apply(&mut config).unwrap();
+        let http = &config.client.http;
+        assert!(http.http2_prior_knowledge);
+        assert_eq!(http.pool_max_idle_per_host, 2);
+        // Zero keeps idle connections for good
+        assert_eq!(http.pool_idle_timeout, None);
+        assert_eq!(http.tcp_keepalive, Some(Duration::from_secs(15)));
+        assert!(!http.tcp_nodelay);
+
+        let negative = Profile {
+            tcp_keepalive: Some(-1.0),
+            ..Profile::default()
+        };
+        assert!(negative.apply(&mut MountConfig::new(Vec::new())).is_err());
+
+        let options = format!("config={},http2_prior_knowledge", fixture("valid.toml").display());
+        let args = ["http://server", "/mnt/x", "-o", &options];
+        let (config, _) = MountConfig::from_mount_helper(args).unwrap();
+        assert!(config.client.http.http2_prior_knowledge);
+    }
+
+    #[test]
+    fn owners_and_id_maps_come_from_profiles() {
+        let mut config = MountConfig::new(Vec::new());
+        let profile = Profile {
+            uid: Some(70),
+            uid_map: Some("1000:501".to_string()),
+            ..Profile::default()
+        };
+        profile.apply(&mut config).unwrap();
+        assert_eq!(config.fs.uid, Some(70));
+        assert_eq!(config.fs.gid, None);
+        assert_eq!(config.fs.uid_map.get(&1000), Some(&501));
+
+        let profile = Profile {
+            gid_map: Some("1000".to_string()),
+            ..Profile::default()
+        };
+        let e = profile.apply(&mut config).unwrap_err();
+        assert!(format!("{:#}", e).contains("Invalid gid_map"), "{:#}", e);
+    }
+
+    #[test]
+    fn modes_are_octal_integers_or_strings() {
+        let profile: Profile =
+            toml::from_str("file_mode = 0o640\ndir_mode = \"0o750\"\numask = \"022\"").unwrap();
+        let mut config = MountConfig::new(Vec::new());
+        profile.apply(&mut config).unwrap();
+        assert_eq!(config.fs.file_mode, Some(0o640));
+        assert_eq!(config.fs.dir_mode, Some(0o750));
+        assert_eq!(config.fs.umask, 0o022);
+
+        let profile: Profile = toml::from_str("umask = \"999\"").unwrap();
+        let e = profile.apply(&mut config).unwrap_err();
+        assert!(format!("{:#}", e).contains("Invalid umask"), "{:#}", e);
+    }
+
+    #[test]
+    fn blksize_is_a_power_of_two_in_range() {
+        let profile: Profile = toml::from_str("blksize = \"1M\"").unwrap();
+        let mut config = MountConfig::new(Vec::new());
+        profile.apply(&mut config).unwrap();
+        assert_eq!(config.fs.blksize, 1024 * 1024);
+
+        for bad in ["blksize = 256", "blksize = 3000", "blksize = \"32M\""] {
+            let profile: Profile = toml::from_str(bad).unwrap();
+            assert!(profile.apply(&mut MountConfig::new(Vec::new())).is_err(), "{}", bad);
+        }
+
+        let options = format!("config={},blksize=4096", fixture("valid.toml").display());
+        let args = ["http://server", "/mnt/x", "-o", &options];
+        let (config, _) = MountConfig::from_mount_helper(args).unwrap();
+        assert_eq!(config.fs.blksize, 4096);
+        let args = ["http://server", "/mnt/x", "-o", "blksize"];
+        let e = MountConfig::from_mount_helper(args).unwrap_err();
+        assert!(format!("{:#}", e).contains("Missing value for blksize"), "{:#}", e);
+    }
+
+    #[test]
+    fn max_path_len_leaves_room_for_a_name() {
+        let profile: Profile = toml::from_str("max_path_len = 1024").unwrap();
+        let mut config = MountConfig::new(Vec::new());
+        profile.apply(&mut config).unwrap();
+        assert_eq!(config.fs.max_path_len, 1024);
+
+        let profile: Profile = toml::from_str("max_path_len = 255").unwrap();
+        assert!(profile.apply(&mut MountConfig::new(Vec::new())).is_err());
+
+        let options = format!("config={},max_path_len=512", fixture("valid.toml").display());
+        let args = ["http://server", "/mnt/x", "-o", &options];
+        let (config, _) = MountConfig::from_mount_helper(args).unwrap();
+        assert_eq!(config.fs.max_path_len, 512);
+    }
+
+    #[test]
+    fn sort_dirs_comes_from_profiles_and_options() {
+        let profile: Profile = toml::from_str("sort_dirs = \"name-ci\"").unwrap();
+        let mut config = MountConfig::new(Vec::new());
+        profile.apply(&mut config).unwrap();
+        assert_eq!(config.fs.sort_dirs, SortDirs::NameCi);
+        assert!(toml::from_str::<Profile>("sort_dirs = \"size\"").is_err());
+
+        let options = format!("config={},sort_dirs=name", fixture("valid.toml").display());
+        let args = ["http://server", "/mnt/x", "-o", &options];
+        let (config, _) = MountConfig::from_mount_helper(args).unwrap();
+        assert_eq!(config.fs.sort_dirs, SortDirs::Name);
+        let args = ["http://server", "/mnt/x", "-o", "sort_dirs=size"];
+        assert!(MountConfig::from_mount_helper(args).is_err());
+    }
+
+    #[test]
+    fn mount_helper_arguments_translate() {
+        let options = format!(
+            "config={},profile=work,rw,_netdev,x-systemd.automount,uid=5,file_mode=0600,noexec",
+            fixture("valid.toml").display()
+        );
+        let args = ["https://other.example.com", "/mnt/x", "-s", "-o", &options];
+        let (config, mountpoint) = MountConfig::from_mount_helper(args).unwrap();
+        assert_eq!(mountpoint, "/mnt/x");
+        assert_eq!(config.client.base_urls, ["https://other.example.com"]);
+        assert_eq!(config.backend, BackendKind::WebDav);
+        // Later than the profile's read_only
+        assert!(!config.fs.read_only);
+        assert_eq!(config.fs.uid, Some(5));
+        assert_eq!(config.fs.file_mode, Some(0o600));
+        assert_eq!(config.options, [fuser::MountOption::NoExec]);
+
+        // The profile's servers
+        let args = ["none", "/mnt/x", "-o", &options];
+        let (config, _) = MountConfig::from_mount_helper(args).unwrap();
+        assert_eq!(config.client.base_urls, ["https://work.example.com"]);
+    }
+
+    #[test]
+    fn bad_mount_helper_arguments_are_refused() {
+        let config = format!("config={}", fixture("valid.toml").display());
+        let bad: [&[&str]; 5] = [
+            &["server", "/mnt/x", "-z"],
+            &["server"],
+            &["server", "/mnt/x", "-o"],
+            &["server", "/mnt/x", "-o", "uid=me"],
+            &["server", "/mnt/x", "-o", &config, "-o", "allow_other,allow_root"],
+        ];
+        for args in bad {
+            assert!(MountConfig::from_mount_helper(args).is_err(), "{:?}", args);
+        }
+    }
+
+    // Even with the valid default profile picked
+    #[test]
+    fn mount_refuses_a_file_with_an_invalid_profile() {
+        let path = fixture("invalid.toml");
+        let e = MountConfig::load(Some(&path), Some("default")).unwrap_err();
+        assert!(format!("{:#}", e).contains("Invalid chunk_size"), "{:#}", e);
+        MountConfig::load(Some(&fixture("valid.toml")), Some("default")).unwrap();
+    }
+}
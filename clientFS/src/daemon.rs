@@ -0,0 +1,198 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+use std::process;
+
+const READY: &str = "ready";
+
+// Where the output and process id of a daemon go
+#[derive(Debug, Clone, Default)]
+pub struct DaemonConfig {
+    // Log output is appended here, and discarded when unset
+    pub log_file: Option<PathBuf>,
+    pub pidfile: Option<PathBuf>,
+}
+
+// The background half of daemonize. It tells the waiting parent whether
+// mounting worked and removes the pidfile when dropped.
+pub struct Daemon {
+    pipe: Option<File>,
+    pidfile: Option<PathBuf>,
+}
+
+// Forks into the background the way mount helpers are expected to. This has
+// to happen before any thread is started, so the parent does nothing but
+// wait: it exits with status 0 once the child calls ready, and otherwise
+// prints the child's error and exits with the child's exit status, so
+// MountError statuses reach the caller. Only the child returns.
+// The child runs from /, so relative paths must be resolved beforehand.
+pub fn daemonize(config: &DaemonConfig) -> Result<Daemon> {
+    // Opened before forking so a bad path is still reported on the terminal
+    let log = match &config.log_file {
+        Some(path) => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open log file {}", path.display()))?,
+        ),
+        None => None,
+    };
+
+    let (reader, writer) = pipe()?;
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()).context("Failed to fork"),
+        0 => {
+            drop(reader);
+            let mut daemon = Daemon {
+                pipe: Some(writer),
+                pidfile: None,
+            };
+            if let Err(e) = daemon.detach(log, config) {
+                daemon.failed(&e);
+                process::exit(1);
+            }
+            Ok(daemon)
+        }
+        child => {
+            drop(writer);
+            wait_for_child(reader, child)
+        }
+    }
+}
+
+// The reading and the writing end of a new pipe, not inherited by programs
+// the process runs
+fn pipe() -> Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error()).context("Failed to create pipe");
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+fn wait_for_child(mut pipe: File, child: libc::pid_t) -> ! {
+    let mut report = String::new();
+    let _ = pipe.read_to_string(&mut report);
+
+    if report == READY {
+        process::exit(0);
+    }
+    if report.is_empty() {
+        eprintln!("Error: the mount process exited before the filesystem was ready");
+    } else {
+        eprintln!("Error: {}", report);
+    }
+
+    let mut status = 0;
+    if unsafe { libc::waitpid(child, &mut status, 0) } == child
+        && libc::WIFEXITED(status)
+        && libc::WEXITSTATUS(status) != 0
+    {
+        process::exit(libc::WEXITSTATUS(status));
+    }
+    process::exit(1);
+}
+
+impl Daemon {
+    fn detach(&mut self, log: Option<File>, config: &DaemonConfig) -> Result<()> {
+        if unsafe { libc::setsid() } == -1 {
+            return Err(io::Error::last_os_error()).context("Failed to start a new session");
+        }
+        std::env::set_current_dir("/").context("Failed to change directory to /")?;
+
+        let null = File::options()
+            .read(true)
+            .write(true)
+            .open("/dev/null")
+            .context("Failed to open /dev/null")?;
+        let output = log.as_ref().unwrap_or(&null);
+        for (from, to) in [(&null, 0), (output, 1), (output, 2)] {
+            if unsafe { libc::dup2(from.as_raw_fd(), to) } == -1 {
+                return Err(io::Error::last_os_error()).context("Failed to redirect output");
+            }
+        }
+
+        if let Some(path) = &config.pidfile {
+            fs::write(path, format!("{}\n", process::id()))
+                .with_context(|| format!("Failed to write pidfile {}", path.display()))?;
+            self.pidfile = Some(path.clone());
+        }
+        Ok(())
+    }
+
+    // Lets the parent exit successfully, once the filesystem is mounted
+    pub fn ready(&mut self) {
+        if let Some(mut pipe) = self.pipe.take() {
+            let _ = pipe.write_all(READY.as_bytes());
+        }
+    }
+
+    // Hands an error to the parent to print. The child should exit afterwards,
+    // with the status the parent is to exit with.
+    pub fn failed(&mut self, error: &anyhow::Error) {
+        if let Some(mut pipe) = self.pipe.take() {
+            let _ = write!(pipe, "{:#}", error);
+        }
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if let Some(path) = &self.pidfile {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A daemon reporting to the returned end of a pipe
+    fn daemon(pidfile: Option<PathBuf>) -> (Daemon, File) {
+        let (reader, writer) = pipe().unwrap();
+        let daemon = Daemon {
+            pipe: Some(writer),
+            pidfile,
+        };
+        (daemon, reader)
+    }
+
+    fn report(mut reader: File) -> String {
+        let mut report = String::new();
+        reader.read_to_string(&mut report).unwrap();
+        report
+    }
+
+    #[test]
+    fn ready_is_reported_once() {
+        let (mut daemon, reader) = daemon(None);
+        daemon.ready();
+        daemon.failed(&anyhow::anyhow!("too late"));
+        assert_eq!(report(reader), READY);
+    }
+
+    #[test]
+    fn failure_is_reported_with_its_context() {
+        let (mut daemon, reader) = daemon(None);
+        let error = anyhow::anyhow!("connection refused").context("Failed to mount /mnt/x");
+        daemon.failed(&error);
+        assert_eq!(report(reader), "Failed to mount /mnt/x: connection refused");
+    }
+
+    #[test]
+    fn pidfile_goes_with_the_daemon() {
+        let dir = tempfile::tempdir().unwrap();
+        let pidfile = dir.path().join("remotefs.pid");
+        fs::write(&pidfile, "1\n").unwrap();
+        let (daemon, reader) = daemon(Some(pidfile.clone()));
+        drop(daemon);
+        assert!(!pidfile.exists());
+        // Exited without a word
+        assert_eq!(report(reader), "");
+    }
+}
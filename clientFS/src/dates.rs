@@ -0,0 +1,100 @@
+// Parsing of the dates WebDAV and S3 servers report, to seconds since the
+// epoch. Formatting is logging::rfc3339.
+
+// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn unix_time(year: i64, month: u32, day: u32, hms: &str) -> Option<f64> {
+    let mut parts = hms.split(':');
+    let hour: u32 = parts.next()?.parse().ok()?;
+    let minute: u32 = parts.next()?.parse().ok()?;
+    let second: f64 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    Some((days * 86_400 + hour as i64 * 3_600 + minute as i64 * 60) as f64 + second)
+}
+
+// HTTP dates, RFC 1123 like "Sun, 06 Nov 1994 08:49:37 GMT"
+pub(crate) fn parse_http_date(value: &str) -> Option<f64> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let value = value.split_once(',').map_or(value, |(_, rest)| rest);
+    let mut fields = value.split_whitespace();
+    let day = fields.next()?.parse().ok()?;
+    let month = fields.next()?.to_ascii_lowercase();
+    let month = MONTHS.iter().position(|name| month.starts_with(name))? as u32 + 1;
+    let year = fields.next()?.parse().ok()?;
+    unix_time(year, month, day, fields.next()?)
+}
+
+// RFC 3339, like "1997-12-01T17:42:21-08:00"
+pub(crate) fn parse_rfc3339(value: &str) -> Option<f64> {
+    let (date, time) = value.split_once(['T', 't', ' '])?;
+    let mut date = date.splitn(3, '-');
+    let year = date.next()?.parse().ok()?;
+    let month = date.next()?.parse().ok()?;
+    let day = date.next()?.parse().ok()?;
+
+    let (time, offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else {
+        let at = time.rfind(['+', '-'])?;
+        let (hours, minutes) = time[at + 1..].split_once(':')?;
+        let minutes = hours.parse::<i64>().ok()? * 60 + minutes.parse::<i64>().ok()?;
+        let sign = if time.as_bytes()[at] == b'-' { -1 } else { 1 };
+        (&time[..at], sign * minutes * 60)
+    };
+    Some(unix_time(year, month, day, time)? - offset as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_dates_are_parsed() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784_111_777.0));
+        assert_eq!(parse_http_date("06 NOV 1994 08:49:37 GMT"), Some(784_111_777.0));
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0.0));
+        assert_eq!(parse_http_date("Tue, 29 Feb 2000 12:00:00 GMT"), Some(951_825_600.0));
+    }
+
+    #[test]
+    fn rfc3339_offsets_and_fractions_are_applied() {
+        assert_eq!(parse_rfc3339("1994-11-06T08:49:37Z"), Some(784_111_777.0));
+        assert_eq!(parse_rfc3339("1994-11-06 08:49:37z"), Some(784_111_777.0));
+        assert_eq!(parse_rfc3339("1994-11-06T00:49:37-08:00"), Some(784_111_777.0));
+        assert_eq!(parse_rfc3339("1994-11-06T10:19:37+01:30"), Some(784_111_777.0));
+        assert_eq!(parse_rfc3339("2024-01-02T03:04:05.250Z"), Some(1_704_164_645.25));
+        assert_eq!(parse_rfc3339("1969-12-31T23:59:59Z"), Some(-1.0));
+    }
+
+    #[test]
+    fn malformed_dates_are_none() {
+        for value in [
+            "",
+            "yesterday",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "1994-13-06T08:49:37Z",
+            "1994-11-06T08:60:37Z",
+            "1994-11-06T08:49:37",
+            "1994-11-06",
+        ] {
+            assert_eq!(parse_http_date(value).or(parse_rfc3339(value)), None, "{}", value);
+        }
+    }
+}
@@ -1,40 +1,710 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    ReplyWrite, Request,
+    FileAttr, FileType, Filesystem, MountOption, Notifier, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyEntry, ReplyIoctl, ReplyWrite, Request, Session, TimeOrNow, FUSE_ROOT_ID,
 };
-use libc::ENOENT;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-
-use crate::api_client::{ApiClient, FileEntry};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::api_client::{
+    with_upload_mtime, ApiClient, Conditional, Expected, FileEntry, Listing, Timestamp,
+    Version,
+};
+use crate::config::ConfigSource;
+
+mod archive;
+mod backend;
+mod cache;
+mod change_queue;
+mod conflict;
+mod control;
+mod disk_cache;
+mod dispatch;
+mod error;
+mod exclude;
+mod inode_lock;
+mod inode_table;
+mod journal;
+mod maintenance;
+mod metrics;
+mod notify;
+mod offline;
+mod permissions;
+mod readahead;
+mod session;
+mod sort;
+mod spill;
+mod stale;
+mod stats;
+mod stats_file;
+mod streamed;
+#[cfg(test)]
+mod tests;
+mod trace;
+mod trim;
+mod watch;
+mod write_buffer;
+
+use cache::{Block, BlockCache, LruCache};
+use change_queue::ChangeQueue;
+use disk_cache::{DiskCache, Validator};
+use dispatch::Dispatcher;
+use inode_lock::InodeLocks;
+use inode_table::InodeTable;
+use journal::Journal;
+use permissions::{Caller, READ, WRITE};
+use readahead::{Prefetch, ReadAhead};
+use spill::SpillFile;
+use stats::{CacheStats, FsStats, Op};
+use stats_file::{STATS_FILE, STATS_INO};
+use streamed::StreamedListing;
+use trace::{replied, OpTrace};
+use trim::CacheTrimmer;
+use write_buffer::WriteBuffer;
+
+pub use backend::{BackendKind, RemoteBackend};
+pub use control::{control, default_control_socket, ControlRequest};
+pub use error::FsError;
+pub use exclude::Glob;
+pub use conflict::ConflictMode;
+pub use notify::NotifyMode;
+pub use watch::Watch;
+pub use offline::OfflineMode;
+pub use stale::StaleHandles;
+pub use session::MountGuard;
+pub use sort::SortDirs;
+pub(crate) use session::{install_shutdown_handlers, shutdown_requested};
+pub use stats::StatsSnapshot;
 
 const TTL: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_ATTR_ENTRIES: usize = 100_000;
+const DEFAULT_MAX_LISTING_ENTRIES: usize = 1024;
+const DEFAULT_MAX_DATA_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_FLUSH_THRESHOLD: usize = 16 * 1024 * 1024;
+const DEFAULT_READAHEAD_WINDOW: u64 = 8 * 1024 * 1024;
+const DEFAULT_WRITE_DEBOUNCE: Duration = Duration::from_millis(100);
+const DEFAULT_REFRESH_TOP_N: usize = 32;
+const DEFAULT_SPILL_THRESHOLD: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_CONCURRENT_OPS: usize = 16;
+const DEFAULT_MAINTENANCE_HOLD: u64 = 64 * 1024 * 1024;
+// NAME_MAX and PATH_MAX of Linux. The server truncates longer names, so a
+// file created under one could never be looked up again.
+const MAX_NAME_LEN: usize = 255;
+const DEFAULT_MAX_PATH_LEN: usize = 4096;
+const DEFAULT_BLKSIZE: u32 = 128 * 1024;
+// Most a single read or write request carries, far above what FUSE sends
+const MAX_IO_SIZE: u64 = 32 * 1024 * 1024;
+pub(crate) const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const FUSE_CONF: &str = "/etc/fuse.conf";
+
+// ioctl(fd, _IO('R', 1)) on any file or directory of the mount drops the
+// caches below it, SIGUSR1 drops them for the whole mount
+pub const DROP_CACHES_IOCTL: u32 = (b'R' as u32) << 8 | 1;
+
+static DROP_CACHES_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_drop_caches(_signal: libc::c_int) {
+    DROP_CACHES_REQUESTED.store(true, Ordering::Relaxed);
+}
+const DEFAULT_MAX_DISK_BYTES: u64 = 1024 * 1024 * 1024;
+const DEFAULT_TRIM_INTERVAL: Duration = Duration::from_secs(30);
+
+// Attributes with ino 0 tell the kernel to cache the lookup as a miss
+const NEGATIVE_ATTR: FileAttr = FileAttr {
+    ino: 0,
+    size: 0,
+    blocks: 0,
+    atime: UNIX_EPOCH,
+    mtime: UNIX_EPOCH,
+    ctime: UNIX_EPOCH,
+    crtime: UNIX_EPOCH,
+    kind: FileType::RegularFile,
+    perm: 0,
+    nlink: 0,
+    uid: 0,
+    gid: 0,
+    rdev: 0,
+    flags: 0,
+    blksize: 0,
+};
+
+// Budgets and timeouts for everything the client keeps about the remote tree
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    // How long cached attributes are trusted before asking the server again
+    pub attr_timeout: Duration,
+    // How long a name that was not found is remembered as missing
+    pub negative_timeout: Duration,
+    pub listing_timeout: Duration,
+    // Inodes kept once their attributes expired, only closed regular files are dropped
+    pub max_attr_entries: usize,
+    // Hard cap on the inode table. Least recently used inodes without open
+    // handles are evicted even while the kernel knows them, by asking it to
+    // drop its entries first. The table may briefly exceed it. Unset by default.
+    pub max_inodes: Option<usize>,
+    // Directories whose listing is kept, least recently used ones are dropped first
+    pub max_listing_entries: usize,
+    pub max_data_bytes: usize,
+    // Persistent cache that survives remounts, disabled when unset
+    pub cache_dir: Option<PathBuf>,
+    pub max_disk_bytes: u64,
+    // How often expired entries are dropped and the attribute budget enforced
+    pub trim_interval: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            attr_timeout: TTL,
+            negative_timeout: TTL,
+            listing_timeout: TTL,
+            max_attr_entries: DEFAULT_MAX_ATTR_ENTRIES,
+            max_inodes: None,
+            max_listing_entries: DEFAULT_MAX_LISTING_ENTRIES,
+            max_data_bytes: DEFAULT_MAX_DATA_BYTES,
+            cache_dir: None,
+            max_disk_bytes: DEFAULT_MAX_DISK_BYTES,
+            trim_interval: DEFAULT_TRIM_INTERVAL,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FsConfig {
+    pub cache: CacheConfig,
+    // Buffered writes on a handle are uploaded early once they exceed this many bytes
+    pub flush_threshold: usize,
+    // How far ahead of a sequential reader data is prefetched, 0 disables it
+    pub readahead_window: u64,
+    // Buffered writes are uploaded once a handle saw no writes for this long,
+    // zero leaves them until flush, fsync, release or the flush threshold
+    pub write_debounce: Duration,
+    // Subtrees walked in the background after mounting to warm the caches
+    pub preload: Vec<String>,
+    // Files up to this size inside preloaded subtrees also get their contents cached,
+    // from one tar archive per subtree when the server offers GET /archive
+    pub preload_data_max: u64,
+    // How often the most used listings are refreshed ahead of expiry, zero disables it
+    pub refresh_interval: Duration,
+    pub refresh_top_n: usize,
+    // Directories listed again on a timer, for bounded staleness on servers
+    // that send no change notifications
+    pub watch: Vec<Watch>,
+    // Whole-file rewrites larger than this are assembled in a temporary file
+    // under spill_dir instead of in memory
+    pub spill_threshold: u64,
+    pub spill_dir: PathBuf,
+    // FUSE operations served at the same time, 1 serves them one by one on
+    // the session thread
+    pub max_concurrent_ops: usize,
+    // Mutations fail with EROFS without contacting the server
+    pub read_only: bool,
+    // Owner presented for entries the server reports none for. When unset,
+    // entries created through the mount show whoever created them and the
+    // rest the user who mounts.
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    // Ignore the owner reported by the server, presenting every entry as
+    // if the server had none
+    pub local_owner: bool,
+    // Remote to local tables the server's owner is translated through. Ids
+    // missing from them are shown as they are.
+    pub uid_map: HashMap<u32, u32>,
+    pub gid_map: HashMap<u32, u32>,
+    // Permissions shown in place of the server's mode, which is otherwise
+    // shown with the bits in umask cleared
+    pub file_mode: Option<u16>,
+    pub dir_mode: Option<u16>,
+    pub umask: u16,
+    // I/O size presented in st_blksize and statfs, which applications size
+    // their reads and writes by
+    pub blksize: u32,
+    // Longest path, in bytes, looked up or created on the server
+    pub max_path_len: usize,
+    // Match names looked up, created and renamed to against the cached
+    // listing ignoring case. Listings and new entries keep their own case.
+    pub casefold: bool,
+    // Entries hidden from the mount, as if the server did not have them.
+    // Include patterns carve exceptions out of the excluded ones.
+    pub exclude: Vec<Glob>,
+    pub include: Vec<Glob>,
+    // Order of the entries in listings, as the server sends them by default
+    pub sort_dirs: SortDirs,
+    // Let other users, or root only, access the mount
+    pub allow_other: bool,
+    pub allow_root: bool,
+    // Have the kernel check permissions against the presented mode bits, and
+    // check them here again on open, create, unlink and rename. Defaults to
+    // on with allow_other, since the presented owner is not the user who
+    // mounted.
+    pub default_permissions: Option<bool>,
+    // Names the mount in error logs and worker thread names, for processes
+    // serving several mounts
+    pub label: Option<String>,
+    // Log a line per FUSE operation at debug level, with its duration,
+    // requests issued and errno
+    pub trace_ops: bool,
+    // Operations taking at least this long are logged at warn level, traced or not
+    pub slow_op: Option<Duration>,
+    // Where to serve statistics in Prometheus format at /metrics, off when unset
+    pub metrics_addr: Option<SocketAddr>,
+    // List the stats file in the root and prefer it over a server file of
+    // the same name. It can be read either way.
+    pub show_stats_file: bool,
+    // Unix socket taking commands while mounted, off when unset
+    pub control_socket: Option<PathBuf>,
+    // Where the reload-config command reads the configuration again from
+    pub config_source: Option<ConfigSource>,
+    // Whether to keep serving cached data while the server is unreachable
+    pub offline_mode: OfflineMode,
+    // Accept writes, creates, mkdir and unlink while offline, queued under
+    // cache_dir and replayed once the server is back. Needs offline_mode
+    // auto and a cache_dir.
+    pub offline_writes: bool,
+    // Journal buffered writes under cache_dir until they are uploaded, so
+    // the next mount can upload them after a crash. Needs a cache_dir.
+    pub journal: bool,
+    // What a flush does when the file changed on the server since it was
+    // opened. Conflict copies need a version per open for writing, and make
+    // uploads from such handles whole-file and conditional.
+    pub on_conflict: ConflictMode,
+    // What reads on a handle do once the file changed on the server since
+    // it was opened. Changes are seen through versions and attributes the
+    // server reports, on revalidation, notifications and watches.
+    pub stale_handles: StaleHandles,
+    // Buffered writes kept while the server announces maintenance, uploaded
+    // once it takes writes again. Closing a file whose writes do not fit
+    // fails with EROFS and drops them.
+    pub maintenance_hold: u64,
+    // How changes made elsewhere reach the caches. Notified entries are
+    // dropped as soon as they change, rather than once their timeouts pass.
+    pub notify: NotifyMode,
+}
+
+impl Default for FsConfig {
+    fn default() -> Self {
+        Self {
+            cache: CacheConfig::default(),
+            flush_threshold: DEFAULT_FLUSH_THRESHOLD,
+            readahead_window: DEFAULT_READAHEAD_WINDOW,
+            write_debounce: DEFAULT_WRITE_DEBOUNCE,
+            preload: Vec::new(),
+            preload_data_max: 0,
+            refresh_interval: Duration::ZERO,
+            refresh_top_n: DEFAULT_REFRESH_TOP_N,
+            watch: Vec::new(),
+            spill_threshold: DEFAULT_SPILL_THRESHOLD,
+            spill_dir: std::env::temp_dir(),
+            max_concurrent_ops: DEFAULT_MAX_CONCURRENT_OPS,
+            read_only: false,
+            uid: None,
+            gid: None,
+            local_owner: false,
+            uid_map: HashMap::new(),
+            gid_map: HashMap::new(),
+            file_mode: None,
+            dir_mode: None,
+            umask: 0,
+            blksize: DEFAULT_BLKSIZE,
+            max_path_len: DEFAULT_MAX_PATH_LEN,
+            casefold: false,
+            exclude: Vec::new(),
+            include: Vec::new(),
+            sort_dirs: SortDirs::None,
+            allow_other: false,
+            allow_root: false,
+            default_permissions: None,
+            label: None,
+            trace_ops: false,
+            slow_op: None,
+            metrics_addr: None,
+            show_stats_file: false,
+            control_socket: None,
+            config_source: None,
+            offline_mode: OfflineMode::Off,
+            offline_writes: false,
+            journal: false,
+            on_conflict: ConflictMode::Error,
+            stale_handles: StaleHandles::Error,
+            maintenance_hold: DEFAULT_MAINTENANCE_HOLD,
+            notify: NotifyMode::Ws,
+        }
+    }
+}
+
+impl FsConfig {
+    // Explicit modes win over umask, which wins over the server's mode
+    fn presented_perm(&self, is_dir: bool, mode: u32) -> u16 {
+        let explicit = if is_dir { self.dir_mode } else { self.file_mode };
+        // The sticky bit stays, it restricts who may remove entries
+        explicit.unwrap_or((mode & 0o1777) as u16 & !self.umask)
+    }
+
+    // Checks options that only work together, before anything is mounted
+    pub fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            !self.offline_writes
+                || (self.offline_mode == OfflineMode::Auto && self.cache.cache_dir.is_some()),
+            "offline_writes needs offline_mode auto and a cache_dir"
+        );
+        anyhow::ensure!(
+            !self.journal || self.cache.cache_dir.is_some(),
+            "journal needs a cache_dir"
+        );
+        Ok(())
+    }
+
+    // Checks `--blksize`, a power of two from 512 bytes to 16 MiB
+    pub fn check_blksize(bytes: u64) -> Result<u32> {
+        if !bytes.is_power_of_two() || !(512..=16 * 1024 * 1024).contains(&bytes) {
+            anyhow::bail!("Block size {} is not a power of two from 512 to 16M", bytes);
+        }
+        Ok(bytes as u32)
+    }
+
+    // Checks `--max-path-len`, which must leave room for one full-length name
+    pub fn check_max_path_len(bytes: usize) -> Result<usize> {
+        if bytes <= MAX_NAME_LEN {
+            anyhow::bail!("Path length limit {} is not above {}", bytes, MAX_NAME_LEN);
+        }
+        Ok(bytes)
+    }
+
+    // Parses octal permissions like `0644` or `0o644` for `--file-mode`,
+    // `--dir-mode` and `--umask`
+    pub fn parse_mode(value: &str) -> Result<u16> {
+        let value = value.trim();
+        let digits = value.strip_prefix("0o").unwrap_or(value);
+        let mode = u16::from_str_radix(digits, 8)
+            .with_context(|| format!("Invalid octal mode '{}'", value))?;
+        if mode > 0o777 {
+            anyhow::bail!("Mode '{}' has bits beyond 0777", value);
+        }
+        Ok(mode)
+    }
+
+    // Parses id translation tables like `1000:501,1001:502` for `--uid-map`
+    // and `--gid-map`, each pair a server id and the local one shown for it
+    pub fn parse_id_map(value: &str) -> Result<HashMap<u32, u32>> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (remote, local) = pair
+                    .split_once(':')
+                    .with_context(|| format!("Expected remote:local in '{}'", pair))?;
+                let parse = |id: &str| {
+                    id.trim()
+                        .parse::<u32>()
+                        .with_context(|| format!("Invalid id '{}' in '{}'", id, pair))
+                };
+                Ok((parse(remote)?, parse(local)?))
+            })
+            .collect()
+    }
+}
+
+// Current size of the caches, dirty data is reported separately since it is never evicted
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheUsage {
+    pub data_bytes: usize,
+    pub data_blocks: usize,
+    pub attr_entries: usize,
+    pub listing_entries: usize,
+    pub negative_entries: usize,
+    pub dirty_bytes: usize,
+}
+
+struct OpenFile {
+    ino: u64,
+    // Size of the file on the server when the buffer was last uploaded
+    remote_size: u64,
+    buffer: WriteBuffer,
+    // Error from an upload that has not been reported to the application yet
+    flush_error: Option<String>,
+    readahead: ReadAhead,
+    last_write: Instant,
+    // Modification time the next upload asks the server to keep: that of
+    // the last write, or one set since with utimens
+    mtime: Option<SystemTime>,
+    // Journal records of the buffered changes, oldest first
+    journaled: Vec<u64>,
+    // Version of the remote copy the buffered changes apply to, with
+    // on_conflict conflict-copy
+    base: Option<Version>,
+    // The inode's remote_changes when opened or last caught up
+    seen_changes: u64,
+    // The buffer waits for the server to leave maintenance, and counts
+    // against maintenance_hold
+    held: bool,
+    // Released by the application while held, dropped once uploaded
+    released: bool,
+}
+
+impl OpenFile {
+    // Whether a read of [offset, offset + len) needs any data from the server
+    fn needs_remote(&self, offset: u64, len: u64) -> bool {
+        let file_size = self.buffer.file_size(self.remote_size);
+        let len = len.min(file_size.saturating_sub(offset));
+        offset < self.buffer.kept_remote(self.remote_size) && !self.buffer.covers(offset, len)
+    }
+}
+
+struct CachedListing {
+    entries: Arc<Vec<FileEntry>>,
+    version: Option<Version>,
+    fetched_at: Instant,
+    // Recent cache hits, halved on every refresh round
+    hits: u64,
+}
 
 #[derive(Debug, Clone)]
 struct INode {
-    #[allow(dead_code)]
     ino: u64,
     path: String,
     attr: FileAttr,
+    fetched_at: Instant,
+    // Version of the remote contents the cached blocks were read from
+    version: Option<Version>,
+    // Times the server's copy was found changed by someone else, handles
+    // compare it with what they saw
+    remote_changes: u64,
+    // The next attributes from the server are known to differ, after our
+    // own upload or a change already counted in remote_changes
+    expects_attrs: bool,
+    // Times moved to now for an entry we added or removed, listed ones older
+    // than them were read before the change
+    touched: bool,
+    // Who made the entry through this mount, presented as its owner when
+    // neither the server nor the config names one
+    creator: Option<Caller>,
+    // Deleted while handles were open on it, here or on the server. Served
+    // from what is cached with nlink 0 until the last handle closes.
+    deleted: bool,
+    // References the kernel holds from entry replies, the inode is only
+    // evicted once it forgot all of them
+    lookups: u64,
+    used_at: Instant,
+}
+
+impl INode {
+    fn is_fresh(&self, timeout: Duration) -> bool {
+        self.fetched_at.elapsed() < timeout
+    }
+}
+
+fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("/", path),
+    }
+}
+
+// Whether `path` is `dir` or lies below it
+fn is_under(path: &str, dir: &str) -> bool {
+    dir == "/"
+        || path
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    normalize_path(&format!("{}/{}", parent, name))
+}
+
+// The one spelling of a path the inode table and caches key on: absolute,
+// without repeated or trailing slashes, "/" for the root. "docs//a.txt/" and
+// "/docs/a.txt" would otherwise get an inode each.
+pub(crate) fn normalize_path(path: &str) -> String {
+    let mut normalized = String::with_capacity(path.len() + 1);
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    normalized
 }
 
+// The offset and length of a read, None for a negative offset. Reads are
+// cut to MAX_IO_SIZE and to end at the largest offset, past which files
+// have nothing anyway.
+fn read_range(offset: i64, size: u32) -> Option<(u64, u64)> {
+    let offset = u64::try_from(offset).ok()?;
+    let size = (size as u64).min(MAX_IO_SIZE).min(i64::MAX as u64 - offset);
+    Some((offset, size))
+}
+
+// Where a write starts and ends. A negative offset is refused, like one
+// ending past the largest file offset, before either wraps around.
+fn write_range(offset: i64, len: usize) -> Result<(u64, u64), FsError> {
+    let offset = u64::try_from(offset).map_err(|_| FsError::InvalidArgument)?;
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= i64::MAX as u64 && len as u64 <= MAX_IO_SIZE => Ok((offset, end)),
+        _ => Err(FsError::FileTooLarge),
+    }
+}
+
+// Whether `name` can be one entry of a directory. "." and ".." lead
+// elsewhere, and a slash or NUL makes it several names or none.
+fn valid_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.contains(['/', '\0'])
+}
+
+// `name` as compared with casefold: every character by its simple lowercase
+// mapping and final sigma as sigma. Characters lowercasing to several stay.
+fn casefold(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c == 'ς' {
+                return 'σ';
+            }
+            let mut lower = c.to_lowercase();
+            match (lower.next(), lower.next()) {
+                (Some(lower), None) => lower,
+                _ => c,
+            }
+        })
+        .collect()
+}
+
+// Unprivileged users may only share a mount if fuse.conf allows it
+fn check_user_allow_other() -> Result<()> {
+    if unsafe { libc::geteuid() } == 0 {
+        return Ok(());
+    }
+
+    let allowed = std::fs::read_to_string(FUSE_CONF).is_ok_and(|conf| allows_other(&conf));
+    if !allowed {
+        anyhow::bail!(
+            "Sharing the mount with other users requires user_allow_other in {} \
+             when not running as root",
+            FUSE_CONF
+        );
+    }
+    Ok(())
+}
+
+// Whether a fuse.conf has user_allow_other set, ignoring comments
+fn allows_other(conf: &str) -> bool {
+    conf.lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .any(|line| line == "user_allow_other")
+}
+
+// Kind of a listed entry. Servers that send the whole st_mode have special
+// files in its type bits, anything else is a plain file.
+fn entry_kind(entry: &FileEntry) -> FileType {
+    if entry.is_dir {
+        return FileType::Directory;
+    }
+    match entry.mode & libc::S_IFMT {
+        libc::S_IFLNK => FileType::Symlink,
+        libc::S_IFIFO => FileType::NamedPipe,
+        libc::S_IFSOCK => FileType::Socket,
+        libc::S_IFCHR => FileType::CharDevice,
+        libc::S_IFBLK => FileType::BlockDevice,
+        _ => FileType::RegularFile,
+    }
+}
+
+// Device number of a device node in the kernel's 32-bit encoding, 0 for
+// anything else or when the server did not send it
+fn device_number(entry: &FileEntry) -> u32 {
+    match (entry_kind(entry), entry.major, entry.minor) {
+        (FileType::CharDevice | FileType::BlockDevice, Some(major), Some(minor)) => {
+            (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
+        }
+        _ => 0,
+    }
+}
+
+// st_blocks of a file, counted in 512-byte units as POSIX has it whatever
+// the block size
+fn blocks_of(size: u64) -> u64 {
+    size.div_ceil(512)
+}
+
+fn validator(attr: &FileAttr) -> Validator {
+    Validator {
+        size: attr.size,
+        mtime_nanos: attr
+            .mtime
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0),
+    }
+}
+
+// Clones share all state, background tasks use them to reach the caches
+#[derive(Clone)]
 pub struct RemoteFS {
-    api_client: Arc<ApiClient>,
-    inodes: Arc<Mutex<HashMap<u64, INode>>>,
-    path_to_ino: Arc<Mutex<HashMap<String, u64>>>,
-    next_ino: Arc<Mutex<u64>>,
-    #[allow(dead_code)]
-    file_handles: Arc<Mutex<HashMap<u64, Vec<u8>>>>,
+    backend: Arc<dyn RemoteBackend>,
+    // Replaced as a whole when the configuration is reloaded
+    config: Arc<RwLock<Arc<FsConfig>>>,
+    inodes: Arc<RwLock<InodeTable>>,
+    negative: Arc<Mutex<HashMap<(u64, String), Instant>>>,
+    listings: Arc<Mutex<LruCache<String, CachedListing>>>,
+    // Listings being read from the server a line at a time
+    streamed: Arc<Mutex<HashMap<String, Arc<StreamedListing>>>>,
+    // Bumped whenever cached listings are invalidated. A listing fetched
+    // while it changed may predate the change and is not cached.
+    listing_epoch: Arc<AtomicU64>,
+    blocks: Arc<BlockCache>,
+    disk_cache: Option<Arc<DiskCache>>,
+    file_handles: Arc<Mutex<HashMap<u64, OpenFile>>>,
     next_fh: Arc<Mutex<u64>>,
+    // Taken before any other lock and held for a whole upload
+    upload_locks: Arc<InodeLocks>,
+    // Set once the session is up, used to drop kernel page cache for changed files
+    notifier: Arc<OnceLock<Arc<Notifier>>>,
+    stats: Arc<FsStats>,
+    // Set when the session ends, stops background write-back
+    shutdown: Arc<AtomicBool>,
+    // Set once unmounting began, mutations are refused from then on
+    draining: Arc<AtomicBool>,
+    // Set at mount when operations are served concurrently
+    dispatcher: Arc<OnceLock<Dispatcher>>,
+    // Owner presented when no other is known, resolved once from the config
+    owner: (u32, u32),
+    // Snapshots behind open handles of the stats file
+    stats_files: Arc<Mutex<HashMap<u64, Arc<Vec<u8>>>>>,
+    // Set while the server is unreachable and offline_mode is auto
+    offline: Arc<AtomicBool>,
+    // Set while the server announces maintenance or overload
+    maintenance: Arc<AtomicBool>,
+    // Paths answered from the caches while offline, revalidated once online
+    served_stale: Arc<Mutex<HashSet<String>>>,
+    // Changes made offline, with offline_writes
+    change_queue: Option<Arc<ChangeQueue>>,
+    // Buffered writes not uploaded yet, with the journal option
+    journal: Option<Arc<Journal>>,
 }
 
 impl RemoteFS {
     pub fn new(api_client: ApiClient) -> Self {
-        let mut inodes = HashMap::new();
-        let mut path_to_ino = HashMap::new();
+        Self::with_config(api_client, FsConfig::default())
+    }
+
+    pub fn with_config(api_client: ApiClient, config: FsConfig) -> Self {
+        Self::with_backend(Arc::new(api_client), config)
+    }
+
+    pub fn with_backend(backend: Arc<dyn RemoteBackend>, config: FsConfig) -> Self {
+        let owner = (
+            config.uid.unwrap_or_else(|| unsafe { libc::getuid() }),
+            config.gid.unwrap_or_else(|| unsafe { libc::getgid() }),
+        );
 
         // Create root inode
         let root_attr = FileAttr {
@@ -46,251 +716,2464 @@ impl RemoteFS {
             ctime: SystemTime::now(),
             crtime: SystemTime::now(),
             kind: FileType::Directory,
-            perm: 0o755,
-            nlink: 2,
-            uid: 501,
-            gid: 20,
+            perm: config.presented_perm(true, 0o755),
+            // Counted once the root is listed
+            nlink: 1,
+            uid: owner.0,
+            gid: owner.1,
             rdev: 0,
             flags: 0,
-            blksize: 512,
+            blksize: config.blksize,
         };
 
         let root_inode = INode {
             ino: 1,
             path: "/".to_string(),
             attr: root_attr,
+            fetched_at: Instant::now(),
+            version: None,
+            remote_changes: 0,
+            expects_attrs: false,
+            touched: false,
+            creator: None,
+            deleted: false,
+            lookups: 0,
+            used_at: Instant::now(),
+        };
+
+        let inodes = InodeTable::new(root_inode);
+
+        let cache = &config.cache;
+        let listings = LruCache::new(cache.max_listing_entries);
+        // Blocks are one transfer chunk each, the disk cache keeps them per chunk size
+        let block_size = backend.chunk_size();
+        let blocks = BlockCache::new(block_size, cache.max_data_bytes);
+        let disk_cache = cache.cache_dir.as_ref().and_then(|dir| {
+            match DiskCache::open(dir, block_size, cache.max_disk_bytes) {
+                Ok(cache) => Some(Arc::new(cache)),
+                Err(e) => {
+                    log::warn!("Disk cache at {} disabled: {}", dir.display(), e);
+                    None
+                }
+            }
+        });
+
+        let change_queue = match &cache.cache_dir {
+            Some(dir) if config.offline_writes => match ChangeQueue::open(dir) {
+                Ok(queue) => Some(Arc::new(queue)),
+                Err(e) => {
+                    log::warn!("Offline writes disabled, no queue in {}: {}", dir.display(), e);
+                    None
+                }
+            },
+            _ => None,
         };
+        // Changes left by an earlier run go to the server before anything is
+        // read from it
+        let queued = change_queue.as_ref().map_or(0, |queue| queue.len());
+        if queued > 0 {
+            log::info!("{} changes queued by an earlier mount, replaying them first", queued);
+        }
 
-        inodes.insert(1, root_inode);
-        path_to_ino.insert("/".to_string(), 1);
+        let journal = match &cache.cache_dir {
+            Some(dir) if config.journal => match Journal::open(dir) {
+                Ok(journal) => Some(Arc::new(journal)),
+                Err(e) => {
+                    log::warn!("Write journal in {} disabled: {}", dir.display(), e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let stats = FsStats::default();
+        if let Some(journal) = &journal {
+            stats
+                .journal_corrupt
+                .store(journal.corrupt(), Ordering::Relaxed);
+        }
 
         Self {
-            api_client: Arc::new(api_client),
-            inodes: Arc::new(Mutex::new(inodes)),
-            path_to_ino: Arc::new(Mutex::new(path_to_ino)),
-            next_ino: Arc::new(Mutex::new(2)),
+            backend,
+            config: Arc::new(RwLock::new(Arc::new(config))),
+            inodes: Arc::new(RwLock::new(inodes)),
+            negative: Arc::new(Mutex::new(HashMap::new())),
+            listings: Arc::new(Mutex::new(listings)),
+            streamed: Arc::new(Mutex::new(HashMap::new())),
+            listing_epoch: Arc::new(AtomicU64::new(0)),
+            blocks: Arc::new(blocks),
+            disk_cache,
             file_handles: Arc::new(Mutex::new(HashMap::new())),
             next_fh: Arc::new(Mutex::new(1)),
+            upload_locks: Arc::new(InodeLocks::default()),
+            notifier: Arc::new(OnceLock::new()),
+            stats: Arc::new(stats),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            draining: Arc::new(AtomicBool::new(false)),
+            dispatcher: Arc::new(OnceLock::new()),
+            owner,
+            stats_files: Arc::new(Mutex::new(HashMap::new())),
+            offline: Arc::new(AtomicBool::new(queued > 0)),
+            maintenance: Arc::new(AtomicBool::new(false)),
+            served_stale: Arc::new(Mutex::new(HashSet::new())),
+            change_queue,
+            journal,
         }
     }
 
+    // The configuration in effect, a later reload does not change the copy
+    fn config(&self) -> Arc<FsConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    // Takes the timeouts and limits that apply without remounting from `new`,
+    // everything else stays as mounted. Returns the names of the settings
+    // that changed.
+    pub fn reload_config(&self, new: &FsConfig) -> Vec<&'static str> {
+        fn take<T: Clone + PartialEq>(
+            changed: &mut Vec<&'static str>,
+            name: &'static str,
+            current: &mut T,
+            new: &T,
+        ) {
+            if current != new {
+                *current = new.clone();
+                changed.push(name);
+            }
+        }
+
+        let mut shared = self.config.write().unwrap();
+        let mut config = FsConfig::clone(&shared);
+        let mut changed = Vec::new();
+        let (cache, new_cache) = (&mut config.cache, &new.cache);
+        take(&mut changed, "attr_timeout", &mut cache.attr_timeout, &new_cache.attr_timeout);
+        take(
+            &mut changed,
+            "negative_timeout",
+            &mut cache.negative_timeout,
+            &new_cache.negative_timeout,
+        );
+        take(
+            &mut changed,
+            "listing_timeout",
+            &mut cache.listing_timeout,
+            &new_cache.listing_timeout,
+        );
+        take(
+            &mut changed,
+            "max_attr_entries",
+            &mut cache.max_attr_entries,
+            &new_cache.max_attr_entries,
+        );
+        take(&mut changed, "flush_threshold", &mut config.flush_threshold, &new.flush_threshold);
+        take(&mut changed, "readahead_window", &mut config.readahead_window, &new.readahead_window);
+        take(&mut changed, "refresh_top_n", &mut config.refresh_top_n, &new.refresh_top_n);
+        take(&mut changed, "spill_threshold", &mut config.spill_threshold, &new.spill_threshold);
+        take(&mut changed, "maintenance_hold", &mut config.maintenance_hold, &new.maintenance_hold);
+        take(&mut changed, "trace_ops", &mut config.trace_ops, &new.trace_ops);
+        take(&mut changed, "slow_op", &mut config.slow_op, &new.slow_op);
+        *shared = Arc::new(config);
+        changed
+    }
+
     fn get_or_create_inode(&self, path: &str, entry: &FileEntry) -> u64 {
-        let mut path_to_ino = self.path_to_ino.lock().unwrap();
-        let mut inodes = self.inodes.lock().unwrap();
-        let mut next_ino = self.next_ino.lock().unwrap();
+        let nlink = if entry.is_dir {
+            entry.nlink.or_else(|| self.counted_nlink(path))
+        } else {
+            Some(1)
+        };
+        let mut inodes = self.inodes.write().unwrap();
+
+        if let Some(ino) = inodes.resolve_path(path) {
+            // The listing is newer than whatever we had cached, refresh from it
+            if let Some(inode) = inodes.get_mut(ino) {
+                // Buffered writes are newer than anything the server can report
+                if self.has_dirty_data(ino) {
+                    return ino;
+                }
 
-        if let Some(&ino) = path_to_ino.get(path) {
+                let mut attr = FileAttr {
+                    // A count known from before stays until the listing is fetched again
+                    nlink: nlink.unwrap_or(inode.attr.nlink),
+                    ..self.attr_from_entry(ino, entry, inode.creator)
+                };
+                // So do times the server did not report or sent unreadable
+                if !entry.mtime.is_known() {
+                    (attr.atime, attr.mtime) = (inode.attr.atime, inode.attr.mtime);
+                }
+                if !entry.ctime.is_known() {
+                    (attr.ctime, attr.crtime) = (inode.attr.ctime, inode.attr.crtime);
+                }
+                if inode.touched {
+                    if attr.mtime < inode.attr.mtime {
+                        (attr.mtime, attr.ctime) = (inode.attr.mtime, inode.attr.ctime);
+                    } else {
+                        inode.touched = false;
+                    }
+                }
+                if attr.size != inode.attr.size || attr.mtime != inode.attr.mtime {
+                    log::debug!("Attributes of {} changed on the server", path);
+                    if !inode.expects_attrs {
+                        inode.remote_changes += 1;
+                    }
+                    inode.version = None;
+                    self.blocks.invalidate(ino);
+                    self.invalidate_kernel_cache(ino);
+                }
+                inode.attr = attr;
+                inode.fetched_at = Instant::now();
+                inode.expects_attrs = false;
+            }
             return ino;
         }
 
-        let ino = *next_ino;
-        *next_ino += 1;
+        inodes.insert(path, entry.id, |ino| FileAttr {
+            nlink: nlink.unwrap_or(1),
+            ..self.attr_from_entry(ino, entry, None)
+        })
+    }
+
+    // A time the server reported, as shown. Times ahead of our clock, which
+    // make takes for files modified in the future, are shifted back by how
+    // far the server's clock runs ahead and shown as now at the latest. The
+    // skew estimate only moves by whole seconds past a tolerance, so the
+    // same server time keeps showing the same. Unknown times are now.
+    fn presented_time(&self, time: Timestamp) -> SystemTime {
+        let now = SystemTime::now();
+        if !time.is_known() {
+            return now;
+        }
+        let time = SystemTime::from(time);
+        if time <= now {
+            return time;
+        }
+        let shifted = match self.backend.clock_skew() {
+            Some(skew) if skew > 0 => time
+                .checked_sub(Duration::from_secs(skew as u64))
+                .unwrap_or(now),
+            _ => time,
+        };
+        if shifted > now {
+            log::debug!("Time {:?} is still in the future, shown as now", shifted);
+        }
+        shifted.min(now)
+    }
+
+    // Link count of a directory counted in its cached listing, 2 and one per
+    // subdirectory as on local filesystems. Tools like find skip descending
+    // into directories once they have seen nlink - 2 subdirectories, so an
+    // unknown count is 1, which they take as no count at all.
+    fn counted_nlink(&self, path: &str) -> Option<u32> {
+        let listings = self.listings.lock().unwrap();
+        let listing = listings.peek(path)?;
+        Some(2 + listing.entries.iter().filter(|entry| entry.is_dir).count() as u32)
+    }
+
+    fn set_nlink(&self, path: &str, nlink: u32) {
+        let mut inodes = self.inodes.write().unwrap();
+        if let Some(ino) = inodes.resolve_path(path) {
+            if let Some(inode) = inodes.get_mut(ino) {
+                inode.attr.nlink = nlink;
+            }
+        }
+    }
+
+    // Keeps the link count of `parent` right after a subdirectory was made
+    // (`added`) or removed in it: counted again from the listing if it is
+    // still cached, else adjusted when it was known
+    fn subdirs_changed(&self, parent: &str, added: bool) {
+        if let Some(nlink) = self.counted_nlink(parent) {
+            self.set_nlink(parent, nlink);
+            return;
+        }
+        let mut inodes = self.inodes.write().unwrap();
+        let Some(ino) = inodes.resolve_path(parent) else {
+            return;
+        };
+        if let Some(inode) = inodes.get_mut(ino).filter(|inode| inode.attr.nlink >= 2) {
+            if added {
+                inode.attr.nlink += 1;
+            } else if inode.attr.nlink > 2 {
+                inode.attr.nlink -= 1;
+            }
+        }
+    }
+
+    // Moves the times of directory `dir` to now after an entry was added or
+    // removed in it, until the server's own are checked, which comes early
+    fn entries_changed(&self, dir: &str) {
+        let now = SystemTime::now();
+        let ino = {
+            let mut inodes = self.inodes.write().unwrap();
+            let Some(ino) = inodes.resolve_path(dir) else {
+                return;
+            };
+            let Some(inode) = inodes.get_mut(ino) else {
+                return;
+            };
+            (inode.attr.mtime, inode.attr.ctime) = (now, now);
+            inode.touched = true;
+            ino
+        };
+        self.expire_attr(ino);
+    }
 
-        let attr = FileAttr {
+    // Every attribute shown for a remote entry is built here, with the
+    // configured owner and permissions applied
+    fn attr_from_entry(&self, ino: u64, entry: &FileEntry, creator: Option<Caller>) -> FileAttr {
+        let (uid, gid) = self.ownership_for(entry, creator);
+        let mtime = self.presented_time(entry.mtime);
+        let ctime = self.presented_time(entry.ctime);
+        FileAttr {
             ino,
             size: entry.size,
-            blocks: (entry.size + 511) / 512,
-            atime: UNIX_EPOCH + Duration::from_secs_f64(entry.mtime),
-            mtime: UNIX_EPOCH + Duration::from_secs_f64(entry.mtime),
-            ctime: UNIX_EPOCH + Duration::from_secs_f64(entry.ctime),
-            crtime: UNIX_EPOCH + Duration::from_secs_f64(entry.ctime),
-            kind: if entry.is_dir {
-                FileType::Directory
+            blocks: blocks_of(entry.size),
+            atime: mtime,
+            mtime,
+            ctime,
+            crtime: ctime,
+            kind: entry_kind(entry),
+            perm: self.config().presented_perm(entry.is_dir, entry.mode),
+            nlink: if entry.is_dir {
+                entry.nlink.unwrap_or(1)
             } else {
-                FileType::RegularFile
+                1
             },
-            perm: (entry.mode & 0o777) as u16,
-            nlink: if entry.is_dir { 2 } else { 1 },
-            uid: 501,
-            gid: 20,
-            rdev: 0,
+            uid,
+            gid,
+            rdev: device_number(entry),
             flags: 0,
-            blksize: 512,
+            blksize: self.config().blksize,
+        }
+    }
+
+    // Owner presented for an entry, each id taken from the first that has
+    // it: the server's translated through the id maps unless local_owner is
+    // set, the configured one, whoever created the entry through this mount,
+    // and last the user who mounted
+    fn ownership_for(&self, entry: &FileEntry, creator: Option<Caller>) -> (u32, u32) {
+        let config = self.config();
+        let remote = |id: Option<u32>, table: &HashMap<u32, u32>| {
+            id.filter(|_| !config.local_owner)
+                .map(|id| table.get(&id).copied().unwrap_or(id))
         };
+        (
+            remote(entry.uid, &config.uid_map)
+                .or(config.uid)
+                .or(creator.map(|caller| caller.uid))
+                .unwrap_or(self.owner.0),
+            remote(entry.gid, &config.gid_map)
+                .or(config.gid)
+                .or(creator.map(|caller| caller.gid))
+                .unwrap_or(self.owner.1),
+        )
+    }
 
-        let inode = INode {
-            ino,
-            path: path.to_string(),
-            attr,
+    // Presents `caller` as the owner of the entry they just created, where
+    // nothing else names one
+    fn created_by(&self, ino: u64, entry: &FileEntry, caller: Caller) {
+        let owner = self.ownership_for(entry, Some(caller));
+        if let Some(inode) = self.inodes.write().unwrap().get_mut(ino) {
+            inode.creator = Some(caller);
+            (inode.attr.uid, inode.attr.gid) = owner;
+        }
+    }
+
+    fn get_inode(&self, ino: u64) -> Option<INode> {
+        self.inodes.read().unwrap().get(ino).cloned()
+    }
+
+    // Like get_inode, for inodes about to be handed to the kernel in an entry
+    fn looked_up(&self, ino: u64) -> Option<INode> {
+        self.inodes.write().unwrap().looked_up(ino).cloned()
+    }
+
+    // Drops references the kernel forgot. An inode left without any is only
+    // evicted here while the table is over max_inodes, otherwise it stays
+    // cached for the next lookup.
+    fn forget_lookups(&self, ino: u64, nlookup: u64) {
+        let evicted = {
+            let mut inodes = self.inodes.write().unwrap();
+            let unused = inodes.forget(ino, nlookup);
+            let over = self.config().cache.max_inodes.is_some_and(|max| inodes.len() > max);
+            if unused && over && ino != 1 && !self.is_open(ino) {
+                inodes.remove_ino(ino)
+            } else {
+                None
+            }
         };
 
-        inodes.insert(ino, inode);
-        path_to_ino.insert(path.to_string(), ino);
+        if evicted.is_some() {
+            self.blocks.invalidate(ino);
+            self.stats.inodes_evicted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Returns the inode, refreshing its attributes when they are older than the
+    // configured attr_timeout. Files whose contents were read with a known
+    // version are checked with a conditional request first, and only looked up
+    // in the parent listing again if they changed.
+    fn revalidate_inode(&self, ino: u64) -> Option<INode> {
+        let inode = self.get_inode(ino)?;
+        // A deleted file has nothing left on the server to check against
+        if ino == 1 || inode.deleted || inode.is_fresh(self.config().cache.attr_timeout) {
+            self.stats.attrs.hit();
+            return Some(inode);
+        }
+        self.stats.attrs.miss();
+        if self.is_offline() {
+            self.served_offline(&inode.path);
+            return Some(inode);
+        }
+
+        let mut modified = false;
+        if let Some(version) = &inode.version {
+            match self.backend.revalidate_file(&inode.path, version) {
+                Ok(Conditional::NotModified) => return self.touch_inode(ino),
+                Ok(Conditional::Modified(_)) => {
+                    modified = true;
+                    self.invalidate_parent_listing(&inode.path);
+                }
+                Err(e) => log::debug!("Failed to revalidate {}: {}", inode.path, e),
+            }
+        }
 
-        ino
+        let (parent_path, name) = split_path(&inode.path);
+        match self.list_directory(parent_path) {
+            Ok(entries) => match self.find_entry(&entries, parent_path, name) {
+                Some(entry) => {
+                    // Contents of a deleted file stay cached for its open handles
+                    if modified {
+                        log::debug!("Contents of {} changed on the server", inode.path);
+                        self.drop_file_data(ino);
+                    }
+                    self.get_or_create_inode(&inode.path, entry);
+                    self.get_inode(ino)
+                }
+                // Kept, as deleted, while it is open
+                None => {
+                    self.purge_subtree(&inode.path);
+                    self.get_inode(ino)
+                }
+            },
+            Err(e) => {
+                if modified {
+                    self.drop_file_data(ino);
+                }
+                log::warn!("Failed to revalidate {}, using cached attributes: {}", inode.path, e);
+                Some(inode)
+            }
+        }
     }
 
-    fn get_inode(&self, ino: u64) -> Option<INode> {
-        let inodes = self.inodes.lock().unwrap();
-        inodes.get(&ino).cloned()
+    // Lists a directory, reusing the cached listing while it is younger than
+    // listing_timeout and afterwards for as long as the server reports it
+    // unchanged. Offline, any cached listing is used however old.
+    fn list_directory(&self, path: &str) -> Result<Arc<Vec<FileEntry>>> {
+        if self.is_offline() {
+            return self.list_directory_offline(path);
+        }
+        let expired = match self.listings.lock().unwrap().get_mut(path) {
+            Some(listing) if listing.fetched_at.elapsed() < self.config().cache.listing_timeout => {
+                listing.hits += 1;
+                self.stats.listings.hit();
+                return Ok(listing.entries.clone());
+            }
+            Some(listing) => listing
+                .version
+                .clone()
+                .map(|version| (version, listing.entries.clone())),
+            None => None,
+        };
+        if let Some(streamed) = self.streamed_listing(path) {
+            return streamed.wait();
+        }
+
+        self.stats.listings.miss();
+        self.fetch_listing(path, expired)
     }
 
-    fn path_from_parent_and_name(&self, parent: u64, name: &OsStr) -> Option<String> {
-        let inodes = self.inodes.lock().unwrap();
-        let parent_inode = inodes.get(&parent)?;
-        let name_str = name.to_str()?;
+    // Whether a listing younger than listing_timeout shows `path`, without
+    // asking the server
+    fn listed_fresh(&self, path: &str) -> bool {
+        let (parent, name) = split_path(path);
+        let timeout = self.config().cache.listing_timeout;
+        self.listings.lock().unwrap().peek(parent).is_some_and(|listing| {
+            listing.fetched_at.elapsed() < timeout
+                && self.find_entry(&listing.entries, parent, name).is_some()
+        })
+    }
 
-        let parent_path = &parent_inode.path;
-        let path = if parent_path == "/" {
-            format!("/{}", name_str)
-        } else {
-            format!("{}/{}", parent_path, name_str)
+    // The entry named `name`, with casefold also one whose name differs only
+    // in case. An exact match wins, then the first listed of the others.
+    fn find_entry<'a>(
+        &self,
+        entries: &'a [FileEntry],
+        dir: &str,
+        name: &str,
+    ) -> Option<&'a FileEntry> {
+        if let Some(entry) = entries.iter().find(|entry| entry.name == name) {
+            return Some(entry);
+        }
+        if !self.config().casefold {
+            return None;
+        }
+        let folded = casefold(name);
+        let mut matches = entries.iter().filter(|entry| casefold(&entry.name) == folded);
+        let first = matches.next()?;
+        if matches.next().is_some() {
+            log::warn!(
+                "{:?} matches several entries of {} ignoring case, taking {:?}",
+                name,
+                dir,
+                first.name
+            );
+        }
+        Some(first)
+    }
+
+    // The server's spelling of `name` in `dir`, from the cached listing
+    fn listed_name(&self, dir: &str, name: &str) -> Option<String> {
+        let listings = self.listings.lock().unwrap();
+        let listing = listings.peek(dir)?;
+        self.find_entry(&listing.entries, dir, name)
+            .map(|entry| entry.name.clone())
+    }
+
+    fn list_directory_offline(&self, path: &str) -> Result<Arc<Vec<FileEntry>>> {
+        let cached = self
+            .listings
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(|listing| listing.entries.clone());
+        let entries = match cached {
+            Some(entries) => entries,
+            None => match self.disk_cache.as_ref().and_then(|cache| cache.load_listing(path)) {
+                Some(mut entries) => {
+                    self.sort_listing(&mut entries);
+                    Arc::new(entries)
+                }
+                None => return Err(self.not_cached_offline(path)),
+            },
+        };
+        self.served_offline(path);
+        Ok(entries)
+    }
+
+    // Fetches a listing, conditionally when an expired copy with a known version is given
+    fn fetch_listing(
+        &self,
+        path: &str,
+        expired: Option<(Version, Arc<Vec<FileEntry>>)>,
+    ) -> Result<Arc<Vec<FileEntry>>> {
+        let epoch = self.listing_epoch.load(Ordering::Relaxed);
+        let fetched = match expired {
+            Some((version, entries)) => match self.backend.revalidate_listing(path, &version) {
+                Ok(Conditional::NotModified) => {
+                    self.cache_fetched_listing(epoch, path, entries.clone(), Some(version));
+                    return Ok(entries);
+                }
+                Ok(Conditional::Modified(listing)) => Ok(listing),
+                Err(e) => Err(e),
+            },
+            None => self.backend.list_directory(path),
+        };
+        let fetched = fetched.map(|mut listing| {
+            listing.entries.retain(|entry| self.listable(path, entry));
+            self.sort_listing(&mut listing.entries);
+            listing
+        });
+
+        let Listing { entries, version } = match (fetched, &self.disk_cache) {
+            (Ok(listing), Some(disk_cache)) => {
+                disk_cache.store_listing(path, &listing.entries);
+                listing
+            }
+            (Ok(listing), None) => listing,
+            (Err(e), Some(disk_cache)) => match disk_cache.load_listing(path) {
+                Some(mut entries) => {
+                    log::warn!("Serving cached listing of {}: {}", path, e);
+                    self.sort_listing(&mut entries);
+                    return Ok(Arc::new(entries));
+                }
+                None => return Err(e),
+            },
+            (Err(e), None) => return Err(e),
         };
 
-        Some(path)
+        let entries = Arc::new(entries);
+        self.cache_fetched_listing(epoch, path, entries.clone(), version);
+        Ok(entries)
     }
 
-    pub fn mount(self, mountpoint: &str) -> Result<()> {
-        let options = vec![
-            MountOption::RW,
-            MountOption::FSName("remotefs".to_string()),
-        ];
+    // Caches a listing fetched from the server unless listings were
+    // invalidated since `epoch`, when it may miss a change made meanwhile,
+    // like a rename, and would show it for a whole listing_timeout
+    fn cache_fetched_listing(
+        &self,
+        epoch: u64,
+        path: &str,
+        entries: Arc<Vec<FileEntry>>,
+        version: Option<Version>,
+    ) {
+        if self.listing_epoch.load(Ordering::Relaxed) != epoch {
+            log::debug!("Not caching the listing of {}, changes were made meanwhile", path);
+            return;
+        }
+        self.cache_listing(path, entries, version);
+    }
 
-        log::info!("Mounting filesystem at {}", mountpoint);
-        fuser::mount2(self, mountpoint, &options)?;
-        Ok(())
+    fn cache_listing(&self, path: &str, entries: Arc<Vec<FileEntry>>, version: Option<Version>) {
+        let mut listings = self.listings.lock().unwrap();
+        // A refreshed listing stays as hot as the one it replaces
+        let hits = listings.remove(path).map_or(0, |old| old.hits);
+        let listing = CachedListing {
+            entries,
+            version,
+            fetched_at: Instant::now(),
+            hits,
+        };
+        let nlink = 2 + listing.entries.iter().filter(|entry| entry.is_dir).count() as u32;
+        listings.insert(path.to_string(), listing, 1);
+        drop(listings);
+        self.set_nlink(path, nlink);
     }
-}
 
-impl Filesystem for RemoteFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        log::debug!("lookup(parent={}, name={:?})", parent, name);
+    fn invalidate_listing(&self, path: &str) {
+        self.listing_epoch.fetch_add(1, Ordering::Relaxed);
+        self.listings.lock().unwrap().remove(path);
+    }
 
-        let path = match self.path_from_parent_and_name(parent, name) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
+    // Moves the cached listings of the directory `from` and those below it
+    // to their paths under `to`: a rename changes where they are, not what
+    // they hold. The on-disk copies under `from` would show a directory made
+    // there later.
+    fn move_listings(&self, from: &str, to: &str) {
+        self.listing_epoch.fetch_add(1, Ordering::Relaxed);
+        let mut listings = self.listings.lock().unwrap();
+        let moved: Vec<String> = listings
+            .iter_mut()
+            .map(|(path, _)| path)
+            .filter(|path| is_under(path, from))
+            .cloned()
+            .collect();
+        for old in moved {
+            if let Some(listing) = listings.remove(&old) {
+                listings.insert(format!("{}{}", to, &old[from.len()..]), listing, 1);
+            }
+            if let Some(disk_cache) = &self.disk_cache {
+                disk_cache.discard_listing(&old);
             }
+        }
+    }
+
+    fn invalidate_parent_listing(&self, path: &str) {
+        self.invalidate_listing(split_path(path).0);
+    }
+
+    // Extends the lifetime of attributes the server confirmed are still current
+    fn touch_inode(&self, ino: u64) -> Option<INode> {
+        let mut inodes = self.inodes.write().unwrap();
+        let inode = inodes.get_mut(ino)?;
+        inode.fetched_at = Instant::now();
+        Some(inode.clone())
+    }
+
+    // Makes the next access check the attributes with the server
+    fn expire_attr(&self, ino: u64) {
+        let Some(expired) = Instant::now().checked_sub(self.config().cache.attr_timeout) else {
+            return;
         };
+        if let Some(inode) = self.inodes.write().unwrap().get_mut(ino) {
+            inode.fetched_at = expired;
+        }
+    }
 
-        // Check if we already have this inode cached
-        {
-            let path_to_ino = self.path_to_ino.lock().unwrap();
-            if let Some(&ino) = path_to_ino.get(&path) {
-                if let Some(inode) = self.get_inode(ino) {
-                    reply.entry(&TTL, &inode.attr, 0);
-                    return;
+    // Forgets cached contents of a file that changed on the server
+    fn drop_file_data(&self, ino: u64) {
+        if let Some(inode) = self.inodes.write().unwrap().get_mut(ino) {
+            inode.version = None;
+            inode.remote_changes += 1;
+            inode.expects_attrs = true;
+        }
+        self.blocks.invalidate(ino);
+        self.invalidate_kernel_cache(ino);
+    }
+
+    // Records the version a block was just fetched from, dropping blocks read
+    // from an older one
+    fn record_version(&self, ino: u64, version: Option<Version>) {
+        let changed = {
+            let mut inodes = self.inodes.write().unwrap();
+            match inodes.get_mut(ino) {
+                Some(inode) => {
+                    let changed = inode.version.is_some() && inode.version != version;
+                    inode.version = version;
+                    if changed {
+                        inode.remote_changes += 1;
+                        inode.expects_attrs = true;
+                    }
+                    changed
                 }
+                None => false,
             }
+        };
+
+        if changed {
+            log::debug!("Inode {} changed on the server while cached", ino);
+            self.blocks.invalidate(ino);
+            self.invalidate_kernel_cache(ino);
         }
+    }
 
-        // Try to get parent directory listing to find this entry
-        let parent_inode = match self.get_inode(parent) {
-            Some(inode) => inode,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
+    fn invalidate_kernel_cache(&self, ino: u64) {
+        let Some(notifier) = self.notifier.get().cloned() else {
+            return;
         };
 
-        match self.api_client.list_directory(&parent_inode.path) {
-            Ok(entries) => {
-                for entry in entries {
-                    if entry.name == name.to_string_lossy() {
-                        let full_path = if parent_inode.path == "/" {
-                            format!("/{}", entry.name)
-                        } else {
-                            format!("{}/{}", parent_inode.path, entry.name)
-                        };
+        // Notifying from the thread serving the request can deadlock when the
+        // kernel waits on that same request, so it is sent from its own thread
+        thread::spawn(move || {
+            if let Err(e) = notifier.inval_inode(ino, 0, 0) {
+                log::debug!("Failed to invalidate kernel cache of inode {}: {}", ino, e);
+            }
+        });
+    }
+
+    // Makes the kernel look `name` up again, from its own thread like above
+    fn invalidate_kernel_entry(&self, parent: u64, name: &str) {
+        let Some(notifier) = self.notifier.get().cloned() else {
+            return;
+        };
+        let name = name.to_string();
+        thread::spawn(move || {
+            if let Err(e) = notifier.inval_entry(parent, OsStr::new(&name)) {
+                log::debug!("Failed to invalidate kernel entry {}: {}", name, e);
+            }
+        });
+    }
+
+    // Forgets everything cached under `subtree` except dirty data, for when the
+    // server's copy was replaced behind our back. Attributes are marked stale
+    // rather than removed since the kernel may still refer to the inodes.
+    // Returns how many entries and bytes were dropped.
+    pub fn drop_caches(&self, subtree: &str) -> (usize, usize) {
+        let mut entries = 0;
+        let mut bytes = 0;
+
+        {
+            let mut listings = self.listings.lock().unwrap();
+            let before = listings.len();
+            listings.retain(|path, _| !is_under(path, subtree));
+            entries += before - listings.len();
+        }
+
+        let dirty: HashSet<u64> = self
+            .file_handles
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|handle| !handle.buffer.is_empty())
+            .map(|handle| handle.ino)
+            .collect();
+
+        // (ino, parent ino, name) of every entry the kernel has to look up again
+        let mut stale_entries = Vec::new();
+        let mut subtree_inos = HashSet::new();
+        let mut dropped = Vec::new();
+        {
+            let mut inodes = self.inodes.write().unwrap();
+            let expired = Instant::now().checked_sub(self.config().cache.attr_timeout);
+
+            let matching: Vec<(u64, String)> = inodes
+                .values()
+                .filter(|inode| is_under(&inode.path, subtree))
+                .map(|inode| (inode.ino, inode.path.clone()))
+                .collect();
+            for (ino, path) in matching {
+                subtree_inos.insert(ino);
+                if dirty.contains(&ino) {
+                    continue;
+                }
+
+                let (parent, name) = split_path(&path);
+                let parent = inodes.resolve_path(parent);
+                let Some(inode) = inodes.get_mut(ino) else {
+                    continue;
+                };
+                if let Some(expired) = expired {
+                    inode.fetched_at = expired;
+                }
+                inode.version = None;
+                entries += 1;
+
+                stale_entries.push((ino, parent, name.to_string()));
+                dropped.push((ino, path, inode.attr));
+            }
+        }
+
+        {
+            let mut negative = self.negative.lock().unwrap();
+            negative.retain(|(parent, name), _| {
+                let keep = !subtree_inos.contains(parent);
+                if !keep {
+                    stale_entries.push((0, Some(*parent), name.clone()));
+                    entries += 1;
+                }
+                keep
+            });
+        }
+
+        for (ino, path, attr) in &dropped {
+            bytes += self.blocks.invalidate(*ino);
+            if let Some(disk_cache) = &self.disk_cache {
+                if attr.kind == FileType::Directory {
+                    disk_cache.discard_listing(path);
+                } else {
+                    disk_cache.discard_file(path, attr.size);
+                }
+            }
+        }
+
+        if let Some(notifier) = self.notifier.get().cloned() {
+            thread::spawn(move || {
+                for (ino, parent, name) in stale_entries {
+                    if ino != 0 {
+                        let _ = notifier.inval_inode(ino, 0, 0);
+                    }
+                    if let Some(parent) = parent {
+                        let _ = notifier.inval_entry(parent, OsStr::new(&name));
+                    }
+                }
+            });
+        }
+
+        log::info!(
+            "Dropped caches under {}: {} entries, {} bytes",
+            subtree,
+            entries,
+            bytes
+        );
+        (entries, bytes)
+    }
+
+    // Drops all caches whenever SIGUSR1 arrives
+    fn spawn_signal_watcher(&self) {
+        let handler = request_drop_caches as extern "C" fn(libc::c_int);
+        // The handler only sets an atomic flag, which is async-signal-safe
+        if unsafe { libc::signal(libc::SIGUSR1, handler as libc::sighandler_t) } == libc::SIG_ERR {
+            log::warn!("Failed to install SIGUSR1 handler, cache dropping by signal is disabled");
+            return;
+        }
+
+        let fs = self.clone();
+        thread::spawn(move || {
+            while !fs.shutdown.load(Ordering::Relaxed) {
+                thread::sleep(SIGNAL_POLL_INTERVAL);
+                if DROP_CACHES_REQUESTED.swap(false, Ordering::Relaxed) {
+                    fs.drop_caches("/");
+                }
+            }
+        });
+    }
+
+    // Forgets `path` and everything cached below it, for a file or directory
+    // deleted or replaced: inodes, contents in memory and on disk, listings
+    // and negative entries. A directory made again under the same name starts
+    // out empty instead of showing what the old one held. Files still open
+    // stay reachable by number, as deleted, with their cached contents.
+    fn purge_subtree(&self, path: &str) {
+        let removed = {
+            let mut inodes = self.inodes.write().unwrap();
+            let (open, removed): (Vec<INode>, Vec<INode>) = inodes
+                .remove_subtree(path)
+                .into_iter()
+                .partition(|inode| {
+                    inode.attr.kind != FileType::Directory && self.is_open(inode.ino)
+                });
+            for mut inode in open {
+                log::debug!("{} was deleted while open, keeping it until closed", inode.path);
+                inode.deleted = true;
+                inode.attr.nlink = 0;
+                if let Some(disk_cache) = &self.disk_cache {
+                    disk_cache.discard_file(&inode.path, inode.attr.size);
+                }
+                inodes.keep_deleted(inode);
+            }
+            removed
+        };
+        for inode in &removed {
+            self.blocks.invalidate(inode.ino);
+            match &self.disk_cache {
+                Some(disk_cache) if inode.attr.kind == FileType::Directory => {
+                    disk_cache.discard_listing(&inode.path)
+                }
+                Some(disk_cache) => disk_cache.discard_file(&inode.path, inode.attr.size),
+                None => {}
+            }
+        }
+
+        self.listing_epoch.fetch_add(1, Ordering::Relaxed);
+        self.listings
+            .lock()
+            .unwrap()
+            .retain(|listed, _| !is_under(listed, path));
+        let dirs: HashSet<u64> = removed.iter().map(|inode| inode.ino).collect();
+        self.negative
+            .lock()
+            .unwrap()
+            .retain(|(parent, _), _| !dirs.contains(parent));
+    }
+
+    fn open_handle(&self, ino: u64, remote_size: u64, base: Option<Version>) -> u64 {
+        let seen_changes = self.get_inode(ino).map_or(0, |inode| inode.remote_changes);
+        let mut next_fh = self.next_fh.lock().unwrap();
+        let fh = *next_fh;
+        *next_fh += 1;
+
+        let handle = OpenFile {
+            ino,
+            remote_size,
+            buffer: WriteBuffer::default(),
+            flush_error: None,
+            readahead: ReadAhead::default(),
+            last_write: Instant::now(),
+            mtime: None,
+            journaled: Vec::new(),
+            base,
+            seen_changes,
+            held: false,
+            released: false,
+        };
+        self.file_handles.lock().unwrap().insert(fh, handle);
+
+        fh
+    }
+
+    // Counts and logs a mutation refused because the mount is read-only,
+    // offline without queueing it, under maintenance or being unmounted
+    fn refuse_mutation(&self, op: Op) -> bool {
+        let offline = self.is_offline() && !self.queues_op(op);
+        if !self.config().read_only
+            && !self.draining.load(Ordering::Relaxed)
+            && !offline
+            && !self.in_maintenance()
+        {
+            return false;
+        }
+        log::debug!("Refused {:?} on a read-only, offline, maintenance or unmounting mount", op);
+        self.stats.refused_mutations.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    fn is_open(&self, ino: u64) -> bool {
+        let file_handles = self.file_handles.lock().unwrap();
+        file_handles.values().any(|handle| handle.ino == ino)
+    }
+
+    // Forgets a deleted file once its last handle closed
+    fn drop_if_deleted(&self, ino: u64) {
+        let dropped = {
+            let mut inodes = self.inodes.write().unwrap();
+            let deleted = inodes.get(ino).is_some_and(|inode| inode.deleted);
+            if deleted && !self.is_open(ino) {
+                inodes.remove_ino(ino)
+            } else {
+                None
+            }
+        };
+        if let Some(inode) = dropped {
+            log::debug!("Last handle of deleted {} closed", inode.path);
+            self.blocks.invalidate(ino);
+        }
+    }
+
+    fn has_dirty_data(&self, ino: u64) -> bool {
+        let file_handles = self.file_handles.lock().unwrap();
+        file_handles
+            .values()
+            .any(|handle| handle.ino == ino && !handle.buffer.is_empty())
+    }
+
+    // Uploads the writes buffered on a handle, returning any error from this
+    // or an earlier upload that was not reported yet
+    fn flush_handle(&self, fh: u64) -> Result<()> {
+        let ino = match self.file_handles.lock().unwrap().get(&fh) {
+            Some(handle) => handle.ino,
+            None => return Ok(()),
+        };
+        // Another handle of the same file may be uploading from an older remote copy
+        let _upload = self.upload_locks.lock(ino);
+
+        let (remote_size, buffer, journaled, base, seen_changes, mtime) = {
+            let mut file_handles = self.file_handles.lock().unwrap();
+            let handle = match file_handles.get_mut(&fh) {
+                Some(handle) => handle,
+                None => return Ok(()),
+            };
+
+            if handle.buffer.is_empty() {
+                return match handle.flush_error.take() {
+                    Some(e) => Err(anyhow::anyhow!(e)),
+                    None => Ok(()),
+                };
+            }
+
+            (
+                handle.remote_size,
+                std::mem::take(&mut handle.buffer),
+                std::mem::take(&mut handle.journaled),
+                handle.base.clone(),
+                handle.seen_changes,
+                handle.mtime.take().unwrap_or_else(SystemTime::now),
+            )
+        };
+
+        let queue = self.offline_queue();
+        let inode = self.get_inode(ino);
+        // The buffered changes apply to a copy someone else replaced since
+        let changed = inode
+            .as_ref()
+            .is_some_and(|inode| inode.deleted || inode.remote_changes != seen_changes);
+        let mut result = match (&inode, queue) {
+            (Some(inode), _) if inode.deleted => Err(anyhow::Error::new(FsError::Stale)
+                .context(format!("{} was deleted since it was opened", inode.path))),
+            (Some(inode), _) if changed => Err(anyhow::Error::new(FsError::Stale)
+                .context(format!("{} changed on the server since it was opened", inode.path))),
+            (Some(inode), Some(queue)) => self
+                .queue_buffer(queue, inode, remote_size, &buffer)
+                .map(|size| (size, None)),
+            (Some(inode), None) => with_upload_mtime(mtime, || {
+                self.upload_buffer(&inode.path, remote_size, &buffer, base.as_ref())
+            }),
+            (None, _) => Err(anyhow::anyhow!("inode {} no longer exists", ino)),
+        };
+        // Someone else changed the file since it was opened, their copy stays
+        let mut conflicted = false;
+        if let (Err(e), Some(inode)) = (&result, &inode) {
+            let copy = changed && self.config().on_conflict == ConflictMode::ConflictCopy;
+            if copy || self.is_write_conflict(base.as_ref(), e) {
+                conflicted = true;
+                result = match self.save_conflict_copy(inode, remote_size, &buffer) {
+                    Ok(size) => Ok((size, self.get_inode(ino).and_then(|i| self.write_base(&i)))),
+                    Err(copy_error) => Err(copy_error.context(format!("{:#}", e))),
+                };
+            }
+        }
+
+        let remote_changes = self.get_inode(ino).map_or(seen_changes, |inode| inode.remote_changes);
+        let mut file_handles = self.file_handles.lock().unwrap();
+        match result {
+            Ok((size, version)) => {
+                if let Some(journal) = &self.journal {
+                    journal.complete(&journaled);
+                }
+                if let Some(handle) = file_handles.get_mut(&fh) {
+                    handle.flush_error = None;
+                }
+                // Other handles of the file build on the new remote copy from now on
+                for handle in file_handles.values_mut().filter(|handle| handle.ino == ino) {
+                    handle.remote_size = size;
+                    handle.base = version.clone();
+                    handle.seen_changes = remote_changes;
+                }
+                drop(file_handles);
+                // save_conflict_copy already shows the server's copy
+                if conflicted {
+                    return Ok(());
+                }
+                // queue_buffer already shows the contents in the caches
+                if queue.is_some() {
+                    return Ok(());
+                }
+
+                let first_dirty = buffer.start().unwrap_or(0).min(remote_size);
+                self.blocks.invalidate_range(ino, first_dirty, size.max(remote_size));
+
+                let mut inodes = self.inodes.write().unwrap();
+                if let Some(inode) = inodes.get_mut(ino) {
+                    inode.attr.size = size;
+                    inode.attr.blocks = blocks_of(size);
+                    inode.attr.mtime = mtime;
+                    inode.fetched_at = Instant::now();
+                    // Our own upload changed the version, the next check goes through the listing
+                    inode.version = None;
+                    inode.expects_attrs = true;
+                    let path = inode.path.clone();
+                    drop(inodes);
+                    self.invalidate_parent_listing(&path);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // Changes to a replaced copy can never be uploaded, and kept
+                // they would pin the old attributes for every other handle
+                if let (true, false, Some(inode)) = (changed, conflicted, &inode) {
+                    log::warn!("Discarded writes to {} made before it changed", inode.path);
+                    if let Some(journal) = &self.journal {
+                        journal.complete(&journaled);
+                    }
+                    return Err(e);
+                }
+                // Keep the data so the next flush can retry it
+                if let Some(handle) = file_handles.get_mut(&fh) {
+                    handle.buffer.restore_older(buffer);
+                    handle.journaled.splice(0..0, journaled);
+                    handle.mtime.get_or_insert(mtime);
+                    handle.flush_error = Some(e.to_string());
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Applies the buffered changes to the server's copy, returning the new size
+    // and version. Dirty ranges are written in place when the server supports
+    // partial writes; the whole file is only rewritten after a truncation, when
+    // it does not, or when the upload must find `base` on the server.
+    fn upload_buffer(
+        &self,
+        path: &str,
+        remote_size: u64,
+        buffer: &WriteBuffer,
+        base: Option<&Version>,
+    ) -> Result<(u64, Option<Version>)> {
+        let size = buffer.file_size(remote_size);
+
+        if base.is_none() && !buffer.needs_rewrite(remote_size) {
+            let chunk_size = self.backend.chunk_size() as usize;
+            let mut written = true;
+            'ranges: for (offset, bytes) in buffer.ranges() {
+                for (n, chunk) in bytes.chunks(chunk_size).enumerate() {
+                    let at = offset + (n * chunk_size) as u64;
+                    if !self.backend.write_range(path, at, chunk)? {
+                        written = false;
+                        break 'ranges;
+                    }
+                    self.stats.uploads_issued.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            if written {
+                return Ok((size, None));
+            }
+        }
+
+        if size > self.config().spill_threshold {
+            if let Some(base) = base {
+                self.check_base(path, base)?;
+            }
+            let kept = buffer.kept_remote(remote_size);
+            self.rewrite_spilled(path, kept, size, buffer)?;
+            self.stats.uploads_issued.fetch_add(1, Ordering::Relaxed);
+            return Ok((size, None));
+        }
+
+        let content = self.assemble(path, remote_size, buffer)?;
+        let expected = base.cloned().map_or(Expected::Any, Expected::Version);
+        let version = self.backend.write_file_if(path, &content, &expected)?;
+        self.stats.uploads_issued.fetch_add(1, Ordering::Relaxed);
+
+        Ok((size, version))
+    }
+
+    // The whole contents of a file with the buffered changes applied
+    fn assemble(&self, path: &str, remote_size: u64, buffer: &WriteBuffer) -> Result<Vec<u8>> {
+        let size = buffer.file_size(remote_size);
+        // Only the part of the remote copy that survives and is not overwritten is fetched
+        let kept = buffer.kept_remote(remote_size);
+        let mut content = if buffer.covers_prefix(kept) {
+            Vec::new()
+        } else {
+            self.backend.read_range(path, 0, kept)?.data
+        };
+        content.truncate(kept as usize);
+        buffer.overlay(0, &mut content, size);
+        content.resize(size as usize, 0);
+        Ok(content)
+    }
+
+    // Rewrites a file too large to assemble in memory through a temporary file,
+    // copying the surviving remote data one chunk at a time and streaming the
+    // result back to the server
+    fn rewrite_spilled(&self, path: &str, kept: u64, size: u64, buffer: &WriteBuffer) -> Result<()> {
+        log::debug!("Rewriting {} ({} bytes) through a spill file", path, size);
+        let spill_dir = &self.config().spill_dir;
+        let mut spill = SpillFile::create(spill_dir).with_context(|| {
+            format!("Failed to create spill file in {}", spill_dir.display())
+        })?;
+
+        let chunk_size = self.backend.chunk_size();
+        let mut offset = 0;
+        while offset < kept {
+            let len = chunk_size.min(kept - offset);
+            if !buffer.covers(offset, len) {
+                let data = self.backend.read_range(path, offset, len)?.data;
+                spill.write_at(offset, &data)?;
+                // The remote copy is shorter than we knew, the rest reads as zeros
+                if (data.len() as u64) < len {
+                    break;
+                }
+            }
+            offset += len;
+        }
+
+        for (start, bytes) in buffer.ranges() {
+            spill.write_at(start, bytes)?;
+        }
+        spill.set_len(size)?;
+
+        self.backend.write_file_streamed(path, size, &|| Box::new(spill.reader()))
+    }
+
+    // An mtime set while writes to the file are buffered, on any of its
+    // handles, goes to the server with them. Without any it is sent on its
+    // own, failing with Unsupported where the backend has no way to.
+    fn set_mtime(&self, ino: u64, mtime: TimeOrNow) -> Result<()> {
+        let mtime = match mtime {
+            TimeOrNow::SpecificTime(time) => time,
+            TimeOrNow::Now => SystemTime::now(),
+        };
+        let mut pending = false;
+        for handle in self.file_handles.lock().unwrap().values_mut() {
+            if handle.ino == ino && !handle.buffer.is_empty() {
+                handle.mtime = Some(mtime);
+                pending = true;
+            }
+        }
+
+        let mut inodes = self.inodes.write().unwrap();
+        let inode = inodes
+            .get_mut(ino)
+            .ok_or_else(|| anyhow::anyhow!("inode {} no longer exists", ino))?;
+        if pending {
+            inode.attr.mtime = mtime;
+            return Ok(());
+        }
+        let path = inode.path.clone();
+        if inode.attr.kind == FileType::Directory {
+            return Err(anyhow::Error::new(FsError::Unsupported)
+                .context(format!("Cannot set the modification time of directory {}", path)));
+        }
+        drop(inodes);
+        if self.refuse_mutation(Op::Setattr) {
+            return Err(anyhow::Error::new(FsError::ReadOnly));
+        }
+
+        self.backend.set_mtime(&path, mtime)?;
+        let mut inodes = self.inodes.write().unwrap();
+        if let Some(inode) = inodes.get_mut(ino) {
+            inode.attr.mtime = mtime;
+            inode.fetched_at = Instant::now();
+            // Like after a flush, the next check goes through the listing
+            inode.version = None;
+            inode.expects_attrs = true;
+        }
+        drop(inodes);
+        self.invalidate_parent_listing(&path);
+        Ok(())
+    }
+
+    // Creates `name` in `parent` and opens it, or opens what another client
+    // created there since the kernel looked. Returns the attributes and
+    // handle to reply with, or the errno.
+    fn create_and_open(
+        &self,
+        caller: Caller,
+        parent: u64,
+        name: &OsStr,
+        flags: i32,
+    ) -> Result<(FileAttr, u64), i32> {
+        let path = self
+            .path_from_parent_and_name(parent, name)
+            .map_err(|e| e.errno())?;
+
+        if let Err(e) = self.check_creatable(&path) {
+            self.stats.error(Op::Create);
+            return Err(e.errno());
+        }
+        self.check_entry_change(caller, parent, &path)
+            .map_err(|e| e.errno())?;
+
+        // Create empty file on server
+        match self.create_file(&path) {
+            Ok(_) => {
+                self.forget_missing(parent, &name.to_string_lossy());
+
+                let entry = FileEntry {
+                    name: name.to_string_lossy().to_string(),
+                    is_dir: false,
+                    size: 0,
+                    mtime: Timestamp::now(),
+                    ctime: Timestamp::now(),
+                    mode: 0o644,
+                    id: None,
+                    uid: None,
+                    gid: None,
+                    major: None,
+                    minor: None,
+                    nlink: None,
+                };
+                self.changed_in_parent(&path, Some(&entry));
+                self.entries_changed(split_path(&path).0);
+
+                let ino = self.get_or_create_inode(&path, &entry);
+                self.created_by(ino, &entry, caller);
+                match self.looked_up(ino) {
+                    Some(inode) => Ok((inode.attr, self.open_handle(ino, 0, None))),
+                    None => {
+                        self.stats.error(Op::Create);
+                        Err(FsError::Io.errno())
+                    }
+                }
+            }
+            // Created elsewhere since the kernel looked, opened as it is
+            // unless the caller insisted on a new file
+            Err(e) if FsError::from_backend(&e) == FsError::AlreadyExists => {
+                if flags & libc::O_EXCL != 0 {
+                    self.stats.error(Op::Create);
+                    return Err(FsError::AlreadyExists.errno());
+                }
+                self.forget_missing(parent, &name.to_string_lossy());
+                self.open_existing(caller, &path, flags)
+                    .map_err(|e| self.fail(Op::Create, &path, &e))
+            }
+            Err(e) => Err(self.fail(Op::Create, &path, &e)),
+        }
+    }
+
+    // Opens the file create() found already on the server, made by another
+    // client since the kernel last looked. Its attributes are fetched fresh,
+    // and O_TRUNC is applied here since the kernel expected a new file.
+    fn open_existing(
+        &self,
+        caller: Caller,
+        path: &str,
+        flags: i32,
+    ) -> Result<(FileAttr, u64)> {
+        let (parent, name) = split_path(path);
+        self.invalidate_listing(parent);
+        let entry = self
+            .list_directory(parent)?
+            .iter()
+            .find(|entry| entry.name == name)
+            .cloned()
+            .ok_or_else(|| {
+                anyhow::Error::new(FsError::Stale)
+                    .context(format!("{} existed but is gone again", path))
+            })?;
+        if entry.is_dir {
+            return Err(anyhow::Error::new(FsError::IsADirectory));
+        }
+        if entry_kind(&entry) != FileType::RegularFile {
+            return Err(anyhow::Error::new(FsError::NoDevice));
+        }
+
+        let ino = self.get_or_create_inode(path, &entry);
+        let access = if flags & libc::O_ACCMODE == libc::O_RDWR {
+            READ | WRITE
+        } else {
+            WRITE
+        };
+        self.check_access(caller, ino, access)?;
+        let inode = self
+            .looked_up(ino)
+            .ok_or_else(|| anyhow::anyhow!("inode {} no longer exists", ino))?;
+        let fh = self.open_handle(ino, inode.attr.size, self.write_base(&inode));
+        if flags & libc::O_TRUNC != 0 && inode.attr.size > 0 {
+            if let Err(e) = self.truncate(ino, Some(fh), 0) {
+                self.file_handles.lock().unwrap().remove(&fh);
+                return Err(e);
+            }
+        }
+        let attr = self.get_inode(ino).map_or(inode.attr, |inode| inode.attr);
+        Ok((attr, fh))
+    }
+
+    // Truncates through the given handle, or through a temporary one that is
+    // uploaded right away when the file is not open
+    fn truncate(&self, ino: u64, fh: Option<u64>, size: u64) -> Result<()> {
+        let inode = self
+            .get_inode(ino)
+            .ok_or_else(|| anyhow::anyhow!("inode {} no longer exists", ino))?;
+
+        let open_fh = fh.filter(|fh| self.file_handles.lock().unwrap().contains_key(fh));
+        let fh = match open_fh {
+            Some(fh) => fh,
+            None => self.open_handle(ino, inode.attr.size, self.write_base(&inode)),
+        };
+
+        if let Some(handle) = self.file_handles.lock().unwrap().get_mut(&fh) {
+            if let Some(journal) = &self.journal {
+                let seq = journal
+                    .truncate(&inode.path, size)
+                    .context("Failed to journal the truncation")?;
+                handle.journaled.push(seq);
+            }
+            handle.readahead.cancel();
+            handle.buffer.truncate(size);
+            handle.last_write = Instant::now();
+            handle.mtime = Some(SystemTime::now());
+        }
+
+        {
+            let mut inodes = self.inodes.write().unwrap();
+            if let Some(inode) = inodes.get_mut(ino) {
+                inode.attr.size = size;
+                inode.attr.blocks = blocks_of(size);
+                inode.attr.mtime = SystemTime::now();
+                inode.fetched_at = Instant::now();
+            }
+        }
+
+        if open_fh.is_some() {
+            return Ok(());
+        }
+        let result = self.flush_handle(fh);
+        self.file_handles.lock().unwrap().remove(&fh);
+        result
+    }
+
+    // Reads a byte range through the block cache, fetching each run of
+    // missing blocks with a single ranged request
+    fn read_blocks(&self, inode: &INode, offset: u64, size: u64) -> Result<Vec<u8>> {
+        // Empty files and reads at or past the end have nothing to fetch, the
+        // size was just revalidated. Buffered writes past it are overlaid later.
+        if size == 0 || offset >= inode.attr.size {
+            return Ok(Vec::new());
+        }
+
+        let block_size = self.blocks.block_size();
+        let first = offset / block_size;
+        let last = (offset + size - 1) / block_size;
+        let mut blocks: Vec<Option<Block>> = (first..=last)
+            .map(|index| self.blocks.get(inode.ino, index))
+            .collect();
+
+        let validator = validator(&inode.attr);
+        if let Some(disk_cache) = &self.disk_cache {
+            for (n, slot) in blocks.iter_mut().enumerate() {
+                if slot.is_some() {
+                    continue;
+                }
+                let index = first + n as u64;
+                if let Some(data) = disk_cache.load_block(&inode.path, index, validator) {
+                    let block = Arc::new(data);
+                    self.blocks.insert(inode.ino, index, block.clone());
+                    *slot = Some(block);
+                }
+            }
+        }
+
+        let mut i = 0;
+        while i < blocks.len() {
+            if blocks[i].is_some() {
+                i += 1;
+                continue;
+            }
+
+            let run_start = i;
+            while i < blocks.len() && blocks[i].is_none() {
+                i += 1;
+            }
+
+            if self.is_offline() {
+                return Err(self.not_cached_offline(&inode.path));
+            }
+            // Whatever is at the path now is another file
+            if inode.deleted {
+                return Err(anyhow::Error::new(FsError::Stale)
+                    .context(format!("{} was deleted and is not cached", inode.path)));
+            }
+            let start = (first + run_start as u64) * block_size;
+            let len = (i - run_start) as u64 * block_size;
+            let fetched = self.backend.read_range(&inode.path, start, len)?;
+            self.record_version(inode.ino, fetched.version);
+
+            for (n, chunk) in fetched.data.chunks(block_size as usize).enumerate() {
+                let index = first + (run_start + n) as u64;
+                if let Some(disk_cache) = &self.disk_cache {
+                    disk_cache.store_block(&inode.path, index, validator, chunk);
+                }
+                let block = Arc::new(chunk.to_vec());
+                self.blocks.insert(inode.ino, index, block.clone());
+                blocks[run_start + n] = Some(block);
+            }
+        }
+
+        let end = offset + size;
+        let mut result = Vec::with_capacity(size as usize);
+        for (n, block) in blocks.iter().enumerate() {
+            // Missing blocks lie past the end of the file
+            let block = match block {
+                Some(block) => block,
+                None => break,
+            };
+
+            let block_start = (first + n as u64) * block_size;
+            let from = offset.max(block_start) - block_start;
+            let to = (end.min(block_start + block_size) - block_start).min(block.len() as u64);
+            if from < to {
+                result.extend_from_slice(&block[from as usize..to as usize]);
+            }
+            if (block.len() as u64) < block_size {
+                break;
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Fetches a prefetch window into the block cache in the background. The
+    // requests go through the client's concurrency limiter like any other, and
+    // blocks are only kept while the file still has the version being read.
+    fn spawn_prefetch(&self, inode: &INode, prefetch: Prefetch) {
+        if self.is_offline() {
+            return;
+        }
+        let backend = self.backend.clone();
+        let blocks = self.blocks.clone();
+        let disk_cache = self.disk_cache.clone();
+        let ino = inode.ino;
+        let path = inode.path.clone();
+        let version = inode.version.clone();
+        let validator = validator(&inode.attr);
+
+        thread::spawn(move || {
+            let block_size = blocks.block_size();
+            let last = (prefetch.end - 1) / block_size;
+            let mut index = prefetch.start / block_size;
+
+            // Each run of missing blocks is fetched with one request, which the
+            // client splits into parallel parts when it is large
+            while index <= last && !prefetch.is_cancelled() {
+                if blocks.contains(ino, index) {
+                    index += 1;
+                    continue;
+                }
+
+                let run_start = index;
+                while index <= last && !blocks.contains(ino, index) {
+                    index += 1;
+                }
+
+                let start = run_start * block_size;
+                let len = (index - run_start) * block_size;
+                let fetched = match backend.read_range(&path, start, len) {
+                    Ok(fetched) => fetched,
+                    Err(e) => {
+                        log::debug!("Prefetch of {} stopped: {}", path, e);
+                        break;
+                    }
+                };
+                if prefetch.is_cancelled() {
+                    break;
+                }
+                if version.is_some() && fetched.version != version {
+                    log::debug!("{} changed while prefetching", path);
+                    break;
+                }
+
+                for (n, chunk) in fetched.data.chunks(block_size as usize).enumerate() {
+                    let block_index = run_start + n as u64;
+                    if let Some(disk_cache) = &disk_cache {
+                        disk_cache.store_block(&path, block_index, validator, chunk);
+                    }
+                    blocks.insert(ino, block_index, Arc::new(chunk.to_vec()));
+                }
+                if (fetched.data.len() as u64) < len {
+                    break;
+                }
+            }
+        });
+    }
+
+    // With casefold a name is missing in every case
+    fn negative_key(&self, parent: u64, name: &str) -> (u64, String) {
+        if self.config().casefold {
+            (parent, casefold(name))
+        } else {
+            (parent, name.to_string())
+        }
+    }
+
+    fn is_known_missing(&self, parent: u64, name: &str) -> bool {
+        let key = self.negative_key(parent, name);
+        let mut negative = self.negative.lock().unwrap();
+
+        let missing = match negative.get(&key) {
+            Some(expires) if *expires > Instant::now() => true,
+            Some(_) => {
+                negative.remove(&key);
+                false
+            }
+            None => false,
+        };
+
+        if missing {
+            self.stats.negative.hit();
+        } else {
+            self.stats.negative.miss();
+        }
+        missing
+    }
+
+    fn remember_missing(&self, parent: u64, name: &str) {
+        let timeout = self.config().cache.negative_timeout;
+        if timeout.is_zero() {
+            return;
+        }
+
+        let key = self.negative_key(parent, name);
+        let mut negative = self.negative.lock().unwrap();
+        negative.insert(key, Instant::now() + timeout);
+    }
+
+    fn forget_missing(&self, parent: u64, name: &str) {
+        let key = self.negative_key(parent, name);
+        let mut negative = self.negative.lock().unwrap();
+        negative.remove(&key);
+    }
+
+    fn reply_missing(&self, reply: ReplyEntry) {
+        let timeout = self.config().cache.negative_timeout;
+        if timeout.is_zero() {
+            reply.error(replied(FsError::NotFound.errno()));
+        } else {
+            reply.entry(&timeout, &NEGATIVE_ATTR, 0);
+        }
+    }
+
+    // Names that are not UTF-8 are refused with EILSEQ by every operation
+    // taking one, and backends leave them out of listings, so such a file can
+    // neither be made nor be seen half-working under a mangled name
+    fn path_from_parent_and_name(&self, parent: u64, name: &OsStr) -> Result<String, FsError> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(FsError::NameTooLong);
+        }
+        let name_str = name.to_str().ok_or(FsError::IllegalName)?;
+        // The kernel resolves these itself, requests naming them come from
+        // something else and could address a path outside the parent
+        if !valid_name(name_str) {
+            return Err(FsError::InvalidPath);
+        }
+
+        let (parent_path, known) = {
+            let inodes = self.inodes.read().unwrap();
+            let parent_inode = inodes.get(parent).ok_or(FsError::NotFound)?;
+            if parent_inode.attr.kind != FileType::Directory {
+                return Err(FsError::NotADirectory);
+            }
+            let known = inodes.resolve_path(&join_path(&parent_inode.path, name_str));
+            (parent_inode.path.clone(), known.is_some())
+        };
+
+        // A name differing only in case addresses the entry already there
+        let mut path = join_path(&parent_path, name_str);
+        if self.config().casefold && !known {
+            if let Some(listed) = self.listed_name(&parent_path, name_str) {
+                path = join_path(&parent_path, &listed);
+            }
+        }
+        if path.len() > self.config().max_path_len {
+            return Err(FsError::NameTooLong);
+        }
+        Ok(path)
+    }
+
+    // Entries a server lists under a name that could not be looked up are
+    // left out of the listing rather than shown and then refused, and
+    // excluded ones without a word
+    fn listable(&self, dir: &str, entry: &FileEntry) -> bool {
+        if self.is_excluded(&join_path(dir, &entry.name)) {
+            return false;
+        }
+        let refused = if !valid_name(&entry.name) {
+            "it is not a valid name"
+        } else if entry.name.len() > MAX_NAME_LEN {
+            "the name is too long"
+        } else if join_path(dir, &entry.name).len() > self.config().max_path_len {
+            "the path is too long"
+        } else {
+            return true;
+        };
+        log::warn!("Leaving out {:?} the server lists in {}, {}", entry.name, dir, refused);
+        false
+    }
+
+    // Checks a rename may replace what is at `to`, as rename(2) has it: a
+    // directory only with a directory, and only an empty one. The kernel
+    // looked both paths up just before, so the inode table knows them.
+    fn check_rename(&self, from: &str, to: &str) -> Result<(), FsError> {
+        if from == to {
+            return Ok(());
+        }
+        let from_dir = self.kind_of(from) == Some(FileType::Directory);
+        if from_dir && to.strip_prefix(from).is_some_and(|rest| rest.starts_with('/')) {
+            return Err(FsError::InvalidArgument);
+        }
+        match self.kind_of(to) {
+            None => Ok(()),
+            Some(FileType::Directory) if !from_dir => Err(FsError::IsADirectory),
+            Some(FileType::Directory) => match self.list_directory(to) {
+                Ok(entries) if !entries.is_empty() => Err(FsError::NotEmpty),
+                // The server has the final word on what it could not list
+                _ => Ok(()),
+            },
+            Some(_) if from_dir => Err(FsError::NotADirectory),
+            Some(_) => Ok(()),
+        }
+    }
+
+    // Kind of a known path, None when it was never looked up
+    fn kind_of(&self, path: &str) -> Option<FileType> {
+        self.inodes
+            .read()
+            .unwrap()
+            .get_path(path)
+            .map(|inode| inode.attr.kind)
+    }
+
+    fn path_of(&self, ino: u64) -> String {
+        self.get_inode(ino)
+            .map_or_else(|| format!("inode {}", ino), |inode| inode.path)
+    }
+
+    // Logs a failed backend call and counts it, returning the errno to reply with
+    fn fail(&self, op: Op, path: &str, error: &anyhow::Error) -> i32 {
+        // Mutations turned down for maintenance fail like on a read-only mount
+        let kind = if maintenance::mutates(op) && self.note_maintenance(error) {
+            FsError::ReadOnly
+        } else {
+            match (FsError::from_backend(error), op) {
+                // A conflict removing a directory is its remaining entries
+                (FsError::AlreadyExists, Op::Rmdir) => FsError::NotEmpty,
+                // So is a file gone from the server while it is read
+                (FsError::NotFound, Op::Read) => FsError::Stale,
+                (kind, _) => kind,
+            }
+        };
+        match &self.config().label {
+            Some(label) => log::error!(
+                "[{}] {:?} of {} failed, {:?}: {:#}",
+                label,
+                op,
+                path,
+                kind,
+                error
+            ),
+            None => log::error!("{:?} of {} failed, {:?}: {:#}", op, path, kind, error),
+        }
+        self.stats.error(op);
+        kind.errno()
+    }
+
+    pub fn cache_usage(&self) -> CacheUsage {
+        let (data_bytes, data_blocks) = self.blocks.usage();
+        let dirty_bytes = self
+            .file_handles
+            .lock()
+            .unwrap()
+            .values()
+            .map(|handle| handle.buffer.dirty_bytes())
+            .sum();
+
+        CacheUsage {
+            data_bytes,
+            data_blocks,
+            attr_entries: self.inodes.read().unwrap().len(),
+            listing_entries: self.listings.lock().unwrap().len(),
+            negative_entries: self.negative.lock().unwrap().len(),
+            dirty_bytes,
+        }
+    }
+
+    // Counters since mount, including the HTTP traffic of the client. The
+    // coalescing ratio of writes is writes_received over uploads_issued.
+    pub fn stats_snapshot(&self) -> StatsSnapshot {
+        let data_cache = CacheStats {
+            hits: self.blocks.hits(),
+            misses: self.blocks.misses(),
+        };
+        let inodes = self.inodes.read().unwrap().len();
+        let queued = self.change_queue.as_ref().map_or(0, |queue| queue.len());
+        let offline = self.is_offline();
+        let maintenance = self.in_maintenance();
+        let http = self.backend.stats();
+        self.stats
+            .snapshot(data_cache, inodes, offline, maintenance, queued, http)
+    }
+
+    // Uploads buffers of handles that saw no writes for write_debounce, so
+    // bursts of small writes turn into a few large uploads. Handles whose last
+    // upload failed are left for the next explicit flush to retry and report.
+    fn spawn_writeback(&self) {
+        let debounce = self.config().write_debounce;
+        if debounce.is_zero() {
+            return;
+        }
+
+        let fs = self.clone();
+        thread::spawn(move || {
+            while !fs.shutdown.load(Ordering::Relaxed) {
+                thread::sleep(debounce / 2);
+                // Held buffers wait for the prober
+                if fs.in_maintenance() {
+                    continue;
+                }
+
+                let idle: Vec<u64> = fs
+                    .file_handles
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(_, handle)| {
+                        !handle.buffer.is_empty()
+                            && handle.flush_error.is_none()
+                            && handle.last_write.elapsed() >= debounce
+                    })
+                    .map(|(&fh, _)| fh)
+                    .collect();
+
+                for fh in idle {
+                    if let Err(e) = fs.flush_handle(fh) {
+                        fs.note_maintenance(&e);
+                        log::warn!("Background write-back of handle {} failed: {}", fh, e);
+                    }
+                }
+            }
+        });
+    }
+
+    // Walks the preload subtrees without delaying the mount. Requests go through
+    // the client's limiter like any other, and the walk stops at unmount.
+    fn spawn_preload(&self) {
+        if self.config().preload.is_empty() {
+            return;
+        }
+
+        let fs = self.clone();
+        thread::spawn(move || {
+            let started = Instant::now();
+            let mut entries = 0;
+            let mut bytes = 0;
+
+            let config = fs.config();
+            for root in &config.preload {
+                let root = normalize_path(root);
+
+                // Listing the ancestors lets lookups reach the subtree from the cache too
+                let mut ancestor = "/".to_string();
+                for name in root.split('/').filter(|name| !name.is_empty()) {
+                    if let Ok(listing) = fs.list_directory(&ancestor) {
+                        if let Some(entry) = listing.iter().find(|entry| entry.name == name) {
+                            fs.get_or_create_inode(&join_path(&ancestor, name), entry);
+                        }
+                    }
+                    ancestor = join_path(&ancestor, name);
+                }
+
+                // One archive of the subtree replaces the walk when file contents
+                // are wanted too, except for files the data cache could not hold
+                let data_max = config.preload_data_max.min(config.cache.max_data_bytes as u64);
+                if data_max > 0 {
+                    match fs.preload_archive(&root, data_max) {
+                        Ok(Some(unpacked)) => {
+                            entries += unpacked.entries;
+                            bytes += unpacked.bytes;
+                            continue;
+                        }
+                        Ok(None) => {}
+                        Err(e) => log::warn!("{:#}, walking it instead", e),
+                    }
+                }
+
+                let mut pending = vec![root];
+                while let Some(dir) = pending.pop() {
+                    if fs.shutdown.load(Ordering::Relaxed) {
+                        log::info!("Preload aborted after {} entries", entries);
+                        return;
+                    }
+
+                    let listing = match fs.list_directory(&dir) {
+                        Ok(listing) => listing,
+                        Err(e) => {
+                            log::warn!("Failed to preload {}: {}", dir, e);
+                            continue;
+                        }
+                    };
+
+                    for entry in listing.iter() {
+                        let path = join_path(&dir, &entry.name);
+                        let ino = fs.get_or_create_inode(&path, entry);
+                        entries += 1;
+
+                        if entry.is_dir {
+                            pending.push(path);
+                        } else if entry.size > 0 && entry.size <= fs.config().preload_data_max {
+                            let read = fs
+                                .get_inode(ino)
+                                .map(|inode| fs.read_blocks(&inode, 0, entry.size));
+                            match read {
+                                Some(Ok(data)) => bytes += data.len(),
+                                Some(Err(e)) => log::debug!("Failed to preload {}: {}", path, e),
+                                None => {}
+                            }
+                        }
+                    }
+                }
+            }
+
+            log::info!(
+                "Preloaded {} entries and {} bytes in {:.1?}",
+                entries,
+                bytes,
+                started.elapsed()
+            );
+        });
+    }
+
+    // Revalidates the most used listings shortly before they expire, so hot
+    // directories never wait on the server. Attributes of known children are
+    // refreshed along, except for files with dirty data, which
+    // get_or_create_inode leaves alone. Rounds are skipped while the server is
+    // failing.
+    fn spawn_refresher(&self) {
+        let interval = self.config().refresh_interval;
+        if interval.is_zero() || self.config().refresh_top_n == 0 {
+            return;
+        }
+
+        let fs = self.clone();
+        thread::spawn(move || {
+            while !fs.shutdown.load(Ordering::Relaxed) {
+                thread::sleep(interval);
+                if !fs.backend.is_healthy() {
+                    continue;
+                }
+
+                // Anything that would expire before the next round is refreshed now
+                let due = fs.config().cache.listing_timeout.saturating_sub(interval);
+                let mut hot: Vec<(u64, String)> = fs
+                    .listings
+                    .lock()
+                    .unwrap()
+                    .iter_mut()
+                    .filter_map(|(path, listing)| {
+                        let hits = listing.hits;
+                        listing.hits /= 2;
+                        let due = hits > 0 && listing.fetched_at.elapsed() >= due;
+                        due.then(|| (hits, path.clone()))
+                    })
+                    .collect();
+                hot.sort_by_key(|&(hits, _)| Reverse(hits));
+                hot.truncate(fs.config().refresh_top_n);
+
+                for (_, path) in hot {
+                    let expired = fs.listings.lock().unwrap().get(&path).and_then(|listing| {
+                        let entries = listing.entries.clone();
+                        listing.version.clone().map(|version| (version, entries))
+                    });
+                    match fs.fetch_listing(&path, expired) {
+                        Ok(entries) => {
+                            for entry in entries.iter() {
+                                let child = join_path(&path, &entry.name);
+                                if fs.inodes.read().unwrap().resolve_path(&child).is_some() {
+                                    fs.get_or_create_inode(&child, entry);
+                                }
+                            }
+                        }
+                        Err(e) => log::debug!("Failed to refresh listing of {}: {}", path, e),
+                    }
+                }
+            }
+        });
+    }
+
+    // Trace of an operation on `ino`, or on `name` inside it, to be dropped
+    // once it replied. The path and `args` are only resolved when they may be
+    // logged or, when built with tracing, go into a span.
+    fn trace(
+        &self,
+        op: Op,
+        req: &Request,
+        ino: u64,
+        name: Option<&OsStr>,
+        args: impl FnOnce() -> String,
+    ) -> OpTrace {
+        let config = self.config();
+        let spans = cfg!(feature = "tracing");
+        let described = config.trace_ops || config.slow_op.is_some() || spans;
+        let (path, args) = if described {
+            let path = match name {
+                Some(name) => self.path_from_parent_and_name(ino, name).unwrap_or_else(|_| {
+                    format!("{}/{}", self.path_of(ino), name.to_string_lossy())
+                }),
+                None => self.path_of(ino),
+            };
+            (path, args())
+        } else {
+            (String::new(), String::new())
+        };
+        OpTrace::start(op, req.unique(), req.pid(), path, args, &config, self.stats.clone())
+    }
+
+    // Serves an operation on a worker thread once the session is up, inside
+    // `trace`. Replies are sent from wherever `op` finishes, so the session
+    // thread is free to read the next request meanwhile.
+    fn dispatch<F>(&self, trace: OpTrace, op: F)
+    where
+        F: FnOnce(&RemoteFS) + Send + 'static,
+    {
+        let traced = move |fs: &RemoteFS| trace.run(|| op(fs));
+        match self.dispatcher.get() {
+            Some(dispatcher) => {
+                let fs = self.clone();
+                dispatcher.run(move || traced(&fs));
+            }
+            None => traced(self),
+        }
+    }
+
+    // Stops taking mutations and ends background work, before the last flush
+    fn begin_shutdown(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+        self.shutdown.store(true, Ordering::Relaxed);
+        for handle in self.file_handles.lock().unwrap().values_mut() {
+            handle.readahead.cancel();
+        }
+    }
+
+    // Uploads the buffered writes of every handle, returning the first failure
+    fn flush_all(&self) -> Result<()> {
+        let dirty: Vec<u64> = self
+            .file_handles
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, handle)| !handle.buffer.is_empty())
+            .map(|(&fh, _)| fh)
+            .collect();
+
+        let mut result = Ok(());
+        for fh in dirty {
+            if let Err(e) = self.flush_handle(fh) {
+                log::error!("Failed to write back handle {}: {}", fh, e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    // Mount options for this configuration, checked before mounting so a
+    // refusal comes with a reason instead of the kernel's EPERM
+    pub fn mount_options(&self) -> Result<Vec<MountOption>> {
+        let config = self.config();
+        let mut options = vec![
+            if config.read_only {
+                MountOption::RO
+            } else {
+                MountOption::RW
+            },
+            MountOption::FSName("remotefs".to_string()),
+        ];
+
+        if config.allow_other && config.allow_root {
+            anyhow::bail!("allow_other and allow_root cannot be used together");
+        }
+        if config.allow_other || config.allow_root {
+            check_user_allow_other()?;
+        }
+        if config.allow_other {
+            options.push(MountOption::AllowOther);
+        }
+        if config.allow_root {
+            options.push(MountOption::AllowRoot);
+        }
+        if self.enforces_permissions() {
+            options.push(MountOption::DefaultPermissions);
+        }
+
+        Ok(options)
+    }
+
+    // Sets up what the session needs before it starts serving requests
+    fn prepare_mount(&self) -> Result<()> {
+        let config = self.config();
+        config.validate()?;
+        self.replay_journal();
+        if let Some(addr) = config.metrics_addr {
+            metrics::spawn(self, addr)?;
+        }
+        if let Some(path) = &config.control_socket {
+            control::spawn(self, path)?;
+        }
+        CacheTrimmer::new(self).spawn();
+        if config.max_concurrent_ops > 1 {
+            let name = config.label.as_deref().unwrap_or("fuse");
+            let _ = self
+                .dispatcher
+                .set(Dispatcher::new(name, config.max_concurrent_ops));
+        }
+        Ok(())
+    }
+
+    // Starts the background tasks once the session is up
+    fn start_background(&self, notifier: Notifier) {
+        let _ = self.notifier.set(Arc::new(notifier));
+        self.spawn_writeback();
+        self.spawn_preload();
+        self.spawn_refresher();
+        self.spawn_watches();
+        self.spawn_signal_watcher();
+        let config = self.config();
+        if config.offline_mode == OfflineMode::Auto {
+            self.spawn_offline_watcher();
+        }
+        if matches!(config.notify, NotifyMode::Ws | NotifyMode::Sse) {
+            self.spawn_event_listener(config.notify);
+        }
+    }
+
+    pub fn mount(self, mountpoint: &str) -> Result<()> {
+        let options = self.mount_options()?;
+        self.prepare_mount()?;
+        let background = self.clone();
+
+        log::info!("Mounting filesystem at {}", mountpoint);
+        // Background work started by prepare_mount stops if mounting fails
+        let mut session = Session::new(self, Path::new(mountpoint), &options)
+            .inspect_err(|_| background.shutdown.store(true, Ordering::Relaxed))?;
+        background.start_background(session.notifier());
+        session.run()?;
+        Ok(())
+    }
+
+    // Mounts on a thread of its own and returns right away; the guard unmounts
+    // when dropped. Without options the ones of mount() are used.
+    pub fn spawn_mount(self, mountpoint: &str, options: &[MountOption]) -> Result<MountGuard> {
+        let options = if options.is_empty() {
+            self.mount_options()?
+        } else {
+            options.to_vec()
+        };
+        self.prepare_mount()?;
+        let background = self.clone();
+
+        log::info!("Mounting filesystem at {} in the background", mountpoint);
+        let session = fuser::spawn_mount2(self, mountpoint, &options)
+            .inspect_err(|_| background.shutdown.store(true, Ordering::Relaxed))?;
+        background.start_background(session.notifier());
+        Ok(MountGuard::new(mountpoint, session, background))
+    }
+}
+
+impl Filesystem for RemoteFS {
+    fn destroy(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        self.forget_lookups(ino, nlookup);
+    }
+
+    // The server reports no capacity, only the sizes are meaningful. The
+    // fragment size matches the block size, the unit df counts blocks in.
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: fuser::ReplyStatfs) {
+        let blksize = self.config().blksize;
+        reply.statfs(0, 0, 0, 0, 0, blksize, MAX_NAME_LEN as u32, blksize);
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        log::debug!("lookup(parent={}, name={:?})", parent, name);
+        self.stats.call(Op::Lookup);
+        let trace = self.trace(Op::Lookup, req, parent, Some(name), || {
+            format!("parent={} name={:?}", parent, name)
+        });
+
+        let name = name.to_owned();
+        self.dispatch(trace, move |fs| {
+            let name = name.as_os_str();
+
+            let path = match fs.path_from_parent_and_name(parent, name) {
+                Ok(p) => p,
+                Err(e) => {
+                    reply.error(replied(e.errno()));
+                    return;
+                }
+            };
+
+            let name_str = name.to_string_lossy();
+            if fs.shows_stats_file(parent, &name_str) {
+                fs.reply_stats_file(reply);
+                return;
+            }
+            if fs.is_excluded(&path) {
+                fs.reply_missing(reply);
+                return;
+            }
+            if fs.is_known_missing(parent, &name_str) {
+                fs.reply_not_on_server(parent, &name_str, reply);
+                return;
+            }
+
+            // Check if we already have fresh attributes for this inode, or
+            // any while offline
+            let cached = fs.inodes.read().unwrap().get_path(&path).cloned();
+            if let Some(inode) = cached {
+                let offline = fs.is_offline();
+                if offline {
+                    fs.served_offline(&path);
+                }
+                if offline || inode.is_fresh(fs.config().cache.attr_timeout) {
+                    if let Some(inode) = fs.looked_up(inode.ino) {
+                        fs.stats.attrs.hit();
+                        reply.entry(&TTL, &inode.attr, 0);
+                        return;
+                    }
+                }
+            }
+            fs.stats.attrs.miss();
+
+            // Try to get parent directory listing to find this entry
+            let parent_inode = match fs.get_inode(parent) {
+                Some(inode) => inode,
+                None => {
+                    reply.error(replied(FsError::NotFound.errno()));
+                    return;
+                }
+            };
+
+            match fs.list_directory(&parent_inode.path) {
+                Ok(entries) => {
+                    if let Some(entry) = fs.find_entry(&entries, &parent_inode.path, &name_str) {
+                        let full_path = join_path(&parent_inode.path, &entry.name);
 
-                        let ino = self.get_or_create_inode(&full_path, &entry);
-                        if let Some(inode) = self.get_inode(ino) {
+                        let ino = fs.get_or_create_inode(&full_path, entry);
+                        if let Some(inode) = fs.looked_up(ino) {
                             reply.entry(&TTL, &inode.attr, 0);
                             return;
                         }
                     }
+                    fs.purge_subtree(&path);
+                    fs.remember_missing(parent, &name_str);
+                    fs.reply_not_on_server(parent, &name_str, reply);
                 }
-                reply.error(ENOENT);
+                Err(e) => reply.error(replied(fs.fail(Op::Lookup, &parent_inode.path, &e))),
             }
-            Err(e) => {
-                log::error!("Failed to list directory: {}", e);
-                reply.error(ENOENT);
+        });
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        log::debug!("getattr(ino={})", ino);
+        self.stats.call(Op::Getattr);
+        let trace = self.trace(Op::Getattr, req, ino, None, || format!("ino={}", ino));
+
+        self.dispatch(trace, move |fs| {
+            if ino == STATS_INO {
+                reply.attr(&Duration::ZERO, &fs.stats_file_attr());
+                return;
             }
+            match fs.revalidate_inode(ino) {
+                Some(inode) => reply.attr(&TTL, &inode.attr),
+                None => reply.error(replied(FsError::NotFound.errno())),
+            }
+        });
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        log::debug!("setattr(ino={}, size={:?}, fh={:?})", ino, size, fh);
+        self.stats.call(Op::Setattr);
+        let trace = self.trace(Op::Setattr, req, ino, None, || {
+            format!("ino={} size={:?} fh={:?}", ino, size, fh)
+        });
+
+        if ino == STATS_INO {
+            reply.error(replied(FsError::PermissionDenied.errno()));
+            return;
         }
+        if size.is_some() && self.refuse_mutation(Op::Setattr) {
+            reply.error(replied(FsError::ReadOnly.errno()));
+            return;
+        }
+        if size.is_some_and(|size| size > i64::MAX as u64) {
+            reply.error(replied(FsError::FileTooLarge.errno()));
+            return;
+        }
+
+        self.dispatch(trace, move |fs| {
+            if let Some(size) = size {
+                if let Err(e) = fs.truncate(ino, fh, size) {
+                    reply.error(replied(fs.fail(Op::Setattr, &fs.path_of(ino), &e)));
+                    return;
+                }
+            }
+
+            if let Some(mtime) = mtime {
+                if let Err(e) = fs.set_mtime(ino, mtime) {
+                    reply.error(replied(fs.fail(Op::Setattr, &fs.path_of(ino), &e)));
+                    return;
+                }
+            }
+
+            // The server has no way to change the other attributes, they are left as they are
+            match fs.get_inode(ino) {
+                Some(inode) => reply.attr(&TTL, &inode.attr),
+                None => reply.error(replied(FsError::NotFound.errno())),
+            }
+        });
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        log::debug!("getattr(ino={})", ino);
+    fn ioctl(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        _in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        log::debug!("ioctl(ino={}, cmd={:#x})", ino, cmd);
+        self.stats.call(Op::Ioctl);
+        let _trace = self.trace(Op::Ioctl, req, ino, None, || {
+            format!("ino={} cmd={:#x}", ino, cmd)
+        });
+
+        if cmd != DROP_CACHES_IOCTL {
+            reply.error(replied(libc::ENOTTY));
+            return;
+        }
 
         match self.get_inode(ino) {
-            Some(inode) => reply.attr(&TTL, &inode.attr),
-            None => reply.error(ENOENT),
+            Some(inode) => {
+                self.drop_caches(&inode.path);
+                reply.ioctl(0, &[]);
+            }
+            None => reply.error(replied(FsError::NotFound.errno())),
         }
     }
 
     fn readdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         _fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
         log::debug!("readdir(ino={}, offset={})", ino, offset);
-
-        let inode = match self.get_inode(ino) {
-            Some(inode) => inode,
-            None => {
-                reply.error(ENOENT);
+        self.stats.call(Op::Readdir);
+        let trace = self.trace(Op::Readdir, req, ino, None, || {
+            format!("ino={} offset={}", ino, offset)
+        });
+
+        self.dispatch(trace, move |fs| {
+            let inode = match fs.get_inode(ino) {
+                Some(inode) => inode,
+                None => {
+                    reply.error(replied(FsError::NotFound.errno()));
+                    return;
+                }
+            };
+            if inode.attr.kind != FileType::Directory {
+                reply.error(replied(FsError::NotADirectory.errno()));
                 return;
             }
-        };
 
-        match self.api_client.list_directory(&inode.path) {
-            Ok(entries) => {
-                let mut i = offset;
+            match fs.open_listing(&inode.path) {
+                Ok(entries) => {
+                    let mut i = offset;
 
-                if i == 0 {
-                    if reply.add(ino, i + 1, FileType::Directory, ".") {
-                        reply.ok();
-                        return;
+                    if i == 0 {
+                        if reply.add(ino, i + 1, FileType::Directory, ".") {
+                            reply.ok();
+                            return;
+                        }
+                        i += 1;
                     }
-                    i += 1;
-                }
 
-                if i == 1 {
-                    if reply.add(ino, i + 1, FileType::Directory, "..") {
-                        reply.ok();
-                        return;
+                    if i == 1 {
+                        if reply.add(ino, i + 1, FileType::Directory, "..") {
+                            reply.ok();
+                            return;
+                        }
+                        i += 1;
                     }
-                    i += 1;
-                }
 
-                for (_idx, entry) in entries.iter().enumerate().skip((i - 2).max(0) as usize) {
-                    let full_path = if inode.path == "/" {
-                        format!("/{}", entry.name)
-                    } else {
-                        format!("{}/{}", inode.path, entry.name)
-                    };
+                    // Offsets follow the server's entries, entry n is at n + 3
+                    // even while later ones are still arriving. The stats
+                    // file hides a server file of its name and is listed
+                    // after the server's entries.
+                    let stats_file = ino == FUSE_ROOT_ID && fs.config().show_stats_file;
+                    let mut index = (i - 2) as usize;
+                    loop {
+                        let entry = match entries.get(index) {
+                            Ok(Some(entry)) => entry,
+                            Ok(None) => break,
+                            Err(e) => {
+                                reply.error(replied(fs.fail(Op::Readdir, &inode.path, &e)));
+                                return;
+                            }
+                        };
+                        index += 1;
+                        if stats_file && entry.name == STATS_FILE {
+                            continue;
+                        }
 
-                    let entry_ino = self.get_or_create_inode(&full_path, entry);
-                    let kind = if entry.is_dir {
-                        FileType::Directory
-                    } else {
-                        FileType::RegularFile
-                    };
+                        let full_path = join_path(&inode.path, &entry.name);
 
-                    if reply.add(entry_ino, i + 1, kind, &entry.name) {
-                        break;
+                        let entry_ino = fs.get_or_create_inode(&full_path, &entry);
+                        let kind = entry_kind(&entry);
+
+                        if reply.add(entry_ino, index as i64 + 2, kind, &entry.name) {
+                            reply.ok();
+                            return;
+                        }
                     }
-                    i += 1;
-                }
 
-                reply.ok();
-            }
-            Err(e) => {
-                log::error!("Failed to list directory: {}", e);
-                reply.error(libc::EIO);
+                    // Past the end when the stats file was listed already. A
+                    // full buffer leaves it to the call resuming at the last
+                    // server entry's offset, which lands here again.
+                    if stats_file && index == entries.len() {
+                        let offset = index as i64 + 3;
+                        if reply.add(STATS_INO, offset, FileType::RegularFile, STATS_FILE) {
+                            reply.ok();
+                            return;
+                        }
+                    }
+
+                    reply.ok();
+                }
+                Err(e) => reply.error(replied(fs.fail(Op::Readdir, &inode.path, &e))),
             }
-        }
+        });
     }
 
     fn read(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
@@ -298,36 +3181,91 @@ impl Filesystem for RemoteFS {
         reply: ReplyData,
     ) {
         log::debug!("read(ino={}, offset={}, size={})", ino, offset, size);
+        self.stats.call(Op::Read);
+        let trace = self.trace(Op::Read, req, ino, None, || {
+            format!("ino={} offset={} size={}", ino, offset, size)
+        });
+
+        let Some((offset, size)) = read_range(offset, size) else {
+            reply.error(replied(FsError::InvalidArgument.errno()));
+            return;
+        };
+        self.dispatch(trace, move |fs| {
+            if ino == STATS_INO {
+                reply.data(&fs.read_stats_file(fh, offset, size));
+                return;
+            }
+            let inode = match fs.revalidate_inode(ino) {
+                Some(inode) => inode,
+                None => {
+                    reply.error(replied(FsError::NotFound.errno()));
+                    return;
+                }
+            };
 
-        let inode = match self.get_inode(ino) {
-            Some(inode) => inode,
-            None => {
-                reply.error(ENOENT);
+            if let Err(e) = fs.check_handle(fh, &inode) {
+                reply.error(replied(fs.fail(Op::Read, &inode.path, &e)));
                 return;
             }
-        };
 
-        match self.api_client.read_file(&inode.path) {
-            Ok(data) => {
-                let start = offset as usize;
-                let end = (start + size as usize).min(data.len());
+            let needs_remote = fs
+                .file_handles
+                .lock()
+                .unwrap()
+                .get(&fh)
+                .is_none_or(|handle| handle.needs_remote(offset, size));
+            let result = if needs_remote {
+                fs.read_blocks(&inode, offset, size)
+            } else {
+                Ok(Vec::new())
+            };
+
+            match result {
+                Ok(mut data) => {
+                    let prefetch = {
+                        let mut file_handles = fs.file_handles.lock().unwrap();
+                        match file_handles.get_mut(&fh) {
+                            Some(handle) => {
+                                if !handle.buffer.is_empty() {
+                                    // Cut off truncated data and zero-fill holes up
+                                    // to the buffered size
+                                    let file_size = handle.buffer.file_size(handle.remote_size);
+                                    let len = file_size.saturating_sub(offset).min(size);
+                                    data.resize(len as usize, 0);
+                                }
+                                // Writes buffered on this handle take precedence
+                                // over the server's copy
+                                handle.buffer.overlay(offset, &mut data, size);
+                                handle.readahead.on_read(
+                                    offset,
+                                    size,
+                                    fs.config().readahead_window,
+                                    inode.attr.size,
+                                )
+                            }
+                            None => None,
+                        }
+                    };
+                    reply.data(&data);
 
-                if start >= data.len() {
-                    reply.data(&[]);
-                } else {
-                    reply.data(&data[start..end]);
+                    if let Some(prefetch) = prefetch {
+                        fs.spawn_prefetch(&inode, prefetch);
+                    }
+                }
+                Err(e) => {
+                    // Blocks already cached stay readable through the handle
+                    if FsError::from_backend(&e) == FsError::NotFound {
+                        fs.purge_subtree(&inode.path);
+                    }
+                    reply.error(replied(fs.fail(Op::Read, &inode.path, &e)));
                 }
             }
-            Err(e) => {
-                log::error!("Failed to read file: {}", e);
-                reply.error(libc::EIO);
-            }
-        }
+        });
     }
 
     fn write(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         fh: u64,
         offset: i64,
@@ -338,51 +3276,230 @@ impl Filesystem for RemoteFS {
         reply: ReplyWrite,
     ) {
         log::debug!("write(ino={}, fh={}, offset={}, size={})", ino, fh, offset, data.len());
-
-        let inode = match self.get_inode(ino) {
-            Some(inode) => inode,
-            None => {
-                reply.error(ENOENT);
+        self.stats.call(Op::Write);
+        let trace = self.trace(Op::Write, req, ino, None, || {
+            format!("ino={} fh={} offset={} size={}", ino, fh, offset, data.len())
+        });
+        self.stats.writes_received.fetch_add(1, Ordering::Relaxed);
+
+        if ino == STATS_INO {
+            reply.error(replied(FsError::PermissionDenied.errno()));
+            return;
+        }
+        if self.refuse_mutation(Op::Write) {
+            reply.error(replied(FsError::ReadOnly.errno()));
+            return;
+        }
+        let (offset, end_offset) = match write_range(offset, data.len()) {
+            Ok(range) => range,
+            Err(e) => {
+                reply.error(replied(e.errno()));
                 return;
             }
         };
 
-        // Read existing file data
-        let mut file_data = match self.api_client.read_file(&inode.path) {
-            Ok(data) => data,
-            Err(_) => Vec::new(), // New file
-        };
+        let data = data.to_vec();
+        self.dispatch(trace, move |fs| {
+            let data = data.as_slice();
 
-        // Expand file if necessary
-        let end_offset = (offset as usize) + data.len();
-        if end_offset > file_data.len() {
-            file_data.resize(end_offset, 0);
-        }
+            let inode = match fs.get_inode(ino) {
+                Some(inode) => inode,
+                None => {
+                    reply.error(replied(FsError::NotFound.errno()));
+                    return;
+                }
+            };
+            // Writes to a deleted file can only end up in a conflict copy
+            if inode.deleted && fs.config().on_conflict != ConflictMode::ConflictCopy {
+                fs.stats.error(Op::Write);
+                reply.error(replied(FsError::Stale.errno()));
+                return;
+            }
 
-        // Write data at offset
-        file_data[offset as usize..end_offset].copy_from_slice(data);
+            // Buffer the data, it is uploaded on flush, fsync or release
+            let over_threshold = {
+                let mut file_handles = fs.file_handles.lock().unwrap();
+                match file_handles.get_mut(&fh) {
+                    Some(handle) => {
+                        // Journaled before it is acknowledged
+                        let journal = fs.journal.as_ref().filter(|_| !data.is_empty());
+                        match journal.map(|journal| journal.write(&inode.path, offset, data)) {
+                            Some(Ok(seq)) => handle.journaled.push(seq),
+                            Some(Err(e)) => {
+                                log::error!("Failed to journal a write to {}: {}", inode.path, e);
+                                reply.error(replied(FsError::Io.errno()));
+                                return;
+                            }
+                            None => {}
+                        }
+                        // Data prefetched from now on could predate the upload of these writes
+                        handle.readahead.cancel();
+                        handle.buffer.write(offset, data);
+                        handle.last_write = Instant::now();
+                        handle.mtime = Some(SystemTime::now());
+                        handle.buffer.dirty_bytes() > fs.config().flush_threshold
+                    }
+                    None => {
+                        reply.error(replied(FsError::BadHandle.errno()));
+                        return;
+                    }
+                }
+            };
 
-        // Write back to server
-        match self.api_client.write_file(&inode.path, &file_data) {
-            Ok(_) => {
-                // Update inode size
-                let mut inodes = self.inodes.lock().unwrap();
-                if let Some(inode) = inodes.get_mut(&ino) {
-                    inode.attr.size = file_data.len() as u64;
+            {
+                let mut inodes = fs.inodes.write().unwrap();
+                if let Some(inode) = inodes.get_mut(ino) {
+                    inode.attr.size = inode.attr.size.max(end_offset);
+                    inode.attr.blocks = blocks_of(inode.attr.size);
                     inode.attr.mtime = SystemTime::now();
+                    inode.fetched_at = Instant::now();
                 }
-                reply.written(data.len() as u32);
             }
-            Err(e) => {
-                log::error!("Failed to write file: {}", e);
-                reply.error(libc::EIO);
+
+            if over_threshold {
+                // A failure is kept on the handle and reported by the next flush
+                if let Err(e) = fs.flush_handle(fh) {
+                    fs.note_maintenance(&e);
+                    log::error!("Failed to write back {}: {}", inode.path, e);
+                }
+            }
+
+            reply.written(data.len() as u32);
+        });
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        log::debug!("open(ino={})", ino);
+        self.stats.call(Op::Open);
+        let _trace = self.trace(Op::Open, req, ino, None, || {
+            format!("ino={} flags={:#o}", ino, flags)
+        });
+
+        let writes = flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0;
+        if ino == STATS_INO {
+            if writes {
+                reply.error(replied(FsError::PermissionDenied.errno()));
+            } else {
+                // Reads go past the size from getattr, for a snapshot taken later
+                reply.opened(self.open_stats_file(), fuser::consts::FOPEN_DIRECT_IO);
+            }
+            return;
+        }
+        if writes && self.refuse_mutation(Op::Open) {
+            reply.error(replied(FsError::ReadOnly.errno()));
+            return;
+        }
+        let access = match flags & libc::O_ACCMODE {
+            libc::O_WRONLY => WRITE,
+            libc::O_RDWR => READ | WRITE,
+            _ if writes => READ | WRITE,
+            _ => READ,
+        };
+        if let Err(e) = self.check_access(Caller::of(req), ino, access) {
+            reply.error(replied(e.errno()));
+            return;
+        }
+
+        match self.get_inode(ino) {
+            // Links, pipes, sockets and devices have no contents to read
+            Some(inode) if inode.attr.kind != FileType::RegularFile => {
+                reply.error(replied(FsError::NoDevice.errno()))
+            }
+            Some(inode) => {
+                let base = if writes {
+                    self.write_base(&inode)
+                } else {
+                    None
+                };
+                let fh = self.open_handle(ino, inode.attr.size, base);
+                reply.opened(fh, 0);
             }
+            None => reply.error(replied(FsError::NotFound.errno())),
         }
     }
 
+    fn flush(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        _lock_owner: u64,
+        reply: fuser::ReplyEmpty,
+    ) {
+        log::debug!("flush(ino={}, fh={})", ino, fh);
+        self.stats.call(Op::Flush);
+        let trace = self.trace(Op::Flush, req, ino, None, || format!("ino={} fh={}", ino, fh));
+
+        self.dispatch(trace, move |fs| {
+            match fs.flush_or_hold(fh, Op::Flush) {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(replied(fs.fail(Op::Flush, &fs.path_of(ino), &e))),
+            }
+        });
+    }
+
+    fn fsync(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        _datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        log::debug!("fsync(ino={}, fh={})", ino, fh);
+        self.stats.call(Op::Fsync);
+        let trace = self.trace(Op::Fsync, req, ino, None, || format!("ino={} fh={}", ino, fh));
+
+        self.dispatch(trace, move |fs| {
+            match fs.flush_or_hold(fh, Op::Fsync) {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(replied(fs.fail(Op::Fsync, &fs.path_of(ino), &e))),
+            }
+        });
+    }
+
+    fn release(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        log::debug!("release(ino={}, fh={})", ino, fh);
+        self.stats.call(Op::Release);
+        let trace = self.trace(Op::Release, req, ino, None, || format!("ino={} fh={}", ino, fh));
+
+        self.dispatch(trace, move |fs| {
+            if ino == STATS_INO {
+                fs.release_stats_file(fh);
+                reply.ok();
+                return;
+            }
+            let result = fs.flush_or_hold(fh, Op::Release);
+            {
+                let mut file_handles = fs.file_handles.lock().unwrap();
+                // A held buffer keeps its handle until it is uploaded
+                if !file_handles.get(&fh).is_some_and(|handle| handle.released) {
+                    if let Some(mut handle) = file_handles.remove(&fh) {
+                        handle.readahead.cancel();
+                    }
+                }
+            }
+            fs.drop_if_deleted(ino);
+
+            match result {
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(replied(fs.fail(Op::Release, &fs.path_of(ino), &e))),
+            }
+        });
+    }
+
     fn mkdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         _mode: u32,
@@ -390,103 +3507,196 @@ impl Filesystem for RemoteFS {
         reply: ReplyEntry,
     ) {
         log::debug!("mkdir(parent={}, name={:?})", parent, name);
+        self.stats.call(Op::Mkdir);
+        let trace = self.trace(Op::Mkdir, req, parent, Some(name), || {
+            format!("parent={} name={:?}", parent, name)
+        });
+
+        if self.refuse_mutation(Op::Mkdir) {
+            reply.error(replied(FsError::ReadOnly.errno()));
+            return;
+        }
+
+        let caller = Caller::of(req);
+        let name = name.to_owned();
+        self.dispatch(trace, move |fs| {
+            let name = name.as_os_str();
 
-        let path = match self.path_from_parent_and_name(parent, name) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
+            let path = match fs.path_from_parent_and_name(parent, name) {
+                Ok(p) => p,
+                Err(e) => {
+                    reply.error(replied(e.errno()));
+                    return;
+                }
+            };
+
+            if let Err(e) = fs.check_creatable(&path) {
+                fs.stats.error(Op::Mkdir);
+                reply.error(replied(e.errno()));
+                return;
+            }
+            if let Err(e) = fs.check_entry_change(caller, parent, &path) {
+                reply.error(replied(e.errno()));
+                return;
+            }
+            // Whatever is there, directory or file, mkdir fails with EEXIST.
+            // The request below is exclusive too, for what the cache missed.
+            if fs.listed_fresh(&path) {
+                fs.stats.error(Op::Mkdir);
+                reply.error(replied(FsError::AlreadyExists.errno()));
                 return;
             }
-        };
-
-        match self.api_client.create_directory(&path) {
-            Ok(_) => {
-                let entry = FileEntry {
-                    name: name.to_string_lossy().to_string(),
-                    is_dir: true,
-                    size: 0,
-                    mtime: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
-                    ctime: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
-                    mode: 0o755,
-                };
 
-                let ino = self.get_or_create_inode(&path, &entry);
-                if let Some(inode) = self.get_inode(ino) {
-                    reply.entry(&TTL, &inode.attr, 0);
-                } else {
-                    reply.error(libc::EIO);
+            match fs.create_directory(&path) {
+                Ok(_) => {
+                    fs.forget_missing(parent, &name.to_string_lossy());
+
+                    let entry = FileEntry {
+                        name: name.to_string_lossy().to_string(),
+                        is_dir: true,
+                        size: 0,
+                        mtime: Timestamp::now(),
+                        ctime: Timestamp::now(),
+                        mode: 0o755,
+                        id: None,
+                        uid: None,
+                        gid: None,
+                        major: None,
+                        minor: None,
+                        nlink: Some(2),
+                    };
+                    fs.changed_in_parent(&path, Some(&entry));
+                    fs.subdirs_changed(split_path(&path).0, true);
+                    fs.entries_changed(split_path(&path).0);
+
+                    let ino = fs.get_or_create_inode(&path, &entry);
+                    fs.created_by(ino, &entry, caller);
+                    if let Some(inode) = fs.looked_up(ino) {
+                        reply.entry(&TTL, &inode.attr, 0);
+                    } else {
+                        fs.stats.error(Op::Mkdir);
+                        reply.error(replied(FsError::Io.errno()));
+                    }
                 }
+                Err(e) => reply.error(replied(fs.fail(Op::Mkdir, &path, &e))),
             }
-            Err(e) => {
-                log::error!("Failed to create directory: {}", e);
-                reply.error(libc::EIO);
-            }
-        }
+        });
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
         log::debug!("unlink(parent={}, name={:?})", parent, name);
+        self.stats.call(Op::Unlink);
+        let trace = self.trace(Op::Unlink, req, parent, Some(name), || {
+            format!("parent={} name={:?}", parent, name)
+        });
+
+        if self.shows_stats_file(parent, &name.to_string_lossy()) {
+            reply.error(replied(FsError::PermissionDenied.errno()));
+            return;
+        }
+        if self.refuse_mutation(Op::Unlink) {
+            reply.error(replied(FsError::ReadOnly.errno()));
+            return;
+        }
 
-        let path = match self.path_from_parent_and_name(parent, name) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
+        let caller = Caller::of(req);
+        let name = name.to_owned();
+        self.dispatch(trace, move |fs| {
+            let name = name.as_os_str();
+
+            let path = match fs.path_from_parent_and_name(parent, name) {
+                Ok(p) => p,
+                Err(e) => {
+                    reply.error(replied(e.errno()));
+                    return;
+                }
+            };
+
+            if let Err(e) = fs.check_entry_change(caller, parent, &path) {
+                reply.error(replied(e.errno()));
                 return;
             }
-        };
 
-        match self.api_client.delete(&path) {
-            Ok(_) => {
-                // Remove from cache
-                let mut path_to_ino = self.path_to_ino.lock().unwrap();
-                let mut inodes = self.inodes.lock().unwrap();
+            if fs.kind_of(&path) == Some(FileType::Directory) {
+                reply.error(replied(FsError::IsADirectory.errno()));
+                return;
+            }
 
-                if let Some(ino) = path_to_ino.remove(&path) {
-                    inodes.remove(&ino);
+            match fs.delete_file(&path) {
+                Ok(_) => {
+                    fs.purge_subtree(&path);
+                    fs.changed_in_parent(&path, None);
+                    fs.entries_changed(split_path(&path).0);
+                    reply.ok();
                 }
-
-                reply.ok();
-            }
-            Err(e) => {
-                log::error!("Failed to delete file: {}", e);
-                reply.error(libc::EIO);
+                Err(e) => reply.error(replied(fs.fail(Op::Unlink, &path, &e))),
             }
-        }
+        });
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
         log::debug!("rmdir(parent={}, name={:?})", parent, name);
+        self.stats.call(Op::Rmdir);
+        let trace = self.trace(Op::Rmdir, req, parent, Some(name), || {
+            format!("parent={} name={:?}", parent, name)
+        });
+
+        if self.refuse_mutation(Op::Rmdir) {
+            reply.error(replied(FsError::ReadOnly.errno()));
+            return;
+        }
+
+        let caller = Caller::of(req);
+        let name = name.to_owned();
+        self.dispatch(trace, move |fs| {
+            let name = name.as_os_str();
+
+            let path = match fs.path_from_parent_and_name(parent, name) {
+                Ok(p) => p,
+                Err(e) => {
+                    reply.error(replied(e.errno()));
+                    return;
+                }
+            };
 
-        let path = match self.path_from_parent_and_name(parent, name) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
+            if let Err(e) = fs.check_entry_change(caller, parent, &path) {
+                reply.error(replied(e.errno()));
                 return;
             }
-        };
-
-        match self.api_client.delete(&path) {
-            Ok(_) => {
-                // Remove from cache
-                let mut path_to_ino = self.path_to_ino.lock().unwrap();
-                let mut inodes = self.inodes.lock().unwrap();
 
-                if let Some(ino) = path_to_ino.remove(&path) {
-                    inodes.remove(&ino);
+            if fs.kind_of(&path).is_some_and(|kind| kind != FileType::Directory) {
+                reply.error(replied(FsError::NotADirectory.errno()));
+                return;
+            }
+            // The server deletes directories recursively, so emptiness is checked here
+            match fs.list_directory(&path) {
+                Ok(entries) if !entries.is_empty() => {
+                    reply.error(replied(FsError::NotEmpty.errno()));
+                    return;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    reply.error(replied(fs.fail(Op::Rmdir, &path, &e)));
+                    return;
                 }
-
-                reply.ok();
             }
-            Err(e) => {
-                log::error!("Failed to delete directory: {}", e);
-                reply.error(libc::EIO);
+
+            match fs.backend.delete(&path) {
+                Ok(_) => {
+                    fs.purge_subtree(&path);
+                    fs.invalidate_parent_listing(&path);
+                    fs.subdirs_changed(split_path(&path).0, false);
+                    fs.entries_changed(split_path(&path).0);
+                    reply.ok();
+                }
+                Err(e) => reply.error(replied(fs.fail(Op::Rmdir, &path, &e))),
             }
-        }
+        });
     }
 
     fn rename(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         newparent: u64,
@@ -498,93 +3708,138 @@ impl Filesystem for RemoteFS {
             "rename(parent={}, name={:?}, newparent={}, newname={:?})",
             parent, name, newparent, newname
         );
+        self.stats.call(Op::Rename);
+        let trace = self.trace(Op::Rename, req, parent, Some(name), || {
+            format!(
+                "parent={} name={:?} newparent={} newname={:?}",
+                parent,
+                name,
+                newparent,
+                newname
+            )
+        });
+
+        if self.shows_stats_file(parent, &name.to_string_lossy())
+            || self.shows_stats_file(newparent, &newname.to_string_lossy())
+        {
+            reply.error(replied(FsError::PermissionDenied.errno()));
+            return;
+        }
+        if self.refuse_mutation(Op::Rename) {
+            reply.error(replied(FsError::ReadOnly.errno()));
+            return;
+        }
+
+        let caller = Caller::of(req);
+        let name = name.to_owned();
+        let newname = newname.to_owned();
+        self.dispatch(trace, move |fs| {
+            let name = name.as_os_str();
+            let newname = newname.as_os_str();
+
+            let from_path = match fs.path_from_parent_and_name(parent, name) {
+                Ok(p) => p,
+                Err(e) => {
+                    reply.error(replied(e.errno()));
+                    return;
+                }
+            };
+
+            let mut to_path = match fs.path_from_parent_and_name(newparent, newname) {
+                Ok(p) => p,
+                Err(e) => {
+                    reply.error(replied(e.errno()));
+                    return;
+                }
+            };
+            // With casefold, a new case for the same name is a rename still
+            let newname_str = newname.to_string_lossy();
+            if to_path == from_path && split_path(&to_path).1 != newname_str {
+                to_path = join_path(split_path(&to_path).0, &newname_str);
+            }
 
-        let from_path = match self.path_from_parent_and_name(parent, name) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
+            if let Err(e) = fs.check_creatable(&to_path) {
+                fs.stats.error(Op::Rename);
+                reply.error(replied(e.errno()));
                 return;
             }
-        };
 
-        let to_path = match self.path_from_parent_and_name(newparent, newname) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
+            // The entry leaves one directory and replaces any in the other
+            let permitted = fs
+                .check_entry_change(caller, parent, &from_path)
+                .and_then(|_| fs.check_entry_change(caller, newparent, &to_path));
+            if let Err(e) = permitted {
+                reply.error(replied(e.errno()));
                 return;
             }
-        };
 
-        match self.api_client.rename(&from_path, &to_path) {
-            Ok(_) => {
-                // Update cache
-                let mut path_to_ino = self.path_to_ino.lock().unwrap();
-                let mut inodes = self.inodes.lock().unwrap();
-
-                if let Some(ino) = path_to_ino.remove(&from_path) {
-                    path_to_ino.insert(to_path.clone(), ino);
-                    if let Some(inode) = inodes.get_mut(&ino) {
-                        inode.path = to_path;
+            if let Err(e) = fs.check_rename(&from_path, &to_path) {
+                fs.stats.error(Op::Rename);
+                reply.error(replied(e.errno()));
+                return;
+            }
+
+            let moves_dir = fs.kind_of(&from_path) == Some(FileType::Directory)
+                && parent != newparent;
+            let replaces_dir = fs.kind_of(&to_path) == Some(FileType::Directory);
+            match fs.backend.rename(&from_path, &to_path) {
+                Ok(_) => {
+                    fs.forget_missing(newparent, &newname.to_string_lossy());
+                    // What the rename replaced goes first, then the moved
+                    // entries take its place
+                    if from_path != to_path {
+                        fs.purge_subtree(&to_path);
+                    }
+                    fs.move_listings(&from_path, &to_path);
+                    fs.invalidate_parent_listing(&from_path);
+                    fs.invalidate_parent_listing(&to_path);
+                    fs.entries_changed(split_path(&from_path).0);
+                    fs.entries_changed(split_path(&to_path).0);
+                    if moves_dir {
+                        fs.subdirs_changed(split_path(&from_path).0, false);
+                        if !replaces_dir {
+                            fs.subdirs_changed(split_path(&to_path).0, true);
+                        }
                     }
-                }
 
-                reply.ok();
-            }
-            Err(e) => {
-                log::error!("Failed to rename: {}", e);
-                reply.error(libc::EIO);
+                    // Update cache, entries below a renamed directory move along
+                    fs.inodes.write().unwrap().rename(&from_path, &to_path);
+
+                    reply.ok();
+                }
+                Err(e) => reply.error(replied(fs.fail(Op::Rename, &from_path, &e))),
             }
-        }
+        });
     }
 
     fn create(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         _mode: u32,
         _umask: u32,
-        _flags: i32,
+        flags: i32,
         reply: fuser::ReplyCreate,
     ) {
         log::debug!("create(parent={}, name={:?})", parent, name);
+        self.stats.call(Op::Create);
+        let trace = self.trace(Op::Create, req, parent, Some(name), || {
+            format!("parent={} name={:?}", parent, name)
+        });
+
+        if self.refuse_mutation(Op::Create) {
+            reply.error(replied(FsError::ReadOnly.errno()));
+            return;
+        }
 
-        let path = match self.path_from_parent_and_name(parent, name) {
-            Some(p) => p,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        // Create empty file on server
-        match self.api_client.write_file(&path, &[]) {
-            Ok(_) => {
-                let entry = FileEntry {
-                    name: name.to_string_lossy().to_string(),
-                    is_dir: false,
-                    size: 0,
-                    mtime: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
-                    ctime: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64(),
-                    mode: 0o644,
-                };
-
-                let ino = self.get_or_create_inode(&path, &entry);
-                if let Some(inode) = self.get_inode(ino) {
-                    let mut next_fh = self.next_fh.lock().unwrap();
-                    let fh = *next_fh;
-                    *next_fh += 1;
-
-                    reply.created(&TTL, &inode.attr, 0, fh, 0);
-                } else {
-                    reply.error(libc::EIO);
-                }
+        let caller = Caller::of(req);
+        let name = name.to_owned();
+        self.dispatch(trace, move |fs| {
+            match fs.create_and_open(caller, parent, &name, flags) {
+                Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, 0),
+                Err(errno) => reply.error(replied(errno)),
             }
-            Err(e) => {
-                log::error!("Failed to create file: {}", e);
-                reply.error(libc::EIO);
-            }
-        }
+        });
     }
 }
-
@@ -0,0 +1,260 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Component, Path};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tar::{Archive, Entry, EntryType};
+
+use super::{join_path, validator, RemoteFS};
+use crate::api_client::{FileEntry, Timestamp};
+
+// What a preload from an archive cached
+#[derive(Default)]
+pub struct Unpacked {
+    pub entries: usize,
+    pub bytes: usize,
+    // Files whose blocks were stored, discarded again if the archive is rejected
+    cached: Vec<(u64, String, u64)>,
+}
+
+impl RemoteFS {
+    // Preloads the subtree at `root` from one tar archive instead of a
+    // request per file: listings and attributes come from the entry headers,
+    // and files up to `data_max` bytes go into the block caches. None when
+    // the server sends no archive, the subtree is walked then. An archive
+    // that is corrupt or has entries outside `root` is rejected, leaving
+    // nothing of it cached.
+    pub(super) fn preload_archive(&self, root: &str, data_max: u64) -> Result<Option<Unpacked>> {
+        let reader = match self.backend.download_archive(root) {
+            Ok(Some(reader)) => reader,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                log::debug!("No archive of {}, walking it instead: {:#}", root, e);
+                return Ok(None);
+            }
+        };
+
+        let mut unpacked = Unpacked::default();
+        match self.unpack_archive(root, reader, data_max, &mut unpacked) {
+            Ok(listings) => {
+                for (dir, entries) in listings {
+                    let mut entries: Vec<FileEntry> = entries.into_values().collect();
+                    entries.sort_by(|a, b| a.name.cmp(&b.name));
+                    self.sort_listing(&mut entries);
+                    if let Some(disk_cache) = &self.disk_cache {
+                        disk_cache.store_listing(&dir, &entries);
+                    }
+                    self.cache_listing(&dir, Arc::new(entries), None);
+                }
+                Ok(Some(unpacked))
+            }
+            Err(e) => {
+                for (ino, path, size) in unpacked.cached {
+                    self.blocks.invalidate(ino);
+                    if let Some(disk_cache) = &self.disk_cache {
+                        disk_cache.discard_file(&path, size);
+                    }
+                }
+                Err(e.context(format!("Rejected the archive of {}", root)))
+            }
+        }
+    }
+
+    // Reads the archive through once, returning the listing of every
+    // directory in it by path
+    fn unpack_archive(
+        &self,
+        root: &str,
+        reader: Box<dyn Read + Send + '_>,
+        data_max: u64,
+        unpacked: &mut Unpacked,
+    ) -> Result<HashMap<String, HashMap<String, FileEntry>>> {
+        let mut listings = HashMap::from([(root.to_string(), HashMap::new())]);
+        let mut archive = Archive::new(reader);
+        let entries = archive.entries().context("Failed to read archive")?;
+
+        for entry in entries {
+            if self.shutdown.load(Ordering::Relaxed) {
+                anyhow::bail!("Aborted at unmount");
+            }
+            let mut entry = entry.context("Corrupt archive")?;
+            let is_dir = match entry.header().entry_type() {
+                EntryType::Directory => true,
+                EntryType::Regular | EntryType::Continuous => false,
+                // Links, devices and the like have nothing to cache
+                _ => continue,
+            };
+            let path = entry.path().context("Corrupt archive entry path")?;
+            let names = relative_names(&path)?;
+            let Some((name, parents)) = names.split_last() else {
+                // The root itself, its attributes are in the parent listing
+                continue;
+            };
+            if self.is_excluded(&join_path(root, &names.join("/"))) {
+                continue;
+            }
+
+            let mut dir = root.to_string();
+            for parent in parents {
+                add_directory(&mut listings, &dir, parent)?;
+                dir = join_path(&dir, parent);
+            }
+            let path = join_path(&dir, name);
+            let file_entry = file_entry(&mut entry, name, is_dir)?;
+            let ino = self.get_or_create_inode(&path, &file_entry);
+            unpacked.entries += 1;
+
+            if is_dir {
+                listings.entry(path).or_default();
+            } else if file_entry.size > 0 && file_entry.size <= data_max {
+                unpacked.cached.push((ino, path.clone(), file_entry.size));
+                unpacked.bytes += self.cache_entry(ino, &path, &mut entry, file_entry.size)?;
+            }
+            listings
+                .get_mut(&dir)
+                .context("Archive entry inside a file")?
+                .insert(name.clone(), file_entry);
+        }
+
+        Ok(listings)
+    }
+
+    // Stores the contents of a file entry block by block, as the caches
+    // would hold it after reading the file
+    fn cache_entry(
+        &self,
+        ino: u64,
+        path: &str,
+        entry: &mut Entry<impl Read>,
+        size: u64,
+    ) -> Result<usize> {
+        // Local changes are newer than the archive
+        let Some(inode) = self.get_inode(ino).filter(|inode| inode.attr.size == size) else {
+            return Ok(0);
+        };
+        let validator = validator(&inode.attr);
+        let block_size = self.blocks.block_size();
+
+        let mut read = 0;
+        for index in 0.. {
+            let mut block = Vec::with_capacity(block_size as usize);
+            entry
+                .by_ref()
+                .take(block_size)
+                .read_to_end(&mut block)
+                .with_context(|| format!("Failed to read {} from the archive", path))?;
+            if block.is_empty() {
+                break;
+            }
+            read += block.len();
+            if let Some(disk_cache) = &self.disk_cache {
+                disk_cache.store_block(path, index, validator, &block);
+            }
+            let full = block.len() as u64 == block_size;
+            self.blocks.insert(ino, index, Arc::new(block));
+            if !full {
+                break;
+            }
+        }
+
+        anyhow::ensure!(
+            read as u64 == size,
+            "Archive ends inside {}, {} of {} bytes",
+            path,
+            read,
+            size
+        );
+        Ok(read)
+    }
+}
+
+// The names leading to an archive entry from the root, rejecting any that
+// would lead out of it
+fn relative_names(path: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(name) => names.push(
+                name.to_str()
+                    .with_context(|| format!("Archive entry {} is not UTF-8", path.display()))?
+                    .to_string(),
+            ),
+            _ => anyhow::bail!("Archive entry {} escapes the subtree", path.display()),
+        }
+    }
+    Ok(names)
+}
+
+// Lists `name` in `dir` as a directory, unless its own entry came first.
+// Archives normally hold an entry per directory before its contents; one
+// without gets the attributes of a directory created now.
+fn add_directory(
+    listings: &mut HashMap<String, HashMap<String, FileEntry>>,
+    dir: &str,
+    name: &str,
+) -> Result<()> {
+    let entries = listings
+        .get_mut(dir)
+        .context("Archive entry inside a file")?;
+    if let Some(entry) = entries.get(name) {
+        anyhow::ensure!(entry.is_dir, "Archive entry inside the file {}", name);
+        return Ok(());
+    }
+
+    let now = Timestamp::now();
+    entries.insert(
+        name.to_string(),
+        FileEntry {
+            name: name.to_string(),
+            is_dir: true,
+            size: 0,
+            mtime: now,
+            ctime: now,
+            mode: 0o755,
+            id: None,
+            uid: None,
+            gid: None,
+            major: None,
+            minor: None,
+            nlink: None,
+        },
+    );
+    listings.entry(join_path(dir, name)).or_default();
+    Ok(())
+}
+
+// The attributes of an archive entry, as a listing would report them
+fn file_entry(entry: &mut Entry<impl Read>, name: &str, is_dir: bool) -> Result<FileEntry> {
+    // PAX headers carry the mtime with its fraction, ustar only whole seconds
+    let pax_mtime = entry
+        .pax_extensions()
+        .context("Corrupt archive PAX header")?
+        .and_then(|extensions| {
+            extensions
+                .filter_map(|extension| extension.ok())
+                .find(|extension| extension.key() == Ok("mtime"))
+                .and_then(|extension| extension.value().ok()?.parse::<Timestamp>().ok())
+        });
+    let header = entry.header();
+    let mtime = match pax_mtime {
+        Some(mtime) => mtime,
+        None => Timestamp::new(header.mtime().context("Corrupt archive mtime")? as i64, 0),
+    };
+
+    Ok(FileEntry {
+        name: name.to_string(),
+        is_dir,
+        size: if is_dir { 0 } else { entry.size() },
+        mtime,
+        ctime: mtime,
+        mode: header.mode().context("Corrupt archive mode")? & 0o7777,
+        id: None,
+        uid: header.uid().ok().and_then(|uid| u32::try_from(uid).ok()),
+        gid: header.gid().ok().and_then(|gid| u32::try_from(gid).ok()),
+        major: None,
+        minor: None,
+        nlink: None,
+    })
+}
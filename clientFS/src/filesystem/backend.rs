@@ -0,0 +1,330 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::api_client::{
+    ApiClient, BreakerState, Conditional, EventStream, Expected, FileData, FileEntry, Listing,
+    RequestStatsSnapshot, ServerCapabilities, Version,
+};
+use crate::filesystem::FsError;
+
+// What speaks to the server, picked with `--backend`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum BackendKind {
+    // The storage API, ApiClient
+    #[default]
+    Rest,
+    // Any WebDAV server, WebDavBackend
+    WebDav,
+    // An S3 bucket, S3Backend
+    S3,
+    // A directory reached over SSH, SftpBackend
+    Sftp,
+    // The storage service over gRPC, GrpcBackend
+    Grpc,
+    // A local directory, LocalBackend, for development and tests
+    Local(PathBuf),
+}
+
+impl BackendKind {
+    // Parses `--backend`
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "rest" | "http" => Ok(Self::Rest),
+            "webdav" | "dav" => Ok(Self::WebDav),
+            "s3" => Ok(Self::S3),
+            "sftp" | "ssh" => Ok(Self::Sftp),
+            "grpc" => Ok(Self::Grpc),
+            _ => match value.trim().split_once(':') {
+                Some(("local", dir)) if !dir.is_empty() => Ok(Self::Local(PathBuf::from(dir))),
+                Some(("local", _)) => anyhow::bail!("Expected local:<dir>"),
+                _ => anyhow::bail!(
+                    "Unknown backend '{}', expected rest, webdav, s3, sftp, grpc or local:<dir>",
+                    value.trim()
+                ),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for BackendKind {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        Self::parse(&value)
+    }
+}
+
+// Everything the filesystem needs from the server. ApiClient is the real
+// implementation; anything else speaking the same operations can be mounted
+// in its place.
+pub trait RemoteBackend: Send + Sync {
+    // Transfer unit, the block cache and uploads are split along it
+    fn chunk_size(&self) -> u64;
+
+    fn is_healthy(&self) -> bool {
+        true
+    }
+
+    // The server URL in use, for messages
+    fn endpoint(&self) -> &str {
+        "custom backend"
+    }
+
+    // Open while requests fail fast because the server is known to be down
+    fn breaker_state(&self) -> BreakerState {
+        BreakerState::Closed
+    }
+
+    fn stats(&self) -> RequestStatsSnapshot {
+        RequestStatsSnapshot::default()
+    }
+
+    // Asks the server which optional features it has, at mount and after
+    // reconnecting. None from backends that know without asking.
+    fn negotiate_capabilities(&self) -> Result<Option<ServerCapabilities>> {
+        Ok(None)
+    }
+
+    // Seconds the server's clock runs ahead of ours, None when unknown
+    fn clock_skew(&self) -> Option<i64> {
+        None
+    }
+
+    // Whether the server answers at all, within `timeout`
+    fn check_reachable(&self, _timeout: Duration) -> Result<()> {
+        self.list_directory("/").map(|_| ())
+    }
+
+    // Whether the server takes writes again after announcing maintenance
+    fn check_writable(&self, timeout: Duration) -> Result<()> {
+        self.check_reachable(timeout)
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Listing>;
+
+    // The same, passing each entry on as it arrives. Returns the version of
+    // the listing.
+    fn list_directory_streamed(
+        &self,
+        path: &str,
+        entry: &mut dyn FnMut(FileEntry),
+    ) -> Result<Option<Version>> {
+        let listing = self.list_directory(path)?;
+        listing.entries.into_iter().for_each(entry);
+        Ok(listing.version)
+    }
+
+    fn revalidate_listing(&self, path: &str, version: &Version) -> Result<Conditional<Listing>>;
+
+    // Stat by version: Modified(None) means the file is gone
+    fn revalidate_file(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Option<Version>>>;
+
+    // The version writes to `path` are based on, None when the backend has none
+    fn file_version(&self, _path: &str) -> Result<Option<Version>> {
+        Ok(None)
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData>;
+
+    // The subtree at `path` as one tar stream, None when the backend cannot
+    // send one and files are read one by one
+    fn download_archive(&self, _path: &str) -> Result<Option<Box<dyn Read + Send + '_>>> {
+        Ok(None)
+    }
+
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<()>;
+
+    // Writes guarded by what the server is expected to have, for replaying
+    // changes made offline and conflict copies. Backends without
+    // preconditions ignore it. Returns the version of the new contents, if known.
+    fn write_file_if(
+        &self,
+        path: &str,
+        data: &[u8],
+        _expected: &Expected,
+    ) -> Result<Option<Version>> {
+        self.write_file(path, data).map(|_| None)
+    }
+
+    // `open` is called once per attempt and returns a reader at the start
+    fn write_file_streamed(
+        &self,
+        path: &str,
+        len: u64,
+        open: &dyn Fn() -> Box<dyn Read + Send>,
+    ) -> Result<()>;
+
+    // Returns false when partial writes are unsupported, nothing is written then
+    fn write_range(&self, path: &str, offset: u64, data: &[u8]) -> Result<bool>;
+
+    // Sets the modification time of a file with no writes to carry it
+    fn set_mtime(&self, path: &str, _mtime: SystemTime) -> Result<()> {
+        Err(anyhow::Error::new(FsError::Unsupported)
+            .context(format!("Cannot set the modification time of {}", path)))
+    }
+
+    fn create_directory(&self, path: &str) -> Result<()>;
+
+    fn delete(&self, path: &str) -> Result<()>;
+
+    fn delete_if(&self, path: &str, _expected: &Expected) -> Result<()> {
+        self.delete(path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+
+    // Changes made on the server as they happen, resuming after
+    // `last_event_id`. None when the server sends no notifications.
+    fn events(&self, _last_event_id: Option<&str>) -> Result<Option<EventStream>> {
+        Ok(None)
+    }
+
+    // The same over a WebSocket, limited to paths under `prefixes`
+    fn ws_events(
+        &self,
+        _prefixes: &[String],
+        _last_event_id: Option<&str>,
+    ) -> Result<Option<EventStream>> {
+        Ok(None)
+    }
+}
+
+impl RemoteBackend for ApiClient {
+    fn chunk_size(&self) -> u64 {
+        ApiClient::chunk_size(self)
+    }
+
+    fn is_healthy(&self) -> bool {
+        ApiClient::is_healthy(self)
+    }
+
+    fn endpoint(&self) -> &str {
+        ApiClient::active_endpoint(self)
+    }
+
+    fn breaker_state(&self) -> BreakerState {
+        ApiClient::breaker_state(self)
+    }
+
+    fn stats(&self) -> RequestStatsSnapshot {
+        ApiClient::stats(self)
+    }
+
+    fn negotiate_capabilities(&self) -> Result<Option<ServerCapabilities>> {
+        ApiClient::negotiate_capabilities(self).map(Some)
+    }
+
+    fn clock_skew(&self) -> Option<i64> {
+        ApiClient::clock_skew(self)
+    }
+
+    fn check_reachable(&self, timeout: Duration) -> Result<()> {
+        ApiClient::check_reachable(self, timeout)
+    }
+
+    fn check_writable(&self, timeout: Duration) -> Result<()> {
+        ApiClient::check_writable(self, timeout)
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Listing> {
+        ApiClient::list_directory(self, path)
+    }
+
+    fn list_directory_streamed(
+        &self,
+        path: &str,
+        entry: &mut dyn FnMut(FileEntry),
+    ) -> Result<Option<Version>> {
+        ApiClient::list_directory_streamed(self, path, entry)
+    }
+
+    fn revalidate_listing(&self, path: &str, version: &Version) -> Result<Conditional<Listing>> {
+        ApiClient::revalidate_listing(self, path, version)
+    }
+
+    fn revalidate_file(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Option<Version>>> {
+        ApiClient::revalidate_file(self, path, version)
+    }
+
+    fn file_version(&self, path: &str) -> Result<Option<Version>> {
+        ApiClient::file_version(self, path)
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        ApiClient::read_range(self, path, offset, len)
+    }
+
+    fn download_archive(&self, path: &str) -> Result<Option<Box<dyn Read + Send + '_>>> {
+        Ok(Some(Box::new(ApiClient::download_archive(self, path)?)))
+    }
+
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<()> {
+        ApiClient::write_file(self, path, data)
+    }
+
+    fn write_file_if(
+        &self,
+        path: &str,
+        data: &[u8],
+        expected: &Expected,
+    ) -> Result<Option<Version>> {
+        ApiClient::write_file_if(self, path, data, expected)
+    }
+
+    fn write_file_streamed(
+        &self,
+        path: &str,
+        len: u64,
+        open: &dyn Fn() -> Box<dyn Read + Send>,
+    ) -> Result<()> {
+        ApiClient::write_file_streamed(self, path, len, open)
+    }
+
+    fn write_range(&self, path: &str, offset: u64, data: &[u8]) -> Result<bool> {
+        ApiClient::write_range(self, path, offset, data)
+    }
+
+    fn set_mtime(&self, path: &str, mtime: SystemTime) -> Result<()> {
+        ApiClient::set_mtime(self, path, mtime)
+    }
+
+    fn create_directory(&self, path: &str) -> Result<()> {
+        ApiClient::create_directory(self, path)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        ApiClient::delete(self, path)
+    }
+
+    fn delete_if(&self, path: &str, expected: &Expected) -> Result<()> {
+        ApiClient::delete_if(self, path, expected)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        ApiClient::rename(self, from, to)
+    }
+
+    fn events(&self, last_event_id: Option<&str>) -> Result<Option<EventStream>> {
+        ApiClient::events(self, last_event_id)
+    }
+
+    fn ws_events(
+        &self,
+        prefixes: &[String],
+        last_event_id: Option<&str>,
+    ) -> Result<Option<EventStream>> {
+        ApiClient::ws_events(self, prefixes, last_event_id)
+    }
+}
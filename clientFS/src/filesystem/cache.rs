@@ -0,0 +1,299 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Least-recently-used map bounded by the total weight of its entries
+pub struct LruCache<K, V> {
+    entries: HashMap<K, (V, u64, usize)>,
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    weight: usize,
+    max_weight: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub fn new(max_weight: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            weight: 0,
+            max_weight,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.tick += 1;
+        let entry = self.entries.get_mut(key)?;
+        if let Some(key) = self.order.remove(&entry.1) {
+            self.order.insert(self.tick, key);
+        }
+        entry.1 = self.tick;
+        Some(&entry.0)
+    }
+
+    // Like get, without counting as a use
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries.get(key).map(|entry| &entry.0)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.tick += 1;
+        let entry = self.entries.get_mut(key)?;
+        if let Some(key) = self.order.remove(&entry.1) {
+            self.order.insert(self.tick, key);
+        }
+        entry.1 = self.tick;
+        Some(&mut entry.0)
+    }
+
+    // Visits every entry without affecting recency
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.entries
+            .iter_mut()
+            .map(|(key, (value, _, _))| (key, value))
+    }
+
+    // Inserts an entry, evicting and returning the least recently used ones
+    // needed to stay within budget
+    pub fn insert(&mut self, key: K, value: V, weight: usize) -> Vec<(K, V)> {
+        self.remove(&key);
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick, weight));
+        self.weight += weight;
+
+        let mut evicted = Vec::new();
+        while self.weight > self.max_weight && self.entries.len() > 1 {
+            let oldest = match self.order.keys().next() {
+                Some(&tick) => tick,
+                None => break,
+            };
+            if let Some(key) = self.order.remove(&oldest) {
+                if let Some((value, _, weight)) = self.entries.remove(&key) {
+                    self.weight -= weight;
+                    evicted.push((key, value));
+                }
+            }
+        }
+        evicted
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (value, tick, weight) = self.entries.remove(key)?;
+        self.order.remove(&tick);
+        self.weight -= weight;
+        Some(value)
+    }
+
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let removed: Vec<K> = self
+            .entries
+            .iter()
+            .filter(|(key, (value, _, _))| !keep(key, value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in removed {
+            self.remove(&key);
+        }
+    }
+}
+
+pub type Block = Arc<Vec<u8>>;
+
+// File contents cached in fixed-size blocks keyed by (ino, block index)
+pub struct BlockCache {
+    block_size: u64,
+    blocks: Mutex<LruCache<(u64, u64), Block>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    pub fn new(block_size: u64, max_bytes: usize) -> Self {
+        Self {
+            block_size: block_size.max(1),
+            blocks: Mutex::new(LruCache::new(max_bytes)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn get(&self, ino: u64, index: u64) -> Option<Block> {
+        let block = self.blocks.lock().unwrap().get(&(ino, index)).cloned();
+        let counter = if block.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        block
+    }
+
+    // Like get, but without touching recency or the hit counters
+    pub fn contains(&self, ino: u64, index: u64) -> bool {
+        self.blocks
+            .lock()
+            .unwrap()
+            .entries
+            .contains_key(&(ino, index))
+    }
+
+    pub fn insert(&self, ino: u64, index: u64, data: Block) {
+        let weight = data.len();
+        self.blocks
+            .lock()
+            .unwrap()
+            .insert((ino, index), data, weight);
+    }
+
+    // Drops every block overlapping the byte range [start, end)
+    pub fn invalidate_range(&self, ino: u64, start: u64, end: u64) {
+        if end <= start {
+            return;
+        }
+
+        let first = start / self.block_size;
+        let last = (end - 1) / self.block_size;
+        let mut blocks = self.blocks.lock().unwrap();
+        for index in first..=last {
+            blocks.remove(&(ino, index));
+        }
+    }
+
+    // Drops every block of an inode, returning how many bytes were freed
+    pub fn invalidate(&self, ino: u64) -> usize {
+        let mut blocks = self.blocks.lock().unwrap();
+        let before = blocks.weight();
+        blocks.retain(|&(block_ino, _), _| block_ino != ino);
+        before - blocks.weight()
+    }
+
+    // Bytes and number of blocks currently held
+    pub fn usage(&self) -> (usize, usize) {
+        let blocks = self.blocks.lock().unwrap();
+        (blocks.weight(), blocks.len())
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_entries_go_first() {
+        let mut cache = LruCache::new(3);
+        cache.insert("a", 1, 1);
+        cache.insert("b", 2, 1);
+        cache.insert("c", 3, 1);
+        // Used, so b is now the oldest
+        assert_eq!(cache.get("a"), Some(&1));
+
+        let evicted = cache.insert("d", 4, 1);
+        assert_eq!(evicted, [("b", 2)]);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.weight(), 3);
+    }
+
+    #[test]
+    fn peeking_is_not_a_use() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a", 1, 1);
+        cache.insert("b", 2, 1);
+        assert_eq!(cache.peek("a"), Some(&1));
+        assert_eq!(cache.insert("c", 3, 1), [("a", 1)]);
+    }
+
+    #[test]
+    fn changing_in_place_is_a_use_only_by_key() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a", 1, 1);
+        cache.insert("b", 2, 1);
+        for (_, value) in cache.iter_mut() {
+            *value *= 10;
+        }
+        *cache.get_mut("a").unwrap() += 1;
+        assert_eq!(cache.insert("c", 3, 1), [("b", 20)]);
+        assert_eq!(cache.peek("a"), Some(&11));
+    }
+
+    #[test]
+    fn blocks_are_counted_and_invalidated_by_range() {
+        let cache = BlockCache::new(4, 1024);
+        assert!(cache.get(7, 0).is_none());
+        for index in 0..3 {
+            cache.insert(7, index, Arc::new(vec![index as u8; 4]));
+        }
+        cache.insert(8, 0, Arc::new(vec![9; 4]));
+        assert_eq!(cache.get(7, 1).unwrap()[0], 1);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+        // Bytes 5..9 lie in blocks 1 and 2
+        cache.invalidate_range(7, 5, 9);
+        assert!(cache.contains(7, 0));
+        assert!(!cache.contains(7, 1) && !cache.contains(7, 2));
+        assert_eq!(cache.invalidate(7), 4);
+        assert_eq!(cache.usage(), (4, 1));
+    }
+
+    #[test]
+    fn weight_is_the_budget() {
+        let mut cache = LruCache::new(10);
+        cache.insert("small", 1, 2);
+        let evicted = cache.insert("large", 2, 9);
+        assert_eq!(evicted, [("small", 1)]);
+        // The only entry stays even over budget
+        assert!(cache.insert("huge", 3, 20).len() == 1);
+        assert_eq!(cache.len(), 1);
+
+        // Replacing an entry does not count it twice
+        let mut cache = LruCache::new(4);
+        cache.insert("a", 1, 2);
+        cache.insert("a", 2, 2);
+        assert_eq!(cache.weight(), 2);
+        assert_eq!(cache.remove("a"), Some(2));
+        assert_eq!(cache.weight(), 0);
+    }
+}
@@ -0,0 +1,750 @@
+use anyhow::{Context, Result};
+use fuser::FileType;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use super::offline::is_down;
+use super::{entry_kind, split_path, validator, FsError, INode, Op, RemoteFS, WriteBuffer};
+use crate::api_client::{Expected, FileEntry, Timestamp};
+
+// Bumped whenever the layout of queued entries changes
+const FORMAT_VERSION: u32 = 1;
+
+// A mutation accepted while offline, replayed on the server once it answers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "kebab-case")]
+pub enum Change {
+    // New contents of the whole file, kept in the data file of the entry
+    Write {
+        path: String,
+        size: u64,
+        expected: Expected,
+    },
+    Mkdir {
+        path: String,
+    },
+    Unlink {
+        path: String,
+        expected: Expected,
+    },
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Write { path, .. } | Self::Mkdir { path } | Self::Unlink { path, .. } => path,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredChange {
+    version: u32,
+    seq: u64,
+    change: Change,
+}
+
+// Why a change was set aside instead of replayed, next to its data
+#[derive(Serialize)]
+struct Conflict<'a> {
+    change: &'a Change,
+    error: String,
+}
+
+// Changes made offline, in the order they were made, kept under
+// <cache_dir>/queue as one JSON file per change plus a data file for writes.
+// A change is on disk and synced before it is acknowledged, so the queue
+// survives the process. Changes the server turns down are moved to
+// <cache_dir>/conflicts for the user to sort out.
+pub struct ChangeQueue {
+    dir: PathBuf,
+    conflicts: PathBuf,
+    pending: Mutex<VecDeque<(u64, Change)>>,
+}
+
+impl ChangeQueue {
+    pub fn open(cache_dir: &Path) -> io::Result<Self> {
+        let dir = cache_dir.join("queue");
+        let conflicts = cache_dir.join("conflicts");
+        for dir in [&dir, &conflicts] {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)?;
+        }
+
+        let mut pending = Vec::new();
+        let mut data_files = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let file = entry?.path();
+            match file.extension().and_then(|ext| ext.to_str()) {
+                Some("json") => match Self::load(&file) {
+                    Some(stored) => pending.push((stored.seq, stored.change)),
+                    None => {
+                        log::warn!("Discarding unreadable queued change {}", file.display());
+                        let _ = fs::remove_file(&file);
+                    }
+                },
+                Some("data") => data_files.push(file),
+                // Left behind by a change that was never acknowledged
+                _ => {
+                    let _ = fs::remove_file(&file);
+                }
+            }
+        }
+        pending.sort_by_key(|&(seq, _)| seq);
+
+        let queue = Self {
+            dir,
+            conflicts,
+            pending: Mutex::new(pending.into_iter().collect()),
+        };
+        // Data of changes replayed just before the process stopped
+        for file in data_files {
+            if !queue.change_file_for(&file).exists() {
+                let _ = fs::remove_file(&file);
+            }
+        }
+        Ok(queue)
+    }
+
+    fn load(file: &Path) -> Option<StoredChange> {
+        let contents = fs::read(file).ok()?;
+        serde_json::from_slice::<StoredChange>(&contents)
+            .ok()
+            .filter(|stored| stored.version == FORMAT_VERSION)
+    }
+
+    fn change_file(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:016}.json", seq))
+    }
+
+    fn data_file(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:016}.data", seq))
+    }
+
+    fn change_file_for(&self, data_file: &Path) -> PathBuf {
+        data_file.with_extension("json")
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.lock().unwrap().is_empty()
+    }
+
+    // Whether a change to `path` is waiting, which then guards later ones
+    pub fn has_path(&self, path: &str) -> bool {
+        let pending = self.pending.lock().unwrap();
+        pending.iter().any(|(_, change)| change.path() == path)
+    }
+
+    // Appends a change once it and its data are synced to disk
+    pub fn push(&self, change: Change, data: Option<&[u8]>) -> io::Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        let seq = pending.back().map_or(0, |&(seq, _)| seq + 1);
+
+        if let Some(data) = data {
+            write_synced(&self.data_file(seq), data)?;
+        }
+        let stored = StoredChange {
+            version: FORMAT_VERSION,
+            seq,
+            change,
+        };
+        let contents = serde_json::to_vec(&stored).map_err(io::Error::other)?;
+        if let Err(e) = write_synced(&self.change_file(seq), &contents) {
+            let _ = fs::remove_file(self.data_file(seq));
+            return Err(e);
+        }
+        File::open(&self.dir)?.sync_all()?;
+
+        pending.push_back((seq, stored.change));
+        Ok(())
+    }
+
+    pub fn front(&self) -> Option<(u64, Change)> {
+        self.pending.lock().unwrap().front().cloned()
+    }
+
+    pub fn data(&self, seq: u64) -> io::Result<Vec<u8>> {
+        fs::read(self.data_file(seq))
+    }
+
+    // Forgets the first change once the server has it
+    pub fn pop(&self, seq: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.front().is_some_and(|&(front, _)| front == seq) {
+            pending.pop_front();
+        }
+        let _ = fs::remove_file(self.change_file(seq));
+        let _ = fs::remove_file(self.data_file(seq));
+    }
+
+    // Moves the first change to the conflicts directory, returning where.
+    // Sequence numbers start over with an empty queue, the time tells
+    // conflicts of different runs apart.
+    pub fn set_aside(&self, seq: u64, change: &Change, error: &str) -> io::Result<PathBuf> {
+        let name = format!(
+            "{}-{}-{}",
+            Timestamp::now().secs,
+            seq,
+            change.path().trim_start_matches('/').replace('/', "%2F")
+        );
+        let conflict = Conflict {
+            change,
+            error: error.to_string(),
+        };
+        let contents = serde_json::to_vec_pretty(&conflict).map_err(io::Error::other)?;
+        write_synced(&self.conflicts.join(format!("{}.json", name)), &contents)?;
+        let data = self.conflicts.join(name);
+        if self.data_file(seq).exists() {
+            fs::rename(self.data_file(seq), &data)?;
+        }
+        self.pop(seq);
+        Ok(data)
+    }
+
+    // Runs `then` if nothing is queued, with pushes held off meanwhile
+    pub fn if_empty(&self, then: impl FnOnce()) -> bool {
+        let pending = self.pending.lock().unwrap();
+        if pending.is_empty() {
+            then();
+        }
+        pending.is_empty()
+    }
+}
+
+// Writes through a temporary file synced before it takes the final name
+fn write_synced(file: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = file.with_extension("tmp");
+    let result = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut f| {
+            f.write_all(contents)?;
+            f.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, file));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+impl RemoteFS {
+    // The queue changes go to instead of the server, while offline
+    pub(super) fn offline_queue(&self) -> Option<&Arc<ChangeQueue>> {
+        self.change_queue.as_ref().filter(|_| self.is_offline())
+    }
+
+    // Whether `op` is taken into the queue while offline
+    pub(super) fn queues_op(&self, op: Op) -> bool {
+        self.change_queue.is_some()
+            && matches!(
+                op,
+                Op::Write | Op::Open | Op::Setattr | Op::Create | Op::Mkdir | Op::Unlink
+            )
+    }
+
+    // What a change to `path` may overwrite: whatever an earlier queued change
+    // left there, or else the version last read from the server
+    fn expected(&self, queue: &ChangeQueue, path: &str, fallback: Expected) -> Expected {
+        if queue.has_path(path) {
+            return Expected::Any;
+        }
+        let version = self
+            .inodes
+            .read()
+            .unwrap()
+            .get_path(path)
+            .and_then(|inode| inode.version.clone());
+        version.map_or(fallback, Expected::Version)
+    }
+
+    fn queue_change(&self, queue: &ChangeQueue, change: Change, data: Option<&[u8]>) -> Result<()> {
+        log::debug!("Queued {:?}", change);
+        queue
+            .push(change, data)
+            .context("Failed to queue the change for when the server is back")
+    }
+
+    // Creates a directory on the server, or queues it while offline
+    pub(super) fn create_directory(&self, path: &str) -> Result<()> {
+        match self.offline_queue() {
+            Some(queue) => {
+                let change = Change::Mkdir {
+                    path: path.to_string(),
+                };
+                self.queue_change(queue, change, None)
+            }
+            None => self.backend.create_directory(path),
+        }
+    }
+
+    // Creates an empty file on the server, or queues it while offline
+    pub(super) fn create_file(&self, path: &str) -> Result<()> {
+        match self.offline_queue() {
+            Some(queue) => {
+                let change = Change::Write {
+                    path: path.to_string(),
+                    size: 0,
+                    expected: self.expected(queue, path, Expected::Absent),
+                };
+                self.queue_change(queue, change, Some(&[]))
+            }
+            // Only if absent, a file made by someone else since the lookup keeps
+            // its contents. The failed precondition means just that.
+            None => match self.backend.write_file_if(path, &[], &Expected::Absent) {
+                Err(e) if FsError::from_backend(&e) == FsError::Stale => {
+                    Err(anyhow::Error::new(FsError::AlreadyExists)
+                        .context(format!("{} already exists", path)))
+                }
+                result => result.map(|_| ()),
+            },
+        }
+    }
+
+    // Deletes a file on the server, or queues it while offline
+    pub(super) fn delete_file(&self, path: &str) -> Result<()> {
+        match self.offline_queue() {
+            Some(queue) => {
+                let change = Change::Unlink {
+                    path: path.to_string(),
+                    expected: self.expected(queue, path, Expected::Any),
+                };
+                self.queue_change(queue, change, None)
+            }
+            None => self.backend.delete(path),
+        }
+    }
+
+    // Records that `path` was created, replaced (with an entry) or removed
+    // (without). Online the parent listing is fetched again; offline there is
+    // nothing to fetch, so the cached listings are edited to show the change.
+    pub(super) fn changed_in_parent(&self, path: &str, entry: Option<&FileEntry>) {
+        if !self.is_offline() {
+            self.invalidate_parent_listing(path);
+            return;
+        }
+
+        let (parent, name) = split_path(path);
+        self.edit_listing(parent, |entries| {
+            entries.retain(|existing| existing.name != name);
+            entries.extend(entry.cloned());
+        });
+        // A directory made offline starts out empty
+        if entry.is_some_and(|entry| entry.is_dir) {
+            if let Some(disk_cache) = &self.disk_cache {
+                disk_cache.store_listing(path, &[]);
+            }
+            self.cache_listing(path, Arc::new(Vec::new()), None);
+        }
+    }
+
+    // Applies `edit` to the cached listing of `path`, in memory and on disk.
+    // Without one the change only shows in the inode table.
+    fn edit_listing(&self, path: &str, edit: impl FnOnce(&mut Vec<FileEntry>)) {
+        let cached = self
+            .listings
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(|listing| listing.entries.clone());
+        let mut entries = match cached {
+            Some(entries) => Vec::clone(&entries),
+            None => match self
+                .disk_cache
+                .as_ref()
+                .and_then(|cache| cache.load_listing(path))
+            {
+                Some(entries) => entries,
+                None => return,
+            },
+        };
+        edit(&mut entries);
+        self.sort_listing(&mut entries);
+
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.store_listing(path, &entries);
+        }
+        // Without a version the listing is fetched whole once online
+        self.cache_listing(path, Arc::new(entries), None);
+        self.served_offline(path);
+    }
+
+    // Queues the buffered writes of a handle as the new contents of the whole
+    // file, and shows them in the caches. Parts of the file not rewritten must
+    // be cached. Returns the new size like upload_buffer.
+    pub(super) fn queue_buffer(
+        &self,
+        queue: &ChangeQueue,
+        inode: &INode,
+        remote_size: u64,
+        buffer: &WriteBuffer,
+    ) -> Result<u64> {
+        let size = buffer.file_size(remote_size);
+        let kept = buffer.kept_remote(remote_size);
+        let (parent, name) = split_path(&inode.path);
+        let cached_entry = self
+            .list_directory(parent)
+            .ok()
+            .and_then(|entries| entries.iter().find(|entry| entry.name == name).cloned());
+
+        // Cached blocks were stored for the attributes in the listing, the
+        // inode's already show the buffered writes
+        let mut content = if buffer.covers_prefix(kept) {
+            Vec::new()
+        } else {
+            let mut cached = inode.clone();
+            if let Some(entry) = &cached_entry {
+                cached.attr = self.attr_from_entry(inode.ino, entry, inode.creator);
+            }
+            self.read_blocks(&cached, 0, kept)?
+        };
+        content.truncate(kept as usize);
+        buffer.overlay(0, &mut content, size);
+        content.resize(size as usize, 0);
+
+        let change = Change::Write {
+            path: inode.path.clone(),
+            size,
+            expected: self.expected(queue, &inode.path, Expected::Any),
+        };
+        self.queue_change(queue, change, Some(&content))?;
+
+        let entry = match cached_entry {
+            Some(entry) => FileEntry {
+                size,
+                mtime: Timestamp::now(),
+                ..entry
+            },
+            None => FileEntry {
+                name: name.to_string(),
+                is_dir: false,
+                size,
+                mtime: Timestamp::now(),
+                ctime: Timestamp::now(),
+                mode: 0o644,
+                id: None,
+                uid: None,
+                gid: None,
+                major: None,
+                minor: None,
+                nlink: None,
+            },
+        };
+        self.show_content(inode, &entry, &content);
+        Ok(size)
+    }
+
+    // Puts queued contents in the block caches, stored for the attributes of
+    // `entry` so a remount finds them through the edited listing
+    fn show_content(&self, inode: &INode, entry: &FileEntry, content: &[u8]) {
+        let attr = self.attr_from_entry(inode.ino, entry, inode.creator);
+        let validator = validator(&attr);
+        self.blocks.invalidate(inode.ino);
+        for (index, chunk) in content
+            .chunks(self.blocks.block_size() as usize)
+            .enumerate()
+        {
+            if let Some(disk_cache) = &self.disk_cache {
+                disk_cache.store_block(&inode.path, index as u64, validator, chunk);
+            }
+            self.blocks
+                .insert(inode.ino, index as u64, Arc::new(chunk.to_vec()));
+        }
+
+        if let Some(cached) = self.inodes.write().unwrap().get_mut(inode.ino) {
+            cached.attr = attr;
+            cached.fetched_at = Instant::now();
+        }
+        self.changed_in_parent(&inode.path, Some(entry));
+        self.invalidate_kernel_cache(inode.ino);
+    }
+
+    // Sends the queued changes to the server in order. Changes it turns down
+    // are set aside as conflicts and the rest go on; the replay stops when
+    // the server is unreachable again. Once the queue is empty the mount is
+    // online, before anything else can be queued.
+    pub(super) fn replay_changes(&self) -> Result<()> {
+        let Some(queue) = &self.change_queue else {
+            self.offline.store(false, Ordering::Relaxed);
+            return Ok(());
+        };
+        let pending = queue.len();
+        if pending > 0 {
+            log::info!("Replaying {} changes made offline", pending);
+        }
+
+        // Later changes to a path build on the earlier ones, once one is
+        // turned down the rest go with it
+        let mut conflicted = HashSet::new();
+        loop {
+            let Some((seq, change)) = queue.front() else {
+                if queue.if_empty(|| self.offline.store(false, Ordering::Relaxed)) {
+                    return Ok(());
+                }
+                continue;
+            };
+
+            let result = if conflicted.contains(change.path()) {
+                Err(anyhow::anyhow!(
+                    "An earlier change to {} was turned down",
+                    change.path()
+                ))
+            } else {
+                self.replay(queue, seq, &change)
+            };
+            match result {
+                Ok(()) => {
+                    queue.pop(seq);
+                    self.stats.replayed_changes.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) if is_down(&e) => return Err(e),
+                Err(e) => {
+                    conflicted.insert(change.path().to_string());
+                    self.stats.replay_conflicts.fetch_add(1, Ordering::Relaxed);
+                    match queue.set_aside(seq, &change, &format!("{:#}", e)) {
+                        Ok(kept) => log::error!(
+                            "Failed to replay {:?}, kept in {}: {:#}",
+                            change,
+                            kept.display(),
+                            e
+                        ),
+                        Err(io) => {
+                            log::error!(
+                                "Failed to replay {:?}: {:#}, and to keep it as a conflict: {}",
+                                change,
+                                e,
+                                io
+                            );
+                            queue.pop(seq);
+                        }
+                    }
+                    // Show the server's copy from now on
+                    self.invalidate_parent_listing(change.path());
+                    let ino = self.inodes.read().unwrap().resolve_path(change.path());
+                    if let Some(ino) = ino {
+                        self.blocks.invalidate(ino);
+                    }
+                }
+            }
+        }
+    }
+
+    fn replay(&self, queue: &ChangeQueue, seq: u64, change: &Change) -> Result<()> {
+        log::debug!("Replaying {:?}", change);
+        match change {
+            Change::Write { path, expected, .. } => {
+                let data = queue
+                    .data(seq)
+                    .with_context(|| format!("Queued contents of {} are missing", path))?;
+                self.backend.write_file_if(path, &data, expected)?;
+                self.stats.uploads_issued.fetch_add(1, Ordering::Relaxed);
+                // The server's copy has a new version, the cached blocks hold
+                // the same contents
+                let mut inodes = self.inodes.write().unwrap();
+                if let Some(ino) = inodes.resolve_path(path) {
+                    if let Some(inode) = inodes.get_mut(ino) {
+                        inode.version = None;
+                    }
+                }
+                Ok(())
+            }
+            Change::Mkdir { path } => match self.backend.create_directory(path) {
+                // Made by someone else meanwhile, which is what was wanted
+                Err(e) if self.kind_on_server(path) == Some(FileType::Directory) => {
+                    log::debug!("{} already exists: {:#}", path, e);
+                    Ok(())
+                }
+                result => result,
+            },
+            Change::Unlink { path, expected } => match self.backend.delete_if(path, expected) {
+                Err(e) if self.kind_on_server(path).is_none() && !is_down(&e) => {
+                    log::debug!("{} was already deleted: {:#}", path, e);
+                    Ok(())
+                }
+                result => result,
+            },
+        }
+    }
+
+    fn kind_on_server(&self, path: &str) -> Option<FileType> {
+        let (parent, name) = split_path(path);
+        let listing = self.backend.list_directory(parent).ok()?;
+        let entry = listing
+            .entries
+            .into_iter()
+            .find(|entry| entry.name == name)?;
+        Some(entry_kind(&entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{FsConfig, OfflineMode};
+    use crate::testing::MockBackend;
+
+    fn write(path: &str, expected: Expected) -> Change {
+        Change::Write {
+            path: path.to_string(),
+            size: 0,
+            expected,
+        }
+    }
+
+    fn mkdir(path: &str) -> Change {
+        Change::Mkdir {
+            path: path.to_string(),
+        }
+    }
+
+    fn paths(queue: &ChangeQueue) -> Vec<String> {
+        let pending = queue.pending.lock().unwrap();
+        pending.iter().map(|(_, change)| change.path().to_string()).collect()
+    }
+
+    // An offline mount queueing its changes in `cache`
+    fn offline(cache: &Path) -> (Arc<MockBackend>, RemoteFS) {
+        let mock = Arc::new(MockBackend::new());
+        let mut config = FsConfig {
+            offline_mode: OfflineMode::Auto,
+            offline_writes: true,
+            ..FsConfig::default()
+        };
+        config.cache.cache_dir = Some(cache.to_path_buf());
+        let fs = RemoteFS::with_backend(mock.clone(), config);
+        fs.offline.store(true, Ordering::Relaxed);
+        (mock, fs)
+    }
+
+    #[test]
+    fn changes_survive_reopening_in_order() {
+        let cache = tempfile::tempdir().unwrap();
+        let queue = ChangeQueue::open(cache.path()).unwrap();
+        queue.push(mkdir("/d"), None).unwrap();
+        queue.push(write("/d/a", Expected::Absent), Some(b"one")).unwrap();
+        queue.push(write("/b", Expected::Any), Some(b"two")).unwrap();
+        assert!(queue.has_path("/d/a"));
+        assert!(!queue.has_path("/d/c"));
+        drop(queue);
+
+        let dir = cache.path().join("queue");
+        fs::write(dir.join("0000000000000009.tmp"), b"half").unwrap();
+        fs::write(dir.join("0000000000000008.data"), b"orphan").unwrap();
+        fs::write(dir.join("0000000000000007.json"), b"{not json").unwrap();
+
+        let queue = ChangeQueue::open(cache.path()).unwrap();
+        assert_eq!(paths(&queue), ["/d", "/d/a", "/b"]);
+        assert_eq!(queue.data(1).unwrap(), b"one");
+        let mut left: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        let expected = [
+            "0000000000000000.json",
+            "0000000000000001.data",
+            "0000000000000001.json",
+            "0000000000000002.data",
+            "0000000000000002.json",
+        ];
+        assert_eq!(left, expected);
+    }
+
+    #[test]
+    fn popped_and_set_aside_changes_leave_the_queue() {
+        let cache = tempfile::tempdir().unwrap();
+        let queue = ChangeQueue::open(cache.path()).unwrap();
+        queue.push(write("/a/b", Expected::Absent), Some(b"mine")).unwrap();
+        queue.push(mkdir("/c"), None).unwrap();
+
+        let (seq, change) = queue.front().unwrap();
+        let kept = queue.set_aside(seq, &change, "taken").unwrap();
+        assert_eq!(fs::read(&kept).unwrap(), b"mine");
+        assert!(kept.file_name().unwrap().to_str().unwrap().ends_with("-0-a%2Fb"));
+        let conflict: serde_json::Value =
+            serde_json::from_slice(&fs::read(kept.with_extension("json")).unwrap()).unwrap();
+        assert_eq!(conflict["error"], "taken");
+        assert_eq!(conflict["change"]["path"], "/a/b");
+
+        let (seq, _) = queue.front().unwrap();
+        queue.pop(seq);
+        assert!(queue.is_empty());
+        assert_eq!(fs::read_dir(cache.path().join("queue")).unwrap().count(), 0);
+        // Numbers start over once empty
+        queue.push(mkdir("/e"), None).unwrap();
+        assert_eq!(queue.front().unwrap().0, 0);
+    }
+
+    #[test]
+    fn offline_changes_are_queued_and_replayed_in_order() {
+        let cache = tempfile::tempdir().unwrap();
+        let (mock, fs) = offline(cache.path());
+        mock.add_file("/old", b"x");
+        fs.create_directory("/d").unwrap();
+        fs.create_file("/d/new").unwrap();
+        fs.delete_file("/old").unwrap();
+        assert!(mock.take_calls().is_empty());
+        assert_eq!(fs.change_queue.as_ref().unwrap().len(), 3);
+        assert!(fs.queues_op(Op::Mkdir));
+
+        fs.replay_changes().unwrap();
+        assert!(!fs.is_offline());
+        assert!(fs.change_queue.as_ref().unwrap().is_empty());
+        assert_eq!(mock.contents("/d/new").unwrap(), b"");
+        assert!(mock.contents("/old").is_none());
+        let calls = mock.take_calls();
+        assert_eq!(calls, ["mkdir /d", "write /d/new", "delete /old"]);
+        assert_eq!(fs.stats.replayed_changes.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn turned_down_changes_take_later_ones_on_their_path_along() {
+        let cache = tempfile::tempdir().unwrap();
+        let (mock, fs) = offline(cache.path());
+        fs.create_file("/a").unwrap();
+        fs.create_file("/b").unwrap();
+        let queue = fs.change_queue.as_ref().unwrap();
+        queue.push(write("/a", Expected::Any), Some(b"later")).unwrap();
+        // Made by someone else meanwhile
+        mock.add_file("/a", b"theirs");
+
+        fs.replay_changes().unwrap();
+        assert_eq!(mock.contents("/a").unwrap(), b"theirs");
+        assert_eq!(mock.contents("/b").unwrap(), b"");
+        assert_eq!(fs.stats.replay_conflicts.load(Ordering::Relaxed), 2);
+        assert_eq!(fs.stats.replayed_changes.load(Ordering::Relaxed), 1);
+        let conflicts = fs::read_dir(cache.path().join("conflicts")).unwrap().count();
+        // Each with its JSON and data file
+        assert_eq!(conflicts, 4);
+    }
+
+    #[test]
+    fn replay_stops_while_the_server_is_down() {
+        let cache = tempfile::tempdir().unwrap();
+        let (mock, fs) = offline(cache.path());
+        fs.create_directory("/d").unwrap();
+        mock.fail_next("mkdir", FsError::Unreachable);
+
+        assert!(fs.replay_changes().is_err());
+        assert!(fs.is_offline());
+        assert_eq!(fs.change_queue.as_ref().unwrap().len(), 1);
+        fs.replay_changes().unwrap();
+        assert!(!fs.is_offline());
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::ffi::OsStr;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Instant, SystemTime};
+
+use super::{join_path, split_path, FsError, INode, RemoteFS, WriteBuffer};
+use crate::api_client::{Conditional, Expected, Version};
+use crate::logging::rfc3339;
+
+// What a flush does when someone else changed the file on the server since
+// the handle's writes were based on it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictMode {
+    // Uploads are not conditional and the last one wins; changes replayed
+    // after offline that the server turns down fail
+    #[default]
+    Error,
+    // Uploads are conditional on the version the handle was opened on. When
+    // it changed, ours is saved next to it as
+    // <name>.conflict-<host>-<time><ext> and the flush succeeds.
+    ConflictCopy,
+}
+
+impl ConflictMode {
+    // Parses `--on-conflict`
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "conflict-copy" => Ok(Self::ConflictCopy),
+            "error" => Ok(Self::Error),
+            other => anyhow::bail!(
+                "Unknown conflict mode '{}', expected conflict-copy or error",
+                other
+            ),
+        }
+    }
+}
+
+// `report.txt` becomes `report.conflict-<host>-20240501T120000.txt`, names
+// without an extension or starting with their only dot get the suffix last
+fn conflict_name(name: &str, host: &str, time: SystemTime) -> String {
+    let stamp: String = rfc3339(time)[..19]
+        .chars()
+        .filter(|c| *c != '-' && *c != ':')
+        .collect();
+    let (stem, ext) = match name.rfind('.') {
+        Some(at) if at > 0 => name.split_at(at),
+        _ => (name, ""),
+    };
+    format!("{}.conflict-{}-{}{}", stem, host, stamp, ext)
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    let len = buf.iter().position(|&byte| byte == 0).unwrap_or(buf.len());
+    let name = match result {
+        0 => String::from_utf8_lossy(&buf[..len]).replace('/', "-"),
+        _ => String::new(),
+    };
+    if name.is_empty() {
+        "localhost".to_string()
+    } else {
+        name
+    }
+}
+
+impl RemoteFS {
+    // The version a handle opened for writing bases its uploads on, with
+    // on_conflict conflict-copy. Asked from the server unless known.
+    pub(super) fn write_base(&self, inode: &INode) -> Option<Version> {
+        if self.config().on_conflict != ConflictMode::ConflictCopy {
+            return None;
+        }
+        if inode.version.is_some() {
+            return inode.version.clone();
+        }
+        match self.backend.file_version(&inode.path) {
+            Ok(version) => version,
+            Err(e) => {
+                log::debug!("Failed to get the version of {}: {:#}", inode.path, e);
+                None
+            }
+        }
+    }
+
+    // Whether an upload based on `base` failed because the file changed
+    pub(super) fn is_write_conflict(&self, base: Option<&Version>, error: &anyhow::Error) -> bool {
+        base.is_some()
+            && self.config().on_conflict == ConflictMode::ConflictCopy
+            && FsError::from_backend(error) == FsError::Stale
+    }
+
+    // Makes sure the server still has `base` before an upload that cannot
+    // be made conditional. Another writer may still get in between.
+    pub(super) fn check_base(&self, path: &str, base: &Version) -> Result<()> {
+        match self.backend.revalidate_file(path, base)? {
+            Conditional::NotModified => Ok(()),
+            Conditional::Modified(_) => Err(anyhow::Error::new(FsError::Stale)
+                .context(format!("{} changed on the server", path))),
+        }
+    }
+
+    // Saves the contents a flush could not upload next to the file someone
+    // else changed, then shows the server's copy of the original: handles
+    // build on it from now on and the caches forget the old contents.
+    // Returns the server's size of the original.
+    pub(super) fn save_conflict_copy(
+        &self,
+        inode: &INode,
+        remote_size: u64,
+        buffer: &WriteBuffer,
+    ) -> Result<u64> {
+        let (parent, name) = split_path(&inode.path);
+        let copy_name = conflict_name(name, &hostname(), SystemTime::now());
+        let copy = join_path(parent, &copy_name);
+        // Whatever the handle did not write comes from the server's new copy
+        let content = self.assemble(&inode.path, remote_size, buffer)?;
+        self.backend
+            .write_file_if(&copy, &content, &Expected::Absent)
+            .with_context(|| format!("Failed to save a conflict copy at {}", copy))?;
+        self.stats.uploads_issued.fetch_add(1, Ordering::Relaxed);
+        self.stats.write_conflicts.fetch_add(1, Ordering::Relaxed);
+        log::warn!(
+            "{} changed on the server since it was opened, saved our version as {}",
+            inode.path,
+            copy
+        );
+
+        self.drop_file_data(inode.ino);
+        {
+            let mut inodes = self.inodes.write().unwrap();
+            let expired = Instant::now().checked_sub(self.config().cache.attr_timeout);
+            if let (Some(inode), Some(expired)) = (inodes.get_mut(inode.ino), expired) {
+                inode.fetched_at = expired;
+            }
+        }
+        // The copy shows up in the parent's next listing and lookup
+        self.invalidate_parent_listing(&inode.path);
+        let parent_ino = self.inodes.read().unwrap().resolve_path(parent);
+        if let Some(parent_ino) = parent_ino {
+            self.forget_missing(parent_ino, &copy_name);
+            if let Some(notifier) = self.notifier.get().cloned() {
+                thread::spawn(move || {
+                    let _ = notifier.inval_entry(parent_ino, OsStr::new(&copy_name));
+                });
+            }
+        }
+
+        let size = self
+            .revalidate_inode(inode.ino)
+            .map_or(0, |inode| inode.attr.size);
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{FsConfig, RemoteBackend};
+    use crate::testing::MockBackend;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // /docs/r.txt opened with "mine" written over it, then replaced on the
+    // server by another client
+    fn changed_under_a_handle(on_conflict: ConflictMode) -> (Arc<MockBackend>, RemoteFS, u64) {
+        let mock = Arc::new(MockBackend::new());
+        mock.add_dir("/docs");
+        mock.add_file("/docs/r.txt", b"v1");
+        let config = FsConfig {
+            on_conflict,
+            ..FsConfig::default()
+        };
+        let fs = RemoteFS::with_backend(mock.clone(), config);
+        let listing = fs.list_directory("/docs").unwrap();
+        let ino = fs.get_or_create_inode("/docs/r.txt", &listing[0]);
+        let base = fs.write_base(&fs.get_inode(ino).unwrap());
+        let fh = fs.open_handle(ino, 2, base);
+        fs.file_handles.lock().unwrap().get_mut(&fh).unwrap().buffer.write(0, b"mine");
+
+        mock.add_file("/docs/r.txt", b"theirs");
+        (mock, fs, fh)
+    }
+
+    fn names(mock: &MockBackend) -> Vec<String> {
+        let listing = mock.list_directory("/docs").unwrap();
+        listing.entries.into_iter().map(|entry| entry.name).collect()
+    }
+
+    #[test]
+    fn copies_keep_the_extension_last() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_714_564_800);
+        let name = |name| conflict_name(name, "laptop", time);
+        assert_eq!(name("report.txt"), "report.conflict-laptop-20240501T120000.txt");
+        assert_eq!(name("a.tar.gz"), "a.tar.conflict-laptop-20240501T120000.gz");
+        assert_eq!(name("Makefile"), "Makefile.conflict-laptop-20240501T120000");
+        assert_eq!(name(".bashrc"), ".bashrc.conflict-laptop-20240501T120000");
+    }
+
+    #[test]
+    fn modes_parse_by_name() {
+        assert_eq!(ConflictMode::parse("Conflict-Copy").unwrap(), ConflictMode::ConflictCopy);
+        assert_eq!(ConflictMode::parse("error").unwrap(), ConflictMode::Error);
+        assert!(ConflictMode::parse("merge").is_err());
+        let host = hostname();
+        assert!(!host.is_empty() && !host.contains('/'));
+    }
+
+    #[test]
+    fn changed_files_get_a_copy_of_ours_next_to_them() {
+        let (mock, fs, fh) = changed_under_a_handle(ConflictMode::ConflictCopy);
+        fs.flush_handle(fh).unwrap();
+
+        assert_eq!(mock.contents("/docs/r.txt").unwrap(), b"theirs");
+        let names = names(&mock);
+        let copy = names
+            .iter()
+            .find(|name| name.starts_with("r.conflict-") && name.ends_with(".txt"))
+            .unwrap();
+        assert_eq!(mock.contents(&format!("/docs/{}", copy)).unwrap(), b"mine");
+        assert_eq!(fs.stats.write_conflicts.load(Ordering::Relaxed), 1);
+        // The handle builds on the server's copy from now on
+        let inode = fs.inodes.read().unwrap().get_path("/docs/r.txt").cloned().unwrap();
+        assert_eq!(inode.attr.size, 6);
+    }
+
+    #[test]
+    fn without_conflict_copies_the_last_upload_wins() {
+        let (mock, fs, fh) = changed_under_a_handle(ConflictMode::Error);
+        fs.flush_handle(fh).unwrap();
+        // Written over their copy, as a partial write
+        assert_eq!(mock.contents("/docs/r.txt").unwrap(), b"miners");
+        assert_eq!(names(&mock), ["r.txt"]);
+        assert_eq!(fs.stats.write_conflicts.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn unchanged_bases_pass_the_check() {
+        let (mock, fs, _) = changed_under_a_handle(ConflictMode::ConflictCopy);
+        let version = mock.file_version("/docs/r.txt").unwrap().unwrap();
+        fs.check_base("/docs/r.txt", &version).unwrap();
+        mock.add_file("/docs/r.txt", b"again");
+        let error = fs.check_base("/docs/r.txt", &version).unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::Stale);
+        assert!(fs.is_write_conflict(Some(&version), &error));
+        assert!(!fs.is_write_conflict(None, &error));
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::{Context, Result};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::{self, DirBuilder};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use super::{normalize_path, RemoteFS, SIGNAL_POLL_INTERVAL};
+
+// Flushing may take as long as the uploads it waits for
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Commands taken on the control socket. The protocol is one JSON object per
+// line each way, like {"command":"drop-caches","path":"/docs"} answered by
+// {"ok":true,"result":{...}} or {"ok":false,"error":"..."}.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum ControlRequest {
+    // The statistics snapshot
+    Stats,
+    // Everything cached under `path` except dirty data, the whole mount
+    // when unset
+    DropCaches {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+    },
+    // Uploads all buffered writes now
+    Flush,
+    // Like "debug" or "warn", for the whole process
+    SetLogLevel {
+        level: String,
+    },
+    // Reads the configuration file again and applies the timeouts and
+    // limits that do not need a remount
+    ReloadConfig,
+}
+
+impl ControlRequest {
+    // Parses the words after the socket in `remotefs ctl <socket> <command>`
+    pub fn parse<S: AsRef<str>>(args: &[S]) -> Result<Self> {
+        let args: Vec<&str> = args.iter().map(AsRef::as_ref).collect();
+        match args.as_slice() {
+            ["stats"] => Ok(Self::Stats),
+            ["drop-caches"] => Ok(Self::DropCaches { path: None }),
+            ["drop-caches", path] => Ok(Self::DropCaches {
+                path: Some(path.to_string()),
+            }),
+            ["flush"] => Ok(Self::Flush),
+            ["set-log-level", level] => Ok(Self::SetLogLevel {
+                level: level.to_string(),
+            }),
+            ["reload-config"] => Ok(Self::ReloadConfig),
+            _ => anyhow::bail!(
+                "Expected one of: stats, drop-caches [path], flush, set-log-level <level>, \
+                 reload-config"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ControlResponse {
+    ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// $XDG_RUNTIME_DIR/remotefs/<name>.sock, where `name` is a label or a
+// mountpoint with its slashes turned into dashes. None without
+// XDG_RUNTIME_DIR.
+pub fn default_control_socket(name: &str) -> Option<PathBuf> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")?;
+    let name = name.trim_matches('/').replace('/', "-");
+    let name = if name.is_empty() { "root" } else { &name };
+    Some(
+        Path::new(&runtime_dir)
+            .join("remotefs")
+            .join(format!("{}.sock", name)),
+    )
+}
+
+// Sends one command to the mount listening on `socket`, returning its result
+pub fn control(socket: &Path, request: &ControlRequest) -> Result<Value> {
+    let stream = UnixStream::connect(socket)
+        .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    (&stream).write_all(line.as_bytes())?;
+
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .context("No answer on the control socket")?;
+    let response: ControlResponse =
+        serde_json::from_str(&line).context("Invalid answer on the control socket")?;
+    match response.error {
+        Some(error) if !response.ok => anyhow::bail!("{}", error),
+        _ => Ok(response.result.unwrap_or(Value::Null)),
+    }
+}
+
+// Listens on `path` until the mount shuts down, then removes the socket.
+// Only the user running the mount may connect: the socket is made owner-only
+// and peers with another uid are turned away.
+pub fn spawn(fs: &RemoteFS, path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    // A socket left behind by a mount that died is replaced, a live one is not
+    if path.exists() {
+        anyhow::ensure!(
+            UnixStream::connect(path).is_err(),
+            "Control socket {} is in use by another mount",
+            path.display()
+        );
+        fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    // Polled, so the listener notices the shutdown and removes the socket
+    listener.set_nonblocking(true)?;
+    log::info!("Taking commands on {}", path.display());
+
+    let fs = fs.clone();
+    let path = path.to_path_buf();
+    thread::spawn(move || {
+        while !fs.shutdown.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    // A flush must not hold up the next command
+                    let fs = fs.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(&fs, stream) {
+                            log::debug!("Control connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(SIGNAL_POLL_INTERVAL)
+                }
+                Err(e) => log::warn!("Failed to accept a control connection: {}", e),
+            }
+        }
+        let _ = fs::remove_file(&path);
+    });
+    Ok(())
+}
+
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+fn serve(fs: &RemoteFS, stream: UnixStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    if peer_uid(&stream)? != unsafe { libc::geteuid() } {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "peer runs as another user",
+        ));
+    }
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        let result = serde_json::from_str(&line)
+            .context("Invalid request")
+            .and_then(|request| execute(fs, request));
+        let response = match result {
+            Ok(result) => ControlResponse {
+                ok: true,
+                result: Some(result),
+                error: None,
+            },
+            Err(e) => ControlResponse {
+                ok: false,
+                result: None,
+                error: Some(format!("{:#}", e)),
+            },
+        };
+        let mut answer = serde_json::to_string(&response)?;
+        answer.push('\n');
+        (&stream).write_all(answer.as_bytes())?;
+        line.clear();
+    }
+    Ok(())
+}
+
+fn execute(fs: &RemoteFS, request: ControlRequest) -> Result<Value> {
+    log::info!("Control command {:?}", request);
+    match request {
+        ControlRequest::Stats => Ok(serde_json::to_value(fs.stats_snapshot())?),
+        ControlRequest::DropCaches { path } => {
+            let path = path.as_deref().unwrap_or("/");
+            anyhow::ensure!(path.starts_with('/'), "Expected an absolute path");
+            let (entries, bytes) = fs.drop_caches(&normalize_path(path));
+            Ok(json!({ "entries": entries, "bytes": bytes }))
+        }
+        ControlRequest::Flush => {
+            fs.flush_all()?;
+            Ok(Value::Null)
+        }
+        ControlRequest::SetLogLevel { level } => {
+            let level: LevelFilter = level
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Unknown log level '{}'", level))?;
+            log::set_max_level(level);
+            Ok(json!({ "level": level.to_string().to_ascii_lowercase() }))
+        }
+        ControlRequest::ReloadConfig => {
+            let source = fs
+                .config()
+                .config_source
+                .clone()
+                .context("The mount was not configured from a file")?;
+            let reloaded = source.load()?;
+            let changed = fs.reload_config(&reloaded.fs);
+            log::info!("Reloaded configuration, changed: {:?}", changed);
+            Ok(json!({ "changed": changed }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockBackend;
+    use crate::FsConfig;
+    use std::sync::Arc;
+
+    #[test]
+    fn commands_parse_from_words() {
+        assert_eq!(ControlRequest::parse(&["stats"]).unwrap(), ControlRequest::Stats);
+        assert_eq!(
+            ControlRequest::parse(&["set-log-level", "warn"]).unwrap(),
+            ControlRequest::SetLogLevel {
+                level: "warn".to_string()
+            }
+        );
+        assert!(ControlRequest::parse(&["flush", "now"]).is_err());
+        assert!(ControlRequest::parse::<&str>(&[]).is_err());
+    }
+
+    #[test]
+    fn commands_round_trip_over_the_socket() {
+        let mock = Arc::new(MockBackend::new());
+        mock.add_file("/a.txt", b"alpha");
+        let fs = RemoteFS::with_backend(mock, FsConfig::default());
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("sub/mount.sock");
+        spawn(&fs, &socket).unwrap();
+
+        let stats = control(&socket, &ControlRequest::Stats).unwrap();
+        assert!(stats.is_object(), "{}", stats);
+        let dropped = control(&socket, &ControlRequest::DropCaches { path: None }).unwrap();
+        assert!(dropped["entries"].is_number(), "{}", dropped);
+        assert!(control(&socket, &ControlRequest::Flush).unwrap().is_null());
+
+        // Refused commands come back as errors
+        let relative = ControlRequest::DropCaches {
+            path: Some("docs".to_string()),
+        };
+        let e = control(&socket, &relative).unwrap_err();
+        assert!(e.to_string().contains("absolute path"), "{:#}", e);
+        let e = control(&socket, &ControlRequest::ReloadConfig).unwrap_err();
+        assert!(e.to_string().contains("not configured from a file"), "{:#}", e);
+
+        // A second mount cannot take the socket over
+        assert!(spawn(&fs, &socket).is_err());
+        fs.shutdown.store(true, Ordering::Relaxed);
+    }
+}
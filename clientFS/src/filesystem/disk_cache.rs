@@ -0,0 +1,375 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::cache::LruCache;
+use crate::api_client::FileEntry;
+
+// Bumped whenever the on-disk layout changes; older entries are ignored
+const FORMAT_VERSION: u32 = 1;
+const BLOCK_MAGIC: &[u8; 4] = b"RFSB";
+
+// Identifies the remote version of a file a cached block was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Validator {
+    pub size: u64,
+    pub mtime_nanos: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredListing {
+    version: u32,
+    path: String,
+    entries: Vec<FileEntry>,
+}
+
+// FNV-1a, used for file names and checksums because it is stable across builds
+pub(super) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub(super) fn read_u32(bytes: &[u8], at: &mut usize) -> Option<u32> {
+    let value = u32::from_le_bytes(bytes.get(*at..*at + 4)?.try_into().ok()?);
+    *at += 4;
+    Some(value)
+}
+
+pub(super) fn read_u64(bytes: &[u8], at: &mut usize) -> Option<u64> {
+    let value = u64::from_le_bytes(bytes.get(*at..*at + 8)?.try_into().ok()?);
+    *at += 8;
+    Some(value)
+}
+
+// Cache of file blocks and directory listings kept under `--cache-dir` so a
+// remount starts warm. Entries are only trusted after checking them against
+// the current remote attributes, and anything unreadable is deleted.
+pub struct DiskCache {
+    root: PathBuf,
+    block_size: u64,
+    index: Mutex<LruCache<PathBuf, ()>>,
+}
+
+impl DiskCache {
+    pub fn open(dir: &Path, block_size: u64, max_bytes: u64) -> io::Result<Self> {
+        // Entries written with another format or block size live in their own namespace
+        let root = dir.join(format!("v{}-{}", FORMAT_VERSION, block_size));
+        for sub in ["blocks", "listings"] {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(root.join(sub))?;
+        }
+
+        let cache = Self {
+            root,
+            block_size,
+            index: Mutex::new(LruCache::new(max_bytes as usize)),
+        };
+        cache.load_index()?;
+
+        Ok(cache)
+    }
+
+    // Registers existing files, oldest first, so eviction resumes where it left off
+    fn load_index(&self) -> io::Result<()> {
+        let mut files = Vec::new();
+        for sub in ["blocks", "listings"] {
+            for entry in fs::read_dir(self.root.join(sub))? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if entry.path().extension().is_some_and(|ext| ext == "tmp") {
+                    // Left behind by a write that was interrupted
+                    let _ = fs::remove_file(entry.path());
+                } else if metadata.is_file() {
+                    files.push((metadata.modified()?, entry.path(), metadata.len()));
+                }
+            }
+        }
+        files.sort();
+
+        for (_, path, len) in files {
+            self.track(path, len);
+        }
+        Ok(())
+    }
+
+    fn track(&self, file: PathBuf, len: u64) {
+        let evicted = self.index.lock().unwrap().insert(file, (), len as usize);
+        for (file, _) in evicted {
+            let _ = fs::remove_file(file);
+        }
+    }
+
+    fn discard(&self, file: &Path) {
+        self.index.lock().unwrap().remove(file);
+        let _ = fs::remove_file(file);
+    }
+
+    fn block_file(&self, path: &str, index: u64) -> PathBuf {
+        let key = format!("{}\0{}", path, index);
+        self.root
+            .join("blocks")
+            .join(format!("{:016x}", fnv1a(key.as_bytes())))
+    }
+
+    fn listing_file(&self, path: &str) -> PathBuf {
+        self.root
+            .join("listings")
+            .join(format!("{:016x}.json", fnv1a(path.as_bytes())))
+    }
+
+    // Writes through a temporary file so readers never see a partial entry
+    fn write_file(&self, file: &Path, contents: &[u8]) {
+        let tmp = file.with_extension("tmp");
+        let result = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .and_then(|mut f| f.write_all(contents))
+            .and_then(|_| fs::rename(&tmp, file));
+
+        match result {
+            Ok(_) => self.track(file.to_path_buf(), contents.len() as u64),
+            Err(e) => {
+                log::warn!("Failed to write cache file {}: {}", file.display(), e);
+                let _ = fs::remove_file(&tmp);
+            }
+        }
+    }
+
+    fn read_file(&self, file: &Path) -> Option<Vec<u8>> {
+        let mut contents = Vec::new();
+        File::open(file).ok()?.read_to_end(&mut contents).ok()?;
+        self.index.lock().unwrap().get(file);
+        Some(contents)
+    }
+
+    pub fn load_block(&self, path: &str, index: u64, validator: Validator) -> Option<Vec<u8>> {
+        let file = self.block_file(path, index);
+        let contents = self.read_file(&file)?;
+
+        match self.parse_block(&contents, path, index) {
+            Some((stored, data)) if stored == validator => Some(data.to_vec()),
+            Some(_) => {
+                log::debug!("Cached block {} of {} is outdated", index, path);
+                self.discard(&file);
+                None
+            }
+            None => {
+                log::warn!("Discarding corrupt cache file {}", file.display());
+                self.discard(&file);
+                None
+            }
+        }
+    }
+
+    fn parse_block<'a>(
+        &self,
+        contents: &'a [u8],
+        path: &str,
+        index: u64,
+    ) -> Option<(Validator, &'a [u8])> {
+        if contents.get(..4)? != BLOCK_MAGIC {
+            return None;
+        }
+
+        let mut at = 4;
+        if read_u32(contents, &mut at)? != FORMAT_VERSION
+            || read_u64(contents, &mut at)? != self.block_size
+        {
+            return None;
+        }
+
+        let validator = Validator {
+            size: read_u64(contents, &mut at)?,
+            mtime_nanos: read_u64(contents, &mut at)?,
+        };
+        let stored_index = read_u64(contents, &mut at)?;
+        let path_len = read_u32(contents, &mut at)? as usize;
+        let stored_path = contents.get(at..at + path_len)?;
+        at += path_len;
+        let checksum = read_u64(contents, &mut at)?;
+        let data = &contents[at..];
+
+        // A hash collision shows up as a different path or index
+        if stored_index != index || stored_path != path.as_bytes() || fnv1a(data) != checksum {
+            return None;
+        }
+
+        Some((validator, data))
+    }
+
+    pub fn store_block(&self, path: &str, index: u64, validator: Validator, data: &[u8]) {
+        let mut contents = Vec::with_capacity(data.len() + path.len() + 48);
+        contents.extend_from_slice(BLOCK_MAGIC);
+        contents.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        contents.extend_from_slice(&self.block_size.to_le_bytes());
+        contents.extend_from_slice(&validator.size.to_le_bytes());
+        contents.extend_from_slice(&validator.mtime_nanos.to_le_bytes());
+        contents.extend_from_slice(&index.to_le_bytes());
+        contents.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        contents.extend_from_slice(path.as_bytes());
+        contents.extend_from_slice(&fnv1a(data).to_le_bytes());
+        contents.extend_from_slice(data);
+
+        self.write_file(&self.block_file(path, index), &contents);
+    }
+
+    // Deletes the cached blocks of a file of the given size
+    pub fn discard_file(&self, path: &str, size: u64) {
+        for index in 0..=size / self.block_size {
+            let file = self.block_file(path, index);
+            if file.exists() {
+                self.discard(&file);
+            }
+        }
+    }
+
+    pub fn discard_listing(&self, path: &str) {
+        let file = self.listing_file(path);
+        if file.exists() {
+            self.discard(&file);
+        }
+    }
+
+    pub fn load_listing(&self, path: &str) -> Option<Vec<FileEntry>> {
+        let file = self.listing_file(path);
+        let contents = self.read_file(&file)?;
+
+        match serde_json::from_slice::<StoredListing>(&contents) {
+            Ok(stored) if stored.version == FORMAT_VERSION && stored.path == path => {
+                Some(stored.entries)
+            }
+            _ => {
+                log::warn!("Discarding unusable cache file {}", file.display());
+                self.discard(&file);
+                None
+            }
+        }
+    }
+
+    pub fn store_listing(&self, path: &str, entries: &[FileEntry]) {
+        let stored = StoredListing {
+            version: FORMAT_VERSION,
+            path: path.to_string(),
+            entries: entries.to_vec(),
+        };
+
+        match serde_json::to_vec(&stored) {
+            Ok(contents) => self.write_file(&self.listing_file(path), &contents),
+            Err(e) => log::warn!("Failed to serialize listing of {}: {}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALIDATOR: Validator = Validator {
+        size: 10,
+        mtime_nanos: 1_700_000_000_000_000_000,
+    };
+
+    fn block_files(dir: &Path) -> usize {
+        let blocks = dir.join(format!("v{}-4", FORMAT_VERSION)).join("blocks");
+        fs::read_dir(blocks).unwrap().count()
+    }
+
+    #[test]
+    fn blocks_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        DiskCache::open(dir.path(), 4, 1 << 20)
+            .unwrap()
+            .store_block("/a", 1, VALIDATOR, b"efgh");
+
+        let cache = DiskCache::open(dir.path(), 4, 1 << 20).unwrap();
+        assert_eq!(cache.load_block("/a", 1, VALIDATOR).unwrap(), b"efgh");
+        assert!(cache.load_block("/a", 0, VALIDATOR).is_none());
+        assert!(cache.load_block("/b", 1, VALIDATOR).is_none());
+        // Another block size is another namespace
+        let other = DiskCache::open(dir.path(), 8, 1 << 20).unwrap();
+        assert!(other.load_block("/a", 1, VALIDATOR).is_none());
+    }
+
+    #[test]
+    fn outdated_and_corrupt_blocks_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::open(dir.path(), 4, 1 << 20).unwrap();
+        cache.store_block("/a", 0, VALIDATOR, b"abcd");
+        let changed = Validator {
+            size: 11,
+            ..VALIDATOR
+        };
+        assert!(cache.load_block("/a", 0, changed).is_none());
+        assert_eq!(block_files(dir.path()), 0);
+
+        cache.store_block("/a", 0, VALIDATOR, b"abcd");
+        let file = cache.block_file("/a", 0);
+        let mut contents = fs::read(&file).unwrap();
+        *contents.last_mut().unwrap() ^= 1;
+        fs::write(&file, contents).unwrap();
+        assert!(cache.load_block("/a", 0, VALIDATOR).is_none());
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn oldest_files_go_over_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        // Room for two blocks of 4 bytes with their 54 byte headers
+        let cache = DiskCache::open(dir.path(), 4, 120).unwrap();
+        for index in 0..3 {
+            cache.store_block("/a", index, VALIDATOR, b"abcd");
+        }
+        assert_eq!(block_files(dir.path()), 2);
+        assert!(cache.load_block("/a", 0, VALIDATOR).is_none());
+        assert!(cache.load_block("/a", 2, VALIDATOR).is_some());
+    }
+
+    #[test]
+    fn listings_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::open(dir.path(), 4, 1 << 20).unwrap();
+        let entry: FileEntry =
+            serde_json::from_str(r#"{"name":"a.txt","is_dir":false,"size":3,"mode":420}"#)
+                .unwrap();
+        cache.store_listing("/docs", std::slice::from_ref(&entry));
+
+        let listed = cache.load_listing("/docs").unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].name, "a.txt");
+        cache.discard_listing("/docs");
+        assert!(cache.load_listing("/docs").is_none());
+    }
+
+    #[test]
+    fn discarding_a_file_drops_all_its_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::open(dir.path(), 4, 1 << 20).unwrap();
+        for index in 0..3 {
+            cache.store_block("/a", index, VALIDATOR, b"abcd");
+        }
+        cache.store_block("/b", 0, VALIDATOR, b"abcd");
+
+        cache.discard_file("/a", 10);
+        assert_eq!(block_files(dir.path()), 1);
+        assert!(cache.load_block("/b", 0, VALIDATOR).is_some());
+    }
+
+    #[test]
+    fn interrupted_writes_are_cleaned_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::open(dir.path(), 4, 1 << 20).unwrap();
+        let tmp = cache.block_file("/a", 0).with_extension("tmp");
+        fs::write(&tmp, b"half").unwrap();
+        DiskCache::open(dir.path(), 4, 1 << 20).unwrap();
+        assert!(!tmp.exists());
+    }
+}
@@ -0,0 +1,110 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::Semaphore;
+
+// Runs FUSE operations as tasks on a shared multi-threaded tokio runtime, so
+// a slow request only holds up its own caller instead of the whole session.
+// Each operation owns its reply and answers the kernel when it finishes. The
+// backends make blocking calls, so operations run on the runtime's blocking
+// pool. A semaphore bounds them to max_concurrent_ops: once every permit is
+// out the session thread waits for one, and reads no further requests.
+pub struct Dispatcher {
+    runtime: Option<Runtime>,
+    slots: Arc<Semaphore>,
+}
+
+impl Dispatcher {
+    pub fn new(name: &str, workers: usize) -> Self {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .max_blocking_threads(workers)
+            .thread_name(format!("{}-worker", name))
+            .build();
+        let runtime = match runtime {
+            Ok(runtime) => Some(runtime),
+            Err(e) => {
+                log::warn!("Failed to start the FUSE runtime, serving inline: {}", e);
+                None
+            }
+        };
+
+        Self {
+            runtime,
+            slots: Arc::new(Semaphore::new(workers)),
+        }
+    }
+
+    // Runs `job` as a task once one of the slots is free, or right here
+    // without a runtime
+    pub fn run<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let Some(runtime) = &self.runtime else {
+            return job();
+        };
+        let Ok(permit) = runtime.block_on(self.slots.clone().acquire_owned()) else {
+            return job();
+        };
+
+        runtime.spawn_blocking(move || {
+            let _permit = permit;
+            // A panicking operation drops its reply, which answers the kernel
+            // with EIO, and must not take anything else down with it
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                log::error!("FUSE operation panicked");
+            }
+        });
+    }
+}
+
+impl Drop for Dispatcher {
+    // The last handle on the filesystem may go away inside one of the tasks,
+    // where waiting for the runtime's threads would wait for itself
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn no_more_operations_at_once_than_slots() {
+        let dispatcher = Dispatcher::new("test", 3);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (done, finished) = mpsc::channel();
+        for _ in 0..20 {
+            let (running, most, done) = (running.clone(), most.clone(), done.clone());
+            dispatcher.run(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(5));
+                running.fetch_sub(1, Ordering::SeqCst);
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..20 {
+            finished.recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+        assert!(most.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[test]
+    fn panicking_operation_gives_its_slot_back() {
+        let dispatcher = Dispatcher::new("test", 1);
+        dispatcher.run(|| panic!("operation failed"));
+        let (done, finished) = mpsc::channel();
+        dispatcher.run(move || done.send(()).unwrap());
+        finished.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+}
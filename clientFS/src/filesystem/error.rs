@@ -0,0 +1,239 @@
+use reqwest::StatusCode;
+use std::fmt;
+
+use crate::api_client::{CircuitOpen, ServerError};
+
+// Why an operation failed, in the terms the kernel understands. Failed backend
+// calls are classified by from_backend, checks made locally use the variants
+// directly, and errno is the only place they turn into error numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    PermissionDenied,
+    // Refused by the sticky bit of the directory, with default_permissions
+    NotPermitted,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    NotEmpty,
+    NameTooLong,
+    // A name that is not UTF-8, which no server path can carry
+    IllegalName,
+    BadHandle,
+    // The object changed on the server under an open handle
+    Stale,
+    NoSpace,
+    // Refused by the client in a read-only mount
+    ReadOnly,
+    FileTooLarge,
+    TimedOut,
+    // The server could not be reached or is not serving requests
+    Unreachable,
+    // The server is overloaded or rate limiting, worth retrying later
+    Busy,
+    // Not cached while the mount is offline, or the server is known to be
+    // down and requests fail fast
+    HostDown,
+    // Opening a pipe, socket or device node, which have no contents to read
+    NoDevice,
+    // A name or path with an empty, '.' or '..' component, refused before it
+    // is sent
+    InvalidPath,
+    // A negative offset or a range past the largest file offset
+    InvalidArgument,
+    Unsupported,
+    // The caller was signalled while the operation waited, see caller_interrupted
+    Interrupted,
+    Io,
+}
+
+impl FsError {
+    pub fn errno(&self) -> i32 {
+        match self {
+            Self::NotFound => libc::ENOENT,
+            Self::PermissionDenied => libc::EACCES,
+            Self::NotPermitted => libc::EPERM,
+            Self::AlreadyExists => libc::EEXIST,
+            Self::NotADirectory => libc::ENOTDIR,
+            Self::IsADirectory => libc::EISDIR,
+            Self::NotEmpty => libc::ENOTEMPTY,
+            Self::NameTooLong => libc::ENAMETOOLONG,
+            Self::IllegalName => libc::EILSEQ,
+            Self::BadHandle => libc::EBADF,
+            Self::Stale => libc::ESTALE,
+            Self::NoSpace => libc::ENOSPC,
+            Self::ReadOnly => libc::EROFS,
+            Self::FileTooLarge => libc::EFBIG,
+            Self::TimedOut => libc::ETIMEDOUT,
+            // Not ENOTCONN, which reads as the mount itself being gone
+            Self::Unreachable => libc::EHOSTDOWN,
+            Self::Busy => libc::EAGAIN,
+            Self::HostDown => libc::EHOSTDOWN,
+            Self::NoDevice => libc::ENXIO,
+            Self::InvalidPath => libc::EINVAL,
+            Self::InvalidArgument => libc::EINVAL,
+            Self::Unsupported => libc::ENOTSUP,
+            Self::Interrupted => libc::EINTR,
+            Self::Io => libc::EIO,
+        }
+    }
+
+    // Classifies an error returned by the backend
+    pub fn from_backend(error: &anyhow::Error) -> Self {
+        if let Some(kind) = error.downcast_ref::<Self>() {
+            return *kind;
+        }
+        if let Some(server) = error.downcast_ref::<ServerError>() {
+            return Self::from_status(server.status);
+        }
+        if error.downcast_ref::<CircuitOpen>().is_some() {
+            return Self::HostDown;
+        }
+
+        match error.downcast_ref::<reqwest::Error>() {
+            Some(e) if e.is_timeout() => Self::TimedOut,
+            Some(e) if e.is_connect() => Self::Unreachable,
+            _ => Self::Io,
+        }
+    }
+
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::NOT_FOUND | StatusCode::GONE => Self::NotFound,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::LOCKED => {
+                Self::PermissionDenied
+            }
+            StatusCode::CONFLICT => Self::AlreadyExists,
+            StatusCode::PRECONDITION_FAILED => Self::Stale,
+            StatusCode::PAYLOAD_TOO_LARGE => Self::FileTooLarge,
+            StatusCode::URI_TOO_LONG => Self::NameTooLong,
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => Self::Unsupported,
+            StatusCode::INSUFFICIENT_STORAGE => Self::NoSpace,
+            StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => Self::TimedOut,
+            StatusCode::BAD_GATEWAY => Self::Unreachable,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => Self::Busy,
+            _ => Self::Io,
+        }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::NotFound => "no such file or directory",
+            Self::PermissionDenied => "permission denied by the server",
+            Self::NotPermitted => "operation not permitted",
+            Self::AlreadyExists => "already exists",
+            Self::NotADirectory => "not a directory",
+            Self::IsADirectory => "is a directory",
+            Self::NotEmpty => "directory not empty",
+            Self::NameTooLong => "name too long",
+            Self::IllegalName => "name is not UTF-8",
+            Self::BadHandle => "bad file handle",
+            Self::Stale => "changed on the server",
+            Self::NoSpace => "no space left on the server",
+            Self::ReadOnly => "read-only mount",
+            Self::FileTooLarge => "file too large for the server",
+            Self::TimedOut => "server timed out",
+            Self::Unreachable => "server unreachable",
+            Self::Busy => "server busy, try again",
+            Self::HostDown => "not cached and the server is unreachable",
+            Self::NoDevice => "no such device or address",
+            Self::InvalidPath => "invalid path",
+            Self::InvalidArgument => "invalid argument",
+            Self::Unsupported => "not supported by the server",
+            Self::Interrupted => "interrupted",
+            Self::Io => "remote I/O error",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for FsError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use std::time::Duration;
+
+    fn server_error(status: u16) -> anyhow::Error {
+        let error = ServerError {
+            status: StatusCode::from_u16(status).unwrap(),
+            maintenance: false,
+        };
+        anyhow::Error::new(error)
+    }
+
+    #[test]
+    fn statuses_map_to_errnos() {
+        let table = [
+            (401, libc::EACCES),
+            (403, libc::EACCES),
+            (404, libc::ENOENT),
+            (405, libc::ENOTSUP),
+            (408, libc::ETIMEDOUT),
+            (409, libc::EEXIST),
+            (410, libc::ENOENT),
+            (412, libc::ESTALE),
+            (413, libc::EFBIG),
+            (414, libc::ENAMETOOLONG),
+            (423, libc::EACCES),
+            (429, libc::EAGAIN),
+            (500, libc::EIO),
+            (501, libc::ENOTSUP),
+            (502, libc::EHOSTDOWN),
+            (503, libc::EAGAIN),
+            (504, libc::ETIMEDOUT),
+            (507, libc::ENOSPC),
+            (418, libc::EIO),
+        ];
+        for (status, errno) in table {
+            let kind = FsError::from_backend(&server_error(status));
+            assert_eq!(kind.errno(), errno, "{} gave {:?}", status, kind);
+        }
+    }
+
+    #[test]
+    fn kinds_are_found_under_context() {
+        let error = server_error(404).context("Failed to send read request");
+        assert_eq!(FsError::from_backend(&error), FsError::NotFound);
+
+        let error = anyhow::Error::new(FsError::NoSpace).context("write /a");
+        assert_eq!(FsError::from_backend(&error), FsError::NoSpace);
+
+        let error: anyhow::Result<()> = Err(server_error(507));
+        let error = error.context("outer").context("outermost").unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::NoSpace);
+    }
+
+    #[test]
+    fn open_circuit_is_host_down() {
+        let error = anyhow::Error::new(CircuitOpen {
+            retry_in: Duration::from_secs(5),
+        });
+        assert_eq!(FsError::from_backend(&error).errno(), libc::EHOSTDOWN);
+    }
+
+    #[test]
+    fn local_kinds_keep_their_errno() {
+        let table = [
+            (FsError::ReadOnly, libc::EROFS),
+            (FsError::NotPermitted, libc::EPERM),
+            (FsError::IllegalName, libc::EILSEQ),
+            (FsError::InvalidPath, libc::EINVAL),
+            (FsError::NoDevice, libc::ENXIO),
+            (FsError::Interrupted, libc::EINTR),
+        ];
+        for (kind, errno) in table {
+            let error = anyhow::Error::new(kind).context("checked locally");
+            assert_eq!(FsError::from_backend(&error).errno(), errno, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn anything_else_is_io() {
+        let error = anyhow::anyhow!("something broke");
+        assert_eq!(FsError::from_backend(&error), FsError::Io);
+    }
+}
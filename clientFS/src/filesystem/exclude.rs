@@ -0,0 +1,206 @@
+use anyhow::Result;
+
+use super::{FsError, RemoteFS};
+
+// A pattern for `--exclude` and `--include`. `*` and `?` stay within one
+// name, `**` spans any number of them, and `[...]` is a class, negated with
+// `!` or `^`. Patterns with a slash match the path from the remote root,
+// others the name at any depth.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Glob {
+    pattern: Vec<char>,
+    anchored: bool,
+}
+
+impl Glob {
+    // Parses `--exclude <glob>` and `--include <glob>`. A leading slash
+    // anchors a name at the root, a trailing one is dropped.
+    pub fn parse(value: &str) -> Result<Self> {
+        let trimmed = value.trim().trim_end_matches('/');
+        let anchored = trimmed.contains('/');
+        let pattern: Vec<char> = trimmed.trim_start_matches('/').chars().collect();
+        anyhow::ensure!(!pattern.is_empty(), "Empty pattern '{}'", value);
+
+        let mut rest = pattern.as_slice();
+        while let Some(open) = rest.iter().position(|c| *c == '[') {
+            let Some(len) = class_len(&rest[open..]) else {
+                anyhow::bail!("Unclosed '[' in pattern '{}'", value);
+            };
+            rest = &rest[open + len..];
+        }
+        Ok(Self { pattern, anchored })
+    }
+
+    // `relative` is a path from the remote root without the leading slash
+    fn matches(&self, relative: &str) -> bool {
+        let target = if self.anchored {
+            relative
+        } else {
+            relative.rsplit('/').next().unwrap_or(relative)
+        };
+        let target: Vec<char> = target.chars().collect();
+        glob_match(&self.pattern, &target)
+    }
+}
+
+// Length of the class `pattern` starts with, up to its closing bracket.
+// A bracket first in the class is part of it.
+fn class_len(pattern: &[char]) -> Option<usize> {
+    let mut i = 1;
+    if matches!(pattern.get(i), Some('!' | '^')) {
+        i += 1;
+    }
+    if pattern.get(i) == Some(&']') {
+        i += 1;
+    }
+    pattern[i..]
+        .iter()
+        .position(|c| *c == ']')
+        .map(|close| i + close + 1)
+}
+
+fn class_matches(class: &[char], c: char) -> bool {
+    let (negated, mut members) = match class.get(1) {
+        Some('!' | '^') => (true, &class[2..class.len() - 1]),
+        _ => (false, &class[1..class.len() - 1]),
+    };
+    let mut found = false;
+    while let Some(&first) = members.first() {
+        if members.len() >= 3 && members[1] == '-' {
+            found |= (first..=members[2]).contains(&c);
+            members = &members[3..];
+        } else {
+            found |= first == c;
+            members = &members[1..];
+        }
+    }
+    found != negated
+}
+
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[pattern.iter().take_while(|c| **c == '*').count()..];
+            // `**/` is any number of whole names, none included
+            if let Some(rest) = rest.strip_prefix(&['/']) {
+                return glob_match(rest, text)
+                    || (0..text.len())
+                        .any(|i| text[i] == '/' && glob_match(rest, &text[i + 1..]));
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some('*') => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != '/')
+            .any(|i| glob_match(&pattern[1..], &text[i..])),
+        Some('?') => {
+            text.first().is_some_and(|c| *c != '/') && glob_match(&pattern[1..], &text[1..])
+        }
+        Some('[') => {
+            // Validated by parse
+            let len = class_len(pattern).unwrap_or(pattern.len());
+            text.first()
+                .is_some_and(|c| *c != '/' && class_matches(&pattern[..len], *c))
+                && glob_match(&pattern[len..], &text[1..])
+        }
+        Some(c) => text.first() == Some(c) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+impl RemoteFS {
+    // Whether `path` is hidden from the mount: it or a directory above it
+    // matches an exclude pattern and no include pattern
+    pub(super) fn is_excluded(&self, path: &str) -> bool {
+        let config = self.config();
+        if config.exclude.is_empty() {
+            return false;
+        }
+        let hidden = |relative: &str| {
+            config.exclude.iter().any(|glob| glob.matches(relative))
+                && !config.include.iter().any(|glob| glob.matches(relative))
+        };
+
+        let relative = path.trim_start_matches('/');
+        relative
+            .match_indices('/')
+            .map(|(end, _)| end)
+            .chain([relative.len()])
+            .filter(|end| *end > 0)
+            .any(|end| hidden(&relative[..end]))
+    }
+
+    // Creating or renaming to an excluded name would add an entry the mount
+    // cannot show
+    pub(super) fn check_creatable(&self, path: &str) -> Result<(), FsError> {
+        if self.is_excluded(path) {
+            log::warn!("Refusing to create {}, it matches an exclude pattern", path);
+            return Err(FsError::NotPermitted);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, relative: &str) -> bool {
+        Glob::parse(pattern).unwrap().matches(relative)
+    }
+
+    #[test]
+    fn wildcards_stay_within_a_name() {
+        assert!(matches("*.o", "main.o"));
+        assert!(matches("*.o", "src/deep/main.o"));
+        assert!(!matches("*.o", "main.c"));
+        assert!(matches("?.tmp", "a.tmp"));
+        assert!(!matches("?.tmp", "ab.tmp"));
+        assert!(!matches("src/*.rs", "src/bin/main.rs"));
+        assert!(!matches("a?b", "a/b"));
+    }
+
+    #[test]
+    fn double_star_spans_names() {
+        assert!(matches("src/**/*.rs", "src/main.rs"));
+        assert!(matches("src/**/*.rs", "src/bin/deep/main.rs"));
+        assert!(!matches("src/**/*.rs", "lib/main.rs"));
+        assert!(matches("**/target", "target"));
+        assert!(matches("**/target", "a/b/target"));
+        assert!(!matches("**/target", "a/mytarget"));
+        assert!(matches("logs/**", "logs/2024/01.txt"));
+    }
+
+    #[test]
+    fn slashes_anchor_at_the_root() {
+        assert!(matches("build", "build"));
+        assert!(matches("build", "sub/build"));
+        assert!(matches("/build", "build"));
+        assert!(!matches("/build", "sub/build"));
+        assert!(matches("build/", "build"));
+        assert!(matches("sub/build", "sub/build"));
+        assert!(!matches("sub/build", "other/sub/build"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("[abc].txt", "b.txt"));
+        assert!(!matches("[abc].txt", "d.txt"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[!a-c]x", "bx"));
+        assert!(matches("[^a-c]x", "dx"));
+        // A bracket first in the class is a member
+        assert!(matches("[]]", "]"));
+        assert!(matches("[!]]", "a"));
+        assert!(!matches("[a/]", "/"));
+    }
+
+    #[test]
+    fn malformed_patterns_are_refused() {
+        assert!(Glob::parse("").is_err());
+        assert!(Glob::parse("/").is_err());
+        assert!(Glob::parse("  ").is_err());
+        assert!(Glob::parse("[abc").is_err());
+        assert!(Glob::parse("ok/[]").is_err());
+        assert!(Glob::parse("*.[ch]").is_ok());
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::HashSet;
+use std::sync::{Condvar, Mutex};
+
+// Per-inode exclusion for uploads, so two handles of the same file never
+// interleave their read-modify-write cycles while different files proceed in
+// parallel. Only inodes currently held are tracked, nothing lingers once the
+// guard is dropped.
+#[derive(Default)]
+pub struct InodeLocks {
+    held: Mutex<HashSet<u64>>,
+    released: Condvar,
+}
+
+pub struct InodeGuard<'a> {
+    locks: &'a InodeLocks,
+    ino: u64,
+}
+
+impl InodeLocks {
+    pub fn lock(&self, ino: u64) -> InodeGuard<'_> {
+        let mut held = self.held.lock().unwrap();
+        while held.contains(&ino) {
+            held = self.released.wait(held).unwrap();
+        }
+        held.insert(ino);
+
+        InodeGuard { locks: self, ino }
+    }
+}
+
+impl Drop for InodeGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.held.lock().unwrap().remove(&self.ino);
+        self.locks.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn same_inode_waits_for_the_guard() {
+        let locks = InodeLocks::default();
+        let (locked, waiting) = mpsc::channel();
+        thread::scope(|scope| {
+            let guard = locks.lock(7);
+            scope.spawn(|| {
+                let _guard = locks.lock(7);
+                locked.send(()).unwrap();
+            });
+            assert!(waiting.recv_timeout(Duration::from_millis(50)).is_err());
+            drop(guard);
+            waiting.recv_timeout(Duration::from_secs(5)).unwrap();
+        });
+        assert!(locks.held.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn other_inodes_go_ahead() {
+        let locks = InodeLocks::default();
+        let _guard = locks.lock(7);
+        thread::scope(|scope| {
+            scope.spawn(|| drop(locks.lock(8))).join().unwrap();
+        });
+        assert_eq!(*locks.held.lock().unwrap(), HashSet::from([7]));
+    }
+}
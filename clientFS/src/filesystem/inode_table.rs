@@ -0,0 +1,329 @@
+use fuser::FileAttr;
+use std::collections::HashMap;
+use std::time::Instant;
+
+use super::stats_file::STATS_INO;
+use super::INode;
+
+// Both directions of the inode mapping behind one lock, so they can never
+// disagree. Every change goes through the methods below, which keep
+// `by_path` pointing at exactly the inodes in `inodes`, save deleted files
+// still open, which are only in `inodes`.
+pub struct InodeTable {
+    inodes: HashMap<u64, INode>,
+    by_path: HashMap<String, u64>,
+}
+
+// FNV-1a, fixed so numbers stay the same across builds and mounts
+fn path_hash(path: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in path.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+impl InodeTable {
+    pub fn new(root: INode) -> Self {
+        let mut table = Self {
+            inodes: HashMap::new(),
+            by_path: HashMap::new(),
+        };
+        table.by_path.insert(root.path.clone(), root.ino);
+        table.inodes.insert(root.ino, root);
+        table
+    }
+
+    pub fn len(&self) -> usize {
+        self.inodes.len()
+    }
+
+    pub fn get(&self, ino: u64) -> Option<&INode> {
+        self.inodes.get(&ino)
+    }
+
+    pub fn get_mut(&mut self, ino: u64) -> Option<&mut INode> {
+        self.inodes.get_mut(&ino)
+    }
+
+    pub fn resolve_path(&self, path: &str) -> Option<u64> {
+        self.by_path.get(path).copied()
+    }
+
+    pub fn get_path(&self, path: &str) -> Option<&INode> {
+        self.inodes.get(self.by_path.get(path)?)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &INode> {
+        self.inodes.values()
+    }
+
+    // Adds a path not in the table yet. Its number is the server's id when
+    // there is one and otherwise derived from the path, so the same tree gets
+    // the same numbers on every mount.
+    pub fn insert(
+        &mut self,
+        path: &str,
+        id: Option<u64>,
+        attr: impl FnOnce(u64) -> FileAttr,
+    ) -> u64 {
+        let ino = self.allocate(path, id);
+
+        let inode = INode {
+            ino,
+            path: path.to_string(),
+            attr: attr(ino),
+            fetched_at: Instant::now(),
+            version: None,
+            remote_changes: 0,
+            expects_attrs: false,
+            touched: false,
+            creator: None,
+            deleted: false,
+            lookups: 0,
+            used_at: Instant::now(),
+        };
+        self.inodes.insert(ino, inode);
+        self.by_path.insert(path.to_string(), ino);
+        ino
+    }
+
+    // A number taken by another path, as well as 0 and the root's, moves on
+    // to the next free one. Only colliding paths can then change numbers
+    // between mounts, depending on which of them was seen first.
+    fn allocate(&mut self, path: &str, id: Option<u64>) -> u64 {
+        let mut ino = id.unwrap_or_else(|| path_hash(path));
+        // The stats file's number stays free even when it is not shown
+        while ino <= STATS_INO || self.inodes.contains_key(&ino) {
+            if ino > STATS_INO {
+                log::debug!("Inode number {} of {} is taken, probing", ino, path);
+            }
+            ino = ino.wrapping_add(1);
+        }
+        ino
+    }
+
+    // Counts a reference handed to the kernel with an entry reply
+    pub fn looked_up(&mut self, ino: u64) -> Option<&INode> {
+        let inode = self.inodes.get_mut(&ino)?;
+        inode.lookups += 1;
+        inode.used_at = Instant::now();
+        Some(inode)
+    }
+
+    // Drops references the kernel forgot, true once none are left
+    pub fn forget(&mut self, ino: u64, nlookup: u64) -> bool {
+        match self.inodes.get_mut(&ino) {
+            Some(inode) => {
+                inode.lookups = inode.lookups.saturating_sub(nlookup);
+                inode.lookups == 0
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, path: &str) -> Option<INode> {
+        let ino = self.by_path.remove(path)?;
+        self.inodes.remove(&ino)
+    }
+
+    // Removes `path` and everything below it
+    pub fn remove_subtree(&mut self, path: &str) -> Vec<INode> {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        let removed: Vec<String> = self
+            .by_path
+            .keys()
+            .filter(|known| *known == path || known.starts_with(&prefix))
+            .cloned()
+            .collect();
+        removed.iter().filter_map(|known| self.remove(known)).collect()
+    }
+
+    pub fn remove_ino(&mut self, ino: u64) -> Option<INode> {
+        let inode = self.inodes.remove(&ino)?;
+        // A deleted file's path may have been taken again since
+        if self.by_path.get(&inode.path) == Some(&ino) {
+            self.by_path.remove(&inode.path);
+        }
+        Some(inode)
+    }
+
+    // Keeps a removed inode reachable by number only, for a file deleted
+    // while open. Its path is free for whatever is created there next.
+    pub fn keep_deleted(&mut self, inode: INode) {
+        self.inodes.insert(inode.ino, inode);
+    }
+
+    // Moves `from` and everything below it to `to`, keeping their inode
+    // numbers. Whatever was at `to` is replaced and returned.
+    pub fn rename(&mut self, from: &str, to: &str) -> Option<INode> {
+        if from == to {
+            return None;
+        }
+        let replaced = self.remove(to);
+
+        let prefix = format!("{}/", from.trim_end_matches('/'));
+        let moved: Vec<String> = self
+            .by_path
+            .keys()
+            .filter(|path| *path == from || path.starts_with(&prefix))
+            .cloned()
+            .collect();
+
+        for old in moved {
+            let new = format!("{}{}", to, &old[from.len()..]);
+            if let Some(ino) = self.by_path.remove(&old) {
+                self.by_path.insert(new.clone(), ino);
+                if let Some(inode) = self.inodes.get_mut(&ino) {
+                    inode.path = new;
+                }
+            }
+        }
+
+        replaced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fuser::FileType;
+    use std::time::UNIX_EPOCH;
+
+    fn attr(ino: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+            blksize: 4096,
+        }
+    }
+
+    // A table holding the root and the given paths, in order
+    fn table(paths: &[&str]) -> InodeTable {
+        let root = INode {
+            ino: 1,
+            path: "/".to_string(),
+            attr: attr(1),
+            fetched_at: Instant::now(),
+            version: None,
+            remote_changes: 0,
+            expects_attrs: false,
+            touched: false,
+            creator: None,
+            deleted: false,
+            lookups: 0,
+            used_at: Instant::now(),
+        };
+        let mut table = InodeTable::new(root);
+        for path in paths {
+            table.insert(path, None, attr);
+        }
+        table
+    }
+
+    // Every path leads to an inode that has it, and the other way round
+    fn assert_consistent(table: &InodeTable) {
+        for (path, ino) in &table.by_path {
+            assert_eq!(&table.inodes[ino].path, path);
+        }
+        let deleted = table.values().filter(|inode| inode.deleted).count();
+        assert_eq!(table.by_path.len() + deleted, table.len());
+    }
+
+    #[test]
+    fn paths_and_numbers_resolve_both_ways() {
+        let table = table(&["/a", "/a/b"]);
+        let ino = table.resolve_path("/a/b").unwrap();
+        assert_eq!(table.get(ino).unwrap().path, "/a/b");
+        assert_eq!(table.get(ino).unwrap().attr.ino, ino);
+        assert_eq!(table.get_path("/a/b").unwrap().ino, ino);
+        assert_eq!(table.resolve_path("/"), Some(1));
+        assert!(table.resolve_path("/c").is_none());
+        assert_consistent(&table);
+    }
+
+    #[test]
+    fn subtrees_are_removed_and_renamed_whole() {
+        let mut table = table(&["/a", "/a/b", "/a/b/c", "/ab", "/d"]);
+        let c = table.resolve_path("/a/b/c").unwrap();
+        let d = table.resolve_path("/d").unwrap();
+
+        // "/d" is replaced, "/ab" only shares a prefix
+        assert_eq!(table.rename("/a", "/d").unwrap().ino, d);
+        assert_eq!(table.resolve_path("/d/b/c"), Some(c));
+        assert_eq!(table.get(c).unwrap().path, "/d/b/c");
+        assert!(table.resolve_path("/a").is_none());
+        assert!(table.resolve_path("/ab").is_some());
+        assert_consistent(&table);
+
+        let removed = table.remove_subtree("/d");
+        assert_eq!(removed.len(), 3);
+        assert_eq!(table.len(), 2);
+        assert_consistent(&table);
+    }
+
+    #[test]
+    fn numbers_are_the_same_on_every_mount() {
+        let first = table(&["/a", "/a/b"]);
+        let second = table(&["/a/b", "/a"]);
+        for path in ["/a", "/a/b"] {
+            assert_eq!(first.resolve_path(path), second.resolve_path(path));
+        }
+        assert_eq!(first.resolve_path("/a"), Some(path_hash("/a")));
+    }
+
+    #[test]
+    fn server_ids_win_and_taken_numbers_probe() {
+        let mut table = table(&[]);
+        assert_eq!(table.insert("/a", Some(77), attr), 77);
+        assert_eq!(table.insert("/b", Some(77), attr), 78);
+        // Reserved for the root and the stats file
+        assert_eq!(table.insert("/c", Some(0), attr), STATS_INO + 1);
+        assert_eq!(table.insert("/d", Some(1), attr), STATS_INO + 2);
+        assert_eq!(table.insert("/e", Some(u64::MAX), attr), u64::MAX);
+        assert_eq!(table.insert("/f", Some(u64::MAX), attr), STATS_INO + 3);
+    }
+
+    #[test]
+    fn references_are_counted_until_forgotten() {
+        let mut table = table(&["/a"]);
+        let ino = table.resolve_path("/a").unwrap();
+        table.looked_up(ino);
+        table.looked_up(ino);
+        assert!(!table.forget(ino, 1));
+        assert!(table.forget(ino, 5));
+        assert_eq!(table.get(ino).unwrap().lookups, 0);
+        assert!(table.looked_up(ino + 1).is_none());
+        assert!(!table.forget(ino + 1, 1));
+    }
+
+    #[test]
+    fn deleted_inodes_stay_reachable_by_number_only() {
+        let mut table = table(&["/a"]);
+        let mut deleted = table.remove("/a").unwrap();
+        let ino = deleted.ino;
+        deleted.deleted = true;
+        table.keep_deleted(deleted);
+        // Created again in its place
+        let recreated = table.insert("/a", Some(ino + 1000), attr);
+        assert_consistent(&table);
+
+        assert!(table.get(ino).is_some());
+        table.remove_ino(ino);
+        assert_eq!(table.resolve_path("/a"), Some(recreated));
+        assert_consistent(&table);
+    }
+}
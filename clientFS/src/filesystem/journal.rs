@@ -0,0 +1,567 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use super::disk_cache::{fnv1a, read_u32, read_u64};
+use super::{split_path, FsError, RemoteFS, WriteBuffer};
+
+const MAGIC: &[u8; 4] = b"RFSJ";
+const WRITE: u8 = 1;
+const TRUNCATE: u8 = 2;
+// Lists the records whose data reached the server
+const DONE: u8 = 3;
+// Magic, kind, sequence number and payload length
+const HEADER_LEN: usize = 4 + 1 + 8 + 4;
+const CHECKSUM_LEN: usize = 8;
+// A journal smaller than this is left to grow until nothing is pending
+const COMPACT_MIN: u64 = 16 << 20;
+
+// A buffered change to a file, journaled until it is uploaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Write {
+        path: String,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    Truncate {
+        path: String,
+        size: u64,
+    },
+}
+
+impl Entry {
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Write { path, .. } | Self::Truncate { path, .. } => path,
+        }
+    }
+}
+
+enum Record {
+    Entry(Entry),
+    Done(Vec<u64>),
+}
+
+enum Bad {
+    // Runs past the end of the journal, as a record cut short by a crash does
+    Torn,
+    Corrupt,
+}
+
+fn encode(kind: u8, seq: u64, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len() + CHECKSUM_LEN);
+    record.extend_from_slice(MAGIC);
+    record.push(kind);
+    record.extend_from_slice(&seq.to_le_bytes());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(payload);
+    let checksum = fnv1a(&record);
+    record.extend_from_slice(&checksum.to_le_bytes());
+    record
+}
+
+fn encode_entry(seq: u64, entry: &Entry) -> Vec<u8> {
+    match entry {
+        Entry::Write { path, offset, data } => {
+            encode(WRITE, seq, &path_payload(path, *offset, data))
+        }
+        Entry::Truncate { path, size } => encode(TRUNCATE, seq, &path_payload(path, *size, &[])),
+    }
+}
+
+fn path_payload(path: &str, value: u64, data: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(4 + path.len() + 8 + data.len());
+    payload.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    payload.extend_from_slice(path.as_bytes());
+    payload.extend_from_slice(&value.to_le_bytes());
+    payload.extend_from_slice(data);
+    payload
+}
+
+fn decode_payload(kind: u8, payload: &[u8]) -> Option<Record> {
+    if kind == DONE {
+        let seqs = payload
+            .chunks(8)
+            .map(|seq| Some(u64::from_le_bytes(seq.try_into().ok()?)))
+            .collect::<Option<_>>()?;
+        return Some(Record::Done(seqs));
+    }
+
+    let mut at = 0;
+    let path_len = read_u32(payload, &mut at)? as usize;
+    let path = std::str::from_utf8(payload.get(at..at + path_len)?)
+        .ok()?
+        .to_string();
+    at += path_len;
+    let value = read_u64(payload, &mut at)?;
+    match kind {
+        WRITE => Some(Record::Entry(Entry::Write {
+            path,
+            offset: value,
+            data: payload[at..].to_vec(),
+        })),
+        TRUNCATE if at == payload.len() => {
+            Some(Record::Entry(Entry::Truncate { path, size: value }))
+        }
+        _ => None,
+    }
+}
+
+// Decodes the record at the start of `bytes`, returning its sequence number
+// and length
+fn decode(bytes: &[u8]) -> Result<(u64, Record, usize), Bad> {
+    if bytes.len() < HEADER_LEN {
+        return Err(Bad::Torn);
+    }
+    if &bytes[..4] != MAGIC {
+        return Err(Bad::Corrupt);
+    }
+    let kind = bytes[4];
+    let mut at = 5;
+    let seq = read_u64(bytes, &mut at).ok_or(Bad::Corrupt)?;
+    let payload_len = read_u32(bytes, &mut at).ok_or(Bad::Corrupt)? as usize;
+    let len = HEADER_LEN + payload_len + CHECKSUM_LEN;
+    if bytes.len() < len {
+        return Err(Bad::Torn);
+    }
+
+    let mut at = len - CHECKSUM_LEN;
+    let checksum = read_u64(bytes, &mut at).ok_or(Bad::Corrupt)?;
+    if fnv1a(&bytes[..len - CHECKSUM_LEN]) != checksum {
+        return Err(Bad::Corrupt);
+    }
+    let record =
+        decode_payload(kind, &bytes[HEADER_LEN..len - CHECKSUM_LEN]).ok_or(Bad::Corrupt)?;
+    Ok((seq, record, len))
+}
+
+fn next_magic(bytes: &[u8], from: usize) -> Option<usize> {
+    bytes
+        .get(from..)?
+        .windows(MAGIC.len())
+        .position(|window| window == MAGIC)
+        .map(|at| from + at)
+}
+
+// Every record that decodes, in order, and how many corrupt ones were
+// skipped. A torn record at the end is dropped quietly: it was never
+// acknowledged.
+fn scan(bytes: &[u8]) -> (Vec<(u64, Record)>, u64) {
+    let mut records = Vec::new();
+    let mut corrupt = 0;
+    let mut at = 0;
+    while at < bytes.len() {
+        match decode(&bytes[at..]) {
+            Ok((seq, record, len)) => {
+                records.push((seq, record));
+                at += len;
+            }
+            Err(bad) => {
+                let next = next_magic(bytes, at + 1);
+                if matches!(bad, Bad::Torn) && next.is_none() {
+                    log::debug!("Dropping a journal record cut short at offset {}", at);
+                    break;
+                }
+                log::warn!("Skipping a corrupt journal record at offset {}", at);
+                corrupt += 1;
+                at = next.unwrap_or(bytes.len());
+            }
+        }
+    }
+    (records, corrupt)
+}
+
+struct JournalState {
+    file: File,
+    len: u64,
+    next_seq: u64,
+    // Records whose data has not reached the server, with their length
+    pending: BTreeMap<u64, u64>,
+}
+
+// Write-ahead journal of the writes and truncations buffered on file
+// handles, kept in <cache_dir>/journal with `journal`. A change is appended
+// before it is acknowledged and marked done once an upload carried it, so a
+// mount that crashes in between can upload it on the next start. Records are
+// not synced one by one: they outlive a crash of the process, and like any
+// write they are only durable after an fsync, which uploads them.
+pub struct Journal {
+    file: PathBuf,
+    state: Mutex<JournalState>,
+    // Left pending by an earlier mount, until replay_journal takes them
+    recovered: Mutex<Vec<(u64, Entry)>>,
+    // Records skipped because their checksum did not match
+    corrupt: u64,
+}
+
+impl Journal {
+    pub fn open(cache_dir: &Path) -> io::Result<Self> {
+        fs::DirBuilder::new().recursive(true).create(cache_dir)?;
+        let file = cache_dir.join("journal");
+        let bytes = match fs::read(&file) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let (records, corrupt) = scan(&bytes);
+        let mut next_seq = 1;
+        let mut left = BTreeMap::new();
+        for (seq, record) in records {
+            next_seq = next_seq.max(seq + 1);
+            match record {
+                Record::Entry(entry) => {
+                    left.insert(seq, entry);
+                }
+                Record::Done(seqs) => {
+                    for seq in seqs {
+                        left.remove(&seq);
+                    }
+                }
+            }
+        }
+
+        // Only what is still pending is kept, without the corrupt records
+        let mut pending = BTreeMap::new();
+        let mut contents = Vec::new();
+        for (&seq, entry) in &left {
+            let record = encode_entry(seq, entry);
+            pending.insert(seq, record.len() as u64);
+            contents.extend_from_slice(&record);
+        }
+        let handle = rewrite(&file, &contents)?;
+
+        Ok(Self {
+            file,
+            state: Mutex::new(JournalState {
+                file: handle,
+                len: contents.len() as u64,
+                next_seq,
+                pending,
+            }),
+            recovered: Mutex::new(left.into_iter().collect()),
+            corrupt,
+        })
+    }
+
+    pub fn corrupt(&self) -> u64 {
+        self.corrupt
+    }
+
+    pub fn take_recovered(&self) -> Vec<(u64, Entry)> {
+        std::mem::take(&mut *self.recovered.lock().unwrap())
+    }
+
+    // Appends a write, returning the sequence number to mark done later
+    pub fn write(&self, path: &str, offset: u64, data: &[u8]) -> io::Result<u64> {
+        self.append(WRITE, &path_payload(path, offset, data))
+    }
+
+    pub fn truncate(&self, path: &str, size: u64) -> io::Result<u64> {
+        self.append(TRUNCATE, &path_payload(path, size, &[]))
+    }
+
+    fn append(&self, kind: u8, payload: &[u8]) -> io::Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.next_seq;
+        let record = encode(kind, seq, payload);
+        if let Err(e) = state.file.write_all(&record) {
+            // Drops whatever part of the record made it
+            let len = state.len;
+            let _ = state.file.set_len(len);
+            return Err(e);
+        }
+        state.next_seq += 1;
+        state.len += record.len() as u64;
+        state.pending.insert(seq, record.len() as u64);
+        Ok(seq)
+    }
+
+    // Marks records done once the server has their data. The journal is
+    // emptied whenever nothing is left pending, and compacted when mostly
+    // made of records already done.
+    pub fn complete(&self, seqs: &[u64]) {
+        let mut state = self.state.lock().unwrap();
+        let done: Vec<u64> = seqs
+            .iter()
+            .copied()
+            .filter(|seq| state.pending.remove(seq).is_some())
+            .collect();
+        if done.is_empty() {
+            return;
+        }
+
+        if state.pending.is_empty() {
+            match state.file.set_len(0) {
+                Ok(()) => state.len = 0,
+                Err(e) => log::warn!("Failed to truncate {}: {}", self.file.display(), e),
+            }
+            return;
+        }
+
+        let payload: Vec<u8> = done.iter().flat_map(|seq| seq.to_le_bytes()).collect();
+        let seq = state.next_seq;
+        let record = encode(DONE, seq, &payload);
+        match state.file.write_all(&record) {
+            Ok(()) => {
+                state.next_seq += 1;
+                state.len += record.len() as u64;
+            }
+            // The records are replayed once more after a crash, which
+            // rewrites the same data
+            Err(e) => log::warn!("Failed to append to {}: {}", self.file.display(), e),
+        }
+
+        let live: u64 = state.pending.values().sum();
+        if state.len >= COMPACT_MIN && live * 2 < state.len {
+            if let Err(e) = self.compact(&mut state) {
+                log::warn!("Failed to compact {}: {}", self.file.display(), e);
+            }
+        }
+    }
+
+    // Rewrites the journal with only the pending records
+    fn compact(&self, state: &mut JournalState) -> io::Result<()> {
+        let bytes = fs::read(&self.file)?;
+        let mut contents = Vec::new();
+        for (seq, record) in scan(&bytes).0 {
+            if let Record::Entry(entry) = record {
+                if state.pending.contains_key(&seq) {
+                    contents.extend_from_slice(&encode_entry(seq, &entry));
+                }
+            }
+        }
+        state.file = rewrite(&self.file, &contents)?;
+        state.len = contents.len() as u64;
+        Ok(())
+    }
+}
+
+// Creates `file` with `contents` through a synced temporary file and opens it
+// for appending
+fn rewrite(file: &Path, contents: &[u8]) -> io::Result<File> {
+    let tmp = file.with_extension("tmp");
+    let result = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut f| {
+            f.write_all(contents)?;
+            f.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, file));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    OpenOptions::new().append(true).open(file)
+}
+
+impl RemoteFS {
+    // Uploads what an earlier mount journaled but never got to the server,
+    // file by file with the changes in the order they were made. Files that
+    // fail stay in the journal for the next mount.
+    pub(super) fn replay_journal(&self) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+        let mut files: Vec<(String, Vec<(u64, Entry)>)> = Vec::new();
+        for (seq, entry) in journal.take_recovered() {
+            match files.iter_mut().find(|(path, _)| path == entry.path()) {
+                Some((_, entries)) => entries.push((seq, entry)),
+                None => files.push((entry.path().to_string(), vec![(seq, entry)])),
+            }
+        }
+        if files.is_empty() {
+            return;
+        }
+        log::info!("Replaying journaled writes to {} files", files.len());
+
+        for (path, entries) in files {
+            let seqs: Vec<u64> = entries.iter().map(|&(seq, _)| seq).collect();
+            // Made offline on top of a queued change, replayed next time
+            let queued = self
+                .change_queue
+                .as_ref()
+                .is_some_and(|queue| queue.has_path(&path));
+            if queued {
+                log::warn!(
+                    "Keeping {} journaled writes to {} until its queued changes are replayed",
+                    seqs.len(),
+                    path
+                );
+                continue;
+            }
+
+            match self.replay_file(&path, entries) {
+                Ok(true) => {
+                    log::info!("Recovered {} journaled writes to {}", seqs.len(), path);
+                    self.stats
+                        .journal_replayed
+                        .fetch_add(seqs.len() as u64, Ordering::Relaxed);
+                    journal.complete(&seqs);
+                }
+                Ok(false) => {
+                    log::warn!(
+                        "Dropping {} journaled writes to {}, it no longer exists",
+                        seqs.len(),
+                        path
+                    );
+                    journal.complete(&seqs);
+                }
+                Err(e) => log::error!(
+                    "Failed to replay {} journaled writes to {}, keeping them: {:#}",
+                    seqs.len(),
+                    path,
+                    e
+                ),
+            }
+        }
+    }
+
+    // Applies the changes on top of the server's copy like a flush would.
+    // False when the file is gone.
+    fn replay_file(&self, path: &str, entries: Vec<(u64, Entry)>) -> Result<bool> {
+        let (parent, name) = split_path(path);
+        let listing = match self.backend.list_directory(parent) {
+            Ok(listing) => listing,
+            Err(e) if FsError::from_backend(&e) == FsError::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let Some(remote) = listing
+            .entries
+            .iter()
+            .find(|entry| entry.name == name && !entry.is_dir)
+        else {
+            return Ok(false);
+        };
+
+        let mut buffer = WriteBuffer::default();
+        for (_, entry) in entries {
+            match entry {
+                Entry::Write { offset, data, .. } => buffer.write(offset, &data),
+                Entry::Truncate { size, .. } => buffer.truncate(size),
+            }
+        }
+        self.upload_buffer(path, remote.size, &buffer, None)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &str, offset: u64, data: &[u8]) -> Entry {
+        Entry::Write {
+            path: path.to_string(),
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
+    fn reopen(dir: &Path) -> Journal {
+        Journal::open(dir).unwrap()
+    }
+
+    #[test]
+    fn pending_records_are_recovered_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = reopen(dir.path());
+        let first = journal.write("/a", 0, b"hello").unwrap();
+        let second = journal.truncate("/a", 3).unwrap();
+        let third = journal.write("/b", 7, b"x").unwrap();
+        journal.complete(&[second]);
+        drop(journal);
+
+        let journal = reopen(dir.path());
+        assert_eq!(journal.corrupt(), 0);
+        assert_eq!(
+            journal.take_recovered(),
+            [(first, write("/a", 0, b"hello")), (third, write("/b", 7, b"x"))]
+        );
+        // Sequence numbers go on from the last one
+        assert!(journal.write("/c", 0, b"").unwrap() > third);
+    }
+
+    #[test]
+    fn nothing_is_recovered_once_all_is_done() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = reopen(dir.path());
+        let seqs = [
+            journal.write("/a", 0, b"hello").unwrap(),
+            journal.write("/a", 5, b"!").unwrap(),
+        ];
+        journal.complete(&seqs);
+        assert_eq!(fs::metadata(dir.path().join("journal")).unwrap().len(), 0);
+        drop(journal);
+
+        assert!(reopen(dir.path()).take_recovered().is_empty());
+    }
+
+    #[test]
+    fn records_failing_their_checksum_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = reopen(dir.path());
+        journal.write("/a", 0, b"first").unwrap();
+        let second = journal.write("/b", 0, b"second").unwrap();
+        drop(journal);
+
+        // A bit flipped in the data of the first record
+        let file = dir.path().join("journal");
+        let mut bytes = fs::read(&file).unwrap();
+        let at = bytes.windows(5).position(|window| window == b"first").unwrap();
+        bytes[at] ^= 1;
+        fs::write(&file, &bytes).unwrap();
+
+        let journal = reopen(dir.path());
+        assert_eq!(journal.corrupt(), 1);
+        assert_eq!(journal.take_recovered(), [(second, write("/b", 0, b"second"))]);
+    }
+
+    #[test]
+    fn torn_last_record_is_dropped_quietly() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = reopen(dir.path());
+        let first = journal.write("/a", 0, b"kept").unwrap();
+        journal.write("/a", 4, b"cut short").unwrap();
+        drop(journal);
+
+        let file = dir.path().join("journal");
+        let bytes = fs::read(&file).unwrap();
+        fs::write(&file, &bytes[..bytes.len() - 3]).unwrap();
+
+        let journal = reopen(dir.path());
+        assert_eq!(journal.corrupt(), 0);
+        assert_eq!(journal.take_recovered(), [(first, write("/a", 0, b"kept"))]);
+    }
+
+    #[test]
+    fn records_decode_as_encoded() {
+        let entry = Entry::Truncate {
+            path: "/dir/file".to_string(),
+            size: 42,
+        };
+        let record = encode_entry(9, &entry);
+        match decode(&record) {
+            Ok((9, Record::Entry(decoded), len)) => {
+                assert_eq!(decoded, entry);
+                assert_eq!(len, record.len());
+            }
+            _ => panic!("record did not decode"),
+        }
+        let mut damaged = record.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xff;
+        assert!(matches!(decode(&damaged), Err(Bad::Corrupt)));
+        assert!(matches!(decode(&record[..record.len() - 1]), Err(Bad::Torn)));
+    }
+}
@@ -0,0 +1,340 @@
+use anyhow::Result;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{FsError, Op, RemoteFS, SIGNAL_POLL_INTERVAL};
+use crate::api_client::ServerError;
+
+// How often the server is asked whether it takes writes again
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Whether `error` says the server takes no writes for now: a 503, or any
+// status with X-Maintenance: true
+pub(super) fn is_maintenance(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<ServerError>()
+        .is_some_and(ServerError::is_maintenance)
+}
+
+// Operations that change something on the server
+pub(super) fn mutates(op: Op) -> bool {
+    matches!(
+        op,
+        Op::Setattr
+            | Op::Write
+            | Op::Flush
+            | Op::Fsync
+            | Op::Release
+            | Op::Mkdir
+            | Op::Unlink
+            | Op::Rmdir
+            | Op::Rename
+            | Op::Create
+    )
+}
+
+impl RemoteFS {
+    pub(super) fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    // Makes the mount read-only if `error` announces maintenance, returning
+    // whether it did. Reads keep going to the server meanwhile.
+    pub(super) fn note_maintenance(&self, error: &anyhow::Error) -> bool {
+        if !is_maintenance(error) {
+            return false;
+        }
+        if self.maintenance.swap(true, Ordering::Relaxed) {
+            return true;
+        }
+        self.stats
+            .maintenance_entered
+            .fetch_add(1, Ordering::Relaxed);
+        match &self.config().label {
+            Some(label) => log::warn!(
+                "[{}] Server under maintenance, read-only until it takes writes: {:#}",
+                label,
+                error
+            ),
+            None => log::warn!(
+                "Server under maintenance, read-only until it takes writes: {:#}",
+                error
+            ),
+        }
+        self.spawn_maintenance_prober();
+        true
+    }
+
+    // Uploads a handle for flush, fsync or release. Under maintenance the
+    // server is not asked; the buffer of a flush or release is held for
+    // later instead, as long as everything held fits in maintenance_hold.
+    // fsync promises the data reached the server, so it fails with EROFS.
+    pub(super) fn flush_or_hold(&self, fh: u64, op: Op) -> Result<()> {
+        let dirty = self
+            .file_handles
+            .lock()
+            .unwrap()
+            .get(&fh)
+            .is_some_and(|handle| !handle.buffer.is_empty());
+        let error = if self.in_maintenance() && dirty {
+            anyhow::Error::new(FsError::ReadOnly).context("Server under maintenance")
+        } else {
+            match self.flush_handle(fh) {
+                Ok(()) => return Ok(()),
+                Err(e) if self.note_maintenance(&e) => e,
+                Err(e) => return Err(e),
+            }
+        };
+        if matches!(op, Op::Fsync) || !self.hold(fh, matches!(op, Op::Release)) {
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    // Marks the buffer of `fh` held for the prober, unless it does not fit
+    // in maintenance_hold next to the others
+    fn hold(&self, fh: u64, release: bool) -> bool {
+        let budget = self.config().maintenance_hold;
+        let Some(ino) = self.file_handles.lock().unwrap().get(&fh).map(|h| h.ino) else {
+            return false;
+        };
+        let path = self.path_of(ino);
+
+        let mut file_handles = self.file_handles.lock().unwrap();
+        let held: u64 = file_handles
+            .iter()
+            .filter(|&(&other, handle)| other != fh && handle.held)
+            .map(|(_, handle)| handle.buffer.dirty_bytes() as u64)
+            .sum();
+        let Some(handle) = file_handles.get_mut(&fh) else {
+            return false;
+        };
+        let dirty = handle.buffer.dirty_bytes() as u64;
+        if held + dirty > budget {
+            let what = if release { "Dropped" } else { "Cannot hold" };
+            log::warn!(
+                "{} {} bytes written to {}, maintenance_hold is used up",
+                what,
+                dirty,
+                path
+            );
+            return false;
+        }
+
+        log::debug!(
+            "Holding {} bytes written to {} until maintenance ends",
+            dirty,
+            path
+        );
+        handle.held = true;
+        handle.flush_error = None;
+        if release {
+            handle.released = true;
+            handle.readahead.cancel();
+        }
+        true
+    }
+
+    // Asks the server every PROBE_INTERVAL whether it takes writes again,
+    // by uploading what is held, or with a health check when nothing is.
+    // Stops once the mount leaves maintenance or is unmounted.
+    fn spawn_maintenance_prober(&self) {
+        let fs = self.clone();
+        thread::spawn(move || {
+            let mut last_probe = Instant::now();
+            while !fs.shutdown.load(Ordering::Relaxed) {
+                thread::sleep(SIGNAL_POLL_INTERVAL);
+                if last_probe.elapsed() < PROBE_INTERVAL {
+                    continue;
+                }
+                last_probe = Instant::now();
+
+                let held = fs.held_handles();
+                let result = if held.is_empty() {
+                    fs.backend.check_writable(PROBE_TIMEOUT)
+                } else {
+                    fs.upload_held(held)
+                };
+                match result {
+                    Ok(()) => {
+                        fs.leave_maintenance();
+                        return;
+                    }
+                    Err(e) => log::debug!("Server still under maintenance: {:#}", e),
+                }
+            }
+        });
+    }
+
+    fn held_handles(&self) -> Vec<u64> {
+        self.file_handles
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, handle)| handle.held && !handle.buffer.is_empty())
+            .map(|(&fh, _)| fh)
+            .collect()
+    }
+
+    // Uploads held buffers until the server announces maintenance again.
+    // One failing for another reason is given up: its error is left for the
+    // application if the handle is still open, and logged otherwise.
+    fn upload_held(&self, held: Vec<u64>) -> Result<()> {
+        for fh in held {
+            match self.flush_handle(fh) {
+                Ok(()) => {}
+                Err(e) if is_maintenance(&e) => {
+                    if let Some(handle) = self.file_handles.lock().unwrap().get_mut(&fh) {
+                        handle.flush_error = None;
+                    }
+                    return Err(e);
+                }
+                Err(e) => {
+                    let ino = self.file_handles.lock().unwrap().get(&fh).map(|h| h.ino);
+                    let path =
+                        ino.map_or_else(|| format!("handle {}", fh), |ino| self.path_of(ino));
+                    log::error!("Failed to upload writes held for {}: {:#}", path, e);
+                }
+            }
+
+            let mut file_handles = self.file_handles.lock().unwrap();
+            match file_handles.get(&fh).map(|handle| handle.released) {
+                Some(true) => {
+                    file_handles.remove(&fh);
+                }
+                Some(false) => {
+                    if let Some(handle) = file_handles.get_mut(&fh) {
+                        handle.held = false;
+                    }
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Makes the mount writable again. Buffers held while the prober was
+    // uploading the others are uploaded right away.
+    fn leave_maintenance(&self) {
+        self.maintenance.store(false, Ordering::Relaxed);
+        self.stats
+            .maintenance_exited
+            .fetch_add(1, Ordering::Relaxed);
+        match &self.config().label {
+            Some(label) => log::info!("[{}] Server takes writes again", label),
+            None => log::info!("Server takes writes again"),
+        }
+
+        let late = self.held_handles();
+        if late.is_empty() {
+            return;
+        }
+        if let Err(e) = self.upload_held(late) {
+            self.note_maintenance(&e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::FsConfig;
+    use crate::testing::MockBackend;
+    use reqwest::StatusCode;
+    use std::sync::Arc;
+
+    fn server_error(status: u16, maintenance: bool) -> anyhow::Error {
+        anyhow::Error::new(ServerError {
+            status: StatusCode::from_u16(status).unwrap(),
+            maintenance,
+        })
+    }
+
+    // A mount under maintenance with `count` files open, each with "new"
+    // buffered over its start
+    fn under_maintenance(count: usize, hold: u64) -> (Arc<MockBackend>, RemoteFS, Vec<u64>) {
+        let mock = Arc::new(MockBackend::new());
+        for i in 0..count {
+            mock.add_file(&format!("/f{}", i), b"old");
+        }
+        let config = FsConfig {
+            maintenance_hold: hold,
+            ..FsConfig::default()
+        };
+        let fs = RemoteFS::with_backend(mock.clone(), config);
+        let listing = fs.list_directory("/").unwrap();
+        let handles = listing
+            .iter()
+            .map(|entry| {
+                let ino = fs.get_or_create_inode(&format!("/{}", entry.name), entry);
+                let fh = fs.open_handle(ino, 3, None);
+                fs.file_handles.lock().unwrap().get_mut(&fh).unwrap().buffer.write(0, b"new");
+                fh
+            })
+            .collect();
+        assert!(fs.note_maintenance(&server_error(503, false)));
+        mock.take_calls();
+        (mock, fs, handles)
+    }
+
+    #[test]
+    fn maintenance_is_a_503_or_announced() {
+        assert!(is_maintenance(&server_error(503, false)));
+        assert!(is_maintenance(&server_error(500, true)));
+        assert!(is_maintenance(&server_error(200, true).context("write /a")));
+        assert!(!is_maintenance(&server_error(500, false)));
+        assert!(!is_maintenance(&anyhow::Error::new(FsError::Busy)));
+
+        assert!(mutates(Op::Write) && mutates(Op::Rename) && mutates(Op::Release));
+        assert!(!mutates(Op::Read) && !mutates(Op::Lookup) && !mutates(Op::Readdir));
+    }
+
+    #[test]
+    fn the_mount_turns_read_only_once() {
+        let (_mock, fs, _) = under_maintenance(0, 0);
+        assert!(fs.in_maintenance());
+        assert!(fs.note_maintenance(&server_error(503, false)));
+        assert!(!fs.note_maintenance(&server_error(500, false)));
+        assert_eq!(fs.stats.maintenance_entered.load(Ordering::Relaxed), 1);
+        assert!(fs.refuse_mutation(Op::Mkdir));
+    }
+
+    #[test]
+    fn writes_are_held_within_the_budget() {
+        let (mock, fs, handles) = under_maintenance(3, 6);
+        fs.flush_or_hold(handles[0], Op::Flush).unwrap();
+        fs.flush_or_hold(handles[1], Op::Release).unwrap();
+        // Over maintenance_hold
+        let error = fs.flush_or_hold(handles[2], Op::Release).unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::ReadOnly);
+        assert!(mock.take_calls().is_empty());
+        assert_eq!(fs.held_handles().len(), 2);
+    }
+
+    #[test]
+    fn fsync_is_never_held() {
+        let (_mock, fs, handles) = under_maintenance(1, 1 << 20);
+        let error = fs.flush_or_hold(handles[0], Op::Fsync).unwrap_err();
+        assert_eq!(FsError::from_backend(&error).errno(), libc::EROFS);
+        assert!(fs.held_handles().is_empty());
+    }
+
+    #[test]
+    fn held_writes_are_uploaded_when_maintenance_ends() {
+        let (mock, fs, handles) = under_maintenance(2, 1 << 20);
+        fs.flush_or_hold(handles[0], Op::Flush).unwrap();
+        fs.flush_or_hold(handles[1], Op::Release).unwrap();
+
+        fs.leave_maintenance();
+        assert!(!fs.in_maintenance());
+        assert_eq!(mock.contents("/f0").unwrap(), b"new");
+        assert_eq!(mock.contents("/f1").unwrap(), b"new");
+        // The released handle is gone, the open one writable again
+        let file_handles = fs.file_handles.lock().unwrap();
+        assert!(!file_handles.contains_key(&handles[1]));
+        assert!(!file_handles[&handles[0]].held);
+        assert_eq!(fs.stats.maintenance_exited.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,484 @@
+use anyhow::{Context, Result};
+use std::fmt::{Display, Write as _};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use super::stats::LATENCY_BUCKETS;
+use super::{RemoteFS, SIGNAL_POLL_INTERVAL};
+use crate::api_client::BreakerState;
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Serves the statistics of the mount in Prometheus text format at /metrics
+// until the mount shuts down. Binding happens right away, so a taken
+// address fails the mount instead of going unnoticed.
+pub fn spawn(fs: &RemoteFS, addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("Failed to listen for metrics on {}", addr))?;
+    // Polled, so the listener notices the shutdown and releases the port
+    listener.set_nonblocking(true)?;
+    log::info!("Serving metrics on http://{}/metrics", addr);
+
+    let fs = fs.clone();
+    thread::spawn(move || {
+        while !fs.shutdown.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = serve(&fs, stream) {
+                        log::debug!("Metrics request failed: {}", e);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(SIGNAL_POLL_INTERVAL)
+                }
+                Err(e) => log::warn!("Failed to accept a metrics connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(fs: &RemoteFS, stream: TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers are of no interest but must be read before answering
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(fs)),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+// Prometheus exposition text, every sample labeled with the mount's label
+// when it has one
+struct Exposition {
+    out: String,
+    mount: Option<String>,
+}
+
+impl Exposition {
+    fn metric(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP remotefs_{} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE remotefs_{} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let mount = self.mount.as_deref().map(|mount| ("mount", mount));
+        let labels: Vec<String> = mount
+            .iter()
+            .chain(labels)
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+            .collect();
+        if labels.is_empty() {
+            let _ = writeln!(self.out, "remotefs_{} {}", name, value);
+        } else {
+            let _ = writeln!(
+                self.out,
+                "remotefs_{}{{{}}} {}",
+                name,
+                labels.join(","),
+                value
+            );
+        }
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: impl Display) {
+        self.metric(name, "gauge", help);
+        self.sample(name, &[], value);
+    }
+
+    fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.metric(name, "counter", help);
+        self.sample(name, &[], value);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub fn render(fs: &RemoteFS) -> String {
+    let stats = fs.stats_snapshot();
+    let usage = fs.cache_usage();
+    let mut out = Exposition {
+        out: String::new(),
+        mount: fs.config().label.clone(),
+    };
+
+    out.metric("ops_total", "counter", "FUSE operations received");
+    for (op, count) in &stats.calls {
+        out.sample("ops_total", &[("op", op)], count);
+    }
+    out.metric(
+        "op_errors_total",
+        "counter",
+        "FUSE operations failed against the server",
+    );
+    for (op, count) in &stats.errors {
+        out.sample("op_errors_total", &[("op", op)], count);
+    }
+    out.metric(
+        "op_duration_seconds",
+        "histogram",
+        "Time to serve FUSE operations",
+    );
+    for (op, latency) in &stats.latency {
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&latency.buckets) {
+            cumulative += count;
+            let bound = bound.to_string();
+            out.sample(
+                "op_duration_seconds_bucket",
+                &[("op", op), ("le", &bound)],
+                cumulative,
+            );
+        }
+        let labels = [("op", op.as_str()), ("le", "+Inf")];
+        out.sample("op_duration_seconds_bucket", &labels, latency.count);
+        out.sample(
+            "op_duration_seconds_sum",
+            &[("op", op)],
+            latency.sum_seconds,
+        );
+        out.sample("op_duration_seconds_count", &[("op", op)], latency.count);
+    }
+    out.gauge(
+        "ops_in_flight",
+        "FUSE operations being served",
+        stats.ops_in_flight,
+    );
+
+    out.metric(
+        "http_requests_total",
+        "counter",
+        "HTTP attempts by method and status class",
+    );
+    for (method, outcomes) in &stats.http.requests {
+        for (class, count) in outcomes {
+            let labels = [("method", method.as_str()), ("class", class.as_str())];
+            out.sample("http_requests_total", &labels, count);
+        }
+    }
+    out.metric(
+        "http_bytes_total",
+        "counter",
+        "HTTP body bytes by direction",
+    );
+    out.sample(
+        "http_bytes_total",
+        &[("direction", "in")],
+        stats.http.bytes_downloaded,
+    );
+    out.sample(
+        "http_bytes_total",
+        &[("direction", "out")],
+        stats.http.bytes_uploaded,
+    );
+    out.metric(
+        "http_responses_total",
+        "counter",
+        "HTTP responses by protocol version",
+    );
+    for (protocol, count) in &stats.http.protocols {
+        out.sample("http_responses_total", &[("protocol", protocol.as_str())], count);
+    }
+    out.counter(
+        "http_retries_total",
+        "HTTP attempts repeated",
+        stats.http.retries,
+    );
+    out.counter(
+        "http_errors_total",
+        "HTTP attempts failed in transport or with a 5xx",
+        stats.http.errors,
+    );
+    if let Some(capabilities) = &stats.http.capabilities {
+        out.metric(
+            "server_capability",
+            "gauge",
+            "Optional server features, 1 when the client uses them",
+        );
+        for (feature, supported) in capabilities.features() {
+            out.sample("server_capability", &[("feature", feature)], u8::from(supported));
+        }
+    }
+    out.gauge(
+        "backend_healthy",
+        "Whether the last request to the active server went through",
+        u8::from(fs.backend.is_healthy()),
+    );
+    out.gauge(
+        "circuit_breaker_state",
+        "Circuit breaker in front of the server: 0 closed, 1 open, 2 half-open",
+        match stats.http.breaker {
+            BreakerState::Closed => 0,
+            BreakerState::Open => 1,
+            BreakerState::HalfOpen => 2,
+        },
+    );
+    out.counter(
+        "circuit_breaker_opened_total",
+        "Times consecutive failures opened the circuit breaker",
+        stats.http.breaker_opened,
+    );
+    out.counter(
+        "circuit_breaker_rejected_total",
+        "Requests failed fast while the circuit breaker was open",
+        stats.http.breaker_rejected,
+    );
+    out.gauge(
+        "offline",
+        "Whether cached data is served because the server is unreachable",
+        u8::from(stats.offline),
+    );
+    out.counter(
+        "served_offline_total",
+        "Attributes and listings answered from the caches while offline",
+        stats.served_offline,
+    );
+    out.gauge(
+        "queued_changes",
+        "Changes made offline waiting to be replayed",
+        stats.queued_changes,
+    );
+    out.counter(
+        "replayed_changes_total",
+        "Changes made offline that reached the server",
+        stats.replayed_changes,
+    );
+    out.counter(
+        "replay_conflicts_total",
+        "Changes made offline the server turned down, kept as conflicts",
+        stats.replay_conflicts,
+    );
+    out.gauge(
+        "maintenance",
+        "Whether the server announced maintenance and the mount is read-only",
+        u8::from(stats.maintenance),
+    );
+    out.counter(
+        "maintenance_entered_total",
+        "Times the server announced maintenance or overload",
+        stats.maintenance_entered,
+    );
+    out.counter(
+        "maintenance_exited_total",
+        "Times the server took writes again after maintenance",
+        stats.maintenance_exited,
+    );
+    out.counter(
+        "write_conflicts_total",
+        "Flushes that found the file changed on the server and saved a conflict copy",
+        stats.write_conflicts,
+    );
+    out.counter(
+        "server_events_total",
+        "Change notifications received from the server",
+        stats.server_events,
+    );
+    out.counter(
+        "journal_replayed_total",
+        "Journaled writes left by a crashed mount and uploaded at mount",
+        stats.journal_replayed,
+    );
+    out.counter(
+        "journal_corrupt_total",
+        "Journal records skipped at mount because their checksum did not match",
+        stats.journal_corrupt,
+    );
+    out.metric(
+        "watch_last_refresh_timestamp_seconds",
+        "gauge",
+        "When a watched directory was last checked with the server",
+    );
+    for (path, watch) in &stats.watches {
+        if let Some(time) = watch.last_refresh {
+            out.sample("watch_last_refresh_timestamp_seconds", &[("path", path)], time);
+        }
+    }
+    out.metric(
+        "watch_changes_total",
+        "counter",
+        "Entries of watched directories found changed on the server",
+    );
+    for (path, watch) in &stats.watches {
+        out.sample("watch_changes_total", &[("path", path)], watch.changes);
+    }
+
+    // Hit ratios are hits over hits plus misses
+    out.metric(
+        "cache_hits_total",
+        "counter",
+        "Cache lookups answered from the cache",
+    );
+    let caches = [
+        ("attr", stats.attr_cache),
+        ("listing", stats.listing_cache),
+        ("negative", stats.negative_cache),
+        ("data", stats.data_cache),
+    ];
+    for (cache, counts) in &caches {
+        out.sample("cache_hits_total", &[("cache", cache)], counts.hits);
+    }
+    out.metric(
+        "cache_misses_total",
+        "counter",
+        "Cache lookups that went to the server",
+    );
+    for (cache, counts) in &caches {
+        out.sample("cache_misses_total", &[("cache", cache)], counts.misses);
+    }
+    out.gauge("cached_bytes", "File data held in memory", usage.data_bytes);
+    out.gauge(
+        "dirty_bytes",
+        "Buffered writes not uploaded yet",
+        usage.dirty_bytes,
+    );
+    out.gauge("inodes", "Entries in the inode table", stats.inodes);
+
+    out.counter(
+        "writes_received_total",
+        "Write calls received",
+        stats.writes_received,
+    );
+    out.counter(
+        "uploads_issued_total",
+        "Uploads sent to the server",
+        stats.uploads_issued,
+    );
+    out.counter(
+        "inodes_evicted_total",
+        "Inodes dropped to stay under max_inodes",
+        stats.inodes_evicted,
+    );
+    out.counter(
+        "refused_mutations_total",
+        "Mutations refused on a read-only, offline or unmounting mount",
+        stats.refused_mutations,
+    );
+    out.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{FsConfig, Op};
+    use crate::testing::MockBackend;
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    fn mounted(label: Option<&str>) -> RemoteFS {
+        let mock = Arc::new(MockBackend::new());
+        mock.add_file("/a.txt", b"hello");
+        let config = FsConfig {
+            label: label.map(str::to_string),
+            ..FsConfig::default()
+        };
+        let fs = RemoteFS::with_backend(mock, config);
+        fs.list_directory("/").unwrap();
+        fs.list_directory("/").unwrap();
+        fs.stats.call(Op::Read);
+        fs.stats.started();
+        fs.stats.finished(Op::Read, Duration::from_millis(3));
+        fs
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut stream = loop {
+            match TcpStream::connect(addr) {
+                Ok(stream) => break stream,
+                Err(e) if Instant::now() > deadline => panic!("{}", e),
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn statistics_render_as_prometheus_text() {
+        let text = render(&mounted(None));
+        assert!(text.contains("# TYPE remotefs_ops_total counter\n"));
+        assert!(text.contains("remotefs_ops_total{op=\"read\"} 1\n"));
+        assert!(text.contains("# TYPE remotefs_op_duration_seconds histogram\n"));
+        assert!(text.contains("remotefs_op_duration_seconds_bucket{op=\"read\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("remotefs_op_duration_seconds_count{op=\"read\"} 1\n"));
+        assert!(text.contains("remotefs_cache_hits_total{cache=\"listing\"} 1\n"));
+        assert!(text.contains("remotefs_cache_misses_total{cache=\"listing\"} 1\n"));
+        assert!(text.contains("remotefs_circuit_breaker_state 0\n"));
+        assert!(text.contains("remotefs_dirty_bytes 0\n"));
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            assert!(line.starts_with("remotefs_"), "{}", line);
+        }
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let text = render(&mounted(None));
+        let counts: Vec<u64> = text
+            .lines()
+            .filter(|line| line.starts_with("remotefs_op_duration_seconds_bucket{op=\"read\""))
+            .map(|line| line.rsplit(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(counts.len(), LATENCY_BUCKETS.len() + 1);
+        assert!(counts.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(counts.last(), Some(&1));
+    }
+
+    #[test]
+    fn labels_carry_the_mount_and_are_escaped() {
+        let text = render(&mounted(Some("work \"a\"")));
+        assert!(text.contains("remotefs_ops_total{mount=\"work \\\"a\\\"\",op=\"read\"} 1\n"));
+        assert!(text.contains("remotefs_inodes{mount=\"work \\\"a\\\"\"} "));
+        assert_eq!(escape("a\\b\nc"), "a\\\\b\\nc");
+    }
+
+    #[test]
+    fn metrics_are_served_until_shutdown() {
+        let fs = mounted(None);
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        spawn(&fs, addr).unwrap();
+        // The address stays taken while served
+        assert!(spawn(&fs, addr).is_err());
+
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("remotefs_ops_total{op=\"read\"} 1\n"));
+        assert!(get(addr, "/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        fs.shutdown.store(true, Ordering::Relaxed);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while TcpListener::bind(addr).is_err() {
+            assert!(Instant::now() < deadline, "the port was not released");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
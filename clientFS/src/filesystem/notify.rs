@@ -0,0 +1,345 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{normalize_path, split_path, RemoteFS, SIGNAL_POLL_INTERVAL};
+use crate::api_client::{ChangeEvent, ChangeKind, EventStream, Version};
+
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// The whole tree is mounted, so every change is of interest
+const SUBSCRIBED_PREFIX: &str = "/";
+
+// How the mount learns about changes made elsewhere. Each transport falls
+// back to the next one the server lacks: ws, sse, then poll.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyMode {
+    // A WebSocket on /ws, for gateways that do not pass server-sent events
+    #[default]
+    Ws,
+    // Server-sent events on /events
+    Sse,
+    // No notifications, cached entries are revalidated once their timeouts
+    // pass. What the other modes end up with when the server has neither.
+    Poll,
+    // Like poll, without asking the server for notifications at all
+    Off,
+}
+
+impl NotifyMode {
+    // Parses `--notify`
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "ws" | "websocket" => Ok(Self::Ws),
+            "sse" => Ok(Self::Sse),
+            "poll" => Ok(Self::Poll),
+            "off" => Ok(Self::Off),
+            other => {
+                anyhow::bail!(
+                    "Unknown notify mode '{}', expected ws, sse, poll or off",
+                    other
+                )
+            }
+        }
+    }
+
+    // The transport tried after this one
+    fn fallback(self) -> Self {
+        match self {
+            Self::Ws => Self::Sse,
+            Self::Sse | Self::Poll => Self::Poll,
+            Self::Off => Self::Off,
+        }
+    }
+}
+
+impl RemoteFS {
+    // Listens to the server's change notifications, reconnecting with
+    // backoff and resuming after the last event seen; a WebSocket subscribes
+    // again on every connection. Falls back to the next transport while the
+    // server lacks the current one, and stays on the first that works.
+    // An unmount is noticed with the next event or keepalive.
+    pub(super) fn spawn_event_listener(&self, mode: NotifyMode) {
+        let fs = self.clone();
+        let result = thread::Builder::new()
+            .name("server-events".to_string())
+            .spawn(move || {
+                let prefixes = [SUBSCRIBED_PREFIX.to_string()];
+                let mut mode = mode;
+                let mut last_id: Option<String> = None;
+                let mut backoff = FIRST_BACKOFF;
+                let mut connected_before = false;
+                while !fs.shutdown.load(Ordering::Relaxed) {
+                    let stream = match mode {
+                        NotifyMode::Ws => fs.backend.ws_events(&prefixes, last_id.as_deref()),
+                        NotifyMode::Sse => fs.backend.events(last_id.as_deref()),
+                        NotifyMode::Poll | NotifyMode::Off => return,
+                    };
+                    match stream {
+                        Ok(None) if connected_before => {
+                            log::warn!("Server stopped sending change notifications");
+                            return;
+                        }
+                        Ok(None) => {
+                            mode = mode.fallback();
+                            match mode {
+                                NotifyMode::Poll => log::info!(
+                                    "Server sends no change notifications, using cache timeouts"
+                                ),
+                                _ => log::debug!("Trying {:?} change notifications", mode),
+                            }
+                            continue;
+                        }
+                        Ok(Some(stream)) => {
+                            log::debug!("Listening to {:?} change notifications", mode);
+                            backoff = FIRST_BACKOFF;
+                            // Whatever changed while disconnected is only
+                            // known if the server can resume
+                            if connected_before && last_id.is_none() {
+                                fs.expire_cached();
+                            }
+                            connected_before = true;
+                            fs.consume_events(stream, &mut last_id);
+                            log::debug!("Change notifications ended, reconnecting");
+                        }
+                        Err(e) => log::debug!("Failed to open change notifications: {:#}", e),
+                    }
+
+                    let until = Instant::now() + backoff;
+                    while Instant::now() < until && !fs.shutdown.load(Ordering::Relaxed) {
+                        thread::sleep(SIGNAL_POLL_INTERVAL);
+                    }
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            });
+        if let Err(e) = result {
+            log::warn!("Failed to start listening to change notifications: {}", e);
+        }
+    }
+
+    // Applies events until the stream ends, fails or the mount goes away,
+    // whichever transport they came over
+    fn consume_events(&self, stream: EventStream, last_id: &mut Option<String>) {
+        for event in stream {
+            if self.shutdown.load(Ordering::Relaxed) {
+                return;
+            }
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    log::debug!("{:#}", e);
+                    return;
+                }
+            };
+            if event.id.is_some() {
+                *last_id = event.id.clone();
+            }
+            // The server could not resume after last_id
+            if event.event == "reset" {
+                self.expire_cached();
+                continue;
+            }
+            match event.change() {
+                Some(Ok(change)) => self.apply_change(&change),
+                Some(Err(e)) => log::warn!("{:#}", e),
+                None => {}
+            }
+        }
+    }
+
+    // Drops what the caches know about a path that changed on the server.
+    // Attributes and listings are fetched again on next use, contents once
+    // read again; buffered writes are left alone.
+    pub(super) fn apply_change(&self, change: &ChangeEvent) {
+        self.stats.server_events.fetch_add(1, Ordering::Relaxed);
+        let path = normalize_path(&change.path);
+        log::debug!("{} {:?} on the server", path, change.kind);
+
+        let (ino, parent_ino, known) = {
+            let inodes = self.inodes.read().unwrap();
+            let ino = inodes.resolve_path(&path);
+            let known = ino
+                .and_then(|ino| inodes.get(ino))
+                .and_then(|inode| inode.version.clone());
+            (ino, inodes.resolve_path(split_path(&path).0), known)
+        };
+        // Our own upload, or a version the caches already have
+        let current = matches!(
+            (&known, &change.etag),
+            (Some(Version::ETag(known)), Some(etag)) if known == etag
+        );
+        if change.kind == ChangeKind::Modified && current {
+            return;
+        }
+
+        self.invalidate_parent_listing(&path);
+        if change.kind != ChangeKind::Modified {
+            self.invalidate_listing(&path);
+        }
+        if let Some(ino) = ino {
+            self.drop_file_data(ino);
+            self.expire_attr(ino);
+        }
+        // Nothing under a deleted directory is kept for one made again there,
+        // unless it holds writes still to be uploaded
+        if change.kind == ChangeKind::Deleted && !ino.is_some_and(|ino| self.has_dirty_data(ino)) {
+            self.purge_subtree(&path);
+        }
+
+        // The kernel looks the name up again, created or deleted
+        let (_, name) = split_path(&path);
+        if let Some(parent_ino) = parent_ino.filter(|_| change.kind != ChangeKind::Modified) {
+            self.forget_missing(parent_ino, name);
+            self.invalidate_kernel_entry(parent_ino, name);
+        }
+    }
+
+    // Marks every cached attribute and listing expired, for when changes
+    // may have been missed. They are checked with the server on next use,
+    // conditionally where a version is known, so nothing still current is
+    // downloaded again.
+    pub(super) fn expire_cached(&self) {
+        log::info!("Change notifications may have been missed, revalidating the caches");
+        let config = self.config();
+        if let Some(expired) = Instant::now().checked_sub(config.cache.attr_timeout) {
+            let mut inodes = self.inodes.write().unwrap();
+            let inos: Vec<u64> = inodes.values().map(|inode| inode.ino).collect();
+            for ino in inos {
+                if let Some(inode) = inodes.get_mut(ino) {
+                    inode.fetched_at = expired;
+                }
+            }
+        }
+        match Instant::now().checked_sub(config.cache.listing_timeout) {
+            Some(expired) => {
+                let mut listings = self.listings.lock().unwrap();
+                for (_, listing) in listings.iter_mut() {
+                    listing.fetched_at = expired;
+                }
+            }
+            None => self.listings.lock().unwrap().retain(|_, _| false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_client::ServerEvent;
+    use crate::filesystem::FsConfig;
+    use crate::testing::MockBackend;
+    use std::sync::Arc;
+
+    // A mount with /docs listed and a.txt in it read
+    fn cached() -> (Arc<MockBackend>, RemoteFS, u64) {
+        let mock = Arc::new(MockBackend::new());
+        mock.add_dir("/docs");
+        mock.add_file("/docs/a.txt", b"hello");
+        let fs = RemoteFS::with_backend(mock.clone(), FsConfig::default());
+        fs.list_directory("/").unwrap();
+        let listing = fs.list_directory("/docs").unwrap();
+        let ino = fs.get_or_create_inode("/docs/a.txt", &listing[0]);
+        fs.read_blocks(&fs.get_inode(ino).unwrap(), 0, 5).unwrap();
+        mock.take_calls();
+        (mock, fs, ino)
+    }
+
+    fn change(path: &str, kind: ChangeKind, etag: Option<String>) -> ChangeEvent {
+        ChangeEvent {
+            path: path.to_string(),
+            kind,
+            etag,
+        }
+    }
+
+    fn fresh(fs: &RemoteFS, ino: u64) -> bool {
+        let inode = fs.get_inode(ino).unwrap();
+        inode.is_fresh(fs.config().cache.attr_timeout)
+    }
+
+    #[test]
+    fn transports_fall_back_to_polling() {
+        assert_eq!(NotifyMode::parse("WebSocket").unwrap(), NotifyMode::Ws);
+        assert_eq!(NotifyMode::parse("sse").unwrap(), NotifyMode::Sse);
+        assert!(NotifyMode::parse("push").is_err());
+
+        assert_eq!(NotifyMode::Ws.fallback(), NotifyMode::Sse);
+        assert_eq!(NotifyMode::Sse.fallback(), NotifyMode::Poll);
+        assert_eq!(NotifyMode::Poll.fallback(), NotifyMode::Poll);
+        assert_eq!(NotifyMode::Off.fallback(), NotifyMode::Off);
+    }
+
+    #[test]
+    fn our_own_version_changes_nothing() {
+        let (mock, fs, ino) = cached();
+        let Some(Version::ETag(etag)) = fs.get_inode(ino).unwrap().version else {
+            panic!("no version recorded");
+        };
+        fs.apply_change(&change("docs/a.txt", ChangeKind::Modified, Some(etag)));
+        assert!(fs.blocks.contains(ino, 0));
+        assert!(fresh(&fs, ino));
+        fs.list_directory("/docs").unwrap();
+        assert!(mock.take_calls().is_empty());
+        assert_eq!(fs.stats.server_events.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn changed_files_are_fetched_again() {
+        let (mock, fs, ino) = cached();
+        let etag = Some("\"other\"".to_string());
+        fs.apply_change(&change("/docs/a.txt", ChangeKind::Modified, etag));
+        assert!(!fs.blocks.contains(ino, 0));
+        assert!(!fresh(&fs, ino));
+        fs.list_directory("/docs").unwrap();
+        assert_eq!(mock.take_calls(), ["list /docs"]);
+    }
+
+    #[test]
+    fn deleted_directories_go_with_their_subtree() {
+        let (_mock, fs, ino) = cached();
+        fs.apply_change(&change("/docs", ChangeKind::Deleted, None));
+        let inodes = fs.inodes.read().unwrap();
+        assert!(inodes.resolve_path("/docs").is_none());
+        assert!(inodes.resolve_path("/docs/a.txt").is_none());
+        assert!(inodes.get(ino).is_none());
+        assert!(fs.listings.lock().unwrap().peek("/docs").is_none());
+    }
+
+    #[test]
+    fn deleted_files_with_writes_to_upload_are_kept() {
+        let (_mock, fs, ino) = cached();
+        let fh = fs.open_handle(ino, 5, None);
+        fs.file_handles.lock().unwrap().get_mut(&fh).unwrap().buffer.write(0, b"H");
+        fs.apply_change(&change("/docs/a.txt", ChangeKind::Deleted, None));
+        assert!(fs.inodes.read().unwrap().resolve_path("/docs/a.txt").is_some());
+        assert!(fs.get_inode(ino).is_some());
+    }
+
+    #[test]
+    fn events_are_applied_and_resets_expire_everything() {
+        let (mock, fs, ino) = cached();
+        let event = |id: &str, event: &str, data: &str| {
+            Ok(ServerEvent {
+                id: Some(id.to_string()),
+                event: event.to_string(),
+                data: data.to_string(),
+            })
+        };
+        let events = vec![
+            event("1", "message", r#"{"path": "/new.txt", "kind": "created"}"#),
+            event("2", "ping", ""),
+            event("3", "reset", ""),
+        ];
+        let mut last_id = None;
+        fs.consume_events(EventStream::from_events(events.into_iter()), &mut last_id);
+        assert_eq!(last_id.as_deref(), Some("3"));
+        assert_eq!(fs.stats.server_events.load(Ordering::Relaxed), 1);
+
+        assert!(!fresh(&fs, ino));
+        fs.list_directory("/docs").unwrap();
+        assert_eq!(mock.take_calls(), ["list /docs"]);
+    }
+}
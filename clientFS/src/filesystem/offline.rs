@@ -0,0 +1,304 @@
+use anyhow::Result;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{FsError, RemoteFS, SIGNAL_POLL_INTERVAL};
+use crate::api_client::{BreakerState, ServerError};
+
+// How long requests must keep failing before the mount goes offline
+const OFFLINE_AFTER: Duration = Duration::from_secs(10);
+// How often an offline mount checks whether the server is back
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What the mount does once the server stays unreachable
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OfflineMode {
+    // Every operation keeps going to the server and fails with it
+    #[default]
+    Off,
+    // Reads, attributes and listings are served from the caches, possibly
+    // stale, until the server answers again. Anything not cached fails with
+    // EHOSTDOWN and mutations with EROFS.
+    Auto,
+}
+
+impl OfflineMode {
+    // Parses `--offline-mode`
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "off" => Ok(Self::Off),
+            other => anyhow::bail!("Unknown offline mode '{}', expected auto or off", other),
+        }
+    }
+}
+
+// A 503 fails with EAGAIN but still counts as down, a 429 only means slow down
+pub(super) fn is_down(error: &anyhow::Error) -> bool {
+    let unavailable = error
+        .downcast_ref::<ServerError>()
+        .is_some_and(|e| e.status == StatusCode::SERVICE_UNAVAILABLE);
+    unavailable
+        || matches!(
+            FsError::from_backend(error),
+            FsError::Unreachable | FsError::TimedOut | FsError::HostDown
+        )
+}
+
+impl RemoteFS {
+    pub(super) fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    // The error for anything the caches cannot answer while offline
+    pub(super) fn not_cached_offline(&self, path: &str) -> anyhow::Error {
+        anyhow::Error::new(FsError::HostDown).context(format!("{} is not cached", path))
+    }
+
+    // Remembers a path answered from the caches while offline, so it is
+    // checked with the server first thing once back online
+    pub(super) fn served_offline(&self, path: &str) {
+        self.stats.served_offline.fetch_add(1, Ordering::Relaxed);
+        self.served_stale.lock().unwrap().insert(path.to_string());
+    }
+
+    // Watches the health of the server while offline_mode is auto. Requests
+    // failing for OFFLINE_AFTER, or until the circuit breaker opens, take the
+    // mount offline unless a probe gets through; offline, a probe every
+    // PROBE_INTERVAL brings it back.
+    pub(super) fn spawn_offline_watcher(&self) {
+        let fs = self.clone();
+        thread::spawn(move || {
+            let mut failing_since: Option<Instant> = None;
+            let mut last_probe = Instant::now();
+            let mut probe_now = false;
+            while !fs.shutdown.load(Ordering::Relaxed) {
+                thread::sleep(SIGNAL_POLL_INTERVAL);
+
+                // A change queued just as the mount went online is replayed
+                // like the others
+                let queued = fs
+                    .change_queue
+                    .as_ref()
+                    .is_some_and(|queue| !queue.is_empty());
+                if queued && !fs.is_offline() {
+                    fs.offline.store(true, Ordering::Relaxed);
+                    probe_now = true;
+                }
+
+                if fs.is_offline() {
+                    if !probe_now && last_probe.elapsed() < PROBE_INTERVAL {
+                        continue;
+                    }
+                    probe_now = false;
+                    last_probe = Instant::now();
+                    match fs.backend.check_reachable(PROBE_TIMEOUT) {
+                        Ok(()) => {
+                            failing_since = None;
+                            fs.go_online();
+                        }
+                        Err(e) => log::debug!("Server still unreachable: {:#}", e),
+                    }
+                    continue;
+                }
+
+                if fs.backend.is_healthy() {
+                    failing_since = None;
+                    continue;
+                }
+                let since = *failing_since.get_or_insert_with(Instant::now);
+                // An open circuit has seen enough failures already
+                let tripped = fs.backend.breaker_state() == BreakerState::Open;
+                if since.elapsed() < OFFLINE_AFTER && !tripped {
+                    continue;
+                }
+                last_probe = Instant::now();
+                // A server refusing the credentials is not down
+                match fs.backend.check_reachable(PROBE_TIMEOUT) {
+                    Err(e) if is_down(&e) => fs.go_offline(&e),
+                    _ => failing_since = None,
+                }
+            }
+        });
+    }
+
+    fn go_offline(&self, error: &anyhow::Error) {
+        if self.offline.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let changes = if self.change_queue.is_some() {
+            "queueing changes"
+        } else {
+            "read-only"
+        };
+        match &self.config().label {
+            Some(label) => log::warn!(
+                "[{}] Server unreachable, serving cached data {}: {:#}",
+                label,
+                changes,
+                error
+            ),
+            None => log::warn!(
+                "Server unreachable, serving cached data {}: {:#}",
+                changes,
+                error
+            ),
+        }
+    }
+
+    // Replays the changes queued meanwhile, then expires whatever was served
+    // from the caches, so the next access asks the server and drops contents
+    // that changed. Stays offline if the server goes away during the replay.
+    fn go_online(&self) {
+        if let Err(e) = self.replay_changes() {
+            log::warn!("Server unreachable again while replaying changes: {:#}", e);
+            return;
+        }
+        // The server may have been upgraded or replaced while away
+        if let Err(e) = self.backend.negotiate_capabilities() {
+            log::warn!("Failed to negotiate server capabilities: {:#}", e);
+        }
+        let stale: Vec<String> = self.served_stale.lock().unwrap().drain().collect();
+        match &self.config().label {
+            Some(label) => log::info!(
+                "[{}] Server reachable again, revalidating {} entries",
+                label,
+                stale.len()
+            ),
+            None => log::info!(
+                "Server reachable again, revalidating {} entries",
+                stale.len()
+            ),
+        }
+
+        let config = self.config();
+        let expired_attr = Instant::now().checked_sub(config.cache.attr_timeout);
+        let expired_listing = Instant::now().checked_sub(config.cache.listing_timeout);
+        {
+            let mut inodes = self.inodes.write().unwrap();
+            for path in &stale {
+                let Some(ino) = inodes.resolve_path(path) else {
+                    continue;
+                };
+                if let (Some(inode), Some(expired)) = (inodes.get_mut(ino), expired_attr) {
+                    inode.fetched_at = expired;
+                }
+            }
+        }
+        let mut listings = self.listings.lock().unwrap();
+        for path in &stale {
+            match (listings.get_mut(path), expired_listing) {
+                (Some(listing), Some(expired)) => listing.fetched_at = expired,
+                _ => {
+                    listings.remove(path);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{FsConfig, Op};
+    use crate::testing::MockBackend;
+    use std::sync::Arc;
+
+    // A mount that cached /docs and a.txt in it, then lost the server
+    fn gone_offline() -> (Arc<MockBackend>, RemoteFS, u64) {
+        let mock = Arc::new(MockBackend::new());
+        mock.add_dir("/docs");
+        mock.add_file("/docs/a.txt", b"hello");
+        mock.add_file("/docs/b.txt", b"world");
+        mock.add_dir("/other");
+        let config = FsConfig {
+            offline_mode: OfflineMode::Auto,
+            ..FsConfig::default()
+        };
+        let fs = RemoteFS::with_backend(mock.clone(), config);
+        fs.list_directory("/").unwrap();
+        let listing = fs.list_directory("/docs").unwrap();
+        let a = fs.get_or_create_inode("/docs/a.txt", &listing[0]);
+        fs.get_or_create_inode("/docs/b.txt", &listing[1]);
+        fs.read_blocks(&fs.get_inode(a).unwrap(), 0, 5).unwrap();
+
+        fs.offline.store(true, Ordering::Relaxed);
+        mock.take_calls();
+        (mock, fs, a)
+    }
+
+    fn server_error(status: u16) -> anyhow::Error {
+        anyhow::Error::new(ServerError {
+            status: StatusCode::from_u16(status).unwrap(),
+            maintenance: false,
+        })
+    }
+
+    #[test]
+    fn only_an_absent_server_is_down() {
+        for kind in [FsError::Unreachable, FsError::TimedOut, FsError::HostDown] {
+            assert!(is_down(&anyhow::Error::new(kind)), "{:?}", kind);
+        }
+        assert!(is_down(&server_error(503)));
+        assert!(!is_down(&server_error(429)));
+        assert!(!is_down(&server_error(401)));
+        assert!(!is_down(&anyhow::Error::new(FsError::NotFound)));
+    }
+
+    #[test]
+    fn modes_parse_by_name() {
+        assert_eq!(OfflineMode::parse(" Auto").unwrap(), OfflineMode::Auto);
+        assert_eq!(OfflineMode::parse("off").unwrap(), OfflineMode::Off);
+        assert!(OfflineMode::parse("sometimes").is_err());
+    }
+
+    #[test]
+    fn cached_data_is_served_without_the_server() {
+        let (mock, fs, a) = gone_offline();
+        fs.expire_attr(a);
+        assert_eq!(fs.revalidate_inode(a).unwrap().attr.size, 5);
+        assert_eq!(fs.list_directory("/docs").unwrap().len(), 2);
+        let inode = fs.get_inode(a).unwrap();
+        assert_eq!(fs.read_blocks(&inode, 0, 5).unwrap(), b"hello");
+        assert!(mock.take_calls().is_empty());
+        assert_eq!(fs.stats.served_offline.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn what_is_not_cached_fails_and_mutations_are_refused() {
+        let (mock, fs, _) = gone_offline();
+        let b = fs.inodes.read().unwrap().resolve_path("/docs/b.txt").unwrap();
+        let inode = fs.get_inode(b).unwrap();
+        let error = fs.read_blocks(&inode, 0, 5).unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::HostDown);
+        let error = fs.list_directory("/other").unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::HostDown);
+        assert!(mock.take_calls().is_empty());
+
+        assert!(fs.refuse_mutation(Op::Write));
+        assert!(fs.refuse_mutation(Op::Mkdir));
+    }
+
+    #[test]
+    fn what_was_served_offline_is_checked_once_back() {
+        let (mock, fs, a) = gone_offline();
+        fs.expire_attr(a);
+        fs.revalidate_inode(a).unwrap();
+        fs.list_directory("/docs").unwrap();
+
+        fs.offline.store(false, Ordering::Relaxed);
+        fs.go_online();
+        assert!(fs.served_stale.lock().unwrap().is_empty());
+        mock.take_calls();
+        fs.list_directory("/docs").unwrap();
+        assert_eq!(mock.take_calls(), ["list /docs"]);
+        // Listings not served offline are still trusted
+        fs.list_directory("/").unwrap();
+        assert!(mock.take_calls().is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+use fuser::{FileAttr, FileType, Request};
+
+use super::{FsError, RemoteFS};
+
+// Access asked of an inode, as the bits of one class of its mode
+pub const READ: u16 = 0o4;
+pub const WRITE: u16 = 0o2;
+pub const EXEC: u16 = 0o1;
+
+const STICKY: u16 = 0o1000;
+
+// Who a request comes from. FUSE passes the primary group only, so access
+// through supplementary groups is not granted here.
+#[derive(Debug, Clone, Copy)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Caller {
+    pub fn of(req: &Request<'_>) -> Self {
+        Self {
+            uid: req.uid(),
+            gid: req.gid(),
+        }
+    }
+
+    // The owner, group or other bits of the mode, whichever class the caller
+    // is in. Root has all access, except executing a file nobody may execute.
+    fn may(&self, attr: &FileAttr, access: u16) -> bool {
+        if self.uid == 0 {
+            return access & EXEC == 0
+                || attr.kind == FileType::Directory
+                || attr.perm & 0o111 != 0;
+        }
+        let shift = if self.uid == attr.uid {
+            6
+        } else if self.gid == attr.gid {
+            3
+        } else {
+            0
+        };
+        (attr.perm >> shift) & access == access
+    }
+
+    // In a directory with the sticky bit, like /tmp, only the owner of an
+    // entry or of the directory, or root, may remove or replace it
+    fn may_remove(&self, dir: &FileAttr, entry: Option<&FileAttr>) -> bool {
+        dir.perm & STICKY == 0
+            || self.uid == 0
+            || self.uid == dir.uid
+            || entry.is_none_or(|entry| entry.uid == self.uid)
+    }
+}
+
+impl RemoteFS {
+    // Whether permissions are checked against the presented mode bits, by the
+    // kernel and again here
+    pub(super) fn enforces_permissions(&self) -> bool {
+        let config = self.config();
+        config.default_permissions.unwrap_or(config.allow_other)
+    }
+
+    // Checks the caller has `access` to an inode. The kernel already does
+    // with default_permissions, but against attributes it may have cached
+    // before the ids were mapped or the mode changed. Inodes not known here
+    // pass, the server decides on them.
+    pub(super) fn check_access(
+        &self,
+        caller: Caller,
+        ino: u64,
+        access: u16,
+    ) -> Result<(), FsError> {
+        if !self.enforces_permissions() {
+            return Ok(());
+        }
+        match self.get_inode(ino) {
+            Some(inode) if !caller.may(&inode.attr, access) => Err(FsError::PermissionDenied),
+            _ => Ok(()),
+        }
+    }
+
+    // Checks the caller may add, remove or replace the entry at `path` in
+    // `parent`: it needs write and search access to the directory and, with
+    // the directory's sticky bit set, to own the entry there
+    pub(super) fn check_entry_change(
+        &self,
+        caller: Caller,
+        parent: u64,
+        path: &str,
+    ) -> Result<(), FsError> {
+        if !self.enforces_permissions() {
+            return Ok(());
+        }
+        let Some(dir) = self.get_inode(parent) else {
+            return Ok(());
+        };
+        if !caller.may(&dir.attr, WRITE | EXEC) {
+            return Err(FsError::PermissionDenied);
+        }
+        let inodes = self.inodes.read().unwrap();
+        let entry = inodes.get_path(path).map(|inode| &inode.attr);
+        if !caller.may_remove(&dir.attr, entry) {
+            return Err(FsError::NotPermitted);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_client::{FileEntry, Timestamp};
+    use crate::filesystem::FsConfig;
+    use crate::testing::MockBackend;
+    use std::sync::Arc;
+
+    const OWNER: Caller = Caller { uid: 1000, gid: 5 };
+    const GROUP: Caller = Caller { uid: 2000, gid: 100 };
+    const OTHER: Caller = Caller { uid: 3000, gid: 300 };
+    const ROOT: Caller = Caller { uid: 0, gid: 0 };
+
+    fn mounted(default_permissions: Option<bool>) -> RemoteFS {
+        let config = FsConfig {
+            default_permissions,
+            ..FsConfig::default()
+        };
+        RemoteFS::with_backend(Arc::new(MockBackend::new()), config)
+    }
+
+    // An inode for `path` as a listing owned by uid 1000 and gid 100 would
+    // make it
+    fn node(fs: &RemoteFS, path: &str, is_dir: bool, mode: u32) -> u64 {
+        let entry = FileEntry {
+            name: path.rsplit('/').next().unwrap().to_string(),
+            is_dir,
+            size: 0,
+            mtime: Timestamp::UNKNOWN,
+            ctime: Timestamp::UNKNOWN,
+            mode,
+            id: None,
+            uid: Some(1000),
+            gid: Some(100),
+            major: None,
+            minor: None,
+            nlink: None,
+        };
+        fs.get_or_create_inode(path, &entry)
+    }
+
+    #[test]
+    fn callers_get_the_bits_of_their_class() {
+        let fs = mounted(Some(true));
+        let ino = node(&fs, "/a.txt", false, 0o640);
+        assert_eq!(fs.check_access(OWNER, ino, READ | WRITE), Ok(()));
+        assert_eq!(fs.check_access(GROUP, ino, READ), Ok(()));
+        assert_eq!(fs.check_access(GROUP, ino, WRITE), Err(FsError::PermissionDenied));
+        assert_eq!(fs.check_access(OTHER, ino, READ), Err(FsError::PermissionDenied));
+        assert_eq!(fs.check_access(ROOT, ino, READ | WRITE), Ok(()));
+        // Not cached, the server decides
+        assert_eq!(fs.check_access(OTHER, 999, WRITE), Ok(()));
+    }
+
+    #[test]
+    fn root_executes_only_what_someone_may() {
+        let fs = mounted(Some(true));
+        let plain = node(&fs, "/plain", false, 0o644);
+        let script = node(&fs, "/script", false, 0o700);
+        let dir = node(&fs, "/dir", true, 0o600);
+        assert_eq!(fs.check_access(ROOT, plain, EXEC), Err(FsError::PermissionDenied));
+        assert_eq!(fs.check_access(ROOT, script, EXEC), Ok(()));
+        assert_eq!(fs.check_access(ROOT, dir, EXEC), Ok(()));
+    }
+
+    #[test]
+    fn entries_change_with_write_and_search_access() {
+        let fs = mounted(Some(true));
+        let dir = node(&fs, "/shared", true, 0o775);
+        assert_eq!(fs.check_entry_change(GROUP, dir, "/shared/new"), Ok(()));
+        assert_eq!(
+            fs.check_entry_change(OTHER, dir, "/shared/new"),
+            Err(FsError::PermissionDenied)
+        );
+        let readonly = node(&fs, "/readonly", true, 0o555);
+        assert_eq!(
+            fs.check_entry_change(OWNER, readonly, "/readonly/new"),
+            Err(FsError::PermissionDenied)
+        );
+    }
+
+    #[test]
+    fn sticky_directories_keep_entries_to_their_owners() {
+        let fs = mounted(Some(true));
+        let tmp = node(&fs, "/tmp", true, 0o1777);
+        assert_eq!(fs.get_inode(tmp).unwrap().attr.perm, 0o1777);
+        node(&fs, "/tmp/theirs", false, 0o666);
+
+        let stranger = Caller { uid: 4000, gid: 100 };
+        assert_eq!(
+            fs.check_entry_change(stranger, tmp, "/tmp/theirs"),
+            Err(FsError::NotPermitted)
+        );
+        assert_eq!(fs.check_entry_change(stranger, tmp, "/tmp/new"), Ok(()));
+        // Owner of the entry and the directory, then root
+        assert_eq!(fs.check_entry_change(OWNER, tmp, "/tmp/theirs"), Ok(()));
+        assert_eq!(fs.check_entry_change(ROOT, tmp, "/tmp/theirs"), Ok(()));
+
+        let open = node(&fs, "/open", true, 0o777);
+        node(&fs, "/open/theirs", false, 0o666);
+        assert_eq!(fs.check_entry_change(stranger, open, "/open/theirs"), Ok(()));
+    }
+
+    #[test]
+    fn checks_follow_default_permissions() {
+        let fs = mounted(None);
+        assert!(!fs.enforces_permissions());
+        let ino = node(&fs, "/private", false, 0o600);
+        assert_eq!(fs.check_access(OTHER, ino, READ), Ok(()));
+
+        let config = |allow_other, default_permissions| FsConfig {
+            allow_other,
+            default_permissions,
+            ..FsConfig::default()
+        };
+        let mock = || Arc::new(MockBackend::new());
+        assert!(RemoteFS::with_backend(mock(), config(true, None)).enforces_permissions());
+        let off = RemoteFS::with_backend(mock(), config(true, Some(false)));
+        assert!(!off.enforces_permissions());
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Contiguous reads needed on a handle before anything is prefetched
+const MIN_SEQUENTIAL_READS: u32 = 2;
+
+// Access pattern of one file handle. Once reads follow each other the next
+// window is prefetched; a read anywhere else resets the streak, so random
+// access never triggers prefetching.
+#[derive(Debug, Default)]
+pub struct ReadAhead {
+    next_offset: u64,
+    sequential: u32,
+    // End of the data already requested ahead of the reader
+    prefetched_until: u64,
+    in_flight: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+}
+
+// A window to prefetch. Only one runs per handle at a time, the slot is
+// released when this is dropped.
+pub struct Prefetch {
+    pub start: u64,
+    pub end: u64,
+    in_flight: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ReadAhead {
+    // Records a read and returns the window to prefetch next, if any
+    pub fn on_read(
+        &mut self,
+        offset: u64,
+        len: u64,
+        window: u64,
+        file_size: u64,
+    ) -> Option<Prefetch> {
+        if offset == self.next_offset {
+            self.sequential += 1;
+        } else {
+            self.sequential = 1;
+            self.prefetched_until = 0;
+        }
+        self.next_offset = offset + len;
+
+        if window == 0 || self.sequential < MIN_SEQUENTIAL_READS {
+            return None;
+        }
+
+        // Wait until the reader is halfway through what was prefetched
+        let start = self.prefetched_until.max(self.next_offset);
+        let end = (self.next_offset + window).min(file_size);
+        if start >= end || self.prefetched_until >= self.next_offset + window / 2 {
+            return None;
+        }
+
+        if self.in_flight.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        self.prefetched_until = end;
+
+        Some(Prefetch {
+            start,
+            end,
+            in_flight: self.in_flight.clone(),
+            cancelled: self.cancelled.clone(),
+        })
+    }
+
+    // Stops a running prefetch and starts detection over
+    pub fn cancel(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        *self = Self::default();
+    }
+}
+
+impl Prefetch {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        self.in_flight.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: u64 = 100;
+    const SIZE: u64 = 1000;
+
+    #[test]
+    fn second_sequential_read_prefetches_the_next_window() {
+        let mut readahead = ReadAhead::default();
+        assert!(readahead.on_read(0, 10, WINDOW, SIZE).is_none());
+        let prefetch = readahead.on_read(10, 10, WINDOW, SIZE).unwrap();
+        assert_eq!((prefetch.start, prefetch.end), (20, 120));
+    }
+
+    #[test]
+    fn random_reads_prefetch_nothing() {
+        let mut readahead = ReadAhead::default();
+        for offset in [500, 0, 300, 900, 100] {
+            assert!(readahead.on_read(offset, 10, WINDOW, SIZE).is_none());
+        }
+        assert!(ReadAhead::default().on_read(0, 10, 0, SIZE).is_none());
+    }
+
+    #[test]
+    fn one_prefetch_runs_at_a_time() {
+        let mut readahead = ReadAhead::default();
+        readahead.on_read(0, 10, WINDOW, SIZE);
+        let running = readahead.on_read(10, 10, WINDOW, SIZE).unwrap();
+        // Past half the window, but the first prefetch is still running
+        assert!(readahead.on_read(20, 60, WINDOW, SIZE).is_none());
+
+        drop(running);
+        let next = readahead.on_read(80, 10, WINDOW, SIZE).unwrap();
+        assert_eq!((next.start, next.end), (120, 190));
+    }
+
+    #[test]
+    fn prefetching_stops_at_the_end_of_the_file() {
+        let mut readahead = ReadAhead::default();
+        readahead.on_read(900, 10, WINDOW, SIZE);
+        let prefetch = readahead.on_read(910, 10, WINDOW, SIZE).unwrap();
+        assert_eq!(prefetch.end, SIZE);
+        drop(prefetch);
+        assert!(readahead.on_read(920, 10, WINDOW, SIZE).is_none());
+    }
+
+    #[test]
+    fn cancel_reaches_the_running_prefetch() {
+        let mut readahead = ReadAhead::default();
+        readahead.on_read(0, 10, WINDOW, SIZE);
+        let prefetch = readahead.on_read(10, 10, WINDOW, SIZE).unwrap();
+        readahead.cancel();
+        assert!(prefetch.is_cancelled());
+        // Detection starts over
+        assert!(readahead.on_read(20, 10, WINDOW, SIZE).is_none());
+    }
+}
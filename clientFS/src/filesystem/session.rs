@@ -0,0 +1,258 @@
+use anyhow::Result;
+use fuser::BackgroundSession;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use super::{RemoteFS, SIGNAL_POLL_INTERVAL};
+
+static SHUTDOWN_SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    // A second signal means the user will not wait for the flush. Only
+    // async-signal-safe calls are allowed here, which _exit is.
+    if SHUTDOWN_SIGNALS.fetch_add(1, Ordering::SeqCst) > 0 {
+        unsafe { libc::_exit(1) };
+    }
+}
+
+// Routes SIGINT, SIGTERM and SIGHUP to shutdown_requested
+pub(crate) fn install_shutdown_handlers() -> Result<()> {
+    let handler = request_shutdown as extern "C" fn(libc::c_int);
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        if unsafe { libc::signal(signal, handler as libc::sighandler_t) } == libc::SIG_ERR {
+            anyhow::bail!("Failed to install handler for signal {}", signal);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn shutdown_requested() -> bool {
+    SHUTDOWN_SIGNALS.load(Ordering::SeqCst) > 0
+}
+
+// A filesystem mounted in the background. Unmounting, explicitly or by
+// dropping the guard, uploads pending writes first and stops the background
+// tasks along with the session.
+pub struct MountGuard {
+    mountpoint: String,
+    session: Option<BackgroundSession>,
+    fs: RemoteFS,
+}
+
+impl MountGuard {
+    pub(super) fn new(mountpoint: &str, session: BackgroundSession, fs: RemoteFS) -> Self {
+        Self {
+            mountpoint: mountpoint.to_string(),
+            session: Some(session),
+            fs,
+        }
+    }
+
+    pub fn mountpoint(&self) -> &str {
+        &self.mountpoint
+    }
+
+    pub fn filesystem(&self) -> &RemoteFS {
+        &self.fs
+    }
+
+    // False once the session ended, e.g. unmounted from outside or failed
+    pub fn is_running(&self) -> bool {
+        self.session.as_ref().is_some_and(|s| !s.guard.is_finished())
+    }
+
+    // Flushes, unmounts and waits for the session to end. A failed upload is
+    // reported but does not keep the filesystem mounted.
+    pub fn unmount(mut self) -> Result<()> {
+        self.stop()
+    }
+
+    // Like unmount, but gives buffered writes at most flush_deadline to reach
+    // the server
+    pub fn unmount_within(mut self, flush_deadline: Duration) -> Result<()> {
+        self.stop_within(Some(flush_deadline))
+    }
+
+    // Waits until the filesystem is unmounted from outside, e.g. with
+    // fusermount -u, and returns how the session ended
+    pub fn join(mut self) -> Result<()> {
+        let Some(session) = self.session.take() else {
+            return Ok(());
+        };
+
+        // The mount is only released once the rest of the session drops,
+        // after the session thread finished
+        let guard = session.guard;
+        let result = guard
+            .join()
+            .map_err(|_| anyhow::anyhow!("FUSE session for {} panicked", self.mountpoint))?;
+        self.fs.shutdown.store(true, Ordering::Relaxed);
+        result?;
+        Ok(())
+    }
+
+    // Serves until SIGINT, SIGTERM or SIGHUP arrives or the filesystem is
+    // unmounted from outside. After a signal, mutations are refused, buffered
+    // writes get up to flush_deadline to reach the server and the filesystem
+    // is unmounted. An error means some data may not have been written.
+    pub fn run_until_signal(mut self, flush_deadline: Duration) -> Result<()> {
+        install_shutdown_handlers()?;
+
+        while !shutdown_requested() {
+            if !self.is_running() {
+                return self.join();
+            }
+            thread::sleep(SIGNAL_POLL_INTERVAL);
+        }
+
+        log::info!("Signal received, shutting down {}", self.mountpoint);
+        self.stop_within(Some(flush_deadline))
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.stop_within(None)
+    }
+
+    fn stop_within(&mut self, flush_deadline: Option<Duration>) -> Result<()> {
+        let Some(session) = self.session.take() else {
+            return Ok(());
+        };
+
+        log::info!("Unmounting {}", self.mountpoint);
+        self.fs.begin_shutdown();
+        let flushed = match flush_deadline {
+            Some(deadline) => flush_within(&self.fs, deadline),
+            None => self.fs.flush_all(),
+        };
+
+        // Dropping everything but the thread handle unmounts, which ends the session
+        let guard = {
+            let session = session;
+            session.guard
+        };
+        let result = guard
+            .join()
+            .map_err(|_| anyhow::anyhow!("FUSE session for {} panicked", self.mountpoint))?;
+
+        result?;
+        flushed
+    }
+}
+
+// Flushes on a thread of its own so a hanging server cannot hold up the
+// unmount past the deadline
+fn flush_within(fs: &RemoteFS, deadline: Duration) -> Result<()> {
+    let (done, flushed) = mpsc::channel();
+    let fs = fs.clone();
+    thread::spawn(move || {
+        let _ = done.send(fs.flush_all());
+    });
+
+    match flushed.recv_timeout(deadline) {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::anyhow!(
+            "Buffered writes were not uploaded within {:?}",
+            deadline
+        )),
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            log::error!("Failed to unmount {} cleanly: {}", self.mountpoint, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::FsConfig;
+    use crate::testing::MockBackend;
+    use std::sync::Arc;
+
+    // A filesystem with "AB" buffered over the start of /data
+    fn with_pending_write() -> (Arc<MockBackend>, RemoteFS) {
+        let mock = Arc::new(MockBackend::new());
+        mock.add_file("/data", b"abcd");
+        let fs = RemoteFS::with_backend(mock.clone(), FsConfig::default());
+        let listing = fs.list_directory("/").unwrap();
+        let ino = fs.get_or_create_inode("/data", &listing[0]);
+        let fh = fs.open_handle(ino, 4, None);
+        let mut handles = fs.file_handles.lock().unwrap();
+        handles.get_mut(&fh).unwrap().buffer.write(0, b"AB");
+        drop(handles);
+        (mock, fs)
+    }
+
+    #[test]
+    fn pending_writes_are_flushed_within_the_deadline() {
+        let (mock, fs) = with_pending_write();
+        flush_within(&fs, Duration::from_secs(5)).unwrap();
+        assert_eq!(mock.contents("/data").unwrap(), b"ABcd");
+    }
+
+    #[test]
+    fn draining_refuses_mutations_but_flushes_what_is_buffered() {
+        use crate::filesystem::Op;
+
+        let (mock, fs) = with_pending_write();
+        assert!(!fs.refuse_mutation(Op::Write));
+        fs.begin_shutdown();
+        assert!(fs.refuse_mutation(Op::Write));
+        assert!(fs.refuse_mutation(Op::Create));
+
+        flush_within(&fs, Duration::from_secs(5)).unwrap();
+        assert_eq!(mock.contents("/data").unwrap(), b"ABcd");
+    }
+
+    #[test]
+    fn failed_upload_is_reported_after_the_others() {
+        let mock = Arc::new(MockBackend::new());
+        mock.add_file("/data", b"abcd");
+        mock.add_file("/other", b"wxyz");
+        let fs = RemoteFS::with_backend(mock.clone(), FsConfig::default());
+        for (entry, change) in fs.list_directory("/").unwrap().iter().zip([b"AB", b"yZ"]) {
+            let ino = fs.get_or_create_inode(&format!("/{}", entry.name), entry);
+            let fh = fs.open_handle(ino, 4, None);
+            let mut handles = fs.file_handles.lock().unwrap();
+            handles.get_mut(&fh).unwrap().buffer.write(0, change);
+        }
+
+        mock.fail_next("write", crate::filesystem::FsError::NoSpace);
+        assert!(flush_within(&fs, Duration::from_secs(5)).is_err());
+        // Whichever went first failed, the other was still uploaded
+        let data = mock.contents("/data").unwrap();
+        let other = mock.contents("/other").unwrap();
+        assert!(
+            (data == b"ABcd" && other == b"wxyz") || (data == b"abcd" && other == b"yZyz"),
+            "{:?} {:?}",
+            data,
+            other
+        );
+    }
+
+    // The only test raising a signal: a second one would end the process
+    #[test]
+    fn a_signal_requests_shutdown() {
+        install_shutdown_handlers().unwrap();
+        assert!(!shutdown_requested());
+        unsafe { libc::raise(libc::SIGHUP) };
+        assert!(shutdown_requested());
+    }
+
+    #[test]
+    fn hanging_server_does_not_hold_up_the_unmount() {
+        let (mock, fs) = with_pending_write();
+        mock.on_call(|_, op, _| {
+            if op == "write" {
+                thread::sleep(Duration::from_millis(500));
+            }
+        });
+        let error = flush_within(&fs, Duration::from_millis(50)).unwrap_err();
+        assert!(error.to_string().contains("not uploaded within"), "{}", error);
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{casefold, RemoteFS};
+use crate::api_client::FileEntry;
+
+// Order of the entries readdir returns. Listings are sorted once as they
+// are cached, so offsets index into the same order on every call.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortDirs {
+    // As the server lists them
+    #[default]
+    None,
+    // By name, byte by byte
+    Name,
+    // By name ignoring case, names differing only in case byte by byte
+    NameCi,
+}
+
+impl SortDirs {
+    // Parses `--sort-dirs`
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "name" => Ok(Self::Name),
+            "name-ci" => Ok(Self::NameCi),
+            other => anyhow::bail!(
+                "Unknown directory order '{}', expected none, name or name-ci",
+                other
+            ),
+        }
+    }
+}
+
+impl RemoteFS {
+    // Puts a listing about to be cached in the configured order
+    pub(super) fn sort_listing(&self, entries: &mut [FileEntry]) {
+        match self.config().sort_dirs {
+            SortDirs::None => {}
+            SortDirs::Name => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+            SortDirs::NameCi => {
+                entries.sort_by_cached_key(|entry| (casefold(&entry.name), entry.name.clone()))
+            }
+        }
+    }
+
+    // Sorted listings are served once complete, never streamed
+    pub(super) fn sorts_listings(&self) -> bool {
+        self.config().sort_dirs != SortDirs::None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::streamed::DirEntries;
+    use crate::filesystem::FsConfig;
+    use crate::testing::MockBackend;
+    use std::sync::Arc;
+
+    fn mounted(sort_dirs: SortDirs) -> (Arc<MockBackend>, RemoteFS) {
+        let mock = Arc::new(MockBackend::new());
+        let config = FsConfig {
+            sort_dirs,
+            ..FsConfig::default()
+        };
+        (mock.clone(), RemoteFS::with_backend(mock, config))
+    }
+
+    fn sorted(sort_dirs: SortDirs, names: &[&str]) -> Vec<String> {
+        let mut entries: Vec<FileEntry> = names
+            .iter()
+            .map(|name| {
+                let entry =
+                    serde_json::json!({"name": name, "is_dir": false, "size": 0, "mode": 0o644});
+                serde_json::from_value(entry).unwrap()
+            })
+            .collect();
+        mounted(sort_dirs).1.sort_listing(&mut entries);
+        entries.into_iter().map(|entry| entry.name).collect()
+    }
+
+    #[test]
+    fn orders_are_parsed() {
+        assert_eq!(SortDirs::parse("none").unwrap(), SortDirs::None);
+        assert_eq!(SortDirs::parse(" Name ").unwrap(), SortDirs::Name);
+        assert_eq!(SortDirs::parse("NAME-CI").unwrap(), SortDirs::NameCi);
+        assert!(SortDirs::parse("size").is_err());
+        assert!(SortDirs::parse("").is_err());
+        let order: SortDirs = serde_json::from_str("\"name-ci\"").unwrap();
+        assert_eq!(order, SortDirs::NameCi);
+    }
+
+    #[test]
+    fn names_sort_by_bytes_or_ignoring_case() {
+        let names = ["b", "B", "a", "C", "A"];
+        assert_eq!(sorted(SortDirs::None, &names), names);
+        assert_eq!(sorted(SortDirs::Name, &names), ["A", "B", "C", "a", "b"]);
+        // Ties by bytes keep the order total
+        assert_eq!(sorted(SortDirs::NameCi, &names), ["A", "a", "B", "b", "C"]);
+    }
+
+    #[test]
+    fn sorted_listings_are_never_streamed() {
+        let (mock, fs) = mounted(SortDirs::NameCi);
+        mock.add_file("/B", b"");
+        mock.add_file("/a", b"");
+        assert!(fs.sorts_listings());
+        let DirEntries::Cached(entries) = fs.open_listing("/").unwrap() else {
+            panic!("streamed a sorted listing");
+        };
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["a", "B"]);
+        assert!(!mounted(SortDirs::None).1.sorts_listings());
+    }
+}
@@ -0,0 +1,121 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+static NEXT_SPILL: AtomicU64 = AtomicU64::new(0);
+
+// Temporary file holding file contents too large to assemble in memory. It is
+// unlinked as soon as it is created, so nothing is left behind once it is
+// dropped, even if the process dies.
+pub struct SpillFile {
+    file: Arc<File>,
+    len: u64,
+}
+
+// Reads a spill file from the start; each reader keeps its own position so an
+// upload can be replayed from a fresh one
+pub struct SpillReader {
+    file: Arc<File>,
+    pos: u64,
+    end: u64,
+}
+
+impl SpillFile {
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(format!(
+            ".remotefs-spill-{}-{}",
+            std::process::id(),
+            NEXT_SPILL.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        std::fs::remove_file(&path)?;
+
+        Ok(Self {
+            file: Arc::new(file),
+            len: 0,
+        })
+    }
+
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(data, offset)?;
+        self.len = self.len.max(offset + data.len() as u64);
+        Ok(())
+    }
+
+    // Growing leaves a hole that reads back as zeros without using disk space
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.len = len;
+        Ok(())
+    }
+
+    pub fn reader(&self) -> SpillReader {
+        SpillReader {
+            file: self.file.clone(),
+            pos: 0,
+            end: self.len,
+        }
+    }
+}
+
+impl Read for SpillReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = (self.end - self.pos).min(buf.len() as u64) as usize;
+        if max == 0 {
+            return Ok(0);
+        }
+
+        let n = self.file.read_at(&mut buf[..max], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_is_left_in_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spill = SpillFile::create(dir.path()).unwrap();
+        spill.write_at(0, b"data").unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn every_reader_starts_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spill = SpillFile::create(dir.path()).unwrap();
+        spill.write_at(4, b"efgh").unwrap();
+        spill.write_at(0, b"abcd").unwrap();
+
+        let mut first = Vec::new();
+        spill.reader().read_to_end(&mut first).unwrap();
+        let mut second = Vec::new();
+        spill.reader().read_to_end(&mut second).unwrap();
+        assert_eq!(first, b"abcdefgh");
+        assert_eq!(second, first);
+    }
+
+    #[test]
+    fn length_cuts_and_grows_with_zeros() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spill = SpillFile::create(dir.path()).unwrap();
+        spill.write_at(0, b"abcdefgh").unwrap();
+        spill.set_len(2).unwrap();
+        spill.set_len(4).unwrap();
+
+        let mut data = Vec::new();
+        spill.reader().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"ab\0\0");
+    }
+}
@@ -0,0 +1,169 @@
+use anyhow::Result;
+use serde::Deserialize;
+
+use super::{FsError, INode, RemoteFS};
+
+// What reads on a handle do once the file changed on the server since it
+// was opened, so they never mix blocks of two versions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StaleHandles {
+    // Every further read on the handle fails with ESTALE
+    #[default]
+    Error,
+    // The handle continues against the new version, reading it afresh
+    Refresh,
+}
+
+impl StaleHandles {
+    // Parses `--stale-handles`
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "refresh" => Ok(Self::Refresh),
+            other => anyhow::bail!(
+                "Unknown stale handles policy '{}', expected error or refresh",
+                other
+            ),
+        }
+    }
+}
+
+impl RemoteFS {
+    // Checks a handle against changes found on the server since it was
+    // opened, or since it last caught up. Buffered writes keep the handle
+    // on its version, their flush takes the conflict path.
+    pub(super) fn check_handle(&self, fh: u64, inode: &INode) -> Result<()> {
+        let mut file_handles = self.file_handles.lock().unwrap();
+        let Some(handle) = file_handles.get_mut(&fh) else {
+            return Ok(());
+        };
+        if handle.seen_changes == inode.remote_changes {
+            return Ok(());
+        }
+
+        match self.config().stale_handles {
+            StaleHandles::Error => Err(anyhow::Error::new(FsError::Stale).context(format!(
+                "{} changed on the server since it was opened",
+                inode.path
+            ))),
+            StaleHandles::Refresh => {
+                if handle.buffer.is_empty() {
+                    log::debug!(
+                        "{} changed on the server, reading the new version",
+                        inode.path
+                    );
+                    handle.seen_changes = inode.remote_changes;
+                    handle.remote_size = inode.attr.size;
+                    handle.readahead.cancel();
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_client::{ChangeEvent, ChangeKind};
+    use crate::filesystem::FsConfig;
+    use crate::testing::MockBackend;
+    use std::sync::Arc;
+
+    // /f open on a handle, and the inode number
+    fn opened(stale_handles: StaleHandles) -> (Arc<MockBackend>, RemoteFS, u64, u64) {
+        let mock = Arc::new(MockBackend::new());
+        mock.add_file("/f", b"old");
+        let config = FsConfig {
+            stale_handles,
+            ..FsConfig::default()
+        };
+        let fs = RemoteFS::with_backend(mock.clone(), config);
+        let listing = fs.list_directory("/").unwrap();
+        let ino = fs.get_or_create_inode("/f", &listing[0]);
+        let fh = fs.open_handle(ino, 3, None);
+        (mock, fs, ino, fh)
+    }
+
+    // Another client replaces /f, a notification tells and a listing shows
+    // the new attributes. Listings alone do not count while writes are
+    // buffered.
+    fn replaced(mock: &MockBackend, fs: &RemoteFS, data: &[u8]) -> INode {
+        mock.add_file("/f", data);
+        fs.apply_change(&ChangeEvent {
+            path: "/f".to_string(),
+            kind: ChangeKind::Modified,
+            etag: None,
+        });
+        fs.drop_caches("/");
+        let listing = fs.list_directory("/").unwrap();
+        let ino = fs.get_or_create_inode("/f", &listing[0]);
+        fs.get_inode(ino).unwrap()
+    }
+
+    fn error_kind(result: Result<()>) -> FsError {
+        FsError::from_backend(&result.unwrap_err())
+    }
+
+    #[test]
+    fn policies_parse_by_name() {
+        assert_eq!(StaleHandles::parse("Refresh").unwrap(), StaleHandles::Refresh);
+        assert_eq!(StaleHandles::parse("error").unwrap(), StaleHandles::Error);
+        assert!(StaleHandles::parse("ignore").is_err());
+    }
+
+    #[test]
+    fn handles_on_a_replaced_file_are_stale() {
+        let (mock, fs, ino, fh) = opened(StaleHandles::Error);
+        fs.check_handle(fh, &fs.get_inode(ino).unwrap()).unwrap();
+        let inode = replaced(&mock, &fs, b"newer");
+        assert_eq!(error_kind(fs.check_handle(fh, &inode)), FsError::Stale);
+        // Handles opened since see the new version
+        let later = fs.open_handle(ino, inode.attr.size, None);
+        fs.check_handle(later, &inode).unwrap();
+    }
+
+    #[test]
+    fn our_own_uploads_keep_handles_current() {
+        let (_mock, fs, ino, fh) = opened(StaleHandles::Error);
+        let other = fs.open_handle(ino, 3, None);
+        fs.file_handles.lock().unwrap().get_mut(&fh).unwrap().buffer.write(0, b"ours!");
+        fs.flush_handle(fh).unwrap();
+
+        fs.drop_caches("/");
+        let listing = fs.list_directory("/").unwrap();
+        fs.get_or_create_inode("/f", &listing[0]);
+        let inode = fs.get_inode(ino).unwrap();
+        fs.check_handle(fh, &inode).unwrap();
+        fs.check_handle(other, &inode).unwrap();
+    }
+
+    #[test]
+    fn refreshed_handles_catch_up_unless_they_hold_writes() {
+        let (mock, fs, _ino, fh) = opened(StaleHandles::Refresh);
+        let inode = replaced(&mock, &fs, b"newer");
+        fs.check_handle(fh, &inode).unwrap();
+        {
+            let handles = fs.file_handles.lock().unwrap();
+            assert_eq!(handles[&fh].seen_changes, inode.remote_changes);
+            assert_eq!(handles[&fh].remote_size, 5);
+        }
+
+        fs.file_handles.lock().unwrap().get_mut(&fh).unwrap().buffer.write(0, b"x");
+        let inode = replaced(&mock, &fs, b"newest");
+        fs.check_handle(fh, &inode).unwrap();
+        let handles = fs.file_handles.lock().unwrap();
+        assert_ne!(handles[&fh].seen_changes, inode.remote_changes);
+        assert_eq!(handles[&fh].remote_size, 5);
+    }
+
+    #[test]
+    fn writes_to_a_replaced_file_are_not_uploaded() {
+        let (mock, fs, _ino, fh) = opened(StaleHandles::Error);
+        fs.file_handles.lock().unwrap().get_mut(&fh).unwrap().buffer.write(0, b"x");
+        replaced(&mock, &fs, b"newer");
+        assert_eq!(error_kind(fs.flush_handle(fh)), FsError::Stale);
+        assert_eq!(mock.contents("/f").unwrap(), b"newer");
+    }
+}
@@ -0,0 +1,309 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::api_client::RequestStatsSnapshot;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Lookup,
+    Getattr,
+    Setattr,
+    Ioctl,
+    Readdir,
+    Read,
+    Write,
+    Open,
+    Flush,
+    Fsync,
+    Release,
+    Mkdir,
+    Unlink,
+    Rmdir,
+    Rename,
+    Create,
+}
+
+const OPS: [&str; 16] = [
+    "lookup", "getattr", "setattr", "ioctl", "readdir", "read", "write", "open", "flush", "fsync",
+    "release", "mkdir", "unlink", "rmdir", "rename", "create",
+];
+
+// Upper bounds in seconds of the operation latency buckets, the last bucket
+// takes everything slower
+pub const LATENCY_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+impl Op {
+    pub fn name(self) -> &'static str {
+        OPS[self as usize]
+    }
+}
+
+#[derive(Default)]
+pub struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheCounters {
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Counters of the FUSE side, all relaxed atomics since they are bumped on
+// every operation and only read for reporting
+#[derive(Default)]
+pub struct FsStats {
+    calls: [AtomicU64; OPS.len()],
+    // Operations that failed against the server, not ordinary ENOENT replies
+    errors: [AtomicU64; OPS.len()],
+    pub attrs: CacheCounters,
+    pub listings: CacheCounters,
+    pub negative: CacheCounters,
+    pub writes_received: AtomicU64,
+    pub uploads_issued: AtomicU64,
+    // Inodes dropped to stay under max_inodes
+    pub inodes_evicted: AtomicU64,
+    // Mutations refused because the mount is read-only or being unmounted
+    pub refused_mutations: AtomicU64,
+    // Attributes and listings answered from the caches while offline
+    pub served_offline: AtomicU64,
+    // Changes made offline that reached the server, or that it turned down
+    pub replayed_changes: AtomicU64,
+    pub replay_conflicts: AtomicU64,
+    // Times the server announced maintenance, and times it took writes again
+    pub maintenance_entered: AtomicU64,
+    pub maintenance_exited: AtomicU64,
+    // Flushes that found the file changed on the server and saved a conflict copy
+    pub write_conflicts: AtomicU64,
+    // Change notifications received from the server
+    pub server_events: AtomicU64,
+    // Journaled writes an earlier mount left pending and uploaded at mount
+    pub journal_replayed: AtomicU64,
+    // Journal records skipped at mount because their checksum did not match
+    pub journal_corrupt: AtomicU64,
+    // By watched directory
+    pub watches: Mutex<BTreeMap<String, WatchStats>>,
+    latency: [Latency; OPS.len()],
+    in_flight: AtomicU64,
+}
+
+#[derive(Default)]
+struct Latency {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+// Operation latencies, per bucket of LATENCY_BUCKETS and not cumulative
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LatencySnapshot {
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_seconds: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// A watched directory, see FsConfig::watch
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WatchStats {
+    pub interval_seconds: f64,
+    // Unix time the listing was last checked with the server
+    pub last_refresh: Option<f64>,
+    // Entries found added, removed or changed
+    pub changes: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatsSnapshot {
+    // Operations never called are left out
+    pub calls: BTreeMap<String, u64>,
+    pub errors: BTreeMap<String, u64>,
+    pub attr_cache: CacheStats,
+    pub listing_cache: CacheStats,
+    pub negative_cache: CacheStats,
+    pub data_cache: CacheStats,
+    pub writes_received: u64,
+    pub uploads_issued: u64,
+    // Current size of the inode table
+    pub inodes: usize,
+    pub inodes_evicted: u64,
+    pub refused_mutations: u64,
+    // Whether the server is unreachable and cached data is being served
+    pub offline: bool,
+    pub served_offline: u64,
+    // Changes made offline and not replayed yet
+    pub queued_changes: usize,
+    pub replayed_changes: u64,
+    // Changes set aside under <cache_dir>/conflicts
+    pub replay_conflicts: u64,
+    // Whether the server takes no writes for now and the mount is read-only
+    pub maintenance: bool,
+    pub maintenance_entered: u64,
+    pub maintenance_exited: u64,
+    pub write_conflicts: u64,
+    pub server_events: u64,
+    pub journal_replayed: u64,
+    pub journal_corrupt: u64,
+    pub watches: BTreeMap<String, WatchStats>,
+    // Operations that finished, by name
+    pub latency: BTreeMap<String, LatencySnapshot>,
+    pub ops_in_flight: u64,
+    pub http: RequestStatsSnapshot,
+}
+
+impl FsStats {
+    pub fn call(&self, op: Op) {
+        self.calls[op as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn error(&self, op: Op) {
+        self.errors[op as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn started(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn finished(&self, op: Op, elapsed: Duration) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        let latency = &self.latency[op as usize];
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        latency.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        latency
+            .sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(
+        &self,
+        data_cache: CacheStats,
+        inodes: usize,
+        offline: bool,
+        maintenance: bool,
+        queued_changes: usize,
+        http: RequestStatsSnapshot,
+    ) -> StatsSnapshot {
+        let counts = |counters: &[AtomicU64]| {
+            OPS.iter()
+                .zip(counters)
+                .map(|(op, count)| (op.to_string(), count.load(Ordering::Relaxed)))
+                .filter(|&(_, count)| count > 0)
+                .collect()
+        };
+
+        StatsSnapshot {
+            calls: counts(&self.calls),
+            errors: counts(&self.errors),
+            attr_cache: self.attrs.snapshot(),
+            listing_cache: self.listings.snapshot(),
+            negative_cache: self.negative.snapshot(),
+            data_cache,
+            writes_received: self.writes_received.load(Ordering::Relaxed),
+            uploads_issued: self.uploads_issued.load(Ordering::Relaxed),
+            inodes,
+            inodes_evicted: self.inodes_evicted.load(Ordering::Relaxed),
+            refused_mutations: self.refused_mutations.load(Ordering::Relaxed),
+            offline,
+            served_offline: self.served_offline.load(Ordering::Relaxed),
+            queued_changes,
+            replayed_changes: self.replayed_changes.load(Ordering::Relaxed),
+            replay_conflicts: self.replay_conflicts.load(Ordering::Relaxed),
+            maintenance,
+            maintenance_entered: self.maintenance_entered.load(Ordering::Relaxed),
+            maintenance_exited: self.maintenance_exited.load(Ordering::Relaxed),
+            write_conflicts: self.write_conflicts.load(Ordering::Relaxed),
+            server_events: self.server_events.load(Ordering::Relaxed),
+            journal_replayed: self.journal_replayed.load(Ordering::Relaxed),
+            journal_corrupt: self.journal_corrupt.load(Ordering::Relaxed),
+            watches: self.watches.lock().unwrap().clone(),
+            latency: OPS
+                .iter()
+                .zip(&self.latency)
+                .map(|(op, latency)| {
+                    let buckets: Vec<u64> = latency
+                        .buckets
+                        .iter()
+                        .map(|count| count.load(Ordering::Relaxed))
+                        .collect();
+                    let snapshot = LatencySnapshot {
+                        count: buckets.iter().sum(),
+                        buckets,
+                        sum_seconds: latency.sum_micros.load(Ordering::Relaxed) as f64 / 1e6,
+                    };
+                    (op.to_string(), snapshot)
+                })
+                .filter(|(_, latency)| latency.count > 0)
+                .collect(),
+            ops_in_flight: self.in_flight.load(Ordering::Relaxed),
+            http,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(stats: &FsStats) -> StatsSnapshot {
+        stats.snapshot(CacheStats::default(), 1, false, false, 0, Default::default())
+    }
+
+    #[test]
+    fn operations_never_called_are_left_out() {
+        let stats = FsStats::default();
+        stats.call(Op::Read);
+        stats.call(Op::Read);
+        stats.call(Op::Write);
+        stats.error(Op::Write);
+        stats.attrs.hit();
+        stats.attrs.miss();
+        stats.attrs.hit();
+
+        let snapshot = snapshot(&stats);
+        let calls = BTreeMap::from([("read".to_string(), 2), ("write".to_string(), 1)]);
+        assert_eq!(snapshot.calls, calls);
+        assert_eq!(snapshot.errors, BTreeMap::from([("write".to_string(), 1)]));
+        assert_eq!((snapshot.attr_cache.hits, snapshot.attr_cache.misses), (2, 1));
+        assert!(snapshot.latency.is_empty());
+    }
+
+    #[test]
+    fn latencies_fall_in_the_first_bucket_that_holds_them() {
+        let stats = FsStats::default();
+        for millis in [0, 1, 7, 60_000] {
+            stats.started();
+            stats.finished(Op::Lookup, Duration::from_millis(millis));
+        }
+        stats.started();
+
+        let snapshot = snapshot(&stats);
+        let lookup = &snapshot.latency["lookup"];
+        assert_eq!(lookup.count, 4);
+        assert_eq!(lookup.buckets, [1, 1, 0, 1, 0, 0, 0, 0, 0, 0, 1]);
+        assert!((lookup.sum_seconds - 60.008).abs() < 1e-9);
+        assert_eq!(snapshot.ops_in_flight, 1);
+    }
+}
@@ -0,0 +1,177 @@
+use fuser::{FileAttr, FileType, ReplyEntry, FUSE_ROOT_ID};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use super::{blocks_of, RemoteFS};
+
+// A read-only file in the root showing the statistics as JSON, made up by
+// the client and never sent to the server
+pub const STATS_FILE: &str = ".remotefs-stats";
+// Kept out of the inode numbers handed to remote files
+pub const STATS_INO: u64 = FUSE_ROOT_ID + 1;
+
+pub fn is_stats_file_name(parent: u64, name: &str) -> bool {
+    parent == FUSE_ROOT_ID && name == STATS_FILE
+}
+
+impl RemoteFS {
+    // Whether `name` in `parent` is the stats file regardless of the server.
+    // Without show_stats_file a real file of that name wins, and the stats
+    // file only answers lookups the server has nothing for.
+    pub(super) fn shows_stats_file(&self, parent: u64, name: &str) -> bool {
+        self.config().show_stats_file && is_stats_file_name(parent, name)
+    }
+
+    // Answers a lookup the server has nothing for, with the stats file when
+    // that is the name asked for
+    pub(super) fn reply_not_on_server(&self, parent: u64, name: &str, reply: ReplyEntry) {
+        if is_stats_file_name(parent, name) {
+            self.reply_stats_file(reply);
+        } else {
+            self.reply_missing(reply);
+        }
+    }
+
+    // Never cached by the kernel, so a server file of the same name showing
+    // up later is found on the next lookup
+    pub(super) fn reply_stats_file(&self, reply: ReplyEntry) {
+        reply.entry(&Duration::ZERO, &self.stats_file_attr(), 0);
+    }
+
+    // The size is the one of a snapshot taken now, later ones may differ,
+    // which is why the file is opened with direct I/O
+    pub(super) fn stats_file_attr(&self) -> FileAttr {
+        let now = SystemTime::now();
+        let size = self.render_stats().len() as u64;
+        FileAttr {
+            ino: STATS_INO,
+            size,
+            blocks: blocks_of(size),
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: FileType::RegularFile,
+            perm: 0o444,
+            nlink: 1,
+            uid: self.owner.0,
+            gid: self.owner.1,
+            rdev: 0,
+            flags: 0,
+            blksize: self.config().blksize,
+        }
+    }
+
+    fn render_stats(&self) -> Vec<u8> {
+        let mut json = serde_json::to_vec_pretty(&self.stats_snapshot()).unwrap_or_default();
+        json.push(b'\n');
+        json
+    }
+
+    // Every open gets its own snapshot, so a reader sees one consistent state
+    pub(super) fn open_stats_file(&self) -> u64 {
+        let mut next_fh = self.next_fh.lock().unwrap();
+        let fh = *next_fh;
+        *next_fh += 1;
+
+        let snapshot = Arc::new(self.render_stats());
+        self.stats_files.lock().unwrap().insert(fh, snapshot);
+        fh
+    }
+
+    pub(super) fn read_stats_file(&self, fh: u64, offset: u64, size: u64) -> Vec<u8> {
+        let Some(snapshot) = self.stats_files.lock().unwrap().get(&fh).cloned() else {
+            return Vec::new();
+        };
+        let start = (offset as usize).min(snapshot.len());
+        let end = start.saturating_add(size as usize).min(snapshot.len());
+        snapshot[start..end].to_vec()
+    }
+
+    pub(super) fn release_stats_file(&self, fh: u64) {
+        self.stats_files.lock().unwrap().remove(&fh);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{FsConfig, Op};
+    use crate::testing::MockBackend;
+
+    fn mounted(show_stats_file: bool) -> (Arc<MockBackend>, RemoteFS) {
+        let mock = Arc::new(MockBackend::new());
+        let config = FsConfig {
+            show_stats_file,
+            ..FsConfig::default()
+        };
+        (mock.clone(), RemoteFS::with_backend(mock, config))
+    }
+
+    fn read_all(fs: &RemoteFS, fh: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        loop {
+            let chunk = fs.read_stats_file(fh, data.len() as u64, 7);
+            if chunk.is_empty() {
+                return data;
+            }
+            data.extend(chunk);
+        }
+    }
+
+    #[test]
+    fn only_the_name_in_the_root_is_the_stats_file() {
+        assert!(is_stats_file_name(FUSE_ROOT_ID, STATS_FILE));
+        assert!(!is_stats_file_name(FUSE_ROOT_ID + 5, STATS_FILE));
+        assert!(!is_stats_file_name(FUSE_ROOT_ID, ".remotefs-stats.txt"));
+
+        // Without the flag a server file of that name comes first
+        assert!(!mounted(false).1.shows_stats_file(FUSE_ROOT_ID, STATS_FILE));
+        assert!(mounted(true).1.shows_stats_file(FUSE_ROOT_ID, STATS_FILE));
+    }
+
+    #[test]
+    fn each_open_reads_its_own_snapshot_as_json() {
+        let (mock, fs) = mounted(false);
+        fs.stats.call(Op::Read);
+        let first = fs.open_stats_file();
+        fs.stats.call(Op::Read);
+        let second = fs.open_stats_file();
+        assert_ne!(first, second);
+
+        let json: serde_json::Value = serde_json::from_slice(&read_all(&fs, first)).unwrap();
+        assert_eq!(json["calls"]["read"], 1);
+        assert!(json["ops_in_flight"].is_u64());
+        assert!(json["http"].is_object());
+        let json: serde_json::Value = serde_json::from_slice(&read_all(&fs, second)).unwrap();
+        assert_eq!(json["calls"]["read"], 2);
+        // Made up by the client alone
+        assert!(mock.take_calls().is_empty());
+
+        fs.release_stats_file(first);
+        assert!(fs.read_stats_file(first, 0, 4096).is_empty());
+        assert!(!fs.read_stats_file(second, 0, 4096).is_empty());
+    }
+
+    #[test]
+    fn reads_past_the_end_are_empty() {
+        let (_mock, fs) = mounted(false);
+        let fh = fs.open_stats_file();
+        let len = read_all(&fs, fh).len() as u64;
+        assert_eq!(fs.read_stats_file(fh, len - 1, 100), b"\n");
+        assert!(fs.read_stats_file(fh, len + 10, 100).is_empty());
+        assert!(fs.read_stats_file(fh, u64::MAX, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn the_file_is_read_only_and_sized_by_a_snapshot() {
+        let (_mock, fs) = mounted(false);
+        let attr = fs.stats_file_attr();
+        assert_eq!(attr.ino, STATS_INO);
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.perm, 0o444);
+        assert_eq!(attr.size, fs.render_stats().len() as u64);
+        assert_eq!(attr.blocks, attr.size.div_ceil(512));
+        assert_eq!(attr.blksize, fs.config().blksize);
+    }
+}
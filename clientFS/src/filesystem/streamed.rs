@@ -0,0 +1,170 @@
+use anyhow::Result;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use super::{FsError, RemoteFS};
+use crate::api_client::FileEntry;
+
+#[derive(Default)]
+struct Progress {
+    entries: Vec<FileEntry>,
+    done: bool,
+    // Why the listing ended early, errors are not Clone
+    failed: Option<(FsError, String)>,
+}
+
+// A listing still arriving from the server. readdir serves its first
+// entries while the rest are read, and whoever needs all of it waits.
+#[derive(Default)]
+pub struct StreamedListing {
+    progress: Mutex<Progress>,
+    grown: Condvar,
+}
+
+impl StreamedListing {
+    fn push(&self, entry: FileEntry) {
+        self.progress.lock().unwrap().entries.push(entry);
+        self.grown.notify_all();
+    }
+
+    fn finish(&self, failed: Option<(FsError, String)>) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.done = true;
+        progress.failed = failed;
+        drop(progress);
+        self.grown.notify_all();
+    }
+
+    fn error(failed: &(FsError, String)) -> anyhow::Error {
+        anyhow::Error::new(failed.0).context(failed.1.clone())
+    }
+
+    // Entry `index`, waiting for it to arrive. None past the end.
+    pub fn get(&self, index: usize) -> Result<Option<FileEntry>> {
+        let mut progress = self.progress.lock().unwrap();
+        loop {
+            if let Some(entry) = progress.entries.get(index) {
+                return Ok(Some(entry.clone()));
+            }
+            if let Some(failed) = &progress.failed {
+                return Err(Self::error(failed));
+            }
+            if progress.done {
+                return Ok(None);
+            }
+            progress = self.grown.wait(progress).unwrap();
+        }
+    }
+
+    // Every entry, once the listing is complete
+    pub fn wait(&self) -> Result<Arc<Vec<FileEntry>>> {
+        let mut progress = self.progress.lock().unwrap();
+        while !progress.done {
+            progress = self.grown.wait(progress).unwrap();
+        }
+        match &progress.failed {
+            Some(failed) => Err(Self::error(failed)),
+            None => Ok(Arc::new(progress.entries.clone())),
+        }
+    }
+}
+
+// The entries of a directory as readdir walks them
+pub enum DirEntries {
+    Cached(Arc<Vec<FileEntry>>),
+    Streamed(Arc<StreamedListing>),
+}
+
+impl DirEntries {
+    pub fn get(&self, index: usize) -> Result<Option<FileEntry>> {
+        match self {
+            Self::Cached(entries) => Ok(entries.get(index).cloned()),
+            Self::Streamed(listing) => listing.get(index),
+        }
+    }
+
+    // Entries received so far, all of them once get returned None
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Cached(entries) => entries.len(),
+            Self::Streamed(listing) => listing.progress.lock().unwrap().entries.len(),
+        }
+    }
+}
+
+impl RemoteFS {
+    // The listing of `path` for readdir. A directory not cached at all is
+    // streamed from the server on a thread of its own, into the listing
+    // cache once complete; the rest goes through list_directory.
+    pub(super) fn open_listing(&self, path: &str) -> Result<DirEntries> {
+        if self.is_offline()
+            || self.sorts_listings()
+            || self.listings.lock().unwrap().get(path).is_some()
+        {
+            return self.list_directory(path).map(DirEntries::Cached);
+        }
+
+        let listing = {
+            let mut streamed = self.streamed.lock().unwrap();
+            if let Some(listing) = streamed.get(path) {
+                return Ok(DirEntries::Streamed(listing.clone()));
+            }
+            let listing = Arc::new(StreamedListing::default());
+            streamed.insert(path.to_string(), listing.clone());
+            listing
+        };
+        self.stats.listings.miss();
+
+        let fs = self.clone();
+        let path = path.to_string();
+        let streaming = listing.clone();
+        thread::spawn(move || fs.stream_listing(&path, &streaming));
+        Ok(DirEntries::Streamed(listing))
+    }
+
+    // The listing of `path` being streamed, if it is
+    pub(super) fn streamed_listing(&self, path: &str) -> Option<Arc<StreamedListing>> {
+        self.streamed.lock().unwrap().get(path).cloned()
+    }
+
+    fn stream_listing(&self, path: &str, listing: &StreamedListing) {
+        let epoch = self.listing_epoch.load(Ordering::Relaxed);
+        let result = self
+            .backend
+            .list_directory_streamed(path, &mut |entry| {
+                if self.listable(path, &entry) {
+                    listing.push(entry)
+                }
+            });
+
+        let failed = match result {
+            Ok(version) => {
+                let entries = Arc::new(listing.progress.lock().unwrap().entries.clone());
+                if let Some(disk_cache) = &self.disk_cache {
+                    disk_cache.store_listing(path, &entries);
+                }
+                self.cache_fetched_listing(epoch, path, entries, version);
+                None
+            }
+            Err(e) => {
+                let cached = match &self.disk_cache {
+                    Some(disk_cache) if listing.progress.lock().unwrap().entries.is_empty() => {
+                        disk_cache.load_listing(path)
+                    }
+                    _ => None,
+                };
+                match cached {
+                    Some(entries) => {
+                        log::warn!("Serving cached listing of {}: {}", path, e);
+                        entries.into_iter().for_each(|entry| listing.push(entry));
+                        None
+                    }
+                    None => Some((FsError::from_backend(&e), format!("{:#}", e))),
+                }
+            }
+        };
+        self.streamed.lock().unwrap().remove(path);
+        listing.finish(failed);
+    }
+}
@@ -0,0 +1,1621 @@
+use std::os::unix::ffi::OsStrExt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::permissions::Caller;
+use super::{FsConfig, FsError, Op, RemoteFS};
+use fuser::TimeOrNow;
+use crate::api_client::{FileEntry, Timestamp};
+use crate::testing::MockBackend;
+
+// A filesystem over a fresh in-memory tree, returned with it
+fn mount(config: FsConfig) -> (Arc<MockBackend>, RemoteFS) {
+    let mock = Arc::new(MockBackend::new());
+    (mock.clone(), RemoteFS::with_backend(mock, config))
+}
+
+fn caller() -> Caller {
+    Caller {
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+    }
+}
+
+// Another client creates the file after the kernel saw it missing and
+// before create reaches the server
+fn created_meanwhile(mock: &MockBackend) {
+    mock.on_call(|mock, op, path| {
+        if op == "write" && path == "/report.txt" && !mock.exists(path) {
+            mock.add_file(path, b"theirs");
+        }
+    });
+}
+
+#[test]
+fn create_opens_file_created_since_lookup() {
+    let (mock, fs) = mount(FsConfig::default());
+    created_meanwhile(&mock);
+    assert!(fs.list_directory("/").unwrap().is_empty());
+
+    let (attr, _fh) = fs
+        .create_and_open(caller(), 1, "report.txt".as_ref(), libc::O_WRONLY)
+        .unwrap();
+    assert_eq!(attr.size, 6);
+    assert_eq!(mock.contents("/report.txt").unwrap(), b"theirs");
+}
+
+#[test]
+fn exclusive_create_fails_on_file_created_since_lookup() {
+    let (mock, fs) = mount(FsConfig::default());
+    created_meanwhile(&mock);
+    assert!(fs.list_directory("/").unwrap().is_empty());
+
+    let flags = libc::O_WRONLY | libc::O_EXCL;
+    let result = fs.create_and_open(caller(), 1, "report.txt".as_ref(), flags);
+    assert_eq!(result.unwrap_err(), FsError::AlreadyExists.errno());
+    assert_eq!(mock.contents("/report.txt").unwrap(), b"theirs");
+}
+
+#[test]
+fn create_makes_missing_file() {
+    let (mock, fs) = mount(FsConfig::default());
+
+    let (attr, _fh) = fs
+        .create_and_open(caller(), 1, "new.txt".as_ref(), libc::O_WRONLY)
+        .unwrap();
+    assert_eq!(attr.size, 0);
+    assert_eq!(mock.contents("/new.txt").unwrap(), b"");
+}
+
+// What touch -d sets, with a fraction the server has to keep too
+fn touched() -> SystemTime {
+    UNIX_EPOCH + Duration::new(1_600_000_000, 250_000_000)
+}
+
+// The ino of `path` as a lookup would make it
+fn look_up(fs: &RemoteFS, path: &str) -> u64 {
+    let (parent, name) = super::split_path(path);
+    let listing = fs.list_directory(parent).unwrap();
+    let entry = listing.iter().find(|entry| entry.name == name).unwrap();
+    fs.get_or_create_inode(path, entry)
+}
+
+// Nothing cached is trusted on the next stat
+fn expire(fs: &RemoteFS, ino: u64) {
+    fs.drop_caches("/");
+    fs.expire_attr(ino);
+}
+
+#[test]
+fn mtime_of_buffered_writes_survives_flush() {
+    let (mock, fs) = mount(FsConfig::default());
+    let (attr, fh) = fs
+        .create_and_open(caller(), 1, "copy.txt".as_ref(), libc::O_WRONLY)
+        .unwrap();
+    if let Some(handle) = fs.file_handles.lock().unwrap().get_mut(&fh) {
+        handle.buffer.write(0, b"contents");
+    }
+
+    fs.set_mtime(attr.ino, TimeOrNow::SpecificTime(touched())).unwrap();
+    fs.flush_handle(fh).unwrap();
+    assert_eq!(fs.get_inode(attr.ino).unwrap().attr.mtime, touched());
+    expire(&fs, attr.ino);
+
+    let inode = fs.revalidate_inode(attr.ino).unwrap();
+    assert_eq!(inode.attr.mtime, touched());
+    assert_eq!(inode.attr.size, 8);
+    assert_eq!(mock.mtime("/copy.txt"), Some(touched()));
+}
+
+#[test]
+fn mtime_of_clean_file_is_sent_on_its_own() {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_file("/kept.txt", b"contents");
+    let ino = look_up(&fs, "/kept.txt");
+    let fh = fs.open_handle(ino, 8, None);
+
+    fs.set_mtime(ino, TimeOrNow::SpecificTime(touched())).unwrap();
+    fs.flush_handle(fh).unwrap();
+    expire(&fs, ino);
+
+    let inode = fs.revalidate_inode(ino).unwrap();
+    assert_eq!(inode.attr.mtime, touched());
+    assert_eq!(mock.contents("/kept.txt").unwrap(), b"contents");
+    // Set on its own, not by uploading the file again
+    assert!(!mock.calls().iter().any(|call| call.starts_with("write")), "{:?}", mock.calls());
+}
+
+#[test]
+fn mtime_the_backend_cannot_set_is_unsupported() {
+    let (_mock, fs) = mount(FsConfig::default());
+    let error = fs.set_mtime(1, TimeOrNow::SpecificTime(touched())).unwrap_err();
+    assert_eq!(fs.fail(Op::Setattr, "/", &error), libc::ENOTSUP);
+}
+
+// An entry as a listing or stat response has it
+fn entry(name: &str, ids: Option<(u32, u32)>) -> FileEntry {
+    let mut entry = serde_json::json!({"name": name, "is_dir": false, "size": 0, "mode": 0o644});
+    if let Some((uid, gid)) = ids {
+        entry["uid"] = uid.into();
+        entry["gid"] = gid.into();
+    }
+    serde_json::from_value(entry).unwrap()
+}
+
+#[test]
+fn owner_is_the_servers() {
+    let config = FsConfig {
+        uid: Some(70),
+        gid: Some(71),
+        ..FsConfig::default()
+    };
+    let (_mock, fs) = mount(config);
+
+    let ino = fs.get_or_create_inode("/theirs", &entry("theirs", Some((1200, 1300))));
+    let attr = fs.get_inode(ino).unwrap().attr;
+    assert_eq!((attr.uid, attr.gid), (1200, 1300));
+    // --uid and --gid only for entries without an owner
+    assert_eq!(fs.ownership_for(&entry("unowned", None), None), (70, 71));
+}
+
+#[test]
+fn id_maps_are_remote_local_pairs() {
+    let map = FsConfig::parse_id_map(" 1000:501, ,1001 : 502").unwrap();
+    assert_eq!(map, [(1000, 501), (1001, 502)].into_iter().collect());
+    assert!(FsConfig::parse_id_map("").unwrap().is_empty());
+    for bad in ["1000", "1000:x", "-1:0", "1:2:3"] {
+        assert!(FsConfig::parse_id_map(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn owner_from_server_goes_through_id_maps() {
+    let config = FsConfig {
+        uid_map: FsConfig::parse_id_map("1200:501").unwrap(),
+        gid_map: FsConfig::parse_id_map("1300:20").unwrap(),
+        ..FsConfig::default()
+    };
+    let (_mock, fs) = mount(config);
+
+    assert_eq!(fs.ownership_for(&entry("mapped", Some((1200, 1300))), None), (501, 20));
+    assert_eq!(fs.ownership_for(&entry("unmapped", Some((1201, 1301))), None), (1201, 1301));
+}
+
+#[test]
+fn local_owner_ignores_the_servers() {
+    let config = FsConfig {
+        local_owner: true,
+        ..FsConfig::default()
+    };
+    let (_mock, fs) = mount(config);
+    let creator = Caller { uid: 600, gid: 601 };
+
+    let theirs = entry("theirs", Some((1200, 1300)));
+    assert_eq!(fs.ownership_for(&theirs, None), fs.owner);
+    assert_eq!(fs.ownership_for(&theirs, Some(creator)), (600, 601));
+}
+
+#[test]
+fn owner_falls_back_one_id_at_a_time() {
+    let config = FsConfig {
+        gid: Some(71),
+        ..FsConfig::default()
+    };
+    let (_mock, fs) = mount(config);
+    let creator = Caller { uid: 600, gid: 601 };
+    let uid_only: FileEntry = serde_json::from_value(serde_json::json!(
+        {"name": "half", "is_dir": false, "size": 0, "mode": 0o644, "uid": 1200}
+    ))
+    .unwrap();
+
+    assert_eq!(fs.ownership_for(&uid_only, None), (1200, 71));
+    assert_eq!(fs.ownership_for(&uid_only, Some(creator)), (1200, 71));
+    // Without --uid, the creator and then whoever mounted
+    let unowned = entry("unowned", None);
+    assert_eq!(fs.ownership_for(&unowned, Some(creator)), (600, 71));
+    assert_eq!(fs.ownership_for(&unowned, None), (fs.owner.0, 71));
+}
+
+#[test]
+fn created_entries_show_their_creator_until_the_server_names_one() {
+    let (_mock, fs) = mount(FsConfig::default());
+    let creator = Caller { uid: 600, gid: 601 };
+    let (attr, _fh) = fs.create_and_open(creator, 1, "new.txt".as_ref(), libc::O_WRONLY).unwrap();
+    assert_eq!((attr.uid, attr.gid), (600, 601));
+
+    let ino = fs.get_or_create_inode("/new.txt", &entry("new.txt", Some((1200, 1300))));
+    let attr = fs.get_inode(ino).unwrap().attr;
+    assert_eq!((attr.uid, attr.gid), (1200, 1300));
+}
+
+#[test]
+fn creators_stay_owners_across_refreshes_unless_configured() {
+    let (_mock, fs) = mount(FsConfig::default());
+    let creator = Caller { uid: 600, gid: 601 };
+    let ino = fs.get_or_create_inode("/made", &entry("made", None));
+    fs.created_by(ino, &entry("made", None), creator);
+    let recorded = fs.get_inode(ino).unwrap().creator.unwrap();
+    assert_eq!((recorded.uid, recorded.gid), (600, 601));
+    // A listing with no owner for it keeps the creator's
+    fs.get_or_create_inode("/made", &entry("made", None));
+    let attr = fs.get_inode(ino).unwrap().attr;
+    assert_eq!((attr.uid, attr.gid), (600, 601));
+
+    let config = FsConfig {
+        uid: Some(70),
+        gid: Some(71),
+        ..FsConfig::default()
+    };
+    let (_mock, fs) = mount(config);
+    let ino = fs.get_or_create_inode("/made", &entry("made", None));
+    fs.created_by(ino, &entry("made", None), creator);
+    let attr = fs.get_inode(ino).unwrap().attr;
+    assert_eq!((attr.uid, attr.gid), (70, 71));
+}
+
+#[test]
+fn paths_normalize_to_one_spelling() {
+    let cases = [
+        ("", "/"),
+        ("/", "/"),
+        ("//", "/"),
+        ("docs", "/docs"),
+        ("/docs/", "/docs"),
+        ("//docs//a.txt", "/docs/a.txt"),
+        ("/docs/a.txt///", "/docs/a.txt"),
+        ("docs/sub/", "/docs/sub"),
+    ];
+    for (messy, canonical) in cases {
+        assert_eq!(super::normalize_path(messy), canonical, "{:?}", messy);
+    }
+}
+
+#[test]
+fn joined_paths_are_normalized() {
+    assert_eq!(super::join_path("/", "a.txt"), "/a.txt");
+    assert_eq!(super::join_path("/docs/", "a.txt"), "/docs/a.txt");
+    assert_eq!(super::join_path("//docs", "a.txt"), "/docs/a.txt");
+}
+
+#[test]
+fn one_inode_per_object_however_spelled() {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_dir("/docs");
+    mock.add_file("/docs/a.txt", b"a");
+
+    let docs = look_up(&fs, "/docs");
+    let a = look_up(&fs, "/docs/a.txt");
+    let path = fs.path_from_parent_and_name(docs, "a.txt".as_ref()).unwrap();
+    assert_eq!(path, "/docs/a.txt");
+    let listed = fs.list_directory("//docs/").unwrap();
+    assert_eq!(fs.get_or_create_inode(&path, &listed[0]), a);
+}
+
+#[test]
+fn names_that_are_not_one_entry_are_invalid() {
+    for name in ["", ".", "..", "a/b", "/", "nul\0", "../etc"] {
+        assert!(!super::valid_name(name), "{:?}", name);
+    }
+    for name in ["a", "...", ".hidden", "..b", "a.", "with space", "日本"] {
+        assert!(super::valid_name(name), "{:?}", name);
+    }
+}
+
+#[test]
+fn hostile_names_never_reach_the_server() {
+    let (mock, fs) = mount(FsConfig::default());
+
+    for name in [".", "..", "a/b"] {
+        let result = fs.create_and_open(caller(), 1, name.as_ref(), libc::O_WRONLY);
+        assert_eq!(result.unwrap_err(), libc::EINVAL, "{:?}", name);
+    }
+    let non_utf8 = std::ffi::OsStr::from_bytes(b"\xff");
+    let result = fs.create_and_open(caller(), 1, non_utf8, libc::O_WRONLY);
+    assert_eq!(result.unwrap_err(), FsError::IllegalName.errno());
+    assert!(mock.calls().is_empty(), "{:?}", mock.calls());
+}
+
+#[test]
+fn names_that_are_not_utf8_are_illegal() {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_file("/a.txt", b"a");
+    let file = look_up(&fs, "/a.txt");
+    let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+    // Before anything about the parent is checked
+    for parent in [1, file, 999] {
+        let result = fs.path_from_parent_and_name(parent, name);
+        assert_eq!(result.unwrap_err(), FsError::IllegalName, "{}", parent);
+    }
+    assert_eq!(FsError::IllegalName.errno(), libc::EILSEQ);
+}
+
+#[test]
+fn names_and_paths_are_limited_in_bytes() {
+    let config = FsConfig {
+        max_path_len: 300,
+        ..FsConfig::default()
+    };
+    let (mock, fs) = mount(config);
+    let dir = format!("/{}", "d".repeat(50));
+    mock.add_dir(&dir);
+    let dir = look_up(&fs, &dir);
+
+    // 254 bytes and a two-byte character crossing the limit
+    let name = format!("{}é", "a".repeat(254));
+    let result = fs.path_from_parent_and_name(1, name.as_ref());
+    assert_eq!(result.unwrap_err(), FsError::NameTooLong);
+    let name = "a".repeat(255);
+    assert!(fs.path_from_parent_and_name(1, name.as_ref()).is_ok());
+
+    // The directory's 52 bytes with its slashes and 248 is the longest path
+    let name = "b".repeat(248);
+    assert_eq!(fs.path_from_parent_and_name(dir, name.as_ref()).unwrap().len(), 300);
+    let name = "b".repeat(249);
+    let result = fs.path_from_parent_and_name(dir, name.as_ref());
+    assert_eq!(result.unwrap_err(), FsError::NameTooLong);
+    assert!(mock.take_calls().iter().all(|call| call.starts_with("list")));
+
+    assert!(FsConfig::check_max_path_len(256).is_ok());
+    assert!(FsConfig::check_max_path_len(255).is_err());
+}
+
+#[test]
+fn listed_names_that_cannot_be_looked_up_are_left_out() {
+    let config = FsConfig {
+        max_path_len: 300,
+        ..FsConfig::default()
+    };
+    let (mock, fs) = mount(config);
+    let dir = format!("/{}", "d".repeat(50));
+    mock.add_dir(&dir);
+    mock.add_file(&format!("{}/ok", dir), b"");
+    mock.add_file(&format!("{}/{}", dir, "b".repeat(249)), b"");
+    mock.add_file(&format!("/{}", "a".repeat(256)), b"");
+    mock.add_file(&format!("/{}", "c".repeat(255)), b"");
+
+    let names = |path: &str| -> Vec<String> {
+        let listing = fs.list_directory(path).unwrap();
+        listing.iter().map(|entry| entry.name.clone()).collect()
+    };
+    // Too long a path, too long a name
+    assert_eq!(names(&dir), ["ok"]);
+    assert_eq!(names("/"), ["c".repeat(255), "d".repeat(50)]);
+}
+
+#[test]
+fn read_ranges_at_the_boundaries() {
+    use super::{read_range, MAX_IO_SIZE};
+    assert_eq!(read_range(0, 4096), Some((0, 4096)));
+    assert_eq!(read_range(-1, 4096), None);
+    assert_eq!(read_range(i64::MIN, 1), None);
+    // Cut to end at the largest offset
+    assert_eq!(read_range(i64::MAX - 10, 4096), Some(((i64::MAX - 10) as u64, 10)));
+    assert_eq!(read_range(i64::MAX, 4096), Some((i64::MAX as u64, 0)));
+    assert_eq!(read_range(0, u32::MAX), Some((0, MAX_IO_SIZE)));
+}
+
+#[test]
+fn write_ranges_at_the_boundaries() {
+    use super::{write_range, MAX_IO_SIZE};
+    assert_eq!(write_range(0, 10), Ok((0, 10)));
+    assert_eq!(write_range(-1, 10), Err(FsError::InvalidArgument));
+    assert_eq!(write_range(i64::MIN, 0), Err(FsError::InvalidArgument));
+    assert_eq!(write_range(i64::MAX - 10, 10), Ok(((i64::MAX - 10) as u64, i64::MAX as u64)));
+    assert_eq!(write_range(i64::MAX - 10, 11), Err(FsError::FileTooLarge));
+    assert_eq!(write_range(i64::MAX, usize::MAX), Err(FsError::FileTooLarge));
+    let too_long = MAX_IO_SIZE as usize + 1;
+    assert_eq!(write_range(0, too_long), Err(FsError::FileTooLarge));
+}
+
+#[test]
+fn errors_map_by_operation() {
+    let (_mock, fs) = mount(FsConfig::default());
+    let conflict = anyhow::Error::new(FsError::AlreadyExists);
+    assert_eq!(fs.fail(Op::Mkdir, "/d", &conflict), libc::EEXIST);
+    assert_eq!(fs.fail(Op::Rmdir, "/d", &conflict), libc::ENOTEMPTY);
+
+    let gone = anyhow::Error::new(FsError::NotFound);
+    assert_eq!(fs.fail(Op::Getattr, "/f", &gone), libc::ENOENT);
+    assert_eq!(fs.fail(Op::Read, "/f", &gone), libc::ESTALE);
+}
+
+#[test]
+fn backend_errors_reach_the_caller_as_errnos() {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_dir("/docs");
+    // A file where the server has a directory
+    let result = fs.create_and_open(caller(), 1, "docs".as_ref(), libc::O_WRONLY);
+    assert_eq!(result.unwrap_err(), libc::EISDIR);
+}
+
+#[test]
+fn journaled_writes_are_replayed_on_mount() {
+    let cache = tempfile::tempdir().unwrap();
+    let journal = super::journal::Journal::open(cache.path()).unwrap();
+    journal.write("/notes", 6, b"there").unwrap();
+    journal.truncate("/notes", 11).unwrap();
+    journal.write("/gone", 0, b"lost").unwrap();
+    drop(journal);
+
+    let mut config = FsConfig {
+        journal: true,
+        ..FsConfig::default()
+    };
+    config.cache.cache_dir = Some(cache.path().to_path_buf());
+    let (mock, fs) = mount(config);
+    mock.add_file("/notes", b"hello world!");
+    fs.replay_journal();
+
+    assert_eq!(mock.contents("/notes").unwrap(), b"hello there");
+    assert!(!mock.exists("/gone"));
+    // Both are done with, replayed or dropped
+    let journal = super::journal::Journal::open(cache.path()).unwrap();
+    assert!(journal.take_recovered().is_empty());
+}
+
+// A tar of files and directories, named as given. Names are written into
+// the header as they are, the tar crate refuses to build ones leading out.
+fn tar_of(entries: &[(&str, Option<&[u8]>)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (name, data) in entries {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_mtime(1_600_000_000);
+        match data {
+            Some(data) => {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_mode(0o644);
+                header.set_size(data.len() as u64);
+            }
+            None => {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+            }
+        }
+        header.set_cksum();
+        builder.append(&header, data.unwrap_or_default()).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+fn mount_serving(tar: Vec<u8>) -> (Arc<MockBackend>, RemoteFS) {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.serve_archive(tar);
+    (mock, fs)
+}
+
+#[test]
+fn archive_fills_listings_and_blocks() {
+    let tar = tar_of(&[
+        ("./", None),
+        ("./docs/", None),
+        ("./docs/a.txt", Some(b"alpha")),
+        // Without an entry of its own for the directory
+        ("src/lib.rs", Some(b"")),
+    ]);
+    let (mock, fs) = mount_serving(tar);
+
+    let unpacked = fs.preload_archive("/", 1 << 20).unwrap().unwrap();
+    assert_eq!((unpacked.entries, unpacked.bytes), (3, 5));
+    let names = |path: &str| -> Vec<String> {
+        let listing = fs.list_directory(path).unwrap();
+        listing.iter().map(|entry| entry.name.clone()).collect()
+    };
+    assert_eq!(names("/"), ["docs", "src"]);
+    assert_eq!(names("/docs"), ["a.txt"]);
+    assert_eq!(names("/src"), ["lib.rs"]);
+    // All served from what the archive cached
+    assert_eq!(mock.calls(), ["archive /"]);
+    let ino = look_up(&fs, "/docs/a.txt");
+    assert_eq!(*fs.blocks.get(ino, 0).unwrap(), b"alpha");
+}
+
+#[test]
+fn archive_mtimes_keep_their_nanoseconds() {
+    // A PAX header for the first file only
+    let record = b"30 mtime=1600000000.123456789\n";
+    let mut header = tar::Header::new_ustar();
+    header.set_path("PaxHeader").unwrap();
+    header.set_entry_type(tar::EntryType::XHeader);
+    header.set_size(record.len() as u64);
+    header.set_cksum();
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append(&header, &record[..]).unwrap();
+    let mut tar = builder.into_inner().unwrap();
+    tar.truncate(tar.len() - 1024);
+    tar.extend(tar_of(&[("a.txt", Some(b"alpha")), ("b.txt", Some(b"beta"))]));
+    let (_mock, fs) = mount_serving(tar);
+
+    fs.preload_archive("/", 1 << 20).unwrap().unwrap();
+    let a = fs.get_inode(look_up(&fs, "/a.txt")).unwrap().attr;
+    assert_eq!(a.mtime, UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789));
+    let b = fs.get_inode(look_up(&fs, "/b.txt")).unwrap().attr;
+    assert_eq!(b.mtime, UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+}
+
+#[test]
+fn archive_with_entries_outside_the_subtree_is_rejected() {
+    for escaping in ["../secret", "docs/../../secret", "/etc/passwd"] {
+        let tar = tar_of(&[("a.txt", Some(b"alpha")), (escaping, Some(b"x"))]);
+        let (mock, fs) = mount_serving(tar);
+        mock.add_dir("/docs");
+
+        let e = fs.preload_archive("/docs", 1 << 20).err().unwrap();
+        assert!(format!("{:#}", e).contains("escapes the subtree"), "{:#}", e);
+        // Nothing of the archive is kept, not even what came before
+        let ino = fs.get_or_create_inode("/docs/a.txt", &entry("a.txt", None));
+        assert!(fs.blocks.get(ino, 0).is_none(), "{}", escaping);
+        assert!(fs.list_directory("/docs").unwrap().is_empty(), "{}", escaping);
+    }
+}
+
+#[test]
+fn corrupt_archive_is_rejected() {
+    let mut tar = tar_of(&[("a.txt", Some(b"alpha"))]);
+    // Breaks the header checksum
+    tar[0] ^= 1;
+    let (_mock, fs) = mount_serving(tar);
+    let e = fs.preload_archive("/", 1 << 20).err().unwrap();
+    assert!(format!("{:#}", e).contains("Corrupt archive"), "{:#}", e);
+}
+
+fn globs(patterns: &[&str]) -> Vec<super::Glob> {
+    patterns
+        .iter()
+        .map(|pattern| super::Glob::parse(pattern).unwrap())
+        .collect()
+}
+
+#[test]
+fn excluded_paths_and_whatever_is_below_them() {
+    let config = FsConfig {
+        exclude: globs(&["*.tmp", "/cache", "node_modules"]),
+        include: globs(&["keep.tmp"]),
+        ..FsConfig::default()
+    };
+    let (_mock, fs) = mount(config);
+    assert!(fs.is_excluded("/a.tmp"));
+    assert!(fs.is_excluded("/docs/a.tmp"));
+    assert!(fs.is_excluded("/cache"));
+    assert!(fs.is_excluded("/cache/blob"));
+    assert!(fs.is_excluded("/web/node_modules/pkg/index.js"));
+    assert!(!fs.is_excluded("/docs/cache"));
+    assert!(!fs.is_excluded("/docs/keep.tmp"));
+    assert!(!fs.is_excluded("/"));
+    assert!(!fs.is_excluded("/a.txt"));
+
+    assert_eq!(fs.check_creatable("/docs/new.tmp"), Err(FsError::NotPermitted));
+    assert_eq!(fs.check_creatable("/docs/new.txt"), Ok(()));
+}
+
+#[test]
+fn excluded_names_are_not_created_on_the_server() {
+    let config = FsConfig {
+        exclude: globs(&["*.swp"]),
+        ..FsConfig::default()
+    };
+    let (mock, fs) = mount(config);
+
+    let result = fs.create_and_open(caller(), 1, ".notes.swp".as_ref(), libc::O_WRONLY);
+    assert_eq!(result.unwrap_err(), libc::EPERM);
+    assert!(!mock.exists("/.notes.swp"));
+    assert!(!mock.calls().iter().any(|call| call.starts_with("write")));
+}
+
+#[test]
+fn casefold_lowercases_one_character_at_a_time() {
+    use super::casefold;
+    assert_eq!(casefold("README.Md"), "readme.md");
+    assert_eq!(casefold("ÀÉÎ"), "àéî");
+    // Final sigma folds with the other two
+    assert_eq!(casefold("ΟΔΟΣ"), casefold("οδος"));
+    assert_eq!(casefold("οδος"), "οδοσ");
+    // Lowercasing to several characters would change the length, kept as is
+    assert_eq!(casefold("İ"), "İ");
+    assert_eq!(casefold("straße"), "straße");
+}
+
+fn casefolding() -> FsConfig {
+    FsConfig {
+        casefold: true,
+        ..FsConfig::default()
+    }
+}
+
+#[test]
+fn names_differing_in_case_find_the_listed_entry() {
+    let entries = [entry("Notes.txt", None), entry("notes.TXT", None), entry("a", None)];
+    let (_mock, fs) = mount(casefolding());
+    let found = |name| fs.find_entry(&entries, "/", name).map(|entry| entry.name.as_str());
+    assert_eq!(found("notes.TXT"), Some("notes.TXT"));
+    assert_eq!(found("NOTES.txt"), Some("Notes.txt"));
+    assert_eq!(found("A"), Some("a"));
+    assert_eq!(found("b"), None);
+
+    let (_mock, fs) = mount(FsConfig::default());
+    assert_eq!(fs.find_entry(&entries, "/", "A").map(|entry| &entry.name), None);
+}
+
+#[test]
+fn create_in_another_case_opens_the_existing_file() {
+    let (mock, fs) = mount(casefolding());
+    mock.add_file("/Report.txt", b"theirs");
+    fs.list_directory("/").unwrap();
+
+    let (attr, _fh) = fs
+        .create_and_open(caller(), 1, "REPORT.TXT".as_ref(), libc::O_WRONLY)
+        .unwrap();
+    assert_eq!(attr.size, 6);
+    assert_eq!(mock.names("/"), ["Report.txt"]);
+}
+
+#[test]
+fn inode_looked_up_in_another_case_survives_revalidation() {
+    let (mock, fs) = mount(casefolding());
+    mock.add_file("/Report.txt", b"theirs");
+    let listing = fs.list_directory("/").unwrap();
+    let entry = fs.find_entry(&listing, "/", "REPORT.TXT").unwrap();
+    let ino = fs.get_or_create_inode("/REPORT.TXT", entry);
+
+    expire(&fs, ino);
+    let inode = fs.revalidate_inode(ino).unwrap();
+    assert!(!inode.deleted);
+    assert_eq!(inode.attr.size, 6);
+}
+
+// What `ls -l` does: one readdir, then a lookup and a getattr per name,
+// all answered from the listing with nothing asked of each file
+#[test]
+fn long_listing_is_one_request() {
+    let (mock, fs) = mount(FsConfig::default());
+    for i in 0..20 {
+        mock.add_file(&format!("/file{}", i), &vec![b'x'; i]);
+    }
+
+    let listing = fs.list_directory("/").unwrap();
+    assert_eq!(listing.len(), 20);
+    for entry in listing.iter() {
+        let path = format!("/{}", entry.name);
+        let ino = look_up(&fs, &path);
+        let inode = fs.revalidate_inode(ino).unwrap();
+        assert_eq!(inode.attr.size, entry.size);
+        assert_eq!(Some(inode.attr.mtime), mock.mtime(&path));
+    }
+    assert_eq!(mock.calls(), ["list /"]);
+}
+
+#[test]
+fn slow_backend_call_holds_up_only_its_own_operation() {
+    use super::dispatch::Dispatcher;
+    use super::trace::OpTrace;
+    use std::sync::mpsc;
+
+    let (release, held) = mpsc::channel::<()>();
+    let (started, slow_started) = mpsc::channel();
+    let held = Mutex::new(held);
+    let started = Mutex::new(started);
+    let config = FsConfig {
+        max_concurrent_ops: 2,
+        ..FsConfig::default()
+    };
+    let (mock, fs) = mount(config);
+    mock.on_call(move |_, op, path| {
+        if op == "read" && path == "/slow" {
+            started.lock().unwrap().send(()).unwrap();
+            let _ = held.lock().unwrap().recv_timeout(Duration::from_secs(10));
+        }
+    });
+    mock.add_file("/slow", b"slow");
+    mock.add_dir("/docs");
+    let slow = look_up(&fs, "/slow");
+    let _ = fs.dispatcher.set(Dispatcher::new("test", 2));
+    let trace = |op, path: &str| {
+        OpTrace::start(op, 0, 0, path.to_string(), String::new(), &fs.config(), fs.stats.clone())
+    };
+
+    let (read_done, read) = mpsc::channel();
+    fs.dispatch(trace(Op::Read, "/slow"), move |fs| {
+        let inode = fs.get_inode(slow).unwrap();
+        read_done.send(fs.read_blocks(&inode, 0, 4).unwrap()).unwrap();
+    });
+    slow_started.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    // Served while the read is still waiting for the server
+    let (listed, listing) = mpsc::channel();
+    fs.dispatch(trace(Op::Readdir, "/docs"), move |fs| {
+        listed.send(fs.list_directory("/docs").unwrap().len()).unwrap();
+    });
+    assert_eq!(listing.recv_timeout(Duration::from_secs(5)), Ok(0));
+    assert!(read.try_recv().is_err());
+
+    release.send(()).unwrap();
+    assert_eq!(read.recv_timeout(Duration::from_secs(5)).unwrap(), b"slow");
+}
+
+#[test]
+fn attributes_are_trusted_until_attr_timeout() {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_file("/notes", b"short");
+    let ino = look_up(&fs, "/notes");
+    mock.take_calls();
+
+    mock.add_file("/notes", b"longer now");
+    assert_eq!(fs.revalidate_inode(ino).unwrap().attr.size, 5);
+    assert!(mock.calls().is_empty(), "{:?}", mock.calls());
+
+    expire(&fs, ino);
+    assert_eq!(fs.revalidate_inode(ino).unwrap().attr.size, 10);
+    assert_eq!(mock.calls(), ["list /"]);
+}
+
+#[test]
+fn zero_attr_timeout_asks_every_time() {
+    let mut config = FsConfig::default();
+    config.cache.attr_timeout = Duration::ZERO;
+    config.cache.listing_timeout = Duration::ZERO;
+    let (mock, fs) = mount(config);
+    mock.add_file("/notes", b"short");
+    let ino = look_up(&fs, "/notes");
+
+    mock.add_file("/notes", b"longer now");
+    assert_eq!(fs.revalidate_inode(ino).unwrap().attr.size, 10);
+}
+
+#[test]
+fn missing_names_are_remembered_for_negative_timeout() {
+    let mut config = FsConfig::default();
+    config.cache.negative_timeout = Duration::from_millis(50);
+    let (_mock, fs) = mount(config);
+
+    assert!(!fs.is_known_missing(1, "gone"));
+    fs.remember_missing(1, "gone");
+    assert!(fs.is_known_missing(1, "gone"));
+    assert!(!fs.is_known_missing(1, "other"));
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(!fs.is_known_missing(1, "gone"));
+
+    // Created meanwhile
+    fs.remember_missing(1, "gone");
+    fs.forget_missing(1, "gone");
+    assert!(!fs.is_known_missing(1, "gone"));
+}
+
+#[test]
+fn zero_negative_timeout_remembers_nothing() {
+    let mut config = FsConfig::default();
+    config.cache.negative_timeout = Duration::ZERO;
+    let (_mock, fs) = mount(config);
+    fs.remember_missing(1, "gone");
+    assert!(!fs.is_known_missing(1, "gone"));
+}
+
+#[test]
+fn missing_names_fold_case_with_casefold() {
+    let (_mock, fs) = mount(casefolding());
+    fs.remember_missing(1, "Gone");
+    assert!(fs.is_known_missing(1, "GONE"));
+    fs.forget_missing(1, "gone");
+    assert!(!fs.is_known_missing(1, "Gone"));
+}
+
+#[test]
+fn lookup_and_readdir_share_one_listing() {
+    let mut config = FsConfig::default();
+    config.cache.listing_timeout = Duration::from_millis(50);
+    let (mock, fs) = mount(config);
+    mock.add_dir("/docs");
+    mock.add_file("/docs/a.txt", b"a");
+
+    look_up(&fs, "/docs/a.txt");
+    assert_eq!(fs.list_directory("/docs").unwrap().len(), 1);
+    assert_eq!(mock.take_calls(), ["list /docs"]);
+
+    std::thread::sleep(Duration::from_millis(60));
+    mock.add_file("/docs/b.txt", b"b");
+    assert_eq!(fs.list_directory("/docs").unwrap().len(), 2);
+    assert_eq!(mock.take_calls(), ["list /docs"]);
+}
+
+#[test]
+fn reads_are_served_from_cached_blocks() {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_file("/data", b"abcdefghij");
+    let ino = look_up(&fs, "/data");
+    let inode = fs.get_inode(ino).unwrap();
+    mock.take_calls();
+
+    assert_eq!(fs.read_blocks(&inode, 2, 4).unwrap(), b"cdef");
+    assert_eq!(fs.read_blocks(&inode, 0, 100).unwrap(), b"abcdefghij");
+    assert_eq!(fs.read_blocks(&inode, 8, 100).unwrap(), b"ij");
+    assert_eq!(mock.take_calls(), ["read /data"]);
+
+    fs.drop_file_data(ino);
+    assert_eq!(fs.read_blocks(&inode, 0, 3).unwrap(), b"abc");
+    assert_eq!(mock.take_calls(), ["read /data"]);
+}
+
+#[test]
+fn reads_at_or_past_the_end_send_nothing() {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_file("/empty", b"");
+    mock.add_file("/data", b"abc");
+    let empty = fs.get_inode(look_up(&fs, "/empty")).unwrap();
+    let data = fs.get_inode(look_up(&fs, "/data")).unwrap();
+    mock.take_calls();
+
+    assert!(fs.read_blocks(&empty, 0, 4096).unwrap().is_empty());
+    assert!(fs.read_blocks(&data, 3, 4096).unwrap().is_empty());
+    assert!(fs.read_blocks(&data, u64::MAX - 1, 1).unwrap().is_empty());
+    assert!(fs.read_blocks(&data, 0, 0).unwrap().is_empty());
+    assert!(mock.take_calls().is_empty());
+    assert_eq!(fs.read_blocks(&data, 2, 4096).unwrap(), b"c");
+}
+
+// Changes the write buffer of handle `fh`, as write and truncate do
+fn buffered(fs: &RemoteFS, fh: u64, change: impl FnOnce(&mut super::WriteBuffer)) {
+    change(&mut fs.file_handles.lock().unwrap().get_mut(&fh).unwrap().buffer);
+}
+
+#[test]
+fn writes_wait_in_the_buffer_until_flush() {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_file("/data", b"abcdefgh");
+    let ino = look_up(&fs, "/data");
+    let fh = fs.open_handle(ino, 8, None);
+    mock.take_calls();
+
+    buffered(&fs, fh, |buffer| {
+        buffer.write(0, b"AB");
+        buffer.write(2, b"C");
+    });
+    assert!(fs.has_dirty_data(ino));
+    assert!(mock.calls().is_empty());
+
+    fs.flush_handle(fh).unwrap();
+    assert!(!fs.has_dirty_data(ino));
+    assert_eq!(mock.contents("/data").unwrap(), b"ABCdefgh");
+    // Merged into one range, written in place
+    assert_eq!(mock.take_calls(), ["write /data"]);
+
+    // Nothing left to upload
+    fs.flush_handle(fh).unwrap();
+    assert!(mock.calls().is_empty());
+}
+
+#[test]
+fn unchanged_file_keeps_its_blocks_after_a_conditional_check() {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_file("/data", b"abcdefghij");
+    let ino = look_up(&fs, "/data");
+    fs.read_blocks(&fs.get_inode(ino).unwrap(), 0, 10).unwrap();
+    mock.take_calls();
+
+    fs.expire_attr(ino);
+    assert_eq!(fs.revalidate_inode(ino).unwrap().attr.size, 10);
+    assert_eq!(mock.take_calls(), ["stat /data"]);
+    assert_eq!(fs.read_blocks(&fs.get_inode(ino).unwrap(), 0, 3).unwrap(), b"abc");
+    assert!(mock.calls().is_empty());
+
+    mock.add_file("/data", b"ABCDEFGHIJ");
+    fs.expire_attr(ino);
+    fs.revalidate_inode(ino).unwrap();
+    assert_eq!(mock.take_calls(), ["stat /data", "list /"]);
+    assert_eq!(fs.read_blocks(&fs.get_inode(ino).unwrap(), 0, 3).unwrap(), b"ABC");
+    assert_eq!(mock.take_calls(), ["read /data"]);
+}
+
+#[test]
+fn truncation_reaches_the_server_on_flush() {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_file("/data", b"abcdefgh");
+    let ino = look_up(&fs, "/data");
+    let fh = fs.open_handle(ino, 8, None);
+
+    fs.truncate(ino, Some(fh), 3).unwrap();
+    assert_eq!(fs.get_inode(ino).unwrap().attr.size, 3);
+    assert_eq!(mock.contents("/data").unwrap(), b"abcdefgh");
+    fs.flush_handle(fh).unwrap();
+    assert_eq!(mock.contents("/data").unwrap(), b"abc");
+
+    // Without a handle it is uploaded right away
+    fs.truncate(ino, None, 5).unwrap();
+    assert_eq!(mock.contents("/data").unwrap(), b"abc\0\0");
+}
+
+#[test]
+fn idle_buffers_are_written_back_after_the_debounce() {
+    use std::sync::atomic::Ordering;
+
+    let (mock, fs) = mount(FsConfig {
+        write_debounce: Duration::from_millis(200),
+        ..FsConfig::default()
+    });
+    mock.add_file("/data", b"abcd");
+    let ino = look_up(&fs, "/data");
+    let fh = fs.open_handle(ino, 4, None);
+    buffered(&fs, fh, |buffer| buffer.write(0, b"AB"));
+    fs.spawn_writeback();
+
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(mock.contents("/data").unwrap(), b"abcd");
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while fs.stats.uploads_issued.load(Ordering::Relaxed) == 0 {
+        assert!(std::time::Instant::now() < deadline, "never written back");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    fs.shutdown.store(true, Ordering::Relaxed);
+    assert_eq!(mock.contents("/data").unwrap(), b"ABcd");
+    assert!(!fs.has_dirty_data(ino));
+}
+
+#[test]
+fn zero_debounce_leaves_buffers_until_flush() {
+    let (mock, fs) = mount(FsConfig {
+        write_debounce: Duration::ZERO,
+        ..FsConfig::default()
+    });
+    mock.add_file("/data", b"abcd");
+    let ino = look_up(&fs, "/data");
+    let fh = fs.open_handle(ino, 4, None);
+    buffered(&fs, fh, |buffer| buffer.write(0, b"AB"));
+    fs.spawn_writeback();
+
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(fs.has_dirty_data(ino));
+}
+
+#[test]
+fn blocks_are_chunks_of_the_backend() {
+    use super::RemoteBackend;
+
+    let (mock, fs) = mount(FsConfig::default());
+    let chunk = mock.chunk_size();
+    assert_eq!(fs.blocks.block_size(), chunk);
+    mock.add_file("/data", &vec![7; (chunk * 3 / 2) as usize]);
+    let ino = look_up(&fs, "/data");
+    mock.take_calls();
+
+    let read = fs.read_blocks(&fs.get_inode(ino).unwrap(), chunk - 1, 2).unwrap();
+    assert_eq!(read, [7, 7]);
+    // The two chunks it spans, fetched with one request and cached apart
+    assert_eq!(mock.take_calls(), ["read /data"]);
+    assert_eq!(fs.blocks.usage(), ((chunk * 3 / 2) as usize, 2));
+}
+
+#[test]
+fn preload_walks_the_subtree_and_caches_small_files() {
+    let (mock, fs) = mount(FsConfig {
+        preload: vec!["/docs".to_string()],
+        preload_data_max: 4,
+        ..FsConfig::default()
+    });
+    mock.add_dir("/docs");
+    mock.add_dir("/docs/sub");
+    mock.add_dir("/other");
+    mock.add_file("/docs/sub/small", b"abc");
+    mock.add_file("/docs/big", b"0123456789");
+    mock.add_file("/other/file", b"x");
+    fs.spawn_preload();
+
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while fs.blocks.usage().1 == 0 {
+        assert!(std::time::Instant::now() < deadline, "{:?}", mock.calls());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let mut calls = mock.take_calls();
+    calls.sort();
+    // No archive to serve, so the subtree is walked
+    assert_eq!(
+        calls,
+        ["archive /docs", "list /", "list /docs", "list /docs/sub", "read /docs/sub/small"]
+    );
+
+    let ino = look_up(&fs, "/docs/sub/small");
+    assert_eq!(fs.read_blocks(&fs.get_inode(ino).unwrap(), 0, 3).unwrap(), b"abc");
+    assert!(mock.calls().is_empty(), "{:?}", mock.calls());
+}
+
+#[test]
+fn hot_listings_are_refreshed_before_they_expire() {
+    use std::sync::atomic::Ordering;
+
+    let (mock, fs) = mount(FsConfig {
+        cache: super::CacheConfig {
+            listing_timeout: Duration::from_millis(200),
+            ..Default::default()
+        },
+        refresh_interval: Duration::from_millis(50),
+        refresh_top_n: 1,
+        ..FsConfig::default()
+    });
+    mock.add_dir("/hot");
+    mock.add_dir("/cold");
+    // Hits are halved every round, these last until the listing is due
+    for _ in 0..16 {
+        fs.list_directory("/hot").unwrap();
+    }
+    fs.list_directory("/cold").unwrap();
+    mock.add_file("/hot/new", b"");
+    mock.take_calls();
+    fs.spawn_refresher();
+
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    let refreshed = || {
+        let listings = fs.listings.lock().unwrap();
+        listings.peek("/hot").is_some_and(|listing| listing.entries.len() == 1)
+    };
+    while !refreshed() {
+        assert!(std::time::Instant::now() < deadline, "never refreshed");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    fs.shutdown.store(true, Ordering::Relaxed);
+    assert_eq!(mock.calls(), ["list /hot"]);
+}
+
+#[test]
+fn dropping_caches_spares_other_subtrees_and_dirty_data() {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_dir("/docs");
+    mock.add_dir("/other");
+    mock.add_file("/docs/a", b"aaaa");
+    mock.add_file("/docs/dirty", b"dddd");
+    mock.add_file("/other/b", b"bb");
+    let a = look_up(&fs, "/docs/a");
+    let dirty = look_up(&fs, "/docs/dirty");
+    let b = look_up(&fs, "/other/b");
+    for ino in [a, dirty, b] {
+        fs.read_blocks(&fs.get_inode(ino).unwrap(), 0, 4).unwrap();
+    }
+    let fh = fs.open_handle(dirty, 4, None);
+    buffered(&fs, fh, |buffer| buffer.write(0, b"D"));
+    let docs = look_up(&fs, "/docs");
+    fs.remember_missing(docs, "gone");
+    mock.take_calls();
+
+    // The /docs listing, /docs itself, /docs/a and the missing name
+    assert_eq!(fs.drop_caches("/docs"), (4, 4));
+    assert!(fs.blocks.contains(dirty, 0));
+    assert!(fs.blocks.contains(b, 0));
+    assert!(!fs.blocks.contains(a, 0));
+    assert!(!fs.is_known_missing(docs, "gone"));
+    fs.list_directory("/other").unwrap();
+    fs.list_directory("/docs").unwrap();
+    assert_eq!(mock.take_calls(), ["list /docs"]);
+}
+
+#[test]
+fn user_allow_other_is_read_from_fuse_conf() {
+    use super::allows_other;
+
+    assert!(allows_other("# mount_max = 1000\n  user_allow_other  # for remotefs\n"));
+    assert!(!allows_other("#user_allow_other\n"));
+    assert!(!allows_other("user_allow_other_too\n"));
+}
+
+#[test]
+fn sharing_options_reach_the_mount() {
+    use fuser::MountOption;
+
+    let options = |config: FsConfig| mount(config).1.mount_options();
+    let shared = options(FsConfig {
+        allow_other: true,
+        ..FsConfig::default()
+    });
+    // Checked against the presented owner, which is not who mounted
+    if unsafe { libc::geteuid() } == 0 {
+        let shared = shared.unwrap();
+        assert!(shared.contains(&MountOption::AllowOther));
+        assert!(shared.contains(&MountOption::DefaultPermissions));
+    }
+
+    let unchecked = options(FsConfig {
+        allow_root: true,
+        default_permissions: Some(false),
+        ..FsConfig::default()
+    });
+    if let Ok(unchecked) = unchecked {
+        assert!(unchecked.contains(&MountOption::AllowRoot));
+        assert!(!unchecked.contains(&MountOption::DefaultPermissions));
+    }
+
+    let private = options(FsConfig::default()).unwrap();
+    assert!(!private.contains(&MountOption::AllowOther));
+    assert!(!private.contains(&MountOption::DefaultPermissions));
+
+    let both = options(FsConfig {
+        allow_other: true,
+        allow_root: true,
+        ..FsConfig::default()
+    });
+    assert!(both.unwrap_err().to_string().contains("cannot be used together"));
+}
+
+#[test]
+fn read_only_mount_refuses_mutations_locally() {
+    use fuser::MountOption;
+    use std::sync::atomic::Ordering;
+
+    let (mock, fs) = mount(FsConfig {
+        read_only: true,
+        ..FsConfig::default()
+    });
+    for op in [Op::Write, Op::Create, Op::Mkdir, Op::Unlink, Op::Rename, Op::Setattr] {
+        assert!(fs.refuse_mutation(op), "{:?}", op);
+    }
+    assert_eq!(fs.stats.refused_mutations.load(Ordering::Relaxed), 6);
+    assert!(fs.mount_options().unwrap().contains(&MountOption::RO));
+    assert!(mock.calls().is_empty());
+
+    let (_mock, fs) = mount(FsConfig::default());
+    assert!(!fs.refuse_mutation(Op::Write));
+    assert!(fs.mount_options().unwrap().contains(&MountOption::RW));
+}
+
+#[test]
+fn modes_parse_as_octal_up_to_0777() {
+    assert_eq!(FsConfig::parse_mode("0644").unwrap(), 0o644);
+    assert_eq!(FsConfig::parse_mode(" 0o750 ").unwrap(), 0o750);
+    assert_eq!(FsConfig::parse_mode("22").unwrap(), 0o22);
+    for bad in ["1777", "0689", "rw-r--r--", ""] {
+        assert!(FsConfig::parse_mode(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn explicit_modes_win_over_umask_and_the_servers() {
+    let mut dir = entry("dir", None);
+    dir.is_dir = true;
+    dir.mode = 0o777;
+    let mut file = entry("file", None);
+    file.mode = 0o666;
+
+    let (_mock, fs) = mount(FsConfig {
+        umask: 0o027,
+        ..FsConfig::default()
+    });
+    let perm = |fs: &RemoteFS, entry: &FileEntry| {
+        let ino = fs.get_or_create_inode(&format!("/{}", entry.name), entry);
+        fs.get_inode(ino).unwrap().attr.perm
+    };
+    assert_eq!(perm(&fs, &dir), 0o750);
+    assert_eq!(perm(&fs, &file), 0o640);
+
+    let (_mock, fs) = mount(FsConfig {
+        umask: 0o027,
+        file_mode: Some(0o604),
+        dir_mode: Some(0o711),
+        ..FsConfig::default()
+    });
+    assert_eq!(perm(&fs, &dir), 0o711);
+    assert_eq!(perm(&fs, &file), 0o604);
+    assert_eq!(fs.get_inode(1).unwrap().attr.perm, 0o711);
+}
+
+#[test]
+fn special_files_keep_their_type_from_the_mode() {
+    use super::{device_number, entry_kind};
+    use fuser::FileType;
+
+    let special = |mode: u32| {
+        let mut entry = entry("special", None);
+        entry.mode = mode;
+        entry
+    };
+    let kinds = [
+        (libc::S_IFREG | 0o644, FileType::RegularFile),
+        (0o644, FileType::RegularFile),
+        (libc::S_IFLNK | 0o777, FileType::Symlink),
+        (libc::S_IFIFO | 0o600, FileType::NamedPipe),
+        (libc::S_IFSOCK | 0o755, FileType::Socket),
+        (libc::S_IFCHR | 0o666, FileType::CharDevice),
+        (libc::S_IFBLK | 0o660, FileType::BlockDevice),
+    ];
+    for (mode, kind) in kinds {
+        assert_eq!(entry_kind(&special(mode)), kind, "{:o}", mode);
+    }
+    // A directory whatever its mode says
+    let mut dir = special(libc::S_IFCHR);
+    dir.is_dir = true;
+    assert_eq!(entry_kind(&dir), FileType::Directory);
+
+    let device = |mode: u32, major: Option<u32>, minor: Option<u32>| FileEntry {
+        major,
+        minor,
+        ..special(mode)
+    };
+    let null = device(libc::S_IFCHR | 0o666, Some(1), Some(3));
+    assert_eq!(device_number(&null), libc::makedev(1, 3) as u32);
+    let disk = device(libc::S_IFBLK | 0o660, Some(259), Some(300));
+    assert_eq!(device_number(&disk), libc::makedev(259, 300) as u32);
+    assert_eq!(device_number(&device(libc::S_IFCHR, Some(1), None)), 0);
+    assert_eq!(device_number(&device(libc::S_IFREG, Some(1), Some(3))), 0);
+}
+
+#[test]
+fn special_files_are_shown_with_their_kind_and_device() {
+    use fuser::FileType;
+
+    let (_mock, fs) = mount(FsConfig::default());
+    let mut null = entry("null", None);
+    null.mode = libc::S_IFCHR | 0o666;
+    null.major = Some(1);
+    null.minor = Some(3);
+    let ino = fs.get_or_create_inode("/null", &null);
+    let attr = fs.get_inode(ino).unwrap().attr;
+    assert_eq!(attr.kind, FileType::CharDevice);
+    assert_eq!(attr.rdev, libc::makedev(1, 3) as u32);
+    // The type bits are not permission bits
+    assert_eq!(attr.perm, 0o666);
+    assert_eq!(fs.kind_of("/null"), Some(FileType::CharDevice));
+}
+
+fn nlink(fs: &RemoteFS, path: &str) -> u32 {
+    let ino = match path {
+        "/" => 1,
+        path => fs.inodes.read().unwrap().resolve_path(path).unwrap(),
+    };
+    fs.get_inode(ino).unwrap().attr.nlink
+}
+
+#[test]
+fn directories_count_their_subdirectories_once_listed() {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_dir("/a");
+    mock.add_dir("/b");
+    mock.add_dir("/a/sub");
+    mock.add_file("/file", b"");
+    // Unknown, which find takes as no count
+    assert_eq!(nlink(&fs, "/"), 1);
+    look_up(&fs, "/a");
+    assert_eq!(nlink(&fs, "/"), 4);
+    assert_eq!(nlink(&fs, "/a"), 1);
+    look_up(&fs, "/file");
+    assert_eq!(nlink(&fs, "/file"), 1);
+
+    fs.list_directory("/a").unwrap();
+    assert_eq!(nlink(&fs, "/a"), 3);
+    // A count known from before survives the parent being listed again
+    fs.invalidate_listing("/a");
+    fs.invalidate_listing("/");
+    look_up(&fs, "/a");
+    assert_eq!(nlink(&fs, "/a"), 3);
+}
+
+#[test]
+fn counts_from_the_server_win() {
+    let (_mock, fs) = mount(FsConfig::default());
+    let mut dir = entry("dir", None);
+    dir.is_dir = true;
+    dir.nlink = Some(7);
+    fs.get_or_create_inode("/dir", &dir);
+    assert_eq!(nlink(&fs, "/dir"), 7);
+}
+
+#[test]
+fn made_and_removed_subdirectories_adjust_the_count() {
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_dir("/a");
+    mock.add_dir("/a/one");
+    look_up(&fs, "/a");
+    fs.list_directory("/a").unwrap();
+    assert_eq!(nlink(&fs, "/a"), 3);
+
+    // Counted again from the listing while it is cached
+    mock.add_dir("/a/two");
+    fs.invalidate_listing("/a");
+    fs.list_directory("/a").unwrap();
+    fs.subdirs_changed("/a", true);
+    assert_eq!(nlink(&fs, "/a"), 4);
+
+    // Adjusted once it is not, never below 2
+    fs.invalidate_listing("/a");
+    fs.subdirs_changed("/a", true);
+    assert_eq!(nlink(&fs, "/a"), 5);
+    for _ in 0..5 {
+        fs.subdirs_changed("/a", false);
+    }
+    assert_eq!(nlink(&fs, "/a"), 2);
+
+    // An unknown count stays unknown
+    mock.add_dir("/b");
+    fs.invalidate_listing("/");
+    look_up(&fs, "/b");
+    fs.subdirs_changed("/b", true);
+    assert_eq!(nlink(&fs, "/b"), 1);
+}
+
+#[test]
+fn block_size_is_configured_and_blocks_stay_512_bytes() {
+    assert_eq!(FsConfig::default().blksize, 128 * 1024);
+    assert_eq!(super::blocks_of(0), 0);
+    assert_eq!(super::blocks_of(1), 1);
+    assert_eq!(super::blocks_of(512), 1);
+    assert_eq!(super::blocks_of(513), 2);
+
+    let config = FsConfig {
+        blksize: 4096,
+        ..FsConfig::default()
+    };
+    let (mock, fs) = mount(config);
+    mock.add_file("/a", &[0; 1000]);
+    let ino = look_up(&fs, "/a");
+    let attr = fs.get_inode(ino).unwrap().attr;
+    assert_eq!(attr.blksize, 4096);
+    assert_eq!(attr.blocks, 2);
+    assert_eq!(fs.get_inode(1).unwrap().attr.blksize, 4096);
+
+    assert!(FsConfig::check_blksize(512).is_ok());
+    assert!(FsConfig::check_blksize(16 * 1024 * 1024).is_ok());
+    for bad in [0, 256, 1000, 32 * 1024 * 1024] {
+        assert!(FsConfig::check_blksize(bad).is_err(), "{}", bad);
+    }
+}
+
+// How far `time` is from `expected`, in whole seconds
+fn seconds_off(time: SystemTime, expected: SystemTime) -> u64 {
+    match time.duration_since(expected) {
+        Ok(after) => after.as_secs(),
+        Err(before) => before.duration().as_secs(),
+    }
+}
+
+fn entry_at(name: &str, mtime: Timestamp) -> FileEntry {
+    FileEntry {
+        mtime,
+        ctime: mtime,
+        ..entry(name, None)
+    }
+}
+
+#[test]
+fn future_times_are_shifted_back_by_the_skew() {
+    let (mock, fs) = mount(FsConfig::default());
+    let now = SystemTime::now();
+    let past = Timestamp::new(1_600_000_000, 5);
+    let ahead = Timestamp::from(now + Duration::from_secs(50));
+
+    // Past times are left alone, future ones are now at the latest
+    let ino = fs.get_or_create_inode("/past", &entry_at("past", past));
+    assert_eq!(fs.get_inode(ino).unwrap().attr.mtime, SystemTime::from(past));
+    let ino = fs.get_or_create_inode("/ahead", &entry_at("ahead", ahead));
+    assert!(seconds_off(fs.get_inode(ino).unwrap().attr.mtime, now) <= 1);
+
+    mock.set_clock_skew(100);
+    let ino = fs.get_or_create_inode("/skewed", &entry_at("skewed", ahead));
+    let attr = fs.get_inode(ino).unwrap().attr;
+    assert!(seconds_off(attr.mtime, now - Duration::from_secs(50)) <= 1);
+    assert_eq!(attr.ctime, attr.mtime);
+}
+
+#[test]
+fn unknown_times_keep_what_is_shown() {
+    let (_mock, fs) = mount(FsConfig::default());
+    let ino = fs.get_or_create_inode("/a", &entry_at("a", Timestamp::UNKNOWN));
+    let shown = fs.get_inode(ino).unwrap().attr;
+    assert!(seconds_off(shown.mtime, SystemTime::now()) <= 1);
+
+    let known = Timestamp::new(1_600_000_000, 0);
+    fs.get_or_create_inode("/a", &entry_at("a", known));
+    assert_eq!(fs.get_inode(ino).unwrap().attr.mtime, SystemTime::from(known));
+    // Not taken as a change on the server
+    fs.get_or_create_inode("/a", &entry_at("a", Timestamp::UNKNOWN));
+    let attr = fs.get_inode(ino).unwrap().attr;
+    assert_eq!((attr.mtime, attr.ctime), (SystemTime::from(known), SystemTime::from(known)));
+}
+
+#[test]
+fn mkdir_sees_anything_freshly_listed_at_the_path() {
+    let mut config = FsConfig::default();
+    config.cache.listing_timeout = Duration::from_millis(100);
+    let (mock, fs) = mount(config);
+    mock.add_dir("/docs");
+    mock.add_file("/a.txt", b"a");
+    assert!(!fs.listed_fresh("/docs"));
+
+    fs.list_directory("/").unwrap();
+    assert!(fs.listed_fresh("/docs"));
+    assert!(fs.listed_fresh("/a.txt"));
+    assert!(!fs.listed_fresh("/new"));
+    assert!(!fs.listed_fresh("/docs/new"));
+
+    // An old listing leaves it to the server
+    std::thread::sleep(Duration::from_millis(150));
+    assert!(!fs.listed_fresh("/docs"));
+    let error = fs.create_directory("/docs").unwrap_err();
+    assert_eq!(FsError::from_backend(&error), FsError::AlreadyExists);
+}
+
+#[test]
+fn renamed_directories_take_their_listings_along() {
+    let (mock, fs) = mount(FsConfig::default());
+    let dirs = ["/a", "/a/sub", "/ab"];
+    for dir in dirs {
+        mock.add_dir(dir);
+    }
+    mock.add_file("/a/sub/f", b"");
+    for dir in dirs {
+        fs.list_directory(dir).unwrap();
+    }
+
+    fs.move_listings("/a", "/b");
+    let listings = fs.listings.lock().unwrap();
+    let cached = |path: &str| listings.peek(path).map(|listing| listing.entries.len());
+    assert_eq!(cached("/b"), Some(1));
+    assert_eq!(cached("/b/sub"), Some(1));
+    assert_eq!(cached("/a"), None);
+    assert_eq!(cached("/a/sub"), None);
+    // Only sharing a prefix
+    assert_eq!(cached("/ab"), Some(0));
+}
+
+#[test]
+fn listings_fetched_across_an_invalidation_are_not_cached() {
+    use std::sync::atomic::Ordering;
+
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_dir("/docs");
+    mock.add_file("/docs/a.txt", b"a");
+    // A rename finishing while the listing is on its way
+    let epoch = fs.listing_epoch.clone();
+    mock.on_call(move |_, op, _| {
+        if op == "list" {
+            epoch.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+
+    assert_eq!(fs.list_directory("/docs").unwrap().len(), 1);
+    assert!(fs.listings.lock().unwrap().peek("/docs").is_none());
+    mock.take_calls();
+    fs.list_directory("/docs").unwrap();
+    assert_eq!(mock.take_calls(), ["list /docs"]);
+}
+
+#[test]
+fn rename_targets_are_checked_like_rename2() {
+    let (mock, fs) = mount(FsConfig::default());
+    for dir in ["/dir", "/dir/sub", "/empty", "/full"] {
+        mock.add_dir(dir);
+    }
+    mock.add_file("/full/x", b"");
+    mock.add_file("/file", b"");
+    mock.add_file("/other", b"");
+    for path in ["/dir", "/dir/sub", "/empty", "/full", "/file", "/other"] {
+        fs.invalidate_listing(super::split_path(path).0);
+        look_up(&fs, path);
+    }
+
+    let table = [
+        ("/file", "/other", Ok(())),
+        ("/file", "/new", Ok(())),
+        ("/dir", "/empty", Ok(())),
+        ("/dir", "/dir", Ok(())),
+        ("/file", "/empty", Err(FsError::IsADirectory)),
+        ("/dir", "/file", Err(FsError::NotADirectory)),
+        ("/dir", "/full", Err(FsError::NotEmpty)),
+        ("/dir", "/dir/sub/inside", Err(FsError::InvalidArgument)),
+        ("/dir", "/dir/sub", Err(FsError::InvalidArgument)),
+        // Only sharing a prefix
+        ("/dir", "/directory", Ok(())),
+    ];
+    for (from, to, expected) in table {
+        assert_eq!(fs.check_rename(from, to), expected, "{} -> {}", from, to);
+    }
+    assert!(!mock.calls().iter().any(|call| call.starts_with("rename")));
+}
+
+#[test]
+fn removed_paths_take_everything_below_along() {
+    let (mock, fs) = mount(FsConfig::default());
+    for dir in ["/docs", "/docs/sub", "/docsx"] {
+        mock.add_dir(dir);
+    }
+    mock.add_file("/docs/sub/a", b"alpha");
+    mock.add_file("/docsx/b", b"beta");
+    let sub = look_up(&fs, "/docs/sub");
+    let a = look_up(&fs, "/docs/sub/a");
+    let b = look_up(&fs, "/docsx/b");
+    for ino in [a, b] {
+        fs.read_blocks(&fs.get_inode(ino).unwrap(), 0, 4).unwrap();
+    }
+    fs.list_directory("/docs").unwrap();
+    fs.remember_missing(sub, "gone");
+
+    fs.purge_subtree("/docs");
+    for path in ["/docs", "/docs/sub", "/docs/sub/a"] {
+        assert!(fs.inodes.read().unwrap().resolve_path(path).is_none(), "{}", path);
+    }
+    assert!(fs.get_inode(a).is_none());
+    assert!(!fs.blocks.contains(a, 0));
+    assert!(fs.listings.lock().unwrap().peek("/docs").is_none());
+    assert!(fs.listings.lock().unwrap().peek("/docs/sub").is_none());
+    assert!(!fs.is_known_missing(sub, "gone"));
+
+    // Only sharing a prefix
+    assert!(fs.blocks.contains(b, 0));
+    assert!(fs.listings.lock().unwrap().peek("/docsx").is_some());
+}
+
+#[test]
+fn added_and_removed_entries_bump_the_directory_times() {
+    let (_mock, fs) = mount(FsConfig::default());
+    let old = Timestamp::new(1_600_000_000, 0);
+    let dir = FileEntry {
+        is_dir: true,
+        ..entry_at("docs", old)
+    };
+    let ino = fs.get_or_create_inode("/docs", &dir);
+    let before = SystemTime::now();
+    fs.entries_changed("/docs");
+    let inode = fs.get_inode(ino).unwrap();
+    assert!(inode.attr.mtime >= before && inode.attr.ctime == inode.attr.mtime);
+    assert!(inode.touched);
+    assert!(!inode.is_fresh(fs.config().cache.attr_timeout));
+
+    // Listed times from before the change do not win
+    fs.get_or_create_inode("/docs", &dir);
+    assert_eq!(fs.get_inode(ino).unwrap().attr.mtime, inode.attr.mtime);
+    // Newer ones do, from then on
+    std::thread::sleep(Duration::from_millis(5));
+    let newer = Timestamp::now();
+    let dir = FileEntry {
+        is_dir: true,
+        ..entry_at("docs", newer)
+    };
+    fs.get_or_create_inode("/docs", &dir);
+    let inode = fs.get_inode(ino).unwrap();
+    assert_eq!(inode.attr.mtime, SystemTime::from(newer));
+    assert!(!inode.touched);
+
+    // Unknown directories are left alone
+    fs.entries_changed("/missing");
+}
+
+#[test]
+fn files_deleted_while_open_are_served_from_the_cache() {
+    use super::RemoteBackend;
+
+    let (mock, fs) = mount(FsConfig::default());
+    mock.add_file("/open", &vec![b'x'; 3 << 20]);
+    mock.add_file("/closed", b"closed");
+    let ino = look_up(&fs, "/open");
+    let closed = look_up(&fs, "/closed");
+    let fh = fs.open_handle(ino, 3 << 20, None);
+    fs.read_blocks(&fs.get_inode(ino).unwrap(), 0, 4).unwrap();
+    mock.delete("/open").unwrap();
+    mock.delete("/closed").unwrap();
+    fs.invalidate_listing("/");
+    fs.expire_attr(ino);
+    fs.expire_attr(closed);
+    mock.take_calls();
+
+    assert!(fs.revalidate_inode(closed).is_none());
+    let inode = fs.revalidate_inode(ino).unwrap();
+    assert!(inode.deleted);
+    assert_eq!(inode.attr.nlink, 0);
+    assert!(fs.inodes.read().unwrap().resolve_path("/open").is_none());
+    // Still the same on the next getattr, without asking the server
+    mock.take_calls();
+    assert!(fs.revalidate_inode(ino).unwrap().deleted);
+
+    assert_eq!(fs.read_blocks(&inode, 0, 4).unwrap(), b"xxxx");
+    let error = fs.read_blocks(&inode, 2 << 20, 4).unwrap_err();
+    assert_eq!(FsError::from_backend(&error), FsError::Stale);
+    assert!(mock.take_calls().is_empty());
+    // A read finding it gone on the server fails the same way
+    let gone = anyhow::Error::new(FsError::NotFound);
+    assert_eq!(fs.fail(Op::Read, "/open", &gone), libc::ESTALE);
+
+    // Buffered writes have nowhere to go
+    buffered(&fs, fh, |buffer| buffer.write(0, b"y"));
+    let error = fs.flush_handle(fh).unwrap_err();
+    assert_eq!(FsError::from_backend(&error), FsError::Stale);
+
+    // Gone with the last handle
+    fs.drop_if_deleted(ino);
+    assert!(fs.get_inode(ino).is_some());
+    fs.file_handles.lock().unwrap().remove(&fh);
+    fs.drop_if_deleted(ino);
+    assert!(fs.get_inode(ino).is_none());
+    assert!(!fs.blocks.contains(ino, 0));
+}
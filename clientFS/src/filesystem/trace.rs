@@ -0,0 +1,264 @@
+use std::cell::Cell;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::stats::{FsStats, Op};
+use super::FsConfig;
+use crate::api_client::{new_trace_id, take_thread_requests, with_caller, with_trace_id};
+
+thread_local! {
+    // Errno the operation running on this thread replied with, 0 for success
+    static ERRNO: Cell<i32> = const { Cell::new(0) };
+}
+
+// Notes `errno` as the outcome of the traced operation on this thread and
+// passes it through, for use as `reply.error(replied(errno))`
+pub fn replied(errno: i32) -> i32 {
+    ERRNO.set(errno);
+    errno
+}
+
+// Times a FUSE operation for the latency statistics and, if asked to, writes
+// a log line for it when dropped at the end of the operation, on the thread
+// that ran it. Only operation names,
+// inode numbers, paths, offsets and sizes go in, never data or headers.
+// The line reads the same with any logger, and also carries its fields as
+// key-values for the JSON log format. Built with tracing, the operation is
+// also a span, parent of the spans of its HTTP requests.
+pub struct OpTrace {
+    op: Op,
+    // The kernel's id of the request
+    request_id: u64,
+    // Who made it, for waits to notice when it is interrupted
+    caller: u32,
+    // Sent with the operation's HTTP requests as X-Request-Id and traceparent
+    trace_id: u128,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    path: String,
+    args: String,
+    label: Option<String>,
+    started: Instant,
+    // Lines are debug, or warn once the operation took this long
+    log_all: bool,
+    slow: Option<Duration>,
+    stats: Arc<FsStats>,
+}
+
+impl OpTrace {
+    pub fn start(
+        op: Op,
+        request_id: u64,
+        caller: u32,
+        path: String,
+        args: String,
+        config: &FsConfig,
+        stats: Arc<FsStats>,
+    ) -> Self {
+        let trace_id = new_trace_id();
+        stats.started();
+        Self {
+            op,
+            request_id,
+            caller,
+            trace_id,
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(
+                "fuse",
+                op = op.name(),
+                request_id,
+                trace_id = %format!("{:032x}", trace_id),
+                path = %path,
+                args = %args,
+                mount = config.label.as_deref().unwrap_or_default(),
+                errno = tracing::field::Empty,
+                duration_ms = tracing::field::Empty,
+            ),
+            path,
+            args,
+            label: config.label.clone(),
+            started: Instant::now(),
+            log_all: config.trace_ops,
+            slow: config.slow_op,
+            stats,
+        }
+    }
+
+    // Runs the operation inside its trace, on whatever thread serves it
+    pub fn run<R>(&self, f: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "tracing")]
+        let _entered = self.span.enter();
+        with_trace_id(self.trace_id, || with_caller(self.caller, f))
+    }
+}
+
+impl Drop for OpTrace {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        // Taken even when not logged, so the next operation starts from zero
+        let requests = take_thread_requests();
+        let errno = ERRNO.take();
+        let duration_ms = elapsed.as_secs_f64() * 1000.0;
+        self.stats.finished(self.op, elapsed);
+        #[cfg(feature = "tracing")]
+        {
+            self.span.record("errno", errno);
+            self.span.record("duration_ms", duration_ms);
+        }
+
+        let level = if self.slow.is_some_and(|slow| elapsed >= slow) {
+            log::Level::Warn
+        } else if self.log_all {
+            log::Level::Debug
+        } else {
+            return;
+        };
+        let mount = self.label.as_deref().unwrap_or_default();
+        let prefix = self.label.as_deref().map(|l| format!("[{}] ", l));
+        log::log!(
+            level,
+            op = self.op.name(),
+            request_id = self.request_id,
+            trace_id = format!("{:032x}", self.trace_id).as_str(),
+            path = self.path.as_str(),
+            requests = requests,
+            duration_ms = duration_ms,
+            errno = errno,
+            mount = mount;
+            "{}op={} request_id={} path={:?} {} requests={} duration_ms={:.3} errno={}",
+            prefix.unwrap_or_default(),
+            self.op.name(),
+            self.request_id,
+            self.path,
+            self.args,
+            requests,
+            duration_ms,
+            errno
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::kv::{Error as KvError, Key, Value, VisitSource};
+    use log::{Level, Log, Metadata, Record};
+    use std::collections::BTreeMap;
+    use std::sync::{Mutex, Once};
+    use std::thread::{self, ThreadId};
+
+    struct Line {
+        thread: ThreadId,
+        level: Level,
+        message: String,
+        fields: BTreeMap<String, String>,
+    }
+
+    // Keeps every record, tests pick those of their own thread
+    struct Capture(Mutex<Vec<Line>>);
+
+    struct Fields(BTreeMap<String, String>);
+
+    impl<'kvs> VisitSource<'kvs> for Fields {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), KvError> {
+            self.0.insert(key.to_string(), value.to_string());
+            Ok(())
+        }
+    }
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            let mut fields = Fields(BTreeMap::new());
+            let _ = record.key_values().visit(&mut fields);
+            self.0.lock().unwrap().push(Line {
+                thread: thread::current().id(),
+                level: record.level(),
+                message: record.args().to_string(),
+                fields: fields.0,
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+    // Runs an operation traced with `config` replying `errno`, and returns
+    // the lines it logged
+    fn traced(config: &FsConfig, stats: &Arc<FsStats>, errno: i32) -> Vec<Line> {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CAPTURE).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+
+        let path = "/docs/a.txt".to_string();
+        let args = "offset=4096 size=512".to_string();
+        let trace = OpTrace::start(Op::Read, 42, 1000, path, args, config, stats.clone());
+        trace.run(|| replied(errno));
+        drop(trace);
+
+        let me = thread::current().id();
+        let mut lines = CAPTURE.0.lock().unwrap();
+        let (mine, others) = lines.drain(..).partition(|line| line.thread == me);
+        *lines = others;
+        mine
+    }
+
+    #[test]
+    fn operations_are_logged_only_when_asked() {
+        let stats = Arc::new(FsStats::default());
+        assert!(traced(&FsConfig::default(), &stats, 0).is_empty());
+
+        let config = FsConfig {
+            trace_ops: true,
+            label: Some("work".to_string()),
+            ..FsConfig::default()
+        };
+        let lines = traced(&config, &stats, libc::ENOENT);
+        assert_eq!(lines.len(), 1);
+        let line = &lines[0];
+        assert_eq!(line.level, Level::Debug);
+        assert!(line.message.starts_with("[work] op=read request_id=42"));
+        assert!(line.message.contains("path=\"/docs/a.txt\" offset=4096 size=512"));
+        assert!(line.message.ends_with("errno=2"));
+        assert_eq!(line.fields["op"], "read");
+        assert_eq!(line.fields["path"], "/docs/a.txt");
+        assert_eq!(line.fields["errno"], "2");
+        assert_eq!(line.fields["mount"], "work");
+        assert_eq!(line.fields["trace_id"].len(), 32);
+    }
+
+    #[test]
+    fn slow_operations_are_warned_about() {
+        let stats = Arc::new(FsStats::default());
+        let config = FsConfig {
+            slow_op: Some(Duration::ZERO),
+            ..FsConfig::default()
+        };
+        let lines = traced(&config, &stats, 0);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].level, Level::Warn);
+        assert_eq!(lines[0].fields["errno"], "0");
+
+        let config = FsConfig {
+            slow_op: Some(Duration::from_secs(3600)),
+            ..FsConfig::default()
+        };
+        assert!(traced(&config, &stats, 0).is_empty());
+    }
+
+    #[test]
+    fn every_operation_is_timed() {
+        let stats = Arc::new(FsStats::default());
+        traced(&FsConfig::default(), &stats, 0);
+        traced(&FsConfig::default(), &stats, libc::EIO);
+        let snapshot = stats.snapshot(Default::default(), 0, false, false, 0, Default::default());
+        assert_eq!(snapshot.latency["read"].count, 2);
+        assert_eq!(snapshot.ops_in_flight, 0);
+    }
+}
@@ -0,0 +1,327 @@
+use fuser::{FileType, Notifier};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, OnceLock, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::cache::{BlockCache, LruCache};
+use super::inode_table::InodeTable;
+use super::stats::FsStats;
+use super::{split_path, CacheConfig, CachedListing, FsConfig, OpenFile, RemoteFS, TTL};
+
+// How often the table is checked against max_inodes, when set
+const INODE_CAP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Background task that drops expired entries and keeps the inode table within
+// max_attr_entries and max_inodes. It only holds weak references, so it stops
+// on its own once the filesystem is dropped. Write buffers live on the file
+// handles and are never touched here.
+pub struct CacheTrimmer {
+    // Read on every pass, timeouts and budgets may be reloaded
+    config: Weak<RwLock<Arc<FsConfig>>>,
+    inodes: Weak<RwLock<InodeTable>>,
+    negative: Weak<Mutex<HashMap<(u64, String), Instant>>>,
+    listings: Weak<Mutex<LruCache<String, CachedListing>>>,
+    blocks: Weak<BlockCache>,
+    file_handles: Weak<Mutex<HashMap<u64, OpenFile>>>,
+    notifier: Weak<OnceLock<Arc<Notifier>>>,
+    stats: Weak<FsStats>,
+}
+
+impl CacheTrimmer {
+    pub fn new(fs: &RemoteFS) -> Self {
+        Self {
+            config: Arc::downgrade(&fs.config),
+            inodes: Arc::downgrade(&fs.inodes),
+            negative: Arc::downgrade(&fs.negative),
+            listings: Arc::downgrade(&fs.listings),
+            blocks: Arc::downgrade(&fs.blocks),
+            file_handles: Arc::downgrade(&fs.file_handles),
+            notifier: Arc::downgrade(&fs.notifier),
+            stats: Arc::downgrade(&fs.stats),
+        }
+    }
+
+    // None once the filesystem is gone
+    fn config(&self) -> Option<CacheConfig> {
+        Some(self.config.upgrade()?.read().unwrap().cache.clone())
+    }
+
+    // The schedule is fixed at mount, max_inodes and trim_interval are not
+    // reloadable
+    pub fn spawn(self) {
+        let Some(config) = self.config() else {
+            return;
+        };
+        let tick = match config.max_inodes {
+            Some(_) => config.trim_interval.min(INODE_CAP_INTERVAL),
+            None => config.trim_interval,
+        };
+
+        let result = thread::Builder::new()
+            .name("cache-trim".to_string())
+            .spawn(move || {
+                let mut trimmed_at = Instant::now();
+                loop {
+                    thread::sleep(tick);
+                    if config.max_inodes.is_some() && self.evict_inodes(&config).is_none() {
+                        break;
+                    }
+                    if trimmed_at.elapsed() < config.trim_interval {
+                        continue;
+                    }
+                    trimmed_at = Instant::now();
+                    if self.trim().is_none() {
+                        break;
+                    }
+                }
+            });
+
+        if let Err(e) = result {
+            log::warn!("Failed to start cache trimming: {}", e);
+        }
+    }
+
+    // Returns None once the filesystem is gone
+    fn trim(&self) -> Option<()> {
+        let now = Instant::now();
+
+        self.negative
+            .upgrade()?
+            .lock()
+            .unwrap()
+            .retain(|_, expires| *expires > now);
+
+        let config = self.config()?;
+        let listing_timeout = config.listing_timeout;
+        self.listings
+            .upgrade()?
+            .lock()
+            .unwrap()
+            .retain(|_, listing| listing.fetched_at.elapsed() < listing_timeout);
+
+        self.trim_inodes(&config);
+        Some(())
+    }
+
+    fn trim_inodes(&self, config: &CacheConfig) -> Option<()> {
+        let inodes = self.inodes.upgrade()?;
+        let blocks = self.blocks.upgrade()?;
+        let file_handles = self.file_handles.upgrade()?;
+
+        let mut inodes = inodes.write().unwrap();
+        let excess = inodes.len().saturating_sub(config.max_attr_entries);
+        if excess == 0 {
+            return Some(());
+        }
+
+        // Only closed regular files are dropped, and only after the kernel's own
+        // entry expired, since the next lookup recreates them from the listing
+        let open: HashSet<u64> = file_handles
+            .lock()
+            .unwrap()
+            .values()
+            .map(|handle| handle.ino)
+            .collect();
+        let idle = config.attr_timeout.max(TTL);
+        let mut candidates: Vec<(Instant, u64)> = inodes
+            .values()
+            .filter(|inode| {
+                inode.attr.kind == FileType::RegularFile
+                    && !open.contains(&inode.ino)
+                    && !inode.is_fresh(idle)
+            })
+            .map(|inode| (inode.fetched_at, inode.ino))
+            .collect();
+        candidates.sort();
+
+        let mut dropped = 0;
+        for (_, ino) in candidates.into_iter().take(excess) {
+            if inodes.remove_ino(ino).is_some() {
+                blocks.invalidate(ino);
+                dropped += 1;
+            }
+        }
+        log::debug!("Dropped {} idle inodes, {} remain", dropped, inodes.len());
+
+        Some(())
+    }
+    // Brings the table back under max_inodes, down to a tenth below it so this
+    // does not run again right away. Least recently used inodes the kernel holds
+    // no references to are dropped here. For the rest the kernel is asked to
+    // drop its entries, and they are evicted in forget once it lets go of them.
+    fn evict_inodes(&self, config: &CacheConfig) -> Option<()> {
+        let max_inodes = config.max_inodes?;
+        let inodes = self.inodes.upgrade()?;
+        let blocks = self.blocks.upgrade()?;
+        let file_handles = self.file_handles.upgrade()?;
+        let stats = self.stats.upgrade()?;
+
+        let mut stale_entries = Vec::new();
+        let mut evicted = 0;
+        {
+            let mut inodes = inodes.write().unwrap();
+            if inodes.len() <= max_inodes {
+                return Some(());
+            }
+            let mut excess = inodes.len() - (max_inodes - max_inodes / 10);
+
+            // Open handles may hold buffered writes, so their inodes stay
+            let open: HashSet<u64> = file_handles
+                .lock()
+                .unwrap()
+                .values()
+                .map(|handle| handle.ino)
+                .collect();
+            let mut candidates: Vec<(bool, Instant, u64)> = inodes
+                .values()
+                .filter(|inode| inode.ino != 1 && !open.contains(&inode.ino))
+                .map(|inode| (inode.lookups > 0, inode.used_at, inode.ino))
+                .collect();
+            candidates.sort();
+
+            for (referenced, _, ino) in candidates {
+                if excess == 0 {
+                    break;
+                }
+                excess -= 1;
+
+                if !referenced {
+                    if inodes.remove_ino(ino).is_some() {
+                        blocks.invalidate(ino);
+                        evicted += 1;
+                    }
+                    continue;
+                }
+                let Some(inode) = inodes.get(ino) else {
+                    continue;
+                };
+                let (parent, name) = split_path(&inode.path);
+                if let Some(parent) = inodes.resolve_path(parent) {
+                    stale_entries.push((parent, name.to_string()));
+                }
+            }
+            log::debug!(
+                "Evicted {} inodes, asked the kernel to forget {}, {} remain",
+                evicted,
+                stale_entries.len(),
+                inodes.len()
+            );
+        }
+        stats.inodes_evicted.fetch_add(evicted, Ordering::Relaxed);
+
+        // The kernel answers with forget, which needs the inode lock released
+        if let Some(notifier) = self.notifier.upgrade()?.get() {
+            for (parent, name) in stale_entries {
+                if let Err(e) = notifier.inval_entry(parent, OsStr::new(&name)) {
+                    log::debug!("Failed to invalidate kernel entry {}: {}", name, e);
+                }
+            }
+        }
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockBackend;
+
+    // A filesystem over files "/0" to "/{count - 1}", all looked up
+    fn with_files(config: FsConfig, count: usize) -> (RemoteFS, Vec<u64>) {
+        let mock = Arc::new(MockBackend::new());
+        for n in 0..count {
+            mock.add_file(&format!("/{}", n), b"data");
+        }
+        let fs = RemoteFS::with_backend(mock, config);
+        let listing = fs.list_directory("/").unwrap();
+        let inos = listing
+            .iter()
+            .map(|entry| fs.get_or_create_inode(&format!("/{}", entry.name), entry))
+            .collect();
+        (fs, inos)
+    }
+
+    #[test]
+    fn expired_entries_are_dropped() {
+        let mut config = FsConfig::default();
+        config.cache.listing_timeout = Duration::from_millis(20);
+        config.cache.negative_timeout = Duration::from_millis(20);
+        let (fs, _) = with_files(config, 1);
+        fs.remember_missing(1, "gone");
+        let trimmer = CacheTrimmer::new(&fs);
+
+        trimmer.trim().unwrap();
+        assert_eq!(fs.listings.lock().unwrap().len(), 1);
+        assert_eq!(fs.negative.lock().unwrap().len(), 1);
+
+        thread::sleep(Duration::from_millis(30));
+        trimmer.trim().unwrap();
+        assert_eq!(fs.listings.lock().unwrap().len(), 0);
+        assert!(fs.negative.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn idle_closed_files_go_over_max_attr_entries() {
+        let mut config = FsConfig::default();
+        config.cache.max_attr_entries = 3;
+        let (fs, inos) = with_files(config, 4);
+        // The root and four files, all fresh
+        let trimmer = CacheTrimmer::new(&fs);
+        trimmer.trim().unwrap();
+        assert_eq!(fs.inodes.read().unwrap().len(), 5);
+
+        for &ino in &inos {
+            fs.expire_attr(ino);
+        }
+        // Open files are kept however idle
+        fs.open_handle(inos[0], 4, None);
+        trimmer.trim().unwrap();
+        let inodes = fs.inodes.read().unwrap();
+        assert_eq!(inodes.len(), 3);
+        assert!(inodes.get(1).is_some());
+        assert!(inodes.get(inos[0]).is_some());
+    }
+
+    #[test]
+    fn least_recently_used_inodes_go_over_max_inodes() {
+        let mut config = FsConfig::default();
+        config.cache.max_inodes = Some(10);
+        // The root and 12 files, used in order
+        let (fs, inos) = with_files(config.clone(), 12);
+        {
+            let mut inodes = fs.inodes.write().unwrap();
+            let base = Instant::now();
+            for (n, &ino) in inos.iter().enumerate() {
+                inodes.get_mut(ino).unwrap().used_at = base + Duration::from_millis(n as u64);
+            }
+            // Held by the kernel, however long ago it was used
+            inodes.looked_up(inos[1]);
+            inodes.get_mut(inos[1]).unwrap().used_at = base;
+        }
+        fs.open_handle(inos[0], 4, None);
+
+        // Down to a tenth below the cap
+        CacheTrimmer::new(&fs).evict_inodes(&config.cache).unwrap();
+        let inodes = fs.inodes.read().unwrap();
+        assert_eq!(inodes.len(), 9);
+        for &ino in &inos[2..6] {
+            assert!(inodes.get(ino).is_none());
+        }
+        for &ino in [1, inos[0], inos[1]].iter().chain(&inos[6..]) {
+            assert!(inodes.get(ino).is_some());
+        }
+        assert_eq!(fs.stats.inodes_evicted.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn trimming_stops_with_the_filesystem() {
+        let (fs, _) = with_files(FsConfig::default(), 1);
+        let trimmer = CacheTrimmer::new(&fs);
+        drop(fs);
+        assert!(trimmer.trim().is_none());
+    }
+}
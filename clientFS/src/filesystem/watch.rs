@@ -0,0 +1,272 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::stats::WatchStats;
+use super::{join_path, normalize_path, RemoteFS, SIGNAL_POLL_INTERVAL};
+use crate::api_client::FileEntry;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+// Longest wait between rounds of a watch while the server is failing
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// A directory listed again on a timer, for servers that push no changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watch {
+    pub path: String,
+    pub interval: Duration,
+}
+
+impl Watch {
+    // Parses `--watch <path>[:interval]`, the interval in seconds. A suffix
+    // that is not a number is part of the path.
+    pub fn parse(value: &str) -> Result<Self> {
+        let value = value.trim();
+        let (path, interval) = match value.rsplit_once(':') {
+            Some((path, secs)) => match secs.parse::<f64>() {
+                Ok(secs) => (path, Some(secs)),
+                Err(_) => (value, None),
+            },
+            None => (value, None),
+        };
+        let interval = match interval {
+            Some(secs) => {
+                anyhow::ensure!(
+                    secs.is_finite() && secs > 0.0,
+                    "Invalid watch interval '{}', expected positive seconds",
+                    secs
+                );
+                Duration::from_secs_f64(secs)
+            }
+            None => DEFAULT_INTERVAL,
+        };
+        anyhow::ensure!(!path.is_empty(), "Missing path in watch '{}'", value);
+        Ok(Self {
+            path: normalize_path(path),
+            interval,
+        })
+    }
+}
+
+// Whether the server reports a different entry under the same name
+fn changed(before: &FileEntry, after: &FileEntry) -> bool {
+    before.is_dir != after.is_dir
+        || before.size != after.size
+        || before.mtime != after.mtime
+        || before.id != after.id
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64())
+}
+
+impl RemoteFS {
+    // Lists the watched directories again every interval, conditionally when
+    // the listing has a version, and applies what changed to the caches. A
+    // watch backs off while the circuit breaker is open or its listing
+    // fails, and all of them stop at unmount.
+    pub(super) fn spawn_watches(&self) {
+        let watches = self.config().watch.clone();
+        if watches.is_empty() {
+            return;
+        }
+        {
+            let mut stats = self.stats.watches.lock().unwrap();
+            for watch in &watches {
+                stats.insert(
+                    watch.path.clone(),
+                    WatchStats {
+                        interval_seconds: watch.interval.as_secs_f64(),
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+
+        let fs = self.clone();
+        thread::spawn(move || {
+            let mut delays: Vec<Duration> = watches.iter().map(|watch| watch.interval).collect();
+            let mut due = vec![Instant::now(); watches.len()];
+            while !fs.shutdown.load(Ordering::Relaxed) {
+                thread::sleep(SIGNAL_POLL_INTERVAL);
+                for (i, watch) in watches.iter().enumerate() {
+                    if Instant::now() < due[i] {
+                        continue;
+                    }
+                    let refreshed = if fs.backend.is_healthy() && !fs.is_offline() {
+                        fs.refresh_watch(&watch.path)
+                    } else {
+                        Err(anyhow::anyhow!("server unavailable"))
+                    };
+                    match refreshed {
+                        Ok(changes) => {
+                            delays[i] = watch.interval;
+                            let mut stats = fs.stats.watches.lock().unwrap();
+                            if let Some(stats) = stats.get_mut(&watch.path) {
+                                stats.last_refresh = Some(unix_now());
+                                stats.changes += changes;
+                            }
+                        }
+                        Err(e) => {
+                            log::debug!("Failed to refresh watched {}: {:#}", watch.path, e);
+                            delays[i] = (delays[i] * 2).min(MAX_BACKOFF.max(watch.interval));
+                        }
+                    }
+                    due[i] = Instant::now() + delays[i];
+                }
+            }
+        });
+    }
+
+    // Lists `path` again and invalidates the entries that were added,
+    // removed or changed since the cached listing, which is replaced so the
+    // next readdir needs no request. Returns how many entries changed.
+    fn refresh_watch(&self, path: &str) -> Result<u64> {
+        let cached = self
+            .listings
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(|listing| (listing.entries.clone(), listing.version.clone()));
+        let expired = cached.as_ref().and_then(|(entries, version)| {
+            version.clone().map(|version| (version, entries.clone()))
+        });
+        let entries = self.fetch_listing(path, expired)?;
+
+        // Known entries take the new attributes, dropping contents that changed
+        for entry in entries.iter() {
+            let child = join_path(path, &entry.name);
+            if self.inodes.read().unwrap().resolve_path(&child).is_some() {
+                self.get_or_create_inode(&child, entry);
+            }
+        }
+        // Without an earlier listing there is nothing to compare with
+        let Some((before, _)) = cached else {
+            return Ok(0);
+        };
+        if Arc::ptr_eq(&before, &entries) {
+            return Ok(0);
+        }
+
+        let mut removed: HashMap<&str, &FileEntry> = before
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry))
+            .collect();
+        let mut names: Vec<&str> = Vec::new();
+        for entry in entries.iter() {
+            match removed.remove(entry.name.as_str()) {
+                Some(before) if !changed(before, entry) => {}
+                _ => names.push(&entry.name),
+            }
+        }
+        for name in removed.keys() {
+            let child = join_path(path, name);
+            let ino = self.inodes.read().unwrap().resolve_path(&child);
+            if let Some(ino) = ino {
+                self.drop_file_data(ino);
+                self.expire_attr(ino);
+            }
+            names.push(name);
+        }
+
+        let parent = self.inodes.read().unwrap().resolve_path(path);
+        if let Some(parent) = parent {
+            for name in &names {
+                log::debug!("{} changed on the server", join_path(path, name));
+                self.forget_missing(parent, name);
+                self.invalidate_kernel_entry(parent, name);
+            }
+        }
+        Ok(names.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{FsConfig, RemoteBackend};
+    use crate::testing::MockBackend;
+
+    fn watched(watch: Vec<Watch>) -> (Arc<MockBackend>, RemoteFS) {
+        let mock = Arc::new(MockBackend::new());
+        mock.add_dir("/hot");
+        mock.add_file("/hot/a", b"1");
+        mock.add_file("/hot/b", b"2");
+        let config = FsConfig {
+            watch,
+            ..FsConfig::default()
+        };
+        let fs = RemoteFS::with_backend(mock.clone(), config);
+        fs.list_directory("/").unwrap();
+        let listing = fs.list_directory("/hot").unwrap();
+        fs.get_or_create_inode("/hot/a", &listing[0]);
+        (mock, fs)
+    }
+
+    #[test]
+    fn watches_parse_with_an_optional_interval() {
+        let watch = Watch::parse("/hot:2.5").unwrap();
+        assert_eq!(watch.path, "/hot");
+        assert_eq!(watch.interval, Duration::from_millis(2500));
+        assert_eq!(Watch::parse("hot").unwrap().interval, DEFAULT_INTERVAL);
+        // Not a number, so part of the path
+        assert_eq!(Watch::parse("/a:b").unwrap().path, "/a:b");
+
+        assert!(Watch::parse("/hot:0").is_err());
+        assert!(Watch::parse("/hot:-1").is_err());
+        assert!(Watch::parse("/hot:inf").is_err());
+        assert!(Watch::parse(":5").is_err());
+    }
+
+    #[test]
+    fn refreshing_counts_what_changed() {
+        let (mock, fs) = watched(Vec::new());
+        assert_eq!(fs.refresh_watch("/hot").unwrap(), 0);
+
+        mock.add_file("/hot/a", b"longer");
+        mock.add_file("/hot/c", b"3");
+        mock.delete("/hot/b").unwrap();
+        let ino = fs.inodes.read().unwrap().resolve_path("/hot/a").unwrap();
+        // Changed, added and removed
+        assert_eq!(fs.refresh_watch("/hot").unwrap(), 3);
+        assert_eq!(fs.get_inode(ino).unwrap().attr.size, 6);
+
+        // The new listing is cached for the next readdir
+        mock.take_calls();
+        let names: Vec<String> =
+            fs.list_directory("/hot").unwrap().iter().map(|e| e.name.clone()).collect();
+        assert_eq!(names, ["a", "c"]);
+        assert!(mock.take_calls().is_empty());
+    }
+
+    #[test]
+    fn watched_directories_are_refreshed_until_unmount() {
+        let watch = Watch::parse("/hot:0.05").unwrap();
+        let (mock, fs) = watched(vec![watch]);
+        fs.spawn_watches();
+        mock.add_file("/hot/new", b"x");
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let stats = fs.stats.watches.lock().unwrap()["/hot"].clone();
+            if stats.changes == 1 {
+                assert!(stats.last_refresh.is_some());
+                assert_eq!(stats.interval_seconds, 0.05);
+                break;
+            }
+            assert!(Instant::now() < deadline, "no refresh noticed the new file");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        fs.shutdown.store(true, Ordering::Relaxed);
+        thread::sleep(SIGNAL_POLL_INTERVAL * 3);
+        mock.take_calls();
+        thread::sleep(SIGNAL_POLL_INTERVAL * 3);
+        assert!(mock.take_calls().is_empty());
+    }
+}
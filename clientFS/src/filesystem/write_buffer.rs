@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+
+// Data written through a file handle that has not been uploaded yet, kept as
+// non-overlapping, non-adjacent ranges keyed by their start offset, along with
+// any truncation made since the last upload
+#[derive(Debug, Default)]
+pub struct WriteBuffer {
+    ranges: BTreeMap<u64, Vec<u8>>,
+    dirty_bytes: usize,
+    // Lowest length the file was truncated to, remote data past it is gone
+    truncated_to: Option<u64>,
+    // Length set by the latest truncation
+    set_len: Option<u64>,
+}
+
+impl WriteBuffer {
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty() && self.set_len.is_none()
+    }
+
+    pub fn dirty_bytes(&self) -> usize {
+        self.dirty_bytes
+    }
+
+    // Offset of the first byte that differs from the remote copy
+    pub fn start(&self) -> Option<u64> {
+        let first_write = self.ranges.keys().next().copied();
+        match (first_write, self.truncated_to) {
+            (Some(write), Some(truncate)) => Some(write.min(truncate)),
+            (write, truncate) => write.or(truncate),
+        }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.ranges
+            .iter()
+            .next_back()
+            .map(|(start, bytes)| start + bytes.len() as u64)
+            .unwrap_or(0)
+    }
+
+    // Whether the buffer alone holds the first `len` bytes of the file
+    pub fn covers_prefix(&self, len: u64) -> bool {
+        self.covers(0, len)
+    }
+
+    // Whether a single dirty range holds all of [offset, offset + len)
+    pub fn covers(&self, offset: u64, len: u64) -> bool {
+        len == 0
+            || self
+                .ranges
+                .range(..=offset)
+                .next_back()
+                .is_some_and(|(&start, bytes)| start + bytes.len() as u64 >= offset + len)
+    }
+
+    // How much of a remote copy of `remote_size` bytes survives truncation
+    pub fn kept_remote(&self, remote_size: u64) -> u64 {
+        remote_size.min(self.truncated_to.unwrap_or(u64::MAX))
+    }
+
+    // Whether uploading needs more than writing the dirty ranges in place
+    pub fn needs_rewrite(&self, remote_size: u64) -> bool {
+        self.kept_remote(remote_size) < remote_size
+            || self
+                .set_len
+                .is_some_and(|len| len > self.end().max(remote_size))
+    }
+
+    // Size of the file once the buffer is applied to a remote copy of `remote_size` bytes
+    pub fn file_size(&self, remote_size: u64) -> u64 {
+        self.end().max(self.set_len.unwrap_or(remote_size))
+    }
+
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.ranges
+            .iter()
+            .map(|(&start, bytes)| (start, bytes.as_slice()))
+    }
+
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+
+        // Ranges overlapping or adjacent to the new one get merged into it
+        let touching: Vec<u64> = self
+            .ranges
+            .range(..=end)
+            .rev()
+            .take_while(|(&start, bytes)| start + bytes.len() as u64 >= offset)
+            .map(|(&start, _)| start)
+            .collect();
+
+        let mut start = offset;
+        let mut stop = end;
+        for existing in &touching {
+            let len = self.ranges[existing].len() as u64;
+            start = start.min(*existing);
+            stop = stop.max(existing + len);
+        }
+
+        let mut merged = vec![0u8; (stop - start) as usize];
+        for existing in touching {
+            if let Some(bytes) = self.ranges.remove(&existing) {
+                let at = (existing - start) as usize;
+                merged[at..at + bytes.len()].copy_from_slice(&bytes);
+                self.dirty_bytes -= bytes.len();
+            }
+        }
+
+        let at = (offset - start) as usize;
+        merged[at..at + data.len()].copy_from_slice(data);
+        self.dirty_bytes += merged.len();
+        self.ranges.insert(start, merged);
+    }
+
+    // Drops buffered data past `len`. Remote data past it counts as gone until
+    // the next upload, and growing the file fills it with zeros.
+    pub fn truncate(&mut self, len: u64) {
+        let past_end: Vec<u64> = self.ranges.range(len..).map(|(&start, _)| start).collect();
+        for start in past_end {
+            if let Some(bytes) = self.ranges.remove(&start) {
+                self.dirty_bytes -= bytes.len();
+            }
+        }
+
+        if let Some((&start, bytes)) = self.ranges.range_mut(..len).next_back() {
+            let keep = (len - start) as usize;
+            if bytes.len() > keep {
+                self.dirty_bytes -= bytes.len() - keep;
+                bytes.truncate(keep);
+            }
+        }
+
+        self.truncated_to = Some(self.truncated_to.map_or(len, |cut| cut.min(len)));
+        self.set_len = Some(len);
+    }
+
+    // Re-applies changes from a failed upload underneath the ones made since
+    pub fn restore_older(&mut self, older: WriteBuffer) {
+        let newer = std::mem::replace(self, older);
+        // Writes still buffered in `newer` either came after its truncation or
+        // lie below it, so replaying the truncation first is safe
+        if let Some(len) = newer.set_len {
+            self.truncate(newer.truncated_to.unwrap_or(len));
+            self.truncate(len);
+        }
+        for (start, bytes) in newer.ranges {
+            self.write(start, &bytes);
+        }
+    }
+
+    // Copies buffered data over `buf`, which holds the file contents from
+    // `offset` onwards, growing it when dirty data extends past its end.
+    // Remote data past a truncation is zeroed.
+    pub fn overlay(&self, offset: u64, buf: &mut Vec<u8>, len: u64) {
+        let end = offset + len;
+
+        if let Some(cut) = self.truncated_to {
+            let from = cut.saturating_sub(offset) as usize;
+            if from < buf.len() {
+                buf[from..].fill(0);
+            }
+        }
+
+        for (&start, bytes) in self.ranges.range(..end) {
+            let stop = start + bytes.len() as u64;
+            if stop <= offset {
+                continue;
+            }
+
+            let from = start.max(offset);
+            let to = stop.min(end);
+            let needed = (to - offset) as usize;
+            if buf.len() < needed {
+                buf.resize(needed, 0);
+            }
+
+            buf[(from - offset) as usize..needed]
+                .copy_from_slice(&bytes[(from - start) as usize..(to - start) as usize]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(buffer: &WriteBuffer) -> Vec<(u64, Vec<u8>)> {
+        buffer.ranges().map(|(start, bytes)| (start, bytes.to_vec())).collect()
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_writes_merge() {
+        let mut buffer = WriteBuffer::default();
+        assert!(buffer.is_empty());
+        buffer.write(4, b"efgh");
+        buffer.write(0, b"abcd");
+        buffer.write(10, b"kl");
+        assert_eq!(ranges(&buffer), [(0, b"abcdefgh".to_vec()), (10, b"kl".to_vec())]);
+
+        // Bridges the gap and overwrites both sides
+        buffer.write(6, b"XXXXX");
+        assert_eq!(ranges(&buffer), [(0, b"abcdefXXXXXl".to_vec())]);
+        assert_eq!(buffer.dirty_bytes(), 12);
+        assert_eq!((buffer.start(), buffer.end()), (Some(0), 12));
+    }
+
+    #[test]
+    fn coverage_is_by_a_single_range() {
+        let mut buffer = WriteBuffer::default();
+        buffer.write(0, b"abcd");
+        buffer.write(8, b"ijkl");
+        assert!(buffer.covers_prefix(4));
+        assert!(!buffer.covers_prefix(5));
+        assert!(buffer.covers(9, 3));
+        assert!(!buffer.covers(2, 8));
+        assert!(buffer.covers(100, 0));
+    }
+
+    #[test]
+    fn overlay_puts_dirty_data_over_remote_data() {
+        let mut buffer = WriteBuffer::default();
+        buffer.write(2, b"XY");
+        buffer.write(9, b"Z");
+
+        let mut data = b"abcdef".to_vec();
+        buffer.overlay(0, &mut data, 10);
+        assert_eq!(data, b"abXYef\0\0\0Z");
+
+        // Only the part inside the read
+        let mut data = b"cdef".to_vec();
+        buffer.overlay(2, &mut data, 2);
+        assert_eq!(data, b"XYef");
+    }
+
+    #[test]
+    fn truncation_cuts_buffered_and_remote_data() {
+        let mut buffer = WriteBuffer::default();
+        buffer.write(2, b"abcd");
+        buffer.write(10, b"kl");
+        buffer.truncate(4);
+        assert_eq!(ranges(&buffer), [(2, b"ab".to_vec())]);
+        assert_eq!(buffer.dirty_bytes(), 2);
+        assert_eq!(buffer.start(), Some(2));
+        assert_eq!(buffer.kept_remote(8), 4);
+        assert_eq!(buffer.file_size(8), 4);
+        assert!(buffer.needs_rewrite(8));
+
+        let mut data = b"ABCDEFGH".to_vec();
+        buffer.overlay(0, &mut data, 8);
+        assert_eq!(data, b"ABab\0\0\0\0");
+    }
+
+    #[test]
+    fn growing_past_the_remote_copy_needs_a_rewrite() {
+        let mut buffer = WriteBuffer::default();
+        buffer.truncate(16);
+        assert!(!buffer.is_empty());
+        assert_eq!(buffer.kept_remote(8), 8);
+        assert_eq!(buffer.file_size(8), 16);
+        assert!(buffer.needs_rewrite(8));
+
+        // Writes in place are enough once the file is no longer cut or grown
+        let mut buffer = WriteBuffer::default();
+        buffer.write(20, b"x");
+        assert!(!buffer.needs_rewrite(8));
+        assert_eq!(buffer.file_size(8), 21);
+    }
+
+    #[test]
+    fn failed_upload_goes_under_newer_changes() {
+        let mut older = WriteBuffer::default();
+        older.write(0, b"old data");
+        let mut buffer = WriteBuffer::default();
+        buffer.truncate(3);
+        buffer.write(1, b"NEW");
+
+        buffer.restore_older(older);
+        assert_eq!(ranges(&buffer), [(0, b"oNEW".to_vec())]);
+        assert_eq!(buffer.kept_remote(8), 3);
+        assert_eq!(buffer.file_size(8), 4);
+    }
+}
@@ -0,0 +1,958 @@
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::io::Read;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::AsciiMetadataValue;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::{Code, Request, Status};
+
+use crate::api_client::{
+    ClientConfig, Conditional, Expected, FileData, FileEntry, Listing, Timestamp, Version,
+};
+use crate::filesystem::{FsError, RemoteBackend};
+
+#[allow(clippy::all)]
+mod proto {
+    tonic::include_proto!("remotefs.v1");
+}
+
+use proto::precondition::Condition;
+use proto::storage_client::StorageClient;
+use proto::write_chunk_request::Part;
+
+// Pings on idle connections, so a dead server is noticed before a request
+// waits out its deadline on it
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+// Messages of a write in flight at once
+const WRITE_QUEUE: usize = 4;
+// Trailer refining the status code, see proto/remotefs.proto
+const ERROR_TRAILER: &str = "x-remotefs-error";
+
+fn grpc_error(what: String, status: &Status) -> anyhow::Error {
+    let detail = status
+        .metadata()
+        .get(ERROR_TRAILER)
+        .and_then(|value| value.to_str().ok());
+    let kind = match (status.code(), detail) {
+        (_, Some("not_a_directory")) => FsError::NotADirectory,
+        (_, Some("is_a_directory")) => FsError::IsADirectory,
+        (_, Some("not_empty")) => FsError::NotEmpty,
+        (_, Some("name_too_long")) => FsError::NameTooLong,
+        (_, Some("file_too_large")) => FsError::FileTooLarge,
+        (_, Some("read_only")) => FsError::ReadOnly,
+        (Code::NotFound, _) => FsError::NotFound,
+        (Code::AlreadyExists, _) => FsError::AlreadyExists,
+        (Code::PermissionDenied | Code::Unauthenticated, _) => FsError::PermissionDenied,
+        (Code::FailedPrecondition | Code::Aborted, _) => FsError::Stale,
+        (Code::ResourceExhausted, _) => FsError::NoSpace,
+        (Code::DeadlineExceeded, _) => FsError::TimedOut,
+        (Code::Unavailable, _) => FsError::Unreachable,
+        (Code::Unimplemented, _) => FsError::Unsupported,
+        _ => FsError::Io,
+    };
+    anyhow::Error::new(kind).context(format!(
+        "{} failed: {:?} {}",
+        what,
+        status.code(),
+        status.message()
+    ))
+}
+
+// The server never saw the request, or not all of it
+fn is_transport(status: &Status) -> bool {
+    matches!(status.code(), Code::Unavailable | Code::DeadlineExceeded)
+}
+
+fn version(version: String) -> Option<Version> {
+    (!version.is_empty()).then_some(Version::ETag(version))
+}
+
+fn precondition(expected: &Expected) -> Option<proto::Precondition> {
+    let condition = match expected {
+        Expected::Any => return None,
+        Expected::Absent => Condition::Absent(proto::Empty {}),
+        Expected::Version(Version::ETag(version) | Version::LastModified(version)) => {
+            Condition::Version(version.clone())
+        }
+    };
+    Some(proto::Precondition {
+        condition: Some(condition),
+    })
+}
+
+fn file_entry(entry: proto::Entry) -> FileEntry {
+    FileEntry {
+        name: entry.name,
+        is_dir: entry.is_dir,
+        size: entry.size,
+        mtime: Timestamp::from_secs_f64(entry.mtime),
+        ctime: Timestamp::from_secs_f64(entry.ctime),
+        mode: entry.mode,
+        id: entry.id,
+        uid: entry.uid,
+        gid: entry.gid,
+        major: entry.major,
+        minor: entry.minor,
+        nlink: entry.nlink,
+    }
+}
+
+// The storage service over gRPC, see proto/remotefs.proto. Calls block on
+// a runtime of the backend's own; the channel reconnects by itself, and
+// reads are retried once when the connection was lost under them.
+pub struct GrpcBackend {
+    config: ClientConfig,
+    url: String,
+    runtime: Runtime,
+    client: StorageClient<Channel>,
+    // `Bearer <token>`, sent with every call
+    authorization: Option<AsciiMetadataValue>,
+    consecutive_failures: AtomicU32,
+}
+
+impl GrpcBackend {
+    // Connects to the first server URL, `grpc://host:port` or
+    // `grpcs://host:port` for TLS. The connection is made on first use.
+    pub fn with_config(config: ClientConfig) -> Result<Self> {
+        let url = config
+            .base_urls
+            .first()
+            .context("At least one server URL is required")?
+            .clone();
+        if config.base_urls.len() > 1 {
+            log::warn!("The gRPC backend only uses the first server URL, {}", url);
+        }
+        ClientConfig::validate_chunk_size(config.chunk_size)?;
+
+        let (uri, tls) = match url.split_once("://") {
+            Some(("grpc" | "http", rest)) => (format!("http://{}", rest), false),
+            Some(("grpcs" | "https", rest)) => (format!("https://{}", rest), true),
+            _ => anyhow::bail!(
+                "Expected grpc://host:port or grpcs://host:port, got {}",
+                url
+            ),
+        };
+        let mut endpoint = Endpoint::from_shared(uri)
+            .with_context(|| format!("Invalid server URL {}", url))?
+            .connect_timeout(config.timeout)
+            .http2_keep_alive_interval(KEEPALIVE_INTERVAL)
+            .keep_alive_timeout(KEEPALIVE_TIMEOUT)
+            .keep_alive_while_idle(true);
+        if tls {
+            endpoint = endpoint
+                .tls_config(ClientTlsConfig::new().with_native_roots())
+                .context("Failed to set up TLS")?;
+        }
+
+        let authorization = match &config.token {
+            Some(token) => Some(
+                format!("Bearer {}", token.expose())
+                    .parse()
+                    .context("The token is not valid in a header")?,
+            ),
+            None => None,
+        };
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("grpc")
+            .enable_all()
+            .build()
+            .context("Failed to start the gRPC runtime")?;
+        let channel = {
+            let _runtime = runtime.enter();
+            endpoint.connect_lazy()
+        };
+
+        Ok(Self {
+            client: StorageClient::new(channel),
+            authorization,
+            runtime,
+            url,
+            config,
+            consecutive_failures: AtomicU32::new(0),
+        })
+    }
+
+    // `message` with the token and a deadline of `timeout`, which the server
+    // receives as grpc-timeout
+    fn request<T>(&self, message: T, timeout: Duration) -> Request<T> {
+        let mut request = Request::new(message);
+        request.set_timeout(timeout);
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+        request
+    }
+
+    // Counts failures of the connection, for is_healthy
+    fn finish<T>(&self, what: impl Fn() -> String, result: Result<T, Status>) -> Result<T> {
+        match result {
+            Ok(value) => {
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+                Ok(value)
+            }
+            Err(status) => {
+                if is_transport(&status) {
+                    self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    self.consecutive_failures.store(0, Ordering::Relaxed);
+                }
+                Err(grpc_error(what(), &status))
+            }
+        }
+    }
+
+    // Runs the call `op` makes on a client, a second time when it is
+    // `idempotent` and the connection failed under it
+    fn call<T, F, Fut>(&self, what: impl Fn() -> String, idempotent: bool, op: F) -> Result<T>
+    where
+        F: Fn(StorageClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let mut result = self.runtime.block_on(op(self.client.clone()));
+        if idempotent && matches!(&result, Err(status) if status.code() == Code::Unavailable) {
+            log::debug!("{} lost its connection, retrying", what());
+            result = self.runtime.block_on(op(self.client.clone()));
+        }
+        self.finish(what, result)
+    }
+
+    fn stat(&self, path: &str, timeout: Duration) -> Result<proto::StatResponse> {
+        self.call(
+            || format!("Stat of {}", path),
+            true,
+            |mut client| {
+                let request = self.request(
+                    proto::StatRequest {
+                        path: path.to_string(),
+                    },
+                    timeout,
+                );
+                async move { Ok(client.stat(request).await?.into_inner()) }
+            },
+        )
+    }
+
+    fn list(&self, path: &str, if_none_match: &str) -> Result<proto::ListResponse> {
+        self.call(
+            || format!("Listing {}", path),
+            true,
+            |mut client| {
+                let request = self.request(
+                    proto::ListRequest {
+                        path: path.to_string(),
+                        if_none_match: if_none_match.to_string(),
+                    },
+                    self.config.timeout,
+                );
+                async move { Ok(client.list(request).await?.into_inner()) }
+            },
+        )
+    }
+
+    // Streams `header`, then what `reader` yields in pieces of a chunk. The
+    // reader runs on the calling thread while the runtime sends; a failing
+    // reader cancels the call, so nothing partial is written.
+    fn write_once(
+        &self,
+        header: &proto::WriteHeader,
+        mut reader: Box<dyn Read + Send + '_>,
+    ) -> Result<Result<proto::WriteChunkResponse, Status>> {
+        let (sender, receiver) = mpsc::channel(WRITE_QUEUE);
+        let request = self.request(ReceiverStream::new(receiver), self.config.timeout);
+        let mut client = self.client.clone();
+        let call = self
+            .runtime
+            .spawn(async move { client.write_chunk(request).await });
+
+        let mut part = Part::Header(header.clone());
+        loop {
+            let message = proto::WriteChunkRequest { part: Some(part) };
+            // Closed when the call ended early, its status tells why
+            if sender.blocking_send(message).is_err() {
+                break;
+            }
+            let mut chunk = Vec::new();
+            if let Err(e) = (&mut reader)
+                .take(self.config.chunk_size)
+                .read_to_end(&mut chunk)
+            {
+                // Before the stream ends, or the server would take it as
+                // complete
+                call.abort();
+                let _ = self.runtime.block_on(call);
+                drop(sender);
+                return Err(e).with_context(|| format!("Failed to read data for {}", header.path));
+            }
+            if chunk.is_empty() {
+                break;
+            }
+            part = Part::Data(chunk);
+        }
+        drop(sender);
+
+        let result = self
+            .runtime
+            .block_on(call)
+            .context("The gRPC write task failed")?;
+        Ok(result.map(|response| response.into_inner()))
+    }
+
+    fn write<'a>(
+        &self,
+        header: proto::WriteHeader,
+        idempotent: bool,
+        open: &dyn Fn() -> Box<dyn Read + Send + 'a>,
+    ) -> Result<Option<Version>> {
+        let what = || format!("Write of {}", header.path);
+        let mut result = self.write_once(&header, open())?;
+        if idempotent && matches!(&result, Err(status) if status.code() == Code::Unavailable) {
+            log::debug!("{} lost its connection, retrying", what());
+            result = self.write_once(&header, open())?;
+        }
+        let response = self.finish(what, result)?;
+        Ok(version(response.version))
+    }
+
+    pub fn list_directory(&self, path: &str) -> Result<Listing> {
+        let response = self.list(path, "")?;
+        Ok(Listing {
+            entries: response.entries.into_iter().map(file_entry).collect(),
+            version: version(response.version),
+        })
+    }
+
+    pub fn revalidate_listing(&self, path: &str, cached: &Version) -> Result<Conditional<Listing>> {
+        let (Version::ETag(cached) | Version::LastModified(cached)) = cached;
+        let response = self.list(path, cached)?;
+        if response.not_modified {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified(Listing {
+            entries: response.entries.into_iter().map(file_entry).collect(),
+            version: version(response.version),
+        }))
+    }
+
+    pub fn file_version(&self, path: &str) -> Result<Option<Version>> {
+        Ok(version(self.stat(path, self.config.timeout)?.version))
+    }
+
+    pub fn revalidate_file(
+        &self,
+        path: &str,
+        cached: &Version,
+    ) -> Result<Conditional<Option<Version>>> {
+        match self.stat(path, self.config.timeout) {
+            Ok(response) => match version(response.version) {
+                Some(current) if &current == cached => Ok(Conditional::NotModified),
+                current => Ok(Conditional::Modified(current)),
+            },
+            Err(e) if FsError::from_backend(&e) == FsError::NotFound => {
+                Ok(Conditional::Modified(None))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // The range comes in as many messages as the server sends, gathered
+    // into the one block the cache asked for
+    pub fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        self.call(
+            || format!("Read of {}", path),
+            true,
+            |mut client| {
+                let request = self.request(
+                    proto::ReadChunkRequest {
+                        path: path.to_string(),
+                        offset,
+                        length: len,
+                    },
+                    self.config.timeout,
+                );
+                async move {
+                    let mut stream = client.read_chunk(request).await?.into_inner();
+                    let mut data = Vec::with_capacity(len.min(16 << 20) as usize);
+                    let mut version = String::new();
+                    while let Some(message) = stream.message().await? {
+                        if version.is_empty() {
+                            version = message.version;
+                        }
+                        data.extend_from_slice(&message.data);
+                    }
+                    data.truncate(len as usize);
+                    Ok(FileData {
+                        data,
+                        version: self::version(version),
+                    })
+                }
+            },
+        )
+    }
+
+    // A retried write could find what its first attempt wrote, so only
+    // those without a precondition are retried
+    pub fn write_file_if(
+        &self,
+        path: &str,
+        data: &[u8],
+        expected: &Expected,
+    ) -> Result<Option<Version>> {
+        let header = proto::WriteHeader {
+            path: path.to_string(),
+            truncate: true,
+            offset: 0,
+            precondition: precondition(expected),
+        };
+        self.write(header, matches!(expected, Expected::Any), &|| {
+            Box::new(data)
+        })
+    }
+
+    pub fn write_file_streamed(
+        &self,
+        path: &str,
+        len: u64,
+        open: &dyn Fn() -> Box<dyn Read + Send>,
+    ) -> Result<()> {
+        let header = proto::WriteHeader {
+            path: path.to_string(),
+            truncate: true,
+            offset: 0,
+            precondition: None,
+        };
+        self.write(header, true, &|| Box::new(open().take(len)))
+            .map(|_| ())
+    }
+
+    pub fn write_range(&self, path: &str, offset: u64, data: &[u8]) -> Result<bool> {
+        let header = proto::WriteHeader {
+            path: path.to_string(),
+            truncate: false,
+            offset,
+            precondition: None,
+        };
+        match self.write(header, true, &|| Box::new(data)) {
+            Ok(_) => Ok(true),
+            Err(e) if FsError::from_backend(&e) == FsError::Unsupported => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn create_directory(&self, path: &str) -> Result<()> {
+        self.call(
+            || format!("Mkdir of {}", path),
+            false,
+            |mut client| {
+                let request = self.request(
+                    proto::MkdirRequest {
+                        path: path.to_string(),
+                    },
+                    self.config.timeout,
+                );
+                async move { client.mkdir(request).await.map(|_| ()) }
+            },
+        )
+    }
+
+    pub fn delete_if(&self, path: &str, expected: &Expected) -> Result<()> {
+        self.call(
+            || format!("Delete of {}", path),
+            false,
+            |mut client| {
+                let request = self.request(
+                    proto::DeleteRequest {
+                        path: path.to_string(),
+                        precondition: precondition(expected),
+                    },
+                    self.config.timeout,
+                );
+                async move { client.delete(request).await.map(|_| ()) }
+            },
+        )
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.call(
+            || format!("Rename of {} to {}", from, to),
+            false,
+            |mut client| {
+                let request = self.request(
+                    proto::RenameRequest {
+                        from: from.to_string(),
+                        to: to.to_string(),
+                    },
+                    self.config.timeout,
+                );
+                async move { client.rename(request).await.map(|_| ()) }
+            },
+        )
+    }
+
+    pub fn check_reachable(&self, timeout: Duration) -> Result<()> {
+        self.stat("/", timeout).map(|_| ())
+    }
+}
+
+impl RemoteBackend for GrpcBackend {
+    fn chunk_size(&self) -> u64 {
+        self.config.chunk_size
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.consecutive_failures.load(Ordering::Relaxed) == 0
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.url
+    }
+
+    fn check_reachable(&self, timeout: Duration) -> Result<()> {
+        GrpcBackend::check_reachable(self, timeout)
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Listing> {
+        GrpcBackend::list_directory(self, path)
+    }
+
+    fn revalidate_listing(&self, path: &str, version: &Version) -> Result<Conditional<Listing>> {
+        GrpcBackend::revalidate_listing(self, path, version)
+    }
+
+    fn revalidate_file(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Option<Version>>> {
+        GrpcBackend::revalidate_file(self, path, version)
+    }
+
+    fn file_version(&self, path: &str) -> Result<Option<Version>> {
+        GrpcBackend::file_version(self, path)
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        GrpcBackend::read_range(self, path, offset, len)
+    }
+
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<()> {
+        self.write_file_if(path, data, &Expected::Any).map(|_| ())
+    }
+
+    fn write_file_if(
+        &self,
+        path: &str,
+        data: &[u8],
+        expected: &Expected,
+    ) -> Result<Option<Version>> {
+        GrpcBackend::write_file_if(self, path, data, expected)
+    }
+
+    fn write_file_streamed(
+        &self,
+        path: &str,
+        len: u64,
+        open: &dyn Fn() -> Box<dyn Read + Send>,
+    ) -> Result<()> {
+        GrpcBackend::write_file_streamed(self, path, len, open)
+    }
+
+    fn write_range(&self, path: &str, offset: u64, data: &[u8]) -> Result<bool> {
+        GrpcBackend::write_range(self, path, offset, data)
+    }
+
+    fn create_directory(&self, path: &str) -> Result<()> {
+        GrpcBackend::create_directory(self, path)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.delete_if(path, &Expected::Any)
+    }
+
+    fn delete_if(&self, path: &str, expected: &Expected) -> Result<()> {
+        GrpcBackend::delete_if(self, path, expected)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        GrpcBackend::rename(self, from, to)
+    }
+}
+
+#[cfg(test)]
+// The service answers with tonic's Status, large as it is
+#[allow(clippy::result_large_err)]
+mod tests {
+    use super::*;
+    use crate::api_client::Secret;
+    use proto::storage_server::{Storage, StorageServer};
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use tokio_stream::Stream;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::{Response, Streaming};
+
+    const FILE: &[u8] = b"hello world";
+
+    // Serves "/" with "a.txt" in it to callers with token "t", and records
+    // the calls and writes it gets
+    #[derive(Default)]
+    struct Mock {
+        calls: Mutex<Vec<String>>,
+        writes: Mutex<Vec<(proto::WriteHeader, Vec<u8>)>>,
+    }
+
+    impl Mock {
+        fn called<T>(&self, request: &Request<T>, call: String) -> Result<(), Status> {
+            let calls = {
+                let mut calls = self.calls.lock().unwrap();
+                calls.push(call.clone());
+                calls.iter().filter(|earlier| **earlier == call).count()
+            };
+            let token = request.metadata().get("authorization");
+            if token.and_then(|value| value.to_str().ok()) != Some("Bearer t") {
+                return Err(Status::unauthenticated("no token"));
+            }
+            // Loses the connection under the first of each call on /flaky
+            if call.contains("/flaky") && calls == 1 {
+                return Err(Status::unavailable("connection reset"));
+            }
+            Ok(())
+        }
+    }
+
+    fn entry(name: &str, is_dir: bool) -> proto::Entry {
+        proto::Entry {
+            name: name.to_string(),
+            is_dir,
+            size: if is_dir { 0 } else { FILE.len() as u64 },
+            mtime: 1_700_000_000.5,
+            ctime: 1_700_000_000.5,
+            mode: if is_dir { 0o755 } else { 0o644 },
+            nlink: is_dir.then_some(2),
+            ..proto::Entry::default()
+        }
+    }
+
+    #[tonic::async_trait]
+    impl Storage for Arc<Mock> {
+        async fn stat(
+            &self,
+            request: Request<proto::StatRequest>,
+        ) -> Result<Response<proto::StatResponse>, Status> {
+            let path = request.get_ref().path.clone();
+            self.called(&request, format!("stat {}", path))?;
+            match path.as_str() {
+                "/" | "/a.txt" | "/flaky" => Ok(Response::new(proto::StatResponse {
+                    entry: Some(entry("a.txt", false)),
+                    version: "v1".to_string(),
+                })),
+                "/nowhere" => Ok(Response::new(proto::StatResponse::default())),
+                _ => Err(Status::not_found(path)),
+            }
+        }
+
+        async fn list(
+            &self,
+            request: Request<proto::ListRequest>,
+        ) -> Result<Response<proto::ListResponse>, Status> {
+            let path = request.get_ref().path.clone();
+            self.called(&request, format!("list {}", path))?;
+            if path == "/a.txt" {
+                let mut status = Status::failed_precondition("not a directory");
+                status
+                    .metadata_mut()
+                    .insert(ERROR_TRAILER, "not_a_directory".parse().unwrap());
+                return Err(status);
+            }
+            if request.get_ref().if_none_match == "L1" {
+                return Ok(Response::new(proto::ListResponse {
+                    not_modified: true,
+                    ..proto::ListResponse::default()
+                }));
+            }
+            Ok(Response::new(proto::ListResponse {
+                entries: vec![entry("docs", true), entry("a.txt", false)],
+                version: "L1".to_string(),
+                not_modified: false,
+            }))
+        }
+
+        type ReadChunkStream =
+            Pin<Box<dyn Stream<Item = Result<proto::ReadChunkResponse, Status>> + Send>>;
+
+        async fn read_chunk(
+            &self,
+            request: Request<proto::ReadChunkRequest>,
+        ) -> Result<Response<Self::ReadChunkStream>, Status> {
+            let read = request.get_ref().clone();
+            self.called(&request, format!("read {}", read.path))?;
+            let start = (read.offset as usize).min(FILE.len());
+            let end = (start + read.length as usize).min(FILE.len());
+            // In pieces of three bytes, the version with the first
+            let messages: Vec<_> = FILE[start..end]
+                .chunks(3)
+                .enumerate()
+                .map(|(n, data)| {
+                    Ok(proto::ReadChunkResponse {
+                        data: data.to_vec(),
+                        version: if n == 0 { "v1".to_string() } else { String::new() },
+                    })
+                })
+                .collect();
+            Ok(Response::new(Box::pin(tokio_stream::iter(messages))))
+        }
+
+        async fn write_chunk(
+            &self,
+            request: Request<Streaming<proto::WriteChunkRequest>>,
+        ) -> Result<Response<proto::WriteChunkResponse>, Status> {
+            self.called(&request, "write".to_string())?;
+            let mut stream = request.into_inner();
+            let Some(Part::Header(header)) = stream.message().await?.and_then(|m| m.part) else {
+                return Err(Status::invalid_argument("no header"));
+            };
+            let mut data = Vec::new();
+            while let Some(message) = stream.message().await? {
+                if let Some(Part::Data(chunk)) = message.part {
+                    data.extend(chunk);
+                }
+            }
+            if !header.truncate {
+                return Err(Status::unimplemented("no partial writes"));
+            }
+            let condition = header.precondition.clone().and_then(|p| p.condition);
+            if matches!(&condition, Some(Condition::Version(v)) if v != "v1") {
+                return Err(Status::failed_precondition("changed"));
+            }
+            self.writes.lock().unwrap().push((header, data));
+            Ok(Response::new(proto::WriteChunkResponse {
+                version: "v2".to_string(),
+            }))
+        }
+
+        async fn mkdir(
+            &self,
+            request: Request<proto::MkdirRequest>,
+        ) -> Result<Response<proto::Empty>, Status> {
+            self.called(&request, format!("mkdir {}", request.get_ref().path))?;
+            Err(Status::already_exists("exists"))
+        }
+
+        async fn delete(
+            &self,
+            request: Request<proto::DeleteRequest>,
+        ) -> Result<Response<proto::Empty>, Status> {
+            self.called(&request, format!("delete {}", request.get_ref().path))?;
+            Ok(Response::new(proto::Empty {}))
+        }
+
+        async fn rename(
+            &self,
+            request: Request<proto::RenameRequest>,
+        ) -> Result<Response<proto::Empty>, Status> {
+            let rename = request.get_ref();
+            let call = format!("rename {} {}", rename.from, rename.to);
+            self.called(&request, call)?;
+            Ok(Response::new(proto::Empty {}))
+        }
+    }
+
+    // The runtime serving `mock`, and a backend connecting to it as `token`
+    fn serve(mock: Arc<Mock>, token: Option<&str>) -> (Runtime, GrpcBackend) {
+        let runtime = Runtime::new().unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let url = format!("grpc://{}", listener.local_addr().unwrap());
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        runtime.spawn(
+            tonic::transport::Server::builder()
+                .add_service(StorageServer::new(mock))
+                .serve_with_incoming(incoming),
+        );
+
+        let mut config = ClientConfig::new(vec![url]);
+        config.token = token.map(Secret::new);
+        (runtime, GrpcBackend::with_config(config).unwrap())
+    }
+
+    fn kind(error: anyhow::Error) -> FsError {
+        FsError::from_backend(&error)
+    }
+
+    #[test]
+    fn urls_pick_the_transport() {
+        for url in ["grpc://localhost:50051", "grpcs://localhost", "http://[::1]:1"] {
+            let backend = GrpcBackend::with_config(ClientConfig::new(vec![url.to_string()]));
+            assert_eq!(backend.unwrap().endpoint(), url);
+        }
+        for url in ["localhost:50051", "s3://bucket", "grpc://bad host"] {
+            let backend = GrpcBackend::with_config(ClientConfig::new(vec![url.to_string()]));
+            assert!(backend.is_err(), "{}", url);
+        }
+        let mut config = ClientConfig::new(vec!["grpc://localhost".to_string()]);
+        config.token = Some(Secret::new("bad\ntoken"));
+        assert!(GrpcBackend::with_config(config).is_err());
+    }
+
+    #[test]
+    fn statuses_and_trailers_are_classified() {
+        let table = [
+            (Status::not_found(""), FsError::NotFound),
+            (Status::already_exists(""), FsError::AlreadyExists),
+            (Status::unauthenticated(""), FsError::PermissionDenied),
+            (Status::aborted(""), FsError::Stale),
+            (Status::resource_exhausted(""), FsError::NoSpace),
+            (Status::deadline_exceeded(""), FsError::TimedOut),
+            (Status::unavailable(""), FsError::Unreachable),
+            (Status::unimplemented(""), FsError::Unsupported),
+            (Status::internal(""), FsError::Io),
+        ];
+        for (status, kind) in table {
+            assert_eq!(FsError::from_backend(&grpc_error("x".into(), &status)), kind);
+        }
+        let mut status = Status::failed_precondition("not empty");
+        status
+            .metadata_mut()
+            .insert(ERROR_TRAILER, "not_empty".parse().unwrap());
+        let error = grpc_error("Delete of /d".to_string(), &status);
+        assert_eq!(FsError::from_backend(&error), FsError::NotEmpty);
+        assert_eq!(
+            error.to_string(),
+            "Delete of /d failed: FailedPrecondition not empty"
+        );
+    }
+
+    #[test]
+    fn preconditions_carry_the_version() {
+        assert_eq!(precondition(&Expected::Any), None);
+        let absent = precondition(&Expected::Absent).unwrap().condition;
+        assert_eq!(absent, Some(Condition::Absent(proto::Empty {})));
+        let expected = Expected::Version(Version::LastModified("then".to_string()));
+        let version = precondition(&expected).unwrap().condition;
+        assert_eq!(version, Some(Condition::Version("then".to_string())));
+        assert_eq!(self::version(String::new()), None);
+    }
+
+    #[test]
+    fn listings_and_stats_round_trip() {
+        let mock = Arc::new(Mock::default());
+        let (_runtime, backend) = serve(mock.clone(), Some("t"));
+
+        let listing = backend.list_directory("/").unwrap();
+        assert_eq!(listing.version, Some(Version::ETag("L1".to_string())));
+        let docs = &listing.entries[0];
+        assert_eq!((docs.name.as_str(), docs.is_dir, docs.nlink), ("docs", true, Some(2)));
+        assert_eq!(listing.entries[1].size, FILE.len() as u64);
+        assert_eq!(listing.entries[1].mtime, Timestamp::new(1_700_000_000, 500_000_000));
+        let cached = listing.version.unwrap();
+        assert!(matches!(
+            backend.revalidate_listing("/", &cached).unwrap(),
+            Conditional::NotModified
+        ));
+        let error = backend.list_directory("/a.txt").unwrap_err();
+        assert_eq!(kind(error), FsError::NotADirectory);
+
+        let v1 = Version::ETag("v1".to_string());
+        assert_eq!(backend.file_version("/a.txt").unwrap(), Some(v1.clone()));
+        assert_eq!(backend.file_version("/nowhere").unwrap(), None);
+        assert!(matches!(
+            backend.revalidate_file("/a.txt", &v1).unwrap(),
+            Conditional::NotModified
+        ));
+        assert!(matches!(
+            backend.revalidate_file("/gone", &v1).unwrap(),
+            Conditional::Modified(None)
+        ));
+        backend.check_reachable(Duration::from_secs(5)).unwrap();
+        assert!(backend.is_healthy());
+    }
+
+    #[test]
+    fn reads_gather_the_stream() {
+        let (_runtime, backend) = serve(Arc::new(Mock::default()), Some("t"));
+        let read = backend.read_range("/a.txt", 2, 7).unwrap();
+        assert_eq!(read.data, b"llo wor");
+        assert_eq!(read.version, Some(Version::ETag("v1".to_string())));
+        assert!(backend.read_range("/a.txt", 20, 5).unwrap().data.is_empty());
+    }
+
+    #[test]
+    fn writes_stream_the_header_then_the_data() {
+        let mock = Arc::new(Mock::default());
+        let (_runtime, backend) = serve(mock.clone(), Some("t"));
+        let data = vec![7u8; (backend.chunk_size() * 2 + 5) as usize];
+        let version = backend
+            .write_file_if("/big", &data, &Expected::Absent)
+            .unwrap();
+        assert_eq!(version, Some(Version::ETag("v2".to_string())));
+        let stale = Expected::Version(Version::ETag("v0".to_string()));
+        let error = backend.write_file_if("/big", b"x", &stale).unwrap_err();
+        assert_eq!(kind(error), FsError::Stale);
+        // Servers without partial writes have the whole file uploaded
+        assert!(!backend.write_range("/big", 3, b"x").unwrap());
+
+        let writes = mock.writes.lock().unwrap();
+        assert_eq!(writes.len(), 1);
+        let (header, written) = &writes[0];
+        assert_eq!((header.path.as_str(), header.truncate), ("/big", true));
+        assert_eq!(*written, data);
+    }
+
+    #[test]
+    fn failed_readers_cancel_the_write() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("disk gone"))
+            }
+        }
+        let mock = Arc::new(Mock::default());
+        let (_runtime, backend) = serve(mock.clone(), Some("t"));
+        let error = backend
+            .write_file_streamed("/a.txt", 10, &|| Box::new(Failing))
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("disk gone"), "{:#}", error);
+        assert!(mock.writes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_idempotent_calls_are_retried() {
+        let mock = Arc::new(Mock::default());
+        let (_runtime, backend) = serve(mock.clone(), Some("t"));
+        assert!(backend.file_version("/flaky").is_ok());
+        let error = backend.rename("/flaky", "/b").unwrap_err();
+        assert_eq!(kind(error), FsError::Unreachable);
+        assert!(!backend.is_healthy());
+        backend.delete("/a.txt").unwrap();
+        assert!(backend.is_healthy());
+        assert_eq!(kind(backend.create_directory("/docs").unwrap_err()), FsError::AlreadyExists);
+
+        let calls = mock.calls.lock().unwrap();
+        assert_eq!(
+            *calls,
+            [
+                "stat /flaky",
+                "stat /flaky",
+                "rename /flaky /b",
+                "delete /a.txt",
+                "mkdir /docs"
+            ]
+        );
+    }
+
+    #[test]
+    fn calls_carry_the_token() {
+        let (_runtime, backend) = serve(Arc::new(Mock::default()), None);
+        let error = backend.file_version("/a.txt").unwrap_err();
+        assert_eq!(kind(error), FsError::PermissionDenied);
+    }
+}
@@ -0,0 +1,268 @@
+// Remote filesystem over HTTP, mountable through FUSE from the binary or
+// from any program embedding it. All fallible calls return anyhow errors
+// carrying the failed operation as context; nothing in here panics on bad
+// configuration or an unreachable server.
+
+mod api_client;
+mod cli;
+mod config;
+mod daemon;
+mod dates;
+mod filesystem;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod local;
+mod logging;
+mod s3;
+mod sftp;
+mod startup;
+mod supervisor;
+mod testing;
+mod unmount;
+#[cfg(feature = "webdav")]
+mod webdav;
+
+use anyhow::Result;
+use reqwest::blocking::Client;
+use std::path::Path;
+use std::sync::Arc;
+
+pub use api_client::{
+    parse_size, ApiClient, BreakerState, CapabilitySource, ChangeEvent, ChangeKind, CircuitOpen,
+    ClientConfig, Conditional, EventStream, Expected, FileData, FileEntry, HttpConfig, Listing,
+    Probe, ProbeResult, RequestStatsSnapshot, Secret, SelfTestReport, ServerCapabilities,
+    ServerError, ServerEvent, Timestamp, Version, SELFTEST_DIR,
+};
+pub use cli::Invocation;
+pub use config::{ConfigFile, ConfigSource, Mode, Profile, Size};
+pub use daemon::{daemonize, Daemon, DaemonConfig};
+pub use filesystem::{
+    control, default_control_socket, BackendKind, CacheConfig, CacheUsage, ConflictMode,
+    ControlRequest, FsConfig, FsError, Glob, MountGuard, NotifyMode, OfflineMode, RemoteBackend,
+    RemoteFS, SortDirs, StaleHandles, StatsSnapshot, Watch, DROP_CACHES_IOCTL,
+};
+pub use fuser::MountOption;
+#[cfg(feature = "grpc")]
+pub use grpc::GrpcBackend;
+pub use local::{Faults, LocalBackend};
+pub use logging::{init_logging, LogFormat};
+#[cfg(feature = "s3")]
+pub use s3::S3Backend;
+pub use s3::S3Config;
+#[cfg(feature = "sftp")]
+pub use sftp::SftpBackend;
+pub use sftp::SftpConfig;
+pub use startup::{MountError, StartupConfig};
+pub use supervisor::Supervisor;
+pub use testing::MockBackend;
+pub use unmount::{busy_processes, is_mounted, unmount, BusyProcess, UnmountError};
+#[cfg(feature = "webdav")]
+pub use webdav::WebDavBackend;
+
+// Unmounts when dropped, see MountGuard
+pub type MountHandle = MountGuard;
+
+// Everything needed to mount against an HTTP server
+#[derive(Debug, Clone)]
+pub struct MountConfig {
+    pub client: ClientConfig,
+    // The REST API, WebDAV at the same URL, an S3 bucket, SFTP, the gRPC
+    // service or a local directory
+    pub backend: BackendKind,
+    // Region, endpoint and credentials of the S3 backend
+    pub s3: S3Config,
+    // Authentication and host key checking of the SFTP backend
+    pub sftp: SftpConfig,
+    // Latency and errors added by the local backend, none by default
+    pub faults: Faults,
+    pub fs: FsConfig,
+    // Added to the default mount options, later ones win
+    pub options: Vec<MountOption>,
+    // Where to mount when the command line does not say, set by profiles
+    pub mountpoint: Option<String>,
+    // For the binary's logger, set by profiles and REMOTEFS_LOG_FORMAT
+    pub log_format: LogFormat,
+    // How long mounting waits for the server
+    pub startup: StartupConfig,
+}
+
+impl MountConfig {
+    pub fn new(base_urls: Vec<String>) -> Self {
+        Self {
+            client: ClientConfig::new(base_urls),
+            backend: BackendKind::default(),
+            s3: S3Config::default(),
+            sftp: SftpConfig::default(),
+            faults: Faults::default(),
+            fs: FsConfig::default(),
+            options: Vec::new(),
+            mountpoint: None,
+            log_format: LogFormat::default(),
+            startup: StartupConfig::default(),
+        }
+    }
+
+    // Parses a comma-separated `-o` list like `ro,allow_other,fsname=remote`.
+    // Options fuser has no name for are passed on to the kernel as they are.
+    pub fn parse_mount_options(value: &str) -> Vec<MountOption> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|option| !option.is_empty())
+            .map(|option| match option {
+                "ro" => MountOption::RO,
+                "rw" => MountOption::RW,
+                "allow_other" => MountOption::AllowOther,
+                "allow_root" => MountOption::AllowRoot,
+                "auto_unmount" => MountOption::AutoUnmount,
+                "default_permissions" => MountOption::DefaultPermissions,
+                "dev" => MountOption::Dev,
+                "nodev" => MountOption::NoDev,
+                "suid" => MountOption::Suid,
+                "nosuid" => MountOption::NoSuid,
+                "exec" => MountOption::Exec,
+                "noexec" => MountOption::NoExec,
+                "atime" => MountOption::Atime,
+                "noatime" => MountOption::NoAtime,
+                "dirsync" => MountOption::DirSync,
+                "sync" => MountOption::Sync,
+                "async" => MountOption::Async,
+                _ => match option.split_once('=') {
+                    Some(("fsname", name)) => MountOption::FSName(name.to_string()),
+                    Some(("subtype", name)) => MountOption::Subtype(name.to_string()),
+                    _ => MountOption::CUSTOM(option.to_string()),
+                },
+            })
+            .collect()
+    }
+}
+
+// Waits for the server to answer and serves it at `mountpoint` in the
+// background. Configuration, server and mount errors are returned here,
+// errors of the session itself from MountHandle::join or unmount. A server
+// that stays unreachable, refused credentials and a mountpoint already in
+// use come back as a MountError.
+pub fn mount(config: MountConfig, mountpoint: &str) -> Result<MountHandle> {
+    let http = config.client.http.client()?;
+    let backend = connect(&config, http)?;
+    mount_client(backend, config, mountpoint)
+}
+
+// The backend `config` asks for, over `http` unless it does not speak HTTP
+fn connect(config: &MountConfig, http: Client) -> Result<Arc<dyn RemoteBackend>> {
+    match &config.backend {
+        BackendKind::Rest => Ok(Arc::new(ApiClient::with_http_client(
+            config.client.clone(),
+            http,
+        )?)),
+        #[cfg(feature = "webdav")]
+        BackendKind::WebDav => Ok(Arc::new(WebDavBackend::with_http_client(
+            config.client.clone(),
+            http,
+        )?)),
+        #[cfg(not(feature = "webdav"))]
+        BackendKind::WebDav => anyhow::bail!("Built without WebDAV support"),
+        #[cfg(feature = "s3")]
+        BackendKind::S3 => Ok(Arc::new(S3Backend::with_http_client(
+            config.client.clone(),
+            config.s3.clone(),
+            http,
+        )?)),
+        #[cfg(not(feature = "s3"))]
+        BackendKind::S3 => anyhow::bail!("Built without S3 support"),
+        #[cfg(feature = "sftp")]
+        BackendKind::Sftp => Ok(Arc::new(SftpBackend::with_config(
+            config.client.clone(),
+            config.sftp.clone(),
+        )?)),
+        #[cfg(not(feature = "sftp"))]
+        BackendKind::Sftp => anyhow::bail!("Built without SFTP support"),
+        #[cfg(feature = "grpc")]
+        BackendKind::Grpc => Ok(Arc::new(GrpcBackend::with_config(config.client.clone())?)),
+        #[cfg(not(feature = "grpc"))]
+        BackendKind::Grpc => anyhow::bail!("Built without gRPC support"),
+        BackendKind::Local(dir) => {
+            let backend = LocalBackend::new(dir, config.client.chunk_size)?;
+            backend.set_faults(config.faults);
+            Ok(Arc::new(backend))
+        }
+    }
+}
+
+// Like mount, over a backend built by the caller instead of the one
+// `config` names, such as a MockBackend
+pub fn mount_client(
+    backend: Arc<dyn RemoteBackend>,
+    config: MountConfig,
+    mountpoint: &str,
+) -> Result<MountHandle> {
+    let mounts = std::fs::read_to_string("/proc/mounts").unwrap_or_default();
+    let path = Path::new(mountpoint);
+    if is_mounted(&mounts, &path.canonicalize().unwrap_or(path.to_path_buf())) {
+        return Err(MountError::Busy(mountpoint.to_string()).into());
+    }
+    startup::wait_for_server(backend.as_ref(), config.startup)?;
+    // Missing optional features are settled now rather than as failures later
+    if let Err(e) = backend.negotiate_capabilities() {
+        log::warn!("Failed to negotiate server capabilities, assuming all: {:#}", e);
+    }
+
+    let fs = RemoteFS::with_backend(backend, config.fs);
+    let mut options = fs.mount_options()?;
+    options.extend(config.options);
+    fs.spawn_mount(mountpoint, &options)
+}
+
+// Serves an already built filesystem, for instance one over a custom backend
+pub fn mount_backend(fs: RemoteFS, mountpoint: &str) -> Result<MountHandle> {
+    fs.spawn_mount(mountpoint, &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_options_parse_by_name() {
+        let options =
+            MountConfig::parse_mount_options(" ro,allow_other,,fsname=remote,x-gvfs-hide");
+        assert_eq!(
+            options,
+            [
+                MountOption::RO,
+                MountOption::AllowOther,
+                MountOption::FSName("remote".to_string()),
+                MountOption::CUSTOM("x-gvfs-hide".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn mount_errors_are_returned() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let mut config = MountConfig::new(Vec::new());
+        config.backend = BackendKind::Local(missing.clone());
+        assert!(mount(config, dir.path().to_str().unwrap()).is_err());
+
+        let backend = Arc::new(MockBackend::new());
+        let config = MountConfig::new(Vec::new());
+        assert!(mount_client(backend, config, missing.to_str().unwrap()).is_err());
+    }
+
+    // The fuser filesystem is the only one: neither the old fuse and time
+    // crates nor a feature bringing them back are in the manifest
+    #[test]
+    fn only_the_fuser_implementation_is_built() {
+        let manifest: toml::Table = include_str!("../Cargo.toml").parse().unwrap();
+        let dependencies = manifest["dependencies"].as_table().unwrap();
+        assert!(dependencies.contains_key("fuser"));
+        for legacy in ["fuse", "time"] {
+            assert!(!dependencies.contains_key(legacy), "{} is a dependency", legacy);
+        }
+
+        let features = manifest["features"].as_table().unwrap();
+        assert!(!features.contains_key("legacy-fuse"));
+        assert!(features.get("default").is_none());
+    }
+}
@@ -0,0 +1,679 @@
+use anyhow::{Context, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::api_client::{
+    upload_mtime, Conditional, Expected, FileData, FileEntry, Listing, Timestamp, Version,
+};
+use crate::filesystem::{FsError, RemoteBackend};
+
+// Faults the local backend injects, to exercise caching, retries and
+// offline handling without a network. The same seed fails the same
+// operations.
+#[derive(Debug, Clone, Copy)]
+pub struct Faults {
+    // Slept before every operation
+    pub latency: Duration,
+    // Share of operations failing with `error`, from 0 to 1
+    pub error_rate: f64,
+    pub error: FsError,
+    pub seed: u64,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            error_rate: 0.0,
+            error: FsError::Unreachable,
+            seed: 1,
+        }
+    }
+}
+
+impl Faults {
+    pub fn parse_rate(rate: f64) -> Result<f64> {
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&rate),
+            "fault_error_rate must be between 0 and 1, got {}",
+            rate
+        );
+        Ok(rate)
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.latency.is_zero() || self.error_rate > 0.0
+    }
+}
+
+struct Injector {
+    faults: Faults,
+    // xorshift64 state, never zero
+    state: u64,
+}
+
+impl Injector {
+    fn new(faults: Faults) -> Self {
+        Self {
+            faults,
+            state: faults.seed.max(1),
+        }
+    }
+
+    fn next(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn io_error(what: String, error: io::Error) -> anyhow::Error {
+    let kind = match error.raw_os_error() {
+        Some(libc::ENOENT) => FsError::NotFound,
+        Some(libc::EACCES | libc::EPERM) => FsError::PermissionDenied,
+        Some(libc::EEXIST) => FsError::AlreadyExists,
+        Some(libc::ENOTDIR) => FsError::NotADirectory,
+        Some(libc::EISDIR) => FsError::IsADirectory,
+        Some(libc::ENOTEMPTY) => FsError::NotEmpty,
+        Some(libc::ENAMETOOLONG) => FsError::NameTooLong,
+        Some(libc::ENOSPC | libc::EDQUOT) => FsError::NoSpace,
+        Some(libc::EROFS) => FsError::ReadOnly,
+        Some(libc::EFBIG) => FsError::FileTooLarge,
+        _ => FsError::Io,
+    };
+    anyhow::Error::new(kind).context(format!("{}: {}", what, error))
+}
+
+fn set_modified(path: &Path, mtime: SystemTime) -> Result<()> {
+    let what = || format!("utimens {}", path.display());
+    let file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|e| io_error(what(), e))?;
+    file.set_modified(mtime).map_err(|e| io_error(what(), e))
+}
+
+fn mtime(metadata: &Metadata) -> Timestamp {
+    Timestamp::new(metadata.mtime(), metadata.mtime_nsec() as u32)
+}
+
+// Modification time and size, like the ETags of most web servers
+fn version(metadata: &Metadata) -> Version {
+    Version::ETag(format!(
+        "\"{:x}.{:x}-{:x}\"",
+        metadata.mtime(),
+        metadata.mtime_nsec(),
+        metadata.len()
+    ))
+}
+
+// A local directory served as the remote tree, for developing and testing
+// the filesystem without a server. Faults can be injected, see Faults.
+pub struct LocalBackend {
+    root: PathBuf,
+    endpoint: String,
+    chunk_size: u64,
+    injector: Mutex<Injector>,
+}
+
+impl LocalBackend {
+    pub fn new(root: &Path, chunk_size: u64) -> Result<Self> {
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("Cannot open {}", root.display()))?;
+        anyhow::ensure!(root.is_dir(), "{} is not a directory", root.display());
+        Ok(Self {
+            endpoint: format!("local:{}", root.display()),
+            root,
+            chunk_size,
+            injector: Mutex::new(Injector::new(Faults::default())),
+        })
+    }
+
+    // Replaces the faults injected from now on, restarting their sequence
+    pub fn set_faults(&self, faults: Faults) {
+        if faults.is_active() {
+            log::warn!("Injecting faults into {}: {:?}", self.endpoint, faults);
+        }
+        *self.injector.lock().unwrap() = Injector::new(faults);
+    }
+
+    fn inject(&self, what: &str) -> Result<()> {
+        let (latency, failed, error) = {
+            let mut injector = self.injector.lock().unwrap();
+            let faults = injector.faults;
+            let failed = faults.error_rate > 0.0 && injector.next() < faults.error_rate;
+            (faults.latency, failed, faults.error)
+        };
+        if !latency.is_zero() {
+            thread::sleep(latency);
+        }
+        if failed {
+            log::debug!("Injected {:?} into {}", error, what);
+            return Err(anyhow::Error::new(error).context(format!("Injected failure of {}", what)));
+        }
+        Ok(())
+    }
+
+    // Paths stay under the root
+    fn path(&self, path: &str) -> Result<PathBuf> {
+        let relative = Path::new(path.trim_matches('/'));
+        if relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return Err(anyhow::Error::new(FsError::PermissionDenied)
+                .context(format!("{} leaves the root", path)));
+        }
+        Ok(self.root.join(relative))
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Option<Metadata>> {
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(Some(metadata)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(io_error(format!("stat {}", path.display()), e)),
+        }
+    }
+
+    // Fails with Stale, as a 412 would, when `path` is not as expected
+    fn check(&self, path: &Path, expected: &Expected) -> Result<()> {
+        let current = self.metadata(path)?.map(|metadata| version(&metadata));
+        let met = match expected {
+            Expected::Any => true,
+            Expected::Absent => current.is_none(),
+            Expected::Version(expected) => current.as_ref() == Some(expected),
+        };
+        if !met {
+            return Err(
+                anyhow::Error::new(FsError::Stale).context(format!("{} changed", path.display()))
+            );
+        }
+        Ok(())
+    }
+
+    pub fn list_directory(&self, path: &str) -> Result<Listing> {
+        self.inject("list")?;
+        let dir = self.path(path)?;
+        let what = || format!("readdir {}", dir.display());
+        let mut children = fs::read_dir(&dir)
+            .map_err(|e| io_error(what(), e))?
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(|e| io_error(what(), e))?;
+        children.sort_by_key(|child| child.file_name());
+
+        // Directories have no version of their contents, one is made up
+        let mut fingerprint = DefaultHasher::new();
+        let mut entries = Vec::new();
+        for child in children {
+            let Ok(name) = child.file_name().into_string() else {
+                // Not reachable through the mount, which refuses such names
+                log::debug!("Leaving out {}, its name is not UTF-8", child.path().display());
+                continue;
+            };
+            // Links are followed, dangling ones left out
+            let Ok(metadata) = fs::metadata(child.path()) else {
+                continue;
+            };
+            let is_dir = metadata.is_dir();
+            (
+                &name,
+                is_dir,
+                metadata.len(),
+                metadata.mtime(),
+                metadata.mtime_nsec(),
+            )
+                .hash(&mut fingerprint);
+            entries.push(FileEntry {
+                name,
+                is_dir,
+                size: if is_dir { 0 } else { metadata.len() },
+                mtime: mtime(&metadata),
+                ctime: Timestamp::new(metadata.ctime(), metadata.ctime_nsec() as u32),
+                mode: metadata.mode() & 0o7777,
+                id: Some(metadata.ino()),
+                uid: Some(metadata.uid()),
+                gid: Some(metadata.gid()),
+                major: None,
+                minor: None,
+                nlink: is_dir.then(|| metadata.nlink() as u32),
+            });
+        }
+        Ok(Listing {
+            entries,
+            version: Some(Version::ETag(format!(
+                "W/\"{:016x}\"",
+                fingerprint.finish()
+            ))),
+        })
+    }
+
+    pub fn revalidate_listing(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Listing>> {
+        let listing = self.list_directory(path)?;
+        if listing.version.as_ref() == Some(version) {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified(listing))
+    }
+
+    pub fn revalidate_file(
+        &self,
+        path: &str,
+        cached: &Version,
+    ) -> Result<Conditional<Option<Version>>> {
+        self.inject("stat")?;
+        let current = match self.metadata(&self.path(path)?)? {
+            Some(metadata) => version(&metadata),
+            None => return Ok(Conditional::Modified(None)),
+        };
+        if &current == cached {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified(Some(current)))
+    }
+
+    pub fn file_version(&self, path: &str) -> Result<Option<Version>> {
+        self.inject("stat")?;
+        Ok(self
+            .metadata(&self.path(path)?)?
+            .map(|metadata| version(&metadata)))
+    }
+
+    pub fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        self.inject("read")?;
+        let path = self.path(path)?;
+        let what = || format!("read {}", path.display());
+        let file = File::open(&path).map_err(|e| io_error(what(), e))?;
+        let metadata = file.metadata().map_err(|e| io_error(what(), e))?;
+
+        let len = len.min(metadata.len().saturating_sub(offset));
+        let mut data = vec![0; len as usize];
+        let mut filled = 0;
+        while filled < data.len() {
+            match file.read_at(&mut data[filled..], offset + filled as u64) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(io_error(what(), e)),
+            }
+        }
+        data.truncate(filled);
+        Ok(FileData {
+            data,
+            version: Some(version(&metadata)),
+        })
+    }
+
+    pub fn write_file_if(
+        &self,
+        path: &str,
+        data: &[u8],
+        expected: &Expected,
+    ) -> Result<Option<Version>> {
+        self.inject("write")?;
+        let path = self.path(path)?;
+        self.check(&path, expected)?;
+        fs::write(&path, data).map_err(|e| io_error(format!("write {}", path.display()), e))?;
+        self.keep_upload_mtime(&path)?;
+        Ok(self.metadata(&path)?.map(|metadata| version(&metadata)))
+    }
+
+    pub fn write_file_streamed(
+        &self,
+        path: &str,
+        len: u64,
+        open: &dyn Fn() -> Box<dyn Read + Send>,
+    ) -> Result<()> {
+        self.inject("write")?;
+        let path = self.path(path)?;
+        let what = || format!("write {}", path.display());
+        let mut file = File::create(&path).map_err(|e| io_error(what(), e))?;
+        io::copy(&mut open().take(len), &mut file).map_err(|e| io_error(what(), e))?;
+        self.keep_upload_mtime(&path)
+    }
+
+    pub fn write_range(&self, path: &str, offset: u64, data: &[u8]) -> Result<bool> {
+        self.inject("write")?;
+        let path = self.path(path)?;
+        let what = || format!("write {}", path.display());
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .map_err(|e| io_error(what(), e))?;
+        file.write_all_at(data, offset)
+            .map_err(|e| io_error(what(), e))?;
+        self.keep_upload_mtime(&path)?;
+        Ok(true)
+    }
+
+    // Like the server does with X-Mtime, see with_upload_mtime
+    fn keep_upload_mtime(&self, path: &Path) -> Result<()> {
+        match upload_mtime() {
+            Some(mtime) => set_modified(path, mtime),
+            None => Ok(()),
+        }
+    }
+
+    pub fn set_mtime(&self, path: &str, mtime: SystemTime) -> Result<()> {
+        self.inject("utimens")?;
+        set_modified(&self.path(path)?, mtime)
+    }
+
+    pub fn create_directory(&self, path: &str) -> Result<()> {
+        self.inject("mkdir")?;
+        let path = self.path(path)?;
+        fs::create_dir(&path).map_err(|e| io_error(format!("mkdir {}", path.display()), e))
+    }
+
+    // Directories are deleted with everything under them, like the REST
+    // server does; the filesystem checks they are empty first
+    pub fn delete_if(&self, path: &str, expected: &Expected) -> Result<()> {
+        self.inject("delete")?;
+        let path = self.path(path)?;
+        let what = || format!("remove {}", path.display());
+        self.check(&path, expected)?;
+        let metadata = fs::symlink_metadata(&path).map_err(|e| io_error(what(), e))?;
+        let result = if metadata.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        result.map_err(|e| io_error(what(), e))
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.inject("rename")?;
+        let (from, to) = (self.path(from)?, self.path(to)?);
+        fs::rename(&from, &to)
+            .map_err(|e| io_error(format!("rename {} to {}", from.display(), to.display()), e))
+    }
+
+    pub fn check_reachable(&self) -> Result<()> {
+        self.inject("check")?;
+        anyhow::ensure!(self.root.is_dir(), "{} is gone", self.root.display());
+        Ok(())
+    }
+}
+
+impl RemoteBackend for LocalBackend {
+    fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    fn check_reachable(&self, _timeout: Duration) -> Result<()> {
+        LocalBackend::check_reachable(self)
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Listing> {
+        LocalBackend::list_directory(self, path)
+    }
+
+    fn revalidate_listing(&self, path: &str, version: &Version) -> Result<Conditional<Listing>> {
+        LocalBackend::revalidate_listing(self, path, version)
+    }
+
+    fn revalidate_file(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Option<Version>>> {
+        LocalBackend::revalidate_file(self, path, version)
+    }
+
+    fn file_version(&self, path: &str) -> Result<Option<Version>> {
+        LocalBackend::file_version(self, path)
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        LocalBackend::read_range(self, path, offset, len)
+    }
+
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<()> {
+        self.write_file_if(path, data, &Expected::Any).map(|_| ())
+    }
+
+    fn write_file_if(
+        &self,
+        path: &str,
+        data: &[u8],
+        expected: &Expected,
+    ) -> Result<Option<Version>> {
+        LocalBackend::write_file_if(self, path, data, expected)
+    }
+
+    fn write_file_streamed(
+        &self,
+        path: &str,
+        len: u64,
+        open: &dyn Fn() -> Box<dyn Read + Send>,
+    ) -> Result<()> {
+        LocalBackend::write_file_streamed(self, path, len, open)
+    }
+
+    fn write_range(&self, path: &str, offset: u64, data: &[u8]) -> Result<bool> {
+        LocalBackend::write_range(self, path, offset, data)
+    }
+
+    fn set_mtime(&self, path: &str, mtime: SystemTime) -> Result<()> {
+        LocalBackend::set_mtime(self, path, mtime)
+    }
+
+    fn create_directory(&self, path: &str) -> Result<()> {
+        LocalBackend::create_directory(self, path)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.delete_if(path, &Expected::Any)
+    }
+
+    fn delete_if(&self, path: &str, expected: &Expected) -> Result<()> {
+        LocalBackend::delete_if(self, path, expected)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        LocalBackend::rename(self, from, to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn backend() -> (tempfile::TempDir, LocalBackend) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("docs")).unwrap();
+        fs::write(dir.path().join("docs/a.txt"), b"hello world").unwrap();
+        let backend = LocalBackend::new(dir.path(), 1 << 20).unwrap();
+        (dir, backend)
+    }
+
+    fn kind(error: anyhow::Error) -> FsError {
+        FsError::from_backend(&error)
+    }
+
+    #[test]
+    fn paths_stay_under_the_root() {
+        let (dir, local) = backend();
+        let root = dir.path().canonicalize().unwrap();
+        assert_eq!(local.path("/docs/a.txt/").unwrap(), root.join("docs/a.txt"));
+        assert_eq!(local.path("/").unwrap(), root);
+        for path in ["/../etc", "/docs/../..", "/./docs"] {
+            assert_eq!(kind(local.path(path).unwrap_err()), FsError::PermissionDenied);
+        }
+        assert!(local.endpoint().starts_with("local:/"));
+        assert!(LocalBackend::new(&root.join("docs/a.txt"), 1 << 20).is_err());
+        assert!(LocalBackend::new(&root.join("missing"), 1 << 20).is_err());
+    }
+
+    #[test]
+    fn listings_change_version_with_their_contents() {
+        let (dir, local) = backend();
+        std::os::unix::fs::symlink("nowhere", dir.path().join("dangling")).unwrap();
+        let listing = local.list_directory("/").unwrap();
+        assert_eq!(listing.entries.len(), 1);
+        let docs = &listing.entries[0];
+        assert_eq!((docs.name.as_str(), docs.is_dir, docs.size), ("docs", true, 0));
+        assert_eq!(docs.nlink, Some(2));
+
+        let files = local.list_directory("/docs").unwrap();
+        assert_eq!(files.entries[0].size, 11);
+        assert_eq!(files.entries[0].nlink, None);
+        let version = files.version.unwrap();
+        assert!(matches!(
+            local.revalidate_listing("/docs", &version).unwrap(),
+            Conditional::NotModified
+        ));
+        fs::write(dir.path().join("docs/b.txt"), b"").unwrap();
+        assert!(matches!(
+            local.revalidate_listing("/docs", &version).unwrap(),
+            Conditional::Modified(_)
+        ));
+        assert_eq!(kind(local.list_directory("/missing").unwrap_err()), FsError::NotFound);
+        assert_eq!(
+            kind(local.list_directory("/docs/a.txt").unwrap_err()),
+            FsError::NotADirectory
+        );
+    }
+
+    #[test]
+    fn names_that_are_not_utf8_are_left_out() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let (dir, local) = backend();
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+        fs::write(dir.path().join("docs").join(name), b"").unwrap();
+        let files = local.list_directory("/docs").unwrap();
+        let names: Vec<_> = files.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["a.txt"]);
+    }
+
+    #[test]
+    fn reads_stop_at_the_end() {
+        let (_dir, local) = backend();
+        assert_eq!(local.read_range("/docs/a.txt", 6, 100).unwrap().data, b"world");
+        assert!(local.read_range("/docs/a.txt", 100, 10).unwrap().data.is_empty());
+        let read = local.read_range("/docs/a.txt", 0, 5).unwrap();
+        assert_eq!(read.data, b"hello");
+        assert_eq!(read.version, local.file_version("/docs/a.txt").unwrap());
+        assert_eq!(kind(local.read_range("/gone", 0, 1).unwrap_err()), FsError::NotFound);
+        assert_eq!(local.file_version("/gone").unwrap(), None);
+    }
+
+    #[test]
+    fn conditional_writes_and_deletes_check_the_version() {
+        let (dir, local) = backend();
+        let version = local.file_version("/docs/a.txt").unwrap().unwrap();
+        let stale = Expected::Version(Version::ETag("\"0.0-0\"".to_string()));
+        let error = local.write_file_if("/docs/a.txt", b"lost", &stale).unwrap_err();
+        assert_eq!(kind(error), FsError::Stale);
+        let error = local.write_file_if("/docs/a.txt", b"x", &Expected::Absent).unwrap_err();
+        assert_eq!(kind(error), FsError::Stale);
+
+        let written = local
+            .write_file_if("/docs/a.txt", b"new", &Expected::Version(version))
+            .unwrap();
+        assert_eq!(fs::read(dir.path().join("docs/a.txt")).unwrap(), b"new");
+        assert_eq!(written, local.file_version("/docs/a.txt").unwrap());
+
+        assert_eq!(kind(local.delete_if("/docs/a.txt", &stale).unwrap_err()), FsError::Stale);
+        local.delete_if("/docs/a.txt", &Expected::Version(written.unwrap())).unwrap();
+        assert!(!dir.path().join("docs/a.txt").exists());
+    }
+
+    #[test]
+    fn writes_keep_the_upload_mtime() {
+        let (dir, local) = backend();
+        let mtime = UNIX_EPOCH + Duration::new(1_600_000_000, 5);
+        crate::api_client::with_upload_mtime(mtime, || {
+            local.write_range("/docs/a.txt", 6, b"there").unwrap();
+        });
+        let path = dir.path().join("docs/a.txt");
+        assert_eq!(fs::read(&path).unwrap(), b"hello there");
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+        // Listed to the nanosecond
+        let files = local.list_directory("/docs").unwrap();
+        assert_eq!(files.entries[0].mtime, Timestamp::new(1_600_000_000, 5));
+
+        let later = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        local.set_mtime("/docs/a.txt", later).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), later);
+    }
+
+    #[test]
+    fn tree_changes_map_to_their_errors() {
+        let (dir, local) = backend();
+        local.create_directory("/new").unwrap();
+        assert_eq!(kind(local.create_directory("/new").unwrap_err()), FsError::AlreadyExists);
+        assert_eq!(kind(local.create_directory("/a/b").unwrap_err()), FsError::NotFound);
+        local.write_file("/new/x", b"x").unwrap();
+        local.rename("/new", "/moved").unwrap();
+        assert!(dir.path().join("moved/x").exists());
+        assert_eq!(kind(local.rename("/moved", "/docs").unwrap_err()), FsError::NotEmpty);
+        local.delete("/moved").unwrap();
+        assert!(!dir.path().join("moved").exists());
+        assert_eq!(kind(local.delete("/moved").unwrap_err()), FsError::NotFound);
+    }
+
+    #[test]
+    fn faults_repeat_with_their_seed() {
+        let (_dir, local) = backend();
+        let faults = Faults {
+            error_rate: 0.5,
+            error: FsError::Busy,
+            seed: 42,
+            ..Faults::default()
+        };
+        let outcomes = || {
+            local.set_faults(faults);
+            (0..64)
+                .map(|_| match local.file_version("/docs/a.txt") {
+                    Ok(_) => None,
+                    Err(e) => Some(kind(e)),
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = outcomes();
+        assert_eq!(first, outcomes());
+        let failed = first.iter().filter(|outcome| outcome.is_some()).count();
+        assert!((16..48).contains(&failed), "{} of 64 failed", failed);
+        assert!(first.iter().flatten().all(|kind| *kind == FsError::Busy));
+
+        local.set_faults(Faults {
+            error_rate: 1.0,
+            ..Faults::default()
+        });
+        assert_eq!(kind(local.check_reachable().unwrap_err()), FsError::Unreachable);
+        local.set_faults(Faults::default());
+        local.check_reachable().unwrap();
+    }
+
+    #[test]
+    fn faults_are_checked() {
+        assert!(!Faults::default().is_active());
+        let slow = Faults {
+            latency: Duration::from_millis(1),
+            ..Faults::default()
+        };
+        assert!(slow.is_active());
+        assert_eq!(Faults::parse_rate(0.25).unwrap(), 0.25);
+        assert!(Faults::parse_rate(1.5).is_err());
+        assert!(Faults::parse_rate(-0.1).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::Result;
+use log::kv::{Error as KvError, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+use serde_json::{Map, Value as Json};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// How log records are written to stderr
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // `[timestamp LEVEL target] message`, for people
+    #[default]
+    Text,
+    // One JSON object per line, with the structured fields of operation
+    // traces next to the message, for log pipelines
+    Json,
+}
+
+impl LogFormat {
+    // Parses `--log-format`
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => anyhow::bail!("Unknown log format '{}', expected text or json", other),
+        }
+    }
+}
+
+// Installs a logger writing records up to `level` in `format`. Fails when a
+// logger was already installed. The level can be changed later with
+// log::set_max_level.
+pub fn init_logging(format: LogFormat, level: LevelFilter) -> Result<()> {
+    log::set_logger(Box::leak(Box::new(Logger { format })))
+        .map_err(|_| anyhow::anyhow!("A logger is already installed"))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+struct Logger {
+    format: LogFormat,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_record(self.format, &Fields::of(record));
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+// What both formats are made of
+struct Fields {
+    timestamp: String,
+    level: log::Level,
+    target: String,
+    message: String,
+    // Key-value pairs attached to the record, like op and duration_ms
+    extra: Map<String, Json>,
+}
+
+impl Fields {
+    fn of(record: &Record) -> Self {
+        let mut extra = Collect(Map::new());
+        let _ = record.key_values().visit(&mut extra);
+        Self {
+            timestamp: rfc3339(SystemTime::now()),
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            extra: extra.0,
+        }
+    }
+}
+
+struct Collect(Map<String, Json>);
+
+impl<'kvs> VisitSource<'kvs> for Collect {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), KvError> {
+        let value = if let Some(n) = value.to_u64() {
+            Json::from(n)
+        } else if let Some(n) = value.to_i64() {
+            Json::from(n)
+        } else if let Some(n) = value.to_f64() {
+            Json::from(n)
+        } else if let Some(flag) = value.to_bool() {
+            Json::from(flag)
+        } else {
+            Json::from(value.to_string())
+        };
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
+
+// Text lines carry only the message, which operation traces already fill
+// with their fields
+fn format_record(format: LogFormat, fields: &Fields) -> String {
+    match format {
+        LogFormat::Text => format!(
+            "[{} {:<5} {}] {}",
+            fields.timestamp, fields.level, fields.target, fields.message
+        ),
+        LogFormat::Json => {
+            let mut object = Map::new();
+            object.insert("timestamp".into(), fields.timestamp.clone().into());
+            object.insert("level".into(), fields.level.as_str().into());
+            object.insert("target".into(), fields.target.clone().into());
+            object.insert("message".into(), fields.message.clone().into());
+            for (key, value) in &fields.extra {
+                object.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+            Json::Object(object).to_string()
+        }
+    }
+}
+
+// UTC with milliseconds, e.g. 2024-05-01T12:00:00.000Z
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rest) = (secs / 86_400, secs % 86_400);
+
+    // Days since the epoch to a civil date, after Howard Hinnant's algorithm
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rest / 3_600,
+        rest % 3_600 / 60,
+        rest % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn fields_of(record: &Record) -> Fields {
+        let mut fields = Fields::of(record);
+        fields.timestamp = "2024-05-01T12:00:00.000Z".to_string();
+        fields
+    }
+
+    #[test]
+    fn both_formats_come_from_the_same_fields() {
+        let kvs: [(&str, Value); 3] = [
+            ("op", Value::from("read")),
+            ("duration_ms", Value::from(1.5)),
+            ("errno", Value::from(2i32)),
+        ];
+        let record = Record::builder()
+            .level(log::Level::Warn)
+            .target("remotefs::filesystem")
+            .args(format_args!("op=read errno=2"))
+            .key_values(&kvs)
+            .build();
+        let fields = fields_of(&record);
+
+        assert_eq!(
+            format_record(LogFormat::Text, &fields),
+            "[2024-05-01T12:00:00.000Z WARN  remotefs::filesystem] op=read errno=2"
+        );
+        let line = format_record(LogFormat::Json, &fields);
+        let json: Json = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["timestamp"], "2024-05-01T12:00:00.000Z");
+        assert_eq!(json["level"], "WARN");
+        assert_eq!(json["target"], "remotefs::filesystem");
+        assert_eq!(json["message"], "op=read errno=2");
+        assert_eq!(json["op"], "read");
+        assert_eq!(json["duration_ms"], 1.5);
+        assert_eq!(json["errno"], 2);
+    }
+
+    #[test]
+    fn fields_do_not_replace_the_record_keys() {
+        let kvs: [(&str, Value); 1] = [("message", Value::from("forged"))];
+        let record = Record::builder()
+            .args(format_args!("real"))
+            .key_values(&kvs)
+            .build();
+        let line = format_record(LogFormat::Json, &fields_of(&record));
+        let json: Json = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["message"], "real");
+    }
+
+    #[test]
+    fn formats_parse_by_name() {
+        assert_eq!(LogFormat::parse("json").unwrap(), LogFormat::Json);
+        assert_eq!(LogFormat::parse(" Text ").unwrap(), LogFormat::Text);
+        assert!(LogFormat::parse("yaml").is_err());
+    }
+
+    #[test]
+    fn timestamps_are_utc_with_milliseconds() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(rfc3339(time), "2024-02-29T12:34:56.789Z");
+    }
+}
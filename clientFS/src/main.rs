@@ -0,0 +1,149 @@
+use anyhow::Context;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use remotefs::{
+    control, daemonize, init_logging, mount, unmount, ApiClient, BackendKind, ConfigFile,
+    Invocation, LogFormat, MountError, Supervisor,
+};
+
+// Buffered writes get this long to reach the server after a signal
+const FLUSH_DEADLINE: Duration = Duration::from_secs(30);
+
+fn main() -> ExitCode {
+    let invoked_as = env::args().next().unwrap_or_default();
+    let invocation = if Path::new(&invoked_as).file_name() == Some("mount.remotefs".as_ref()) {
+        Invocation::from_mount_helper(env::args().skip(1))
+    } else {
+        Invocation::from_matches(&Invocation::command().get_matches())
+    };
+
+    let status = match invocation {
+        Ok(invocation) => run(invocation),
+        Err(e) => {
+            eprintln!("remotefs: {:#}", e);
+            1
+        }
+    };
+    ExitCode::from(status as u8)
+}
+
+fn run(invocation: Invocation) -> i32 {
+    match invocation {
+        Invocation::Mount {
+            config,
+            mountpoint,
+            daemon,
+            log_level,
+        } => {
+            // Before any thread is started, and with stderr still the terminal
+            let mut daemon = match daemon.as_ref().map(daemonize).transpose() {
+                Ok(daemon) => daemon,
+                Err(e) => {
+                    eprintln!("remotefs: {:#}", e);
+                    return 1;
+                }
+            };
+            let served = init_logging(config.log_format, log_level)
+                .and_then(|_| mount(*config, &mountpoint))
+                .and_then(|handle| {
+                    if let Some(daemon) = &mut daemon {
+                        daemon.ready();
+                    }
+                    handle.run_until_signal(FLUSH_DEADLINE)
+                });
+            match served {
+                Ok(()) => 0,
+                Err(e) => {
+                    // A daemon's parent prints it, the terminal is gone
+                    match &mut daemon {
+                        Some(daemon) => daemon.failed(&e),
+                        None => eprintln!("remotefs: {:#}", e),
+                    }
+                    MountError::exit_code_of(&e)
+                }
+            }
+        }
+        Invocation::Unmount { mountpoint, lazy } => match unmount(&mountpoint, lazy) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("remotefs: {}: {}", mountpoint.display(), e);
+                e.exit_code()
+            }
+        },
+        Invocation::Check {
+            config,
+            read_only,
+            log_level,
+        } => {
+            let checked = init_logging(config.log_format, log_level).and_then(|_| {
+                anyhow::ensure!(
+                    config.backend == BackendKind::Rest,
+                    "check only knows the REST API"
+                );
+                let http = config.client.http.client()?;
+                let client = ApiClient::with_http_client(config.client, http)
+                    .context("Failed to create client")?;
+                Ok(client.self_test(read_only))
+            });
+            match checked {
+                Ok(report) => {
+                    print!("{}", report);
+                    i32::from(!report.missing_mandatory().is_empty())
+                }
+                Err(e) => {
+                    eprintln!("remotefs: {:#}", e);
+                    1
+                }
+            }
+        }
+        Invocation::Supervise {
+            path,
+            profiles,
+            log_level,
+        } => {
+            let served = init_logging(LogFormat::default(), log_level).and_then(|_| {
+                let path = path
+                    .or_else(|| env::var_os("REMOTEFS_CONFIG").map(PathBuf::from))
+                    .or_else(ConfigFile::default_path)
+                    .context("No configuration file to read the mounts from")?;
+                let file = ConfigFile::load(&path)?;
+                file.validate()?;
+                Supervisor::from_config(&file, &profiles)?.run_until_signal(FLUSH_DEADLINE)
+            });
+            match served {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("remotefs: {:#}", e);
+                    MountError::exit_code_of(&e)
+                }
+            }
+        }
+        Invocation::Control { socket, request } => match control(&socket, &request) {
+            Ok(result) => {
+                if !result.is_null() {
+                    println!("{:#}", result);
+                }
+                0
+            }
+            Err(e) => {
+                eprintln!("remotefs: {:#}", e);
+                1
+            }
+        },
+        Invocation::ValidateConfig { path } => {
+            match ConfigFile::load(&path).and_then(|file| file.validate()) {
+                Ok(()) => {
+                    println!("{}: ok", path.display());
+                    0
+                }
+                Err(e) => {
+                    eprintln!("remotefs: {:#}", e);
+                    1
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use crate::api_client::Secret;
+
+#[cfg(feature = "s3")]
+mod backend;
+#[cfg(feature = "s3")]
+mod credentials;
+#[cfg(feature = "s3")]
+mod sigv4;
+#[cfg(feature = "s3")]
+mod xml;
+
+#[cfg(feature = "s3")]
+pub use backend::S3Backend;
+
+pub const DEFAULT_MULTIPART_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+// Where and as whom an S3 bucket is reached. The bucket and the prefix the
+// mount is rooted at come from the server URL, `s3://bucket/prefix`. Unset
+// fields are looked up the way the AWS tools do.
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    // Defaults to AWS_REGION, AWS_DEFAULT_REGION, the region of the profile,
+    // then us-east-1
+    pub region: Option<String>,
+    // MinIO or another S3-compatible server, addressed path-style like
+    // `http://minio:9000/bucket/key`. AWS itself is addressed
+    // virtual-hosted style.
+    pub endpoint: Option<String>,
+    // Taken from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY when unset, and
+    // then from the shared credentials file
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<Secret>,
+    pub session_token: Option<Secret>,
+    // Profile of ~/.aws/credentials, defaults to AWS_PROFILE then "default"
+    pub profile: Option<String>,
+    // Larger uploads go up in parts
+    pub multipart_threshold: u64,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            region: None,
+            endpoint: None,
+            access_key_id: None,
+            secret_access_key: None,
+            session_token: None,
+            profile: None,
+            multipart_threshold: DEFAULT_MULTIPART_THRESHOLD,
+        }
+    }
+}
@@ -0,0 +1,908 @@
+use anyhow::{Context, Result};
+use reqwest::blocking::{Body, Client, RequestBuilder, Response};
+use reqwest::header::RANGE;
+use reqwest::{Method, StatusCode};
+use std::collections::HashSet;
+use std::io::Read;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime};
+
+use super::sigv4::{self, Canonical, Signer, UNSIGNED_PAYLOAD};
+use super::xml::{self, ListPage};
+use super::S3Config;
+use crate::api_client::{
+    encode_path, last_byte, ClientConfig, Conditional, Expected, FileData, FileEntry, Listing,
+    ServerError, Timestamp, Version,
+};
+use crate::dates::parse_rfc3339;
+use crate::filesystem::{FsError, RemoteBackend};
+
+// Parts of a multipart upload are this large, or larger for files that
+// would otherwise need more than MAX_PARTS of them
+const PART_SIZE: u64 = 16 * 1024 * 1024;
+const MAX_PARTS: u64 = 10_000;
+
+fn s3_error(operation: &str, key: &str, response: &Response) -> anyhow::Error {
+    anyhow::Error::new(ServerError::from(response))
+        .context(format!("{} of {} failed", operation, key))
+}
+
+fn not_found(path: &str) -> anyhow::Error {
+    anyhow::Error::new(FsError::NotFound).context(format!("/{} does not exist", path))
+}
+
+// An S3 bucket, or a prefix of one, served as a directory tree. Keys are
+// paths; directories are the prefixes up to a slash, with an empty
+// `dir/` object marking those created through the mount. Directories that
+// only exist because keys run through them are listed all the same.
+pub struct S3Backend {
+    config: ClientConfig,
+    client: Client,
+    signer: Signer,
+    // Scheme and host of every request, and the path keys are under:
+    // "/bucket" path-style, empty virtual-hosted style
+    origin: String,
+    host: String,
+    bucket_path: String,
+    bucket: String,
+    // Where the mount is rooted in the bucket, empty or ending in a slash
+    prefix: String,
+    multipart_threshold: u64,
+    consecutive_failures: AtomicU32,
+}
+
+impl S3Backend {
+    pub fn with_config(config: ClientConfig, s3: S3Config) -> Result<Self> {
+        let client = config.http.client()?;
+        Self::with_http_client(config, s3, client)
+    }
+
+    // The bucket is named by the first server URL, `s3://bucket/prefix`
+    pub fn with_http_client(config: ClientConfig, s3: S3Config, client: Client) -> Result<Self> {
+        let url = config
+            .base_urls
+            .first()
+            .context("At least one server URL is required")?;
+        let rest = url
+            .strip_prefix("s3://")
+            .with_context(|| format!("Expected s3://bucket/prefix, got {}", url))?;
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        anyhow::ensure!(!bucket.is_empty(), "No bucket in {}", url);
+        let prefix = match prefix.trim_matches('/') {
+            "" => String::new(),
+            prefix => format!("{}/", prefix),
+        };
+        ClientConfig::validate_chunk_size(config.chunk_size)?;
+
+        let region = s3.resolved_region();
+        let (origin, bucket_path) = match &s3.endpoint {
+            Some(endpoint) => (
+                endpoint.trim_end_matches('/').to_string(),
+                format!("/{}", encode_path(bucket)),
+            ),
+            None => (
+                format!("https://{}.s3.{}.amazonaws.com", bucket, region),
+                String::new(),
+            ),
+        };
+        let host = match origin.split_once("://") {
+            Some(("https", host)) => host.trim_end_matches(":443"),
+            Some(("http", host)) => host.trim_end_matches(":80"),
+            _ => anyhow::bail!("S3 endpoint {} is not an http(s) URL", origin),
+        };
+        let host = host.split('/').next().unwrap_or_default().to_string();
+        let signer = Signer::new(s3.credentials()?, region);
+
+        Ok(Self {
+            bucket: bucket.to_string(),
+            client,
+            signer,
+            origin,
+            host,
+            bucket_path,
+            prefix,
+            multipart_threshold: s3.multipart_threshold,
+            config,
+            consecutive_failures: AtomicU32::new(0),
+        })
+    }
+
+    fn key(&self, path: &str) -> String {
+        format!("{}{}", self.prefix, path.trim_matches('/'))
+    }
+
+    // The prefix of everything in directory `path`
+    fn dir_key(&self, path: &str) -> String {
+        let key = self.key(path);
+        if key.is_empty() || key.ends_with('/') {
+            return key;
+        }
+        key + "/"
+    }
+
+    // A signed request for `key`. `headers` are signed along; others, like
+    // Range or preconditions, can be added to the builder.
+    fn request(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        payload_hash: &str,
+    ) -> RequestBuilder {
+        let path = format!("{}/{}", self.bucket_path, encode_path(key));
+        let query = sigv4::canonical_query(query);
+        let date = sigv4::amz_date(SystemTime::now());
+        let canonical = Canonical {
+            method: method.as_str(),
+            host: &self.host,
+            path: &path,
+            query: &query,
+            headers,
+            payload_hash,
+        };
+        let signed = self.signer.sign(&canonical, &date);
+
+        let mut url = format!("{}{}", self.origin, path);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+        let mut request = self
+            .client
+            .request(method, url)
+            .timeout(self.config.timeout);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        for (name, value) in signed {
+            request = request.header(name, value);
+        }
+        request
+    }
+
+    fn send(&self, request: RequestBuilder) -> Result<Response> {
+        match request.send() {
+            Ok(response) => {
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+                Ok(response)
+            }
+            Err(e) => {
+                self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+                Err(e.into())
+            }
+        }
+    }
+
+    fn empty_hash() -> String {
+        sigv4::sha256_hex(b"")
+    }
+
+    // Every page of ListObjectsV2 under `prefix`. With `delimiter` deeper
+    // keys are folded into common prefixes, the subdirectories.
+    fn list(&self, prefix: &str, delimiter: bool) -> Result<ListPage> {
+        let mut all = ListPage::default();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if delimiter {
+                query.push(("delimiter", "/"));
+            }
+            if let Some(token) = token.as_deref() {
+                query.push(("continuation-token", token));
+            }
+            let response = self
+                .send(self.request(Method::GET, "", &query, &[], &Self::empty_hash()))
+                .context("Failed to send list request")?;
+            if !response.status().is_success() {
+                return Err(s3_error("ListObjectsV2", prefix, &response));
+            }
+            let body = response.text().context("Failed to read list response")?;
+            let page = xml::list_page(&body)?;
+            all.objects.extend(page.objects);
+            all.prefixes.extend(page.prefixes);
+            match page.next_token {
+                Some(next) => token = Some(next),
+                None => return Ok(all),
+            }
+        }
+    }
+
+    // HeadObject, None when there is no such key
+    fn head(&self, key: &str) -> Result<Option<Response>> {
+        let response = self
+            .send(self.request(Method::HEAD, key, &[], &[], &Self::empty_hash()))
+            .context("Failed to send HEAD request")?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response)),
+            _ => Err(s3_error("HeadObject", key, &response)),
+        }
+    }
+
+    pub fn list_directory(&self, path: &str) -> Result<Listing> {
+        let dir = self.dir_key(path);
+        log::debug!("ListObjectsV2 {}", dir);
+        let page = self.list(&dir, true)?;
+
+        let mut entries = Vec::new();
+        let mut dirs = HashSet::new();
+        // ListObjectsV2 has no version, one is made up of what it returned
+        let mut fingerprint = String::new();
+        for prefix in &page.prefixes {
+            let name = prefix[dir.len()..].trim_end_matches('/');
+            if name.is_empty() || name.contains('/') || !dirs.insert(name.to_string()) {
+                continue;
+            }
+            fingerprint.push_str(&format!("{}/\n", name));
+            entries.push(FileEntry {
+                name: name.to_string(),
+                is_dir: true,
+                size: 0,
+                mtime: Timestamp::UNKNOWN,
+                ctime: Timestamp::UNKNOWN,
+                mode: 0o755,
+                id: None,
+                uid: None,
+                gid: None,
+                major: None,
+                minor: None,
+                nlink: None,
+            });
+        }
+
+        let mut marker = false;
+        for object in &page.objects {
+            let name = &object.key[dir.len()..];
+            if name.is_empty() {
+                marker = true;
+                continue;
+            }
+            // `a` and `a/...` both exist, the directory wins
+            if dirs.contains(name) {
+                log::debug!("Object {} hidden by the directory of that name", object.key);
+                continue;
+            }
+            let etag = object.etag.as_deref().unwrap_or_default();
+            fingerprint.push_str(&format!("{} {} {}\n", name, object.size, etag));
+            let mtime = object
+                .last_modified
+                .as_deref()
+                .and_then(parse_rfc3339)
+                .map_or(Timestamp::UNKNOWN, Timestamp::from_secs_f64);
+            entries.push(FileEntry {
+                name: name.to_string(),
+                is_dir: false,
+                size: object.size,
+                mtime,
+                ctime: mtime,
+                mode: 0o644,
+                id: None,
+                uid: None,
+                gid: None,
+                major: None,
+                minor: None,
+                nlink: None,
+            });
+        }
+
+        if entries.is_empty() && !marker && dir != self.prefix {
+            return Err(not_found(path.trim_matches('/')));
+        }
+        let version = Version::ETag(format!(
+            "W/\"{}\"",
+            &sigv4::sha256_hex(fingerprint.as_bytes())[..32]
+        ));
+        Ok(Listing {
+            entries,
+            version: Some(version),
+        })
+    }
+
+    pub fn revalidate_listing(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Listing>> {
+        let listing = self.list_directory(path)?;
+        if listing.version.as_ref() == Some(version) {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified(listing))
+    }
+
+    pub fn revalidate_file(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Option<Version>>> {
+        let current = match self.head(&self.key(path))? {
+            Some(response) => Version::from_response(&response),
+            None => return Ok(Conditional::Modified(None)),
+        };
+        if current.as_ref() == Some(version) {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified(current))
+    }
+
+    pub fn file_version(&self, path: &str) -> Result<Option<Version>> {
+        Ok(self
+            .head(&self.key(path))?
+            .and_then(|response| Version::from_response(&response)))
+    }
+
+    pub fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        if len == 0 {
+            return Ok(FileData {
+                data: Vec::new(),
+                version: None,
+            });
+        }
+        let key = self.key(path);
+        log::debug!("GetObject {} ({} bytes at {})", key, len, offset);
+        let range = format!("bytes={}-{}", offset, last_byte(offset, len)?);
+        let request = self
+            .request(Method::GET, &key, &[], &[], &Self::empty_hash())
+            .header(RANGE, range);
+        let response = self.send(request).context("Failed to send read request")?;
+
+        let status = response.status();
+        let version = Version::from_response(&response);
+        if status == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(FileData {
+                data: Vec::new(),
+                version,
+            });
+        }
+        if !status.is_success() {
+            return Err(s3_error("GetObject", &key, &response));
+        }
+        let bytes = response.bytes().context("Failed to read response")?;
+        let data = if status == StatusCode::PARTIAL_CONTENT {
+            bytes.to_vec()
+        } else {
+            let start = (offset as usize).min(bytes.len());
+            let end = (offset + len).min(bytes.len() as u64) as usize;
+            bytes[start..end].to_vec()
+        };
+        Ok(FileData { data, version })
+    }
+
+    fn put(&self, key: &str, data: Vec<u8>, expected: &Expected) -> Result<Option<Version>> {
+        let hash = sigv4::sha256_hex(&data);
+        let request = self.request(Method::PUT, key, &[], &[], &hash).body(data);
+        let response = self
+            .send(expected.condition(request))
+            .context("Failed to send write request")?;
+        if !response.status().is_success() {
+            return Err(s3_error("PutObject", key, &response));
+        }
+        Ok(Version::from_response(&response))
+    }
+
+    // Uploads `len` bytes of `reader` in parts, aborting the upload when a
+    // part or the completion fails so no parts are left billed
+    fn multipart(
+        &self,
+        key: &str,
+        len: u64,
+        reader: &mut dyn Read,
+        expected: &Expected,
+    ) -> Result<Option<Version>> {
+        log::debug!("Multipart upload of {} ({} bytes)", key, len);
+        let response = self
+            .send(self.request(
+                Method::POST,
+                key,
+                &[("uploads", "")],
+                &[],
+                &Self::empty_hash(),
+            ))
+            .context("Failed to start multipart upload")?;
+        if !response.status().is_success() {
+            return Err(s3_error("CreateMultipartUpload", key, &response));
+        }
+        let upload_id = xml::upload_id(&response.text()?)?;
+
+        let result = self.upload_parts(key, len, reader, &upload_id, expected);
+        if result.is_err() {
+            let abort = self.request(
+                Method::DELETE,
+                key,
+                &[("uploadId", &upload_id)],
+                &[],
+                &Self::empty_hash(),
+            );
+            match self.send(abort) {
+                Ok(response) if response.status().is_success() => {}
+                Ok(response) => log::warn!(
+                    "Failed to abort upload {} of {}: {}",
+                    upload_id,
+                    key,
+                    response.status()
+                ),
+                Err(e) => log::warn!("Failed to abort upload {} of {}: {:#}", upload_id, key, e),
+            }
+        }
+        result
+    }
+
+    fn upload_parts(
+        &self,
+        key: &str,
+        len: u64,
+        reader: &mut dyn Read,
+        upload_id: &str,
+        expected: &Expected,
+    ) -> Result<Option<Version>> {
+        let part_size = PART_SIZE.max(len.div_ceil(MAX_PARTS));
+        let mut etags = Vec::new();
+        loop {
+            let mut part = Vec::new();
+            reader
+                .take(part_size)
+                .read_to_end(&mut part)
+                .context("Failed to read the data to upload")?;
+            if part.is_empty() {
+                break;
+            }
+            let full = part.len() as u64 == part_size;
+            let number = (etags.len() + 1).to_string();
+            let hash = sigv4::sha256_hex(&part);
+            let query = [("partNumber", number.as_str()), ("uploadId", upload_id)];
+            let response = self
+                .send(
+                    self.request(Method::PUT, key, &query, &[], &hash)
+                        .body(part),
+                )
+                .context("Failed to send part")?;
+            if !response.status().is_success() {
+                return Err(s3_error("UploadPart", key, &response));
+            }
+            let etag = response
+                .headers()
+                .get("etag")
+                .and_then(|value| value.to_str().ok())
+                .context("Part uploaded without an ETag")?;
+            etags.push(etag.to_string());
+            if !full {
+                break;
+            }
+        }
+
+        let body = xml::complete_body(&etags);
+        let hash = sigv4::sha256_hex(body.as_bytes());
+        let request = self
+            .request(Method::POST, key, &[("uploadId", upload_id)], &[], &hash)
+            .body(body);
+        let response = self
+            .send(expected.condition(request))
+            .context("Failed to complete multipart upload")?;
+        if !response.status().is_success() {
+            return Err(s3_error("CompleteMultipartUpload", key, &response));
+        }
+        let body = response.text().context("Failed to read completion")?;
+        if let Some(error) = xml::error(&body) {
+            anyhow::bail!("Multipart upload of {} failed: {}", key, error);
+        }
+        Ok(xml::completed_etag(&body).map(Version::ETag))
+    }
+
+    pub fn write_file_if(
+        &self,
+        path: &str,
+        data: &[u8],
+        expected: &Expected,
+    ) -> Result<Option<Version>> {
+        let key = self.key(path);
+        let len = data.len() as u64;
+        if len > self.multipart_threshold {
+            return self.multipart(&key, len, &mut &data[..], expected);
+        }
+        log::debug!("PutObject {} ({} bytes)", key, len);
+        self.put(&key, data.to_vec(), expected)
+    }
+
+    pub fn write_file_streamed(
+        &self,
+        path: &str,
+        len: u64,
+        open: &dyn Fn() -> Box<dyn Read + Send>,
+    ) -> Result<()> {
+        let key = self.key(path);
+        if len > self.multipart_threshold {
+            return self
+                .multipart(&key, len, &mut open(), &Expected::Any)
+                .map(|_| ());
+        }
+        log::debug!("PutObject {} ({} bytes, streamed)", key, len);
+        let request = self
+            .request(Method::PUT, &key, &[], &[], UNSIGNED_PAYLOAD)
+            .body(Body::sized(open(), len));
+        let response = self.send(request).context("Failed to send write request")?;
+        if !response.status().is_success() {
+            return Err(s3_error("PutObject", &key, &response));
+        }
+        Ok(())
+    }
+
+    // The marker object of the directory
+    pub fn create_directory(&self, path: &str) -> Result<()> {
+        let exists = || {
+            anyhow::Error::new(FsError::AlreadyExists)
+                .context(format!("/{} already exists", path.trim_matches('/')))
+        };
+        // A file of the same name, or the marker of the directory itself
+        if self.head(&self.key(path))?.is_some() {
+            return Err(exists());
+        }
+        let key = self.dir_key(path);
+        log::debug!("PutObject {} (directory marker)", key);
+        match self.put(&key, Vec::new(), &Expected::Absent) {
+            Err(e) if FsError::from_backend(&e) == FsError::Stale => Err(exists()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    fn delete_key(&self, key: &str) -> Result<()> {
+        let response = self
+            .send(self.request(Method::DELETE, key, &[], &[], &Self::empty_hash()))
+            .context("Failed to send DELETE request")?;
+        if !response.status().is_success() {
+            return Err(s3_error("DeleteObject", key, &response));
+        }
+        Ok(())
+    }
+
+    // Directories are deleted with everything under them, like the REST
+    // server does; the filesystem checks they are empty first
+    pub fn delete(&self, path: &str) -> Result<()> {
+        let key = self.key(path);
+        if self.head(&key)?.is_some() {
+            log::debug!("DeleteObject {}", key);
+            return self.delete_key(&key);
+        }
+        let dir = self.dir_key(path);
+        let objects = self.list(&dir, false)?.objects;
+        if objects.is_empty() {
+            return Err(not_found(path.trim_matches('/')));
+        }
+        for object in objects {
+            log::debug!("DeleteObject {}", object.key);
+            self.delete_key(&object.key)?;
+        }
+        Ok(())
+    }
+
+    fn copy(&self, from: &str, to: &str) -> Result<()> {
+        log::debug!("CopyObject {} to {}", from, to);
+        let source = format!("/{}/{}", self.bucket, encode_path(from));
+        let headers = [("x-amz-copy-source", source.as_str())];
+        let response = self
+            .send(self.request(Method::PUT, to, &[], &headers, &Self::empty_hash()))
+            .context("Failed to send copy request")?;
+        if !response.status().is_success() {
+            return Err(s3_error("CopyObject", from, &response));
+        }
+        let body = response.text().context("Failed to read copy response")?;
+        if let Some(error) = xml::error(&body) {
+            anyhow::bail!("Copy of {} to {} failed: {}", from, to, error);
+        }
+        Ok(())
+    }
+
+    // S3 cannot rename: objects are copied, and deleted once all copies
+    // are done. Renaming a directory copies everything under it.
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let (from_key, to_key) = (self.key(from), self.key(to));
+        if self.head(&from_key)?.is_some() {
+            self.copy(&from_key, &to_key)?;
+            return self.delete_key(&from_key);
+        }
+
+        let (from_dir, to_dir) = (self.dir_key(from), self.dir_key(to));
+        let objects = self.list(&from_dir, false)?.objects;
+        if objects.is_empty() {
+            return Err(not_found(from.trim_matches('/')));
+        }
+        for object in &objects {
+            let target = format!("{}{}", to_dir, &object.key[from_dir.len()..]);
+            self.copy(&object.key, &target)?;
+        }
+        for object in &objects {
+            self.delete_key(&object.key)?;
+        }
+        Ok(())
+    }
+
+    // HeadBucket: the bucket exists and the credentials are good for it
+    pub fn check_reachable(&self, timeout: Duration) -> Result<()> {
+        let request = self
+            .request(Method::HEAD, "", &[], &[], &Self::empty_hash())
+            .timeout(timeout);
+        let response = self.send(request).context("Failed to reach the bucket")?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::NOT_FOUND => anyhow::bail!("Bucket {} does not exist", self.bucket),
+            _ => Err(s3_error("HeadBucket", &self.bucket, &response)),
+        }
+    }
+}
+
+impl RemoteBackend for S3Backend {
+    fn chunk_size(&self) -> u64 {
+        self.config.chunk_size
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.consecutive_failures.load(Ordering::Relaxed) == 0
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.config.base_urls[0]
+    }
+
+    fn check_reachable(&self, timeout: Duration) -> Result<()> {
+        S3Backend::check_reachable(self, timeout)
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Listing> {
+        S3Backend::list_directory(self, path)
+    }
+
+    fn revalidate_listing(&self, path: &str, version: &Version) -> Result<Conditional<Listing>> {
+        S3Backend::revalidate_listing(self, path, version)
+    }
+
+    fn revalidate_file(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Option<Version>>> {
+        S3Backend::revalidate_file(self, path, version)
+    }
+
+    fn file_version(&self, path: &str) -> Result<Option<Version>> {
+        S3Backend::file_version(self, path)
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        S3Backend::read_range(self, path, offset, len)
+    }
+
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<()> {
+        self.write_file_if(path, data, &Expected::Any).map(|_| ())
+    }
+
+    fn write_file_if(
+        &self,
+        path: &str,
+        data: &[u8],
+        expected: &Expected,
+    ) -> Result<Option<Version>> {
+        S3Backend::write_file_if(self, path, data, expected)
+    }
+
+    fn write_file_streamed(
+        &self,
+        path: &str,
+        len: u64,
+        open: &dyn Fn() -> Box<dyn Read + Send>,
+    ) -> Result<()> {
+        S3Backend::write_file_streamed(self, path, len, open)
+    }
+
+    // Objects are written whole
+    fn write_range(&self, _path: &str, _offset: u64, _data: &[u8]) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn create_directory(&self, path: &str) -> Result<()> {
+        S3Backend::create_directory(self, path)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        S3Backend::delete(self, path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        S3Backend::rename(self, from, to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_client::Secret;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    fn s3_config(endpoint: Option<String>) -> S3Config {
+        S3Config {
+            region: Some("eu-west-1".to_string()),
+            endpoint,
+            access_key_id: Some("AKIATEST".to_string()),
+            secret_access_key: Some(Secret::new("secret")),
+            ..S3Config::default()
+        }
+    }
+
+    fn backend(url: &str, endpoint: Option<String>) -> Result<S3Backend> {
+        S3Backend::with_config(ClientConfig::new(vec![url.to_string()]), s3_config(endpoint))
+    }
+
+    // Answers a request with each of `responses` in turn, a status, headers
+    // and a body, handing back the request lines and headers they got
+    fn serve(responses: Vec<(u16, &'static str, &'static str)>) -> (S3Backend, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let served = thread::spawn(move || {
+            let mut heads = String::new();
+            for (status, headers, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line.len() <= 2 {
+                        break;
+                    }
+                    heads.push_str(&line);
+                }
+                let mut content = vec![0; length];
+                std::io::Read::read_exact(&mut reader, &mut content).unwrap();
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} -\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    headers,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+            heads
+        });
+        let backend = backend("s3://bucket/root", Some(endpoint)).unwrap();
+        (backend, served)
+    }
+
+    #[test]
+    fn buckets_are_addressed_by_their_url() {
+        let aws = backend("s3://bucket/some/prefix/", None).unwrap();
+        assert_eq!(aws.origin, "https://bucket.s3.eu-west-1.amazonaws.com");
+        assert_eq!(aws.host, "bucket.s3.eu-west-1.amazonaws.com");
+        assert_eq!(aws.bucket_path, "");
+        assert_eq!(aws.prefix, "some/prefix/");
+        assert_eq!(aws.key("/a/b.txt"), "some/prefix/a/b.txt");
+        assert_eq!(aws.dir_key("/a"), "some/prefix/a/");
+        assert_eq!(aws.dir_key("/"), "some/prefix/");
+
+        let minio = backend("s3://bucket", Some("http://minio:80/".to_string())).unwrap();
+        assert_eq!(minio.origin, "http://minio:80");
+        assert_eq!(minio.host, "minio");
+        assert_eq!(minio.bucket_path, "/bucket");
+        assert_eq!(minio.prefix, "");
+        assert_eq!(minio.dir_key("/"), "");
+        assert_eq!(minio.key("/a"), "a");
+
+        assert!(backend("http://bucket/prefix", None).is_err());
+        assert!(backend("s3:///prefix", None).is_err());
+        assert!(backend("s3://bucket", Some("ftp://minio".to_string())).is_err());
+    }
+
+    #[test]
+    fn listings_fold_pages_and_prefixes_into_entries() {
+        let first = "<ListBucketResult><IsTruncated>true</IsTruncated>\
+            <NextContinuationToken>t/1</NextContinuationToken>\
+            <Contents><Key>root/docs/</Key><Size>0</Size></Contents>\
+            <Contents><Key>root/docs/a.txt</Key><Size>5</Size>\
+            <LastModified>2024-01-02T03:04:05.000Z</LastModified><ETag>\"e\"</ETag></Contents>\
+            <CommonPrefixes><Prefix>root/docs/sub/</Prefix></CommonPrefixes>\
+            </ListBucketResult>";
+        let second = "<ListBucketResult><IsTruncated>false</IsTruncated>\
+            <Contents><Key>root/docs/sub</Key><Size>1</Size></Contents>\
+            </ListBucketResult>";
+        let (backend, served) = serve(vec![(200, "", first), (200, "", second)]);
+        let listing = backend.list_directory("/docs").unwrap();
+
+        let names: Vec<_> = listing.entries.iter().map(|e| (e.name.as_str(), e.is_dir)).collect();
+        assert_eq!(names, [("sub", true), ("a.txt", false)]);
+        assert_eq!(listing.entries[1].size, 5);
+        assert_eq!(listing.entries[1].mtime, Timestamp::from_secs_f64(1_704_164_645.0));
+        // Prefixes have no time of their own
+        assert!(!listing.entries[0].mtime.is_known());
+        assert!(matches!(listing.version, Some(Version::ETag(_))));
+
+        let heads = served.join().unwrap();
+        assert!(heads.contains(
+            "GET /bucket/?delimiter=%2F&list-type=2&prefix=root%2Fdocs%2F HTTP/1.1"
+        ));
+        assert!(heads.contains("continuation-token=t%2F1&delimiter=%2F&list-type=2"));
+        assert!(heads.contains("AWS4-HMAC-SHA256 Credential=AKIATEST/"));
+    }
+
+    #[test]
+    fn missing_directories_are_not_found() {
+        let empty = "<ListBucketResult><IsTruncated>false</IsTruncated></ListBucketResult>";
+        let (backend, served) = serve(vec![(200, "", empty), (200, "", empty)]);
+        let error = backend.list_directory("/gone").unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::NotFound);
+        // The root of the mount is there even with nothing in it
+        assert!(backend.list_directory("/").unwrap().entries.is_empty());
+        served.join().unwrap();
+    }
+
+    #[test]
+    fn reads_ask_for_a_range() {
+        let (backend, served) = serve(vec![
+            (206, "ETag: \"v1\"\r\n", "llo"),
+            (200, "", "whole body"),
+            (416, "", ""),
+        ]);
+        let read = backend.read_range("/a.txt", 2, 3).unwrap();
+        assert_eq!(read.data, b"llo");
+        assert_eq!(read.version, Some(Version::ETag("\"v1\"".to_string())));
+        // Servers ignoring the range send everything
+        assert_eq!(backend.read_range("/a.txt", 6, 10).unwrap().data, b"body");
+        assert!(backend.read_range("/a.txt", 100, 10).unwrap().data.is_empty());
+        assert!(backend.read_range("/a.txt", 0, 0).unwrap().data.is_empty());
+
+        let heads = served.join().unwrap().to_lowercase();
+        assert!(heads.contains("get /bucket/root/a.txt http/1.1"));
+        assert!(heads.contains("range: bytes=2-4\r\n"));
+    }
+
+    #[test]
+    fn files_are_renamed_by_copy_then_delete() {
+        let (backend, served) = serve(vec![
+            (200, "", ""),
+            (200, "", "<CopyObjectResult><ETag>\"e\"</ETag></CopyObjectResult>"),
+            (204, "", ""),
+        ]);
+        backend.rename("/a b.txt", "/c.txt").unwrap();
+        let heads = served.join().unwrap();
+        let lines: Vec<_> = heads.lines().filter(|line| line.contains("HTTP/1.1")).collect();
+        assert_eq!(
+            lines,
+            [
+                "HEAD /bucket/root/a%20b.txt HTTP/1.1",
+                "PUT /bucket/root/c.txt HTTP/1.1",
+                "DELETE /bucket/root/a%20b.txt HTTP/1.1",
+            ]
+        );
+        assert!(heads.contains("x-amz-copy-source: /bucket/root/a%20b.txt\r\n"));
+    }
+
+    #[test]
+    fn failed_copies_keep_the_source() {
+        let error = "<Error><Code>InternalError</Code><Message>Oops</Message></Error>";
+        let (backend, served) = serve(vec![(200, "", ""), (200, "", error)]);
+        let message = format!("{:#}", backend.rename("/a", "/b").unwrap_err());
+        assert!(message.contains("InternalError: Oops"), "{}", message);
+        assert!(!served.join().unwrap().contains("DELETE"));
+    }
+
+    #[test]
+    fn existing_directories_are_not_created_again() {
+        let (backend, served) = serve(vec![(404, "", ""), (412, "", "")]);
+        let error = backend.create_directory("/new").unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::AlreadyExists);
+        let heads = served.join().unwrap().to_lowercase();
+        assert!(heads.contains("put /bucket/root/new/ http/1.1"));
+        assert!(heads.contains("if-none-match: *\r\n"));
+
+        // Nor over a file of the same name
+        let (backend, served) = serve(vec![(200, "", "")]);
+        let error = backend.create_directory("/new").unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::AlreadyExists);
+        assert!(!served.join().unwrap().contains("PUT"));
+    }
+}
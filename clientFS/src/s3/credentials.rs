@@ -0,0 +1,211 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use super::S3Config;
+use crate::api_client::Secret;
+
+const DEFAULT_REGION: &str = "us-east-1";
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: Secret,
+    pub session_token: Option<Secret>,
+}
+
+fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+fn aws_file(variable: &str, default: &str) -> Option<PathBuf> {
+    var(variable)
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(default)))
+}
+
+// The keys of `[section]` of an INI file like ~/.aws/credentials
+fn ini_section(text: &str, section: &str) -> Option<BTreeMap<String, String>> {
+    let mut found = None;
+    let mut current = false;
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current = name.trim() == section;
+            if current {
+                found.get_or_insert_with(BTreeMap::new);
+            }
+            continue;
+        }
+        if let (true, Some((key, value))) = (current, line.split_once('=')) {
+            if let Some(keys) = found.as_mut() {
+                keys.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+    }
+    found
+}
+
+impl S3Config {
+    fn profile_name(&self) -> String {
+        self.profile
+            .clone()
+            .or_else(|| var("AWS_PROFILE"))
+            .unwrap_or_else(|| "default".to_string())
+    }
+
+    // ~/.aws/config names profiles `[profile work]`, except the default one
+    fn profile_setting(&self, key: &str) -> Option<String> {
+        let path = aws_file("AWS_CONFIG_FILE", ".aws/config")?;
+        let text = fs::read_to_string(path).ok()?;
+        let profile = self.profile_name();
+        let section = match profile.as_str() {
+            "default" => profile,
+            name => format!("profile {}", name),
+        };
+        ini_section(&text, &section)?.remove(key)
+    }
+
+    pub fn resolved_region(&self) -> String {
+        self.region
+            .clone()
+            .or_else(|| var("AWS_REGION"))
+            .or_else(|| var("AWS_DEFAULT_REGION"))
+            .or_else(|| self.profile_setting("region"))
+            .unwrap_or_else(|| DEFAULT_REGION.to_string())
+    }
+
+    // Explicit keys, then the environment, then the shared credentials file
+    pub fn credentials(&self) -> Result<Credentials> {
+        if let (Some(id), Some(secret)) = (&self.access_key_id, &self.secret_access_key) {
+            return Ok(Credentials {
+                access_key_id: id.clone(),
+                secret_access_key: secret.clone(),
+                session_token: self.session_token.clone(),
+            });
+        }
+        if self.profile.is_none() {
+            if let (Some(id), Some(secret)) =
+                (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY"))
+            {
+                return Ok(Credentials {
+                    access_key_id: id,
+                    secret_access_key: Secret::new(secret),
+                    session_token: var("AWS_SESSION_TOKEN").map(Secret::new),
+                });
+            }
+        }
+
+        let profile = self.profile_name();
+        let path = aws_file("AWS_SHARED_CREDENTIALS_FILE", ".aws/credentials")
+            .context("No S3 credentials given and HOME is not set")?;
+        let text = fs::read_to_string(&path).with_context(|| {
+            format!(
+                "No S3 credentials given or in the environment, and {} is unreadable",
+                path.display()
+            )
+        })?;
+        let mut keys = ini_section(&text, &profile)
+            .with_context(|| format!("No profile {} in {}", profile, path.display()))?;
+        let mut key = |name: &str| {
+            keys.remove(name)
+                .with_context(|| format!("Profile {} has no {}", profile, name))
+        };
+        Ok(Credentials {
+            access_key_id: key("aws_access_key_id")?,
+            secret_access_key: Secret::new(key("aws_secret_access_key")?),
+            session_token: key("aws_session_token").ok().map(Secret::new),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ini_sections_are_read_alone() {
+        let text = "\
+# comment
+[default]
+aws_access_key_id = AKIADEFAULT
+
+[profile work]
+; another comment
+region=eu-west-1
+aws_access_key_id = AKIAWORK
+[other]
+region = us-west-2
+";
+        let work = ini_section(text, "profile work").unwrap();
+        assert_eq!(work.len(), 2);
+        assert_eq!(work["region"], "eu-west-1");
+        assert_eq!(work["aws_access_key_id"], "AKIAWORK");
+        assert_eq!(ini_section(text, "default").unwrap().len(), 1);
+        assert!(ini_section(text, "missing").is_none());
+        assert!(ini_section("[empty]\n", "empty").unwrap().is_empty());
+    }
+
+    #[test]
+    fn explicit_settings_come_first() {
+        let config = S3Config {
+            region: Some("eu-central-1".to_string()),
+            access_key_id: Some("AKIAGIVEN".to_string()),
+            secret_access_key: Some(Secret::new("secret")),
+            session_token: Some(Secret::new("token")),
+            ..S3Config::default()
+        };
+        assert_eq!(config.resolved_region(), "eu-central-1");
+        let credentials = config.credentials().unwrap();
+        assert_eq!(credentials.access_key_id, "AKIAGIVEN");
+        assert_eq!(credentials.secret_access_key.expose(), "secret");
+        assert_eq!(credentials.session_token.unwrap().expose(), "token");
+    }
+
+    // The only test that sets the AWS variables
+    #[test]
+    fn profiles_come_from_the_shared_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let credentials = dir.path().join("credentials");
+        let config = dir.path().join("config");
+        fs::write(
+            &credentials,
+            "[work]\naws_access_key_id = AKIAWORK\naws_secret_access_key = s3cret\n\
+             [partial]\naws_access_key_id = AKIAPART\n",
+        )
+        .unwrap();
+        fs::write(&config, "[default]\nregion = us-west-2\n[profile work]\nregion = eu-west-3\n")
+            .unwrap();
+        env::set_var("AWS_SHARED_CREDENTIALS_FILE", &credentials);
+        env::set_var("AWS_CONFIG_FILE", &config);
+
+        let work = S3Config {
+            profile: Some("work".to_string()),
+            ..S3Config::default()
+        };
+        assert_eq!(work.profile_setting("region").as_deref(), Some("eu-west-3"));
+        let keys = work.credentials().unwrap();
+        assert_eq!(keys.access_key_id, "AKIAWORK");
+        assert_eq!(keys.secret_access_key.expose(), "s3cret");
+        assert!(keys.session_token.is_none());
+
+        let default = S3Config {
+            profile: Some("default".to_string()),
+            ..S3Config::default()
+        };
+        assert_eq!(default.profile_setting("region").as_deref(), Some("us-west-2"));
+        let error = default.credentials().err().unwrap().to_string();
+        assert!(error.starts_with("No profile default in"), "{}", error);
+
+        let partial = S3Config {
+            profile: Some("partial".to_string()),
+            ..S3Config::default()
+        };
+        let error = partial.credentials().err().unwrap().to_string();
+        assert_eq!(error, "Profile partial has no aws_secret_access_key");
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::{Context, Result};
+use roxmltree::{Document, Node};
+
+// An object of a ListObjectsV2 page
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: Option<String>,
+    pub etag: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ListPage {
+    pub objects: Vec<Object>,
+    // Keys sharing a prefix up to the delimiter, the subdirectories
+    pub prefixes: Vec<String>,
+    // Set when the listing goes on in another page
+    pub next_token: Option<String>,
+}
+
+fn child<'a, 'input>(node: &Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn text(node: &Node, name: &str) -> Option<String> {
+    child(node, name)
+        .and_then(|child| child.text())
+        .map(str::to_string)
+}
+
+fn elements<'a, 'input: 'a>(
+    node: &Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+// S3 reports some failures with a 200 and an <Error> body: a copy or a
+// multipart completion that went wrong after the response started
+pub fn error(body: &str) -> Option<String> {
+    let document = Document::parse(body).ok()?;
+    let root = document.root_element();
+    if root.tag_name().name() != "Error" {
+        return None;
+    }
+    let code = text(&root, "Code").unwrap_or_default();
+    let message = text(&root, "Message").unwrap_or_default();
+    Some(format!("{}: {}", code, message))
+}
+
+pub fn list_page(body: &str) -> Result<ListPage> {
+    let document = Document::parse(body).context("Invalid ListObjectsV2 XML")?;
+    let root = document.root_element();
+    anyhow::ensure!(
+        root.tag_name().name() == "ListBucketResult",
+        "Expected a ListBucketResult, got <{}>",
+        root.tag_name().name()
+    );
+
+    let mut page = ListPage::default();
+    for contents in elements(&root, "Contents") {
+        let Some(key) = text(&contents, "Key") else {
+            continue;
+        };
+        page.objects.push(Object {
+            key,
+            size: text(&contents, "Size")
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(0),
+            last_modified: text(&contents, "LastModified"),
+            etag: text(&contents, "ETag"),
+        });
+    }
+    for common in elements(&root, "CommonPrefixes") {
+        page.prefixes.extend(text(&common, "Prefix"));
+    }
+    if text(&root, "IsTruncated").as_deref() == Some("true") {
+        page.next_token = text(&root, "NextContinuationToken");
+        anyhow::ensure!(
+            page.next_token.is_some(),
+            "Truncated listing without a continuation token"
+        );
+    }
+    Ok(page)
+}
+
+pub fn upload_id(body: &str) -> Result<String> {
+    let document = Document::parse(body).context("Invalid CreateMultipartUpload XML")?;
+    text(&document.root_element(), "UploadId").context("No UploadId in the response")
+}
+
+// The ETag of the object a multipart upload made
+pub fn completed_etag(body: &str) -> Option<String> {
+    let document = Document::parse(body).ok()?;
+    text(&document.root_element(), "ETag")
+}
+
+// The body of CompleteMultipartUpload, from the ETags of the parts in order
+pub fn complete_body(etags: &[String]) -> String {
+    let mut body = String::from("<CompleteMultipartUpload>");
+    for (n, etag) in etags.iter().enumerate() {
+        let etag = etag.replace('&', "&amp;").replace('<', "&lt;");
+        body.push_str(&format!(
+            "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+            n + 1,
+            etag
+        ));
+    }
+    body.push_str("</CompleteMultipartUpload>");
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_have_objects_and_prefixes() {
+        let body = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Name>bucket</Name>
+  <Prefix>docs/</Prefix>
+  <IsTruncated>true</IsTruncated>
+  <NextContinuationToken>next/token</NextContinuationToken>
+  <Contents>
+    <Key>docs/a.txt</Key>
+    <LastModified>2024-01-02T03:04:05.000Z</LastModified>
+    <ETag>"abc"</ETag>
+    <Size>12</Size>
+  </Contents>
+  <Contents><Size>3</Size></Contents>
+  <Contents><Key>docs/b &amp; c</Key></Contents>
+  <CommonPrefixes><Prefix>docs/sub/</Prefix></CommonPrefixes>
+</ListBucketResult>"#;
+        let page = list_page(body).unwrap();
+        assert_eq!(page.objects.len(), 2);
+        assert_eq!(page.objects[0].key, "docs/a.txt");
+        assert_eq!(page.objects[0].size, 12);
+        assert_eq!(page.objects[0].etag.as_deref(), Some("\"abc\""));
+        assert_eq!(
+            page.objects[0].last_modified.as_deref(),
+            Some("2024-01-02T03:04:05.000Z")
+        );
+        assert_eq!(page.objects[1].key, "docs/b & c");
+        assert_eq!(page.objects[1].size, 0);
+        assert_eq!(page.prefixes, ["docs/sub/"]);
+        assert_eq!(page.next_token.as_deref(), Some("next/token"));
+    }
+
+    #[test]
+    fn bad_pages_are_refused() {
+        assert!(list_page("not xml").is_err());
+        assert!(list_page("<Error><Code>NoSuchBucket</Code></Error>").is_err());
+        let truncated = "<ListBucketResult><IsTruncated>true</IsTruncated></ListBucketResult>";
+        assert!(list_page(truncated).is_err());
+        let last = "<ListBucketResult><IsTruncated>false</IsTruncated></ListBucketResult>";
+        assert!(list_page(last).unwrap().next_token.is_none());
+    }
+
+    #[test]
+    fn errors_in_successful_responses_are_found() {
+        let body = "<Error><Code>InternalError</Code><Message>Try again</Message></Error>";
+        assert_eq!(error(body).as_deref(), Some("InternalError: Try again"));
+        assert_eq!(error("<CopyObjectResult><ETag>x</ETag></CopyObjectResult>"), None);
+        assert_eq!(error(""), None);
+    }
+
+    #[test]
+    fn multipart_uploads_round_trip() {
+        let body = "<InitiateMultipartUploadResult><Bucket>b</Bucket><Key>k</Key>\
+                    <UploadId>up-1</UploadId></InitiateMultipartUploadResult>";
+        assert_eq!(upload_id(body).unwrap(), "up-1");
+        assert!(upload_id("<InitiateMultipartUploadResult/>").is_err());
+
+        let body = "<CompleteMultipartUploadResult><ETag>\"x-2\"</ETag>\
+                    </CompleteMultipartUploadResult>";
+        assert_eq!(completed_etag(body).as_deref(), Some("\"x-2\""));
+
+        let body = complete_body(&["\"a\"".to_string(), "b&<".to_string()]);
+        assert_eq!(
+            body,
+            "<CompleteMultipartUpload>\
+             <Part><PartNumber>1</PartNumber><ETag>\"a\"</ETag></Part>\
+             <Part><PartNumber>2</PartNumber><ETag>b&amp;&lt;</ETag></Part>\
+             </CompleteMultipartUpload>"
+        );
+        assert!(Document::parse(&body).is_ok());
+    }
+}
@@ -0,0 +1,43 @@
+use std::path::PathBuf;
+
+use crate::api_client::Secret;
+
+#[cfg(feature = "sftp")]
+mod backend;
+
+#[cfg(feature = "sftp")]
+pub use backend::SftpBackend;
+
+pub const DEFAULT_SFTP_SESSIONS: usize = 4;
+
+// How an SFTP server is logged into. The host, port, user and root
+// directory come from the server URL, `sftp://user@host:22/srv/data`.
+// Authentication tries the SSH agent, then the identity file, then the
+// password.
+#[derive(Debug, Clone)]
+pub struct SftpConfig {
+    // Private key, instead of ~/.ssh/id_ed25519, id_ecdsa and id_rsa
+    pub identity_file: Option<PathBuf>,
+    // Of an encrypted identity file, asked for by the binary when needed
+    pub passphrase: Option<Secret>,
+    pub password: Option<Secret>,
+    // Defaults to ~/.ssh/known_hosts
+    pub known_hosts: Option<PathBuf>,
+    // Accept any host key, for `--ssh-insecure`
+    pub insecure: bool,
+    // SFTP sessions open at most, each over its own SSH connection
+    pub sessions: usize,
+}
+
+impl Default for SftpConfig {
+    fn default() -> Self {
+        Self {
+            identity_file: None,
+            passphrase: None,
+            password: None,
+            known_hosts: None,
+            insecure: false,
+            sessions: DEFAULT_SFTP_SESSIONS,
+        }
+    }
+}
@@ -0,0 +1,825 @@
+use anyhow::{Context, Result};
+use ssh2::{
+    CheckResult, ErrorCode, FileStat, KnownHostFileKind, OpenFlags, OpenType, RenameFlags, Session,
+    Sftp,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use super::SftpConfig;
+use crate::api_client::{
+    ClientConfig, Conditional, FileData, FileEntry, Listing, Timestamp, Version,
+};
+use crate::filesystem::{FsError, RemoteBackend};
+
+const DEFAULT_PORT: u16 = 22;
+const DEFAULT_IDENTITIES: [&str; 3] = [".ssh/id_ed25519", ".ssh/id_ecdsa", ".ssh/id_rsa"];
+
+// SFTP status codes, from the version 3 draft libssh2 speaks
+const FX_NO_SUCH_FILE: i32 = 2;
+const FX_PERMISSION_DENIED: i32 = 3;
+const FX_FAILURE: i32 = 4;
+const FX_NO_CONNECTION: i32 = 6;
+const FX_CONNECTION_LOST: i32 = 7;
+const FX_OP_UNSUPPORTED: i32 = 8;
+const FX_NO_SUCH_PATH: i32 = 10;
+const FX_FILE_ALREADY_EXISTS: i32 = 11;
+const FX_WRITE_PROTECT: i32 = 12;
+const FX_NO_SPACE_ON_FILESYSTEM: i32 = 14;
+const FX_QUOTA_EXCEEDED: i32 = 15;
+const FX_DIR_NOT_EMPTY: i32 = 18;
+const FX_NOT_A_DIRECTORY: i32 = 19;
+// libssh2's own timeout, as opposed to one of the server
+const SESSION_TIMEOUT: i32 = -9;
+
+// Whether `error` means the SSH connection is gone and has to be opened
+// again. libssh2 reports SFTP statuses as such; anything of the session
+// itself, and I/O errors on file handles which lose their code, count as
+// the connection failing.
+fn connection_lost(error: &anyhow::Error) -> bool {
+    if let Some(error) = error.downcast_ref::<ssh2::Error>() {
+        return match error.code() {
+            ErrorCode::Session(_) => true,
+            ErrorCode::SFTP(code) => matches!(code, FX_NO_CONNECTION | FX_CONNECTION_LOST),
+        };
+    }
+    error
+        .downcast_ref::<io::Error>()
+        .is_some_and(|error| error.kind() != io::ErrorKind::NotFound)
+}
+
+fn classify(error: &anyhow::Error) -> FsError {
+    if let Some(kind) = error.downcast_ref::<FsError>() {
+        return *kind;
+    }
+    let Some(error) = error.downcast_ref::<ssh2::Error>() else {
+        return match error.downcast_ref::<io::Error>().map(io::Error::kind) {
+            Some(io::ErrorKind::NotFound) => FsError::NotFound,
+            Some(io::ErrorKind::TimedOut) => FsError::TimedOut,
+            _ => FsError::Io,
+        };
+    };
+    match error.code() {
+        ErrorCode::SFTP(FX_NO_SUCH_FILE | FX_NO_SUCH_PATH) => FsError::NotFound,
+        ErrorCode::SFTP(FX_PERMISSION_DENIED) => FsError::PermissionDenied,
+        ErrorCode::SFTP(FX_FILE_ALREADY_EXISTS) => FsError::AlreadyExists,
+        ErrorCode::SFTP(FX_WRITE_PROTECT) => FsError::ReadOnly,
+        ErrorCode::SFTP(FX_NO_SPACE_ON_FILESYSTEM | FX_QUOTA_EXCEEDED) => FsError::NoSpace,
+        ErrorCode::SFTP(FX_DIR_NOT_EMPTY) => FsError::NotEmpty,
+        ErrorCode::SFTP(FX_NOT_A_DIRECTORY) => FsError::NotADirectory,
+        ErrorCode::SFTP(FX_OP_UNSUPPORTED) => FsError::Unsupported,
+        ErrorCode::SFTP(FX_NO_CONNECTION | FX_CONNECTION_LOST) => FsError::Unreachable,
+        ErrorCode::SFTP(_) => FsError::Io,
+        ErrorCode::Session(SESSION_TIMEOUT) => FsError::TimedOut,
+        ErrorCode::Session(_) => FsError::Unreachable,
+    }
+}
+
+struct Connection {
+    // Kept for the SFTP channel, which does not outlive it
+    _session: Session,
+    sftp: Sftp,
+}
+
+#[derive(Default)]
+struct Pool {
+    idle: Vec<Connection>,
+    // Connections in use, idle, or being opened
+    open: usize,
+}
+
+// A directory tree on an SFTP server. Each operation borrows one of up to
+// `sessions` connections; one found broken is dropped, and idempotent
+// operations are tried again once over a new one.
+pub struct SftpBackend {
+    config: ClientConfig,
+    sftp: SftpConfig,
+    host: String,
+    port: u16,
+    user: String,
+    // Root of the mount on the server, the login directory when the URL
+    // has no path. Made absolute by the first connection.
+    root: Mutex<PathBuf>,
+    pool: Mutex<Pool>,
+    returned: Condvar,
+    consecutive_failures: AtomicU32,
+}
+
+impl SftpBackend {
+    // The server is named by the first server URL,
+    // `sftp://user@host:port/path`. Nothing is connected before the first
+    // operation.
+    pub fn with_config(config: ClientConfig, sftp: SftpConfig) -> Result<Self> {
+        let url = config
+            .base_urls
+            .first()
+            .context("At least one server URL is required")?;
+        let rest = url
+            .strip_prefix("sftp://")
+            .with_context(|| format!("Expected sftp://user@host/path, got {}", url))?;
+        let (authority, root) = match rest.find('/') {
+            Some(at) => (&rest[..at], &rest[at..]),
+            None => (rest, ""),
+        };
+        let (user, address) = match authority.rsplit_once('@') {
+            Some((user, address)) => (Some(user.to_string()), address),
+            None => (None, authority),
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .with_context(|| format!("Invalid port in {}", url))?,
+            ),
+            _ => (address, DEFAULT_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        anyhow::ensure!(!host.is_empty(), "No host in {}", url);
+        let user = user
+            .or_else(|| env::var("USER").ok())
+            .context("No user in the URL and USER is not set")?;
+        anyhow::ensure!(sftp.sessions > 0, "At least one SFTP session is required");
+        ClientConfig::validate_chunk_size(config.chunk_size)?;
+
+        let root = match root.trim_end_matches('/') {
+            "" => PathBuf::from("."),
+            root => PathBuf::from(root),
+        };
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            user,
+            root: Mutex::new(root),
+            pool: Mutex::new(Pool::default()),
+            returned: Condvar::new(),
+            consecutive_failures: AtomicU32::new(0),
+            config,
+            sftp,
+        })
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        let root = self.root.lock().unwrap();
+        match path.trim_matches('/') {
+            "" => root.clone(),
+            path => root.join(path),
+        }
+    }
+
+    fn connect(&self) -> Result<Connection> {
+        let timeout = self.config.timeout;
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .with_context(|| format!("Cannot resolve {}", self.host))?
+            .next()
+            .with_context(|| format!("No address for {}", self.host))?;
+        let tcp = TcpStream::connect_timeout(&address, timeout)
+            .with_context(|| format!("Failed to connect to {}:{}", self.host, self.port))?;
+
+        let mut session = Session::new().context("Failed to create SSH session")?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(timeout.as_millis().min(u32::MAX as u128) as u32);
+        session.handshake().context("SSH handshake failed")?;
+        self.check_host_key(&session)?;
+        self.authenticate(&session)?;
+        let sftp = session.sftp().context("Failed to start SFTP")?;
+
+        let mut root = self.root.lock().unwrap();
+        if root.is_relative() {
+            *root = sftp
+                .realpath(&root)
+                .with_context(|| format!("Cannot resolve {}", root.display()))?;
+            log::debug!("SFTP root is {}", root.display());
+        }
+        Ok(Connection {
+            _session: session,
+            sftp,
+        })
+    }
+
+    fn check_host_key(&self, session: &Session) -> Result<()> {
+        if self.sftp.insecure {
+            log::debug!("Not checking the host key of {}", self.host);
+            return Ok(());
+        }
+        let path = match &self.sftp.known_hosts {
+            Some(path) => path.clone(),
+            None => env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(".ssh/known_hosts"))
+                .context("HOME is not set, cannot find known_hosts")?,
+        };
+        let mut known = session.known_hosts()?;
+        known
+            .read_file(&path, KnownHostFileKind::OpenSSH)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let (key, _) = session.host_key().context("The server sent no host key")?;
+        match known.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound => anyhow::bail!(
+                "Host key of {} is not in {}, connect once with ssh or use --ssh-insecure",
+                self.host,
+                path.display()
+            ),
+            CheckResult::Mismatch => anyhow::bail!(
+                "Host key of {} does not match the one in {}, refusing to connect",
+                self.host,
+                path.display()
+            ),
+            CheckResult::Failure => anyhow::bail!("Failed to check the host key of {}", self.host),
+        }
+    }
+
+    // The agent, the identity files, then the password, until one is taken
+    fn authenticate(&self, session: &Session) -> Result<()> {
+        let user = self.user.as_str();
+        let mut tried = Vec::new();
+        if env::var_os("SSH_AUTH_SOCK").is_some() {
+            match session.userauth_agent(user) {
+                Ok(()) => return Ok(()),
+                Err(e) => log::debug!("SSH agent authentication failed: {}", e),
+            }
+            tried.push("agent");
+        }
+
+        let identities: Vec<PathBuf> = match &self.sftp.identity_file {
+            Some(path) => vec![path.clone()],
+            None => env::var_os("HOME")
+                .map(|home| {
+                    DEFAULT_IDENTITIES
+                        .iter()
+                        .map(|name| PathBuf::from(&home).join(name))
+                        .filter(|path| path.exists())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+        let passphrase = self.sftp.passphrase.as_ref().map(|secret| secret.expose());
+        for identity in &identities {
+            match session.userauth_pubkey_file(user, None, identity, passphrase) {
+                Ok(()) => return Ok(()),
+                Err(e) => log::debug!("Key {} refused: {}", identity.display(), e),
+            }
+        }
+        if !identities.is_empty() {
+            tried.push("key");
+        }
+
+        if let Some(password) = &self.sftp.password {
+            match session.userauth_password(user, password.expose()) {
+                Ok(()) => return Ok(()),
+                Err(e) => log::debug!("Password authentication failed: {}", e),
+            }
+            tried.push("password");
+        }
+        let tried = match tried.is_empty() {
+            true => "nothing".to_string(),
+            false => tried.join(", "),
+        };
+        Err(
+            anyhow::Error::new(FsError::PermissionDenied).context(format!(
+                "SSH authentication as {}@{} failed, tried {}",
+                user, self.host, tried
+            )),
+        )
+    }
+
+    // An idle connection, a new one while fewer than `sessions` are open,
+    // or the next one returned
+    fn checkout(&self) -> Result<Connection> {
+        let mut pool = self.pool.lock().unwrap();
+        loop {
+            if let Some(connection) = pool.idle.pop() {
+                return Ok(connection);
+            }
+            if pool.open < self.sftp.sessions {
+                pool.open += 1;
+                drop(pool);
+                return self.connect().inspect_err(|_| self.discard());
+            }
+            pool = self.returned.wait(pool).unwrap();
+        }
+    }
+
+    fn checkin(&self, connection: Connection) {
+        self.pool.lock().unwrap().idle.push(connection);
+        self.returned.notify_one();
+    }
+
+    fn discard(&self) {
+        self.pool.lock().unwrap().open -= 1;
+        self.returned.notify_one();
+    }
+
+    // Runs `op` over a pooled connection. The errors are classified as
+    // FsError, with `what` as context.
+    fn run<T>(&self, what: &str, idempotent: bool, op: impl Fn(&Sftp) -> Result<T>) -> Result<T> {
+        let mut retried = false;
+        loop {
+            let connection = match self.checkout() {
+                Ok(connection) => connection,
+                Err(e) => {
+                    self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+                    let kind = e
+                        .downcast_ref::<FsError>()
+                        .copied()
+                        .unwrap_or(FsError::Unreachable);
+                    return Err(anyhow::Error::new(kind).context(format!("{:#}", e)));
+                }
+            };
+            let error = match op(&connection.sftp) {
+                Ok(value) => {
+                    self.consecutive_failures.store(0, Ordering::Relaxed);
+                    self.checkin(connection);
+                    return Ok(value);
+                }
+                Err(e) => e,
+            };
+
+            if !connection_lost(&error) {
+                self.checkin(connection);
+                let kind = classify(&error);
+                return Err(anyhow::Error::new(kind).context(format!("{}: {:#}", what, error)));
+            }
+            drop(connection);
+            self.discard();
+            self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+            if !idempotent || retried {
+                let kind = classify(&error);
+                return Err(anyhow::Error::new(kind).context(format!("{}: {:#}", what, error)));
+            }
+            log::warn!(
+                "SFTP connection lost during {}, reconnecting: {:#}",
+                what,
+                error
+            );
+            retried = true;
+        }
+    }
+
+    pub fn list_directory(&self, path: &str) -> Result<Listing> {
+        let dir = self.path(path);
+        log::debug!("readdir {}", dir.display());
+        let what = format!("readdir {}", dir.display());
+        let children = self.run(&what, true, |sftp| {
+            let mut children = Vec::new();
+            for (child, stat) in sftp.readdir(&dir)? {
+                // Links are followed, dangling ones left out
+                let stat = if stat.file_type().is_symlink() {
+                    match sftp.stat(&child) {
+                        Ok(stat) => stat,
+                        Err(_) => continue,
+                    }
+                } else {
+                    stat
+                };
+                children.push((child, stat));
+            }
+            Ok(children)
+        })?;
+
+        // SFTP has no listing versions, one is made up of what came back
+        let mut fingerprint = DefaultHasher::new();
+        let mut entries = Vec::new();
+        for (child, stat) in children {
+            let Some(name) = child.file_name().and_then(|name| name.to_str()) else {
+                log::debug!("Leaving out {}, its name is not UTF-8", child.display());
+                continue;
+            };
+            let is_dir = stat.is_dir();
+            let mtime = stat.mtime.unwrap_or(0);
+            (name, is_dir, stat.size, mtime).hash(&mut fingerprint);
+            entries.push(FileEntry {
+                name: name.to_string(),
+                is_dir,
+                size: if is_dir { 0 } else { stat.size.unwrap_or(0) },
+                mtime: Timestamp::new(mtime as i64, 0),
+                ctime: Timestamp::new(mtime as i64, 0),
+                mode: stat.perm.map(|perm| perm & 0o7777).unwrap_or(if is_dir {
+                    0o755
+                } else {
+                    0o644
+                }),
+                id: None,
+                uid: stat.uid,
+                gid: stat.gid,
+                major: None,
+                minor: None,
+                nlink: None,
+            });
+        }
+        Ok(Listing {
+            entries,
+            version: Some(Version::ETag(format!(
+                "W/\"{:016x}\"",
+                fingerprint.finish()
+            ))),
+        })
+    }
+
+    pub fn revalidate_listing(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Listing>> {
+        let listing = self.list_directory(path)?;
+        if listing.version.as_ref() == Some(version) {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified(listing))
+    }
+
+    fn stat(&self, path: &str) -> Result<Option<FileStat>> {
+        let path = self.path(path);
+        let what = format!("stat {}", path.display());
+        match self.run(&what, true, |sftp| Ok(sftp.stat(&path)?)) {
+            Ok(stat) => Ok(Some(stat)),
+            Err(e) if e.downcast_ref::<FsError>() == Some(&FsError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Modification time and size, like the ETags of most web servers
+    fn version(stat: &FileStat) -> Version {
+        Version::ETag(format!(
+            "\"{:x}-{:x}\"",
+            stat.mtime.unwrap_or(0),
+            stat.size.unwrap_or(0)
+        ))
+    }
+
+    pub fn revalidate_file(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Option<Version>>> {
+        let current = match self.stat(path)? {
+            Some(stat) => Self::version(&stat),
+            None => return Ok(Conditional::Modified(None)),
+        };
+        if &current == version {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified(Some(current)))
+    }
+
+    pub fn file_version(&self, path: &str) -> Result<Option<Version>> {
+        Ok(self.stat(path)?.as_ref().map(Self::version))
+    }
+
+    pub fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        let path = self.path(path);
+        log::debug!("read {} ({} bytes at {})", path.display(), len, offset);
+        let what = format!("read {}", path.display());
+        self.run(&what, true, |sftp| {
+            let mut file = sftp.open(&path)?;
+            let stat = file.stat()?;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut data = Vec::new();
+            file.take(len).read_to_end(&mut data)?;
+            Ok(FileData {
+                data,
+                version: Some(Self::version(&stat)),
+            })
+        })
+    }
+
+    fn create(sftp: &Sftp, path: &Path) -> Result<ssh2::File> {
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        Ok(sftp.open_mode(path, flags, 0o644, OpenType::File)?)
+    }
+
+    pub fn write_file(&self, path: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(path);
+        log::debug!("write {} ({} bytes)", path.display(), data.len());
+        let what = format!("write {}", path.display());
+        self.run(&what, true, |sftp| {
+            Self::create(sftp, &path)?.write_all(data)?;
+            Ok(())
+        })
+    }
+
+    pub fn write_file_streamed(
+        &self,
+        path: &str,
+        len: u64,
+        open: &dyn Fn() -> Box<dyn Read + Send>,
+    ) -> Result<()> {
+        let path = self.path(path);
+        log::debug!("write {} ({} bytes, streamed)", path.display(), len);
+        let what = format!("write {}", path.display());
+        self.run(&what, true, |sftp| {
+            let mut file = Self::create(sftp, &path)?;
+            io::copy(&mut open().take(len), &mut file)?;
+            Ok(())
+        })
+    }
+
+    // pwrite on a handle opened without truncation
+    pub fn write_range(&self, path: &str, offset: u64, data: &[u8]) -> Result<bool> {
+        let path = self.path(path);
+        log::debug!(
+            "write {} ({} bytes at {})",
+            path.display(),
+            data.len(),
+            offset
+        );
+        let what = format!("write {}", path.display());
+        self.run(&what, true, |sftp| {
+            let flags = OpenFlags::WRITE | OpenFlags::CREATE;
+            let mut file = sftp.open_mode(&path, flags, 0o644, OpenType::File)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(data)?;
+            Ok(true)
+        })
+    }
+
+    pub fn create_directory(&self, path: &str) -> Result<()> {
+        let path = self.path(path);
+        log::debug!("mkdir {}", path.display());
+        let what = format!("mkdir {}", path.display());
+        self.run(&what, false, |sftp| match sftp.mkdir(&path, 0o755) {
+            Ok(()) => Ok(()),
+            // Servers answer a plain failure for a directory that exists
+            Err(e) if e.code() == ErrorCode::SFTP(FX_FAILURE) && sftp.stat(&path).is_ok() => {
+                Err(anyhow::Error::new(FsError::AlreadyExists))
+            }
+            Err(e) => Err(e.into()),
+        })
+    }
+
+    fn remove_all(sftp: &Sftp, path: &Path) -> Result<()> {
+        for (child, stat) in sftp.readdir(path)? {
+            if stat.is_dir() {
+                Self::remove_all(sftp, &child)?;
+            } else {
+                sftp.unlink(&child)?;
+            }
+        }
+        Ok(sftp.rmdir(path)?)
+    }
+
+    // Directories are deleted with everything under them, like the REST
+    // server does; the filesystem checks they are empty first
+    pub fn delete(&self, path: &str) -> Result<()> {
+        let path = self.path(path);
+        log::debug!("remove {}", path.display());
+        let what = format!("remove {}", path.display());
+        self.run(&what, false, |sftp| {
+            if sftp.lstat(&path)?.is_dir() {
+                Self::remove_all(sftp, &path)
+            } else {
+                Ok(sftp.unlink(&path)?)
+            }
+        })
+    }
+
+    // SFTP version 3 refuses to rename over an existing file, which is
+    // removed first then
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (self.path(from), self.path(to));
+        log::debug!("rename {} to {}", from.display(), to.display());
+        let what = format!("rename {} to {}", from.display(), to.display());
+        let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+        self.run(&what, false, |sftp| {
+            match sftp.rename(&from, &to, Some(flags)) {
+                Ok(()) => Ok(()),
+                Err(e)
+                    if matches!(
+                        e.code(),
+                        ErrorCode::SFTP(FX_FAILURE | FX_FILE_ALREADY_EXISTS)
+                    ) =>
+                {
+                    match sftp.lstat(&to) {
+                        Ok(stat) if !stat.is_dir() => {
+                            sftp.unlink(&to)?;
+                            Ok(sftp.rename(&from, &to, Some(flags))?)
+                        }
+                        _ => Err(e.into()),
+                    }
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    // Connects if needed, and stats the root
+    pub fn check_reachable(&self, _timeout: Duration) -> Result<()> {
+        let root = self.path("");
+        let what = format!("stat {}", root.display());
+        self.run(&what, true, |sftp| Ok(sftp.stat(&root)?))
+            .map(|_| ())
+    }
+}
+
+impl RemoteBackend for SftpBackend {
+    fn chunk_size(&self) -> u64 {
+        self.config.chunk_size
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.consecutive_failures.load(Ordering::Relaxed) == 0
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.config.base_urls[0]
+    }
+
+    fn check_reachable(&self, timeout: Duration) -> Result<()> {
+        SftpBackend::check_reachable(self, timeout)
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Listing> {
+        SftpBackend::list_directory(self, path)
+    }
+
+    fn revalidate_listing(&self, path: &str, version: &Version) -> Result<Conditional<Listing>> {
+        SftpBackend::revalidate_listing(self, path, version)
+    }
+
+    fn revalidate_file(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Option<Version>>> {
+        SftpBackend::revalidate_file(self, path, version)
+    }
+
+    fn file_version(&self, path: &str) -> Result<Option<Version>> {
+        SftpBackend::file_version(self, path)
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        SftpBackend::read_range(self, path, offset, len)
+    }
+
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<()> {
+        SftpBackend::write_file(self, path, data)
+    }
+
+    fn write_file_streamed(
+        &self,
+        path: &str,
+        len: u64,
+        open: &dyn Fn() -> Box<dyn Read + Send>,
+    ) -> Result<()> {
+        SftpBackend::write_file_streamed(self, path, len, open)
+    }
+
+    fn write_range(&self, path: &str, offset: u64, data: &[u8]) -> Result<bool> {
+        SftpBackend::write_range(self, path, offset, data)
+    }
+
+    fn create_directory(&self, path: &str) -> Result<()> {
+        SftpBackend::create_directory(self, path)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        SftpBackend::delete(self, path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        SftpBackend::rename(self, from, to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn backend(url: &str) -> Result<SftpBackend> {
+        let config = ClientConfig::new(vec![url.to_string()]);
+        SftpBackend::with_config(config, SftpConfig::default())
+    }
+
+    fn sftp_error(code: i32) -> anyhow::Error {
+        ssh2::Error::new(ErrorCode::SFTP(code), "failed").into()
+    }
+
+    #[test]
+    fn urls_name_the_server() {
+        let sftp = backend("sftp://alice@files.example:2222/srv/data/").unwrap();
+        assert_eq!(sftp.host, "files.example");
+        assert_eq!(sftp.port, 2222);
+        assert_eq!(sftp.user, "alice");
+        assert_eq!(sftp.path("/"), PathBuf::from("/srv/data"));
+        assert_eq!(sftp.path("/a/b.txt/"), PathBuf::from("/srv/data/a/b.txt"));
+
+        let sftp = backend("sftp://me@host.x@[::1]").unwrap();
+        assert_eq!(sftp.user, "me@host.x");
+        assert_eq!(sftp.host, "::1");
+        assert_eq!(sftp.port, DEFAULT_PORT);
+        // The login directory, resolved once connected
+        assert_eq!(sftp.path(""), PathBuf::from("."));
+
+        let sftp = backend("sftp://bob@[::1]:2200/").unwrap();
+        assert_eq!((sftp.host.as_str(), sftp.port), ("::1", 2200));
+    }
+
+    #[test]
+    fn bad_urls_are_refused() {
+        assert!(backend("ssh://alice@host/").is_err());
+        assert!(backend("sftp://alice@/srv").is_err());
+        assert!(backend("sftp://alice@host:ssh/srv").is_err());
+        let config = ClientConfig::new(vec!["sftp://alice@host".to_string()]);
+        let sftp = SftpConfig {
+            sessions: 0,
+            ..SftpConfig::default()
+        };
+        assert!(SftpBackend::with_config(config, sftp).is_err());
+    }
+
+    #[test]
+    fn statuses_are_classified() {
+        let table = [
+            (FX_NO_SUCH_FILE, FsError::NotFound),
+            (FX_NO_SUCH_PATH, FsError::NotFound),
+            (FX_PERMISSION_DENIED, FsError::PermissionDenied),
+            (FX_FILE_ALREADY_EXISTS, FsError::AlreadyExists),
+            (FX_WRITE_PROTECT, FsError::ReadOnly),
+            (FX_QUOTA_EXCEEDED, FsError::NoSpace),
+            (FX_DIR_NOT_EMPTY, FsError::NotEmpty),
+            (FX_NOT_A_DIRECTORY, FsError::NotADirectory),
+            (FX_OP_UNSUPPORTED, FsError::Unsupported),
+            (FX_CONNECTION_LOST, FsError::Unreachable),
+            (FX_FAILURE, FsError::Io),
+        ];
+        for (code, kind) in table {
+            assert_eq!(classify(&sftp_error(code)), kind, "{}", code);
+        }
+        let timeout = ssh2::Error::new(ErrorCode::Session(SESSION_TIMEOUT), "timed out");
+        assert_eq!(classify(&timeout.into()), FsError::TimedOut);
+        let gone = io::Error::new(io::ErrorKind::NotFound, "gone");
+        assert_eq!(classify(&gone.into()), FsError::NotFound);
+        assert_eq!(classify(&FsError::Stale.into()), FsError::Stale);
+    }
+
+    #[test]
+    fn only_session_errors_lose_the_connection() {
+        assert!(connection_lost(&sftp_error(FX_NO_CONNECTION)));
+        assert!(connection_lost(&sftp_error(FX_CONNECTION_LOST)));
+        assert!(!connection_lost(&sftp_error(FX_NO_SUCH_FILE)));
+        let session = ssh2::Error::new(ErrorCode::Session(-7), "socket send");
+        assert!(connection_lost(&session.into()));
+        let broken = io::Error::new(io::ErrorKind::BrokenPipe, "broken");
+        assert!(connection_lost(&broken.into()));
+        let missing = io::Error::new(io::ErrorKind::NotFound, "missing");
+        assert!(!connection_lost(&missing.into()));
+        assert!(!connection_lost(&FsError::NotFound.into()));
+    }
+
+    #[test]
+    fn versions_are_mtime_and_size() {
+        let stat = FileStat {
+            size: Some(4096),
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: None,
+            mtime: Some(1_700_000_000),
+        };
+        assert_eq!(
+            SftpBackend::version(&stat),
+            Version::ETag("\"6553f100-1000\"".to_string())
+        );
+    }
+
+    #[test]
+    fn unreachable_servers_give_their_session_back() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let sftp = backend(&format!("sftp://alice@127.0.0.1:{}/srv", port)).unwrap();
+
+        for _ in 0..2 {
+            let error = sftp.file_version("/a").unwrap_err();
+            assert_eq!(FsError::from_backend(&error), FsError::Unreachable);
+            assert!(format!("{:#}", error).contains("Failed to connect"), "{:#}", error);
+        }
+        assert_eq!(sftp.consecutive_failures.load(Ordering::Relaxed), 2);
+        assert_eq!(sftp.pool.lock().unwrap().open, 0);
+        assert!(!sftp.is_healthy());
+    }
+
+    #[test]
+    fn failed_handshakes_are_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("sftp://alice@{}/srv", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").unwrap();
+        });
+        let sftp = backend(&url).unwrap();
+        let error = sftp.check_reachable(Duration::from_secs(5)).unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::Unreachable);
+        assert!(format!("{:#}", error).contains("SSH handshake failed"), "{:#}", error);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+use reqwest::StatusCode;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::api_client::ServerError;
+use crate::filesystem::RemoteBackend;
+
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+// Time an attempt gets even when the deadline is about to pass
+const MIN_ATTEMPT: Duration = Duration::from_millis(500);
+
+// Why a mount did not come up, with distinct exit statuses for the binary
+#[derive(Debug)]
+pub enum MountError {
+    // The server never answered within the mount timeout and retries
+    Unreachable {
+        url: String,
+        attempts: u32,
+        last_error: anyhow::Error,
+    },
+    // The server turned the credentials down
+    Unauthorized {
+        url: String,
+        status: StatusCode,
+    },
+    // A remotefs mount is already there
+    Busy(String),
+}
+
+impl MountError {
+    // Distinct exit statuses for the mount command, 0 being success and 1
+    // any other failure
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Unreachable { .. } => 2,
+            Self::Unauthorized { .. } => 3,
+            Self::Busy(_) => 4,
+        }
+    }
+
+    // The exit status for any mount error, 1 unless it is a MountError
+    pub fn exit_code_of(error: &anyhow::Error) -> i32 {
+        error.downcast_ref::<Self>().map_or(1, Self::exit_code)
+    }
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unreachable {
+                url,
+                attempts,
+                last_error,
+            } => write!(
+                f,
+                "server {} unreachable after {} attempts, last error: {:#}",
+                url, attempts, last_error
+            ),
+            Self::Unauthorized { url, status } => {
+                write!(f, "server {} refused the credentials: {}", url, status)
+            }
+            Self::Busy(mountpoint) => write!(f, "{} is already mounted", mountpoint),
+        }
+    }
+}
+
+impl std::error::Error for MountError {}
+
+// How long mounting waits for the server to answer
+#[derive(Debug, Clone, Copy)]
+pub struct StartupConfig {
+    // Overall deadline for the health check and the listing of the root
+    pub timeout: Duration,
+    // Attempts after the first one, spaced by a doubling backoff
+    pub retries: u32,
+}
+
+impl Default for StartupConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            retries: 3,
+        }
+    }
+}
+
+// Checks the server until it answers, retrying with backoff until the
+// retries or the deadline run out. Refused credentials are not retried.
+pub fn wait_for_server(
+    backend: &dyn RemoteBackend,
+    config: StartupConfig,
+) -> Result<(), MountError> {
+    let deadline = Instant::now() + config.timeout;
+    let mut backoff = FIRST_BACKOFF;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let error = match backend.check_reachable(remaining.max(MIN_ATTEMPT)) {
+            Ok(()) => {
+                log::info!("Using endpoint {}", backend.endpoint());
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+
+        let url = backend.endpoint().to_string();
+        if let Some(ServerError { status, .. }) = error.downcast_ref::<ServerError>() {
+            if matches!(*status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+                return Err(MountError::Unauthorized {
+                    url,
+                    status: *status,
+                });
+            }
+        }
+        // The last attempt is made at the deadline at the latest, with at
+        // least MIN_ATTEMPT to answer
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if attempts > config.retries || remaining.is_zero() {
+            return Err(MountError::Unreachable {
+                url,
+                attempts,
+                last_error: error,
+            });
+        }
+
+        let delay = backoff.min(remaining);
+        log::warn!(
+            "Server {} not ready, retrying in {:?}: {:#}",
+            url,
+            delay,
+            error
+        );
+        thread::sleep(delay);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_client::ApiClient;
+    use crate::filesystem::FsError;
+    use crate::testing::MockBackend;
+
+    fn listings(mock: &MockBackend) -> usize {
+        mock.take_calls().iter().filter(|call| call.starts_with("list ")).count()
+    }
+
+    #[test]
+    fn a_server_coming_up_is_waited_for() {
+        let mock = MockBackend::new();
+        mock.fail_next("list", FsError::Unreachable);
+        let started = Instant::now();
+        wait_for_server(&mock, StartupConfig::default()).unwrap();
+        assert_eq!(listings(&mock), 2);
+        assert!(started.elapsed() >= FIRST_BACKOFF);
+    }
+
+    #[test]
+    fn retries_run_out() {
+        let mock = MockBackend::new();
+        mock.on_call(|mock, op, _| {
+            if op == "list" {
+                mock.fail_next("list", FsError::Unreachable);
+            }
+        });
+        let config = StartupConfig {
+            timeout: Duration::from_secs(30),
+            retries: 1,
+        };
+        let error = wait_for_server(&mock, config).unwrap_err();
+        assert!(matches!(error, MountError::Unreachable { attempts: 2, .. }), "{}", error);
+        assert_eq!(error.exit_code(), 2);
+        assert!(error.to_string().starts_with("server mock unreachable after 2 attempts"));
+    }
+
+    #[test]
+    fn the_deadline_cuts_retries_short() {
+        let mock = MockBackend::new();
+        mock.on_call(|mock, op, _| {
+            if op == "list" {
+                mock.fail_next("list", FsError::Unreachable);
+            }
+        });
+        let config = StartupConfig {
+            timeout: Duration::from_millis(700),
+            retries: 100,
+        };
+        let started = Instant::now();
+        let error = wait_for_server(&mock, config).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(3));
+        // Tried at once, after 500ms, and at the deadline
+        assert!(matches!(error, MountError::Unreachable { attempts: 2..=3, .. }), "{}", error);
+    }
+
+    #[test]
+    fn refused_credentials_are_not_retried() {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let served = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            while reader.read_line(&mut head).unwrap() > 2 {}
+            let response = "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n";
+            reader.into_inner().write_all(response.as_bytes()).unwrap();
+        });
+
+        let client = ApiClient::new(url).unwrap();
+        let error = wait_for_server(&client, StartupConfig::default()).unwrap_err();
+        served.join().unwrap();
+        assert!(matches!(
+            error,
+            MountError::Unauthorized {
+                status: StatusCode::UNAUTHORIZED,
+                ..
+            }
+        ));
+        assert_eq!(error.exit_code(), 3);
+    }
+
+    #[test]
+    fn exit_codes_tell_mount_errors_apart() {
+        let busy = anyhow::Error::new(MountError::Busy("/mnt".to_string()));
+        assert_eq!(MountError::exit_code_of(&busy), 4);
+        assert_eq!(MountError::exit_code_of(&busy.context("mounting /mnt")), 4);
+        assert_eq!(MountError::exit_code_of(&anyhow::anyhow!("other")), 1);
+    }
+}
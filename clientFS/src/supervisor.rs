@@ -0,0 +1,296 @@
+use anyhow::{Context, Result};
+use reqwest::blocking::Client;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::filesystem::{install_shutdown_handlers, shutdown_requested, SIGNAL_POLL_INTERVAL};
+use crate::{ConfigFile, HttpConfig, MountConfig, MountHandle, StatsSnapshot};
+
+// Pause before a failed session is mounted again, and between attempts
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+struct Supervised {
+    name: String,
+    mountpoint: String,
+    config: MountConfig,
+    handle: Option<MountHandle>,
+    // Set while the mount is down and waiting to be mounted again
+    retry_at: Option<Instant>,
+}
+
+// Serves several mounts from one process. Those with the default connection
+// options share the HTTP client, and with it the connection pool and the
+// runtime behind it, but nothing else: a session that fails is mounted again
+// without disturbing the others.
+pub struct Supervisor {
+    http: Client,
+    mounts: Vec<Supervised>,
+}
+
+impl Supervisor {
+    pub fn new() -> Result<Self> {
+        let http = HttpConfig::default().client()?;
+        Ok(Self {
+            http,
+            mounts: Vec::new(),
+        })
+    }
+
+    // Mounts the named profiles of `file`, or every profile that names a
+    // mountpoint when none are named
+    pub fn from_config(file: &ConfigFile, profiles: &[String]) -> Result<Self> {
+        let mut supervisor = Self::new()?;
+        for (name, mountpoint, config) in planned(file, profiles)? {
+            supervisor.add(&name, &mountpoint, config)?;
+        }
+        Ok(supervisor)
+    }
+
+    // Mounts `config` at `mountpoint` under `name`, which also labels its
+    // logs unless the configuration has a label. Errors mounting are returned
+    // here, a session failing later is mounted again.
+    pub fn add(&mut self, name: &str, mountpoint: &str, mut config: MountConfig) -> Result<()> {
+        if self.mounts.iter().any(|mount| mount.name == name) {
+            anyhow::bail!("A mount named '{}' already exists", name);
+        }
+        config.fs.label.get_or_insert_with(|| name.to_string());
+
+        let handle = mount_shared(&self.http, &config, mountpoint)
+            .with_context(|| format!("Failed to mount '{}' at {}", name, mountpoint))?;
+        log::info!("[{}] Mounted {}", name, mountpoint);
+        self.mounts.push(Supervised {
+            name: name.to_string(),
+            mountpoint: mountpoint.to_string(),
+            config,
+            handle: Some(handle),
+            retry_at: None,
+        });
+        Ok(())
+    }
+
+    // Statistics of the mounts currently up, by name
+    pub fn stats(&self) -> Vec<(String, StatsSnapshot)> {
+        self.mounts
+            .iter()
+            .filter_map(|mount| {
+                let handle = mount.handle.as_ref()?;
+                Some((mount.name.clone(), handle.filesystem().stats_snapshot()))
+            })
+            .collect()
+    }
+
+    // Notices sessions that ended since the last call. Failed ones are
+    // mounted again once RESTART_DELAY passed, ones unmounted from outside
+    // are left alone.
+    pub fn check(&mut self) {
+        let now = Instant::now();
+        for mount in &mut self.mounts {
+            if mount.handle.as_ref().is_some_and(MountHandle::is_running) {
+                continue;
+            }
+            if let Some(handle) = mount.handle.take() {
+                match handle.join() {
+                    Ok(()) => {
+                        log::info!("[{}] {} was unmounted", mount.name, mount.mountpoint);
+                        continue;
+                    }
+                    Err(e) => {
+                        log::error!(
+                            "[{}] Session at {} failed: {:#}",
+                            mount.name,
+                            mount.mountpoint,
+                            e
+                        );
+                        mount.retry_at = Some(now + RESTART_DELAY);
+                    }
+                }
+            }
+
+            if mount.retry_at.is_none_or(|at| at > now) {
+                continue;
+            }
+            match mount_shared(&self.http, &mount.config, &mount.mountpoint) {
+                Ok(handle) => {
+                    log::info!("[{}] Mounted {} again", mount.name, mount.mountpoint);
+                    mount.handle = Some(handle);
+                    mount.retry_at = None;
+                }
+                Err(e) => {
+                    log::error!(
+                        "[{}] Failed to mount {} again: {:#}",
+                        mount.name,
+                        mount.mountpoint,
+                        e
+                    );
+                    mount.retry_at = Some(now + RESTART_DELAY);
+                }
+            }
+        }
+    }
+
+    // Serves until SIGINT, SIGTERM or SIGHUP arrives or every mount was
+    // unmounted from outside, then unmounts the rest as MountGuard's
+    // run_until_signal does
+    pub fn run_until_signal(mut self, flush_deadline: Duration) -> Result<()> {
+        install_shutdown_handlers()?;
+
+        while !shutdown_requested() {
+            self.check();
+            let done = self
+                .mounts
+                .iter()
+                .all(|mount| mount.handle.is_none() && mount.retry_at.is_none());
+            if done {
+                return Ok(());
+            }
+            thread::sleep(SIGNAL_POLL_INTERVAL);
+        }
+
+        log::info!("Signal received, unmounting everything");
+        self.stop(Some(flush_deadline))
+    }
+
+    // Unmounts every mount, the others still when one fails
+    pub fn unmount_all(mut self) -> Result<()> {
+        self.stop(None)
+    }
+
+    // Unmounts in parallel, so the flush deadline holds for all of them at once
+    fn stop(&mut self, flush_deadline: Option<Duration>) -> Result<()> {
+        let failed: Vec<String> = thread::scope(|scope| {
+            let unmounting: Vec<_> = self
+                .mounts
+                .iter_mut()
+                .filter_map(|mount| {
+                    let handle = mount.handle.take()?;
+                    mount.retry_at = None;
+                    let result = scope.spawn(move || match flush_deadline {
+                        Some(deadline) => handle.unmount_within(deadline),
+                        None => handle.unmount(),
+                    });
+                    Some((mount.name.as_str(), result))
+                })
+                .collect();
+
+            unmounting
+                .into_iter()
+                .filter_map(|(name, result)| {
+                    let error = match result.join() {
+                        Ok(Ok(())) => return None,
+                        Ok(Err(e)) => e,
+                        Err(_) => anyhow::anyhow!("unmounting panicked"),
+                    };
+                    log::error!("[{}] Failed to unmount cleanly: {:#}", name, error);
+                    Some(name.to_string())
+                })
+                .collect()
+        });
+
+        if !failed.is_empty() {
+            anyhow::bail!("Failed to unmount cleanly: {}", failed.join(", "));
+        }
+        Ok(())
+    }
+}
+
+// What from_config mounts: name, mountpoint and configuration of each
+// profile. A profile asked for by name has to say where it goes.
+fn planned(file: &ConfigFile, profiles: &[String]) -> Result<Vec<(String, String, MountConfig)>> {
+    let mounts = if profiles.is_empty() {
+        file.mounts()?
+    } else {
+        let mut mounts = Vec::new();
+        for name in profiles {
+            let mut config = MountConfig::new(Vec::new());
+            file.apply(name, &mut config)?;
+            config.fs.label = Some(name.clone());
+            mounts.push((name.clone(), config));
+        }
+        mounts
+    };
+
+    let mut planned = Vec::new();
+    for (name, config) in mounts {
+        let mountpoint = config
+            .mountpoint
+            .clone()
+            .with_context(|| format!("Profile '{}' names no mountpoint", name))?;
+        planned.push((name, mountpoint, config));
+    }
+    if planned.is_empty() {
+        anyhow::bail!("No profile in the configuration names a mountpoint");
+    }
+    Ok(planned)
+}
+
+fn mount_shared(http: &Client, config: &MountConfig, mountpoint: &str) -> Result<MountHandle> {
+    let http = if config.client.http == HttpConfig::default() {
+        http.clone()
+    } else {
+        config.client.http.client()?
+    };
+    let backend = crate::connect(config, http)?;
+    crate::mount_client(backend, config.clone(), mountpoint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(text: &str) -> ConfigFile {
+        toml::from_str(text).unwrap()
+    }
+
+    const TWO_MOUNTS: &str = r#"
+        [profiles.default]
+        server = ["http://a"]
+
+        [profiles.work]
+        server = ["http://work"]
+        mountpoint = "/mnt/work"
+
+        [profiles.home]
+        server = ["http://home"]
+        mountpoint = "/mnt/home"
+        read_only = true
+    "#;
+
+    #[test]
+    fn every_profile_with_a_mountpoint_is_mounted() {
+        let planned = planned(&config(TWO_MOUNTS), &[]).unwrap();
+        let mounts: Vec<_> = planned
+            .iter()
+            .map(|(name, at, _)| (name.as_str(), at.as_str()))
+            .collect();
+        assert_eq!(mounts, [("home", "/mnt/home"), ("work", "/mnt/work")]);
+
+        let (_, _, home) = &planned[0];
+        assert_eq!(home.client.base_urls, ["http://home"]);
+        assert_eq!(home.fs.label.as_deref(), Some("home"));
+        assert!(home.fs.read_only);
+        assert!(!planned[1].2.fs.read_only);
+    }
+
+    #[test]
+    fn profiles_can_be_picked() {
+        let planned = planned(&config(TWO_MOUNTS), &["work".to_string()]).unwrap();
+        assert_eq!(planned.len(), 1);
+        assert_eq!(planned[0].1, "/mnt/work");
+
+        let e = planned_error(TWO_MOUNTS, &["default"]);
+        assert!(e.contains("Profile 'default' names no mountpoint"), "{}", e);
+        let e = planned_error(TWO_MOUNTS, &["play"]);
+        assert!(e.contains("No profile 'play'"), "{}", e);
+    }
+
+    #[test]
+    fn nothing_to_mount_is_an_error() {
+        let e = planned_error("[profiles.default]\nserver = [\"http://a\"]\n", &[]);
+        assert!(e.contains("names a mountpoint"), "{}", e);
+    }
+
+    fn planned_error(text: &str, profiles: &[&str]) -> String {
+        let profiles: Vec<String> = profiles.iter().map(|name| name.to_string()).collect();
+        format!("{:#}", planned(&config(text), &profiles).unwrap_err())
+    }
+}
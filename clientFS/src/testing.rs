@@ -0,0 +1,522 @@
+use anyhow::Result;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::io::{Cursor, Read};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::api_client::{
+    upload_mtime, Conditional, Expected, FileData, FileEntry, Listing, Timestamp, Version,
+};
+use crate::filesystem::{FsError, RemoteBackend};
+
+// Runs before every call of a MockBackend with the call's name and path, to
+// act as another client changing the tree between two operations or to
+// hold a call up
+type Hook = Arc<dyn Fn(&MockBackend, &str, &str) + Send + Sync>;
+
+struct Node {
+    // None for a directory
+    data: Option<Vec<u8>>,
+    mtime: SystemTime,
+    mode: u32,
+    id: u64,
+    owner: Option<(u32, u32)>,
+    // Bumped on every change, the node's ETag
+    generation: u64,
+}
+
+struct Tree {
+    nodes: BTreeMap<String, Node>,
+    generations: u64,
+}
+
+impl Tree {
+    fn next_generation(&mut self) -> u64 {
+        self.generations += 1;
+        self.generations
+    }
+
+    fn insert(&mut self, path: &str, data: Option<Vec<u8>>) -> Result<()> {
+        let (parent, _) = split(path);
+        match self.nodes.get(parent) {
+            Some(node) if node.data.is_none() => {}
+            Some(_) => return Err(failure(FsError::NotADirectory, "create", path)),
+            None => return Err(failure(FsError::NotFound, "create", parent)),
+        }
+        let generation = self.next_generation();
+        let mode = if data.is_some() { 0o644 } else { 0o755 };
+        let node = self.nodes.entry(path.to_string()).or_insert(Node {
+            data: None,
+            mtime: SystemTime::now(),
+            mode,
+            id: generation,
+            owner: None,
+            generation,
+        });
+        node.data = data;
+        node.mtime = upload_mtime().unwrap_or_else(SystemTime::now);
+        node.generation = generation;
+        Ok(())
+    }
+
+    fn file(&mut self, what: &str, path: &str) -> Result<&mut Node> {
+        match self.nodes.get_mut(path) {
+            Some(node) if node.data.is_some() => Ok(node),
+            Some(_) => Err(failure(FsError::IsADirectory, what, path)),
+            None => Err(failure(FsError::NotFound, what, path)),
+        }
+    }
+
+    // Fails with Stale, as a 412 would, when `path` is not as expected
+    fn check(&self, path: &str, expected: &Expected) -> Result<()> {
+        let current = self.nodes.get(path).map(version);
+        let met = match expected {
+            Expected::Any => true,
+            Expected::Absent => current.is_none(),
+            Expected::Version(expected) => current.as_ref() == Some(expected),
+        };
+        if !met {
+            return Err(failure(FsError::Stale, "check", path));
+        }
+        Ok(())
+    }
+
+    // `path` and everything under it
+    fn subtree(&self, path: &str) -> Vec<String> {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        self.nodes
+            .keys()
+            .filter(|key| *key == path || key.starts_with(&prefix))
+            .cloned()
+            .collect()
+    }
+}
+
+fn failure(kind: FsError, what: &str, path: &str) -> anyhow::Error {
+    anyhow::Error::new(kind).context(format!("{} {}", what, path))
+}
+
+fn version(node: &Node) -> Version {
+    Version::ETag(format!("\"{:x}\"", node.generation))
+}
+
+// "/a/b" into "/a" and "b", "/a" into "/" and "a"
+fn split(path: &str) -> (&str, &str) {
+    match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((parent, name)) => (parent, name),
+        None => ("/", path),
+    }
+}
+
+fn normalize(path: &str) -> String {
+    let path = path.trim_matches('/');
+    format!("/{}", path)
+}
+
+// An in-memory tree served as the remote one, for testing the filesystem
+// without a server or a scratch directory. Every call is recorded as
+// "op path", with the same names as in the hooks, and the next call of an
+// operation can be made to fail with a given kind.
+pub struct MockBackend {
+    tree: Mutex<Tree>,
+    calls: Mutex<Vec<String>>,
+    failures: Mutex<HashMap<String, FsError>>,
+    hook: Mutex<Option<Hook>>,
+    archive: Mutex<Option<Vec<u8>>>,
+    clock_skew: Mutex<Option<i64>>,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockBackend {
+    // An empty root directory
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            "/".to_string(),
+            Node {
+                data: None,
+                mtime: SystemTime::now(),
+                mode: 0o755,
+                id: 1,
+                owner: None,
+                generation: 1,
+            },
+        );
+        Self {
+            tree: Mutex::new(Tree {
+                nodes,
+                generations: 1,
+            }),
+            calls: Mutex::new(Vec::new()),
+            failures: Mutex::new(HashMap::new()),
+            hook: Mutex::new(None),
+            archive: Mutex::new(None),
+            clock_skew: Mutex::new(None),
+        }
+    }
+
+    // Creates or replaces a file, as another client would, without a call
+    // being recorded. The parent has to exist.
+    pub fn add_file(&self, path: &str, data: &[u8]) {
+        let path = normalize(path);
+        self.tree.lock().unwrap().insert(&path, Some(data.to_vec())).unwrap();
+    }
+
+    pub fn add_dir(&self, path: &str) {
+        let path = normalize(path);
+        let mut tree = self.tree.lock().unwrap();
+        if !tree.nodes.contains_key(&path) {
+            tree.insert(&path, None).unwrap();
+        }
+    }
+
+    // Owner reported for `path` in listings
+    pub fn set_owner(&self, path: &str, uid: u32, gid: u32) {
+        if let Some(node) = self.tree.lock().unwrap().nodes.get_mut(&normalize(path)) {
+            node.owner = Some((uid, gid));
+        }
+    }
+
+    // Contents of a file, None for directories and missing paths
+    pub fn contents(&self, path: &str) -> Option<Vec<u8>> {
+        let tree = self.tree.lock().unwrap();
+        tree.nodes.get(&normalize(path))?.data.clone()
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.tree.lock().unwrap().nodes.contains_key(&normalize(path))
+    }
+
+    pub fn mtime(&self, path: &str) -> Option<SystemTime> {
+        let tree = self.tree.lock().unwrap();
+        tree.nodes.get(&normalize(path)).map(|node| node.mtime)
+    }
+
+    // Names in directory `path`, sorted
+    pub fn names(&self, path: &str) -> Vec<String> {
+        let path = normalize(path);
+        let tree = self.tree.lock().unwrap();
+        tree.nodes
+            .keys()
+            .filter(|key| *key != "/" && split(key).0 == path)
+            .map(|key| split(key).1.to_string())
+            .collect()
+    }
+
+    // Every call made so far, as "op path"
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    pub fn take_calls(&self) -> Vec<String> {
+        std::mem::take(&mut *self.calls.lock().unwrap())
+    }
+
+    // The next call of `op` fails with `kind`, without doing anything
+    pub fn fail_next(&self, op: &str, kind: FsError) {
+        self.failures.lock().unwrap().insert(op.to_string(), kind);
+    }
+
+    // Runs `hook` before every call from now on
+    pub fn on_call(&self, hook: impl Fn(&MockBackend, &str, &str) + Send + Sync + 'static) {
+        *self.hook.lock().unwrap() = Some(Arc::new(hook));
+    }
+
+    // Sent for any subtree asked for as an archive
+    pub fn serve_archive(&self, tar: Vec<u8>) {
+        *self.archive.lock().unwrap() = Some(tar);
+    }
+
+    // Seconds the server's clock is reported to run ahead of ours
+    pub fn set_clock_skew(&self, skew: i64) {
+        *self.clock_skew.lock().unwrap() = Some(skew);
+    }
+
+    fn call(&self, op: &str, path: &str) -> Result<String> {
+        let path = normalize(path);
+        self.calls.lock().unwrap().push(format!("{} {}", op, path));
+        // Not held while it runs, so it can change the tree
+        let hook = self.hook.lock().unwrap().clone();
+        if let Some(hook) = hook {
+            hook(self, op, &path);
+        }
+        if let Some(kind) = self.failures.lock().unwrap().remove(op) {
+            return Err(anyhow::Error::new(kind).context(format!("Injected failure of {}", op)));
+        }
+        Ok(path)
+    }
+
+    fn listing(&self, path: &str) -> Result<Listing> {
+        let tree = self.tree.lock().unwrap();
+        match tree.nodes.get(path) {
+            Some(node) if node.data.is_none() => {}
+            Some(_) => return Err(failure(FsError::NotADirectory, "list", path)),
+            None => return Err(failure(FsError::NotFound, "list", path)),
+        }
+
+        let mut fingerprint = DefaultHasher::new();
+        let mut entries = Vec::new();
+        for (key, node) in &tree.nodes {
+            if key == "/" || split(key).0 != path {
+                continue;
+            }
+            (key, node.generation).hash(&mut fingerprint);
+            let (uid, gid) = node.owner.unzip();
+            entries.push(FileEntry {
+                name: split(key).1.to_string(),
+                is_dir: node.data.is_none(),
+                size: node.data.as_ref().map_or(0, |data| data.len() as u64),
+                mtime: Timestamp::from(node.mtime),
+                ctime: Timestamp::from(node.mtime),
+                mode: node.mode,
+                id: Some(node.id),
+                uid,
+                gid,
+                major: None,
+                minor: None,
+                nlink: None,
+            });
+        }
+        Ok(Listing {
+            entries,
+            version: Some(Version::ETag(format!("W/\"{:016x}\"", fingerprint.finish()))),
+        })
+    }
+}
+
+impl RemoteBackend for MockBackend {
+    fn chunk_size(&self) -> u64 {
+        1 << 20
+    }
+
+    fn endpoint(&self) -> &str {
+        "mock"
+    }
+
+    fn clock_skew(&self) -> Option<i64> {
+        *self.clock_skew.lock().unwrap()
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Listing> {
+        let path = self.call("list", path)?;
+        self.listing(&path)
+    }
+
+    fn revalidate_listing(&self, path: &str, version: &Version) -> Result<Conditional<Listing>> {
+        let path = self.call("list", path)?;
+        let listing = self.listing(&path)?;
+        if listing.version.as_ref() == Some(version) {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified(listing))
+    }
+
+    fn revalidate_file(
+        &self,
+        path: &str,
+        cached: &Version,
+    ) -> Result<Conditional<Option<Version>>> {
+        let path = self.call("stat", path)?;
+        let current = match self.tree.lock().unwrap().nodes.get(&path) {
+            Some(node) => version(node),
+            None => return Ok(Conditional::Modified(None)),
+        };
+        if &current == cached {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified(Some(current)))
+    }
+
+    fn file_version(&self, path: &str) -> Result<Option<Version>> {
+        let path = self.call("stat", path)?;
+        Ok(self.tree.lock().unwrap().nodes.get(&path).map(version))
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        let path = self.call("read", path)?;
+        let mut tree = self.tree.lock().unwrap();
+        let node = tree.file("read", &path)?;
+        let data = node.data.as_deref().unwrap_or_default();
+        let start = (offset as usize).min(data.len());
+        let end = start.saturating_add(len as usize).min(data.len());
+        Ok(FileData {
+            data: data[start..end].to_vec(),
+            version: Some(version(node)),
+        })
+    }
+
+    fn download_archive(&self, path: &str) -> Result<Option<Box<dyn Read + Send + '_>>> {
+        self.call("archive", path)?;
+        Ok(self
+            .archive
+            .lock()
+            .unwrap()
+            .clone()
+            .map(|tar| Box::new(Cursor::new(tar)) as Box<dyn Read + Send>))
+    }
+
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<()> {
+        self.write_file_if(path, data, &Expected::Any).map(|_| ())
+    }
+
+    fn write_file_if(
+        &self,
+        path: &str,
+        data: &[u8],
+        expected: &Expected,
+    ) -> Result<Option<Version>> {
+        let path = self.call("write", path)?;
+        let mut tree = self.tree.lock().unwrap();
+        tree.check(&path, expected)?;
+        if tree.nodes.get(&path).is_some_and(|node| node.data.is_none()) {
+            return Err(failure(FsError::IsADirectory, "write", &path));
+        }
+        tree.insert(&path, Some(data.to_vec()))?;
+        Ok(tree.nodes.get(&path).map(version))
+    }
+
+    fn write_file_streamed(
+        &self,
+        path: &str,
+        len: u64,
+        open: &dyn Fn() -> Box<dyn Read + Send>,
+    ) -> Result<()> {
+        let mut data = Vec::new();
+        open().take(len).read_to_end(&mut data)?;
+        let path = self.call("write", path)?;
+        self.tree.lock().unwrap().insert(&path, Some(data))
+    }
+
+    fn write_range(&self, path: &str, offset: u64, data: &[u8]) -> Result<bool> {
+        let path = self.call("write", path)?;
+        let mut tree = self.tree.lock().unwrap();
+        let generation = tree.next_generation();
+        let node = tree.file("write", &path)?;
+        let contents = node.data.get_or_insert_with(Vec::new);
+        let end = offset as usize + data.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[offset as usize..end].copy_from_slice(data);
+        node.mtime = upload_mtime().unwrap_or_else(SystemTime::now);
+        node.generation = generation;
+        Ok(true)
+    }
+
+    fn set_mtime(&self, path: &str, mtime: SystemTime) -> Result<()> {
+        let path = self.call("utimens", path)?;
+        let mut tree = self.tree.lock().unwrap();
+        let generation = tree.next_generation();
+        let node = tree.file("utimens", &path)?;
+        node.mtime = mtime;
+        node.generation = generation;
+        Ok(())
+    }
+
+    fn create_directory(&self, path: &str) -> Result<()> {
+        let path = self.call("mkdir", path)?;
+        let mut tree = self.tree.lock().unwrap();
+        if tree.nodes.contains_key(&path) {
+            return Err(failure(FsError::AlreadyExists, "mkdir", &path));
+        }
+        tree.insert(&path, None)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.delete_if(path, &Expected::Any)
+    }
+
+    // Directories are deleted with everything under them, like the REST
+    // server does
+    fn delete_if(&self, path: &str, expected: &Expected) -> Result<()> {
+        let path = self.call("delete", path)?;
+        let mut tree = self.tree.lock().unwrap();
+        if !tree.nodes.contains_key(&path) {
+            return Err(failure(FsError::NotFound, "delete", &path));
+        }
+        tree.check(&path, expected)?;
+        for key in tree.subtree(&path) {
+            tree.nodes.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let from = self.call("rename", from)?;
+        let to = normalize(to);
+        let mut tree = self.tree.lock().unwrap();
+        if !tree.nodes.contains_key(&from) {
+            return Err(failure(FsError::NotFound, "rename", &from));
+        }
+        if !tree.nodes.contains_key(split(&to).0) {
+            return Err(failure(FsError::NotFound, "rename", &to));
+        }
+        for key in tree.subtree(&to) {
+            tree.nodes.remove(&key);
+        }
+        for key in tree.subtree(&from) {
+            if let Some(node) = tree.nodes.remove(&key) {
+                let moved = format!("{}{}", to, &key[from.len()..]);
+                tree.nodes.insert(moved, node);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_are_recorded_in_order() {
+        let mock = MockBackend::new();
+        mock.write_file("docs", b"x").unwrap();
+        mock.list_directory("/").unwrap();
+        mock.read_range("/docs", 0, 1).unwrap();
+        assert_eq!(mock.take_calls(), ["write /docs", "list /", "read /docs"]);
+        assert!(mock.calls().is_empty());
+    }
+
+    #[test]
+    fn injected_failure_hits_only_the_next_call() {
+        let mock = MockBackend::new();
+        mock.add_file("/a", b"alpha");
+        mock.fail_next("read", FsError::Busy);
+
+        let error = mock.read_range("/a", 0, 5).unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::Busy);
+        assert_eq!(mock.read_range("/a", 0, 5).unwrap().data, b"alpha");
+    }
+
+    #[test]
+    fn conditional_write_fails_once_the_file_changed() {
+        let mock = MockBackend::new();
+        let version = mock.write_file_if("/a", b"one", &Expected::Absent).unwrap().unwrap();
+        mock.add_file("/a", b"theirs");
+
+        let error = mock.write_file_if("/a", b"two", &Expected::Version(version)).unwrap_err();
+        assert_eq!(FsError::from_backend(&error), FsError::Stale);
+        assert_eq!(mock.contents("/a").unwrap(), b"theirs");
+    }
+
+    #[test]
+    fn rename_moves_the_whole_subtree() {
+        let mock = MockBackend::new();
+        mock.add_dir("/docs");
+        mock.add_file("/docs/a.txt", b"a");
+        mock.rename("/docs", "/papers").unwrap();
+
+        assert!(!mock.exists("/docs"));
+        assert_eq!(mock.names("/papers"), ["a.txt"]);
+        assert_eq!(mock.contents("/papers/a.txt").unwrap(), b"a");
+    }
+}
@@ -0,0 +1,264 @@
+use anyhow::Context;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const PROC: &str = "/proc";
+const FS_NAME: &str = "remotefs";
+
+// A process keeping a mount busy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusyProcess {
+    pub pid: u32,
+    pub command: String,
+}
+
+#[derive(Debug)]
+pub enum UnmountError {
+    NotMounted,
+    Busy(Vec<BusyProcess>),
+    Failed(anyhow::Error),
+}
+
+impl UnmountError {
+    // Distinct exit statuses for the unmount subcommand, 0 being success
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Failed(_) => 1,
+            Self::NotMounted => 2,
+            Self::Busy(_) => 3,
+        }
+    }
+}
+
+impl fmt::Display for UnmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotMounted => f.write_str("not mounted"),
+            Self::Busy(processes) if processes.is_empty() => f.write_str("mount is busy"),
+            Self::Busy(processes) => {
+                f.write_str("mount is busy, in use by")?;
+                for process in processes {
+                    write!(f, " {} ({})", process.pid, process.command)?;
+                }
+                Ok(())
+            }
+            Self::Failed(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for UnmountError {}
+
+// Unmounts a remotefs mount through fusermount, or umount when that is
+// missing. Lazy detaches it right away even while in use. A mount that
+// stays busy is reported along with the processes holding it.
+pub fn unmount(mountpoint: &Path, lazy: bool) -> Result<(), UnmountError> {
+    // A mount whose session died cannot be resolved, its path is taken as is
+    let mountpoint = mountpoint
+        .canonicalize()
+        .or_else(|_| std::path::absolute(mountpoint))
+        .map_err(|_| UnmountError::NotMounted)?;
+    let mounts = fs::read_to_string(Path::new(PROC).join("mounts"))
+        .context("Failed to read the mount table")
+        .map_err(UnmountError::Failed)?;
+    if !is_mounted(&mounts, &mountpoint) {
+        return Err(UnmountError::NotMounted);
+    }
+
+    let fusermount = |program: &str| {
+        let mut command = Command::new(program);
+        command.arg(if lazy { "-uz" } else { "-u" });
+        command
+    };
+    let mut umount = Command::new("umount");
+    if lazy {
+        umount.arg("-l");
+    }
+
+    for mut command in [fusermount("fusermount3"), fusermount("fusermount"), umount] {
+        let output = match command.arg(&mountpoint).output() {
+            Ok(output) => output,
+            // Not installed, try the next one
+            Err(_) => continue,
+        };
+        if output.status.success() {
+            return Ok(());
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("busy") {
+            return Err(UnmountError::Busy(busy_processes(
+                Path::new(PROC),
+                &mountpoint,
+            )));
+        }
+        return Err(UnmountError::Failed(anyhow::anyhow!(
+            "{:?} failed: {}",
+            command.get_program(),
+            stderr.trim()
+        )));
+    }
+
+    Err(UnmountError::Failed(anyhow::anyhow!(
+        "Neither fusermount nor umount is available"
+    )))
+}
+
+// Whether a /proc/mounts style table has a remotefs mount at `mountpoint`
+pub fn is_mounted(mounts: &str, mountpoint: &Path) -> bool {
+    mounts.lines().any(|line| {
+        let mut fields = line.split(' ');
+        let (Some(source), Some(target), Some(fs_type)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return false;
+        };
+        let ours = source == FS_NAME || fs_type.ends_with(&format!(".{}", FS_NAME));
+        ours && Path::new(&unescape_mount_field(target)) == mountpoint
+    })
+}
+
+// The mount table escapes space, tab, newline and backslash as octal
+fn unescape_mount_field(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(i) = rest.find('\\') {
+        unescaped.push_str(&rest[..i]);
+        let code = rest
+            .get(i + 1..i + 4)
+            .and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        match code {
+            Some(byte) => {
+                unescaped.push(byte as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+// Processes under a /proc style `proc_root` whose working directory, root or
+// open files are inside `mountpoint`. Processes of other users are only seen
+// when running as root.
+pub fn busy_processes(proc_root: &Path, mountpoint: &Path) -> Vec<BusyProcess> {
+    let Ok(entries) = fs::read_dir(proc_root) else {
+        return Vec::new();
+    };
+
+    let mut busy: Vec<BusyProcess> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let pid = entry.file_name().to_str()?.parse::<u32>().ok()?;
+            let dir = entry.path();
+            let mut links: Vec<PathBuf> = vec![dir.join("cwd"), dir.join("root")];
+            if let Ok(fds) = fs::read_dir(dir.join("fd")) {
+                links.extend(fds.flatten().map(|fd| fd.path()));
+            }
+
+            let uses_mount = links
+                .iter()
+                .filter_map(|link| fs::read_link(link).ok())
+                .any(|target| target.starts_with(mountpoint));
+            if !uses_mount {
+                return None;
+            }
+
+            let command = fs::read_to_string(dir.join("comm")).unwrap_or_default();
+            Some(BusyProcess {
+                pid,
+                command: command.trim().to_string(),
+            })
+        })
+        .collect();
+    busy.sort_by_key(|process| process.pid);
+    busy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    const MOUNTS: &str = "\
+sysfs /sys sysfs rw,nosuid 0 0
+remotefs /mnt/remote fuse.remotefs rw,nosuid,nodev 0 0
+https://files.example.com /mnt/with\\040space fuse.remotefs rw 0 0
+other /mnt/other fuse.sshfs rw 0 0
+";
+
+    #[test]
+    fn only_our_mounts_are_found() {
+        assert!(is_mounted(MOUNTS, Path::new("/mnt/remote")));
+        assert!(is_mounted(MOUNTS, Path::new("/mnt/with space")));
+        assert!(!is_mounted(MOUNTS, Path::new("/mnt/other")));
+        assert!(!is_mounted(MOUNTS, Path::new("/sys")));
+        assert!(!is_mounted(MOUNTS, Path::new("/mnt")));
+        assert!(!is_mounted("garbage\n", Path::new("/mnt/remote")));
+    }
+
+    #[test]
+    fn mount_fields_are_unescaped() {
+        assert_eq!(unescape_mount_field("a\\040b\\011c\\134d"), "a b\tc\\d");
+        assert_eq!(unescape_mount_field("plain"), "plain");
+        // Not an octal escape, left as it is
+        assert_eq!(unescape_mount_field("a\\x"), "a\\x");
+        assert_eq!(unescape_mount_field("end\\"), "end\\");
+    }
+
+    #[test]
+    fn processes_in_the_mount_are_busy() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let process = |pid: u32, command: &str, cwd: &str, fds: &[&str]| {
+            let dir = proc_root.path().join(pid.to_string());
+            fs::create_dir_all(dir.join("fd")).unwrap();
+            fs::write(dir.join("comm"), format!("{}\n", command)).unwrap();
+            symlink(cwd, dir.join("cwd")).unwrap();
+            symlink("/", dir.join("root")).unwrap();
+            for (fd, target) in fds.iter().enumerate() {
+                symlink(target, dir.join("fd").join(fd.to_string())).unwrap();
+            }
+        };
+        process(40, "bash", "/mnt/remote/docs", &[]);
+        process(7, "vim", "/home/user", &["/dev/null", "/mnt/remote/notes.txt"]);
+        process(12, "sleep", "/home/user", &["/mnt/remote-other/file"]);
+        fs::create_dir(proc_root.path().join("self-not-a-pid")).unwrap();
+
+        let busy = busy_processes(proc_root.path(), Path::new("/mnt/remote"));
+        let found: Vec<_> = busy.iter().map(|p| (p.pid, p.command.as_str())).collect();
+        assert_eq!(found, [(7, "vim"), (40, "bash")]);
+
+        let missing = busy_processes(&proc_root.path().join("missing"), Path::new("/"));
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn errors_have_their_own_exit_codes() {
+        let busy = UnmountError::Busy(vec![BusyProcess {
+            pid: 7,
+            command: "vim".to_string(),
+        }]);
+        assert_eq!(busy.to_string(), "mount is busy, in use by 7 (vim)");
+        assert_eq!(UnmountError::Busy(Vec::new()).to_string(), "mount is busy");
+
+        let codes = [
+            UnmountError::Failed(anyhow::anyhow!("broke")).exit_code(),
+            UnmountError::NotMounted.exit_code(),
+            busy.exit_code(),
+        ];
+        assert_eq!(codes, [1, 2, 3]);
+    }
+
+    #[test]
+    fn unmounting_what_is_not_mounted_fails_early() {
+        let dir = tempfile::tempdir().unwrap();
+        let error = unmount(dir.path(), false).unwrap_err();
+        assert!(matches!(error, UnmountError::NotMounted));
+    }
+}
@@ -0,0 +1,517 @@
+use anyhow::{Context, Result};
+use reqwest::blocking::{Body, Client, RequestBuilder, Response};
+use reqwest::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, IF_MATCH, RANGE};
+use reqwest::{Method, StatusCode};
+use std::io::Read;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::api_client::{
+    encode_path, last_byte, ClientConfig, Conditional, Endpoint, Expected, FileData, FileEntry,
+    Listing, ServerError, Timestamp, Version,
+};
+use crate::filesystem::{normalize_path, FsError, RemoteBackend};
+
+mod multistatus;
+
+use multistatus::{Resource, PROPFIND_BODY};
+
+// What OPTIONS says the server supports, asked once
+#[derive(Debug, Clone, Copy)]
+struct Capabilities {
+    // sabre/dav style PATCH with X-Update-Range
+    partial_update: bool,
+}
+
+// Storage reached over WebDAV instead of the REST API: PROPFIND for
+// listings and stat, ranged GET, PUT, MKCOL, DELETE and MOVE. The server
+// URLs, timeout and token are those of ClientConfig; only the first URL is
+// used.
+pub struct WebDavBackend {
+    config: ClientConfig,
+    client: Client,
+    // What paths are appended to, see Endpoint
+    base: String,
+    auth: Option<HeaderValue>,
+    capabilities: OnceLock<Capabilities>,
+    consecutive_failures: AtomicU32,
+}
+
+fn method(name: &'static str) -> Method {
+    Method::from_bytes(name.as_bytes()).expect("valid method name")
+}
+
+// DAV statuses with a meaning of their own, the rest are classified like
+// those of the REST API
+fn dav_error(operation: &str, path: &str, response: &Response) -> anyhow::Error {
+    let kind = match (operation, response.status()) {
+        // MKCOL on something that exists, or under a missing parent
+        ("MKCOL", StatusCode::METHOD_NOT_ALLOWED) => Some(FsError::AlreadyExists),
+        ("MKCOL" | "PUT" | "MOVE", StatusCode::CONFLICT) => Some(FsError::NotFound),
+        (_, StatusCode::LOCKED) => Some(FsError::PermissionDenied),
+        (_, StatusCode::FAILED_DEPENDENCY) => Some(FsError::Io),
+        _ => None,
+    };
+    let context = format!("{} of /{} failed", operation, path);
+    match kind {
+        Some(kind) => anyhow::Error::new(kind).context(context),
+        None => anyhow::Error::new(ServerError::from(response)).context(context),
+    }
+}
+
+impl WebDavBackend {
+    pub fn with_config(config: ClientConfig) -> Result<Self> {
+        let client = config.http.client()?;
+        Self::with_http_client(config, client)
+    }
+
+    // Shares `client` with other mounts of the process, like
+    // ApiClient::with_http_client
+    pub fn with_http_client(config: ClientConfig, client: Client) -> Result<Self> {
+        if config.base_urls.is_empty() {
+            anyhow::bail!("At least one server URL is required");
+        }
+        ClientConfig::validate_chunk_size(config.chunk_size)?;
+        let endpoint = Endpoint::new(&config.base_urls[0], &config.http)?;
+        let client = endpoint.client(&client).clone();
+
+        let auth = match &config.token {
+            Some(token) => {
+                let mut value = HeaderValue::from_str(&format!("Bearer {}", token.expose()))
+                    .context("Token contains characters not allowed in a header")?;
+                value.set_sensitive(true);
+                Some(value)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            config,
+            client,
+            base: endpoint.base,
+            auth,
+            capabilities: OnceLock::new(),
+            consecutive_failures: AtomicU32::new(0),
+        })
+    }
+
+    fn base(&self) -> &str {
+        &self.config.base_urls[0]
+    }
+
+    // Collections are addressed with a trailing slash, which some servers
+    // insist on
+    fn url(&self, path: &str, is_dir: bool) -> String {
+        let path = path.trim_matches('/');
+        let mut url = format!("{}/{}", self.base, encode_path(path));
+        if is_dir && !path.is_empty() {
+            url.push('/');
+        }
+        url
+    }
+
+    fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let mut request = request.timeout(self.config.timeout);
+        if let Some(auth) = &self.auth {
+            request = request.header(AUTHORIZATION, auth.clone());
+        }
+        match request.send() {
+            Ok(response) => {
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+                Ok(response)
+            }
+            Err(e) => {
+                self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+                Err(e.into())
+            }
+        }
+    }
+
+    fn capabilities(&self) -> Result<Capabilities> {
+        if let Some(capabilities) = self.capabilities.get() {
+            return Ok(*capabilities);
+        }
+        let response = self
+            .send(self.client.request(Method::OPTIONS, self.url("", true)))
+            .context("Failed to send OPTIONS request")?;
+        if !response.status().is_success() {
+            return Err(dav_error("OPTIONS", "", &response));
+        }
+        let header = |name: &str| {
+            response
+                .headers()
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(",")
+                .to_ascii_lowercase()
+        };
+        let dav = header("dav");
+        anyhow::ensure!(
+            dav.split(',').any(|class| class.trim() == "1"),
+            "{} is not a WebDAV server, OPTIONS has no DAV class 1",
+            self.base()
+        );
+        let allow = header("allow");
+        let capabilities = Capabilities {
+            partial_update: dav.contains("sabredav-partialupdate") && allow.contains("patch"),
+        };
+        log::debug!("WebDAV server capabilities: {:?}", capabilities);
+        Ok(*self.capabilities.get_or_init(|| capabilities))
+    }
+
+    fn propfind(&self, path: &str, depth: u32) -> Result<Option<Vec<Resource>>> {
+        let path = path.trim_matches('/');
+        log::debug!("PROPFIND /{} (depth {})", path, depth);
+        let response = self
+            .send(
+                self.client
+                    .request(method("PROPFIND"), self.url(path, depth > 0))
+                    .header("Depth", depth.to_string())
+                    .header(CONTENT_TYPE, "application/xml; charset=utf-8")
+                    .body(PROPFIND_BODY),
+            )
+            .context("Failed to send PROPFIND request")?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => return Ok(None),
+            StatusCode::MULTI_STATUS => {}
+            _ => return Err(dav_error("PROPFIND", path, &response)),
+        }
+        let body = response
+            .text()
+            .context("Failed to read PROPFIND response")?;
+        multistatus::parse(&body)
+            .with_context(|| format!("Invalid PROPFIND response for /{}", path))
+            .map(Some)
+    }
+
+    // The resource at `path` itself, None when there is nothing there
+    fn stat(&self, path: &str) -> Result<Option<Resource>> {
+        let Some(resources) = self.propfind(path, 0)? else {
+            return Ok(None);
+        };
+        let wanted = normalize_path(path);
+        Ok(resources
+            .iter()
+            .find(|resource| resource.path.ends_with(&wanted))
+            .or(resources.first())
+            .cloned())
+    }
+
+    pub fn list_directory(&self, path: &str) -> Result<Listing> {
+        let resources = self
+            .propfind(path, 1)?
+            .ok_or_else(|| anyhow::Error::new(FsError::NotFound))
+            .with_context(|| format!("/{} does not exist", path.trim_matches('/')))?;
+
+        // The collection comes back along with its members. Its href carries
+        // the server's own prefix, so it is told apart as the shortest one.
+        let own = resources
+            .iter()
+            .map(|resource| resource.path.len())
+            .min()
+            .unwrap_or(0);
+        let mut version = None;
+        let mut entries = Vec::new();
+        for resource in &resources {
+            if resource.path.len() == own {
+                version = resource.version();
+                continue;
+            }
+            let mtime = resource.mtime.map_or(Timestamp::UNKNOWN, Timestamp::from_secs_f64);
+            entries.push(FileEntry {
+                name: resource.name().to_string(),
+                is_dir: resource.is_dir,
+                size: if resource.is_dir { 0 } else { resource.size },
+                mtime,
+                ctime: resource.ctime.map_or(mtime, Timestamp::from_secs_f64),
+                mode: if resource.is_dir { 0o755 } else { 0o644 },
+                id: None,
+                uid: None,
+                gid: None,
+                major: None,
+                minor: None,
+                nlink: None,
+            });
+        }
+        Ok(Listing { entries, version })
+    }
+
+    // PROPFIND is not conditional, the listing is compared by its version
+    pub fn revalidate_listing(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Listing>> {
+        let listing = self.list_directory(path)?;
+        if listing.version.as_ref() == Some(version) {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified(listing))
+    }
+
+    pub fn revalidate_file(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Option<Version>>> {
+        let current = match self.stat(path)? {
+            Some(resource) => resource.version(),
+            None => return Ok(Conditional::Modified(None)),
+        };
+        if current.as_ref() == Some(version) {
+            return Ok(Conditional::NotModified);
+        }
+        Ok(Conditional::Modified(current))
+    }
+
+    pub fn file_version(&self, path: &str) -> Result<Option<Version>> {
+        Ok(self.stat(path)?.and_then(|resource| resource.version()))
+    }
+
+    pub fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        if len == 0 {
+            return Ok(FileData {
+                data: Vec::new(),
+                version: None,
+            });
+        }
+        let path = path.trim_matches('/');
+        log::debug!("GET /{} ({} bytes at {})", path, len, offset);
+        let range = format!("bytes={}-{}", offset, last_byte(offset, len)?);
+        let response = self
+            .send(self.client.get(self.url(path, false)).header(RANGE, range))
+            .context("Failed to send read request")?;
+
+        let status = response.status();
+        let version = Version::from_response(&response);
+        if status == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(FileData {
+                data: Vec::new(),
+                version,
+            });
+        }
+        if !status.is_success() {
+            return Err(dav_error("GET", path, &response));
+        }
+        let bytes = response.bytes().context("Failed to read response")?;
+        // A server ignoring Range sends the whole file
+        let data = if status == StatusCode::PARTIAL_CONTENT {
+            bytes.to_vec()
+        } else {
+            let start = (offset as usize).min(bytes.len());
+            let end = (offset + len).min(bytes.len() as u64) as usize;
+            bytes[start..end].to_vec()
+        };
+        Ok(FileData { data, version })
+    }
+
+    pub fn write_file_if(
+        &self,
+        path: &str,
+        data: &[u8],
+        expected: &Expected,
+    ) -> Result<Option<Version>> {
+        let path = path.trim_matches('/');
+        log::debug!("PUT /{} ({} bytes)", path, data.len());
+        let request = self.client.put(self.url(path, false)).body(data.to_vec());
+        let response = self
+            .send(expected.condition(request))
+            .context("Failed to send write request")?;
+        if !response.status().is_success() {
+            return Err(dav_error("PUT", path, &response));
+        }
+        Ok(Version::from_response(&response))
+    }
+
+    pub fn write_file_streamed(
+        &self,
+        path: &str,
+        len: u64,
+        open: &dyn Fn() -> Box<dyn Read + Send>,
+    ) -> Result<()> {
+        let path = path.trim_matches('/');
+        log::debug!("PUT /{} ({} bytes, streamed)", path, len);
+        let body = Body::sized(open(), len);
+        let response = self
+            .send(self.client.put(self.url(path, false)).body(body))
+            .context("Failed to send write request")?;
+        if !response.status().is_success() {
+            return Err(dav_error("PUT", path, &response));
+        }
+        Ok(())
+    }
+
+    // Only servers announcing sabre/dav partial updates take ranges
+    pub fn write_range(&self, path: &str, offset: u64, data: &[u8]) -> Result<bool> {
+        if data.is_empty() || !self.capabilities()?.partial_update {
+            return Ok(false);
+        }
+        let path = path.trim_matches('/');
+        let range = format!("bytes={}-{}", offset, last_byte(offset, data.len() as u64)?);
+        let response = self
+            .send(
+                self.client
+                    .patch(self.url(path, false))
+                    .header(CONTENT_TYPE, "application/x-sabredav-partialupdate")
+                    .header("X-Update-Range", range)
+                    .body(data.to_vec()),
+            )
+            .context("Failed to send partial write request")?;
+        match response.status() {
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => Ok(false),
+            status if status.is_success() => Ok(true),
+            _ => Err(dav_error("PATCH", path, &response)),
+        }
+    }
+
+    pub fn create_directory(&self, path: &str) -> Result<()> {
+        let path = path.trim_matches('/');
+        log::debug!("MKCOL /{}", path);
+        let response = self
+            .send(self.client.request(method("MKCOL"), self.url(path, true)))
+            .context("Failed to send MKCOL request")?;
+        if !response.status().is_success() {
+            return Err(dav_error("MKCOL", path, &response));
+        }
+        Ok(())
+    }
+
+    pub fn delete_if(&self, path: &str, expected: &Expected) -> Result<()> {
+        let path = path.trim_matches('/');
+        log::debug!("DELETE /{}", path);
+        let mut request = self.client.delete(self.url(path, false));
+        if let Expected::Version(Version::ETag(etag)) = expected {
+            request = request.header(IF_MATCH, etag.as_str());
+        }
+        let response = self
+            .send(request)
+            .context("Failed to send DELETE request")?;
+        if !response.status().is_success() {
+            return Err(dav_error("DELETE", path, &response));
+        }
+        Ok(())
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (from.trim_matches('/'), to.trim_matches('/'));
+        log::debug!("MOVE /{} to /{}", from, to);
+        let response = self
+            .send(
+                self.client
+                    .request(method("MOVE"), self.url(from, false))
+                    .header("Destination", self.url(to, false))
+                    .header("Overwrite", "T"),
+            )
+            .context("Failed to send MOVE request")?;
+        if !response.status().is_success() {
+            return Err(dav_error("MOVE", from, &response));
+        }
+        Ok(())
+    }
+
+    // OPTIONS must announce DAV, then the root must be listable
+    pub fn check_reachable(&self, timeout: Duration) -> Result<()> {
+        self.capabilities()?;
+        let response = self
+            .send(
+                self.client
+                    .request(method("PROPFIND"), self.url("", true))
+                    .header("Depth", "0")
+                    .body(PROPFIND_BODY)
+                    .timeout(timeout),
+            )
+            .context("Failed to list the root")?;
+        if response.status() != StatusCode::MULTI_STATUS {
+            return Err(dav_error("PROPFIND", "", &response));
+        }
+        Ok(())
+    }
+}
+
+impl RemoteBackend for WebDavBackend {
+    fn chunk_size(&self) -> u64 {
+        self.config.chunk_size
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.consecutive_failures.load(Ordering::Relaxed) == 0
+    }
+
+    fn endpoint(&self) -> &str {
+        self.base()
+    }
+
+    fn check_reachable(&self, timeout: Duration) -> Result<()> {
+        WebDavBackend::check_reachable(self, timeout)
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Listing> {
+        WebDavBackend::list_directory(self, path)
+    }
+
+    fn revalidate_listing(&self, path: &str, version: &Version) -> Result<Conditional<Listing>> {
+        WebDavBackend::revalidate_listing(self, path, version)
+    }
+
+    fn revalidate_file(
+        &self,
+        path: &str,
+        version: &Version,
+    ) -> Result<Conditional<Option<Version>>> {
+        WebDavBackend::revalidate_file(self, path, version)
+    }
+
+    fn file_version(&self, path: &str) -> Result<Option<Version>> {
+        WebDavBackend::file_version(self, path)
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<FileData> {
+        WebDavBackend::read_range(self, path, offset, len)
+    }
+
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<()> {
+        self.write_file_if(path, data, &Expected::Any).map(|_| ())
+    }
+
+    fn write_file_if(
+        &self,
+        path: &str,
+        data: &[u8],
+        expected: &Expected,
+    ) -> Result<Option<Version>> {
+        WebDavBackend::write_file_if(self, path, data, expected)
+    }
+
+    fn write_file_streamed(
+        &self,
+        path: &str,
+        len: u64,
+        open: &dyn Fn() -> Box<dyn Read + Send>,
+    ) -> Result<()> {
+        WebDavBackend::write_file_streamed(self, path, len, open)
+    }
+
+    fn write_range(&self, path: &str, offset: u64, data: &[u8]) -> Result<bool> {
+        WebDavBackend::write_range(self, path, offset, data)
+    }
+
+    fn create_directory(&self, path: &str) -> Result<()> {
+        WebDavBackend::create_directory(self, path)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.delete_if(path, &Expected::Any)
+    }
+
+    fn delete_if(&self, path: &str, expected: &Expected) -> Result<()> {
+        WebDavBackend::delete_if(self, path, expected)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        WebDavBackend::rename(self, from, to)
+    }
+}
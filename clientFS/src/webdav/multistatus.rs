@@ -0,0 +1,154 @@
+use anyhow::{Context, Result};
+use roxmltree::{Document, Node};
+
+use crate::api_client::Version;
+use crate::dates::{parse_http_date, parse_rfc3339};
+use crate::filesystem::normalize_path;
+
+// The properties asked for in every PROPFIND
+pub const PROPFIND_BODY: &str = concat!(
+    r#"<?xml version="1.0" encoding="utf-8"?>"#,
+    r#"<D:propfind xmlns:D="DAV:"><D:prop>"#,
+    "<D:resourcetype/><D:getcontentlength/><D:getlastmodified/>",
+    "<D:creationdate/><D:getetag/>",
+    "</D:prop></D:propfind>",
+);
+
+// One <response> of a multistatus, with the properties the server found
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Resource {
+    // Decoded path of the href, without the scheme, host or trailing slash
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub mtime: Option<f64>,
+    pub ctime: Option<f64>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Resource {
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or_default()
+    }
+
+    pub fn version(&self) -> Option<Version> {
+        self.etag
+            .clone()
+            .map(Version::ETag)
+            .or_else(|| self.last_modified.clone().map(Version::LastModified))
+    }
+}
+
+// Elements are matched by local name. Servers put DAV: under any prefix,
+// or as the default namespace, and a few leave it out altogether.
+fn is(node: &Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().name() == name
+}
+
+fn child<'a, 'input>(node: &Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| is(child, name))
+}
+
+fn text(node: &Node, name: &str) -> Option<String> {
+    child(node, name)
+        .and_then(|child| child.text())
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+// "HTTP/1.1 200 OK" is 200
+fn status(line: &str) -> Option<u16> {
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+// Parses a 207 Multi-Status body. Responses failing as a whole, and
+// properties in a propstat that did not succeed, are left out.
+pub fn parse(body: &str) -> Result<Vec<Resource>> {
+    let document = Document::parse(body).context("Invalid multistatus XML")?;
+    let root = document.root_element();
+    anyhow::ensure!(
+        is(&root, "multistatus"),
+        "Expected a multistatus, got <{}>",
+        root.tag_name().name()
+    );
+
+    let mut resources = Vec::new();
+    for response in root.children().filter(|node| is(node, "response")) {
+        let Some(href) = text(&response, "href") else {
+            continue;
+        };
+        if text(&response, "status")
+            .and_then(|line| status(&line))
+            .is_some_and(|code| !(200..300).contains(&code))
+        {
+            continue;
+        }
+
+        let mut resource = Resource {
+            path: href_path(&href),
+            ..Default::default()
+        };
+        for propstat in response.children().filter(|node| is(node, "propstat")) {
+            let ok = text(&propstat, "status")
+                .and_then(|line| status(&line))
+                .is_none_or(|code| (200..300).contains(&code));
+            let Some(prop) = child(&propstat, "prop").filter(|_| ok) else {
+                continue;
+            };
+            if let Some(kind) = child(&prop, "resourcetype") {
+                resource.is_dir = child(&kind, "collection").is_some();
+            }
+            if let Some(length) = text(&prop, "getcontentlength") {
+                resource.size = length.parse().unwrap_or(0);
+            }
+            if let Some(modified) = text(&prop, "getlastmodified") {
+                resource.mtime = parse_http_date(&modified);
+                resource.last_modified = Some(modified);
+            }
+            if let Some(created) = text(&prop, "creationdate") {
+                resource.ctime = parse_rfc3339(&created);
+            }
+            if let Some(etag) = text(&prop, "getetag") {
+                resource.etag = Some(etag);
+            }
+        }
+        resources.push(resource);
+    }
+    Ok(resources)
+}
+
+// Hrefs come as absolute URLs or absolute paths, encoded however the server
+// likes. Both are reduced to the decoded path.
+pub fn href_path(href: &str) -> String {
+    let path = match href.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("", |at| &rest[at..]),
+        None => href,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let decoded = percent_decode(path);
+    normalize_path(&decoded)
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -125,10 +125,41 @@ def write_file(file_path):
         with open(full_path, 'wb') as f:
             f.write(request.get_data())
 
+        # Keep the modification time the client sends instead of now, to the
+        # nanosecond: a float would round it
+        mtime = request.headers.get('X-Mtime')
+        if mtime:
+            try:
+                secs, _, fraction = mtime.partition('.')
+                ns = int(secs) * 10**9 + int((fraction + '0' * 9)[:9])
+                os.utime(full_path, ns=(ns, ns))
+            except ValueError:
+                pass
+
         return jsonify({"success": True, "bytes_written": len(request.get_data())})
     except Exception as e:
         return jsonify({"error": str(e)}), 500
 
+@app.route('/files/<path:file_path>', methods=['PATCH'])
+def set_mtime(file_path):
+    """PATCH /files/<path> – Set the modification time from X-Mtime"""
+    # Only the body-less form that sets a time, writes at an offset are not
+    # supported and the client falls back to whole uploads
+    if request.headers.get('Content-Range') or request.get_data():
+        return jsonify({"error": "Partial writes not supported"}), 501
+
+    full_path = get_full_path(file_path)
+    if not full_path or not os.path.isfile(full_path):
+        return jsonify({"error": "File not found"}), 404
+
+    try:
+        secs, _, fraction = request.headers.get('X-Mtime', '').partition('.')
+        ns = int(secs) * 10**9 + int((fraction + '0' * 9)[:9])
+    except ValueError:
+        return jsonify({"error": "Missing or invalid X-Mtime"}), 400
+    os.utime(full_path, ns=(ns, ns))
+    return jsonify({"success": True})
+
 @app.route('/mkdir/<path:dir_path>', methods=['POST'])
 def create_directory(dir_path):
     """POST /mkdir/<path> – Create directory"""
@@ -136,6 +167,11 @@ def create_directory(dir_path):
     if not full_path:
         return jsonify({"error": "Invalid path"}), 400
 
+    # With If-None-Match: * the client wants mkdir(2) semantics, anything
+    # already at the path is a conflict
+    if request.headers.get('If-None-Match') == '*' and os.path.exists(full_path):
+        return jsonify({"error": "Already exists"}), 409
+
     try:
         os.makedirs(full_path, exist_ok=True)
         return jsonify({"success": True})